@@ -0,0 +1,110 @@
+//! Compares the default epoll-based TCP listener with `UringListener`:
+//! latency of one message at a time from a sender to a receiver, and
+//! throughput of a pipelined burst, kept within a window so the receiver
+//! isn't dropped as a slow consumer. The sender writes raw lines and
+//! discards the echoes of its own messages unread. `UringListener` relays
+//! each socket through an in-memory pipe, so it isn't expected to win.
+//!
+//! Run with `cargo bench --features io-uring`.
+
+use anyhow::Result;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio_chat_server::ChatServer;
+use tokio_chat_server::client::Client;
+use tokio_chat_server::protocol::{ChatMessage, ServerFrame};
+use tokio_chat_server::uring::UringListener;
+
+const WARMUP: usize = 200;
+const ROUND_TRIPS: usize = 2_000;
+const BURST: usize = 20_000;
+/// Messages the burst's sender may be ahead of its receiver.
+const WINDOW: usize = 256;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let epoll = ChatServer::from_listener(TcpListener::bind("127.0.0.1:0").await?);
+    report("epoll", epoll).await?;
+    let uring = ChatServer::from_listener(UringListener::bind("127.0.0.1:0")?);
+    report("io_uring", uring).await?;
+    Ok(())
+}
+
+async fn report(name: &str, server: ChatServer) -> Result<()> {
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let socket = TcpStream::connect(&addr).await?;
+    socket.set_nodelay(true)?;
+    let (mut echoes, mut sender) = socket.into_split();
+    tokio::spawn(async move { tokio::io::copy(&mut echoes, &mut tokio::io::sink()).await });
+    let mut receiver = Client::connect(&addr).await?;
+    // Messages sent before the server serves the receiver don't reach it.
+    receiver.fetch_history(None, None, Some(1)).await?;
+    while !matches!(receiver.receive().await?, ServerFrame::History { .. }) {}
+
+    for i in 0..WARMUP {
+        send(&mut sender, i).await?;
+        next_message(&mut receiver).await?;
+    }
+
+    let mut latencies = Vec::with_capacity(ROUND_TRIPS);
+    for i in 0..ROUND_TRIPS {
+        let started = Instant::now();
+        send(&mut sender, i).await?;
+        next_message(&mut receiver).await?;
+        latencies.push(started.elapsed());
+    }
+    latencies.sort();
+
+    let window = Semaphore::new(WINDOW);
+    let started = Instant::now();
+    let send = async {
+        for i in 0..BURST {
+            window.acquire().await?.forget();
+            send(&mut sender, i).await?;
+        }
+        anyhow::Ok(())
+    };
+    let receive = async {
+        for _ in 0..BURST {
+            next_message(&mut receiver).await?;
+            window.add_permits(1);
+        }
+        anyhow::Ok(())
+    };
+    tokio::try_join!(send, receive)?;
+    let elapsed = started.elapsed();
+
+    println!(
+        "{:>8}: p50 {:>8.1?}  p99 {:>8.1?}  max {:>8.1?}  {:>8.0} msg/s",
+        name,
+        percentile(&latencies, 0.50),
+        percentile(&latencies, 0.99),
+        latencies[latencies.len() - 1],
+        BURST as f64 / elapsed.as_secs_f64(),
+    );
+    Ok(())
+}
+
+async fn send(sender: &mut OwnedWriteHalf, i: usize) -> Result<()> {
+    let message = ChatMessage::from_raw(&format!("bench: message {}", i))?;
+    sender
+        .write_all(format!("{}\n", message.to_json()?).as_bytes())
+        .await?;
+    Ok(())
+}
+
+async fn next_message(client: &mut Client) -> Result<()> {
+    loop {
+        if let ServerFrame::Message { .. } = client.receive().await? {
+            return Ok(());
+        }
+    }
+}
+
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * fraction) as usize]
+}
@@ -1,4 +1,3 @@
-use console_subscriber;
 use tokio_chat_server::ChatServer;
 use tracing::info;
 
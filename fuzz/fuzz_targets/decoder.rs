@@ -0,0 +1,22 @@
+//! Feeds arbitrary bytes to `FrameDecoder`, split at an input-chosen point,
+//! and decodes both frame types. Run with `cargo fuzz run decoder`.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio_chat_server::codec::FrameDecoder;
+use tokio_chat_server::protocol::{ClientFrame, ServerFrame};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&split, data)) = data.split_first() else {
+        return;
+    };
+    let (first, second) = data.split_at(usize::from(split).min(data.len()));
+    let mut decoder = FrameDecoder::new(256);
+    for piece in [first, second] {
+        decoder.extend(piece);
+        while !matches!(decoder.decode::<ClientFrame>(), Ok(None)) {}
+    }
+    decoder.clear();
+    decoder.extend(data);
+    while !matches!(decoder.decode::<ServerFrame>(), Ok(None)) {}
+});
@@ -0,0 +1,250 @@
+use crate::tls::Certificates;
+use anyhow::{Context, Result, anyhow, bail};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, Order, OrderStatus, RetryPolicy,
+};
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::OnceCell;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tracing::{error, info};
+
+/// Where the ACME account's credentials are kept, in the certificate
+/// directory.
+const ACCOUNT_FILE: &str = "acme-account.json";
+
+/// Settings for `Acme`.
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    /// The hostnames to keep certificates for. Wildcards can't be
+    /// validated over TLS-ALPN-01.
+    pub domains: Vec<String>,
+    /// Where the CA can reach the operator, e.g. `mailto:ops@example.com`.
+    pub contact: Vec<String>,
+    /// The CA's ACME directory.
+    pub directory_url: String,
+    /// A PEM root certificate to trust the directory with instead of the
+    /// platform's, for private or test CAs.
+    pub directory_root: Option<PathBuf>,
+    /// How long before a certificate expires it's renewed.
+    pub renew_before: Duration,
+    /// How often the certificates are checked, and failed issuance retried.
+    pub check_interval: Duration,
+}
+
+impl AcmeConfig {
+    /// Settings for certificates from Let's Encrypt for `domains`,
+    /// renewed 30 days before they expire.
+    pub fn new(domains: Vec<String>) -> Self {
+        AcmeConfig {
+            domains,
+            contact: Vec::new(),
+            directory_url: LetsEncrypt::Production.url().to_string(),
+            directory_root: None,
+            renew_before: Duration::from_secs(30 * 24 * 60 * 60),
+            check_interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Keeps certificates for a `TlsListener` from an ACME CA such as Let's
+/// Encrypt, so small deployments don't have to handle them by hand. Each
+/// domain's certificate is issued when missing and renewed when it's about
+/// to expire, proving control of the domain with TLS-ALPN-01 challenges
+/// the listener answers on the chat port. Certificates are written to the
+/// `Certificates`' directory, where the account's credentials are kept too.
+pub struct Acme {
+    config: AcmeConfig,
+    certs: Arc<Certificates>,
+    account: OnceCell<Account>,
+}
+
+impl Acme {
+    /// Keeps `config.domains`' certificates in `certs`, which the port
+    /// the domains resolve to must be served with.
+    pub fn new(config: AcmeConfig, certs: Arc<Certificates>) -> Result<Self> {
+        if config.domains.is_empty() {
+            bail!("No domains to issue certificates for");
+        }
+        if let Some(domain) = config.domains.iter().find(|d| d.starts_with("*.")) {
+            bail!("{} can't be validated over TLS-ALPN-01", domain);
+        }
+        Ok(Acme {
+            config,
+            certs,
+            account: OnceCell::new(),
+        })
+    }
+
+    /// Issues and renews the certificates as they're due, checking every
+    /// `check_interval`.
+    pub async fn run(self) {
+        let mut ticker = tokio::time::interval(self.config.check_interval);
+        loop {
+            ticker.tick().await;
+            for domain in &self.config.domains {
+                if let Err(e) = self.renew_if_due(domain).await {
+                    error!("Failed to issue a certificate for {}: {:#}", domain, e);
+                }
+            }
+        }
+    }
+
+    /// Issues `domain` a certificate if it has none or it's about to
+    /// expire, returning whether it did.
+    pub async fn renew_if_due(&self, domain: &str) -> Result<bool> {
+        let path = self.cert_path(domain, "crt");
+        if let Some(expires) = expiry(&path)?
+            && expires > SystemTime::now() + self.config.renew_before
+        {
+            return Ok(false);
+        }
+        info!("Requesting a certificate for {}", domain);
+        let account = self.account.get_or_try_init(|| self.load_account()).await?;
+        let identifiers = [Identifier::Dns(domain.to_string())];
+        let mut order = account.new_order(&NewOrder::new(&identifiers)).await?;
+        let authorized = self.authorize(domain, &mut order).await;
+        self.certs.clear_challenge(domain);
+        match authorized? {
+            OrderStatus::Ready => {}
+            status => bail!("Order for {} is {:?}", domain, status),
+        }
+        let key = order.finalize().await?;
+        let chain = order.poll_certificate(&RetryPolicy::new()).await?;
+
+        // The key goes first, so the certificate is never reloaded without
+        // it.
+        write_atomically(&self.cert_path(domain, "key"), key.as_bytes())?;
+        write_atomically(&path, chain.as_bytes())?;
+        let certs = self.certs.clone();
+        tokio::task::spawn_blocking(move || certs.reload_if_changed()).await??;
+        info!("Installed a new certificate for {}", domain);
+        Ok(true)
+    }
+
+    /// Answers `order`'s challenges for `domain` and waits for the CA to
+    /// validate them.
+    async fn authorize(&self, domain: &str, order: &mut Order) -> Result<OrderStatus> {
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization?;
+            match authorization.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => bail!("Authorization for {} is {:?}", domain, status),
+            }
+            let mut challenge = authorization
+                .challenge(ChallengeType::TlsAlpn01)
+                .ok_or_else(|| anyhow!("The CA offered no TLS-ALPN-01 challenge"))?;
+            let digest = challenge.key_authorization().digest();
+            let (cert, key) = challenge_cert(domain, digest.as_ref())?;
+            self.certs.answer_challenge(domain, cert, key)?;
+            challenge.set_ready().await?;
+        }
+        Ok(order.poll_ready(&RetryPolicy::new()).await?)
+    }
+
+    /// Restores the account from its credentials, creating it with the CA
+    /// if there are none.
+    async fn load_account(&self) -> Result<Account> {
+        let builder = || match &self.config.directory_root {
+            Some(root) => Account::builder_with_root(root),
+            None => Account::builder(),
+        };
+        let path = self.certs.cert_dir().join(ACCOUNT_FILE);
+        if path.exists() {
+            let credentials: AccountCredentials = serde_json::from_slice(&std::fs::read(&path)?)
+                .with_context(|| format!("Invalid ACME account in {}", path.display()))?;
+            return Ok(builder()?.from_credentials(credentials).await?);
+        }
+        let contact: Vec<&str> = self.config.contact.iter().map(String::as_str).collect();
+        let (account, credentials) = builder()?
+            .create(
+                &NewAccount {
+                    contact: &contact,
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                },
+                self.config.directory_url.clone(),
+                None,
+            )
+            .await?;
+        write_atomically(&path, &serde_json::to_vec(&credentials)?)?;
+        info!("Created an ACME account with {}", self.config.directory_url);
+        Ok(account)
+    }
+
+    fn cert_path(&self, domain: &str, extension: &str) -> PathBuf {
+        self.certs
+            .cert_dir()
+            .join(format!("{}.{}", domain.to_ascii_lowercase(), extension))
+    }
+}
+
+/// Returns when the first certificate in the PEM file at `path` expires,
+/// or `None` if there's no file.
+fn expiry(path: &Path) -> Result<Option<SystemTime>> {
+    let pem = match std::fs::read(path) {
+        Ok(pem) => pem,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let der = CertificateDer::from_pem_slice(&pem)
+        .with_context(|| format!("Invalid certificate in {}", path.display()))?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&der)
+        .map_err(|e| anyhow!("Invalid certificate in {}: {}", path.display(), e))?;
+    let expires = cert.validity().not_after.timestamp();
+    Ok(Some(
+        UNIX_EPOCH + Duration::from_secs(expires.max(0) as u64),
+    ))
+}
+
+/// Returns a self-signed certificate for `domain` carrying the key
+/// authorization `digest`, as RFC 8737 has TLS-ALPN-01 challenges answered.
+fn challenge_cert(
+    domain: &str,
+    digest: &[u8],
+) -> Result<(CertificateDer<'static>, PrivateKeyDer<'static>)> {
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params.custom_extensions = vec![CustomExtension::new_acme_identifier(digest)];
+    let key = KeyPair::generate()?;
+    let cert = params.self_signed(&key)?;
+    let key = PrivateKeyDer::try_from(key.serialize_der()).map_err(|e| anyhow!(e))?;
+    Ok((cert.der().clone(), key))
+}
+
+/// Writes `contents` to `path` through a temporary file, so the reloads
+/// never see it half written.
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let temporary = path.with_extension("tmp");
+    std::fs::write(&temporary, contents)?;
+    std::fs::rename(&temporary, path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expiry_of_missing_and_issued_certificates() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("chat-acme-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("chat.test.crt");
+        assert_eq!(expiry(&path)?, None);
+
+        let mut params = CertificateParams::new(vec!["chat.test".to_string()])?;
+        params.not_after = rcgen::date_time_ymd(2031, 6, 1);
+        let cert = params.self_signed(&KeyPair::generate()?)?;
+        write_atomically(&path, cert.pem().as_bytes())?;
+        let expires = expiry(&path)?.unwrap().duration_since(UNIX_EPOCH)?;
+        assert_eq!(expires.as_secs(), 1_938_038_400);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
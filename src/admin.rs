@@ -0,0 +1,898 @@
+use crate::analytics::{Analytics, AnalyticsReport};
+use crate::announce::Announcer;
+use crate::archive::{ColdStore, read_archive};
+use crate::auth::generate_token;
+use crate::blob::{BlobStore, blob_id};
+use crate::codec::{Encoded, FrameDecoder};
+use crate::export::{ExportFormat, write_header, write_rows};
+use crate::fanout::FanOut;
+use crate::i18n::{Catalogs, DEFAULT_LOCALE};
+use crate::invite;
+use crate::metrics::{ConnectionStats, Metrics, MetricsSnapshot};
+use crate::protocol::{
+    AnnouncementLevel, ChatMessage, CustomEmoji, DEFAULT_ROOM, Priority, ServerFrame,
+};
+use crate::quota::{ResourceTracker, ResourceUsage};
+use crate::registry::Registry;
+use crate::server::room_change_frame;
+use crate::shortcode;
+use crate::store::MessageStore;
+use crate::supervisor::{JobHealth, Supervisor};
+use anyhow::{Result, anyhow};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
+use regex_automata::meta::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{broadcast, watch};
+use tracing::{debug, info, warn};
+
+/// Longest request line the admin socket accepts.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+/// Longest reply line `AdminConnection` accepts; listings can be long.
+const MAX_RESPONSE_LEN: usize = 16 * 1024 * 1024;
+/// Messages buffered for each tail before it starts missing some.
+const TAIL_BUFFER: usize = 1024;
+/// Largest custom emoji image, in bytes.
+pub const MAX_EMOJI_SIZE: usize = 256 * 1024;
+/// Messages read from the store for each chunk of an export.
+const EXPORT_PAGE: usize = 500;
+
+/// A connection as listed for operators.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionInfo {
+    pub addr: String,
+    /// Who the connection is signed in as, or its address if no one.
+    pub user: String,
+    pub stats: ConnectionStats,
+}
+
+/// Whether a server should be sent new clients, for readiness probes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Readiness {
+    /// Not draining, with every background job running.
+    pub ready: bool,
+    pub draining: bool,
+    pub jobs: Vec<JobHealth>,
+}
+
+/// Operator actions on a running server, from `ChatServer::admin`, for the
+/// admin HTTP routes or an application's own tooling. The default controls
+/// nothing, for serving admin routes without a server.
+#[derive(Clone, Default)]
+pub struct AdminControl {
+    pub(crate) registry: Arc<Registry>,
+    pub(crate) fanout: Arc<FanOut>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) resources: Arc<ResourceTracker>,
+    pub(crate) draining: Arc<watch::Sender<bool>>,
+    pub(crate) supervisor: Arc<Supervisor>,
+    pub(crate) feed: Arc<TailFeed>,
+    pub(crate) analytics: Arc<Analytics>,
+    pub(crate) announcer: Arc<Announcer>,
+    pub(crate) catalogs: Arc<Catalogs>,
+    /// Where custom emoji images go; `None` for the default control.
+    pub(crate) blobs: Option<Arc<dyn BlobStore>>,
+    /// History to export; `None` for the default control.
+    pub(crate) store: Option<Arc<dyn MessageStore>>,
+    pub(crate) cold: Option<Arc<dyn ColdStore>>,
+}
+
+impl AdminControl {
+    /// Starts following messages as they're relayed to rooms, as `filter`
+    /// selects and redacts them. Direct messages aren't included.
+    pub fn tail(&self, filter: TailFilter) -> Result<Tail> {
+        let pattern = filter.pattern.as_deref().map(Regex::new).transpose()?;
+        info!("Tailing messages: {:?}", filter);
+        Ok(Tail {
+            messages: self.feed.0.subscribe(),
+            pattern,
+            salt: generate_token(),
+            filter,
+        })
+    }
+
+    /// Starts exporting `room`'s history, optionally only messages relayed
+    /// within `[since, until)` (Unix seconds). The store is read a page at
+    /// a time as the export is consumed; an archived room is read from its
+    /// archive and left archived.
+    pub async fn export(
+        &self,
+        room: &str,
+        format: ExportFormat,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Export> {
+        let store = self
+            .store
+            .clone()
+            .ok_or_else(|| anyhow!("No history to export"))?;
+        let archived = match &self.cold {
+            Some(cold) if self.registry.is_archived(room) => {
+                Some(read_archive(&**cold, room).await?.into_iter())
+            }
+            _ => None,
+        };
+        info!("Exporting {} as {}", room, format);
+        Ok(Export {
+            store,
+            room: room.to_string(),
+            format,
+            since,
+            until,
+            archived,
+            next_seq: Some(0),
+            started: false,
+        })
+    }
+
+    /// Returns every open connection, by address.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut stats = self.metrics.snapshot().connections;
+        self.registry
+            .connections()
+            .into_iter()
+            .map(|(addr, user)| ConnectionInfo {
+                addr: addr.to_string(),
+                user,
+                stats: stats.remove(&addr.to_string()).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Disconnects every connection of `user`, which may also be a
+    /// connection's address, telling it why. Their sessions can still be
+    /// resumed; see `ban` to keep them out. Returns how many connections
+    /// were closed.
+    pub fn kick(&self, user: &str, reason: Option<&str>) -> usize {
+        let message = match reason {
+            Some(reason) => format!("Disconnected by an operator: {}", reason),
+            None => "Disconnected by an operator".to_string(),
+        };
+        let frame = ServerFrame::Error { message };
+        let mut kicked = 0;
+        for addr in self.registry.devices_of(user) {
+            let locale = self.registry.locale(addr);
+            if let Ok(frame) = self.encode(&frame, &locale) {
+                self.fanout.send_to(addr, Priority::System, &frame);
+            }
+            if self.fanout.close(addr) {
+                kicked += 1;
+            }
+        }
+        info!("Kicked {} ({} connections)", user, kicked);
+        kicked
+    }
+
+    /// Bans `user` from signing in, resuming sessions or posting under
+    /// that name, and kicks them. Returns how many connections were closed.
+    pub fn ban(&self, user: &str, reason: Option<&str>) -> usize {
+        if self.registry.ban(user) {
+            info!("Banned {}", user);
+        }
+        self.kick(user, reason)
+    }
+
+    /// Lifts a ban; returns false if `user` wasn't banned.
+    pub fn unban(&self, user: &str) -> bool {
+        let unbanned = self.registry.unban(user);
+        if unbanned {
+            info!("Unbanned {}", user);
+        }
+        unbanned
+    }
+
+    /// Returns every banned user, by name.
+    pub fn bans(&self) -> Vec<String> {
+        self.registry.bans()
+    }
+
+    /// Returns the server's counters.
+    pub fn stats(&self) -> MetricsSnapshot {
+        self.metrics.snapshot()
+    }
+
+    /// Returns the rooms and their use of the resource limits.
+    pub fn rooms(&self) -> ResourceUsage {
+        self.resources.usage(unix_time())
+    }
+
+    /// Sends a `ServerFrame::Announcement` to everyone, or to `room`'s
+    /// viewers. Returns false if it was held back as a repeat or over the
+    /// rate limit; it's then counted in the next identical one sent.
+    pub fn announce(
+        &self,
+        room: Option<&str>,
+        text: &str,
+        level: AnnouncementLevel,
+    ) -> Result<bool> {
+        if let Some(room) = room
+            && room != DEFAULT_ROOM
+            && !self.registry.has_room(room)
+        {
+            return Err(anyhow!("No such room: {}", room));
+        }
+        let Some(repeats) = self.announcer.admit(room, text, unix_time()) else {
+            debug!("Held back announcement: {}", text);
+            return Ok(false);
+        };
+        let frame = ServerFrame::Announcement {
+            room: room.map(str::to_string),
+            text: text.to_string(),
+            level,
+            repeats,
+        };
+        info!("Announcing: {}", frame);
+        self.broadcast(&frame)?;
+        Ok(true)
+    }
+
+    /// Joins `user` to the room the invite `token` is for, as if they'd
+    /// sent `ClientFrame::RedeemInvite`, returning the room. Fails with an
+    /// `InviteError` if the invite can't be used.
+    pub fn redeem_invite(&self, user: &str, token: &str) -> Result<String> {
+        let (room, joined) =
+            invite::redeem(&self.registry, &self.resources, user, token, unix_time())?;
+        if let Some(joined) = joined {
+            info!("{} joined {} by invite", user, room);
+            self.broadcast(&room_change_frame(
+                &room,
+                &joined.before,
+                joined.after,
+                user,
+            ))?;
+        }
+        Ok(room)
+    }
+
+    /// Returns recent activity per interval, server-wide and per room.
+    pub fn analytics(&self) -> AnalyticsReport {
+        self.analytics.report(unix_time())
+    }
+
+    /// Returns whether the server is ready for clients, and the health of
+    /// its background jobs.
+    pub fn readiness(&self) -> Readiness {
+        let draining = *self.draining.borrow();
+        Readiness {
+            ready: !draining && self.supervisor.healthy(),
+            draining,
+            jobs: self.supervisor.health(),
+        }
+    }
+
+    /// Stops the server accepting connections, tells everyone connected
+    /// and disconnects them, tenants included; `ChatServer::run` then
+    /// returns once they've gone. Returns how many connections were closed
+    /// here, not counting tenants'.
+    pub fn drain(&self) -> Result<usize> {
+        if self.draining.send_replace(true) {
+            return Ok(0);
+        }
+        info!("Draining");
+        self.notice("The server is shutting down")?;
+        Ok(close_all(&self.registry, &self.fanout))
+    }
+
+    /// Sends `text` to everyone connected as a `ServerFrame::Notice`.
+    pub fn notice(&self, text: &str) -> Result<()> {
+        let frame = ServerFrame::Notice {
+            text: text.to_string(),
+        };
+        info!("Sending notice: {}", text);
+        self.broadcast(&frame)
+    }
+
+    /// Adds the custom emoji `:name:`, or replaces its image, keeping
+    /// `image` in the blob store. Everyone is sent the new `EmojiList`.
+    pub async fn add_emoji(&self, name: &str, image: Bytes) -> Result<CustomEmoji> {
+        if !shortcode::is_valid_name(name) {
+            return Err(anyhow!("Invalid emoji name {:?}", name));
+        }
+        if image.is_empty() || image.len() > MAX_EMOJI_SIZE {
+            return Err(anyhow!(
+                "Emoji images must be 1 to {} bytes",
+                MAX_EMOJI_SIZE
+            ));
+        }
+        let blobs = self
+            .blobs
+            .as_ref()
+            .ok_or_else(|| anyhow!("No blob store to keep the image in"))?;
+        let blob_id = blob_id(&image);
+        blobs.put(&blob_id, image).await?;
+        self.registry.add_emoji(name, &blob_id);
+        info!("Added emoji :{}:", name);
+        self.broadcast_emoji()?;
+        Ok(CustomEmoji {
+            name: name.to_string(),
+            blob_id,
+        })
+    }
+
+    /// Removes a custom emoji, telling everyone; returns false if there
+    /// was none by that name. Its image stays in the blob store, where
+    /// files shared in chat may refer to it too.
+    pub fn remove_emoji(&self, name: &str) -> Result<bool> {
+        if !self.registry.remove_emoji(name) {
+            return Ok(false);
+        }
+        info!("Removed emoji :{}:", name);
+        self.broadcast_emoji()?;
+        Ok(true)
+    }
+
+    /// Returns the custom emoji, by name.
+    pub fn emoji(&self) -> Vec<CustomEmoji> {
+        self.registry.emoji()
+    }
+
+    fn broadcast_emoji(&self) -> Result<()> {
+        self.broadcast(&ServerFrame::EmojiList {
+            emoji: self.registry.emoji(),
+        })
+    }
+
+    /// Sends `frame` to everyone connected, translated into each
+    /// connection's locale if there are catalogs to translate with.
+    fn broadcast(&self, frame: &ServerFrame) -> Result<()> {
+        if self.catalogs.is_empty() {
+            let encoded = self.encode(frame, DEFAULT_LOCALE)?;
+            self.fanout.send(frame.priority(), Arc::new(encoded));
+            return Ok(());
+        }
+        let mut translations: HashMap<String, Encoded> = HashMap::new();
+        for (addr, locale) in self.registry.locales() {
+            let encoded = match translations.entry(locale) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let encoded = self.encode(frame, entry.key())?;
+                    entry.insert(encoded)
+                }
+            };
+            self.fanout.send_to(addr, frame.priority(), encoded);
+        }
+        Ok(())
+    }
+
+    /// Encodes `frame` translated into `locale`.
+    fn encode(&self, frame: &ServerFrame, locale: &str) -> Result<Encoded> {
+        Encoded::new(self.catalogs.localize(locale, frame.clone()))
+    }
+}
+
+/// Relayed messages, copied to every `Tail`.
+pub(crate) struct TailFeed(broadcast::Sender<ChatMessage>);
+
+impl Default for TailFeed {
+    fn default() -> Self {
+        TailFeed(broadcast::channel(TAIL_BUFFER).0)
+    }
+}
+
+impl TailFeed {
+    /// Copies `message` to any tails; free when there are none.
+    pub(crate) fn publish(&self, message: &ChatMessage) {
+        if self.0.receiver_count() > 0 {
+            let _ = self.0.send(message.clone());
+        }
+    }
+}
+
+/// Which relayed messages a tail follows, and what it hides from them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TailFilter {
+    pub room: Option<String>,
+    /// Only messages from this sender.
+    pub user: Option<String>,
+    /// A regular expression the content must match somewhere.
+    pub pattern: Option<String>,
+    /// Replaces content with its length.
+    pub redact_content: bool,
+    /// Replaces senders with pseudonyms that stay the same for the whole
+    /// tail, so conversations can still be followed.
+    pub redact_senders: bool,
+}
+
+/// Messages followed with `AdminControl::tail`.
+pub struct Tail {
+    messages: broadcast::Receiver<ChatMessage>,
+    filter: TailFilter,
+    pattern: Option<Regex>,
+    /// Mixed into pseudonyms so they can't be reversed by hashing names.
+    salt: String,
+}
+
+impl Tail {
+    /// Waits for the next message the filter selects, returning
+    /// `AdminResponse::Message`, or `AdminResponse::Lagged` if messages
+    /// were missed for not keeping up. `None` once the server has stopped.
+    pub async fn next(&mut self) -> Option<AdminResponse> {
+        loop {
+            match self.messages.recv().await {
+                Ok(message) => {
+                    if let Some(message) = self.apply(message) {
+                        return Some(AdminResponse::Message { message });
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    return Some(AdminResponse::Lagged { skipped });
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Filters and redacts one message.
+    fn apply(&self, mut message: ChatMessage) -> Option<ChatMessage> {
+        let filter = &self.filter;
+        if filter
+            .room
+            .as_deref()
+            .is_some_and(|room| room != message.room())
+            || filter
+                .user
+                .as_ref()
+                .is_some_and(|user| *user != message.sender)
+            || self
+                .pattern
+                .as_ref()
+                .is_some_and(|pattern| !pattern.is_match(&message.content))
+        {
+            return None;
+        }
+        if filter.redact_content {
+            message.content = format!("[{} bytes]", message.content.len());
+        }
+        if filter.redact_senders {
+            let hash = Sha256::digest(format!("{}:{}", self.salt, message.sender));
+            message.sender = format!("user-{:02x}{:02x}{:02x}", hash[0], hash[1], hash[2]);
+        }
+        Some(message)
+    }
+}
+
+/// A room's history being exported with `AdminControl::export`.
+pub struct Export {
+    store: Arc<dyn MessageStore>,
+    room: String,
+    format: ExportFormat,
+    since: Option<u64>,
+    until: Option<u64>,
+    /// The room's archive, when it's archived, instead of the store.
+    archived: Option<std::vec::IntoIter<ChatMessage>>,
+    /// Sequence number to read from next; `None` once the store is done.
+    next_seq: Option<u64>,
+    /// Whether the header has been written.
+    started: bool,
+}
+
+impl Export {
+    /// Returns the next chunk of the export, up to `EXPORT_PAGE` messages
+    /// in the requested format, or `None` once all of it has been returned.
+    pub async fn next(&mut self) -> Result<Option<String>> {
+        let mut data = Vec::new();
+        if !self.started {
+            self.started = true;
+            write_header(self.format, &mut data)?;
+        }
+        let page = self.next_page().await?;
+        if page.is_none() && data.is_empty() {
+            return Ok(None);
+        }
+        let messages: Vec<ChatMessage> = page
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|message| {
+                let timestamp = message.timestamp.unwrap_or_default();
+                self.since.is_none_or(|since| timestamp >= since)
+                    && self.until.is_none_or(|until| timestamp < until)
+            })
+            .collect();
+        write_rows(&messages, self.format, &mut data)?;
+        Ok(Some(String::from_utf8(data)?))
+    }
+
+    /// Reads the next page of messages, before filtering by time; `None`
+    /// when there are no more.
+    async fn next_page(&mut self) -> Result<Option<Vec<ChatMessage>>> {
+        if let Some(archived) = &mut self.archived {
+            let page: Vec<ChatMessage> = archived.by_ref().take(EXPORT_PAGE).collect();
+            return Ok((!page.is_empty()).then_some(page));
+        }
+        let Some(from_seq) = self.next_seq else {
+            return Ok(None);
+        };
+        let page = self
+            .store
+            .sequence(&self.room, from_seq, u64::MAX, EXPORT_PAGE)
+            .await?;
+        self.next_seq = match page.last().and_then(|message| message.seq) {
+            Some(last) if page.len() == EXPORT_PAGE => Some(last + 1),
+            _ => None,
+        };
+        Ok((!page.is_empty()).then_some(page))
+    }
+}
+
+/// Closes every connection once what's queued for it has been written.
+/// Returns how many were closed.
+pub(crate) fn close_all(registry: &Registry, fanout: &FanOut) -> usize {
+    registry
+        .connections()
+        .into_iter()
+        .filter(|(addr, _)| fanout.close(*addr))
+        .count()
+}
+
+/// A request on the admin socket, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminRequest {
+    Kick {
+        user: String,
+        reason: Option<String>,
+    },
+    Ban {
+        user: String,
+        reason: Option<String>,
+    },
+    Unban {
+        user: String,
+    },
+    Notice {
+        text: String,
+    },
+    Announce {
+        #[serde(default)]
+        room: Option<String>,
+        text: String,
+        #[serde(default)]
+        level: AnnouncementLevel,
+    },
+    Connections,
+    Rooms,
+    Stats,
+    Analytics,
+    Drain,
+    /// Adds a custom emoji; answered with `Emoji`.
+    AddEmoji {
+        name: String,
+        /// The image, base64-encoded.
+        image: String,
+    },
+    /// Answered with `EmojiRemoved`.
+    RemoveEmoji {
+        name: String,
+    },
+    /// Lists the custom emoji; answered with `Emoji`.
+    Emoji,
+    /// Streams relayed messages until the connection closes: `Done`, then
+    /// a `Message` or `Lagged` per line.
+    Tail {
+        #[serde(default)]
+        filter: TailFilter,
+    },
+    /// Exports a room's history, optionally only messages relayed within
+    /// `[since, until)` (Unix seconds): an `ExportChunk` per page, then
+    /// `Done`.
+    Export {
+        #[serde(default)]
+        room: Option<String>,
+        format: ExportFormat,
+        #[serde(default)]
+        since: Option<u64>,
+        #[serde(default)]
+        until: Option<u64>,
+    },
+}
+
+/// The reply to an `AdminRequest`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AdminResponse {
+    /// Connections closed by `Kick`, `Ban` or `Drain`.
+    Disconnected {
+        connections: usize,
+    },
+    /// Whether the user `Unban` named was banned.
+    Unbanned {
+        was_banned: bool,
+    },
+    /// Whether `Announce` went out rather than being held back.
+    Announced {
+        sent: bool,
+    },
+    Done,
+    Connections {
+        connections: Vec<ConnectionInfo>,
+    },
+    Rooms {
+        usage: ResourceUsage,
+    },
+    Stats {
+        metrics: MetricsSnapshot,
+    },
+    Analytics {
+        report: AnalyticsReport,
+    },
+    Emoji {
+        emoji: Vec<CustomEmoji>,
+    },
+    /// Whether the emoji `RemoveEmoji` named existed.
+    EmojiRemoved {
+        existed: bool,
+    },
+    /// A message followed by `Tail`.
+    Message {
+        message: ChatMessage,
+    },
+    /// `Tail` fell behind and missed this many messages.
+    Lagged {
+        skipped: u64,
+    },
+    /// Part of an `Export`, in the requested format.
+    ExportChunk {
+        data: String,
+    },
+    Error {
+        message: String,
+    },
+}
+
+impl AdminControl {
+    /// Carries out one admin socket request. `Tail` and `Export` only make
+    /// sense on the socket; use `AdminControl::tail` and
+    /// `AdminControl::export` instead.
+    pub async fn handle(&self, request: AdminRequest) -> AdminResponse {
+        match request {
+            AdminRequest::Kick { user, reason } => AdminResponse::Disconnected {
+                connections: self.kick(&user, reason.as_deref()),
+            },
+            AdminRequest::Ban { user, reason } => AdminResponse::Disconnected {
+                connections: self.ban(&user, reason.as_deref()),
+            },
+            AdminRequest::Unban { user } => AdminResponse::Unbanned {
+                was_banned: self.unban(&user),
+            },
+            AdminRequest::Notice { text } => match self.notice(&text) {
+                Ok(()) => AdminResponse::Done,
+                Err(e) => AdminResponse::Error {
+                    message: e.to_string(),
+                },
+            },
+            AdminRequest::Announce { room, text, level } => {
+                match self.announce(room.as_deref(), &text, level) {
+                    Ok(sent) => AdminResponse::Announced { sent },
+                    Err(e) => AdminResponse::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            AdminRequest::Connections => AdminResponse::Connections {
+                connections: self.connections(),
+            },
+            AdminRequest::Rooms => AdminResponse::Rooms {
+                usage: self.rooms(),
+            },
+            AdminRequest::Stats => AdminResponse::Stats {
+                metrics: self.stats(),
+            },
+            AdminRequest::Analytics => AdminResponse::Analytics {
+                report: self.analytics(),
+            },
+            AdminRequest::Drain => match self.drain() {
+                Ok(connections) => AdminResponse::Disconnected { connections },
+                Err(e) => AdminResponse::Error {
+                    message: e.to_string(),
+                },
+            },
+            AdminRequest::AddEmoji { name, image } => {
+                let added = match BASE64.decode(&image) {
+                    Ok(image) => self.add_emoji(&name, Bytes::from(image)).await,
+                    Err(e) => Err(anyhow!("Invalid image: {}", e)),
+                };
+                match added {
+                    Ok(_) => AdminResponse::Emoji {
+                        emoji: self.emoji(),
+                    },
+                    Err(e) => AdminResponse::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            AdminRequest::RemoveEmoji { name } => match self.remove_emoji(&name) {
+                Ok(existed) => AdminResponse::EmojiRemoved { existed },
+                Err(e) => AdminResponse::Error {
+                    message: e.to_string(),
+                },
+            },
+            AdminRequest::Emoji => AdminResponse::Emoji {
+                emoji: self.emoji(),
+            },
+            AdminRequest::Tail { .. } => AdminResponse::Error {
+                message: "Tail only works on the admin socket".to_string(),
+            },
+            AdminRequest::Export { .. } => AdminResponse::Error {
+                message: "Export only works on the admin socket".to_string(),
+            },
+        }
+    }
+}
+
+/// Serves admin socket connections on `listener` until an error occurs.
+/// Anyone who can open the socket has full control, so keep it somewhere
+/// only operators can reach.
+#[cfg(unix)]
+pub async fn serve_socket(listener: UnixListener, control: AdminControl) -> Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let control = control.clone();
+        control
+            .metrics
+            .tasks()
+            .spawn("admin", "admin connection", async move {
+                if let Err(e) = serve_admin_connection(stream, &control).await {
+                    warn!("Admin socket connection failed: {:?}", e);
+                }
+            });
+    }
+}
+
+/// Answers each request line on `stream` until it closes, streaming
+/// exports, or streams a tail if asked to.
+#[cfg(unix)]
+async fn serve_admin_connection(mut stream: UnixStream, control: &AdminControl) -> Result<()> {
+    let mut decoder = FrameDecoder::new(MAX_REQUEST_LEN);
+    let mut buffer = [0; 4096];
+    loop {
+        while let Some(line) = decoder.next_line()? {
+            let response = match serde_json::from_str::<AdminRequest>(&line) {
+                Ok(AdminRequest::Tail { filter }) => match control.tail(filter) {
+                    Ok(tail) => return stream_tail(stream, tail).await,
+                    Err(e) => AdminResponse::Error {
+                        message: format!("Invalid pattern: {}", e),
+                    },
+                },
+                Ok(AdminRequest::Export {
+                    room,
+                    format,
+                    since,
+                    until,
+                }) => {
+                    let room = room.as_deref().unwrap_or(DEFAULT_ROOM);
+                    match control.export(room, format, since, until).await {
+                        Ok(export) => match stream_export(&mut stream, export).await {
+                            Ok(()) => AdminResponse::Done,
+                            Err(e) => AdminResponse::Error {
+                                message: format!("Export failed: {}", e),
+                            },
+                        },
+                        Err(e) => AdminResponse::Error {
+                            message: e.to_string(),
+                        },
+                    }
+                }
+                Ok(request) => {
+                    debug!("Admin request: {:?}", request);
+                    control.handle(request).await
+                }
+                Err(e) => AdminResponse::Error {
+                    message: format!("Invalid request: {}", e),
+                },
+            };
+            write_response(&mut stream, &response).await?;
+        }
+        let n = stream.read(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        decoder.extend(&buffer[..n]);
+    }
+}
+
+/// Writes each chunk of `export` to `stream` as it's read.
+#[cfg(unix)]
+async fn stream_export(stream: &mut UnixStream, mut export: Export) -> Result<()> {
+    while let Some(data) = export.next().await? {
+        write_response(stream, &AdminResponse::ExportChunk { data }).await?;
+    }
+    Ok(())
+}
+
+/// Writes `tail` to `stream` until either end stops.
+#[cfg(unix)]
+async fn stream_tail(mut stream: UnixStream, mut tail: Tail) -> Result<()> {
+    write_response(&mut stream, &AdminResponse::Done).await?;
+    let (mut reader, mut writer) = stream.split();
+    let mut buffer = [0; 64];
+    loop {
+        tokio::select! {
+            response = tail.next() => match response {
+                Some(response) => write_response(&mut writer, &response).await?,
+                None => return Ok(()),
+            },
+            // Anything sent now is ignored; only closing matters.
+            read = reader.read(&mut buffer) => if read? == 0 {
+                debug!("Tail closed");
+                return Ok(());
+            },
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn write_response(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    response: &AdminResponse,
+) -> Result<()> {
+    let json = serde_json::to_string(response)?;
+    writer.write_all(format!("{}\n", json).as_bytes()).await?;
+    Ok(())
+}
+
+/// A connection to an admin socket.
+#[cfg(unix)]
+pub struct AdminConnection {
+    stream: UnixStream,
+    decoder: FrameDecoder,
+}
+
+#[cfg(unix)]
+impl AdminConnection {
+    pub async fn connect(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(AdminConnection {
+            stream: UnixStream::connect(path).await?,
+            decoder: FrameDecoder::new(MAX_RESPONSE_LEN),
+        })
+    }
+
+    pub async fn send(&mut self, request: &AdminRequest) -> Result<()> {
+        let json = serde_json::to_string(request)?;
+        self.stream
+            .write_all(format!("{}\n", json).as_bytes())
+            .await?;
+        Ok(())
+    }
+
+    /// Waits for the next response line; fails if the server hangs up.
+    pub async fn receive(&mut self) -> Result<AdminResponse> {
+        let mut buffer = [0; 4096];
+        loop {
+            if let Some(line) = self.decoder.next_line()? {
+                return Ok(serde_json::from_str(&line)?);
+            }
+            let n = self.stream.read(&mut buffer).await?;
+            if n == 0 {
+                return Err(anyhow!("Admin socket closed"));
+            }
+            self.decoder.extend(&buffer[..n]);
+        }
+    }
+}
+
+/// Sends one request to the admin socket at `path` and returns the reply.
+#[cfg(unix)]
+pub async fn request(path: impl AsRef<Path>, request: &AdminRequest) -> Result<AdminResponse> {
+    let mut connection = AdminConnection::connect(path).await?;
+    connection.send(request).await?;
+    connection.receive().await
+}
+
+/// Seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
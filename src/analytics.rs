@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How activity is bucketed and how much of it is kept.
+#[derive(Debug, Clone)]
+pub struct AnalyticsPolicy {
+    /// Width of each bucket.
+    pub interval: Duration,
+    /// Buckets kept, newest last; older ones are dropped.
+    pub retain: usize,
+}
+
+impl Default for AnalyticsPolicy {
+    fn default() -> Self {
+        AnalyticsPolicy {
+            interval: Duration::from_secs(60),
+            retain: 60,
+        }
+    }
+}
+
+/// Messages, senders and connections counted per room and per interval.
+#[derive(Debug, Default)]
+pub struct Analytics {
+    policy: AnalyticsPolicy,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    server: VecDeque<Bucket>,
+    rooms: HashMap<String, VecDeque<Bucket>>,
+    /// Connections open as of the last `record_connections`.
+    connections: usize,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    start: u64,
+    messages: u64,
+    senders: HashSet<String>,
+    peak_connections: usize,
+}
+
+/// Activity in one interval.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntervalActivity {
+    /// Unix time (seconds) the interval started.
+    pub start: u64,
+    pub messages: u64,
+    /// Distinct senders.
+    pub active_users: usize,
+    /// Most connections open at once; only counted server-wide.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub peak_connections: Option<usize>,
+}
+
+/// Recent activity in one room, oldest interval first.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomActivity {
+    pub room: String,
+    pub intervals: Vec<IntervalActivity>,
+}
+
+/// Everything `Analytics` has kept.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct AnalyticsReport {
+    pub interval_secs: u64,
+    /// The whole server, oldest interval first.
+    pub server: Vec<IntervalActivity>,
+    /// Rooms with activity in the kept intervals, by name.
+    pub rooms: Vec<RoomActivity>,
+}
+
+impl Analytics {
+    pub fn new(policy: AnalyticsPolicy) -> Self {
+        Analytics {
+            policy,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Counts a message relayed to `room` at Unix time `now`.
+    pub fn record_message(&self, room: &str, sender: &str, now: u64) {
+        let start = self.interval_start(now);
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        for buckets in [
+            &mut inner.server,
+            inner.rooms.entry(room.to_string()).or_default(),
+        ] {
+            let bucket = self.current(buckets, start, inner.connections);
+            bucket.messages += 1;
+            bucket.senders.insert(sender.to_string());
+        }
+    }
+
+    /// Notes that `connections` are open at Unix time `now`.
+    pub fn record_connections(&self, connections: usize, now: u64) {
+        let start = self.interval_start(now);
+        let mut inner = self.inner.lock().unwrap();
+        inner.connections = connections;
+        let bucket = self.current(&mut inner.server, start, connections);
+        bucket.peak_connections = bucket.peak_connections.max(connections);
+    }
+
+    /// Returns the kept intervals as of Unix time `now`.
+    pub fn report(&self, now: u64) -> AnalyticsReport {
+        let oldest = self.oldest_start(now);
+        let mut inner = self.inner.lock().unwrap();
+        inner.rooms.retain(|_, buckets| {
+            buckets.retain(|bucket| bucket.start >= oldest);
+            !buckets.is_empty()
+        });
+        let intervals = |buckets: &VecDeque<Bucket>, server: bool| {
+            buckets
+                .iter()
+                .filter(|bucket| bucket.start >= oldest)
+                .map(|bucket| IntervalActivity {
+                    start: bucket.start,
+                    messages: bucket.messages,
+                    active_users: bucket.senders.len(),
+                    peak_connections: server.then_some(bucket.peak_connections),
+                })
+                .collect()
+        };
+        let mut rooms: Vec<RoomActivity> = inner
+            .rooms
+            .iter()
+            .map(|(room, buckets)| RoomActivity {
+                room: room.clone(),
+                intervals: intervals(buckets, false),
+            })
+            .collect();
+        rooms.sort_by(|a, b| a.room.cmp(&b.room));
+        AnalyticsReport {
+            interval_secs: self.interval_secs(),
+            server: intervals(&inner.server, true),
+            rooms,
+        }
+    }
+
+    /// Counts the messages relayed to `room` in the kept intervals as of
+    /// Unix time `now`.
+    pub fn recent_messages(&self, room: &str, now: u64) -> u64 {
+        let oldest = self.oldest_start(now);
+        let inner = self.inner.lock().unwrap();
+        inner.rooms.get(room).map_or(0, |buckets| {
+            buckets
+                .iter()
+                .filter(|bucket| bucket.start >= oldest)
+                .map(|bucket| bucket.messages)
+                .sum()
+        })
+    }
+
+    /// When the oldest interval kept as of `now` started.
+    fn oldest_start(&self, now: u64) -> u64 {
+        self.interval_start(now)
+            .saturating_sub(self.interval_secs() * (self.policy.retain.max(1) as u64 - 1))
+    }
+
+    fn interval_secs(&self) -> u64 {
+        self.policy.interval.as_secs().max(1)
+    }
+
+    fn interval_start(&self, now: u64) -> u64 {
+        now - now % self.interval_secs()
+    }
+
+    /// The bucket for the interval starting at `start`, adding it (and
+    /// dropping the oldest past `retain`) if it's new. New buckets start
+    /// with the `connections` still open.
+    fn current<'a>(
+        &self,
+        buckets: &'a mut VecDeque<Bucket>,
+        start: u64,
+        connections: usize,
+    ) -> &'a mut Bucket {
+        if buckets.back().is_none_or(|bucket| bucket.start < start) {
+            buckets.push_back(Bucket {
+                start,
+                messages: 0,
+                senders: HashSet::new(),
+                peak_connections: connections,
+            });
+            while buckets.len() > self.policy.retain.max(1) {
+                buckets.pop_front();
+            }
+        }
+        buckets.back_mut().expect("a bucket was just added")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_activity_is_bucketed_per_interval() {
+        let analytics = Analytics::new(AnalyticsPolicy {
+            interval: Duration::from_secs(60),
+            retain: 2,
+        });
+        analytics.record_connections(3, 0);
+        analytics.record_message("lobby", "avery", 10);
+        analytics.record_message("lobby", "avery", 20);
+        analytics.record_message("dev", "blake", 30);
+        analytics.record_connections(5, 50);
+        analytics.record_connections(4, 59);
+        analytics.record_message("lobby", "blake", 70);
+
+        let report = analytics.report(70);
+        assert_eq!(report.interval_secs, 60);
+        assert_eq!(
+            report.server,
+            vec![
+                IntervalActivity {
+                    start: 0,
+                    messages: 3,
+                    active_users: 2,
+                    peak_connections: Some(5),
+                },
+                IntervalActivity {
+                    start: 60,
+                    messages: 1,
+                    active_users: 1,
+                    peak_connections: Some(4),
+                },
+            ]
+        );
+        let lobby = &report.rooms[1];
+        assert_eq!(lobby.room, "lobby");
+        assert_eq!(lobby.intervals[0].messages, 2);
+        assert_eq!(lobby.intervals[0].active_users, 1);
+
+        assert_eq!(analytics.recent_messages("lobby", 70), 3);
+        assert_eq!(analytics.recent_messages("general", 70), 0);
+
+        // Two intervals later only the "lobby" message at 70 is kept.
+        assert_eq!(analytics.recent_messages("lobby", 130), 1);
+        let report = analytics.report(130);
+        assert_eq!(report.rooms.len(), 1);
+        assert_eq!(report.server.len(), 1);
+    }
+}
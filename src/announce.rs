@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// How often announcements may go out.
+#[derive(Debug, Clone)]
+pub struct AnnouncePolicy {
+    /// An announcement repeated within this long of being sent is held back
+    /// and counted instead.
+    pub window: Duration,
+    /// Most announcements sent per `window`, whatever they say; later ones
+    /// are held back the same way.
+    pub max_per_window: usize,
+}
+
+impl Default for AnnouncePolicy {
+    fn default() -> Self {
+        AnnouncePolicy {
+            window: Duration::from_secs(30),
+            max_per_window: 10,
+        }
+    }
+}
+
+/// Coalesces repeated announcements and limits their rate, so a noisy
+/// embedder or operator script can't flood clients.
+#[derive(Debug, Default)]
+pub struct Announcer {
+    policy: AnnouncePolicy,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// By target room (`None` for everyone) and text.
+    recent: HashMap<(Option<String>, String), Recent>,
+    window_start: u64,
+    sent_in_window: usize,
+}
+
+#[derive(Debug, Default)]
+struct Recent {
+    last_sent: Option<u64>,
+    /// Held back since `last_sent`.
+    held: u32,
+}
+
+impl Announcer {
+    pub fn new(policy: AnnouncePolicy) -> Self {
+        Announcer {
+            policy,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Decides whether an announcement made at Unix time `now` goes out.
+    /// Returns how many identical ones were held back since it was last
+    /// sent, or `None` if this one is held back too.
+    pub fn admit(&self, room: Option<&str>, text: &str, now: u64) -> Option<u32> {
+        let window = self.policy.window.as_secs().max(1);
+        let mut state = self.state.lock().unwrap();
+        if now >= state.window_start + window {
+            state.window_start = now;
+            state.sent_in_window = 0;
+            state.recent.retain(|_, recent| {
+                recent.held > 0 || recent.last_sent.is_some_and(|last| now < last + window)
+            });
+        }
+        let full = state.sent_in_window >= self.policy.max_per_window;
+        let recent = state
+            .recent
+            .entry((room.map(str::to_string), text.to_string()))
+            .or_default();
+        if full || recent.last_sent.is_some_and(|last| now < last + window) {
+            recent.held += 1;
+            return None;
+        }
+        recent.last_sent = Some(now);
+        let held = std::mem::take(&mut recent.held);
+        state.sent_in_window += 1;
+        Some(held)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeats_are_coalesced_and_rate_limited() {
+        let announcer = Announcer::new(AnnouncePolicy {
+            window: Duration::from_secs(10),
+            max_per_window: 2,
+        });
+        assert_eq!(announcer.admit(None, "restarting", 0), Some(0));
+        assert_eq!(announcer.admit(None, "restarting", 1), None);
+        assert_eq!(announcer.admit(None, "restarting", 2), None);
+        // Same text in a room is a different announcement.
+        assert_eq!(announcer.admit(Some("dev"), "restarting", 3), Some(0));
+        // Two sent this window already.
+        assert_eq!(announcer.admit(None, "other", 4), None);
+
+        assert_eq!(announcer.admit(None, "restarting", 10), Some(2));
+        assert_eq!(announcer.admit(None, "other", 11), Some(1));
+    }
+}
@@ -0,0 +1,207 @@
+use crate::auth::generate_token;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// What a client signed in with an API key may do.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct KeyScope {
+    /// Rooms the key may post in; any room when unset.
+    pub rooms: Option<BTreeSet<String>>,
+    /// Whether the key may only receive, sending no messages or commands.
+    pub read_only: bool,
+    /// Whether the key may run room commands such as `/create` and
+    /// `/topic`, still subject to room roles. Other keys can only post.
+    pub admin: bool,
+}
+
+impl KeyScope {
+    /// Whether the key may post in `room`.
+    pub fn allows_room(&self, room: &str) -> bool {
+        self.rooms.as_ref().is_none_or(|rooms| rooms.contains(room))
+    }
+}
+
+/// An API key's details. The secret half is never kept.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApiKeyInfo {
+    /// The public half of the key, used to rotate or revoke it.
+    pub id: String,
+    /// The service account or bot the key signs in as.
+    pub account: String,
+    pub scope: KeyScope,
+    /// When the key was issued, in Unix seconds.
+    pub created_at: u64,
+    /// When a rotated-out key stops working, in Unix seconds.
+    pub expires_at: Option<u64>,
+}
+
+/// A key as saved in registry snapshots.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StoredApiKey {
+    #[serde(flatten)]
+    pub info: ApiKeyInfo,
+    /// Hex-encoded SHA-256 of the secret half.
+    pub secret_hash: String,
+}
+
+/// A newly issued key. `key` is what clients send in `ClientFrame::ApiKey`,
+/// and can't be recovered later.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct IssuedApiKey {
+    pub key: String,
+    pub info: ApiKeyInfo,
+}
+
+/// API keys for service accounts and bots, kept apart from nickname
+/// passwords. A key is `<id>.<secret>`; only a hash of the secret is kept.
+/// Secrets are random 128-bit tokens, so a plain SHA-256 is enough.
+#[derive(Debug, Default)]
+pub struct ApiKeys {
+    keys: Mutex<HashMap<String, StoredApiKey>>,
+}
+
+impl ApiKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Issues a key that signs in as `account` with `scope`.
+    pub fn issue(&self, account: impl Into<String>, scope: KeyScope, now: u64) -> IssuedApiKey {
+        let info = ApiKeyInfo {
+            id: generate_token(),
+            account: account.into(),
+            scope,
+            created_at: now,
+            expires_at: None,
+        };
+        let secret = generate_token();
+        self.keys.lock().unwrap().insert(
+            info.id.clone(),
+            StoredApiKey {
+                info: info.clone(),
+                secret_hash: hash_secret(&secret),
+            },
+        );
+        IssuedApiKey {
+            key: format!("{}.{}", info.id, secret),
+            info,
+        }
+    }
+
+    /// Returns the details of `key` if it is valid at `now`.
+    pub fn verify(&self, key: &str, now: u64) -> Option<ApiKeyInfo> {
+        let (id, secret) = key.split_once('.')?;
+        let mut keys = self.keys.lock().unwrap();
+        prune(&mut keys, now);
+        let stored = keys.get(id)?;
+        (stored.secret_hash == hash_secret(secret)).then(|| stored.info.clone())
+    }
+
+    /// Whether the key `id` still works at `now`, i.e. hasn't been revoked
+    /// or rotated out.
+    pub fn is_active(&self, id: &str, now: u64) -> bool {
+        self.keys
+            .lock()
+            .unwrap()
+            .get(id)
+            .is_some_and(|stored| is_live(stored, now))
+    }
+
+    /// Issues a replacement for the key `id` with the same account and
+    /// scope. The old key keeps working for `grace`, so clients can switch
+    /// over. Returns `None` if there's no such key.
+    pub fn rotate(&self, id: &str, grace: Duration, now: u64) -> Option<IssuedApiKey> {
+        let (account, scope) = {
+            let mut keys = self.keys.lock().unwrap();
+            prune(&mut keys, now);
+            let stored = keys.get_mut(id)?;
+            let expires_at = now + grace.as_secs();
+            stored.info.expires_at = Some(
+                stored
+                    .info
+                    .expires_at
+                    .map_or(expires_at, |at| at.min(expires_at)),
+            );
+            (stored.info.account.clone(), stored.info.scope.clone())
+        };
+        Some(self.issue(account, scope, now))
+    }
+
+    /// Revokes the key `id` at once. Returns whether it existed.
+    pub fn revoke(&self, id: &str) -> bool {
+        self.keys.lock().unwrap().remove(id).is_some()
+    }
+
+    /// Returns every key, by account.
+    pub fn list(&self) -> Vec<ApiKeyInfo> {
+        let mut keys: Vec<ApiKeyInfo> = self
+            .keys
+            .lock()
+            .unwrap()
+            .values()
+            .map(|stored| stored.info.clone())
+            .collect();
+        keys.sort_by(|a, b| (&a.account, a.created_at).cmp(&(&b.account, b.created_at)));
+        keys
+    }
+
+    /// Copies every key for a snapshot.
+    pub fn snapshot(&self) -> Vec<StoredApiKey> {
+        self.keys.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Loads keys saved by `snapshot`, keeping any issued since startup.
+    pub fn restore(&self, keys: Vec<StoredApiKey>) {
+        self.keys.lock().unwrap().extend(
+            keys.into_iter()
+                .map(|stored| (stored.info.id.clone(), stored)),
+        );
+    }
+}
+
+fn is_live(stored: &StoredApiKey, now: u64) -> bool {
+    stored.info.expires_at.is_none_or(|at| at > now)
+}
+
+/// Drops keys that have been rotated out.
+fn prune(keys: &mut HashMap<String, StoredApiKey>, now: u64) {
+    keys.retain(|_, stored| is_live(stored, now));
+}
+
+fn hash_secret(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_issue_rotate_and_revoke() {
+        let keys = ApiKeys::new();
+        let issued = keys.issue("deploy-bot", KeyScope::default(), 100);
+        assert_eq!(keys.verify(&issued.key, 100), Some(issued.info.clone()));
+        assert_eq!(keys.verify(&format!("{}.wrong", issued.info.id), 100), None);
+        assert_eq!(keys.verify("garbage", 100), None);
+
+        let rotated = keys
+            .rotate(&issued.info.id, Duration::from_secs(60), 200)
+            .unwrap();
+        assert_eq!(rotated.info.account, "deploy-bot");
+        assert!(keys.verify(&issued.key, 259).is_some());
+        assert!(keys.verify(&issued.key, 260).is_none());
+        assert!(!keys.is_active(&issued.info.id, 260));
+        assert_eq!(keys.list(), vec![rotated.info.clone()]);
+
+        assert!(keys.revoke(&rotated.info.id));
+        assert!(keys.verify(&rotated.key, 260).is_none());
+        assert!(!keys.revoke(&rotated.info.id));
+    }
+}
@@ -0,0 +1,231 @@
+use crate::export::{ExportFormat, write_messages};
+use crate::protocol::ChatMessage;
+use crate::store::MessageStore;
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::collections::HashMap;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// When idle rooms are archived.
+#[derive(Debug, Clone)]
+pub struct ArchivePolicy {
+    /// Rooms with no messages for this long are archived.
+    pub idle: Duration,
+    /// How often rooms are checked.
+    pub interval: Duration,
+}
+
+impl Default for ArchivePolicy {
+    fn default() -> Self {
+        ArchivePolicy {
+            idle: Duration::from_secs(30 * 24 * 60 * 60),
+            interval: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Where archived rooms' history is kept, one gzipped JSONL export per room.
+#[async_trait]
+pub trait ColdStore: Send + Sync {
+    /// Stores a room's archive, overwriting any earlier one.
+    async fn put(&self, room: &str, data: Bytes) -> Result<()>;
+
+    /// Fetches a room's archive, if it has one.
+    async fn get(&self, room: &str) -> Result<Option<Bytes>>;
+
+    /// Deletes a room's archive once it's been restored.
+    async fn remove(&self, room: &str) -> Result<()>;
+}
+
+/// Keeps archives in memory; mostly useful for tests.
+#[derive(Default)]
+pub struct MemoryColdStore {
+    archives: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MemoryColdStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ColdStore for MemoryColdStore {
+    async fn put(&self, room: &str, data: Bytes) -> Result<()> {
+        self.archives.lock().unwrap().insert(room.to_string(), data);
+        Ok(())
+    }
+
+    async fn get(&self, room: &str) -> Result<Option<Bytes>> {
+        Ok(self.archives.lock().unwrap().get(room).cloned())
+    }
+
+    async fn remove(&self, room: &str) -> Result<()> {
+        self.archives.lock().unwrap().remove(room);
+        Ok(())
+    }
+}
+
+/// Stores each archive as a file inside a directory, named by the hex of
+/// the room name so any name is a safe file name.
+pub struct DirColdStore {
+    root: PathBuf,
+}
+
+impl DirColdStore {
+    /// Uses `root` for archive files, creating it if needed.
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(DirColdStore { root })
+    }
+
+    fn path(&self, room: &str) -> PathBuf {
+        let name: String = room.bytes().map(|byte| format!("{:02x}", byte)).collect();
+        self.root.join(format!("{}.jsonl.gz", name))
+    }
+}
+
+#[async_trait]
+impl ColdStore for DirColdStore {
+    async fn put(&self, room: &str, data: Bytes) -> Result<()> {
+        tokio::fs::write(self.path(room), &data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, room: &str) -> Result<Option<Bytes>> {
+        match tokio::fs::read(self.path(room)).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn remove(&self, room: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(room)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Moves a room's history from `store` to `cold`, returning how many
+/// messages were moved. The archive is written before anything is deleted.
+pub async fn archive_room(
+    store: &dyn MessageStore,
+    cold: &dyn ColdStore,
+    room: &str,
+) -> Result<u64> {
+    let messages = store.range(room, None, None).await?;
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    write_messages(&messages, ExportFormat::Jsonl, &mut encoder)?;
+    cold.put(room, Bytes::from(encoder.finish()?)).await?;
+    for message in &messages {
+        if let Some(id) = message.id {
+            store.remove(room, id).await?;
+        }
+    }
+    Ok(messages.len() as u64)
+}
+
+/// Moves a room's archived history from `cold` back into `store`, returning
+/// how many messages were restored; none if the room has no archive.
+///
+/// The history is appended all at once, and the archive only deleted once
+/// it has been, so a failed restore leaves the room archived and can be
+/// retried.
+pub async fn restore_room(
+    store: &dyn MessageStore,
+    cold: &dyn ColdStore,
+    room: &str,
+) -> Result<u64> {
+    let messages = read_archive(cold, room).await?;
+    let restored = store.append_all(&messages).await?;
+    cold.remove(room).await?;
+    Ok(restored)
+}
+
+/// Returns the messages in a room's archive, oldest first, leaving it in
+/// place; none if the room has no archive.
+pub async fn read_archive(cold: &dyn ColdStore, room: &str) -> Result<Vec<ChatMessage>> {
+    let Some(data) = cold.get(room).await? else {
+        return Ok(Vec::new());
+    };
+    let mut jsonl = String::new();
+    GzDecoder::new(data.as_ref()).read_to_string(&mut jsonl)?;
+    jsonl
+        .lines()
+        .map(|line| Ok(serde_json::from_str(line)?))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+
+    fn message(id: u64, room: &str) -> ChatMessage {
+        ChatMessage {
+            sender: "avery".to_string(),
+            content: format!("message {}", id),
+            id: Some(id),
+            room: Some(room.to_string()),
+            timestamp: Some(id),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn archive_and_restore_round_trip() {
+        let store = MemoryStore::default();
+        for id in 1..=3 {
+            store.append(&message(id, "old")).await.unwrap();
+        }
+        store.append(&message(4, "busy")).await.unwrap();
+        let cold = MemoryColdStore::new();
+
+        assert_eq!(archive_room(&store, &cold, "old").await.unwrap(), 3);
+        assert!(store.range("old", None, None).await.unwrap().is_empty());
+        assert_eq!(store.range("busy", None, None).await.unwrap().len(), 1);
+        assert!(cold.get("old").await.unwrap().is_some());
+
+        assert_eq!(restore_room(&store, &cold, "old").await.unwrap(), 3);
+        let restored = store.range("old", None, None).await.unwrap();
+        assert_eq!(
+            restored,
+            (1..=3).map(|id| message(id, "old")).collect::<Vec<_>>()
+        );
+        assert!(cold.get("old").await.unwrap().is_none());
+        assert_eq!(restore_room(&store, &cold, "old").await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn interrupted_restore_can_be_retried() {
+        let store = MemoryStore::default();
+        for id in 1..=3 {
+            store.append(&message(id, "old")).await.unwrap();
+        }
+        let cold = MemoryColdStore::new();
+        archive_room(&store, &cold, "old").await.unwrap();
+        // A restore that stopped after the first message.
+        store.append(&message(1, "old")).await.unwrap();
+
+        assert_eq!(restore_room(&store, &cold, "old").await.unwrap(), 2);
+        let ids: Vec<_> = store
+            .range("old", None, None)
+            .await
+            .unwrap()
+            .iter()
+            .map(|message| message.id.unwrap())
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert!(cold.get("old").await.unwrap().is_none());
+    }
+}
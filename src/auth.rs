@@ -0,0 +1,156 @@
+use crate::quota::QuotaPolicy;
+use crate::room::Role;
+use anyhow::{Result, anyhow};
+use argon2::Argon2;
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use subtle::ConstantTimeEq;
+
+/// Guest nicknames start with this, so it can't be registered.
+pub const GUEST_PREFIX: &str = "guest-";
+
+/// Checks the credentials sent in `ClientFrame::Authenticate`.
+#[async_trait]
+pub trait Authenticator: Send + Sync {
+    /// Returns whether `token` proves the client is `user`.
+    async fn authenticate(&self, user: &str, token: &str) -> Result<bool>;
+
+    /// Returns who `token` proves the client is, or `None` if it doesn't.
+    /// Override it when the nickname or room roles come from the
+    /// credentials, e.g. a token's claims or directory groups; by default
+    /// it's `user` with no roles, if `authenticate` accepts.
+    async fn sign_in(&self, user: &str, token: &str) -> Result<Option<SignIn>> {
+        Ok(self.authenticate(user, token).await?.then(|| SignIn {
+            user: user.to_string(),
+            grants: Vec::new(),
+        }))
+    }
+}
+
+/// Who a client signed in as, from `Authenticator::sign_in`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignIn {
+    /// The nickname they post as.
+    pub user: String,
+    /// Room roles granted on top of any they already have.
+    pub grants: Vec<RoomGrant>,
+}
+
+/// A role in a room, granted on sign-in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoomGrant {
+    pub room: String,
+    pub role: Role,
+}
+
+/// Maps identity-provider or directory groups to room roles, for
+/// authenticators backed by SSO or LDAP. Groups are matched exactly.
+#[derive(Debug, Clone, Default)]
+pub struct RoleMap {
+    groups: HashMap<String, Vec<RoomGrant>>,
+}
+
+impl RoleMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Grants members of `group` `role` in `room`.
+    pub fn grant(mut self, group: impl Into<String>, room: impl Into<String>, role: Role) -> Self {
+        self.groups
+            .entry(group.into())
+            .or_default()
+            .push(RoomGrant {
+                room: room.into(),
+                role,
+            });
+        self
+    }
+
+    /// Returns the grants for someone in `groups`.
+    pub fn grants<'a>(&self, groups: impl IntoIterator<Item = &'a str>) -> Vec<RoomGrant> {
+        groups
+            .into_iter()
+            .filter_map(|group| self.groups.get(group))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+/// A fixed table of user tokens, e.g. loaded from configuration. Tokens
+/// are compared in constant time.
+#[derive(Debug, Clone, Default)]
+pub struct StaticTokens {
+    tokens: HashMap<String, String>,
+}
+
+impl StaticTokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces `user`'s token.
+    pub fn with_user(mut self, user: impl Into<String>, token: impl Into<String>) -> Self {
+        self.tokens.insert(user.into(), token.into());
+        self
+    }
+}
+
+#[async_trait]
+impl Authenticator for StaticTokens {
+    async fn authenticate(&self, user: &str, token: &str) -> Result<bool> {
+        Ok(self
+            .tokens
+            .get(user)
+            .is_some_and(|expected| bool::from(expected.as_bytes().ct_eq(token.as_bytes()))))
+    }
+}
+
+/// Settings for unauthenticated guests. Guests get a generated nickname,
+/// may only post in rooms that already exist, and are held to `quota` on
+/// top of the server-wide quotas.
+#[derive(Debug, Clone)]
+pub struct GuestPolicy {
+    pub quota: QuotaPolicy,
+}
+
+impl Default for GuestPolicy {
+    fn default() -> Self {
+        GuestPolicy {
+            quota: QuotaPolicy {
+                messages_per_hour: Some(60),
+                bytes_per_hour: Some(64 * 1024),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Hashes a nickname password into a PHC string for storage. Slow on
+/// purpose; call it off the async runtime.
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| anyhow!("Password hashing failed: {}", e))?
+        .to_string())
+}
+
+/// Checks `password` against a hash from `hash_password`.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Returns a random 128-bit token, hex-encoded.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 16];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
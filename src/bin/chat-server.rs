@@ -0,0 +1,940 @@
+use anyhow::Result;
+#[cfg(unix)]
+use base64::Engine;
+#[cfg(unix)]
+use base64::engine::general_purpose::STANDARD as BASE64;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::io::Write;
+use tokio_chat_server::ChatServer;
+#[cfg(unix)]
+use tokio_chat_server::admin::{self, AdminConnection, AdminRequest, AdminResponse, TailFilter};
+use tokio_chat_server::archive::{ArchivePolicy, DirColdStore};
+use tokio_chat_server::client::Client;
+use tokio_chat_server::config::Config;
+use tokio_chat_server::conformance::{self, ConformanceOptions};
+#[cfg(unix)]
+use tokio_chat_server::export::ExportFormat;
+use tokio_chat_server::i18n::Catalogs;
+use tokio_chat_server::preview::{HttpFetcher, PreviewPolicy};
+use tokio_chat_server::protocol::{AnnouncementLevel, PushPlatform};
+use tokio_chat_server::push::{PushGateway, RelayProvider};
+use tokio_chat_server::recording::{self, PlaybackOptions};
+use tokio_chat_server::replay::{self, ReplayOptions};
+use tokio_chat_server::sanitize::{SanitizePolicy, Strictness};
+use tokio_chat_server::store::{self, MessageStore};
+use tokio_chat_server::transport::Listener;
+use tokio_chat_server::wal::Wal;
+use tracing::info;
+
+#[derive(Parser)]
+#[command(name = "chat-server", about = "Tokio chat server and operator tools")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+// Parsed once at startup, so `Serve`'s many flags cost nothing to keep inline.
+#[allow(clippy::large_enum_variant)]
+enum Command {
+    /// Runs the chat server.
+    Serve {
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+        /// Accepts, reads and writes client sockets through io_uring, relayed
+        /// to the handlers through in-memory pipes; no faster than the default.
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        #[arg(long)]
+        io_uring: bool,
+        /// Serves TLS with the `<hostname>.crt` and `<hostname>.key` files
+        /// in this directory, chosen by the hostname clients ask for and
+        /// reloaded as they change.
+        #[cfg(feature = "tls")]
+        #[arg(long)]
+        tls_cert_dir: Option<std::path::PathBuf>,
+        /// The hostname whose certificate clients that don't send SNI get.
+        #[cfg(feature = "tls")]
+        #[arg(long, requires = "tls_cert_dir")]
+        tls_default_host: Option<String>,
+        /// Issues and renews this domain's certificate in the TLS
+        /// certificate directory through ACME. Repeatable.
+        #[cfg(feature = "acme")]
+        #[arg(long, requires = "tls_cert_dir")]
+        acme_domain: Vec<String>,
+        /// Where the ACME CA can reach the operator, e.g.
+        /// `mailto:ops@example.com`.
+        #[cfg(feature = "acme")]
+        #[arg(long, requires = "acme_domain")]
+        acme_contact: Vec<String>,
+        /// The ACME CA's directory; Let's Encrypt's by default.
+        #[cfg(feature = "acme")]
+        #[arg(long, requires = "acme_domain")]
+        acme_directory: Option<String>,
+        /// Reads default rooms, onboarding and tenants from this JSON file.
+        #[arg(long)]
+        config: Option<std::path::PathBuf>,
+        /// Translates server-generated text with the `<locale>.json`
+        /// catalogs in this directory.
+        #[arg(long)]
+        catalogs: Option<std::path::PathBuf>,
+        /// What's stripped from messages and profiles before they're relayed.
+        #[arg(long, value_enum, default_value_t = Sanitize::Standard)]
+        sanitize: Sanitize,
+        /// Follows messages with previews of the http:// pages they link to.
+        #[arg(long)]
+        link_previews: bool,
+        /// Pushes notifications for FCM, APNs and Web Push devices through
+        /// the push relay at this http:// URL.
+        #[arg(long)]
+        push_relay: Option<String>,
+        /// Emails digests of missed mentions and direct messages to users
+        /// who opted in, through the SMTP relay at this host:port.
+        #[cfg(feature = "email")]
+        #[arg(long, requires = "mail_from")]
+        smtp: Option<String>,
+        /// The address digests are sent from.
+        #[cfg(feature = "email")]
+        #[arg(long)]
+        mail_from: Option<String>,
+        /// Logs accepted messages to this write-ahead log before broadcast.
+        #[arg(long)]
+        wal: Option<std::path::PathBuf>,
+        /// Saves rooms, bans and read state to this file periodically and
+        /// on drain, and restores them on start.
+        #[arg(long)]
+        snapshot: Option<std::path::PathBuf>,
+        /// Seconds between snapshots.
+        #[arg(long, default_value_t = 60)]
+        snapshot_interval: u64,
+        /// Records every connection's frames, secrets redacted, to a file
+        /// per connection in this directory, for `play-recording`.
+        #[arg(long)]
+        record_dir: Option<std::path::PathBuf>,
+        /// Persists history in this SQLite database.
+        #[cfg(feature = "sqlite")]
+        #[arg(long)]
+        sqlite: Option<std::path::PathBuf>,
+        /// Persists history in Postgres (libpq-style connection string).
+        #[cfg(feature = "postgres")]
+        #[arg(long)]
+        postgres: Option<String>,
+        /// Archives idle rooms' history as compressed files in this directory.
+        #[arg(long, group = "archive")]
+        archive_dir: Option<std::path::PathBuf>,
+        /// Archives idle rooms' history in the S3 bucket instead, under
+        /// `<s3-prefix>archives/`.
+        #[cfg(feature = "s3")]
+        #[arg(long, group = "archive", requires = "s3_bucket")]
+        archive_s3: bool,
+        /// Days without messages before a room is archived.
+        #[arg(long, default_value_t = 30, requires = "archive")]
+        archive_after_days: u64,
+        /// Keeps uploaded files in this S3 bucket, under `<s3-prefix>blobs/`,
+        /// signing in with the standard `AWS_*` environment variables.
+        #[cfg(feature = "s3")]
+        #[arg(long)]
+        s3_bucket: Option<String>,
+        /// Prepended to the keys of the server's objects in the S3 bucket.
+        #[cfg(feature = "s3")]
+        #[arg(long, default_value = "", requires = "s3_bucket")]
+        s3_prefix: String,
+        /// Signs clients in with access tokens from this OpenID Connect
+        /// issuer, nicknamed by their `preferred_username` claim.
+        #[cfg(feature = "oidc")]
+        #[arg(long)]
+        oidc_issuer: Option<String>,
+        /// An `aud` claim the issuer's tokens must carry; repeatable.
+        #[cfg(feature = "oidc")]
+        #[arg(long, requires = "oidc_issuer")]
+        oidc_audience: Vec<String>,
+        /// Signs clients in with their password on this LDAP or Active
+        /// Directory server, e.g. `ldaps://ldap.example.com`.
+        #[cfg(feature = "ldap")]
+        #[arg(long, requires = "ldap_base_dn")]
+        ldap_url: Option<String>,
+        /// Where users are searched for.
+        #[cfg(feature = "ldap")]
+        #[arg(long, requires = "ldap_url")]
+        ldap_base_dn: Option<String>,
+        /// Matches a user's entry, with `{user}` for their nickname.
+        #[cfg(feature = "ldap")]
+        #[arg(long, requires = "ldap_url", default_value = "(uid={user})")]
+        ldap_user_filter: String,
+        /// The account users are looked up as; anonymous if unset.
+        #[cfg(feature = "ldap")]
+        #[arg(long, requires = "ldap_url")]
+        ldap_bind_dn: Option<String>,
+        /// The lookup account's password.
+        #[cfg(feature = "ldap")]
+        #[arg(long, requires = "ldap_bind_dn", default_value = "")]
+        ldap_bind_password: String,
+        /// Admits or refuses clients by country and ASN as this JSON file
+        /// of rules says, reloading it and the databases as they change.
+        #[cfg(feature = "geoip")]
+        #[arg(long)]
+        geoip_rules: Option<std::path::PathBuf>,
+        /// A MaxMind Country or City database for `--geoip-rules`.
+        #[cfg(feature = "geoip")]
+        #[arg(long, requires = "geoip_rules")]
+        geoip_country_db: Option<std::path::PathBuf>,
+        /// A MaxMind ASN database for `--geoip-rules`.
+        #[cfg(feature = "geoip")]
+        #[arg(long, requires = "geoip_rules")]
+        geoip_asn_db: Option<std::path::PathBuf>,
+        /// Serves the admin dashboard and routes on this address.
+        #[cfg(feature = "http")]
+        #[arg(long, requires = "admin_token")]
+        admin_addr: Option<String>,
+        /// Bearer token the admin routes require.
+        #[cfg(feature = "http")]
+        #[arg(long, requires = "admin_addr")]
+        admin_token: Option<String>,
+        /// Serves `chat-server admin` commands on a Unix socket at this path.
+        #[cfg(unix)]
+        #[arg(long)]
+        admin_socket: Option<std::path::PathBuf>,
+    },
+    /// Dumps a room's history from a running server, through its admin
+    /// socket.
+    #[cfg(unix)]
+    Export {
+        /// The `--admin-socket` the server was started with.
+        #[arg(long, default_value = "chat-server.sock")]
+        socket: std::path::PathBuf,
+        #[arg(long)]
+        room: Option<String>,
+        #[arg(long, value_enum, default_value_t = Format::Jsonl)]
+        format: Format,
+        /// Only messages relayed at or after this Unix time (seconds).
+        #[arg(long)]
+        since: Option<u64>,
+        /// Only messages relayed before this Unix time (seconds).
+        #[arg(long)]
+        until: Option<u64>,
+        /// Writes to this file instead of stdout.
+        #[arg(long)]
+        output: Option<std::path::PathBuf>,
+    },
+    /// Sends stored history to a running server again, at its original pace
+    /// or faster.
+    Replay {
+        /// Address of the server to replay to.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+        /// Reads history from a JSONL export.
+        #[arg(long)]
+        input: Option<std::path::PathBuf>,
+        /// Reads history from this SQLite database.
+        #[cfg(feature = "sqlite")]
+        #[arg(long)]
+        sqlite: Option<std::path::PathBuf>,
+        /// Reads history from Postgres (libpq-style connection string).
+        #[cfg(feature = "postgres")]
+        #[arg(long)]
+        postgres: Option<String>,
+        /// Only messages from this room.
+        #[arg(long)]
+        room: Option<String>,
+        /// Sends every message to this room instead.
+        #[arg(long)]
+        into: Option<String>,
+        /// Only messages relayed at or after this Unix time (seconds).
+        #[arg(long)]
+        since: Option<u64>,
+        /// Only messages relayed before this Unix time (seconds).
+        #[arg(long)]
+        until: Option<u64>,
+        /// Multiple of the original pace; 0 sends as fast as possible.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Longest pause between two messages, in seconds.
+        #[arg(long)]
+        max_gap: Option<f64>,
+    },
+    /// Sends what a client sent in a `--record-dir` recording to a server
+    /// again, printing what the server sends back.
+    PlayRecording {
+        /// The recording to play.
+        input: std::path::PathBuf,
+        /// Address of the server to play it to.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+        /// Multiple of the recorded pace; 0 sends as fast as possible.
+        #[arg(long, default_value_t = 1.0)]
+        speed: f64,
+        /// Sent in place of redacted tokens and passwords.
+        #[arg(long)]
+        secret: Option<String>,
+        /// Seconds to keep listening after the last frame is sent.
+        #[arg(long, default_value_t = 1.0)]
+        linger: f64,
+    },
+    /// Copies everything one message store holds to another: history, room
+    /// settings, registrations, saved and scheduled messages and file
+    /// shares. Rerunning it after an interruption carries on where it
+    /// stopped.
+    Migrate {
+        /// Store to copy from: `sqlite://PATH` or a `postgres://` URL.
+        #[arg(long)]
+        from: String,
+        /// Store to copy to, empty or from an earlier run.
+        #[arg(long)]
+        to: String,
+    },
+    /// Checks that a server speaks the protocol the way this one does.
+    /// Exits with an error if any scenario fails.
+    Conformance {
+        /// Address of the server to check.
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        addr: String,
+        /// Authenticates every connection as this user.
+        #[arg(long, requires = "token")]
+        user: Option<String>,
+        #[arg(long)]
+        token: Option<String>,
+        /// Seconds to wait for each expected frame.
+        #[arg(long, default_value_t = 5.0)]
+        timeout: f64,
+    },
+    /// Controls a running server through its admin socket.
+    #[cfg(unix)]
+    Admin {
+        /// The `--admin-socket` the server was started with.
+        #[arg(long, default_value = "chat-server.sock")]
+        socket: std::path::PathBuf,
+        #[command(subcommand)]
+        action: AdminAction,
+    },
+}
+
+#[cfg(unix)]
+#[derive(Subcommand)]
+enum AdminAction {
+    /// Disconnects a user (or a connection, by address).
+    Kick {
+        user: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Bans and disconnects a user.
+    Ban {
+        user: String,
+        #[arg(long)]
+        reason: Option<String>,
+    },
+    /// Lifts a ban.
+    Unban { user: String },
+    /// Sends a notice to everyone connected.
+    Notice { text: String },
+    /// Sends a system announcement; repeats are coalesced.
+    Announce {
+        text: String,
+        /// Announces to this room only.
+        #[arg(long)]
+        room: Option<String>,
+        #[arg(long, value_enum, default_value_t = Level::Info)]
+        level: Level,
+    },
+    /// Lists open connections.
+    Connections,
+    /// Lists rooms and their resource usage.
+    Rooms,
+    /// Prints the server's counters.
+    Stats,
+    /// Prints recent activity per interval, server-wide and per room.
+    Analytics,
+    /// Disconnects everyone and stops the server.
+    Drain,
+    /// Adds a custom emoji, or replaces its image.
+    AddEmoji {
+        name: String,
+        /// The image file.
+        image: std::path::PathBuf,
+    },
+    /// Removes a custom emoji.
+    RemoveEmoji { name: String },
+    /// Lists the custom emoji.
+    Emoji,
+    /// Prints messages as they're sent to rooms, until interrupted.
+    Tail {
+        #[arg(long)]
+        room: Option<String>,
+        #[arg(long)]
+        user: Option<String>,
+        /// Only messages matching this regular expression.
+        #[arg(long)]
+        pattern: Option<String>,
+        /// Hides what messages say.
+        #[arg(long)]
+        redact_content: bool,
+        /// Hides who sent messages behind pseudonyms.
+        #[arg(long)]
+        redact_senders: bool,
+    },
+}
+
+#[cfg(unix)]
+impl TryFrom<AdminAction> for AdminRequest {
+    type Error = anyhow::Error;
+
+    fn try_from(action: AdminAction) -> Result<Self> {
+        Ok(match action {
+            AdminAction::Kick { user, reason } => AdminRequest::Kick { user, reason },
+            AdminAction::Ban { user, reason } => AdminRequest::Ban { user, reason },
+            AdminAction::Unban { user } => AdminRequest::Unban { user },
+            AdminAction::Notice { text } => AdminRequest::Notice { text },
+            AdminAction::Announce { text, room, level } => AdminRequest::Announce {
+                room,
+                text,
+                level: level.into(),
+            },
+            AdminAction::Connections => AdminRequest::Connections,
+            AdminAction::Rooms => AdminRequest::Rooms,
+            AdminAction::Stats => AdminRequest::Stats,
+            AdminAction::Analytics => AdminRequest::Analytics,
+            AdminAction::Drain => AdminRequest::Drain,
+            AdminAction::AddEmoji { name, image } => AdminRequest::AddEmoji {
+                name,
+                image: BASE64.encode(std::fs::read(image)?),
+            },
+            AdminAction::RemoveEmoji { name } => AdminRequest::RemoveEmoji { name },
+            AdminAction::Emoji => AdminRequest::Emoji,
+            AdminAction::Tail {
+                room,
+                user,
+                pattern,
+                redact_content,
+                redact_senders,
+            } => AdminRequest::Tail {
+                filter: TailFilter {
+                    room,
+                    user,
+                    pattern,
+                    redact_content,
+                    redact_senders,
+                },
+            },
+        })
+    }
+}
+
+#[cfg(unix)]
+#[derive(Clone, Copy, ValueEnum)]
+enum Format {
+    Jsonl,
+    Csv,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Level {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl From<Level> for AnnouncementLevel {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Info => AnnouncementLevel::Info,
+            Level::Warning => AnnouncementLevel::Warning,
+            Level::Critical => AnnouncementLevel::Critical,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Sanitize {
+    /// Strip terminal escape sequences and control characters.
+    Standard,
+    /// Also strip invisible characters and long runs of combining marks.
+    Strict,
+}
+
+impl From<Sanitize> for Strictness {
+    fn from(sanitize: Sanitize) -> Self {
+        match sanitize {
+            Sanitize::Standard => Strictness::Standard,
+            Sanitize::Strict => Strictness::Strict,
+        }
+    }
+}
+
+#[cfg(unix)]
+impl From<Format> for ExportFormat {
+    fn from(format: Format) -> Self {
+        match format {
+            Format::Jsonl => ExportFormat::Jsonl,
+            Format::Csv => ExportFormat::Csv,
+        }
+    }
+}
+
+/// Logs to stderr, and with the `console` feature also serves task data to
+/// tokio-console on its default port (6669).
+fn init_logging() {
+    #[cfg(feature = "console")]
+    {
+        use tracing_subscriber::filter::LevelFilter;
+        use tracing_subscriber::prelude::*;
+        tracing_subscriber::registry()
+            .with(console_subscriber::spawn())
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(std::io::stderr)
+                    .with_filter(LevelFilter::INFO),
+            )
+            .init();
+    }
+    #[cfg(not(feature = "console"))]
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    init_logging();
+    match Cli::parse().command {
+        Command::Serve {
+            addr,
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            io_uring,
+            #[cfg(feature = "tls")]
+            tls_cert_dir,
+            #[cfg(feature = "tls")]
+            tls_default_host,
+            #[cfg(feature = "acme")]
+            acme_domain,
+            #[cfg(feature = "acme")]
+            acme_contact,
+            #[cfg(feature = "acme")]
+            acme_directory,
+            config,
+            catalogs,
+            sanitize,
+            link_previews,
+            push_relay,
+            #[cfg(feature = "email")]
+            smtp,
+            #[cfg(feature = "email")]
+            mail_from,
+            wal,
+            snapshot,
+            snapshot_interval,
+            record_dir,
+            #[cfg(feature = "sqlite")]
+            sqlite,
+            #[cfg(feature = "postgres")]
+            postgres,
+            archive_dir,
+            #[cfg(feature = "s3")]
+            archive_s3,
+            archive_after_days,
+            #[cfg(feature = "s3")]
+            s3_bucket,
+            #[cfg(feature = "s3")]
+            s3_prefix,
+            #[cfg(feature = "oidc")]
+            oidc_issuer,
+            #[cfg(feature = "oidc")]
+            oidc_audience,
+            #[cfg(feature = "ldap")]
+            ldap_url,
+            #[cfg(feature = "ldap")]
+            ldap_base_dn,
+            #[cfg(feature = "ldap")]
+            ldap_user_filter,
+            #[cfg(feature = "ldap")]
+            ldap_bind_dn,
+            #[cfg(feature = "ldap")]
+            ldap_bind_password,
+            #[cfg(feature = "geoip")]
+            geoip_rules,
+            #[cfg(feature = "geoip")]
+            geoip_country_db,
+            #[cfg(feature = "geoip")]
+            geoip_asn_db,
+            #[cfg(feature = "http")]
+            admin_addr,
+            #[cfg(feature = "http")]
+            admin_token,
+            #[cfg(unix)]
+            admin_socket,
+        } => {
+            info!("Starting chat server on {}", addr);
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            let listener: Box<dyn Listener> = if io_uring {
+                Box::new(tokio_chat_server::uring::UringListener::bind(&addr)?)
+            } else {
+                Box::new(tokio::net::TcpListener::bind(&addr).await?)
+            };
+            #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+            let listener: Box<dyn Listener> = Box::new(tokio::net::TcpListener::bind(&addr).await?);
+            #[cfg(feature = "tls")]
+            let listener: Box<dyn Listener> = match tls_cert_dir {
+                Some(cert_dir) => {
+                    use tokio_chat_server::tls::{Certificates, TlsConfig, TlsListener};
+                    let config = TlsConfig {
+                        cert_dir,
+                        default_host: tls_default_host,
+                        reload_interval: std::time::Duration::from_secs(60),
+                    };
+                    #[cfg(feature = "acme")]
+                    let certs = if acme_domain.is_empty() {
+                        Certificates::load(config)?
+                    } else {
+                        use tokio_chat_server::acme::{Acme, AcmeConfig};
+                        // The certificates may not have been issued yet.
+                        let certs = Certificates::open(config)?;
+                        let mut acme = AcmeConfig::new(acme_domain);
+                        acme.contact = acme_contact;
+                        if let Some(directory) = acme_directory {
+                            acme.directory_url = directory;
+                        }
+                        tokio::spawn(Acme::new(acme, certs.clone())?.run());
+                        certs
+                    };
+                    #[cfg(not(feature = "acme"))]
+                    let certs = Certificates::load(config)?;
+                    Box::new(TlsListener::new(listener, certs)?)
+                }
+                None => listener,
+            };
+            let mut server = ChatServer::from_listener(listener);
+            if let Some(path) = config {
+                server = Config::load(path).await?.apply(server);
+            }
+            #[cfg(feature = "oidc")]
+            if let Some(issuer) = oidc_issuer {
+                use tokio_chat_server::oidc::{OidcAuthenticator, OidcConfig};
+                let config = OidcConfig {
+                    audiences: oidc_audience,
+                    ..OidcConfig::new(issuer)
+                };
+                server =
+                    server.with_authenticator(std::sync::Arc::new(OidcAuthenticator::new(config)));
+            }
+            #[cfg(feature = "ldap")]
+            if let (Some(url), Some(base_dn)) = (ldap_url, ldap_base_dn) {
+                use tokio_chat_server::ldap::{LdapAuthenticator, LdapConfig};
+                let config = LdapConfig {
+                    user_filter: ldap_user_filter,
+                    bind_dn: ldap_bind_dn,
+                    bind_password: ldap_bind_password,
+                    ..LdapConfig::new(url, base_dn)
+                };
+                server =
+                    server.with_authenticator(std::sync::Arc::new(LdapAuthenticator::new(config)));
+            }
+            #[cfg(feature = "geoip")]
+            if let Some(rules) = geoip_rules {
+                use tokio_chat_server::geoip::{GeoConfig, GeoFilter};
+                server = server.with_geoip(GeoFilter::open(GeoConfig {
+                    country_db: geoip_country_db,
+                    asn_db: geoip_asn_db,
+                    rules,
+                    reload_interval: std::time::Duration::from_secs(60),
+                })?);
+            }
+            if let Some(dir) = catalogs {
+                server = server.with_catalogs(Catalogs::load_dir(dir).await?);
+            }
+            server = server.with_sanitize_policy(SanitizePolicy {
+                strictness: sanitize.into(),
+                ..SanitizePolicy::default()
+            });
+            if link_previews {
+                server = server
+                    .with_link_previews(std::sync::Arc::new(HttpFetcher), PreviewPolicy::default());
+            }
+            if let Some(url) = push_relay {
+                let gateway = [PushPlatform::Fcm, PushPlatform::Apns, PushPlatform::WebPush]
+                    .into_iter()
+                    .fold(PushGateway::new(), |gateway, platform| {
+                        let relay = RelayProvider::new(url.clone(), platform);
+                        gateway.with_provider(platform, std::sync::Arc::new(relay))
+                    });
+                server = server.with_push(gateway);
+            }
+            #[cfg(feature = "email")]
+            if let (Some(relay), Some(from)) = (smtp, mail_from) {
+                let (host, port) = relay
+                    .rsplit_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("--smtp takes host:port"))?;
+                let mailer =
+                    tokio_chat_server::digest::SmtpMailer::new(host, port.parse()?, &from)?;
+                server = server.with_email_digests(
+                    std::sync::Arc::new(mailer),
+                    tokio_chat_server::digest::DigestPolicy::default(),
+                );
+            }
+            if let Some(path) = wal {
+                server = server.with_wal(Wal::open(path).await?);
+            }
+            if let Some(path) = snapshot {
+                server =
+                    server.with_snapshots(path, std::time::Duration::from_secs(snapshot_interval));
+            }
+            if let Some(dir) = record_dir {
+                server = server.with_recordings(dir);
+            }
+            #[cfg(feature = "sqlite")]
+            if let Some(path) = sqlite {
+                let store = tokio_chat_server::store::SqliteStore::open(path).await?;
+                server = server.with_message_store(std::sync::Arc::new(store));
+            }
+            #[cfg(feature = "postgres")]
+            if let Some(config) = postgres {
+                let store = tokio_chat_server::store::PostgresStore::connect(&config).await?;
+                server = server.with_message_store(std::sync::Arc::new(store));
+            }
+            let archive_policy = ArchivePolicy {
+                idle: std::time::Duration::from_secs(archive_after_days * 24 * 60 * 60),
+                ..ArchivePolicy::default()
+            };
+            if let Some(dir) = archive_dir {
+                let cold = std::sync::Arc::new(DirColdStore::new(dir).await?);
+                server = server.with_archiving(cold, archive_policy.clone());
+            }
+            #[cfg(feature = "s3")]
+            if let Some(bucket) = s3_bucket {
+                use tokio_chat_server::s3::{S3BlobStore, S3ColdStore, S3Config};
+                let config = S3Config::from_env(bucket)?;
+                let blobs = S3Config {
+                    prefix: format!("{}blobs/", s3_prefix),
+                    ..config.clone()
+                };
+                server = server.with_blob_store(std::sync::Arc::new(S3BlobStore::new(blobs)));
+                if archive_s3 {
+                    let archives = S3Config {
+                        prefix: format!("{}archives/", s3_prefix),
+                        ..config
+                    };
+                    let cold = std::sync::Arc::new(S3ColdStore::new(archives));
+                    server = server.with_archiving(cold, archive_policy);
+                }
+            }
+            #[cfg(feature = "http")]
+            if let Some(admin_addr) = admin_addr {
+                use tokio_chat_server::http::{Admin, serve_admin};
+                let Some(token) = admin_token.filter(|token| !token.is_empty()) else {
+                    anyhow::bail!("--admin-addr needs a non-empty --admin-token");
+                };
+                let listener = tokio::net::TcpListener::bind(&admin_addr).await?;
+                let admin = Admin {
+                    metrics: server.metrics(),
+                    dead_letters: server.dead_letters(),
+                    api_keys: server.api_keys(),
+                    resources: server.resources(),
+                    control: server.admin(),
+                    token,
+                };
+                tokio_chat_server::tasks::spawn_named("admin http", serve_admin(listener, admin));
+            }
+            #[cfg(unix)]
+            if let Some(path) = admin_socket {
+                server = server.with_admin_socket(path);
+            }
+            server.run().await
+        }
+        #[cfg(unix)]
+        Command::Export {
+            socket,
+            room,
+            format,
+            since,
+            until,
+            output,
+        } => {
+            let mut out: Box<dyn Write> = match output {
+                Some(path) => Box::new(std::fs::File::create(path)?),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let mut connection = AdminConnection::connect(&socket).await?;
+            connection
+                .send(&AdminRequest::Export {
+                    room,
+                    format: format.into(),
+                    since,
+                    until,
+                })
+                .await?;
+            loop {
+                match connection.receive().await? {
+                    AdminResponse::ExportChunk { data } => out.write_all(data.as_bytes())?,
+                    AdminResponse::Done => break,
+                    AdminResponse::Error { message } => return Err(anyhow::anyhow!(message)),
+                    response => {
+                        return Err(anyhow::anyhow!("Unexpected reply: {:?}", response));
+                    }
+                }
+            }
+            out.flush()?;
+            Ok(())
+        }
+        Command::Replay {
+            addr,
+            input,
+            #[cfg(feature = "sqlite")]
+            sqlite,
+            #[cfg(feature = "postgres")]
+            postgres,
+            room,
+            into,
+            since,
+            until,
+            speed,
+            max_gap,
+        } => {
+            let mut store: Option<Box<dyn MessageStore>> = None;
+            if let Some(path) = input {
+                store = Some(Box::new(replay::read_export(path).await?));
+            }
+            #[cfg(feature = "sqlite")]
+            if let Some(path) = sqlite {
+                store = Some(Box::new(
+                    tokio_chat_server::store::SqliteStore::open(path).await?,
+                ));
+            }
+            #[cfg(feature = "postgres")]
+            if let Some(config) = postgres {
+                store = Some(Box::new(
+                    tokio_chat_server::store::PostgresStore::connect(&config).await?,
+                ));
+            }
+            let store = store.ok_or_else(|| anyhow::anyhow!("Nothing to replay from"))?;
+            let options = ReplayOptions {
+                room,
+                into_room: into,
+                since,
+                until,
+                speed,
+                max_gap: max_gap.map(std::time::Duration::from_secs_f64),
+            };
+            let mut client = Client::connect(&addr).await?;
+            let sent = replay::replay(store.as_ref(), &mut client, &options).await?;
+            println!("Replayed {} messages", sent);
+            Ok(())
+        }
+        Command::PlayRecording {
+            input,
+            addr,
+            speed,
+            secret,
+            linger,
+        } => {
+            let records = recording::read(input).await?;
+            let options = PlaybackOptions {
+                speed,
+                secret,
+                linger: std::time::Duration::from_secs_f64(linger),
+            };
+            for line in recording::play(&records, &addr, &options).await? {
+                println!("{}", line);
+            }
+            Ok(())
+        }
+        Command::Migrate { from, to } => {
+            let source = store::open(&from).await?;
+            let destination = store::open(&to).await?;
+            let progress = store::migrate(source.as_ref(), destination.as_ref(), |progress| {
+                eprintln!(
+                    "Copied {} messages, up to id {} of {}",
+                    progress.copied, progress.last_id, progress.source_last_id
+                )
+            })
+            .await?;
+            println!(
+                "Migrated {} messages, {} rooms, {} registrations, {} saved and {} scheduled \
+                 messages and {} file shares from {} to {}",
+                progress.copied,
+                progress.rooms,
+                progress.nicks,
+                progress.saved,
+                progress.scheduled,
+                progress.file_shares,
+                from,
+                to
+            );
+            Ok(())
+        }
+        Command::Conformance {
+            addr,
+            user,
+            token,
+            timeout,
+        } => {
+            let options = ConformanceOptions {
+                timeout: std::time::Duration::from_secs_f64(timeout),
+                credentials: user.zip(token),
+            };
+            let report = conformance::run(&addr, &options).await;
+            println!("{}", report);
+            if !report.passed() {
+                anyhow::bail!("{} does not conform", addr);
+            }
+            Ok(())
+        }
+        #[cfg(unix)]
+        Command::Admin { socket, action } => {
+            let request = action.try_into()?;
+            if let AdminRequest::Tail { .. } = request {
+                return tail(&socket, &request).await;
+            }
+            match admin::request(&socket, &request).await? {
+                AdminResponse::Disconnected { connections } => {
+                    println!("Disconnected {} connections", connections)
+                }
+                AdminResponse::Unbanned { was_banned: true } => println!("Unbanned"),
+                AdminResponse::Unbanned { was_banned: false } => println!("Not banned"),
+                AdminResponse::Announced { sent: true } => {}
+                AdminResponse::Announced { sent: false } => {
+                    println!("Held back (a repeat, or over the rate limit)")
+                }
+                AdminResponse::Done => {}
+                AdminResponse::Connections { connections } => {
+                    for connection in connections {
+                        println!(
+                            "{}\t{}\t{} frames",
+                            connection.addr, connection.user, connection.stats.frames
+                        );
+                    }
+                }
+                AdminResponse::Rooms { usage } => {
+                    println!("{}", serde_json::to_string_pretty(&usage)?)
+                }
+                AdminResponse::Stats { metrics } => {
+                    println!("{}", serde_json::to_string_pretty(&metrics)?)
+                }
+                AdminResponse::Analytics { report } => {
+                    println!("{}", serde_json::to_string_pretty(&report)?)
+                }
+                AdminResponse::Emoji { emoji } => {
+                    for emoji in emoji {
+                        println!("{}\t{}", emoji.name, emoji.blob_id);
+                    }
+                }
+                AdminResponse::EmojiRemoved { existed: true } => println!("Removed"),
+                AdminResponse::EmojiRemoved { existed: false } => println!("No such emoji"),
+                AdminResponse::Error { message } => return Err(anyhow::anyhow!(message)),
+                response => return Err(anyhow::anyhow!("Unexpected reply: {:?}", response)),
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Prints tailed messages until the server goes away.
+#[cfg(unix)]
+async fn tail(socket: &std::path::Path, request: &AdminRequest) -> Result<()> {
+    let mut connection = AdminConnection::connect(socket).await?;
+    connection.send(request).await?;
+    loop {
+        match connection.receive().await? {
+            AdminResponse::Message { message } => println!("{}", message),
+            AdminResponse::Lagged { skipped } => eprintln!("(missed {} messages)", skipped),
+            AdminResponse::Error { message } => return Err(anyhow::anyhow!(message)),
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// Largest blob accepted from clients, over the chat protocol or HTTP.
+pub const MAX_BLOB_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Storage for uploaded files, addressed by the hex SHA-256 of their contents.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores `data` under `id`, overwriting any existing blob.
+    async fn put(&self, id: &str, data: Bytes) -> Result<()>;
+
+    /// Fetches the blob stored under `id`, if any.
+    async fn get(&self, id: &str) -> Result<Option<Bytes>>;
+
+    /// Returns whether a blob is stored under `id`.
+    async fn contains(&self, id: &str) -> Result<bool> {
+        Ok(self.get(id).await?.is_some())
+    }
+}
+
+/// Returns the lowercase hex SHA-256 digest used as a blob id.
+pub fn blob_id(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Blob ids are always 64 lowercase hex characters; anything else is rejected
+/// before it reaches a backend (and, for `DirBlobStore`, the filesystem).
+pub fn is_valid_blob_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// Keeps blobs in memory; the default when no store is configured.
+#[derive(Default)]
+pub struct MemoryBlobStore {
+    blobs: Mutex<HashMap<String, Bytes>>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl BlobStore for MemoryBlobStore {
+    async fn put(&self, id: &str, data: Bytes) -> Result<()> {
+        self.blobs.lock().unwrap().insert(id.to_string(), data);
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Bytes>> {
+        Ok(self.blobs.lock().unwrap().get(id).cloned())
+    }
+}
+
+/// Stores each blob as a file named by its id inside a directory.
+pub struct DirBlobStore {
+    root: PathBuf,
+}
+
+impl DirBlobStore {
+    /// Uses `root` for blob files, creating it if needed.
+    pub async fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        tokio::fs::create_dir_all(&root).await?;
+        Ok(DirBlobStore { root })
+    }
+
+    fn path(&self, id: &str) -> Result<PathBuf> {
+        if !is_valid_blob_id(id) {
+            return Err(anyhow::anyhow!("Invalid blob id: {}", id));
+        }
+        Ok(self.root.join(id))
+    }
+}
+
+#[async_trait]
+impl BlobStore for DirBlobStore {
+    async fn put(&self, id: &str, data: Bytes) -> Result<()> {
+        tokio::fs::write(self.path(id)?, &data).await?;
+        Ok(())
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Bytes>> {
+        match tokio::fs::read(self.path(id)?).await {
+            Ok(data) => Ok(Some(Bytes::from(data))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use crate::client::{self, ConnectionEvents};
+use crate::protocol::{ChatMessage, ClientFrame, MessageId, PresenceState, ServerFrame};
+use anyhow::Result;
+use std::sync::Arc;
+use tokio::runtime::Runtime;
+
+/// A synchronous wrapper around `client::Client` for CLI tools and other
+/// non-async code. Each client drives its own single-threaded runtime, so
+/// it must not be used from inside another tokio runtime.
+pub struct Client {
+    inner: client::Client,
+    runtime: Runtime,
+}
+
+impl Client {
+    /// Connects to the chat server at `addr`, blocking until connected.
+    ///
+    /// # Examples
+    /// ```no_run
+    /// use tokio_chat_server::blocking::Client;
+    /// use tokio_chat_server::protocol::ChatMessage;
+    ///
+    /// let mut client = Client::connect("127.0.0.1:8080").unwrap();
+    /// client
+    ///     .send(ChatMessage::from_raw("avery: hi").unwrap())
+    ///     .unwrap();
+    /// ```
+    pub fn connect(addr: &str) -> Result<Self> {
+        let runtime = new_runtime()?;
+        let inner = runtime.block_on(client::Client::connect(addr))?;
+        Ok(Client { inner, runtime })
+    }
+
+    /// Like `connect`, reporting connection status changes to `events`.
+    pub fn connect_with_events(addr: &str, events: Arc<dyn ConnectionEvents>) -> Result<Self> {
+        let runtime = new_runtime()?;
+        let inner = runtime.block_on(client::Client::connect_with_events(addr, events))?;
+        Ok(Client { inner, runtime })
+    }
+
+    /// Connects with the settings from `builder`, e.g. timeouts so calls
+    /// don't block forever on an unresponsive server.
+    pub fn connect_with(builder: client::ClientBuilder) -> Result<Self> {
+        let runtime = new_runtime()?;
+        let inner = runtime.block_on(builder.connect())?;
+        Ok(Client { inner, runtime })
+    }
+
+    /// See `client::Client::send`.
+    pub fn send(&mut self, message: ChatMessage) -> Result<()> {
+        self.runtime.block_on(self.inner.send(message))
+    }
+
+    /// See `client::Client::send_frame`.
+    pub fn send_frame(&mut self, frame: &ClientFrame) -> Result<()> {
+        self.runtime.block_on(self.inner.send_frame(frame))
+    }
+
+    /// See `client::Client::set_profile`.
+    pub fn set_profile(
+        &mut self,
+        display_name: Option<String>,
+        status_text: Option<String>,
+        state: Option<PresenceState>,
+    ) -> Result<()> {
+        self.runtime
+            .block_on(self.inner.set_profile(display_name, status_text, state))
+    }
+
+    /// See `client::Client::fetch_history`.
+    pub fn fetch_history(
+        &mut self,
+        room: Option<String>,
+        before: Option<MessageId>,
+        limit: Option<usize>,
+    ) -> Result<()> {
+        self.runtime
+            .block_on(self.inner.fetch_history(room, before, limit))
+    }
+
+    /// See `client::Client::receive`; blocks until a frame arrives.
+    pub fn receive(&mut self) -> Result<ServerFrame> {
+        self.runtime.block_on(self.inner.receive())
+    }
+
+    /// See `client::Client::receive_frame`; blocks until a frame arrives.
+    pub fn receive_frame(&mut self) -> Result<ServerFrame> {
+        self.runtime.block_on(self.inner.receive_frame())
+    }
+
+    /// See `client::Client::reconnect`.
+    pub fn reconnect(&mut self) -> Result<()> {
+        self.runtime.block_on(self.inner.reconnect())
+    }
+
+    /// See `client::Client::last_message_id`.
+    pub fn last_message_id(&self) -> Option<MessageId> {
+        self.inner.last_message_id()
+    }
+}
+
+fn new_runtime() -> Result<Runtime> {
+    Ok(tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?)
+}
@@ -0,0 +1,155 @@
+use crate::auth::generate_token;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When new connections must solve a proof-of-work challenge before the
+/// server accepts anything from them, to raise the cost of bot floods.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChallengePolicy {
+    /// Leading zero bits a solution's hash needs. Each extra bit doubles
+    /// the client's expected work.
+    pub difficulty: u8,
+    /// Connections one IP address may open within `window` without a
+    /// challenge; later ones are challenged. Zero challenges everyone.
+    pub free_connections: usize,
+    pub window: Duration,
+}
+
+impl Default for ChallengePolicy {
+    fn default() -> Self {
+        ChallengePolicy {
+            difficulty: 20,
+            free_connections: 5,
+            window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A challenge sent to one connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    pub nonce: String,
+    pub difficulty: u8,
+}
+
+impl Challenge {
+    /// Whether `solution` solves the challenge.
+    pub fn is_solved_by(&self, solution: &str) -> bool {
+        let hash = Sha256::digest(format!("{}:{}", self.nonce, solution));
+        leading_zero_bits(&hash) >= u32::from(self.difficulty)
+    }
+
+    /// Finds a solution by brute force. Slow on purpose; call it off the
+    /// async runtime.
+    pub fn solve(&self) -> String {
+        (0u64..)
+            .map(|n| n.to_string())
+            .find(|solution| self.is_solved_by(solution))
+            .expect("some counter solves it")
+    }
+}
+
+/// Recent connections per IP address, deciding which to challenge.
+#[derive(Debug)]
+pub struct ChallengeTracker {
+    policy: ChallengePolicy,
+    recent: Mutex<Recent>,
+}
+
+#[derive(Debug)]
+struct Recent {
+    connections: HashMap<IpAddr, VecDeque<Instant>>,
+    /// When addresses with no recent connections were last forgotten.
+    swept_at: Instant,
+}
+
+impl ChallengeTracker {
+    pub fn new(policy: ChallengePolicy) -> Self {
+        ChallengeTracker {
+            policy,
+            recent: Mutex::new(Recent {
+                connections: HashMap::new(),
+                swept_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Records a connection from `ip` at `now`, returning a challenge for
+    /// it if `ip` has used up its free connections.
+    pub fn on_connect(&self, ip: IpAddr, now: Instant) -> Option<Challenge> {
+        let window = self.policy.window;
+        let mut recent = self.recent.lock().unwrap();
+        if now.duration_since(recent.swept_at) >= window {
+            recent.connections.retain(|_, times| {
+                times.retain(|at| now.duration_since(*at) < window);
+                !times.is_empty()
+            });
+            recent.swept_at = now;
+        }
+        let times = recent.connections.entry(ip).or_default();
+        times.retain(|at| now.duration_since(*at) < window);
+        times.push_back(now);
+        (times.len() > self.policy.free_connections).then(|| Challenge {
+            nonce: generate_token(),
+            difficulty: self.policy.difficulty,
+        })
+    }
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_solutions_meet_the_difficulty() {
+        let challenge = Challenge {
+            nonce: "abc".to_string(),
+            difficulty: 12,
+        };
+        let solution = challenge.solve();
+        assert!(challenge.is_solved_by(&solution));
+        let harder = Challenge {
+            difficulty: 40,
+            ..challenge
+        };
+        assert!(!harder.is_solved_by(&solution));
+    }
+
+    #[test]
+    fn test_challenges_after_free_connections() {
+        let tracker = ChallengeTracker::new(ChallengePolicy {
+            difficulty: 8,
+            free_connections: 2,
+            window: Duration::from_secs(10),
+        });
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let start = Instant::now();
+        assert!(tracker.on_connect(ip, start).is_none());
+        assert!(tracker.on_connect(ip, start).is_none());
+        assert!(tracker.on_connect(ip, start).is_some());
+        assert!(
+            tracker
+                .on_connect(IpAddr::from([10, 0, 0, 2]), start)
+                .is_none()
+        );
+        assert!(
+            tracker
+                .on_connect(ip, start + Duration::from_secs(10))
+                .is_none()
+        );
+    }
+}
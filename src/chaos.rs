@@ -0,0 +1,62 @@
+use std::hash::{BuildHasher, RandomState};
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// Faults to inject into client connections. Rates are chances from 0.0
+/// (never) to 1.0 (always).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChaosConfig {
+    /// Delay before each write to a client.
+    pub latency: Duration,
+    /// Chance a write sends only part of a frame and then drops the
+    /// connection.
+    pub truncate_rate: f64,
+    /// Chance a connection is dropped instead of written to.
+    pub disconnect_rate: f64,
+    /// Chance a broadcast frame is skipped for a given connection.
+    pub drop_broadcast_rate: f64,
+}
+
+/// Fault injection for testing client reconnect and backfill behaviour,
+/// changeable while the server runs through `ChatServer::chaos`. Injects
+/// nothing until configured.
+#[derive(Debug, Default)]
+pub struct Chaos {
+    config: RwLock<ChaosConfig>,
+}
+
+impl Chaos {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the faults being injected.
+    pub fn set(&self, config: ChaosConfig) {
+        *self.config.write().unwrap() = config;
+    }
+
+    pub fn config(&self) -> ChaosConfig {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn latency(&self) -> Duration {
+        self.config.read().unwrap().latency
+    }
+
+    pub fn truncate(&self) -> bool {
+        roll(self.config.read().unwrap().truncate_rate)
+    }
+
+    pub fn disconnect(&self) -> bool {
+        roll(self.config.read().unwrap().disconnect_rate)
+    }
+
+    pub fn drop_broadcast(&self) -> bool {
+        roll(self.config.read().unwrap().drop_broadcast_rate)
+    }
+}
+
+/// Returns true with probability `rate`.
+fn roll(rate: f64) -> bool {
+    rate > 0.0 && (RandomState::new().hash_one(()) as f64 / u64::MAX as f64) < rate
+}
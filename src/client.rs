@@ -1,19 +1,226 @@
-use crate::protocol::ChatMessage;
-use anyhow::Result;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tracing::info;
+use crate::challenge::Challenge;
+use crate::codec::{FrameDecoder, WireFormat};
+use crate::protocol::{
+    Capability, ChatMessage, ClientFrame, MessageId, PresenceState, ServerFrame,
+};
+use crate::transport::BoxStream;
+use anyhow::{Result, anyhow};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadBuf};
+use tokio::net::{TcpStream, ToSocketAddrs, lookup_host};
+use tokio::task::JoinSet;
+use tracing::{debug, info};
+
+/// Recently received messages kept by each client.
+const MESSAGE_CACHE_CAPACITY: usize = 256;
+/// Longest server frame accepted; history pages and replays can be large.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+/// How long a connection attempt gets before the next address is tried
+/// alongside it, as RFC 8305 recommends.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connection status callbacks for applications that display it, e.g. a
+/// GUI status bar. Every method defaults to doing nothing.
+pub trait ConnectionEvents: Send + Sync {
+    /// A connection to `addr` was established (initially or on reconnect).
+    fn on_connected(&self, _addr: &str) {}
+    /// The server closed the connection.
+    fn on_disconnected(&self, _addr: &str) {}
+    /// A read or write failed.
+    fn on_error(&self, _addr: &str, _error: &anyhow::Error) {}
+    /// `Client::reconnect` is about to dial `addr` again.
+    fn on_reconnecting(&self, _addr: &str) {}
+}
+
+/// Where to connect, kept for reconnects.
+#[derive(Debug, Clone)]
+enum Target {
+    /// A "host:port" resolved again on every connect.
+    Host(String),
+    /// Addresses resolved once, by `Client::connect`.
+    Resolved(Vec<SocketAddr>),
+    /// A "host:port" on turmoil's simulated network, by `Client::simulated`.
+    #[cfg(feature = "simulation")]
+    Simulated(String),
+}
+
+/// Socket settings and timeouts, kept for reconnects. `None` timeouts wait
+/// indefinitely.
+#[derive(Debug, Clone, Default)]
+struct ClientOptions {
+    connect_timeout: Option<Duration>,
+    send_timeout: Option<Duration>,
+    receive_timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    nodelay: bool,
+    backfill_gaps: bool,
+    tenant: Option<String>,
+    locales: Vec<String>,
+    capabilities: Option<BTreeSet<Capability>>,
+}
+
+/// Configures a `Client` before connecting.
+///
+/// # Examples
+/// ```rust
+/// # #[tokio::test]
+/// # async fn doc_test() {
+/// let client = Client::builder("127.0.0.1:8080")
+///     .connect_timeout(Duration::from_secs(5))
+///     .receive_timeout(Duration::from_secs(30))
+///     .nodelay(true)
+///     .connect()
+///     .await
+///     .unwrap();
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ClientBuilder {
+    addr: String,
+    target: Target,
+    options: ClientOptions,
+    events: Option<Arc<dyn ConnectionEvents>>,
+}
+
+impl ClientBuilder {
+    /// Fails `connect` and `reconnect` if the server doesn't accept in time.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.options.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails a send if the message can't be written in time.
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.options.send_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails `receive` and `receive_frame` if nothing arrives in time.
+    pub fn receive_timeout(mut self, timeout: Duration) -> Self {
+        self.options.receive_timeout = Some(timeout);
+        self
+    }
+
+    /// Enables TCP keepalive, probing after the connection is idle this long.
+    pub fn keepalive(mut self, idle: Duration) -> Self {
+        self.options.keepalive = Some(idle);
+        self
+    }
+
+    /// Sets `TCP_NODELAY`, disabling Nagle's algorithm.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.options.nodelay = nodelay;
+        self
+    }
+
+    /// Automatically requests a `Backfill` when a room's sequence numbers
+    /// jump, e.g. after the server dropped frames for a slow connection.
+    /// The missing messages arrive as a `ServerFrame::Backfill`.
+    pub fn backfill_gaps(mut self, backfill: bool) -> Self {
+        self.options.backfill_gaps = backfill;
+        self
+    }
+
+    /// Talks to the server's tenant `name`, selecting it first thing on
+    /// every connection.
+    pub fn tenant(mut self, name: impl Into<String>) -> Self {
+        self.options.tenant = Some(name.into());
+        self
+    }
+
+    /// Asks for server-generated text in one of `locales`, most preferred
+    /// first, on every connection. The server answers with a
+    /// `ServerFrame::LocaleSelected`.
+    pub fn locales<I, S>(mut self, locales: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.options.locales = locales.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Tells the server this client only handles `capabilities`, on every
+    /// connection, so frames needing others arrive downgraded. The server
+    /// answers with a `ServerFrame::CapabilitiesSelected`.
+    pub fn capabilities(mut self, capabilities: impl IntoIterator<Item = Capability>) -> Self {
+        self.options.capabilities = Some(capabilities.into_iter().collect());
+        self
+    }
+
+    /// Reports connection status changes to `events`, starting with
+    /// `on_connected`.
+    pub fn events(mut self, events: Arc<dyn ConnectionEvents>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    /// Connects with the configured settings.
+    pub async fn connect(self) -> Result<Client> {
+        let stream = match open_stream(&self.target, &self.options).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                if let Some(events) = &self.events {
+                    events.on_error(&self.addr, &e);
+                }
+                return Err(e);
+            }
+        };
+        info!("Connected to {}", self.addr);
+        if let Some(events) = &self.events {
+            events.on_connected(&self.addr);
+        }
+        let mut client = Client {
+            stream,
+            addr: self.addr,
+            target: self.target,
+            options: self.options,
+            decoder: FrameDecoder::new(MAX_FRAME_LEN),
+            last_id: None,
+            room_seqs: HashMap::new(),
+            resume_token: None,
+            cache: VecDeque::new(),
+            events: self.events,
+        };
+        client.handshake().await?;
+        Ok(client)
+    }
+}
 
 /// A client for connecting to and interacting with the chat server.
 pub struct Client {
-    stream: TcpStream,
+    stream: BoxStream,
+    addr: String,
+    target: Target,
+    options: ClientOptions,
+    /// Bytes read past the end of the last frame.
+    decoder: FrameDecoder,
+    /// Highest message id received, sent with `Resume` on reconnect.
+    last_id: Option<MessageId>,
+    /// Highest sequence number received in each room.
+    room_seqs: HashMap<String, u64>,
+    /// Latest session token from the server, presented on reconnect.
+    resume_token: Option<String>,
+    cache: VecDeque<ChatMessage>,
+    events: Option<Arc<dyn ConnectionEvents>>,
 }
 
 impl Client {
     /// Establishes a connection to the chat server at the given address.
+    /// A hostname is resolved once; when it has both IPv6 and IPv4
+    /// addresses they're raced, happy-eyeballs style, and the first to
+    /// connect is kept for reconnects too. Use `builder` to resolve it
+    /// again on every reconnect.
     ///
     /// # Arguments
-    /// - `addr`: The server address (e.g., "127.0.0.1:8080").
+    /// - `addr`: The server address (e.g., "127.0.0.1:8080",
+    ///   `("chat.example.com", 8080)` or a `SocketAddr`).
     ///
     /// # Returns
     /// A `Result` containing the `Client` or an error if connection fails.
@@ -25,10 +232,156 @@ impl Client {
     /// let client = Client::connect("127.0.0.1:8080").await.unwrap();
     /// # }
     /// ```
-    pub async fn connect(addr: &str) -> Result<Self> {
-        let stream = TcpStream::connect(addr).await?;
-        info!("Connected to {}", addr);
-        Ok(Client { stream })
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        Self::resolve(addr).await?.connect().await
+    }
+
+    /// Like `connect`, but reports connection status changes to `events`,
+    /// starting with `on_connected`.
+    pub async fn connect_with_events(
+        addr: impl ToSocketAddrs,
+        events: Arc<dyn ConnectionEvents>,
+    ) -> Result<Self> {
+        Self::resolve(addr).await?.events(events).connect().await
+    }
+
+    /// Starts configuring a connection to `addr`, a "host:port", with
+    /// timeouts and socket options; by default nothing times out. The host
+    /// is resolved on every connect and reconnect.
+    pub fn builder(addr: &str) -> ClientBuilder {
+        ClientBuilder {
+            addr: addr.to_string(),
+            target: Target::Host(addr.to_string()),
+            options: ClientOptions::default(),
+            events: None,
+        }
+    }
+
+    /// Like `builder`, but connects over turmoil's simulated network to
+    /// `addr`, a "host:port" in the running simulation. Socket options
+    /// don't apply there.
+    #[cfg(feature = "simulation")]
+    pub fn simulated(addr: &str) -> ClientBuilder {
+        ClientBuilder {
+            addr: addr.to_string(),
+            target: Target::Simulated(addr.to_string()),
+            options: ClientOptions::default(),
+            events: None,
+        }
+    }
+
+    /// A builder for the addresses `addr` resolves to, named after the
+    /// first of them.
+    async fn resolve(addr: impl ToSocketAddrs) -> Result<ClientBuilder> {
+        let addrs: Vec<SocketAddr> = lookup_host(addr).await?.collect();
+        let first = addrs
+            .first()
+            .ok_or_else(|| anyhow!("The address resolved to nothing"))?;
+        Ok(ClientBuilder {
+            addr: first.to_string(),
+            target: Target::Resolved(addrs),
+            options: ClientOptions::default(),
+            events: None,
+        })
+    }
+
+    /// Reconnects to the same server. If the server issued a session token
+    /// the session is resumed with `ResumeSession`; otherwise, if any message
+    /// has been received, the server is asked to replay everything newer.
+    /// Replayed messages arrive as a `ServerFrame::Replay`.
+    pub async fn reconnect(&mut self) -> Result<()> {
+        if let Some(events) = &self.events {
+            events.on_reconnecting(&self.addr);
+        }
+        // Close the old connection first so the server has suspended the
+        // session by the time it's resumed.
+        let _ = self.stream.shutdown().await;
+        let stream = open_stream(&self.target, &self.options).await;
+        self.stream = self.report(stream)?;
+        self.decoder.clear();
+        info!("Reconnected to {}", self.addr);
+        if let Some(events) = &self.events {
+            events.on_connected(&self.addr);
+        }
+        self.handshake().await?;
+        if let Some(token) = self.resume_token.clone() {
+            self.send_frame(&ClientFrame::ResumeSession {
+                token,
+                last_id: self.last_id,
+            })
+            .await?;
+        } else if let Some(last_id) = self.last_id {
+            self.resume_from(last_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends `SelectTenant` if the client was built for a tenant, then
+    /// `SetLocale` if it was built with locales and `SetCapabilities` if it
+    /// was built with capabilities.
+    async fn handshake(&mut self) -> Result<()> {
+        if let Some(tenant) = self.options.tenant.clone() {
+            self.send_frame(&ClientFrame::SelectTenant { tenant })
+                .await?;
+        }
+        if !self.options.locales.is_empty() {
+            let locales = self.options.locales.clone();
+            self.send_frame(&ClientFrame::SetLocale { locales }).await?;
+        }
+        if let Some(capabilities) = self.options.capabilities.clone() {
+            self.send_frame(&ClientFrame::SetCapabilities { capabilities })
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Asks the server to replay messages newer than `last_id`, e.g. one
+    /// saved from `last_message_id` by a previous process.
+    pub async fn resume_from(&mut self, last_id: MessageId) -> Result<()> {
+        self.last_id = self.last_id.max(Some(last_id));
+        self.send_frame(&ClientFrame::Resume { last_id }).await
+    }
+
+    /// Returns the id of the newest message received so far.
+    pub fn last_message_id(&self) -> Option<MessageId> {
+        self.last_id
+    }
+
+    /// Returns the highest sequence number received in `room`.
+    pub fn last_seq(&self, room: &str) -> Option<u64> {
+        self.room_seqs.get(room).copied()
+    }
+
+    /// Requests a room's messages with sequence numbers in
+    /// `[from_seq, to_seq]`; the server answers with `ServerFrame::Backfill`.
+    pub async fn backfill(&mut self, room: &str, from_seq: u64, to_seq: u64) -> Result<()> {
+        self.send_frame(&ClientFrame::Backfill {
+            room: room.to_string(),
+            from_seq,
+            to_seq,
+        })
+        .await
+    }
+
+    /// Acknowledges every message received in `room` up to `seq`, for rooms
+    /// with at-least-once delivery.
+    pub async fn ack(&mut self, room: &str, seq: u64) -> Result<()> {
+        self.send_frame(&ClientFrame::Ack {
+            room: room.to_string(),
+            seq,
+        })
+        .await
+    }
+
+    /// Returns the session token the server last issued, e.g. to save for
+    /// resuming from another process.
+    pub fn resume_token(&self) -> Option<&str> {
+        self.resume_token.as_deref()
+    }
+
+    /// Returns the most recently received messages, oldest first.
+    pub fn cached_messages(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.cache.iter()
     }
 
     /// Sends a `ChatMessage` to the server.
@@ -40,19 +393,315 @@ impl Client {
     /// A `Result` indicating success or failure.
     pub async fn send(&mut self, message: ChatMessage) -> Result<()> {
         let json = message.to_json()?;
-        self.stream.write_all(json.as_bytes()).await?;
-        self.stream.write_all(b"\n").await?; // Delimit with newline
+        self.write_line(&json).await?;
         info!("Sent: {}", json);
         Ok(())
     }
 
-    /// Receives a message from the server.
+    /// Sends a control frame to the server.
+    ///
+    /// # Arguments
+    /// - `frame`: The `ClientFrame` to send.
     ///
     /// # Returns
-    /// A `Result` containing the received string or an error.
-    pub async fn receive(&mut self) -> Result<String> {
+    /// A `Result` indicating success or failure.
+    pub async fn send_frame(&mut self, frame: &ClientFrame) -> Result<()> {
+        let json = frame.to_json()?;
+        self.write_line(&json).await?;
+        info!("Sent: {}", json);
+        Ok(())
+    }
+
+    /// Updates this client's profile; `None` fields are left unchanged.
+    ///
+    /// The server broadcasts the result to everyone as a `PresenceChanged` event.
+    pub async fn set_profile(
+        &mut self,
+        display_name: Option<String>,
+        status_text: Option<String>,
+        state: Option<PresenceState>,
+    ) -> Result<()> {
+        self.send_frame(&ClientFrame::SetProfile {
+            display_name,
+            status_text,
+            state,
+        })
+        .await
+    }
+
+    /// Requests a page of a room's history older than `before`; the server
+    /// answers with a `ServerFrame::History`.
+    pub async fn fetch_history(
+        &mut self,
+        room: Option<String>,
+        before: Option<MessageId>,
+        limit: Option<usize>,
+    ) -> Result<()> {
+        self.send_frame(&ClientFrame::FetchHistory {
+            room,
+            before,
+            limit,
+        })
+        .await
+    }
+
+    /// Authenticates as `user`, e.g. to upgrade a guest session. The server
+    /// answers with `ServerFrame::Authenticated` or an error.
+    pub async fn authenticate(&mut self, user: &str, token: &str) -> Result<()> {
+        self.send_frame(&ClientFrame::Authenticate {
+            user: user.to_string(),
+            token: token.to_string(),
+        })
+        .await
+    }
+
+    /// Signs in with an API key issued to a service account or bot. The
+    /// server answers with `ServerFrame::Authenticated` or an error.
+    pub async fn authenticate_with_api_key(&mut self, key: &str) -> Result<()> {
+        self.send_frame(&ClientFrame::ApiKey {
+            key: key.to_string(),
+        })
+        .await
+    }
+
+    /// Solves a `ServerFrame::Challenge` off the async runtime and sends
+    /// the solution. The server answers with
+    /// `ServerFrame::ChallengeAccepted`.
+    pub async fn answer_challenge(&mut self, nonce: &str, difficulty: u8) -> Result<()> {
+        let challenge = Challenge {
+            nonce: nonce.to_string(),
+            difficulty,
+        };
+        let solution = tokio::task::spawn_blocking(move || challenge.solve()).await?;
+        self.send_frame(&ClientFrame::ChallengeResponse { solution })
+            .await
+    }
+
+    /// Receives the next frame from the server, waiting until a whole
+    /// frame has arrived however many reads that takes.
+    ///
+    /// # Returns
+    /// A `Result` containing the `ServerFrame` or an error if the connection
+    /// closes first.
+    pub async fn receive(&mut self) -> Result<ServerFrame> {
         let mut buffer = [0; 1024];
-        let n = self.stream.read(&mut buffer).await?;
-        Ok(String::from_utf8_lossy(&buffer[..n]).to_string())
+        loop {
+            if let Some(frame) = self.decoder.decode::<ServerFrame>()? {
+                let mut gap = None;
+                match &frame {
+                    ServerFrame::Message { message, .. } => {
+                        gap = self.track_seq(message);
+                        self.observe(message);
+                    }
+                    ServerFrame::Replay { messages, .. } => {
+                        for message in messages {
+                            self.track_seq(message);
+                            self.observe(message);
+                        }
+                    }
+                    ServerFrame::Backfill { messages, .. } | ServerFrame::Unacked { messages } => {
+                        for message in messages {
+                            self.track_seq(message);
+                        }
+                    }
+                    ServerFrame::Welcome { resume_token, .. }
+                    | ServerFrame::Authenticated { resume_token, .. }
+                    | ServerFrame::SessionResumed { resume_token, .. } => {
+                        self.resume_token = Some(resume_token.clone())
+                    }
+                    ServerFrame::CapabilitiesSelected { capabilities } => {
+                        self.decoder
+                            .set_format(WireFormat::negotiated(capabilities));
+                    }
+                    _ => {}
+                }
+                if let Some((room, from_seq, to_seq)) = gap.filter(|_| self.options.backfill_gaps) {
+                    debug!("Backfilling {} {}..={}", room, from_seq, to_seq);
+                    self.backfill(&room, from_seq, to_seq).await?;
+                }
+                return Ok(frame);
+            }
+            let n = self.read_chunk(&mut buffer).await?;
+            if n == 0 {
+                return Err(anyhow!("Connection closed"));
+            }
+            self.decoder.extend(&buffer[..n]);
+        }
+    }
+
+    /// Receives the next control frame from the server, skipping relayed
+    /// chat messages.
+    pub async fn receive_frame(&mut self) -> Result<ServerFrame> {
+        loop {
+            match self.receive().await? {
+                ServerFrame::Message { .. } => continue,
+                frame => return Ok(frame),
+            }
+        }
+    }
+
+    /// Discards whatever the server has sent without waiting, returning
+    /// whether the connection is still open. Used by `ClientPool`, whose
+    /// connections only publish.
+    pub(crate) fn discard_incoming(&mut self) -> bool {
+        self.decoder.clear();
+        let mut buffer = [0; 1024];
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            let mut read = ReadBuf::new(&mut buffer);
+            match Pin::new(&mut self.stream).poll_read(&mut cx, &mut read) {
+                Poll::Ready(Ok(())) if read.filled().is_empty() => return false,
+                Poll::Ready(Ok(())) => continue,
+                Poll::Ready(Err(_)) => return false,
+                Poll::Pending => return true,
+            }
+        }
+    }
+
+    async fn write_line(&mut self, json: &str) -> Result<()> {
+        let stream = &mut self.stream;
+        let write = async {
+            stream.write_all(json.as_bytes()).await?;
+            stream.write_all(b"\n").await // Delimit with newline
+        };
+        let result = with_timeout(self.options.send_timeout, "sending", write).await;
+        self.report(result)
+    }
+
+    /// Reads into `buffer`, reporting errors and disconnects to `events`.
+    async fn read_chunk(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        let read = self.stream.read(buffer);
+        let result = with_timeout(self.options.receive_timeout, "receiving", read).await;
+        let n = self.report(result)?;
+        if n == 0 {
+            info!("Disconnected from {}", self.addr);
+            if let Some(events) = &self.events {
+                events.on_disconnected(&self.addr);
+            }
+        }
+        Ok(n)
+    }
+
+    /// Passes errors to `on_error` before returning them.
+    fn report<T>(&self, result: Result<T>) -> Result<T> {
+        if let (Err(e), Some(events)) = (&result, &self.events) {
+            events.on_error(&self.addr, e);
+        }
+        result
+    }
+
+    /// Records a message's sequence number, returning the range skipped
+    /// since the last one seen in its room.
+    fn track_seq(&mut self, message: &ChatMessage) -> Option<(String, u64, u64)> {
+        let seq = message.seq?;
+        let last = self
+            .room_seqs
+            .entry(message.room().to_string())
+            .or_default();
+        let previous = std::mem::replace(last, seq.max(*last));
+        (previous > 0 && seq > previous + 1)
+            .then(|| (message.room().to_string(), previous + 1, seq - 1))
+    }
+
+    /// Caches a received message unless it has been seen already.
+    fn observe(&mut self, message: &ChatMessage) {
+        let Some(id) = message.id else {
+            return;
+        };
+        if self.last_id.is_some_and(|last_id| id <= last_id) {
+            return;
+        }
+        self.last_id = Some(id);
+        self.cache.push_back(message.clone());
+        if self.cache.len() > MESSAGE_CACHE_CAPACITY {
+            self.cache.pop_front();
+        }
+    }
+}
+
+async fn open_stream(target: &Target, options: &ClientOptions) -> Result<BoxStream> {
+    let connect = async {
+        let addrs = match target {
+            Target::Host(host) => lookup_host(host.as_str()).await?.collect(),
+            Target::Resolved(addrs) => addrs.clone(),
+            #[cfg(feature = "simulation")]
+            Target::Simulated(host) => {
+                let stream = turmoil::net::TcpStream::connect(host.as_str()).await?;
+                return Ok(Box::new(stream) as BoxStream);
+            }
+        };
+        let stream = race(addrs).await?;
+        stream.set_nodelay(options.nodelay)?;
+        if let Some(idle) = options.keepalive {
+            SockRef::from(&stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(idle))?;
+        }
+        Ok(Box::new(stream) as BoxStream)
+    };
+    with_timeout(options.connect_timeout, "connecting", connect).await
+}
+
+/// Connects to whichever of `addrs` answers first, happy-eyeballs style
+/// (RFC 8305): IPv6 and IPv4 addresses are tried alternately, each attempt
+/// getting `ATTEMPT_DELAY` to itself, or until it fails, before the next
+/// starts alongside it. The rest are dropped once one connects; if none
+/// do, the last failure is returned.
+async fn race(addrs: Vec<SocketAddr>) -> std::io::Result<TcpStream> {
+    let mut pending = interleave(addrs);
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+    if let Some(addr) = pending.pop_front() {
+        attempts.spawn(TcpStream::connect(addr));
+    }
+    while !attempts.is_empty() {
+        tokio::select! {
+            Some(attempt) = attempts.join_next() => match attempt {
+                Ok(Ok(stream)) => return Ok(stream),
+                Ok(Err(e)) => {
+                    debug!("Connection attempt failed: {}", e);
+                    last_error = Some(e);
+                }
+                Err(e) => last_error = Some(std::io::Error::other(e)),
+            },
+            _ = tokio::time::sleep(ATTEMPT_DELAY), if !pending.is_empty() => {}
+        }
+        if let Some(addr) = pending.pop_front() {
+            attempts.spawn(TcpStream::connect(addr));
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "The address resolved to nothing",
+        )
+    }))
+}
+
+/// Orders `addrs` alternating between address families, starting with the
+/// family of the first, and otherwise keeping the resolver's order.
+fn interleave(addrs: Vec<SocketAddr>) -> VecDeque<SocketAddr> {
+    let prefer_v6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut preferred, mut other): (VecDeque<_>, VecDeque<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == prefer_v6);
+    let mut ordered = VecDeque::new();
+    while !preferred.is_empty() || !other.is_empty() {
+        ordered.extend(preferred.pop_front());
+        ordered.extend(other.pop_front());
+    }
+    ordered
+}
+
+/// Runs `io`, failing after `timeout` if one is set.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    action: &str,
+    io: impl Future<Output = std::io::Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, io)
+            .await
+            .map_err(|_| anyhow!("Timed out {} after {:?}", action, timeout))?
+            .map_err(Into::into),
+        None => io.await.map_err(Into::into),
     }
 }
@@ -0,0 +1,846 @@
+use crate::protocol::{Capability, ServerFrame};
+use bytes::{Buf, Bytes, BytesMut};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// Why a line could not be decoded.
+#[derive(Debug)]
+pub enum CodecError {
+    /// A line grew past the decoder's limit without a newline.
+    TooLong { max: usize },
+    /// A line was not valid UTF-8.
+    InvalidUtf8,
+    /// A line was not valid JSON for the expected type.
+    Json(serde_json::Error),
+    /// A MessagePack frame was not valid for the expected type.
+    MessagePack(rmp_serde::decode::Error),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::TooLong { max } => write!(f, "Line exceeds {} bytes", max),
+            CodecError::InvalidUtf8 => write!(f, "Line is not valid UTF-8"),
+            CodecError::Json(e) => write!(f, "Invalid frame: {}", e),
+            CodecError::MessagePack(e) => write!(f, "Invalid frame: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// How server frames are written to a connection. Client frames are
+/// always JSON lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WireFormat {
+    /// One JSON object per line.
+    #[default]
+    Json,
+    /// A MessagePack map with named fields, after its length as a
+    /// big-endian `u32`. Negotiated with `Capability::MessagePack`.
+    MessagePack,
+}
+
+impl WireFormat {
+    /// Encodes `value` as one frame in this format.
+    pub fn encode<T: Serialize>(self, value: &T) -> anyhow::Result<Bytes> {
+        match self {
+            WireFormat::Json => Ok(Bytes::from(format!("{}\n", serde_json::to_string(value)?))),
+            WireFormat::MessagePack => {
+                let body = rmp_serde::to_vec_named(value)?;
+                let mut frame = Vec::with_capacity(4 + body.len());
+                frame.extend_from_slice(&u32::try_from(body.len())?.to_be_bytes());
+                frame.extend_from_slice(&body);
+                Ok(Bytes::from(frame))
+            }
+        }
+    }
+}
+
+impl WireFormat {
+    /// The format a client that negotiated `capabilities` is sent frames
+    /// in.
+    pub fn negotiated(capabilities: &BTreeSet<Capability>) -> Self {
+        if capabilities.contains(&Capability::MessagePack) {
+            WireFormat::MessagePack
+        } else {
+            WireFormat::Json
+        }
+    }
+
+    /// Renders a frame encoded in either format as JSON, for recordings.
+    /// JSON lines start with `{`, MessagePack frames with their length.
+    pub fn to_json(frame: &[u8]) -> String {
+        if frame.first() == Some(&b'{') {
+            return String::from_utf8_lossy(frame).into_owned();
+        }
+        frame
+            .get(4..)
+            .and_then(|body| rmp_serde::from_slice::<serde_json::Value>(body).ok())
+            .map_or_else(String::new, |value| format!("{}\n", value))
+    }
+}
+
+/// A server frame and its encodings, each made the first time a
+/// connection using that format needs it, so a broadcast is serialized
+/// once per wire format rather than once per subscriber.
+#[derive(Debug)]
+pub struct Encoded {
+    frame: ServerFrame,
+    json: Bytes,
+    message_pack: OnceLock<Option<Bytes>>,
+}
+
+impl Encoded {
+    /// Encodes `frame` as JSON, which nearly every connection uses.
+    pub fn new(frame: ServerFrame) -> anyhow::Result<Self> {
+        Ok(Encoded {
+            json: WireFormat::Json.encode(&frame)?,
+            frame,
+            message_pack: OnceLock::new(),
+        })
+    }
+
+    /// The frame as a JSON line, without its newline, for logs.
+    pub fn json(&self) -> &str {
+        std::str::from_utf8(&self.json)
+            .unwrap_or_default()
+            .trim_end()
+    }
+
+    /// The frame in `format`, or `None` if it can't be encoded in it.
+    pub fn get(&self, format: WireFormat) -> Option<Bytes> {
+        match format {
+            WireFormat::Json => Some(self.json.clone()),
+            WireFormat::MessagePack => self
+                .message_pack
+                .get_or_init(|| match format.encode(&self.frame) {
+                    Ok(frame) => Some(frame),
+                    Err(e) => {
+                        tracing::error!("Failed to encode {}: {}", self.json(), e);
+                        None
+                    }
+                })
+                .clone(),
+        }
+    }
+}
+
+/// Splits a byte stream into newline-delimited frames, however the bytes
+/// were split across reads. Shared by the client and server.
+///
+/// Never holds more than `max_len` bytes of an unfinished line, and
+/// recovers from errors: a bad line is dropped and decoding carries on
+/// with the next one.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    buffer: BytesMut,
+    max_len: usize,
+    /// Leading bytes of `buffer` already searched for a newline.
+    scanned: usize,
+    /// Set after rejecting an over-long line, until its newline arrives.
+    skipping: bool,
+    /// What `decode` expects; see `set_format`.
+    format: WireFormat,
+}
+
+impl FrameDecoder {
+    /// Creates a decoder that rejects lines longer than `max_len` bytes.
+    pub fn new(max_len: usize) -> Self {
+        FrameDecoder {
+            buffer: BytesMut::new(),
+            max_len,
+            scanned: 0,
+            skipping: false,
+            format: WireFormat::Json,
+        }
+    }
+
+    /// Makes `decode` expect frames in `format` from here on, as a client
+    /// does once the server has agreed to MessagePack.
+    pub fn set_format(&mut self, format: WireFormat) {
+        self.format = format;
+    }
+
+    /// Appends bytes read from the stream.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Whether no bytes are buffered.
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Discards any buffered partial line, and goes back to JSON for a new
+    /// connection.
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.scanned = 0;
+        self.skipping = false;
+        self.format = WireFormat::Json;
+    }
+
+    /// Returns the next complete, non-blank line without its newline, or
+    /// `None` if more bytes are needed. After an error the offending line
+    /// is gone, so the caller may keep decoding.
+    pub fn next_line(&mut self) -> Result<Option<String>, CodecError> {
+        loop {
+            let Some(offset) = self.buffer[self.scanned..].iter().position(|&b| b == b'\n') else {
+                self.scanned = self.buffer.len();
+                if self.skipping {
+                    self.buffer.clear();
+                    self.scanned = 0;
+                } else if self.buffer.len() > self.max_len {
+                    self.buffer.clear();
+                    self.scanned = 0;
+                    self.skipping = true;
+                    return Err(CodecError::TooLong { max: self.max_len });
+                }
+                return Ok(None);
+            };
+            let end = self.scanned + offset;
+            let line = self.buffer.split_to(end + 1);
+            self.scanned = 0;
+            if std::mem::take(&mut self.skipping) {
+                continue;
+            }
+            if end > self.max_len {
+                return Err(CodecError::TooLong { max: self.max_len });
+            }
+            let line = std::str::from_utf8(&line).map_err(|_| CodecError::InvalidUtf8)?;
+            let line = line.trim();
+            if !line.is_empty() {
+                return Ok(Some(line.to_string()));
+            }
+        }
+    }
+
+    /// Decodes the next complete frame in the decoder's format, or returns
+    /// `None` if more bytes are needed.
+    pub fn decode<T: DeserializeOwned>(&mut self) -> Result<Option<T>, CodecError> {
+        if self.format == WireFormat::MessagePack {
+            return self.decode_message_pack();
+        }
+        match self.next_line()? {
+            Some(line) => serde_json::from_str(&line)
+                .map(Some)
+                .map_err(CodecError::Json),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_message_pack<T: DeserializeOwned>(&mut self) -> Result<Option<T>, CodecError> {
+        let Some(prefix) = self.buffer.first_chunk::<4>() else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(*prefix) as usize;
+        if len > self.max_len {
+            // Without delimiters there's nothing to resynchronize on.
+            self.buffer.clear();
+            return Err(CodecError::TooLong { max: self.max_len });
+        }
+        if self.buffer.len() < 4 + len {
+            return Ok(None);
+        }
+        self.buffer.advance(4);
+        let frame = self.buffer.split_to(len);
+        rmp_serde::from_slice(&frame)
+            .map(Some)
+            .map_err(CodecError::MessagePack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::apikey::KeyScope;
+    use crate::invite::Invite;
+    use crate::protocol::{
+        ActivityLevel, AnnouncementLevel, Capability, ChatMessage, ClientFrame,
+        ConnectionDebugInfo, CustomEmoji, DigestFrequency, Draft, EmailDigest, Entity, EntityKind,
+        FileRef, Forwarded, LinkPreview, NotificationPrefs, PresenceState, PresenceSubscription,
+        Profile, PushPlatform, PushToken, QuietHours, RoomListing, SavedMessage, SearchHit,
+        ServerFrame, TextFormat, UserPresence,
+    };
+    use crate::quota::{QuotaResource, QuotaUsage, QuotaWindow, Resource};
+    use crate::room::RoomConfig;
+    use proptest::prelude::*;
+    use serde::Serialize;
+
+    /// Feeds `bytes` to `decoder` in pieces of the sizes in `pieces`, taken
+    /// in turn, returning every frame decoded and how many lines were
+    /// rejected.
+    fn decode_in_pieces<T: DeserializeOwned>(
+        decoder: &mut FrameDecoder,
+        bytes: &[u8],
+        pieces: &[usize],
+    ) -> (Vec<T>, usize) {
+        let (mut frames, mut rejected) = (Vec::new(), 0);
+        let mut rest = bytes;
+        for &size in pieces.iter().cycle() {
+            if rest.is_empty() {
+                break;
+            }
+            let (piece, tail) = rest.split_at(size.min(rest.len()));
+            rest = tail;
+            decoder.extend(piece);
+            loop {
+                match decoder.decode() {
+                    Ok(Some(frame)) => frames.push(frame),
+                    Ok(None) => break,
+                    Err(_) => rejected += 1,
+                }
+            }
+            assert!(decoder.buffer.len() <= decoder.max_len);
+        }
+        (frames, rejected)
+    }
+
+    fn assert_round_trips<T>(frames: &[T], pieces: &[usize])
+    where
+        T: Serialize + DeserializeOwned + PartialEq + fmt::Debug,
+    {
+        let mut wire = Vec::new();
+        for frame in frames {
+            let json = serde_json::to_string(frame).unwrap();
+            assert!(!json.contains('\n'), "frame spans lines: {}", json);
+            wire.extend_from_slice(json.as_bytes());
+            wire.push(b'\n');
+        }
+        let mut decoder = FrameDecoder::new(MAX_LEN);
+        let (decoded, rejected) = decode_in_pieces::<T>(&mut decoder, &wire, pieces);
+        assert_eq!((decoded.as_slice(), rejected), (frames, 0));
+
+        let mut decoder = FrameDecoder::new(MAX_LEN);
+        decoder.set_format(WireFormat::MessagePack);
+        for frame in frames {
+            decoder.extend(&WireFormat::MessagePack.encode(frame).unwrap());
+        }
+        for frame in frames {
+            assert_eq!(decoder.decode::<T>().unwrap().as_ref(), Some(frame));
+        }
+        assert!(decoder.is_empty());
+    }
+
+    /// Longer than any frame the round trips build.
+    const MAX_LEN: usize = 1 << 20;
+
+    /// Strings that have broken line-based protocols before.
+    const AWKWARD: [&str; 5] = [
+        "line\nbreak\r\n",
+        "quote \" and \\ backslash",
+        "unicode \u{e9} \u{1f980} \u{2028}",
+        "  padded  ",
+        "",
+    ];
+
+    /// Mostly JSON punctuation and newlines, so some lines get far into
+    /// the parser before failing.
+    const ALPHABET: &[u8] = b"{}[]\":,\\\n\n\n tyeMsag0123\xff\xc3\x80";
+
+    /// One of `AWKWARD`, or any string at all.
+    fn text() -> impl Strategy<Value = String> {
+        prop_oneof![
+            prop::sample::select(AWKWARD.to_vec()).prop_map(str::to_string),
+            any::<String>(),
+        ]
+    }
+
+    /// The sizes of the reads a stream arrives in, taken in turn.
+    fn pieces() -> impl Strategy<Value = Vec<usize>> {
+        prop::collection::vec(1..=64usize, 1..16)
+    }
+
+    fn message(content: String) -> ChatMessage {
+        ChatMessage {
+            sender: "avery".to_string(),
+            content,
+            id: Some(7),
+            room: Some("lobby".to_string()),
+            ttl_secs: None,
+            send_at: Some(1_700_000_000),
+            timestamp: Some(1_700_000_001),
+            seq: Some(3),
+            format: TextFormat::Markdown,
+            entities: vec![Entity {
+                offset: 0,
+                length: 1,
+                kind: EntityKind::CodeBlock {
+                    language: Some("rust".to_string()),
+                },
+            }],
+            forwarded: Some(Forwarded {
+                message_id: 2,
+                room: "ops".to_string(),
+                sender: "blake".to_string(),
+                timestamp: None,
+            }),
+            attachment: Some(FileRef {
+                id: "ab".repeat(32),
+                name: "notes.txt".to_string(),
+                size: 3,
+            }),
+        }
+    }
+
+    fn notification_prefs(muted_room: String) -> NotificationPrefs {
+        NotificationPrefs {
+            quiet_hours: Some(QuietHours {
+                start: 22 * 60,
+                end: 7 * 60,
+                utc_offset: -300,
+            }),
+            mentions_break_quiet_hours: true,
+            muted_rooms: [muted_room].into(),
+            mention_only: false,
+            email_digest: Some(EmailDigest {
+                address: "avery@example.com".to_string(),
+                frequency: DigestFrequency::Daily,
+            }),
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn test_arbitrary_bytes_never_panic(
+            bytes in prop::collection::vec(
+                prop_oneof![7 => prop::sample::select(ALPHABET), 1 => any::<u8>()],
+                0..1024,
+            ),
+            pieces in pieces(),
+        ) {
+            let mut decoder = FrameDecoder::new(64);
+            decode_in_pieces::<ClientFrame>(&mut decoder, &bytes, &pieces);
+            decode_in_pieces::<ServerFrame>(&mut decoder, &bytes, &pieces);
+        }
+
+        #[test]
+        fn test_client_frames_round_trip(
+            texts in prop::collection::vec(text(), 1..8),
+            capabilities in prop::sample::subsequence(Capability::ALL.to_vec(), 0..=4),
+            pieces in pieces(),
+        ) {
+            let mut texts = texts.into_iter().cycle();
+            let mut text = || texts.next().unwrap();
+            let frames = [
+                ClientFrame::SetProfile {
+                    display_name: Some(text()),
+                    status_text: None,
+                    state: Some(PresenceState::Dnd),
+                },
+                ClientFrame::Presence,
+                ClientFrame::SubscribePresence {
+                    users: vec![text()],
+                    rooms: vec![text(), text()],
+                },
+                ClientFrame::UnsubscribePresence {
+                    users: Vec::new(),
+                    rooms: vec![text()],
+                },
+                ClientFrame::FileOffer {
+                    name: text(),
+                    size: u64::MAX,
+                    hash: "00".repeat(32),
+                    room: Some(text()),
+                },
+                ClientFrame::FileChunk {
+                    transfer_id: 1,
+                    data: "aGk=".to_string(),
+                },
+                ClientFrame::FetchFile { id: text() },
+                ClientFrame::FileReceived {
+                    id: text(),
+                    received: u64::MAX,
+                },
+                ClientFrame::CreateInvite {
+                    room: text(),
+                    expires_in_secs: Some(3600),
+                    max_uses: None,
+                },
+                ClientFrame::RedeemInvite { token: text() },
+                ClientFrame::Forward {
+                    message_id: 7,
+                    to_room: text(),
+                },
+                ClientFrame::ListRooms {
+                    filter: Some(text()),
+                    page: 2,
+                },
+                ClientFrame::ListMembers {
+                    room: text(),
+                    after: Some(text()),
+                    limit: None,
+                },
+                ClientFrame::FetchHistory {
+                    room: Some(text()),
+                    before: Some(9),
+                    limit: Some(50),
+                },
+                ClientFrame::Resume { last_id: 12 },
+                ClientFrame::Search {
+                    query: text(),
+                    room: None,
+                    limit: None,
+                    before: Some(3),
+                },
+                ClientFrame::CancelScheduled { pending_id: 4 },
+                ClientFrame::Authenticate {
+                    user: text(),
+                    token: text(),
+                },
+                ClientFrame::ApiKey { key: text() },
+                ClientFrame::ChallengeResponse {
+                    solution: text(),
+                },
+                ClientFrame::SelectTenant { tenant: text() },
+                ClientFrame::SetLocale {
+                    locales: vec![text(), text()],
+                },
+                ClientFrame::SetCapabilities {
+                    capabilities: capabilities.iter().copied().collect(),
+                },
+                ClientFrame::SetNotificationPrefs {
+                    prefs: notification_prefs(text()),
+                },
+                ClientFrame::SetDraft {
+                    room: text(),
+                    content: text(),
+                },
+                ClientFrame::ClearDraft { room: text() },
+                ClientFrame::GetDrafts,
+                ClientFrame::SaveMessage { message_id: 7 },
+                ClientFrame::UnsaveMessage { message_id: 7 },
+                ClientFrame::ListSaved,
+                ClientFrame::RegisterPushToken {
+                    push: PushToken {
+                        platform: PushPlatform::WebPush,
+                        token: text(),
+                    },
+                },
+                ClientFrame::UnregisterPushToken { token: text() },
+                ClientFrame::ResumeSession {
+                    token: text(),
+                    last_id: None,
+                },
+                ClientFrame::Backfill {
+                    room: text(),
+                    from_seq: 1,
+                    to_seq: u64::MAX,
+                },
+                ClientFrame::Ack {
+                    room: text(),
+                    seq: 5,
+                },
+                ClientFrame::Heartbeat,
+                ClientFrame::ListEmoji,
+                ClientFrame::DebugInfo,
+            ];
+            assert_round_trips(&frames, &pieces);
+        }
+
+        #[test]
+        fn test_server_frames_round_trip(
+            texts in prop::collection::vec(text(), 1..8),
+            capabilities in prop::sample::subsequence(Capability::ALL.to_vec(), 0..=4),
+            pieces in pieces(),
+        ) {
+            let mut texts = texts.into_iter().cycle();
+            let mut text = || texts.next().unwrap();
+            let file = FileRef {
+                id: "cd".repeat(32),
+                name: text(),
+                size: 7,
+            };
+            let presence = UserPresence {
+                user: text(),
+                profile: Profile {
+                    display_name: Some(text()),
+                    status_text: Some(text()),
+                    state: PresenceState::Away,
+                },
+                devices: 2,
+            };
+            let config = RoomConfig {
+                owner: Some("avery".to_string()),
+                topic: Some(text()),
+                pinned: vec![1, 2],
+                max_members: Some(10),
+                ..Default::default()
+            };
+            let frames = [
+                ServerFrame::Challenge {
+                    nonce: text(),
+                    difficulty: u8::MAX,
+                },
+                ServerFrame::ChallengeAccepted,
+                ServerFrame::Welcome {
+                    user: text(),
+                    guest: true,
+                    resume_token: text(),
+                },
+                ServerFrame::Authenticated {
+                    user: text(),
+                    resume_token: text(),
+                    notifications: notification_prefs(text()),
+                },
+                ServerFrame::LocaleSelected { locale: text() },
+                ServerFrame::CapabilitiesSelected {
+                    capabilities: capabilities.iter().copied().collect(),
+                },
+                ServerFrame::NotificationPrefs {
+                    prefs: NotificationPrefs::default(),
+                },
+                ServerFrame::Draft {
+                    room: text(),
+                    draft: None,
+                },
+                ServerFrame::Drafts {
+                    drafts: [(
+                        text(),
+                        Draft {
+                            content: text(),
+                            updated_at: 1_700_000_000,
+                        },
+                    )]
+                    .into(),
+                },
+                ServerFrame::MessageSaved { message_id: 7 },
+                ServerFrame::MessageUnsaved { message_id: 7 },
+                ServerFrame::Saved {
+                    messages: vec![SavedMessage {
+                        message: message(text()),
+                        saved_at: 1_700_000_000,
+                    }],
+                },
+                ServerFrame::NicknameConflict {
+                    nick: text(),
+                    conflicts_with: text(),
+                },
+                ServerFrame::SessionResumed {
+                    user: text(),
+                    resume_token: text(),
+                    notifications: NotificationPrefs::default(),
+                },
+                ServerFrame::Message {
+                    from: "127.0.0.1:9000".to_string(),
+                    message: message(text()),
+                },
+                ServerFrame::EmojiList {
+                    emoji: vec![CustomEmoji {
+                        name: text(),
+                        blob_id: "ab".repeat(32),
+                    }],
+                },
+                ServerFrame::LinkPreview {
+                    room: "lobby".to_string(),
+                    message_id: 7,
+                    preview: LinkPreview {
+                        url: "http://example.com/".to_string(),
+                        title: Some(text()),
+                        description: None,
+                        image: Some("http://example.com/logo.png".to_string()),
+                    },
+                },
+                ServerFrame::Presence {
+                    users: vec![presence.clone()],
+                },
+                ServerFrame::PresenceSubscribed {
+                    subscription: Some(PresenceSubscription {
+                        users: [text()].into(),
+                        rooms: [text()].into(),
+                    }),
+                    users: vec![presence.clone()],
+                },
+                ServerFrame::PresenceSubscribed {
+                    subscription: None,
+                    users: Vec::new(),
+                },
+                ServerFrame::PresenceChanged(presence),
+                ServerFrame::FileAccepted {
+                    transfer_id: 1,
+                    chunk_size: 65536,
+                    window: 4,
+                },
+                ServerFrame::FileProgress {
+                    transfer_id: 1,
+                    received: 10,
+                },
+                ServerFrame::FileShared {
+                    from: text(),
+                    room: text(),
+                    file,
+                },
+                ServerFrame::FileChunk {
+                    id: text(),
+                    offset: 0,
+                    data: "aGk=".to_string(),
+                },
+                ServerFrame::FileEnd {
+                    id: text(),
+                    size: 7,
+                },
+                ServerFrame::Expire { message_id: 3 },
+                ServerFrame::History {
+                    room: text(),
+                    messages: vec![message(text()), message(text())],
+                    has_more: true,
+                },
+                ServerFrame::Replay {
+                    messages: vec![message(text())],
+                    complete: false,
+                },
+                ServerFrame::Backfill {
+                    room: text(),
+                    messages: Vec::new(),
+                    complete: true,
+                },
+                ServerFrame::Unacked {
+                    messages: vec![message(text())],
+                },
+                ServerFrame::SearchResults {
+                    hits: vec![SearchHit {
+                        message: message(text()),
+                        snippet: text(),
+                    }],
+                    next_cursor: Some(1),
+                },
+                ServerFrame::Scheduled {
+                    pending_id: 1,
+                    send_at: 1_700_000_000,
+                },
+                ServerFrame::ScheduleCancelled { pending_id: 1 },
+                ServerFrame::QuotaExceeded {
+                    window: QuotaWindow::Daily,
+                    resource: QuotaResource::Bytes,
+                    limit: 1024,
+                    resets_at: 86400,
+                },
+                ServerFrame::ResourceExceeded {
+                    resource: Resource::HistoryBytes,
+                    room: Some(text()),
+                    limit: 4096,
+                },
+                ServerFrame::ReadOnlyRoom { room: text() },
+                ServerFrame::RoomFull {
+                    room: text(),
+                    max_members: 10,
+                    waitlist_position: Some(1),
+                },
+                ServerFrame::WaitlistAdmitted {
+                    room: text(),
+                    user: text(),
+                },
+                ServerFrame::RoomExpired { room: text() },
+                ServerFrame::RoomUpdated {
+                    room: text(),
+                    config,
+                    by: text(),
+                    member_count: 2,
+                },
+                ServerFrame::MemberDelta {
+                    room: text(),
+                    added: vec![text()],
+                    removed: Vec::new(),
+                    member_count: 101,
+                },
+                ServerFrame::InviteCreated {
+                    invite: Invite {
+                        token: text(),
+                        room: text(),
+                        created_by: text(),
+                        created_at: 1_700_000_000,
+                        expires_at: None,
+                        max_uses: Some(5),
+                        uses: 1,
+                    },
+                },
+                ServerFrame::InviteRedeemed { room: text() },
+                ServerFrame::Rooms {
+                    rooms: vec![RoomListing {
+                        room: text(),
+                        topic: Some(text()),
+                        member_count: 12,
+                        activity: ActivityLevel::Active,
+                    }],
+                    page: 0,
+                    total: 1,
+                    has_more: false,
+                },
+                ServerFrame::Members {
+                    room: text(),
+                    members: vec![text(), text()],
+                    member_count: 2,
+                    has_more: false,
+                },
+                ServerFrame::Notice { text: text() },
+                ServerFrame::Announcement {
+                    room: Some(text()),
+                    text: text(),
+                    level: AnnouncementLevel::Warning,
+                    repeats: 2,
+                },
+                ServerFrame::Motd {
+                    room: Some(text()),
+                    text: text(),
+                },
+                ServerFrame::DebugInfo(Box::new(ConnectionDebugInfo {
+                    addr: "127.0.0.1:4000".to_string(),
+                    user: text(),
+                    guest: false,
+                    api_key_scope: Some(KeyScope {
+                        rooms: Some([text()].into()),
+                        read_only: true,
+                        admin: false,
+                    }),
+                    locale: "en".to_string(),
+                    capabilities: [Capability::Formatting].into(),
+                    profile: Profile::default(),
+                    idle: true,
+                    presence_subscription: None,
+                    rooms: vec![text()],
+                    muted_rooms: [text()].into(),
+                    blocked: vec![text()],
+                    queued_frames: 3,
+                    queued_bytes: 120,
+                    queue_capacity: 256,
+                    quota: QuotaUsage {
+                        user: text(),
+                        messages_this_hour: 2,
+                        ..Default::default()
+                    },
+                })),
+                ServerFrame::Error { message: text() },
+            ];
+            assert_round_trips(&frames, &pieces);
+        }
+    }
+
+    #[test]
+    fn test_frames_split_and_batched_across_reads() {
+        let mut decoder = FrameDecoder::new(1024);
+        decoder.extend(b"{\"type\":\"Expire\",\"message_");
+        assert!(decoder.decode::<ServerFrame>().unwrap().is_none());
+        decoder.extend(b"id\":1}\n\n{\"type\":\"Expire\",\"message_id\":2}\n");
+        for expected in [1, 2] {
+            let frame = decoder.decode::<ServerFrame>().unwrap().unwrap();
+            assert!(matches!(frame, ServerFrame::Expire { message_id } if message_id == expected));
+        }
+        assert!(decoder.decode::<ServerFrame>().unwrap().is_none());
+
+        decoder.extend(b"\xff\xfe\n");
+        assert!(matches!(decoder.next_line(), Err(CodecError::InvalidUtf8)));
+        decoder.extend(&[b'a'; 1025]);
+        assert!(matches!(
+            decoder.next_line(),
+            Err(CodecError::TooLong { .. })
+        ));
+        // The rest of the long line is skipped, not decoded.
+        decoder.extend(b"aaaa\n{\"type\":\"Expire\",\"message_id\":3}\n");
+        let frame = decoder.decode::<ServerFrame>().unwrap().unwrap();
+        assert!(matches!(frame, ServerFrame::Expire { message_id: 3 }));
+    }
+}
@@ -0,0 +1,265 @@
+use crate::protocol::MessageId;
+use crate::room::{DeliveryMode, Role, RoomAction};
+use std::fmt;
+
+const PIN_USAGE: &str = "/pin <room> <message-id>";
+const LIMIT_USAGE: &str = "/limit <room> <max-members|off>";
+const DELIVERY_USAGE: &str = "/delivery <room> <best-effort|at-least-once>";
+const EXPIRE_USAGE: &str = "/expire <room> <idle|after> <seconds> or /expire <room> off";
+const PERM_USAGE: &str =
+    "/perm <room> <read|post|invite|topic|pin> <everyone|members|moderators|owner>";
+
+/// Slash commands a client can type in place of a chat message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `/register <nick> <password>` claims a nickname.
+    Register { nick: String, password: String },
+    /// `/identify <nick> <password>` signs in as a registered nickname.
+    Identify { nick: String, password: String },
+    /// `/create <room>` creates a room owned by the sender.
+    Create { room: String },
+    /// `/invite <user> <room>` makes `user` a member of `room`.
+    Invite { user: String, room: String },
+    /// `/join <room>` joins a room as a member, or its waitlist if full.
+    /// Needs the room's invite permission.
+    Join { room: String },
+    /// `/leave <room>` gives up membership or a waitlist place.
+    Leave { room: String },
+    /// `/topic <room> <topic...>` sets a room's topic.
+    Topic { room: String, topic: String },
+    /// `/pin <room> <message-id>` pins a message in a room.
+    Pin { room: String, message_id: MessageId },
+    /// `/perm <room> <read|post|invite|topic|pin> <role>` sets the role an
+    /// action requires; owners only.
+    Permission {
+        room: String,
+        action: RoomAction,
+        role: Role,
+    },
+    /// `/announce <room> <on|off>` makes a room read-only for everyone
+    /// below moderator; owners only.
+    Announce { room: String, enabled: bool },
+    /// `/limit <room> <max|off>` caps a room's members; owners only.
+    Limit {
+        room: String,
+        max_members: Option<usize>,
+    },
+    /// `/waitlist <room> <on|off>` queues users who join a full room;
+    /// owners only.
+    Waitlist { room: String, enabled: bool },
+    /// `/delivery <room> <best-effort|at-least-once>` sets how a room's
+    /// messages are delivered; owners only.
+    Delivery { room: String, mode: DeliveryMode },
+    /// `/expire <room> idle <secs>` makes a room temporary, destroyed with
+    /// its history once nobody has posted for `secs`; `after <secs>`
+    /// destroys it that long from now regardless. Each sets one limit and
+    /// keeps the other; `/expire <room> off` makes the room permanent
+    /// again. Owners only.
+    Expire {
+        room: String,
+        idle_secs: Option<u64>,
+        ttl_secs: Option<u64>,
+    },
+    /// `/mod <room> <user>` makes `user` a moderator; owners only.
+    Moderator { room: String, user: String },
+    /// `/block [user]` hides everything `user` sends from the sender,
+    /// without telling `user`; with no user, lists who is blocked.
+    Block { user: Option<String> },
+    /// `/unblock <user>` lifts a block.
+    Unblock { user: String },
+}
+
+/// Why a slash command could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    Unknown(String),
+    /// The command's arguments were wrong; holds the expected usage.
+    Usage(&'static str),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::Unknown(name) => write!(f, "Unknown command /{}", name),
+            CommandError::Usage(usage) => write!(f, "Usage: {}", usage),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl Command {
+    /// Parses message content starting with `/`; other content isn't a
+    /// command and yields `None`.
+    pub fn parse(content: &str) -> Option<Result<Command, CommandError>> {
+        let mut words = content.strip_prefix('/')?.split_whitespace();
+        let name = words.next().unwrap_or_default();
+        let args: Vec<&str> = words.collect();
+        Some(match (name, args.as_slice()) {
+            ("register", [nick, password]) => Ok(Command::Register {
+                nick: nick.to_string(),
+                password: password.to_string(),
+            }),
+            ("register", _) => Err(CommandError::Usage("/register <nick> <password>")),
+            ("identify", [nick, password]) => Ok(Command::Identify {
+                nick: nick.to_string(),
+                password: password.to_string(),
+            }),
+            ("identify", _) => Err(CommandError::Usage("/identify <nick> <password>")),
+            ("create", [room]) => Ok(Command::Create {
+                room: room.to_string(),
+            }),
+            ("create", _) => Err(CommandError::Usage("/create <room>")),
+            ("invite", [user, room]) => Ok(Command::Invite {
+                user: user.to_string(),
+                room: room.to_string(),
+            }),
+            ("invite", _) => Err(CommandError::Usage("/invite <user> <room>")),
+            ("join", [room]) => Ok(Command::Join {
+                room: room.to_string(),
+            }),
+            ("join", _) => Err(CommandError::Usage("/join <room>")),
+            ("leave", [room]) => Ok(Command::Leave {
+                room: room.to_string(),
+            }),
+            ("leave", _) => Err(CommandError::Usage("/leave <room>")),
+            ("topic", [room, topic @ ..]) if !topic.is_empty() => Ok(Command::Topic {
+                room: room.to_string(),
+                topic: topic.join(" "),
+            }),
+            ("topic", _) => Err(CommandError::Usage("/topic <room> <topic>")),
+            ("pin", [room, id]) => match id.parse() {
+                Ok(message_id) => Ok(Command::Pin {
+                    room: room.to_string(),
+                    message_id,
+                }),
+                Err(_) => Err(CommandError::Usage(PIN_USAGE)),
+            },
+            ("pin", _) => Err(CommandError::Usage(PIN_USAGE)),
+            ("perm", [room, action, role]) => match (action.parse(), role.parse()) {
+                (Ok(action), Ok(role)) => Ok(Command::Permission {
+                    room: room.to_string(),
+                    action,
+                    role,
+                }),
+                _ => Err(CommandError::Usage(PERM_USAGE)),
+            },
+            ("perm", _) => Err(CommandError::Usage(PERM_USAGE)),
+            ("announce", [room, "on"]) => Ok(Command::Announce {
+                room: room.to_string(),
+                enabled: true,
+            }),
+            ("announce", [room, "off"]) => Ok(Command::Announce {
+                room: room.to_string(),
+                enabled: false,
+            }),
+            ("announce", _) => Err(CommandError::Usage("/announce <room> <on|off>")),
+            ("limit", [room, "off"]) => Ok(Command::Limit {
+                room: room.to_string(),
+                max_members: None,
+            }),
+            ("limit", [room, max]) => match max.parse() {
+                Ok(max) => Ok(Command::Limit {
+                    room: room.to_string(),
+                    max_members: Some(max),
+                }),
+                Err(_) => Err(CommandError::Usage(LIMIT_USAGE)),
+            },
+            ("limit", _) => Err(CommandError::Usage(LIMIT_USAGE)),
+            ("waitlist", [room, "on"]) => Ok(Command::Waitlist {
+                room: room.to_string(),
+                enabled: true,
+            }),
+            ("waitlist", [room, "off"]) => Ok(Command::Waitlist {
+                room: room.to_string(),
+                enabled: false,
+            }),
+            ("waitlist", _) => Err(CommandError::Usage("/waitlist <room> <on|off>")),
+            ("delivery", [room, mode]) => match mode.parse() {
+                Ok(mode) => Ok(Command::Delivery {
+                    room: room.to_string(),
+                    mode,
+                }),
+                Err(()) => Err(CommandError::Usage(DELIVERY_USAGE)),
+            },
+            ("delivery", _) => Err(CommandError::Usage(DELIVERY_USAGE)),
+            ("expire", [room, "off"]) => Ok(Command::Expire {
+                room: room.to_string(),
+                idle_secs: None,
+                ttl_secs: None,
+            }),
+            ("expire", [room, kind @ ("idle" | "after"), secs]) => match secs.parse() {
+                Ok(secs) => Ok(Command::Expire {
+                    room: room.to_string(),
+                    idle_secs: (*kind == "idle").then_some(secs),
+                    ttl_secs: (*kind == "after").then_some(secs),
+                }),
+                Err(_) => Err(CommandError::Usage(EXPIRE_USAGE)),
+            },
+            ("expire", _) => Err(CommandError::Usage(EXPIRE_USAGE)),
+            ("mod", [room, user]) => Ok(Command::Moderator {
+                room: room.to_string(),
+                user: user.to_string(),
+            }),
+            ("mod", _) => Err(CommandError::Usage("/mod <room> <user>")),
+            ("block", []) => Ok(Command::Block { user: None }),
+            ("block", [user]) => Ok(Command::Block {
+                user: Some(user.to_string()),
+            }),
+            ("block", _) => Err(CommandError::Usage("/block [user]")),
+            ("unblock", [user]) => Ok(Command::Unblock {
+                user: user.to_string(),
+            }),
+            ("unblock", _) => Err(CommandError::Usage("/unblock <user>")),
+            (name, _) => Err(CommandError::Unknown(name.to_string())),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Command::parse("hello"), None);
+        assert_eq!(
+            Command::parse("/identify avery hunter2"),
+            Some(Ok(Command::Identify {
+                nick: "avery".to_string(),
+                password: "hunter2".to_string(),
+            }))
+        );
+        assert!(matches!(
+            Command::parse("/register avery"),
+            Some(Err(CommandError::Usage(_)))
+        ));
+        assert_eq!(
+            Command::parse("/topic lobby Plans for the week"),
+            Some(Ok(Command::Topic {
+                room: "lobby".to_string(),
+                topic: "Plans for the week".to_string(),
+            }))
+        );
+        assert!(matches!(
+            Command::parse("/perm lobby post nobody"),
+            Some(Err(CommandError::Usage(_)))
+        ));
+        assert_eq!(
+            Command::parse("/expire huddle after 3600"),
+            Some(Ok(Command::Expire {
+                room: "huddle".to_string(),
+                idle_secs: None,
+                ttl_secs: Some(3600),
+            }))
+        );
+        assert!(matches!(
+            Command::parse("/expire huddle idle soon"),
+            Some(Err(CommandError::Usage(_)))
+        ));
+        assert!(matches!(
+            Command::parse("/shrug"),
+            Some(Err(CommandError::Unknown(name))) if name == "shrug"
+        ));
+    }
+}
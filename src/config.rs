@@ -0,0 +1,121 @@
+use crate::ChatServer;
+use crate::onboarding::Onboarding;
+use crate::protocol::check_room_name;
+use crate::room::RoomConfig;
+use crate::shortcode::Shortcodes;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+/// Settings read from a JSON file, e.g. by `chat-server serve --config`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub onboarding: Onboarding,
+    pub rooms: BTreeMap<String, DefaultRoom>,
+    /// Expands `:name:` shortcodes in messages when set, with these
+    /// (name to replacement) on top of the built-in ones.
+    pub shortcodes: Option<BTreeMap<String, String>>,
+    /// Tenants to serve alongside the server's own namespace, by name.
+    /// Each keeps its rooms and history in memory.
+    pub tenants: HashMap<String, TenantConfig>,
+    /// Users who may ask for `DebugInfo` about their connections.
+    pub debuggers: BTreeSet<String>,
+}
+
+/// Settings for one tenant.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    pub onboarding: Onboarding,
+    pub rooms: BTreeMap<String, DefaultRoom>,
+    pub shortcodes: Option<BTreeMap<String, String>>,
+}
+
+/// A room that exists from startup, with its settings alongside
+/// `auto_join`. A room saved in the snapshot keeps its saved settings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct DefaultRoom {
+    /// Whether users are made members when they arrive, as if listed in
+    /// `Onboarding::auto_join`.
+    pub auto_join: bool,
+    #[serde(flatten)]
+    pub config: RoomConfig,
+}
+
+impl Default for DefaultRoom {
+    fn default() -> Self {
+        DefaultRoom {
+            auto_join: true,
+            config: RoomConfig::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads and checks a config file.
+    pub async fn load(path: impl AsRef<Path>) -> Result<Config> {
+        let config: Config = serde_json::from_slice(&tokio::fs::read(path).await?)?;
+        validate(&config.onboarding, &config.rooms)?;
+        for tenant in config.tenants.values() {
+            validate(&tenant.onboarding, &tenant.rooms)?;
+        }
+        Ok(config)
+    }
+
+    /// Configures `server`, adding the tenants.
+    pub fn apply(self, server: ChatServer) -> ChatServer {
+        let mut server = configure(server, self.onboarding, self.rooms, self.shortcodes);
+        for (name, tenant) in self.tenants {
+            let namespace = configure(
+                ChatServer::tenant(),
+                tenant.onboarding,
+                tenant.rooms,
+                tenant.shortcodes,
+            );
+            server = server.with_tenant(name, namespace);
+        }
+        if !self.debuggers.is_empty() {
+            server = server.with_debuggers(self.debuggers);
+        }
+        server
+    }
+}
+
+/// Checks one namespace's webhooks and room names.
+fn validate(onboarding: &Onboarding, rooms: &BTreeMap<String, DefaultRoom>) -> Result<()> {
+    onboarding.validate()?;
+    for name in rooms.keys() {
+        if let Err(e) = check_room_name(name) {
+            return Err(anyhow!("Invalid room name {:?}: {}", name, e));
+        }
+    }
+    Ok(())
+}
+
+/// Adds one namespace's default rooms, onboarding and shortcodes to
+/// `server`.
+fn configure(
+    mut server: ChatServer,
+    mut onboarding: Onboarding,
+    rooms: BTreeMap<String, DefaultRoom>,
+    shortcodes: Option<BTreeMap<String, String>>,
+) -> ChatServer {
+    for (name, room) in rooms {
+        if room.auto_join && !onboarding.auto_join.contains(&name) {
+            onboarding.auto_join.push(name.clone());
+        }
+        server = server.with_room(name, room.config);
+    }
+    if let Some(custom) = shortcodes {
+        let shortcodes = custom
+            .into_iter()
+            .fold(Shortcodes::new(), |shortcodes, (name, replacement)| {
+                shortcodes.with(name, replacement)
+            });
+        server = server.with_shortcodes(shortcodes);
+    }
+    server.with_onboarding(onboarding)
+}
@@ -0,0 +1,552 @@
+use crate::codec::FrameDecoder;
+use crate::protocol::{Capability, ChatMessage, ClientFrame, MAX_CONTENT_LEN, ServerFrame};
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use serde::Serialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant, sleep, timeout_at};
+
+/// Longest server frame accepted, as for `Client`.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+/// Messages sent back to back by the ordering scenario.
+const ORDERED_MESSAGES: usize = 10;
+/// Sender of the messages scenarios post.
+const SENDER: &str = "conformance";
+
+/// How `run` talks to the server under test.
+#[derive(Debug, Clone)]
+pub struct ConformanceOptions {
+    /// How long to wait for each expected frame.
+    pub timeout: Duration,
+    /// A user and token every connection authenticates with first, for
+    /// servers that don't relay messages from anonymous connections.
+    pub credentials: Option<(String, String)>,
+}
+
+impl Default for ConformanceOptions {
+    fn default() -> Self {
+        ConformanceOptions {
+            timeout: Duration::from_secs(5),
+            credentials: None,
+        }
+    }
+}
+
+/// One scripted check of how a server speaks the protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scenario {
+    /// `SetLocale` is answered with `LocaleSelected`, and `Presence` with
+    /// `Presence`.
+    Handshake,
+    /// `SetCapabilities` is answered with the known capabilities only.
+    Capabilities,
+    /// A frame split across writes is read once its newline arrives.
+    SplitFrame,
+    /// Frames sent in one write are each answered, in order.
+    PipelinedFrames,
+    /// `\r\n` line endings and blank lines are accepted.
+    LineEndings,
+    /// A line that isn't UTF-8 gets an `Error`, and the connection stays
+    /// usable.
+    InvalidUtf8,
+    /// A frame of an unknown type gets an `Error`, and the connection stays
+    /// usable.
+    UnknownFrame,
+    /// A message over `MAX_CONTENT_LEN` gets an `Error`.
+    OversizedMessage,
+    /// A message is relayed to everyone, its sender included, with an id
+    /// and timestamp.
+    Relay,
+    /// Messages sent back to back arrive in order, with increasing
+    /// sequence numbers.
+    Ordering,
+}
+
+impl Scenario {
+    /// Every scenario, in the order `run` plays them.
+    pub const ALL: [Scenario; 10] = [
+        Scenario::Handshake,
+        Scenario::Capabilities,
+        Scenario::SplitFrame,
+        Scenario::PipelinedFrames,
+        Scenario::LineEndings,
+        Scenario::InvalidUtf8,
+        Scenario::UnknownFrame,
+        Scenario::OversizedMessage,
+        Scenario::Relay,
+        Scenario::Ordering,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Scenario::Handshake => "handshake",
+            Scenario::Capabilities => "capabilities",
+            Scenario::SplitFrame => "framing/split",
+            Scenario::PipelinedFrames => "framing/pipelined",
+            Scenario::LineEndings => "framing/line_endings",
+            Scenario::InvalidUtf8 => "errors/invalid_utf8",
+            Scenario::UnknownFrame => "errors/unknown_frame",
+            Scenario::OversizedMessage => "errors/oversized_message",
+            Scenario::Relay => "relay",
+            Scenario::Ordering => "ordering",
+        }
+    }
+
+    /// Plays the scenario against the server at `addr` on fresh
+    /// connections, failing with what went wrong.
+    pub async fn check(self, addr: &str, options: &ConformanceOptions) -> Result<()> {
+        match self {
+            Scenario::Handshake => handshake(addr, options).await,
+            Scenario::Capabilities => capabilities(addr, options).await,
+            Scenario::SplitFrame => split_frame(addr, options).await,
+            Scenario::PipelinedFrames => pipelined_frames(addr, options).await,
+            Scenario::LineEndings => line_endings(addr, options).await,
+            Scenario::InvalidUtf8 => recovers_from(addr, options, b"\xff\xfe\n").await,
+            Scenario::UnknownFrame => {
+                recovers_from(addr, options, b"{\"type\":\"NoSuchFrame\"}\n").await
+            }
+            Scenario::OversizedMessage => oversized_message(addr, options).await,
+            Scenario::Relay => relay(addr, options).await,
+            Scenario::Ordering => ordering(addr, options).await,
+        }
+    }
+}
+
+impl fmt::Display for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// How one scenario went.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScenarioResult {
+    pub scenario: Scenario,
+    /// Why it failed; `None` if it passed.
+    pub failure: Option<String>,
+}
+
+/// The results of `run`, one per scenario in the order played.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub results: Vec<ScenarioResult>,
+}
+
+impl Report {
+    /// Whether every scenario passed.
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|result| result.failure.is_none())
+    }
+
+    /// The scenarios that failed.
+    pub fn failures(&self) -> impl Iterator<Item = &ScenarioResult> {
+        self.results
+            .iter()
+            .filter(|result| result.failure.is_some())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            match &result.failure {
+                None => writeln!(f, "PASS {}", result.scenario)?,
+                Some(failure) => writeln!(f, "FAIL {}: {}", result.scenario, failure)?,
+            }
+        }
+        let failed = self.failures().count();
+        write!(
+            f,
+            "{} of {} scenarios passed",
+            self.results.len() - failed,
+            self.results.len()
+        )
+    }
+}
+
+/// Plays every scenario against the server at `addr`, so another
+/// implementation of the protocol can check it's compatible:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use tokio_chat_server::conformance::{self, ConformanceOptions};
+///
+/// let report = conformance::run("127.0.0.1:8080", &ConformanceOptions::default()).await;
+/// println!("{}", report);
+/// assert!(report.passed());
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Scenarios post to the default room as `conformance`, so are best run
+/// against a server nobody else is using. Servers that require a
+/// proof-of-work challenge on connect aren't supported.
+pub async fn run(addr: &str, options: &ConformanceOptions) -> Report {
+    let mut report = Report::default();
+    for scenario in Scenario::ALL {
+        let failure = scenario.check(addr, options).await.err();
+        report.results.push(ScenarioResult {
+            scenario,
+            failure: failure.map(|e| format!("{:#}", e)),
+        });
+    }
+    report
+}
+
+/// A raw connection, so scenarios control exactly which bytes are sent.
+struct Probe {
+    socket: TcpStream,
+    decoder: FrameDecoder,
+    timeout: Duration,
+}
+
+impl Probe {
+    /// Connects, authenticating first if `options` has credentials.
+    async fn connect(addr: &str, options: &ConformanceOptions) -> Result<Probe> {
+        let deadline = Instant::now() + options.timeout;
+        let socket = timeout_at(deadline, TcpStream::connect(addr))
+            .await
+            .map_err(|_| anyhow!("Timed out connecting to {}", addr))?
+            .with_context(|| format!("Connecting to {}", addr))?;
+        socket.set_nodelay(true)?;
+        let mut probe = Probe {
+            socket,
+            decoder: FrameDecoder::new(MAX_FRAME_LEN),
+            timeout: options.timeout,
+        };
+        if let Some((user, token)) = &options.credentials {
+            probe
+                .send(&ClientFrame::Authenticate {
+                    user: user.clone(),
+                    token: token.clone(),
+                })
+                .await?;
+            probe
+                .expect("Authenticated", |frame| match frame {
+                    ServerFrame::Authenticated { .. } => Some(()),
+                    _ => None,
+                })
+                .await?;
+        }
+        Ok(probe)
+    }
+
+    async fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        self.socket.write_all(bytes).await?;
+        Ok(())
+    }
+
+    async fn send(&mut self, frame: &impl Serialize) -> Result<()> {
+        let line = format!("{}\n", serde_json::to_string(frame)?);
+        self.write(line.as_bytes()).await
+    }
+
+    /// Reads frames until `check` picks one out, skipping the rest (e.g.
+    /// presence changes from other connections), and returns what it
+    /// picked. Fails if the server sends something that isn't a server
+    /// frame, disconnects, or sends nothing `check` picks in time.
+    async fn expect<T>(
+        &mut self,
+        what: &str,
+        mut check: impl FnMut(ServerFrame) -> Option<T>,
+    ) -> Result<T> {
+        let deadline = Instant::now() + self.timeout;
+        let mut skipped = Vec::new();
+        let mut buffer = [0; 4096];
+        loop {
+            while let Some(line) = self.decoder.next_line()? {
+                let frame: ServerFrame = serde_json::from_str(&line)
+                    .with_context(|| format!("Expected {}, got {:?}", what, line))?;
+                let summary = frame.to_string();
+                match check(frame) {
+                    Some(picked) => return Ok(picked),
+                    None => skipped.push(summary),
+                }
+            }
+            let read = timeout_at(deadline, self.socket.read(&mut buffer)).await;
+            match read {
+                Ok(Ok(0)) => bail!(
+                    "Expected {}, but the server disconnected{}",
+                    what,
+                    after(&skipped)
+                ),
+                Ok(Ok(n)) => self.decoder.extend(&buffer[..n]),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => bail!("Timed out waiting for {}{}", what, after(&skipped)),
+            }
+        }
+    }
+
+    async fn expect_error(&mut self) -> Result<String> {
+        self.expect("an Error", |frame| match frame {
+            ServerFrame::Error { message } => Some(message),
+            _ => None,
+        })
+        .await
+    }
+
+    async fn expect_presence(&mut self) -> Result<()> {
+        self.expect("a Presence reply", |frame| match frame {
+            ServerFrame::Presence { .. } => Some(()),
+            _ => None,
+        })
+        .await
+    }
+}
+
+/// Lists the frames skipped while waiting, for failure messages.
+fn after(skipped: &[String]) -> String {
+    match skipped.last() {
+        None => String::new(),
+        Some(last) => format!(" (after {} other frames, last: {})", skipped.len(), last),
+    }
+}
+
+/// A name no one else is using, so scenarios can pick their own messages
+/// and rooms out of whatever else is going on.
+fn unique(prefix: &str) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_nanos())
+        .unwrap_or_default();
+    format!("{}-{:x}", prefix, nanos)
+}
+
+async fn handshake(addr: &str, options: &ConformanceOptions) -> Result<()> {
+    let mut probe = Probe::connect(addr, options).await?;
+    probe
+        .send(&ClientFrame::SetLocale {
+            locales: vec!["en".to_string()],
+        })
+        .await?;
+    let locale = probe
+        .expect("LocaleSelected", |frame| match frame {
+            ServerFrame::LocaleSelected { locale } => Some(locale),
+            _ => None,
+        })
+        .await?;
+    ensure!(!locale.is_empty(), "LocaleSelected named no locale");
+    probe.send(&ClientFrame::Presence).await?;
+    probe.expect_presence().await
+}
+
+async fn capabilities(addr: &str, options: &ConformanceOptions) -> Result<()> {
+    let mut probe = Probe::connect(addr, options).await?;
+    probe
+        .write(b"{\"type\":\"SetCapabilities\",\"capabilities\":[\"formatting\",\"no_such_capability\"]}\n")
+        .await?;
+    let capabilities = probe
+        .expect("CapabilitiesSelected", |frame| match frame {
+            ServerFrame::CapabilitiesSelected { capabilities } => Some(capabilities),
+            _ => None,
+        })
+        .await?;
+    ensure!(
+        capabilities == BTreeSet::from([Capability::Formatting]),
+        "Expected only formatting to be selected, got {:?}",
+        capabilities
+    );
+    Ok(())
+}
+
+async fn split_frame(addr: &str, options: &ConformanceOptions) -> Result<()> {
+    let mut probe = Probe::connect(addr, options).await?;
+    probe.write(b"{\"type\":\"Pre").await?;
+    sleep(Duration::from_millis(50)).await;
+    probe.write(b"sence\"}\n").await?;
+    probe.expect_presence().await
+}
+
+async fn pipelined_frames(addr: &str, options: &ConformanceOptions) -> Result<()> {
+    let mut probe = Probe::connect(addr, options).await?;
+    let rooms: Vec<String> = (0..3)
+        .map(|i| unique(&format!("conformance{}", i)))
+        .collect();
+    let mut batch = String::new();
+    for room in &rooms {
+        let frame = ClientFrame::FetchHistory {
+            room: Some(room.clone()),
+            before: None,
+            limit: Some(1),
+        };
+        batch.push_str(&frame.to_json()?);
+        batch.push('\n');
+    }
+    probe.write(batch.as_bytes()).await?;
+    for room in &rooms {
+        let replied = probe
+            .expect(&format!("History for {}", room), |frame| match frame {
+                ServerFrame::History { room, .. } => Some(room),
+                _ => None,
+            })
+            .await?;
+        ensure!(
+            &replied == room,
+            "Expected History for {} next, got it for {}",
+            room,
+            replied
+        );
+    }
+    Ok(())
+}
+
+async fn line_endings(addr: &str, options: &ConformanceOptions) -> Result<()> {
+    let mut probe = Probe::connect(addr, options).await?;
+    probe.write(b"\r\n\n{\"type\":\"Presence\"}\r\n").await?;
+    probe.expect_presence().await
+}
+
+/// Sends `line`, expects an `Error` for it, then checks the connection
+/// still answers.
+async fn recovers_from(addr: &str, options: &ConformanceOptions, line: &[u8]) -> Result<()> {
+    let mut probe = Probe::connect(addr, options).await?;
+    probe.write(line).await?;
+    probe.expect_error().await?;
+    probe.send(&ClientFrame::Presence).await?;
+    probe.expect_presence().await
+}
+
+async fn oversized_message(addr: &str, options: &ConformanceOptions) -> Result<()> {
+    let mut probe = Probe::connect(addr, options).await?;
+    let message = ChatMessage {
+        sender: SENDER.to_string(),
+        content: "x".repeat(MAX_CONTENT_LEN + 1),
+        ..Default::default()
+    };
+    probe.send(&message).await?;
+    probe.expect_error().await?;
+    Ok(())
+}
+
+async fn relay(addr: &str, options: &ConformanceOptions) -> Result<()> {
+    let mut sender = Probe::connect(addr, options).await?;
+    let mut receiver = Probe::connect(addr, options).await?;
+    // Both are connected once the receiver has been answered.
+    receiver.send(&ClientFrame::Presence).await?;
+    receiver.expect_presence().await?;
+    let content = unique("relay");
+    let message = ChatMessage {
+        sender: SENDER.to_string(),
+        content: content.clone(),
+        ..Default::default()
+    };
+    sender.send(&message).await?;
+    for probe in [&mut receiver, &mut sender] {
+        let relayed = probe
+            .expect("the relayed message", |frame| match frame {
+                ServerFrame::Message { message, .. } if message.content == content => Some(message),
+                _ => None,
+            })
+            .await?;
+        ensure!(relayed.id.is_some(), "Relayed message has no id");
+        ensure!(
+            relayed.timestamp.is_some(),
+            "Relayed message has no timestamp"
+        );
+    }
+    Ok(())
+}
+
+async fn ordering(addr: &str, options: &ConformanceOptions) -> Result<()> {
+    let mut sender = Probe::connect(addr, options).await?;
+    let mut receiver = Probe::connect(addr, options).await?;
+    receiver.send(&ClientFrame::Presence).await?;
+    receiver.expect_presence().await?;
+    let prefix = unique("ordering");
+    let mut batch = String::new();
+    for i in 0..ORDERED_MESSAGES {
+        let message = ChatMessage {
+            sender: SENDER.to_string(),
+            content: format!("{} {}", prefix, i),
+            ..Default::default()
+        };
+        batch.push_str(&message.to_json()?);
+        batch.push('\n');
+    }
+    sender.write(batch.as_bytes()).await?;
+    let mut last_seq = None;
+    for i in 0..ORDERED_MESSAGES {
+        let message = receiver
+            .expect(
+                &format!("message {} of {}", i + 1, ORDERED_MESSAGES),
+                |frame| match frame {
+                    ServerFrame::Message { message, .. }
+                        if message.content.starts_with(&prefix) =>
+                    {
+                        Some(message)
+                    }
+                    _ => None,
+                },
+            )
+            .await?;
+        let expected = format!("{} {}", prefix, i);
+        ensure!(
+            message.content == expected,
+            "Expected {:?} next, got {:?}",
+            expected,
+            message.content
+        );
+        let seq = message
+            .seq
+            .context("Relayed message has no sequence number")?;
+        if let Some(last_seq) = last_seq {
+            ensure!(
+                seq > last_seq,
+                "Sequence number {} follows {}",
+                seq,
+                last_seq
+            );
+        }
+        last_seq = Some(seq);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatServer;
+    use crate::testing::MockServer;
+
+    #[tokio::test]
+    async fn test_chat_server_conforms() -> Result<()> {
+        let server = ChatServer::new("127.0.0.1:0").await?;
+        let addr = server.local_addr()?.to_string();
+        tokio::spawn(server.run());
+
+        let report = run(&addr, &ConformanceOptions::default()).await;
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.results.len(), Scenario::ALL.len());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failures_say_what_was_expected() -> Result<()> {
+        let server = MockServer::builder()
+            .expect(ClientFrame::SetLocale {
+                locales: vec!["en".to_string()],
+            })
+            .send(ServerFrame::Notice {
+                text: "hi".to_string(),
+            })
+            .start()
+            .await?;
+        let options = ConformanceOptions {
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let error = Scenario::Handshake
+            .check(&server.local_addr().to_string(), &options)
+            .await
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            error,
+            "Expected LocaleSelected, but the server disconnected (after 1 other frames, last: notice: hi)"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+use crate::protocol::ChatMessage;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Most unacknowledged messages kept per user; older ones are dead-lettered.
+pub const MAX_UNACKED: usize = 1000;
+/// How long a message waits for an ack by default before it's dead-lettered.
+pub const DEFAULT_UNACKED_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// Most dead letters kept; the oldest are discarded first.
+pub const MAX_DEAD_LETTERS: usize = 10_000;
+
+/// Messages from at-least-once rooms that each user hasn't acknowledged
+/// yet, kept so they can be redelivered when the user signs in again.
+#[derive(Debug)]
+pub struct AckTracker {
+    pending: Mutex<HashMap<String, VecDeque<ChatMessage>>>,
+    capacity: usize,
+    ttl: Duration,
+}
+
+impl Default for AckTracker {
+    fn default() -> Self {
+        Self::new(MAX_UNACKED, DEFAULT_UNACKED_TTL)
+    }
+}
+
+impl AckTracker {
+    /// Creates a tracker keeping up to `capacity` messages per user, each
+    /// for at most `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        AckTracker {
+            pending: Mutex::new(HashMap::new()),
+            capacity,
+            ttl,
+        }
+    }
+
+    /// Holds `message` until `user` acknowledges it, returning the oldest
+    /// pending message if `user` already had `capacity` of them.
+    pub fn hold(&self, user: &str, message: &ChatMessage) -> Option<ChatMessage> {
+        let mut pending = self.pending.lock().unwrap();
+        let queue = pending.entry(user.to_string()).or_default();
+        queue.push_back(message.clone());
+        (queue.len() > self.capacity)
+            .then(|| queue.pop_front())
+            .flatten()
+    }
+
+    /// Acknowledges everything `user` has received in `room` up to and
+    /// including `seq`, returning how many messages were released.
+    pub fn ack(&self, user: &str, room: &str, seq: u64) -> usize {
+        let mut pending = self.pending.lock().unwrap();
+        let Some(queue) = pending.get_mut(user) else {
+            return 0;
+        };
+        let before = queue.len();
+        queue.retain(|message| message.room() != room || message.seq.is_none_or(|s| s > seq));
+        let released = before - queue.len();
+        if queue.is_empty() {
+            pending.remove(user);
+        }
+        released
+    }
+
+    /// Removes messages relayed more than the TTL before `now` (Unix
+    /// seconds), returning them with the user each was held for.
+    pub fn expire(&self, now: u64) -> Vec<(String, ChatMessage)> {
+        let cutoff = now.saturating_sub(self.ttl.as_secs());
+        let mut expired = Vec::new();
+        let mut pending = self.pending.lock().unwrap();
+        pending.retain(|user, queue| {
+            while queue
+                .front()
+                .is_some_and(|message| message.timestamp.unwrap_or_default() <= cutoff)
+            {
+                expired.extend(queue.pop_front().map(|message| (user.clone(), message)));
+            }
+            !queue.is_empty()
+        });
+        expired
+    }
+
+    /// Returns the messages `user` hasn't acknowledged, oldest first.
+    pub fn pending(&self, user: &str) -> Vec<ChatMessage> {
+        self.pending
+            .lock()
+            .unwrap()
+            .get(user)
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns every user's unacknowledged messages, for a snapshot.
+    pub fn snapshot(&self) -> HashMap<String, VecDeque<ChatMessage>> {
+        self.pending.lock().unwrap().clone()
+    }
+
+    /// Loads messages saved by `snapshot`, ahead of any held since startup.
+    pub fn restore(&self, snapshot: HashMap<String, VecDeque<ChatMessage>>) {
+        let mut pending = self.pending.lock().unwrap();
+        for (user, mut saved) in snapshot {
+            let queue = pending.entry(user).or_default();
+            saved.append(queue);
+            *queue = saved;
+        }
+    }
+}
+
+/// Why a message couldn't be delivered.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DeadLetterReason {
+    /// The user had too many unacknowledged messages.
+    QueueOverflow,
+    /// The user didn't acknowledge the message in time.
+    Expired,
+}
+
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeadLetterReason::QueueOverflow => "queue overflow",
+            DeadLetterReason::Expired => "expired",
+        })
+    }
+}
+
+/// A message that was given up on for one user.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeadLetter {
+    pub user: String,
+    pub reason: DeadLetterReason,
+    pub message: ChatMessage,
+    /// Unix time (seconds) the message was given up on.
+    pub dead_at: u64,
+}
+
+/// Keeps undeliverable messages for inspection, readable through
+/// `ChatServer::dead_letters` and the admin HTTP routes.
+#[derive(Debug)]
+pub struct DeadLetterStore {
+    letters: Mutex<VecDeque<DeadLetter>>,
+    capacity: usize,
+}
+
+impl Default for DeadLetterStore {
+    fn default() -> Self {
+        Self::new(MAX_DEAD_LETTERS)
+    }
+}
+
+impl DeadLetterStore {
+    /// Creates a store keeping the newest `capacity` dead letters.
+    pub fn new(capacity: usize) -> Self {
+        DeadLetterStore {
+            letters: Mutex::new(VecDeque::new()),
+            capacity,
+        }
+    }
+
+    pub fn push(&self, letter: DeadLetter) {
+        let mut letters = self.letters.lock().unwrap();
+        letters.push_back(letter);
+        if letters.len() > self.capacity {
+            letters.pop_front();
+        }
+    }
+
+    /// Returns the dead letters for `user`, or everyone's, oldest first.
+    pub fn list(&self, user: Option<&str>) -> Vec<DeadLetter> {
+        self.letters
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|letter| user.is_none_or(|user| letter.user == user))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(room: &str, seq: u64) -> ChatMessage {
+        ChatMessage {
+            sender: "avery".to_string(),
+            content: format!("message {}", seq),
+            room: Some(room.to_string()),
+            seq: Some(seq),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_acks_release_up_to_seq() {
+        let tracker = AckTracker::new(3, DEFAULT_UNACKED_TTL);
+        for seq in 1..=3 {
+            assert_eq!(tracker.hold("blake", &message("ops", seq)), None);
+        }
+        let dropped = tracker.hold("blake", &message("alerts", 1));
+        assert_eq!(dropped.and_then(|message| message.seq), Some(1));
+
+        assert_eq!(tracker.ack("blake", "ops", 2), 1);
+        let pending: Vec<_> = tracker
+            .pending("blake")
+            .into_iter()
+            .map(|message| (message.room.unwrap(), message.seq.unwrap()))
+            .collect();
+        assert_eq!(
+            pending,
+            vec![("ops".to_string(), 3), ("alerts".to_string(), 1)]
+        );
+        assert!(tracker.pending("casey").is_empty());
+    }
+
+    #[test]
+    fn test_expire_removes_old_messages() {
+        let tracker = AckTracker::new(10, Duration::from_secs(60));
+        for (seq, timestamp) in [(1, 100), (2, 200)] {
+            let message = ChatMessage {
+                timestamp: Some(timestamp),
+                ..message("ops", seq)
+            };
+            tracker.hold("blake", &message);
+        }
+        assert!(tracker.expire(159).is_empty());
+        let expired = tracker.expire(160);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, "blake");
+        assert_eq!(tracker.pending("blake").len(), 1);
+    }
+}
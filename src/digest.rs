@@ -0,0 +1,313 @@
+use crate::protocol::ChatMessage;
+use crate::registry::Registry;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Longest line quoted from a message in a digest, in characters.
+const MAX_LINE_LEN: usize = 200;
+
+/// Sends plain-text email.
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<()>;
+}
+
+/// Sends email through an SMTP relay, such as a local MTA, that accepts
+/// unauthenticated plaintext connections.
+#[cfg(feature = "email")]
+pub struct SmtpMailer {
+    transport: lettre::AsyncSmtpTransport<lettre::Tokio1Executor>,
+    from: lettre::message::Mailbox,
+}
+
+#[cfg(feature = "email")]
+impl SmtpMailer {
+    /// A mailer relaying through `host:port`, sending as `from`.
+    pub fn new(host: &str, port: u16, from: &str) -> Result<Self> {
+        let transport =
+            lettre::AsyncSmtpTransport::<lettre::Tokio1Executor>::builder_dangerous(host)
+                .port(port)
+                .build();
+        Ok(SmtpMailer {
+            transport,
+            from: from.parse()?,
+        })
+    }
+}
+
+#[cfg(feature = "email")]
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<()> {
+        use lettre::AsyncTransport;
+        use lettre::message::header::ContentType;
+
+        let email = lettre::Message::builder()
+            .from(self.from.clone())
+            .to(to.parse()?)
+            .subject(subject)
+            .header(ContentType::TEXT_PLAIN)
+            .body(body.to_string())?;
+        self.transport.send(email).await?;
+        Ok(())
+    }
+}
+
+/// How missed messages are gathered into digests.
+#[derive(Debug, Clone)]
+pub struct DigestPolicy {
+    /// How often the background job looks for digests that are due.
+    pub interval: Duration,
+    /// Most messages held per user; older ones are left out of the digest.
+    pub max_messages: usize,
+}
+
+impl Default for DigestPolicy {
+    fn default() -> Self {
+        DigestPolicy {
+            interval: Duration::from_secs(60),
+            max_messages: 50,
+        }
+    }
+}
+
+/// Mentions and direct messages users missed while offline, emailed to
+/// those who opted in once the oldest has waited as long as their
+/// `DigestFrequency` says. Signing in again clears what was held.
+pub struct EmailDigests {
+    mailer: Arc<dyn Mailer>,
+    policy: DigestPolicy,
+    registry: Arc<Registry>,
+    missed: Mutex<HashMap<String, VecDeque<ChatMessage>>>,
+}
+
+impl EmailDigests {
+    pub fn new(mailer: Arc<dyn Mailer>, policy: DigestPolicy, registry: Arc<Registry>) -> Self {
+        EmailDigests {
+            mailer,
+            policy,
+            registry,
+            missed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether `user` has asked for email digests.
+    pub fn wants(&self, user: &str) -> bool {
+        self.registry
+            .notification_prefs(user)
+            .email_digest
+            .is_some()
+    }
+
+    /// Holds `message` for the next digest to `user`.
+    pub fn hold(&self, user: &str, message: &ChatMessage) {
+        let mut missed = self.missed.lock().unwrap();
+        let queue = missed.entry(user.to_string()).or_default();
+        queue.push_back(message.clone());
+        if queue.len() > self.policy.max_messages {
+            queue.pop_front();
+        }
+    }
+
+    /// Forgets what's held for `user`, who is back to read it.
+    pub fn clear(&self, user: &str) {
+        self.missed.lock().unwrap().remove(user);
+    }
+
+    /// Returns the messages held for `user`, oldest first.
+    pub fn missed(&self, user: &str) -> Vec<ChatMessage> {
+        self.missed
+            .lock()
+            .unwrap()
+            .get(user)
+            .map(|queue| queue.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Emails every digest due at the Unix time `now`, returning how many
+    /// were sent. Digests that fail to send are kept for the next try;
+    /// those of users who have since opted out are dropped.
+    pub async fn send_due(&self, now: u64) -> usize {
+        let due: Vec<_> = {
+            let mut missed = self.missed.lock().unwrap();
+            let mut due = Vec::new();
+            missed.retain(|user, queue| {
+                let Some(digest) = self.registry.notification_prefs(user).email_digest else {
+                    return false;
+                };
+                let oldest = queue
+                    .front()
+                    .and_then(|message| message.timestamp)
+                    .unwrap_or_default();
+                if oldest + digest.frequency.period().as_secs() > now {
+                    return true;
+                }
+                due.push((user.clone(), digest.address, std::mem::take(queue)));
+                false
+            });
+            due
+        };
+        let mut sent = 0;
+        for (user, address, messages) in due {
+            let subject = match messages.len() {
+                1 => "1 missed message".to_string(),
+                n => format!("{} missed messages", n),
+            };
+            match self.mailer.send(&address, &subject, &body(&messages)).await {
+                Ok(()) => {
+                    info!("Emailed {} a digest of {}", user, subject);
+                    sent += 1;
+                }
+                Err(e) => {
+                    warn!("Failed to email {} a digest: {}", user, e);
+                    let mut missed = self.missed.lock().unwrap();
+                    let queue = missed.entry(user).or_default();
+                    for message in messages.into_iter().rev() {
+                        queue.push_front(message);
+                    }
+                    queue.truncate(self.policy.max_messages);
+                }
+            }
+        }
+        sent
+    }
+}
+
+/// One line per message: "#room sender: content", or "sender: content"
+/// for a direct message.
+fn body(messages: &VecDeque<ChatMessage>) -> String {
+    let mut body = String::new();
+    for message in messages {
+        if let Some(room) = &message.room {
+            body.push('#');
+            body.push_str(room);
+            body.push(' ');
+        }
+        body.push_str(&message.sender);
+        body.push_str(": ");
+        let line = message.content.lines().next().unwrap_or_default();
+        body.extend(line.chars().take(MAX_LINE_LEN));
+        if line.len() < message.content.len() || line.chars().count() > MAX_LINE_LEN {
+            body.push('\u{2026}');
+        }
+        body.push('\n');
+    }
+    body
+}
+
+/// Emails the digests that are due every `policy.interval`, forever.
+pub async fn run_digests(digests: Arc<EmailDigests>) {
+    let mut ticker = tokio::time::interval(digests.policy.interval);
+    loop {
+        ticker.tick().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        digests.send_due(now).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{DigestFrequency, EmailDigest, NotificationPrefs};
+
+    #[derive(Default)]
+    struct Outbox {
+        sent: Mutex<Vec<(String, String, String)>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl Mailer for Outbox {
+        async fn send(&self, to: &str, subject: &str, body: &str) -> Result<()> {
+            if self.fail {
+                anyhow::bail!("relay down");
+            }
+            let mail = (to.to_string(), subject.to_string(), body.to_string());
+            self.sent.lock().unwrap().push(mail);
+            Ok(())
+        }
+    }
+
+    fn message(room: Option<&str>, content: &str, timestamp: u64) -> ChatMessage {
+        ChatMessage {
+            sender: "avery".to_string(),
+            content: content.to_string(),
+            room: room.map(str::to_string),
+            timestamp: Some(timestamp),
+            ..Default::default()
+        }
+    }
+
+    fn opted_in(registry: &Registry, user: &str) {
+        let prefs = NotificationPrefs {
+            email_digest: Some(EmailDigest {
+                address: format!("{}@example.com", user),
+                frequency: DigestFrequency::Hourly,
+            }),
+            ..Default::default()
+        };
+        registry.set_notification_prefs(user, prefs);
+    }
+
+    #[tokio::test]
+    async fn test_digests_wait_for_their_period() {
+        let registry = Arc::new(Registry::new());
+        opted_in(&registry, "blake");
+        let outbox = Arc::new(Outbox::default());
+        let digests = EmailDigests::new(outbox.clone(), DigestPolicy::default(), registry);
+        assert!(digests.wants("blake"));
+        assert!(!digests.wants("casey"));
+
+        digests.hold("blake", &message(Some("ops"), "@blake deploy?", 1000));
+        digests.hold("blake", &message(None, "ping\nmore", 1500));
+        assert_eq!(digests.send_due(1000 + 3599).await, 0);
+        assert_eq!(digests.send_due(1000 + 3600).await, 1);
+        assert!(digests.missed("blake").is_empty());
+        let sent = outbox.sent.lock().unwrap();
+        assert_eq!(
+            *sent,
+            vec![(
+                "blake@example.com".to_string(),
+                "2 missed messages".to_string(),
+                "#ops avery: @blake deploy?\navery: ping\u{2026}\n".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_digests_are_kept() {
+        let registry = Arc::new(Registry::new());
+        opted_in(&registry, "blake");
+        let outbox = Arc::new(Outbox {
+            fail: true,
+            ..Default::default()
+        });
+        let policy = DigestPolicy {
+            max_messages: 2,
+            ..Default::default()
+        };
+        let digests = EmailDigests::new(outbox, policy, registry.clone());
+        for timestamp in [10, 20, 30] {
+            digests.hold("blake", &message(None, "hi", timestamp));
+        }
+        assert_eq!(digests.send_due(10_000).await, 0);
+        let kept: Vec<_> = digests
+            .missed("blake")
+            .iter()
+            .filter_map(|message| message.timestamp)
+            .collect();
+        assert_eq!(kept, vec![20, 30]);
+
+        // Opting out drops what was held.
+        registry.set_notification_prefs("blake", NotificationPrefs::default());
+        assert_eq!(digests.send_due(10_000).await, 0);
+        assert!(digests.missed("blake").is_empty());
+    }
+}
@@ -0,0 +1,106 @@
+use crate::protocol::ChatMessage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::Write;
+
+/// Output formats for history exports.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ExportFormat {
+    /// One JSON-encoded `ChatMessage` per line.
+    Jsonl,
+    /// `id,timestamp,room,sender,content` with a header row.
+    Csv,
+}
+
+impl fmt::Display for ExportFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExportFormat::Jsonl => "jsonl",
+            ExportFormat::Csv => "csv",
+        })
+    }
+}
+
+/// Writes `messages` to `out` in the given format.
+pub fn write_messages(
+    messages: &[ChatMessage],
+    format: ExportFormat,
+    out: &mut impl Write,
+) -> Result<()> {
+    write_header(format, out)?;
+    write_rows(messages, format, out)
+}
+
+/// Writes what comes before the first message, if the format has anything.
+pub fn write_header(format: ExportFormat, out: &mut impl Write) -> Result<()> {
+    if format == ExportFormat::Csv {
+        writeln!(out, "id,timestamp,room,sender,content")?;
+    }
+    Ok(())
+}
+
+/// Writes `messages` without a header, for exports written a page at a
+/// time after `write_header`.
+pub fn write_rows(
+    messages: &[ChatMessage],
+    format: ExportFormat,
+    out: &mut impl Write,
+) -> Result<()> {
+    match format {
+        ExportFormat::Jsonl => {
+            for message in messages {
+                writeln!(out, "{}", message.to_json()?)?;
+            }
+        }
+        ExportFormat::Csv => {
+            for message in messages {
+                writeln!(
+                    out,
+                    "{},{},{},{},{}",
+                    message.id.map(|id| id.to_string()).unwrap_or_default(),
+                    message
+                        .timestamp
+                        .map(|timestamp| timestamp.to_string())
+                        .unwrap_or_default(),
+                    csv_field(message.room()),
+                    csv_field(&message.sender),
+                    csv_field(&message.content),
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Quotes a CSV field when it contains a delimiter, quote, or line break.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_quotes_special_fields() {
+        let message = ChatMessage {
+            sender: "avery".to_string(),
+            content: "hi, \"all\"".to_string(),
+            id: Some(7),
+            timestamp: Some(1_700_000_000),
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        write_messages(&[message], ExportFormat::Csv, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "id,timestamp,room,sender,content\n7,1700000000,general,avery,\"hi, \"\"all\"\"\"\n"
+        );
+    }
+}
@@ -0,0 +1,292 @@
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::codec::Encoded;
+use crate::metrics::Metrics;
+use crate::outbound::OutboundQueue;
+use crate::protocol::Priority;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// Copies broadcast frames into every connection's `OutboundQueue`.
+///
+/// Connections are split into shards, each served by its own worker task,
+/// so a broadcast costs the sender one channel send per worker however
+/// many clients are connected. A connection always belongs to the same
+/// shard, and each worker handles frames in the order they were sent, so
+/// every client still sees broadcasts in order.
+pub struct FanOut {
+    shards: Vec<Arc<Shard>>,
+    jobs: Vec<mpsc::UnboundedSender<Job>>,
+    /// Receivers waiting for `start` to hand them to workers.
+    pending: Mutex<Vec<mpsc::UnboundedReceiver<Job>>>,
+    hasher: RandomState,
+}
+
+/// A broadcast frame, when it was sent, and who it's for.
+type Job = (Priority, Arc<Encoded>, Instant, Audience);
+
+/// Which subscribed connections a broadcast goes to.
+#[derive(Clone)]
+enum Audience {
+    Everyone,
+    Except(Arc<HashSet<SocketAddr>>),
+    Only(Arc<HashSet<SocketAddr>>),
+}
+
+impl Audience {
+    fn includes(&self, addr: &SocketAddr) -> bool {
+        match self {
+            Audience::Everyone => true,
+            Audience::Except(except) => !except.contains(addr),
+            Audience::Only(only) => only.contains(addr),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Shard {
+    queues: Mutex<HashMap<SocketAddr, Arc<OutboundQueue>>>,
+}
+
+impl FanOut {
+    /// Creates a fan-out with `workers` shards; see `start`.
+    pub fn new(workers: NonZeroUsize) -> Self {
+        let (jobs, pending) = (0..workers.get())
+            .map(|_| mpsc::unbounded_channel())
+            .unzip();
+        FanOut {
+            shards: (0..workers.get()).map(|_| Arc::default()).collect(),
+            jobs,
+            pending: Mutex::new(pending),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Spawns the workers, which report broadcast lag to `metrics`. Frames
+    /// sent before this are held until then; calling it again does nothing.
+    pub fn start(&self, metrics: Arc<Metrics>, #[cfg(feature = "chaos")] chaos: Arc<Chaos>) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for (shard, jobs) in self.shards.iter().zip(pending) {
+            metrics.tasks().spawn(
+                "background",
+                "fanout worker",
+                run_worker(
+                    shard.clone(),
+                    jobs,
+                    metrics.clone(),
+                    #[cfg(feature = "chaos")]
+                    chaos.clone(),
+                ),
+            );
+        }
+    }
+
+    /// Starts delivering broadcasts to a connection's queue.
+    pub fn subscribe(&self, addr: SocketAddr, queue: Arc<OutboundQueue>) {
+        self.shard(addr).queues.lock().unwrap().insert(addr, queue);
+    }
+
+    /// Stops delivering broadcasts to a connection.
+    pub fn unsubscribe(&self, addr: SocketAddr) {
+        self.shard(addr).queues.lock().unwrap().remove(&addr);
+    }
+
+    /// Hands a frame to every worker for delivery, each connection getting
+    /// it in its own wire format.
+    pub fn send(&self, priority: Priority, frame: Arc<Encoded>) {
+        self.dispatch(priority, frame, Audience::Everyone);
+    }
+
+    /// Like `send`, but leaves out the connections in `except`.
+    pub fn send_except(
+        &self,
+        priority: Priority,
+        frame: Arc<Encoded>,
+        except: HashSet<SocketAddr>,
+    ) {
+        let audience = if except.is_empty() {
+            Audience::Everyone
+        } else {
+            Audience::Except(Arc::new(except))
+        };
+        self.dispatch(priority, frame, audience);
+    }
+
+    /// Like `send`, but only to the connections in `only`. Unlike `send_to`,
+    /// ordered with respect to other broadcasts.
+    pub fn send_only(&self, priority: Priority, frame: Arc<Encoded>, only: HashSet<SocketAddr>) {
+        if !only.is_empty() {
+            self.dispatch(priority, frame, Audience::Only(Arc::new(only)));
+        }
+    }
+
+    fn dispatch(&self, priority: Priority, frame: Arc<Encoded>, audience: Audience) {
+        let sent = Instant::now();
+        for jobs in &self.jobs {
+            // Workers only stop when the fan-out is dropped.
+            let _ = jobs.send((priority, frame.clone(), sent, audience.clone()));
+        }
+    }
+
+    /// Queues a frame for one connection straight away, returning false if
+    /// it isn't subscribed. Not ordered with respect to `send`.
+    pub fn send_to(&self, addr: SocketAddr, priority: Priority, frame: &Encoded) -> bool {
+        let queue = self.shard(addr).queues.lock().unwrap().get(&addr).cloned();
+        match queue {
+            Some(queue) => {
+                queue.push_frame(priority, frame);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns a subscribed connection's queue.
+    pub fn queue(&self, addr: SocketAddr) -> Option<Arc<OutboundQueue>> {
+        self.shard(addr).queues.lock().unwrap().get(&addr).cloned()
+    }
+
+    /// Closes a connection's queue once what's queued has been written, so
+    /// the connection is dropped. Returns false if it isn't subscribed.
+    pub fn close(&self, addr: SocketAddr) -> bool {
+        let queue = self.shard(addr).queues.lock().unwrap().get(&addr).cloned();
+        queue.map(|queue| queue.close()).is_some()
+    }
+
+    /// The subscribed connection with the most bytes queued, if any has
+    /// something queued.
+    pub fn heaviest(&self) -> Option<(SocketAddr, Arc<OutboundQueue>)> {
+        self.shards
+            .iter()
+            .filter_map(|shard| {
+                let queues = shard.queues.lock().unwrap();
+                queues
+                    .iter()
+                    .map(|(addr, queue)| (queue.queued_bytes(), *addr, queue.clone()))
+                    .max_by_key(|(bytes, ..)| *bytes)
+            })
+            .max_by_key(|(bytes, ..)| *bytes)
+            .filter(|(bytes, ..)| *bytes > 0)
+            .map(|(_, addr, queue)| (addr, queue))
+    }
+
+    fn shard(&self, addr: SocketAddr) -> &Shard {
+        let index = self.hasher.hash_one(addr) as usize % self.shards.len();
+        &self.shards[index]
+    }
+}
+
+impl Default for FanOut {
+    /// One worker per available CPU.
+    fn default() -> Self {
+        FanOut::new(NonZeroUsize::new(num_cpus::get()).unwrap_or(NonZeroUsize::MIN))
+    }
+}
+
+/// Pushes each frame into every queue in `shard`.
+async fn run_worker(
+    shard: Arc<Shard>,
+    mut jobs: mpsc::UnboundedReceiver<Job>,
+    metrics: Arc<Metrics>,
+    #[cfg(feature = "chaos")] chaos: Arc<Chaos>,
+) {
+    while let Some((priority, frame, sent, audience)) = jobs.recv().await {
+        metrics.record_broadcast_lag(sent.elapsed());
+        let queues = shard.queues.lock().unwrap();
+        for (addr, queue) in queues.iter() {
+            if !audience.includes(addr) {
+                continue;
+            }
+            #[cfg(feature = "chaos")]
+            if chaos.drop_broadcast() {
+                continue;
+            }
+            if !queue.push_broadcast(priority, &frame, sent) {
+                // The client sees a jump in sequence numbers and can
+                // backfill what it missed.
+                warn!("Client {} is behind, dropped its oldest queued frame", addr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::WireFormat;
+    use crate::outbound::QueueCapacity;
+    use crate::protocol::ServerFrame;
+
+    fn encoded(message: &str) -> Arc<Encoded> {
+        let message = message.to_string();
+        Arc::new(Encoded::new(ServerFrame::Error { message }).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_every_subscriber_gets_frames_in_order() {
+        let fanout = FanOut::new(NonZeroUsize::new(3).unwrap());
+        let queues: Vec<Arc<OutboundQueue>> = (0..10)
+            .map(|port| {
+                let queue = Arc::new(OutboundQueue::new(QueueCapacity::Fixed(16)));
+                fanout.subscribe(SocketAddr::from(([127, 0, 0, 1], port)), queue.clone());
+                queue
+            })
+            .collect();
+        fanout.unsubscribe(SocketAddr::from(([127, 0, 0, 1], 9)));
+        let first = encoded("first");
+        fanout.send(Priority::Normal, first.clone());
+        fanout.start(
+            Arc::default(),
+            #[cfg(feature = "chaos")]
+            Arc::default(),
+        );
+        let second = encoded("second");
+        fanout.send(Priority::Normal, second.clone());
+
+        for queue in &queues[..9] {
+            assert_eq!(queue.pop().await, first.get(WireFormat::Json));
+            assert_eq!(queue.pop().await, second.get(WireFormat::Json));
+        }
+        let unsubscribed = &queues[9];
+        unsubscribed.close();
+        assert_eq!(unsubscribed.pop().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_broadcasts_are_encoded_once_per_format() {
+        let fanout = FanOut::new(NonZeroUsize::new(2).unwrap());
+        fanout.start(
+            Arc::default(),
+            #[cfg(feature = "chaos")]
+            Arc::default(),
+        );
+        let queues: Vec<Arc<OutboundQueue>> = (0..6)
+            .map(|port| {
+                let queue = Arc::new(OutboundQueue::new(QueueCapacity::Fixed(16)));
+                if port % 2 == 0 {
+                    queue.set_format(WireFormat::MessagePack);
+                }
+                fanout.subscribe(SocketAddr::from(([127, 0, 0, 1], port)), queue.clone());
+                queue
+            })
+            .collect();
+        fanout.send(Priority::Normal, encoded("hello"));
+
+        let mut lines = Vec::new();
+        for queue in &queues {
+            lines.push(queue.pop().await.unwrap());
+        }
+        let (packed, json): (Vec<_>, Vec<_>) = lines.iter().partition(|line| line[0] != b'{');
+        for lines in [packed, json] {
+            assert_eq!(lines.len(), 3);
+            // Every copy shares the one encoding.
+            assert!(lines.iter().all(|line| line.as_ptr() == lines[0].as_ptr()));
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use anyhow::{Context, Result};
+use maxminddb::{Reader, geoip2};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+/// Which addresses may connect, by where the databases place them. Deny
+/// rules win over allow rules, and when there are allow rules an address
+/// has to match one of them. Read from a JSON file, e.g.
+/// `{"deny_countries": ["KP"], "deny_asns": [64496]}`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct GeoRules {
+    /// ISO 3166-1 alpha-2 country codes, e.g. `NZ`.
+    pub allow_countries: HashSet<String>,
+    pub deny_countries: HashSet<String>,
+    /// Autonomous system numbers.
+    pub allow_asns: HashSet<u32>,
+    pub deny_asns: HashSet<u32>,
+    /// Refuses addresses the databases don't place at all, such as
+    /// private ones. They're admitted by default, even with allow rules.
+    pub deny_unknown: bool,
+}
+
+impl GeoRules {
+    /// Returns whether a client at `location` may connect.
+    pub fn admits(&self, location: &Location) -> bool {
+        let country = location.country.as_deref();
+        if country.is_some_and(|country| self.deny_countries.contains(country))
+            || location
+                .asn
+                .is_some_and(|asn| self.deny_asns.contains(&asn))
+        {
+            return false;
+        }
+        if country.is_none() && location.asn.is_none() {
+            return !self.deny_unknown;
+        }
+        if self.allow_countries.is_empty() && self.allow_asns.is_empty() {
+            return true;
+        }
+        country.is_some_and(|country| self.allow_countries.contains(country))
+            || location
+                .asn
+                .is_some_and(|asn| self.allow_asns.contains(&asn))
+    }
+}
+
+/// Where the databases place an address.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Location {
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// The files a `GeoFilter` loads. Any of them can be replaced while the
+/// server runs; they're reloaded within `reload_interval`.
+#[derive(Debug, Clone)]
+pub struct GeoConfig {
+    /// A MaxMind Country or City database, e.g. `GeoLite2-Country.mmdb`.
+    pub country_db: Option<PathBuf>,
+    /// A MaxMind ASN database, e.g. `GeoLite2-ASN.mmdb`.
+    pub asn_db: Option<PathBuf>,
+    /// The `GeoRules`, as JSON.
+    pub rules: PathBuf,
+    /// How often the files are checked for changes.
+    pub reload_interval: Duration,
+}
+
+/// Admits or refuses clients by country and ASN as they're accepted, for
+/// deployments with regulatory or abuse constraints.
+pub struct GeoFilter {
+    config: GeoConfig,
+    loaded: RwLock<Arc<Loaded>>,
+}
+
+struct Loaded {
+    country: Option<Reader<Vec<u8>>>,
+    asn: Option<Reader<Vec<u8>>>,
+    rules: GeoRules,
+    /// When each of the config's files was last modified, as loaded.
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl GeoFilter {
+    /// Loads the databases and rules `config` names.
+    pub fn open(config: GeoConfig) -> Result<Self> {
+        let loaded = load(&config)?;
+        Ok(GeoFilter {
+            config,
+            loaded: RwLock::new(Arc::new(loaded)),
+        })
+    }
+
+    /// Returns where the databases place `ip`.
+    pub fn locate(&self, ip: IpAddr) -> Location {
+        let loaded = self.loaded.read().unwrap().clone();
+        let ip = ip.to_canonical();
+        let country = loaded.country.as_ref().and_then(|reader| {
+            let record: geoip2::Country = reader.lookup(ip).ok()?.decode().ok()??;
+            record.country.iso_code.map(str::to_string)
+        });
+        let asn = loaded.asn.as_ref().and_then(|reader| {
+            let record: geoip2::Asn = reader.lookup(ip).ok()?.decode().ok()??;
+            record.autonomous_system_number
+        });
+        Location { country, asn }
+    }
+
+    /// Returns whether a client from `ip` may connect.
+    pub fn admits(&self, ip: IpAddr) -> bool {
+        let location = self.locate(ip);
+        self.rules().admits(&location)
+    }
+
+    /// Returns the rules in use.
+    pub fn rules(&self) -> GeoRules {
+        self.loaded.read().unwrap().rules.clone()
+    }
+
+    /// Loads the files again if any has changed since they were loaded,
+    /// returning whether they had. If they can't be loaded, the ones
+    /// already loaded stay in use.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        if modified_times(&self.config) == self.loaded.read().unwrap().modified {
+            return Ok(false);
+        }
+        let loaded = load(&self.config)?;
+        *self.loaded.write().unwrap() = Arc::new(loaded);
+        Ok(true)
+    }
+}
+
+/// Reloads `filter`'s files as they change, every `reload_interval`.
+pub async fn run_reloads(filter: Arc<GeoFilter>) {
+    let mut ticker = tokio::time::interval(filter.config.reload_interval);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let reloading = filter.clone();
+        match tokio::task::spawn_blocking(move || reloading.reload_if_changed()).await {
+            Ok(Ok(true)) => info!("Reloaded GeoIP databases and rules"),
+            Ok(Ok(false)) => {}
+            Ok(Err(e)) => error!("Failed to reload GeoIP rules: {:#}", e),
+            Err(e) => error!("GeoIP reload panicked: {}", e),
+        }
+    }
+}
+
+fn load(config: &GeoConfig) -> Result<Loaded> {
+    // Taken first, so a file replaced while loading is loaded again.
+    let modified = modified_times(config);
+    let open = |path: &Path| {
+        Reader::open_readfile(path)
+            .with_context(|| format!("Failed to open GeoIP database {}", path.display()))
+    };
+    let rules = std::fs::read(&config.rules)
+        .with_context(|| format!("Failed to read {}", config.rules.display()))?;
+    Ok(Loaded {
+        country: config.country_db.as_deref().map(open).transpose()?,
+        asn: config.asn_db.as_deref().map(open).transpose()?,
+        rules: serde_json::from_slice(&rules)
+            .with_context(|| format!("Invalid GeoIP rules in {}", config.rules.display()))?,
+        modified,
+    })
+}
+
+fn modified_times(config: &GeoConfig) -> Vec<Option<SystemTime>> {
+    [
+        config.country_db.as_deref(),
+        config.asn_db.as_deref(),
+        Some(config.rules.as_path()),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(country: Option<&str>, asn: Option<u32>) -> Location {
+        Location {
+            country: country.map(str::to_string),
+            asn,
+        }
+    }
+
+    #[test]
+    fn test_deny_rules_win_over_allow_rules() {
+        let rules = GeoRules {
+            allow_countries: HashSet::from(["NZ".to_string()]),
+            deny_asns: HashSet::from([64496]),
+            ..GeoRules::default()
+        };
+        assert!(rules.admits(&at(Some("NZ"), Some(64500))));
+        assert!(!rules.admits(&at(Some("NZ"), Some(64496))));
+        assert!(!rules.admits(&at(Some("AU"), None)));
+        assert!(rules.admits(&at(None, None)));
+        let strict = GeoRules {
+            deny_unknown: true,
+            ..rules
+        };
+        assert!(!strict.admits(&at(None, None)));
+    }
+}
@@ -0,0 +1,24 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+
+/// Callbacks around each connection's lifetime, for applications embedding
+/// `ChatServer` that keep their own per-user resources or sync an external
+/// presence system. Every method does nothing by default.
+#[async_trait]
+pub trait ConnectionHooks: Send + Sync {
+    /// Called when a client connects, before anything is read from it.
+    /// Returning an error rejects the connection; the client is sent the
+    /// error's message before it is closed.
+    async fn on_connect(&self, _addr: SocketAddr) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called whenever the connection takes on an identity: as a guest, by
+    /// signing in, or by resuming a session.
+    async fn on_authenticated(&self, _addr: SocketAddr, _user: &str, _guest: bool) {}
+
+    /// Called once the client has gone; `user` is who it was signed in as,
+    /// if anyone. Not called for connections `on_connect` rejected.
+    async fn on_disconnect(&self, _addr: SocketAddr, _user: Option<&str>) {}
+}
@@ -0,0 +1,432 @@
+use crate::admin::{AdminControl, ConnectionInfo, Readiness};
+use crate::analytics::AnalyticsReport;
+use crate::apikey::{ApiKeyInfo, ApiKeys, IssuedApiKey, KeyScope};
+use crate::blob::{BlobStore, MAX_BLOB_SIZE, blob_id, is_valid_blob_id};
+use crate::delivery::{DeadLetter, DeadLetterStore};
+use crate::invite::InviteError;
+use crate::metrics::{Metrics, MetricsSnapshot};
+use crate::protocol::{AnnouncementLevel, FileRef};
+use crate::quota::{ResourceTracker, ResourceUsage};
+use anyhow::Result;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, Query, Request, State};
+use axum::http::StatusCode;
+use axum::http::header::AUTHORIZATION;
+use axum::middleware::{self, Next};
+use axum::response::{Html, Json, Response};
+use axum::routing::{delete, get, post};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tokio::net::TcpListener;
+use tracing::{error, info};
+
+#[derive(Deserialize)]
+struct UploadParams {
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct DeadLetterParams {
+    user: Option<String>,
+}
+
+/// Body of `POST /admin/api-keys`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct IssueKeyRequest {
+    pub account: String,
+    #[serde(default)]
+    pub scope: KeyScope,
+}
+
+#[derive(Deserialize)]
+struct RotateParams {
+    /// How long the old key keeps working; immediately by default.
+    grace_secs: Option<u64>,
+}
+
+/// Body of `POST /admin/kick` and `POST /admin/bans`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KickRequest {
+    pub user: String,
+    pub reason: Option<String>,
+}
+
+/// Response to `POST /admin/kick` and `POST /admin/bans`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KickResponse {
+    /// Connections closed.
+    pub kicked: usize,
+}
+
+/// Body of `POST /admin/announce`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnnounceRequest {
+    /// Announces to this room's viewers rather than everyone.
+    #[serde(default)]
+    pub room: Option<String>,
+    pub text: String,
+    #[serde(default)]
+    pub level: AnnouncementLevel,
+}
+
+/// Whether a `POST /admin/announce` went out rather than being held back.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AnnounceResponse {
+    pub sent: bool,
+}
+
+/// Response to `POST /invites/{token}`: the room joined.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RedeemInviteResponse {
+    pub room: String,
+}
+
+/// Body of `POST /admin/notice`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NoticeRequest {
+    pub text: String,
+}
+
+/// Server state the admin routes use, taken from `ChatServer::metrics`,
+/// `ChatServer::dead_letters`, `ChatServer::api_keys`,
+/// `ChatServer::resources` and `ChatServer::admin`.
+#[derive(Clone)]
+pub struct Admin {
+    pub metrics: Arc<Metrics>,
+    pub dead_letters: Arc<DeadLetterStore>,
+    pub api_keys: Arc<ApiKeys>,
+    pub resources: Arc<ResourceTracker>,
+    pub control: AdminControl,
+    /// Bearer token every admin route but the dashboard page requires,
+    /// sent as `Authorization: Bearer <token>`. Must not be empty.
+    pub token: String,
+}
+
+/// Body returned by `POST /blobs`; `file` can be sent as-is as a message's
+/// `attachment`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UploadResponse {
+    pub file: FileRef,
+    /// Path to fetch the blob from this endpoint.
+    pub url: String,
+}
+
+/// Builds the blob routes: `POST /blobs?name=...` with the raw file as the
+/// body, and `GET /blobs/{id}`. Uploads need an API key that may write,
+/// sent as `Authorization: Bearer <key>`.
+pub fn router(blobs: Arc<dyn BlobStore>, api_keys: Arc<ApiKeys>) -> Router {
+    Router::new()
+        .route("/blobs", post(upload))
+        .route_layer(middleware::from_fn_with_state(api_keys, require_api_key))
+        .route("/blobs/{id}", get(fetch))
+        .layer(DefaultBodyLimit::max(MAX_BLOB_SIZE as usize))
+        .with_state(blobs)
+}
+
+/// Serves the blob routes on `listener` until an error occurs.
+pub async fn serve(
+    listener: TcpListener,
+    blobs: Arc<dyn BlobStore>,
+    api_keys: Arc<ApiKeys>,
+) -> Result<()> {
+    info!("Blob HTTP endpoint bound to {}", listener.local_addr()?);
+    axum::serve(listener, router(blobs, api_keys)).await?;
+    Ok(())
+}
+
+/// Builds the admin routes:
+///
+/// - `GET /admin`, a dashboard page built on the routes below
+/// - `GET /admin/metrics`
+/// - `GET /admin/usage` for rooms' use of the resource limits
+/// - `GET /admin/analytics` for recent activity per interval and room
+/// - `GET /admin/connections`
+/// - `POST /admin/kick` with a `KickRequest`
+/// - `GET /admin/bans`, `POST` a `KickRequest` to ban and kick a user, and
+///   `DELETE /admin/bans/{user}` to lift a ban
+/// - `POST /admin/notice` with a `NoticeRequest`
+/// - `POST /admin/announce` with an `AnnounceRequest`
+/// - `GET /admin/dead-letters?user=...`
+/// - `GET /admin/api-keys`, and `POST` an `IssueKeyRequest` to issue one
+/// - `POST /admin/api-keys/{id}/rotate?grace_secs=...`
+/// - `DELETE /admin/api-keys/{id}` to revoke a key
+///
+/// All of them need `Admin::token`.
+///
+/// `GET /ready` answers readiness probes without the token: 200 with a
+/// `Readiness` body while the server takes clients and its background jobs
+/// are running, 503 while it drains or a job is waiting to restart.
+///
+/// Alongside them, for users rather than operators, `POST
+/// /invites/{token}` redeems an invite for the account of the API key
+/// sent as `Authorization: Bearer <key>`, answering with a
+/// `RedeemInviteResponse`.
+pub fn admin_router(admin: Admin) -> Router {
+    Router::new()
+        .route("/admin/metrics", get(metrics))
+        .route("/admin/usage", get(usage))
+        .route("/admin/analytics", get(analytics))
+        .route("/admin/connections", get(connections))
+        .route("/admin/kick", post(kick))
+        .route("/admin/bans", get(list_bans).post(ban))
+        .route("/admin/bans/{user}", delete(unban))
+        .route("/admin/notice", post(notice))
+        .route("/admin/announce", post(announce))
+        .route("/admin/dead-letters", get(dead_letters))
+        .route("/admin/api-keys", get(list_keys).post(issue_key))
+        .route("/admin/api-keys/{id}/rotate", post(rotate_key))
+        .route("/admin/api-keys/{id}", delete(revoke_key))
+        .route_layer(middleware::from_fn_with_state(admin.clone(), require_token))
+        .route("/admin", get(dashboard))
+        .route("/ready", get(ready))
+        .route("/invites/{token}", post(redeem_invite))
+        .with_state(admin)
+}
+
+/// Serves the admin routes on `listener` until an error occurs. Refuses to
+/// start without a token.
+pub async fn serve_admin(listener: TcpListener, admin: Admin) -> Result<()> {
+    if admin.token.is_empty() {
+        anyhow::bail!("The admin endpoint needs a token");
+    }
+    info!("Admin HTTP endpoint bound to {}", listener.local_addr()?);
+    axum::serve(listener, admin_router(admin)).await?;
+    Ok(())
+}
+
+/// Rejects requests without the admin token, comparing it in constant time.
+async fn require_token(
+    State(admin): State<Admin>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let presented = bearer(&request).unwrap_or_default();
+    let matches =
+        !admin.token.is_empty() && bool::from(presented.as_bytes().ct_eq(admin.token.as_bytes()));
+    if !matches {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(next.run(request).await)
+}
+
+/// Rejects uploads without an API key that may write.
+async fn require_api_key(
+    State(api_keys): State<Arc<ApiKeys>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, StatusCode> {
+    let key = bearer(&request)
+        .and_then(|key| api_keys.verify(key, unix_time()))
+        .ok_or(StatusCode::UNAUTHORIZED)?;
+    if key.scope.read_only {
+        return Err(StatusCode::FORBIDDEN);
+    }
+    Ok(next.run(request).await)
+}
+
+/// The credential sent as `Authorization: Bearer <credential>`.
+fn bearer(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// The dashboard is static; it asks for the token and sends it with each
+/// call it makes.
+async fn dashboard() -> Html<&'static str> {
+    Html(include_str!("dashboard.html"))
+}
+
+async fn analytics(State(admin): State<Admin>) -> Json<AnalyticsReport> {
+    Json(admin.control.analytics())
+}
+
+async fn connections(State(admin): State<Admin>) -> Json<Vec<ConnectionInfo>> {
+    Json(admin.control.connections())
+}
+
+async fn kick(State(admin): State<Admin>, Json(request): Json<KickRequest>) -> Json<KickResponse> {
+    let kicked = admin.control.kick(&request.user, request.reason.as_deref());
+    Json(KickResponse { kicked })
+}
+
+async fn list_bans(State(admin): State<Admin>) -> Json<Vec<String>> {
+    Json(admin.control.bans())
+}
+
+async fn ban(State(admin): State<Admin>, Json(request): Json<KickRequest>) -> Json<KickResponse> {
+    let kicked = admin.control.ban(&request.user, request.reason.as_deref());
+    Json(KickResponse { kicked })
+}
+
+async fn unban(State(admin): State<Admin>, Path(user): Path<String>) -> StatusCode {
+    if !admin.control.unban(&user) {
+        return StatusCode::NOT_FOUND;
+    }
+    StatusCode::NO_CONTENT
+}
+
+async fn notice(
+    State(admin): State<Admin>,
+    Json(request): Json<NoticeRequest>,
+) -> Result<StatusCode, StatusCode> {
+    admin.control.notice(&request.text).map_err(|e| {
+        error!("Notice failed: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn announce(
+    State(admin): State<Admin>,
+    Json(request): Json<AnnounceRequest>,
+) -> Result<Json<AnnounceResponse>, (StatusCode, String)> {
+    let sent = admin
+        .control
+        .announce(request.room.as_deref(), &request.text, request.level)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    Ok(Json(AnnounceResponse { sent }))
+}
+
+async fn redeem_invite(
+    State(admin): State<Admin>,
+    Path(token): Path<String>,
+    request: Request,
+) -> Result<Json<RedeemInviteResponse>, (StatusCode, String)> {
+    let key = bearer(&request)
+        .and_then(|key| admin.api_keys.verify(key, unix_time()))
+        .ok_or((StatusCode::UNAUTHORIZED, "Invalid API key".to_string()))?;
+    if key.scope.read_only || admin.control.registry.is_banned(&key.account) {
+        return Err((StatusCode::FORBIDDEN, "Not allowed to join".to_string()));
+    }
+    let room = admin
+        .control
+        .redeem_invite(&key.account, &token)
+        .map_err(|e| {
+            let status = match e.downcast_ref::<InviteError>() {
+                Some(InviteError::Unknown) => StatusCode::NOT_FOUND,
+                Some(InviteError::Expired | InviteError::UsedUp) => StatusCode::GONE,
+                Some(InviteError::RoomFull) => StatusCode::CONFLICT,
+                None => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            (status, e.to_string())
+        })?;
+    Ok(Json(RedeemInviteResponse { room }))
+}
+
+async fn ready(State(admin): State<Admin>) -> (StatusCode, Json<Readiness>) {
+    let readiness = admin.control.readiness();
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
+
+async fn metrics(State(admin): State<Admin>) -> Json<MetricsSnapshot> {
+    Json(admin.metrics.snapshot())
+}
+
+async fn usage(State(admin): State<Admin>) -> Json<ResourceUsage> {
+    Json(admin.resources.usage(unix_time()))
+}
+
+async fn dead_letters(
+    State(admin): State<Admin>,
+    Query(params): Query<DeadLetterParams>,
+) -> Json<Vec<DeadLetter>> {
+    Json(admin.dead_letters.list(params.user.as_deref()))
+}
+
+async fn list_keys(State(admin): State<Admin>) -> Json<Vec<ApiKeyInfo>> {
+    Json(admin.api_keys.list())
+}
+
+async fn issue_key(
+    State(admin): State<Admin>,
+    Json(request): Json<IssueKeyRequest>,
+) -> Json<IssuedApiKey> {
+    let issued = admin
+        .api_keys
+        .issue(request.account, request.scope, unix_time());
+    info!(
+        "Issued API key {} for {}",
+        issued.info.id, issued.info.account
+    );
+    Json(issued)
+}
+
+async fn rotate_key(
+    State(admin): State<Admin>,
+    Path(id): Path<String>,
+    Query(params): Query<RotateParams>,
+) -> Result<Json<IssuedApiKey>, StatusCode> {
+    let grace = Duration::from_secs(params.grace_secs.unwrap_or(0));
+    let issued = admin
+        .api_keys
+        .rotate(&id, grace, unix_time())
+        .ok_or(StatusCode::NOT_FOUND)?;
+    info!("Rotated API key {} to {}", id, issued.info.id);
+    Ok(Json(issued))
+}
+
+async fn revoke_key(State(admin): State<Admin>, Path(id): Path<String>) -> StatusCode {
+    if !admin.api_keys.revoke(&id) {
+        return StatusCode::NOT_FOUND;
+    }
+    info!("Revoked API key {}", id);
+    StatusCode::NO_CONTENT
+}
+
+/// Seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+async fn upload(
+    State(blobs): State<Arc<dyn BlobStore>>,
+    Query(params): Query<UploadParams>,
+    body: Bytes,
+) -> Result<Json<UploadResponse>, StatusCode> {
+    let id = blob_id(&body);
+    let file = FileRef {
+        id: id.clone(),
+        name: params.name.unwrap_or_else(|| id.clone()),
+        size: body.len() as u64,
+    };
+    blobs.put(&id, body).await.map_err(|e| {
+        error!("Blob upload failed: {:?}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    info!("Stored {} ({} bytes) over HTTP", file.name, file.size);
+    Ok(Json(UploadResponse {
+        url: format!("/blobs/{}", id),
+        file,
+    }))
+}
+
+async fn fetch(
+    State(blobs): State<Arc<dyn BlobStore>>,
+    Path(id): Path<String>,
+) -> Result<Bytes, StatusCode> {
+    if !is_valid_blob_id(&id) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    match blobs.get(&id).await {
+        Ok(Some(data)) => Ok(data),
+        Ok(None) => Err(StatusCode::NOT_FOUND),
+        Err(e) => {
+            error!("Blob fetch failed: {:?}", e);
+            Err(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
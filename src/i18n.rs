@@ -0,0 +1,238 @@
+use crate::protocol::ServerFrame;
+use anyhow::{Result, anyhow};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::path::Path;
+use tracing::info;
+
+/// The language server-generated text is written in; never translated.
+pub const DEFAULT_LOCALE: &str = "en";
+
+/// Translations of server-generated text into one locale, keyed by the
+/// English text. A `{}` in a key matches any text, which is substituted in
+/// order for the `{}`s of the translation; `{0}`, `{1}`, ... pick a match
+/// by position, for languages that need another word order.
+#[derive(Debug, Clone, Default)]
+pub struct Catalog {
+    exact: HashMap<String, String>,
+    /// Keys with placeholders, longest first so the most specific wins.
+    patterns: Vec<(String, String)>,
+}
+
+impl Catalog {
+    pub fn new(entries: HashMap<String, String>) -> Self {
+        let (patterns, exact): (HashMap<_, _>, HashMap<_, _>) =
+            entries.into_iter().partition(|(key, _)| key.contains("{}"));
+        let mut patterns: Vec<(String, String)> = patterns.into_iter().collect();
+        patterns.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then_with(|| a.0.cmp(&b.0)));
+        Catalog { exact, patterns }
+    }
+
+    /// Returns the translation of `text`, or `None` if there isn't one.
+    pub fn translate(&self, text: &str) -> Option<String> {
+        if let Some(translation) = self.exact.get(text) {
+            return Some(translation.clone());
+        }
+        self.patterns.iter().find_map(|(key, translation)| {
+            let captured = capture(key, text)?;
+            Some(fill(translation, &captured))
+        })
+    }
+}
+
+/// Catalogs by locale, for translating what the server says to each client
+/// into the locale it asked for.
+#[derive(Debug, Clone, Default)]
+pub struct Catalogs {
+    by_locale: HashMap<String, Catalog>,
+}
+
+impl Catalogs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds or replaces the catalog for `locale`, e.g. "fr" or "pt-BR".
+    pub fn with_catalog(mut self, locale: impl Into<String>, catalog: Catalog) -> Self {
+        self.by_locale.insert(locale.into(), catalog);
+        self
+    }
+
+    /// Reads every `<locale>.json` file in `dir`, each a JSON object
+    /// mapping English text to its translation.
+    pub async fn load_dir(dir: impl AsRef<Path>) -> Result<Catalogs> {
+        let mut catalogs = Catalogs::new();
+        let mut entries = tokio::fs::read_dir(dir.as_ref()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().is_none_or(|extension| extension != "json") {
+                continue;
+            }
+            let locale = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .ok_or_else(|| anyhow!("Bad catalog file name {}", path.display()))?;
+            let entries: HashMap<String, String> =
+                serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+            info!("Loaded {} translations for {}", entries.len(), locale);
+            catalogs = catalogs.with_catalog(locale, Catalog::new(entries));
+        }
+        Ok(catalogs)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_locale.is_empty()
+    }
+
+    /// Picks the best available locale for a client's `preferred` ones,
+    /// most preferred first: an exact match (ignoring case), then the same
+    /// language ("pt" for "pt-BR"), else `DEFAULT_LOCALE`.
+    pub fn negotiate(&self, preferred: &[String]) -> String {
+        let find = |wanted: &str| {
+            std::iter::once(DEFAULT_LOCALE)
+                .chain(self.by_locale.keys().map(String::as_str))
+                .find(|locale| locale.eq_ignore_ascii_case(wanted))
+                .map(str::to_string)
+        };
+        preferred
+            .iter()
+            .find_map(|wanted| {
+                find(wanted).or_else(|| find(wanted.split(['-', '_']).next().unwrap_or_default()))
+            })
+            .unwrap_or_else(|| DEFAULT_LOCALE.to_string())
+    }
+
+    /// Translates `text` into `locale`, leaving it as is if there's no
+    /// translation.
+    pub fn translate<'a>(&self, locale: &str, text: &'a str) -> Cow<'a, str> {
+        match self
+            .by_locale
+            .get(locale)
+            .and_then(|catalog| catalog.translate(text))
+        {
+            Some(translation) => Cow::Owned(translation),
+            None => Cow::Borrowed(text),
+        }
+    }
+
+    /// Translates the server-generated text of `frame`: errors, notices,
+    /// announcements and messages of the day.
+    pub fn localize(&self, locale: &str, mut frame: ServerFrame) -> ServerFrame {
+        if locale == DEFAULT_LOCALE || self.is_empty() {
+            return frame;
+        }
+        match &mut frame {
+            ServerFrame::Error { message: text }
+            | ServerFrame::Notice { text }
+            | ServerFrame::Announcement { text, .. }
+            | ServerFrame::Motd { text, .. } => {
+                if let Cow::Owned(translation) = self.translate(locale, text) {
+                    *text = translation;
+                }
+            }
+            _ => {}
+        }
+        frame
+    }
+}
+
+/// Matches `text` against `pattern`, returning what each `{}` matched.
+/// A `{}` matches as little as it can, except the last one in `pattern`.
+fn capture<'a>(pattern: &str, text: &'a str) -> Option<Vec<&'a str>> {
+    let mut literals = pattern.split("{}");
+    let mut rest = text.strip_prefix(literals.next()?)?;
+    let literals: Vec<&str> = literals.collect();
+    let mut captured = Vec::new();
+    for (i, literal) in literals.iter().enumerate() {
+        if i + 1 == literals.len() {
+            captured.push(rest.strip_suffix(literal)?);
+            rest = "";
+        } else {
+            let end = rest.find(literal)?;
+            captured.push(&rest[..end]);
+            rest = &rest[end + literal.len()..];
+        }
+    }
+    rest.is_empty().then_some(captured)
+}
+
+/// Substitutes `captured` into the placeholders of `translation`.
+fn fill(translation: &str, captured: &[&str]) -> String {
+    let mut filled = String::new();
+    let mut next = 0;
+    let mut rest = translation;
+    while let Some(start) = rest.find('{') {
+        filled.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('}') else {
+            rest = &rest[start..];
+            break;
+        };
+        let index = match &after[..end] {
+            "" => {
+                next += 1;
+                Some(next - 1)
+            }
+            digits => digits.parse::<usize>().ok(),
+        };
+        match index.and_then(|index| captured.get(index)) {
+            Some(value) => filled.push_str(value),
+            None => filled.push_str(&rest[start..start + end + 2]),
+        }
+        rest = &after[end + 1..];
+    }
+    filled.push_str(rest);
+    filled
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn french() -> Catalogs {
+        let entries = [
+            ("Wrong password", "Mot de passe incorrect"),
+            ("Room {} does not exist", "Le salon {} n'existe pas"),
+            (
+                "Only {} can {} in {}",
+                "Dans {2}, seuls les {0} peuvent {1}",
+            ),
+        ];
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+        Catalogs::new().with_catalog("fr", Catalog::new(entries))
+    }
+
+    #[test]
+    fn test_translate_with_placeholders() {
+        let catalogs = french();
+        assert_eq!(
+            catalogs.translate("fr", "Wrong password"),
+            "Mot de passe incorrect"
+        );
+        assert_eq!(
+            catalogs.translate("fr", "Room lobby does not exist"),
+            "Le salon lobby n'existe pas"
+        );
+        assert_eq!(
+            catalogs.translate("fr", "Only moderators can pin messages in lobby"),
+            "Dans lobby, seuls les moderators peuvent pin messages"
+        );
+        assert_eq!(catalogs.translate("fr", "Unknown text"), "Unknown text");
+        assert_eq!(catalogs.translate("de", "Wrong password"), "Wrong password");
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let catalogs = french();
+        let preferred = |locales: &[&str]| -> Vec<String> {
+            locales.iter().map(|locale| locale.to_string()).collect()
+        };
+        assert_eq!(catalogs.negotiate(&preferred(&["fr-CA", "en"])), "fr");
+        assert_eq!(catalogs.negotiate(&preferred(&["de", "FR"])), "fr");
+        assert_eq!(catalogs.negotiate(&preferred(&["en-GB", "fr"])), "en");
+        assert_eq!(catalogs.negotiate(&preferred(&["de"])), "en");
+    }
+}
@@ -0,0 +1,168 @@
+use crate::quota::ResourceTracker;
+use crate::registry::Registry;
+use crate::room::{Role, RoomConfig};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Invites one room may have outstanding; creating another drops the
+/// oldest.
+pub const MAX_INVITES_PER_ROOM: usize = 100;
+
+/// A link into a room that skips its invite permission: whoever redeems
+/// the token becomes a member, until it expires or runs out of uses.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Invite {
+    pub token: String,
+    pub room: String,
+    pub created_by: String,
+    /// Unix time (seconds) the invite was created.
+    pub created_at: u64,
+    /// Unix time (seconds) after which it can't be redeemed; never if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Redemptions allowed; unlimited if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_uses: Option<u32>,
+    #[serde(default)]
+    pub uses: u32,
+}
+
+impl Invite {
+    /// Checks the invite can still be redeemed at the Unix time `now`.
+    pub fn check(&self, now: u64) -> Result<(), InviteError> {
+        if self.expires_at.is_some_and(|expires_at| now >= expires_at) {
+            return Err(InviteError::Expired);
+        }
+        if self.max_uses.is_some_and(|max_uses| self.uses >= max_uses) {
+            return Err(InviteError::UsedUp);
+        }
+        Ok(())
+    }
+}
+
+/// Why an invite couldn't be redeemed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InviteError {
+    /// No invite has the token, or its room is gone.
+    Unknown,
+    Expired,
+    UsedUp,
+    /// The room has no free slot.
+    RoomFull,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            InviteError::Unknown => "Unknown invite",
+            InviteError::Expired => "This invite has expired",
+            InviteError::UsedUp => "This invite has been used up",
+            InviteError::RoomFull => "The room is full",
+        })
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+/// A room's settings before and after a redemption made `user` a member.
+pub(crate) struct Joined {
+    pub before: RoomConfig,
+    pub after: RoomConfig,
+}
+
+/// Makes `user` a member of the room `token` invites to, using up one of
+/// its redemptions. Returns the room, and its settings before and after
+/// if `user` wasn't already taking part in it.
+pub(crate) fn redeem(
+    registry: &Registry,
+    resources: &ResourceTracker,
+    user: &str,
+    token: &str,
+    now: u64,
+) -> Result<(String, Option<Joined>), InviteError> {
+    let invite = registry.invite(token).ok_or(InviteError::Unknown)?;
+    if registry.room_config(&invite.room).role_of(user) >= Role::Members {
+        return Ok((invite.room, None));
+    }
+    invite.check(now)?;
+    let room = invite.room;
+    registry.use_invite(token, now)?;
+    let joined = registry.update_room(&room, |config| {
+        let before = config.clone();
+        resources
+            .check_new_member(&room, config.members.len())
+            .map_err(|_| InviteError::RoomFull)?;
+        config
+            .add_member(user, false)
+            .map_err(|_| InviteError::RoomFull)?;
+        Ok(Joined {
+            before,
+            after: config.clone(),
+        })
+    });
+    match joined.unwrap_or(Err(InviteError::Unknown)) {
+        Ok(joined) => Ok((room, Some(joined))),
+        Err(e) => {
+            registry.refund_invite(token);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_invites_expire_and_run_out() {
+        let registry = Registry::new();
+        let resources = ResourceTracker::default();
+        registry.set_room(
+            "plans",
+            RoomConfig {
+                max_members: Some(2),
+                ..Default::default()
+            },
+        );
+        let invite = Invite {
+            token: "t".to_string(),
+            room: "plans".to_string(),
+            created_by: "avery".to_string(),
+            created_at: 100,
+            expires_at: Some(200),
+            max_uses: Some(1),
+            uses: 0,
+        };
+        registry.add_invite(invite.clone());
+        assert_eq!(
+            redeem(&registry, &resources, "blake", "t", 200).err(),
+            Some(InviteError::Expired)
+        );
+        let (room, joined) = redeem(&registry, &resources, "blake", "t", 150).unwrap();
+        assert_eq!(room, "plans");
+        assert!(joined.unwrap().after.members.contains("blake"));
+        // Members redeem it again without using it up.
+        assert!(redeem(&registry, &resources, "blake", "t", 150).is_ok());
+        assert_eq!(
+            redeem(&registry, &resources, "casey", "t", 150).err(),
+            Some(InviteError::UsedUp)
+        );
+
+        // A failed join gives the use back.
+        registry.add_invite(Invite {
+            token: "u".to_string(),
+            max_uses: None,
+            ..invite
+        });
+        registry.update_room("plans", |config| config.max_members = Some(1));
+        assert_eq!(
+            redeem(&registry, &resources, "casey", "u", 150).err(),
+            Some(InviteError::RoomFull)
+        );
+        assert_eq!(registry.invite("u").unwrap().uses, 0);
+        assert_eq!(
+            redeem(&registry, &resources, "casey", "nope", 150).err(),
+            Some(InviteError::Unknown)
+        );
+    }
+}
@@ -0,0 +1,215 @@
+use crate::auth::{Authenticator, RoleMap, SignIn};
+use anyhow::Result;
+use async_trait::async_trait;
+use ldap3::{Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry, ldap_escape};
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// LDAP's result code for a bind with the wrong password.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Settings for `LdapAuthenticator`. Filters may use `{user}` for the
+/// nickname signing in and, in `group_filter`, `{dn}` for the user's
+/// entry; both are escaped before they're substituted.
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    /// The server, as `ldap://host:389` or `ldaps://host:636`.
+    pub url: String,
+    /// Upgrades an `ldap://` connection with StartTLS.
+    pub starttls: bool,
+    /// The account users are looked up as; anonymous if unset.
+    pub bind_dn: Option<String>,
+    pub bind_password: String,
+    /// Where users are searched for, including its subtree.
+    pub base_dn: String,
+    /// Matches the one entry of the user signing in.
+    pub user_filter: String,
+    /// The user entry's attribute listing the DNs of their groups.
+    pub group_attribute: String,
+    /// Matches the user's groups, for directories without a
+    /// `group_attribute`. Searched for under `base_dn`.
+    pub group_filter: Option<String>,
+    /// Room roles for the user's groups, named by their first RDN's value,
+    /// e.g. `engineering` for `cn=engineering,ou=groups,dc=example,dc=com`.
+    pub roles: RoleMap,
+    /// How long to wait to connect to the server.
+    pub timeout: Duration,
+}
+
+impl LdapConfig {
+    /// Settings for an OpenLDAP-style directory: users found by `uid`,
+    /// their groups by `memberOf`.
+    pub fn new(url: impl Into<String>, base_dn: impl Into<String>) -> Self {
+        LdapConfig {
+            url: url.into(),
+            starttls: false,
+            bind_dn: None,
+            bind_password: String::new(),
+            base_dn: base_dn.into(),
+            user_filter: "(uid={user})".to_string(),
+            group_attribute: "memberOf".to_string(),
+            group_filter: None,
+            roles: RoleMap::new(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Settings for Active Directory: users found by `sAMAccountName`.
+    pub fn active_directory(url: impl Into<String>, base_dn: impl Into<String>) -> Self {
+        LdapConfig {
+            user_filter: "(&(objectClass=user)(sAMAccountName={user}))".to_string(),
+            ..LdapConfig::new(url, base_dn)
+        }
+    }
+}
+
+/// Authenticates users with their directory password, for deployments
+/// whose identities live in LDAP or Active Directory. The client sends its
+/// password as the `Authenticate` token; the server looks the user up with
+/// `user_filter` and binds as them with it, then grants room roles for
+/// their groups. Each sign-in uses its own connection.
+pub struct LdapAuthenticator {
+    config: LdapConfig,
+}
+
+impl LdapAuthenticator {
+    pub fn new(config: LdapConfig) -> Self {
+        LdapAuthenticator { config }
+    }
+
+    async fn connect(&self) -> Result<Ldap> {
+        let settings = LdapConnSettings::new()
+            .set_conn_timeout(self.config.timeout)
+            .set_starttls(self.config.starttls);
+        let (conn, ldap) = LdapConnAsync::with_settings(settings, &self.config.url).await?;
+        ldap3::drive!(conn);
+        Ok(ldap)
+    }
+
+    /// Binds as the lookup account, if there is one.
+    async fn bind_lookup(&self, ldap: &mut Ldap) -> Result<()> {
+        if let Some(dn) = &self.config.bind_dn {
+            ldap.simple_bind(dn, &self.config.bind_password)
+                .await?
+                .success()?;
+        }
+        Ok(())
+    }
+
+    async fn sign_in_with(
+        &self,
+        ldap: &mut Ldap,
+        user: &str,
+        password: &str,
+    ) -> Result<Option<SignIn>> {
+        self.bind_lookup(ldap).await?;
+        let filter = substitute(&self.config.user_filter, user, "");
+        let attributes = [self.config.group_attribute.as_str()];
+        let (entries, _) = ldap
+            .search(&self.config.base_dn, Scope::Subtree, &filter, attributes)
+            .await?
+            .success()?;
+        if entries.len() != 1 {
+            if entries.len() > 1 {
+                warn!("{} directory entries match {}", entries.len(), filter);
+            }
+            return Ok(None);
+        }
+        let entry = SearchEntry::construct(entries.into_iter().next().unwrap());
+
+        let mut groups: Vec<String> = entry
+            .attrs
+            .get(&self.config.group_attribute)
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect();
+        if let Some(group_filter) = &self.config.group_filter {
+            let filter = substitute(group_filter, user, &entry.dn);
+            let (entries, _) = ldap
+                .search(&self.config.base_dn, Scope::Subtree, &filter, ["1.1"])
+                .await?
+                .success()?;
+            groups.extend(entries.into_iter().map(|e| SearchEntry::construct(e).dn));
+        }
+
+        let bound = ldap.simple_bind(&entry.dn, password).await?;
+        if bound.rc == INVALID_CREDENTIALS {
+            return Ok(None);
+        }
+        bound.success()?;
+        let names: Vec<String> = groups.iter().filter_map(|dn| group_name(dn)).collect();
+        Ok(Some(SignIn {
+            user: user.to_string(),
+            grants: self.config.roles.grants(names.iter().map(String::as_str)),
+        }))
+    }
+}
+
+/// Fills `{user}` and `{dn}` into `filter`, escaped.
+fn substitute(filter: &str, user: &str, dn: &str) -> String {
+    filter
+        .replace("{user}", &ldap_escape(user))
+        .replace("{dn}", &ldap_escape(dn))
+}
+
+/// Returns the value of a group DN's first RDN, its name.
+fn group_name(dn: &str) -> Option<String> {
+    let (_, rest) = dn.split_once('=')?;
+    let mut name = String::new();
+    let mut chars = rest.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => name.extend(chars.next()),
+            ',' | '+' => break,
+            c => name.push(c),
+        }
+    }
+    Some(name.trim().to_string())
+}
+
+#[async_trait]
+impl Authenticator for LdapAuthenticator {
+    async fn authenticate(&self, user: &str, token: &str) -> Result<bool> {
+        Ok(self.sign_in(user, token).await?.is_some())
+    }
+
+    async fn sign_in(&self, user: &str, token: &str) -> Result<Option<SignIn>> {
+        // An empty password makes a bind anonymous, which always succeeds.
+        if user.is_empty() || token.is_empty() {
+            return Ok(None);
+        }
+        let mut ldap = self.connect().await?;
+        let signed_in = self.sign_in_with(&mut ldap, user, token).await;
+        if let Err(e) = ldap.unbind().await {
+            debug!("LDAP unbind failed: {}", e);
+        }
+        signed_in
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filters_escape_substitutions() {
+        assert_eq!(
+            substitute("(&(uid={user})(member={dn}))", "*)(uid=*", "cn=a\\,b"),
+            "(&(uid=\\2a\\29\\28uid=\\2a)(member=cn=a\\5c,b))"
+        );
+    }
+
+    #[test]
+    fn test_group_name_is_first_rdn_value() {
+        assert_eq!(
+            group_name("cn=engineering,ou=groups,dc=example"),
+            Some("engineering".to_string())
+        );
+        assert_eq!(
+            group_name("CN=Sales\\, EMEA,OU=Groups"),
+            Some("Sales, EMEA".to_string())
+        );
+        assert_eq!(group_name("engineering"), None);
+    }
+}
@@ -1,7 +1,72 @@
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod admin;
+pub mod analytics;
+pub mod announce;
+pub mod apikey;
+pub mod archive;
+pub mod auth;
+pub mod blob;
+pub mod blocking;
+pub mod challenge;
+#[cfg(feature = "chaos")]
+pub mod chaos;
 pub mod client;
+pub mod codec;
+pub mod command;
+pub mod config;
+pub mod conformance;
+pub mod delivery;
+pub mod digest;
+pub mod export;
+pub mod fanout;
+#[cfg(feature = "geoip")]
+pub mod geoip;
+pub mod hooks;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod i18n;
+pub mod invite;
+#[cfg(feature = "ldap")]
+pub mod ldap;
+pub mod limits;
+pub mod memory;
+pub mod metrics;
+pub mod nickname;
+#[cfg(feature = "oidc")]
+pub mod oidc;
+pub mod onboarding;
+pub mod outbound;
+pub mod pool;
+pub mod preview;
 pub mod protocol;
+pub mod push;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod quota;
+pub mod recording;
+pub mod registry;
+pub mod replay;
+pub mod retention;
+pub mod room;
+pub mod router;
 pub mod runtime;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod sanitize;
 pub mod server;
+pub mod shortcode;
+pub mod snapshot;
+pub mod store;
+pub mod supervisor;
+pub mod tasks;
+pub mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod transport;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod wal;
 
 // Re-export public item for convenience
 pub use server::ChatServer;
@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Limits on connections, so floods of idle or half-open sockets can't
+/// exhaust the server. Nothing is limited by default.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    /// Most simultaneous connections from one IP address. Connections
+    /// over the limit are closed as soon as they are accepted.
+    pub max_per_ip: Option<usize>,
+    /// How long a new connection has to complete its handshake: solve any
+    /// challenge, then authenticate when authentication is required or
+    /// otherwise send a frame. Connections that don't are closed.
+    pub handshake_timeout: Option<Duration>,
+}
+
+/// Counts open connections per IP address against `max_per_ip`.
+#[derive(Debug, Default)]
+pub struct IpCounter {
+    max_per_ip: Option<usize>,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl IpCounter {
+    pub fn new(max_per_ip: Option<usize>) -> Self {
+        IpCounter {
+            max_per_ip,
+            open: Mutex::default(),
+        }
+    }
+
+    /// Counts a connection from `ip`, or returns `None` if `ip` already
+    /// has as many as it may. The connection is counted until the
+    /// returned slot is dropped.
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if self.max_per_ip.is_some_and(|max| *count >= max) {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            counter: self.clone(),
+            ip,
+        })
+    }
+
+    /// Connections currently open from `ip`.
+    pub fn open(&self, ip: IpAddr) -> usize {
+        self.open.lock().unwrap().get(&ip).copied().unwrap_or(0)
+    }
+}
+
+/// One connection counted by an `IpCounter`.
+#[derive(Debug)]
+pub struct IpSlot {
+    counter: Arc<IpCounter>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.counter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slots_are_capped_per_ip() {
+        let counter = Arc::new(IpCounter::new(Some(2)));
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let first = counter.acquire(ip).unwrap();
+        let _second = counter.acquire(ip).unwrap();
+        assert!(counter.acquire(ip).is_none());
+        assert!(counter.acquire(IpAddr::from([10, 0, 0, 2])).is_some());
+
+        drop(first);
+        assert_eq!(counter.open(ip), 1);
+        assert!(counter.acquire(ip).is_some());
+    }
+}
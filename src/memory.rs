@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+/// What the server does while its memory budget is exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overload {
+    /// Stop reading from clients, so no new broadcasts are produced, until
+    /// slow connections drain their queues.
+    #[default]
+    Backpressure,
+    /// Disconnect the connections with the most queued bytes until usage
+    /// is back under the limit.
+    Shed,
+}
+
+/// Bytes buffered for clients across all connections, against an
+/// optional limit.
+#[derive(Debug, Default)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: AtomicUsize,
+    /// Bytes held by each connection.
+    connections: Mutex<HashMap<SocketAddr, usize>>,
+    /// Woken when usage drops back under the limit.
+    freed: Notify,
+    /// Woken when usage goes over the limit.
+    exhausted: Notify,
+}
+
+impl MemoryBudget {
+    /// A budget of `limit` bytes.
+    pub fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit: Some(limit),
+            ..Default::default()
+        }
+    }
+
+    /// Opens an account that charges `addr`'s buffers to this budget. A
+    /// connection may have several, e.g. one per file transfer.
+    pub fn account(self: &Arc<Self>, addr: SocketAddr) -> Account {
+        Account {
+            budget: self.clone(),
+            addr,
+            held: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bytes currently buffered.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Whether usage is over the limit.
+    pub fn exceeded(&self) -> bool {
+        self.limit.is_some_and(|limit| self.used() > limit)
+    }
+
+    /// Bytes currently buffered for each connection.
+    pub fn connections(&self) -> HashMap<SocketAddr, usize> {
+        self.connections.lock().unwrap().clone()
+    }
+
+    /// Waits until usage is under the limit.
+    pub async fn wait_for_room(&self) {
+        loop {
+            let mut freed = pin!(self.freed.notified());
+            freed.as_mut().enable();
+            if !self.exceeded() {
+                return;
+            }
+            freed.await;
+        }
+    }
+
+    /// Waits until usage is over the limit.
+    pub async fn wait_exceeded(&self) {
+        while !self.exceeded() {
+            self.exhausted.notified().await;
+        }
+    }
+
+    fn charge(&self, addr: SocketAddr, bytes: usize) {
+        *self.connections.lock().unwrap().entry(addr).or_default() += bytes;
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if self.limit.is_some_and(|limit| used > limit) {
+            self.exhausted.notify_one();
+        }
+    }
+
+    fn release(&self, addr: SocketAddr, bytes: usize) {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(held) = connections.get_mut(&addr) {
+            *held -= bytes;
+            if *held == 0 {
+                connections.remove(&addr);
+            }
+        }
+        drop(connections);
+        let used = self.used.fetch_sub(bytes, Ordering::Relaxed) - bytes;
+        if self.limit.is_some_and(|limit| used <= limit) {
+            self.freed.notify_waiters();
+        }
+    }
+}
+
+/// One connection's share of a `MemoryBudget`. Dropping it releases
+/// whatever it still has charged.
+#[derive(Debug)]
+pub struct Account {
+    budget: Arc<MemoryBudget>,
+    addr: SocketAddr,
+    /// Bytes charged through this account and not yet released.
+    held: AtomicUsize,
+}
+
+impl Account {
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    pub fn charge(&self, bytes: usize) {
+        self.held.fetch_add(bytes, Ordering::Relaxed);
+        self.budget.charge(self.addr, bytes);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.held.fetch_sub(bytes, Ordering::Relaxed);
+        self.budget.release(self.addr, bytes);
+    }
+}
+
+impl Drop for Account {
+    fn drop(&mut self) {
+        let held = *self.held.get_mut();
+        if held > 0 {
+            self.budget.release(self.addr, held);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accounts_charge_the_shared_budget() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let small = budget.account(SocketAddr::from(([127, 0, 0, 1], 1)));
+        let large = budget.account(SocketAddr::from(([127, 0, 0, 1], 2)));
+        small.charge(30);
+        large.charge(80);
+        assert!(budget.exceeded());
+        assert_eq!(budget.connections()[&large.addr()], 80);
+
+        large.release(20);
+        assert_eq!(budget.used(), 90);
+        assert!(!budget.exceeded());
+
+        drop(large);
+        assert_eq!(budget.used(), 30);
+        assert_eq!(budget.connections().len(), 1);
+        assert_eq!(budget.connections()[&small.addr()], 30);
+    }
+
+    #[test]
+    fn test_accounts_of_one_connection_release_their_own_charges() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        let queue = budget.account(addr);
+        let upload = budget.account(addr);
+        queue.charge(10);
+        upload.charge(50);
+        assert_eq!(budget.connections()[&addr], 60);
+
+        drop(upload);
+        assert_eq!(budget.used(), 10);
+        assert_eq!(budget.connections()[&addr], 10);
+        queue.release(10);
+        assert!(budget.connections().is_empty());
+    }
+}
@@ -0,0 +1,295 @@
+use crate::retention::PruneStats;
+use crate::tasks::TaskRegistry;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Buckets in a `Histogram`: one per power of two up to 2^30 (about 18
+/// minutes in microseconds), then one for anything larger.
+const HISTOGRAM_BUCKETS: usize = 32;
+
+/// Server-wide counters, readable through `ChatServer::metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    messages: AtomicU64,
+    pruned_messages: AtomicU64,
+    pruned_bytes: AtomicU64,
+    quota_rejections: AtomicU64,
+    resource_rejections: AtomicU64,
+    dead_letters: AtomicU64,
+    shed_connections: AtomicU64,
+    rejected_connections: AtomicU64,
+    handshake_timeouts: AtomicU64,
+    challenges: AtomicU64,
+    dropped_frames: AtomicU64,
+    handler_panics: AtomicU64,
+    queue_high_water: AtomicU64,
+    queue_depth: Histogram,
+    broadcast_lag: Histogram,
+    delivery: Histogram,
+    connections: Mutex<HashMap<SocketAddr, ConnectionStats>>,
+    tasks: Arc<TaskRegistry>,
+}
+
+/// Counts of recorded values in power-of-two buckets, so tail values can be
+/// read off without keeping every sample.
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    sum: AtomicU64,
+}
+
+/// A point-in-time copy of a `Histogram`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistogramSnapshot {
+    pub count: u64,
+    pub sum: u64,
+    /// `(upper bound, count)` for each non-empty bucket, lowest first. The
+    /// bucket for values above 2^30 has `u64::MAX` as its bound.
+    pub buckets: Vec<(u64, u64)>,
+}
+
+/// Work the server has done for one open connection.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectionStats {
+    /// Times the connection's task woke up to handle input it sent.
+    pub polls: u64,
+    /// Inbound frames handled.
+    pub frames: u64,
+    /// Time spent handling those frames, in microseconds.
+    pub busy_micros: u64,
+}
+
+/// A point-in-time copy of `Metrics`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct MetricsSnapshot {
+    /// Messages relayed to rooms; sample it twice for throughput.
+    pub messages: u64,
+    /// Messages deleted by retention pruning.
+    pub pruned_messages: u64,
+    /// Bytes of history reclaimed by retention pruning.
+    pub pruned_bytes: u64,
+    /// Messages rejected because their sender was over a quota.
+    pub quota_rejections: u64,
+    /// Messages, rooms and members rejected by resource limits.
+    pub resource_rejections: u64,
+    /// Messages given up on for a user and moved to the dead-letter store.
+    pub dead_letters: u64,
+    /// Connections dropped to get back under the memory limit.
+    pub shed_connections: u64,
+    /// Connections closed on accept because their IP address had too many
+    /// open.
+    pub rejected_connections: u64,
+    /// Connections closed for not completing their handshake in time.
+    pub handshake_timeouts: u64,
+    /// Connections made to solve a proof-of-work challenge.
+    pub challenges: u64,
+    /// Chat frames dropped because a client's outbound queue was full;
+    /// the client sees a gap in sequence numbers.
+    pub dropped_frames: u64,
+    /// Connections closed because their handler panicked.
+    pub handler_panics: u64,
+    /// Most chat frames any one client has had queued at once.
+    pub queue_high_water: u64,
+    /// Chat frames a client had queued, sampled as each was queued.
+    pub queue_depth: HistogramSnapshot,
+    /// Microseconds broadcasts waited for a fan-out worker.
+    pub broadcast_lag_micros: HistogramSnapshot,
+    /// Microseconds from a broadcast being sent to it being written to
+    /// each client, including time spent behind slower frames.
+    pub delivery_micros: HistogramSnapshot,
+    /// Running tasks by subsystem, e.g. "clients" or "background".
+    pub tasks: BTreeMap<String, usize>,
+    /// Per-connection work, by client address, to spot a connection
+    /// hogging its worker thread.
+    pub connections: BTreeMap<String, ConnectionStats>,
+}
+
+impl Histogram {
+    pub fn record(&self, value: u64) {
+        let bucket = (u64::BITS - value.saturating_sub(1).leading_zeros()) as usize;
+        self.buckets[bucket.min(HISTOGRAM_BUCKETS - 1)].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> HistogramSnapshot {
+        let buckets: Vec<(u64, u64)> = self
+            .buckets
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| {
+                let bound = if bucket == HISTOGRAM_BUCKETS - 1 {
+                    u64::MAX
+                } else {
+                    1 << bucket
+                };
+                (bound, count.load(Ordering::Relaxed))
+            })
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        HistogramSnapshot {
+            count: buckets.iter().map(|&(_, count)| count).sum(),
+            sum: self.sum.load(Ordering::Relaxed),
+            buckets,
+        }
+    }
+}
+
+impl HistogramSnapshot {
+    /// Estimates the value that `quantile` (0.0 to 1.0) of recorded values
+    /// are at or below, as the upper bound of the bucket it falls in.
+    /// `None` if nothing was recorded.
+    pub fn quantile(&self, quantile: f64) -> Option<u64> {
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets.iter().find_map(|&(bound, count)| {
+            seen += count;
+            (seen >= rank).then_some(bound)
+        })
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record_message(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_prune(&self, stats: PruneStats) {
+        self.pruned_messages
+            .fetch_add(stats.messages, Ordering::Relaxed);
+        self.pruned_bytes.fetch_add(stats.bytes, Ordering::Relaxed);
+    }
+
+    pub fn record_quota_rejection(&self) {
+        self.quota_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_resource_rejection(&self) {
+        self.resource_rejections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dead_letter(&self) {
+        self.dead_letters.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_shed_connection(&self) {
+        self.shed_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_handshake_timeout(&self) {
+        self.handshake_timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_challenge(&self) {
+        self.challenges.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_dropped_frame(&self) {
+        self.dropped_frames.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_handler_panic(&self) {
+        self.handler_panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records a client's outbound queue depth, keeping the highest seen.
+    pub fn record_queue_depth(&self, depth: usize) {
+        self.queue_high_water
+            .fetch_max(depth as u64, Ordering::Relaxed);
+        self.queue_depth.record(depth as u64);
+    }
+
+    /// Records how long a broadcast waited for a fan-out worker.
+    pub fn record_broadcast_lag(&self, lag: Duration) {
+        self.broadcast_lag.record(lag.as_micros() as u64);
+    }
+
+    /// Records how long a broadcast took from being sent to being taken
+    /// off a client's queue to be written.
+    pub fn record_delivery(&self, latency: Duration) {
+        self.delivery.record(latency.as_micros() as u64);
+    }
+
+    /// Records that handling one read from `addr` took `busy` and
+    /// covered `frames` frames.
+    pub fn record_connection_work(&self, addr: SocketAddr, frames: u64, busy: Duration) {
+        let mut connections = self.connections.lock().unwrap();
+        let stats = connections.entry(addr).or_default();
+        stats.polls += 1;
+        stats.frames += frames;
+        stats.busy_micros += busy.as_micros() as u64;
+    }
+
+    /// Forgets a connection's stats once it closes.
+    pub fn remove_connection(&self, addr: SocketAddr) {
+        self.connections.lock().unwrap().remove(&addr);
+    }
+
+    /// Returns the registry the server's tasks are spawned through.
+    pub fn tasks(&self) -> Arc<TaskRegistry> {
+        self.tasks.clone()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            messages: self.messages.load(Ordering::Relaxed),
+            pruned_messages: self.pruned_messages.load(Ordering::Relaxed),
+            pruned_bytes: self.pruned_bytes.load(Ordering::Relaxed),
+            quota_rejections: self.quota_rejections.load(Ordering::Relaxed),
+            resource_rejections: self.resource_rejections.load(Ordering::Relaxed),
+            dead_letters: self.dead_letters.load(Ordering::Relaxed),
+            shed_connections: self.shed_connections.load(Ordering::Relaxed),
+            rejected_connections: self.rejected_connections.load(Ordering::Relaxed),
+            handshake_timeouts: self.handshake_timeouts.load(Ordering::Relaxed),
+            challenges: self.challenges.load(Ordering::Relaxed),
+            dropped_frames: self.dropped_frames.load(Ordering::Relaxed),
+            handler_panics: self.handler_panics.load(Ordering::Relaxed),
+            queue_high_water: self.queue_high_water.load(Ordering::Relaxed),
+            queue_depth: self.queue_depth.snapshot(),
+            broadcast_lag_micros: self.broadcast_lag.snapshot(),
+            delivery_micros: self.delivery.snapshot(),
+            tasks: self.tasks.counts(),
+            connections: self
+                .connections
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(addr, stats)| (addr.to_string(), stats.clone()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_histogram_buckets_and_quantiles() {
+        let histogram = Histogram::default();
+        for value in [0, 1, 3, 4, 5, 100, u64::MAX] {
+            histogram.record(value);
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 7);
+        assert_eq!(
+            snapshot.buckets,
+            vec![(1, 2), (4, 2), (8, 1), (128, 1), (u64::MAX, 1)]
+        );
+        assert_eq!(snapshot.quantile(0.5), Some(4));
+        assert_eq!(snapshot.quantile(0.8), Some(128));
+        assert_eq!(snapshot.quantile(1.0), Some(u64::MAX));
+        assert_eq!(HistogramSnapshot::default().quantile(0.99), None);
+    }
+}
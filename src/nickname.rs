@@ -0,0 +1,92 @@
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+
+/// Puts a nickname in NFKC form, so compatibility variants such as
+/// fullwidth `ａｖｅｒｙ` become the plain `avery`.
+pub fn normalize(nick: &str) -> String {
+    nick.nfkc().collect()
+}
+
+/// Reduces a nickname to what it looks like: case, accents and characters
+/// that pass for Latin letters or for each other (Cyrillic `а`, Greek `ο`,
+/// `0` and `o`, `1`, `I` and `l`, `rn` and `m`) are folded, so two
+/// nicknames with the same skeleton can be mistaken for one another. A
+/// small take on the skeletons of Unicode TR39, covering the usual
+/// impersonation tricks rather than the full confusables table.
+pub fn skeleton(nick: &str) -> String {
+    let folded: String = nick
+        .nfkd()
+        .filter(|&c| !is_combining_mark(c))
+        .map(|c| lookalike(c).unwrap_or(c))
+        .flat_map(char::to_lowercase)
+        .collect();
+    folded.replace("rn", "m").replace("vv", "w")
+}
+
+/// Whether two nicknames are different but look alike.
+pub fn confusable(a: &str, b: &str) -> bool {
+    a != b && skeleton(a) == skeleton(b)
+}
+
+/// The Latin letter `c` passes for, if it's a known lookalike.
+fn lookalike(c: char) -> Option<char> {
+    Some(match c {
+        '0' => 'o',
+        '1' | 'I' | '|' => 'l',
+        // Cyrillic
+        'а' | 'А' => 'a',
+        'В' | 'в' => 'b',
+        'е' | 'Е' | 'ё' | 'Ё' => 'e',
+        'һ' | 'Н' | 'н' => 'h',
+        'і' | 'І' => 'i',
+        'ј' | 'Ј' => 'j',
+        'К' | 'к' => 'k',
+        'ӏ' | 'Ӏ' => 'l',
+        'М' | 'м' => 'm',
+        'о' | 'О' => 'o',
+        'р' | 'Р' => 'p',
+        'ԛ' | 'Ԛ' => 'q',
+        'ѕ' | 'Ѕ' => 's',
+        'с' | 'С' => 'c',
+        'Т' | 'т' => 't',
+        'у' | 'У' => 'y',
+        'х' | 'Х' => 'x',
+        'ԁ' => 'd',
+        'ԝ' | 'Ԝ' => 'w',
+        // Greek
+        'α' | 'Α' => 'a',
+        'Β' | 'β' => 'b',
+        'Ε' | 'ε' => 'e',
+        'Ζ' => 'z',
+        'Η' => 'h',
+        'ι' | 'Ι' => 'i',
+        'Κ' | 'κ' => 'k',
+        'Μ' => 'm',
+        'Ν' => 'n',
+        'ν' => 'v',
+        'ο' | 'Ο' => 'o',
+        'ρ' | 'Ρ' => 'p',
+        'Τ' | 'τ' => 't',
+        'υ' | 'Υ' => 'y',
+        'χ' | 'Χ' => 'x',
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lookalikes_share_a_skeleton() {
+        assert_eq!(normalize("ａｖｅｒｙ"), "avery");
+        assert!(confusable("admin", "\u{430}dmin"));
+        assert!(confusable("admin", "Admin"));
+        assert!(confusable("admin", "adrnin"));
+        assert!(confusable("paypal", "pаypа1"));
+        assert!(confusable("bob", "bоb"));
+        assert!(confusable("jose", "josé"));
+        assert!(!confusable("admin", "admin"));
+        assert!(!confusable("avery", "blake"));
+    }
+}
@@ -0,0 +1,227 @@
+use crate::auth::{Authenticator, RoleMap, SignIn};
+use crate::nickname;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use jsonwebtoken::jwk::{Jwk, JwkSet};
+use jsonwebtoken::{Algorithm, AlgorithmFamily, DecodingKey, Validation, decode, decode_header};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// How long fetched signing keys are used before being fetched again.
+pub const DEFAULT_JWKS_TTL: Duration = Duration::from_secs(60 * 60);
+/// Least time between fetches for tokens signed with an unknown key, so
+/// made-up key ids can't make the server hammer the issuer.
+const MIN_REFETCH: Duration = Duration::from_secs(30);
+
+/// Settings for `OidcAuthenticator`.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// The issuer's URL, which tokens' `iss` claim must match.
+    pub issuer: String,
+    /// Accepted `aud` claim values; any audience if empty.
+    pub audiences: Vec<String>,
+    /// Where the issuer publishes its signing keys. Found through the
+    /// issuer's discovery document when unset.
+    pub jwks_uri: Option<String>,
+    /// The claim holding the client's nickname.
+    pub nickname_claim: String,
+    /// The claim holding the client's groups, a string or a list of them.
+    pub groups_claim: String,
+    /// Room roles for the groups in `groups_claim`.
+    pub roles: RoleMap,
+    /// How long fetched signing keys are used before being fetched again.
+    pub jwks_ttl: Duration,
+}
+
+impl OidcConfig {
+    /// Settings for tokens from `issuer`, with the nickname taken from
+    /// `preferred_username` and groups from `groups`.
+    pub fn new(issuer: impl Into<String>) -> Self {
+        OidcConfig {
+            issuer: issuer.into(),
+            audiences: Vec::new(),
+            jwks_uri: None,
+            nickname_claim: "preferred_username".to_string(),
+            groups_claim: "groups".to_string(),
+            roles: RoleMap::new(),
+            jwks_ttl: DEFAULT_JWKS_TTL,
+        }
+    }
+}
+
+/// Signs clients in with access tokens from an OpenID Connect provider,
+/// so the server can use an existing SSO. A client sends the token in
+/// `Authenticate`, with its nickname or an empty user; the nickname and
+/// room roles come from the token's claims.
+///
+/// Only asymmetrically signed tokens are accepted, checked against the
+/// issuer's JWKS, which is cached and fetched again when it expires or a
+/// token names a key it doesn't have.
+pub struct OidcAuthenticator {
+    config: OidcConfig,
+    http: reqwest::Client,
+    keys: Mutex<Keys>,
+}
+
+#[derive(Default)]
+struct Keys {
+    jwks_uri: Option<String>,
+    set: Option<JwkSet>,
+    fetched_at: Option<Instant>,
+}
+
+#[derive(Deserialize)]
+struct Discovery {
+    jwks_uri: String,
+}
+
+impl OidcAuthenticator {
+    pub fn new(config: OidcConfig) -> Self {
+        let keys = Keys {
+            jwks_uri: config.jwks_uri.clone(),
+            ..Keys::default()
+        };
+        OidcAuthenticator {
+            config,
+            http: reqwest::Client::new(),
+            keys: Mutex::new(keys),
+        }
+    }
+
+    /// Returns the claims of `token` if it's valid and from the issuer.
+    async fn validate(&self, token: &str) -> Result<Option<Map<String, Value>>> {
+        let Ok(header) = decode_header(token) else {
+            return Ok(None);
+        };
+        // The issuer's keys are public, so a shared-secret signature made
+        // with one would prove nothing.
+        if header.alg.family() == AlgorithmFamily::Hmac {
+            debug!("Rejected OIDC token signed with {:?}", header.alg);
+            return Ok(None);
+        }
+        let Some(jwk) = self.key(header.kid.as_deref()).await? else {
+            debug!("No OIDC signing key matches {:?}", header.kid);
+            return Ok(None);
+        };
+        if let Some(algorithm) = jwk.common.key_algorithm
+            && Algorithm::try_from(algorithm).ok() != Some(header.alg)
+        {
+            return Ok(None);
+        }
+        let Ok(key) = DecodingKey::from_jwk(&jwk) else {
+            return Ok(None);
+        };
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.config.issuer]);
+        if self.config.audiences.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.config.audiences);
+        }
+        match decode::<Map<String, Value>>(token, &key, &validation) {
+            Ok(data) => Ok(Some(data.claims)),
+            Err(e) => {
+                debug!("Rejected OIDC token: {}", e);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns the signing key with id `kid`, or the only key if the
+    /// token doesn't name one, fetching the issuer's keys if needed.
+    async fn key(&self, kid: Option<&str>) -> Result<Option<Jwk>> {
+        let mut keys = self.keys.lock().await;
+        let fresh = keys
+            .fetched_at
+            .is_some_and(|at| at.elapsed() < self.config.jwks_ttl);
+        if fresh {
+            if let Some(jwk) = keys.set.as_ref().and_then(|set| find(set, kid)) {
+                return Ok(Some(jwk.clone()));
+            }
+            if keys.fetched_at.is_some_and(|at| at.elapsed() < MIN_REFETCH) {
+                return Ok(None);
+            }
+        }
+        match self.fetch_keys(&mut keys).await {
+            Ok(set) => {
+                info!("Fetched {} OIDC signing keys", set.keys.len());
+                keys.set = Some(set);
+                keys.fetched_at = Some(Instant::now());
+            }
+            // Keep using the keys we have until the issuer is back.
+            Err(e) if keys.set.is_some() => warn!("Failed to fetch OIDC signing keys: {:#}", e),
+            Err(e) => return Err(e),
+        }
+        Ok(keys.set.as_ref().and_then(|set| find(set, kid)).cloned())
+    }
+
+    async fn fetch_keys(&self, keys: &mut Keys) -> Result<JwkSet> {
+        let jwks_uri = match &keys.jwks_uri {
+            Some(uri) => uri.clone(),
+            None => {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let discovery: Discovery = self.get_json(&url).await?;
+                keys.jwks_uri.insert(discovery.jwks_uri).clone()
+            }
+        };
+        self.get_json(&jwks_uri).await
+    }
+
+    async fn get_json<T: for<'de> Deserialize<'de>>(&self, url: &str) -> Result<T> {
+        let response = self.http.get(url).send().await?.error_for_status()?;
+        response
+            .json()
+            .await
+            .with_context(|| format!("Invalid response from {}", url))
+    }
+}
+
+fn find<'a>(set: &'a JwkSet, kid: Option<&str>) -> Option<&'a Jwk> {
+    match kid {
+        Some(kid) => set.find(kid),
+        None if set.keys.len() == 1 => set.keys.first(),
+        None => None,
+    }
+}
+
+#[async_trait]
+impl Authenticator for OidcAuthenticator {
+    async fn authenticate(&self, user: &str, token: &str) -> Result<bool> {
+        let signed_in = self.sign_in(user, token).await?;
+        Ok(signed_in.is_some_and(|signed_in| signed_in.user == nickname::normalize(user)))
+    }
+
+    /// Accepts `token` for its nickname claim. `user` may be empty;
+    /// otherwise it has to be that nickname.
+    async fn sign_in(&self, user: &str, token: &str) -> Result<Option<SignIn>> {
+        let Some(claims) = self.validate(token).await? else {
+            return Ok(None);
+        };
+        let Some(claimed) = claims
+            .get(&self.config.nickname_claim)
+            .and_then(Value::as_str)
+        else {
+            debug!("OIDC token has no {} claim", self.config.nickname_claim);
+            return Ok(None);
+        };
+        let claimed = nickname::normalize(claimed);
+        if !user.is_empty() && nickname::normalize(user) != claimed {
+            return Ok(None);
+        }
+        let groups: Vec<&str> = match claims.get(&self.config.groups_claim) {
+            Some(Value::String(group)) => vec![group],
+            Some(Value::Array(groups)) => groups.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        Ok(Some(SignIn {
+            grants: self.config.roles.grants(groups),
+            user: claimed,
+        }))
+    }
+}
@@ -0,0 +1,174 @@
+use crate::tasks::TaskRegistry;
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::{debug, warn};
+
+/// How long a webhook has to answer before it's given up on.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What happens when someone arrives, on the server or in one room.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct WelcomeActions {
+    /// Sent to the arriving connection as a `Motd` frame.
+    pub motd: Option<String>,
+    /// Sent to the new arrival as a direct message from the system user.
+    pub direct_message: Option<String>,
+    /// `http://` URL a `WebhookEvent` is posted to as JSON.
+    pub webhook: Option<String>,
+}
+
+/// On-join actions for one namespace (the server or a tenant).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Onboarding {
+    /// Who welcome direct messages come from.
+    pub system_user: String,
+    /// Run whenever a connection is greeted as a guest or signs in, but
+    /// not when it resumes a session. The direct message is only sent the
+    /// first time each user arrives after the server starts.
+    pub welcome: WelcomeActions,
+    /// Existing rooms users are made members of when they arrive, as if
+    /// invited. Full rooms are skipped.
+    pub auto_join: Vec<String>,
+    /// Run when a user becomes a member of the room, by room name.
+    pub rooms: HashMap<String, WelcomeActions>,
+}
+
+impl Default for Onboarding {
+    fn default() -> Self {
+        Onboarding {
+            system_user: "system".to_string(),
+            welcome: WelcomeActions::default(),
+            auto_join: Vec::new(),
+            rooms: HashMap::new(),
+        }
+    }
+}
+
+impl Onboarding {
+    /// Checks that every webhook is a URL `post_webhook` can reach.
+    pub fn validate(&self) -> Result<()> {
+        for actions in std::iter::once(&self.welcome).chain(self.rooms.values()) {
+            if let Some(url) = &actions.webhook {
+                parse_http_url(url)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What a webhook is told happened.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnboardingEvent {
+    /// A connection was greeted as a guest or signed in.
+    Arrived,
+    /// A user became a member of a room.
+    JoinedRoom,
+}
+
+/// Body posted to a welcome webhook.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookEvent {
+    pub event: OnboardingEvent,
+    pub user: String,
+    /// The room joined, for `JoinedRoom`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Unix time (seconds) of the event.
+    pub timestamp: u64,
+}
+
+/// Posts `event` as JSON to a plain `http://` URL, failing unless it
+/// answers with a 2xx status.
+pub async fn post_webhook(url: &str, event: &WebhookEvent) -> Result<()> {
+    let status = post_json(url, event).await?;
+    if !(200..300).contains(&status) {
+        return Err(anyhow!("Webhook {} answered {}", url, status));
+    }
+    debug!("Posted {:?} to {}", event.event, url);
+    Ok(())
+}
+
+/// Posts `body` as JSON to a plain `http://` URL, returning the status it
+/// answers with.
+pub(crate) async fn post_json(url: &str, body: &impl Serialize) -> Result<u16> {
+    let (host, path) = parse_http_url(url)?;
+    let body = serde_json::to_string(body)?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        body.len(),
+        body
+    );
+    let status = timeout(WEBHOOK_TIMEOUT, async {
+        let mut stream = if host.contains(':') {
+            TcpStream::connect(host).await?
+        } else {
+            TcpStream::connect((host, 80)).await?
+        };
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.split_whitespace().nth(1).unwrap_or_default();
+        status
+            .parse()
+            .map_err(|_| anyhow!("{} answered with no status", url))
+    })
+    .await
+    .map_err(|_| anyhow!("{} timed out", url))??;
+    Ok(status)
+}
+
+/// Posts `event` to `url` in the background, logging any failure.
+pub(crate) fn spawn_webhook(tasks: &TaskRegistry, url: String, event: WebhookEvent) {
+    tasks.spawn("webhooks", "webhook", async move {
+        if let Err(e) = post_webhook(&url, &event).await {
+            warn!("{}", e);
+        }
+    });
+}
+
+/// Splits an `http://host[:port]/path` URL into the address to connect to
+/// and the request path.
+fn parse_http_url(url: &str) -> Result<(&str, &str)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| anyhow!("Webhook {} must be an http:// URL", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(anyhow!("Webhook {} has no host", url));
+    }
+    Ok((host, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_http_url() {
+        assert_eq!(
+            parse_http_url("http://127.0.0.1:9000/hooks/join").unwrap(),
+            ("127.0.0.1:9000", "/hooks/join")
+        );
+        assert_eq!(
+            parse_http_url("http://example.com").unwrap(),
+            ("example.com", "/")
+        );
+        assert!(parse_http_url("https://example.com/").is_err());
+        assert!(parse_http_url("http:///path").is_err());
+    }
+}
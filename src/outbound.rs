@@ -0,0 +1,354 @@
+use crate::codec::{Encoded, WireFormat};
+use crate::memory::Account;
+use crate::metrics::Metrics;
+use crate::protocol::Priority;
+use bytes::Bytes;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// Normal-priority frames a connection may fall behind by before the
+/// oldest are dropped, unless configured otherwise.
+pub const OUTBOUND_CAPACITY: usize = 1024;
+
+/// How many normal-priority frames a connection may have queued.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueCapacity {
+    Fixed(usize),
+    /// Starts at `min` and doubles whenever a burst fills the queue, up to
+    /// `max`; halves back toward `min` as the queue drains, so idle
+    /// connections don't keep burst-sized buffers.
+    Adaptive {
+        min: usize,
+        max: usize,
+    },
+}
+
+impl QueueCapacity {
+    fn initial(self) -> usize {
+        match self {
+            QueueCapacity::Fixed(capacity) => capacity,
+            QueueCapacity::Adaptive { min, .. } => min,
+        }
+        .max(1)
+    }
+}
+
+impl Default for QueueCapacity {
+    fn default() -> Self {
+        QueueCapacity::Fixed(OUTBOUND_CAPACITY)
+    }
+}
+
+/// Frames waiting to be written to one connection, one lane per
+/// `Priority`, drained highest first. Only the normal lane is bounded: a
+/// slow client loses its oldest chat traffic (and can backfill it by
+/// sequence number) but never moderator or system frames.
+#[derive(Debug)]
+pub struct OutboundQueue {
+    lanes: Mutex<Lanes>,
+    notify: Notify,
+    capacity: QueueCapacity,
+    /// Where queued bytes are charged, if anywhere.
+    account: Option<Account>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+#[derive(Debug, Default)]
+struct Lanes {
+    system: VecDeque<Queued>,
+    moderator: VecDeque<Queued>,
+    normal: VecDeque<Queued>,
+    /// How many frames the normal lane holds right now.
+    normal_capacity: usize,
+    /// Total length of the queued frames.
+    bytes: usize,
+    closed: bool,
+    /// Set by `shed`; later frames are discarded.
+    shed: bool,
+    /// What `push_frame` and `push_broadcast` encode frames as.
+    format: WireFormat,
+}
+
+#[derive(Debug)]
+struct Queued {
+    line: Bytes,
+    /// When the frame was broadcast, for delivery latency.
+    broadcast_at: Option<Instant>,
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: QueueCapacity) -> Self {
+        OutboundQueue {
+            lanes: Mutex::new(Lanes {
+                normal_capacity: capacity.initial(),
+                ..Default::default()
+            }),
+            notify: Notify::new(),
+            capacity,
+            account: None,
+            metrics: None,
+        }
+    }
+
+    /// Charges queued bytes to `account`.
+    pub fn with_account(mut self, account: Account) -> Self {
+        self.account = Some(account);
+        self
+    }
+
+    /// Reports queue depth, delivery latency and dropped frames to
+    /// `metrics`.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Queues an encoded frame, returning false if the normal lane was full
+    /// and its oldest frame was dropped to make room. Does nothing once the
+    /// queue has been shed.
+    pub fn push(&self, priority: Priority, line: Bytes) -> bool {
+        self.enqueue(priority, None, |_| Some(line))
+    }
+
+    /// Like `push`, for a frame in the connection's wire format.
+    pub fn push_frame(&self, priority: Priority, frame: &Encoded) -> bool {
+        self.enqueue(priority, None, |format| frame.get(format))
+    }
+
+    /// Like `push_frame`, for a frame broadcast at `broadcast_at`; how long
+    /// it takes to reach the writer is recorded as its delivery latency.
+    pub fn push_broadcast(
+        &self,
+        priority: Priority,
+        frame: &Encoded,
+        broadcast_at: Instant,
+    ) -> bool {
+        self.enqueue(priority, Some(broadcast_at), |format| frame.get(format))
+    }
+
+    /// Encodes frames pushed from here on in `format`. Frames already
+    /// queued are written as they were encoded, so the switch happens at
+    /// the same point in the stream for replies and broadcasts alike.
+    pub fn set_format(&self, format: WireFormat) {
+        self.lanes.lock().unwrap().format = format;
+    }
+
+    /// The format frames pushed now are encoded in.
+    pub fn format(&self) -> WireFormat {
+        self.lanes.lock().unwrap().format
+    }
+
+    fn enqueue(
+        &self,
+        priority: Priority,
+        broadcast_at: Option<Instant>,
+        encode: impl FnOnce(WireFormat) -> Option<Bytes>,
+    ) -> bool {
+        let mut lanes = self.lanes.lock().unwrap();
+        if lanes.shed {
+            return true;
+        }
+        // Encoded under the lock, so nothing pushed after `set_format` is
+        // in the old format.
+        let Some(line) = encode(lanes.format) else {
+            return true;
+        };
+        lanes.bytes += line.len();
+        self.charge(line.len());
+        let queued = Queued { line, broadcast_at };
+        let mut kept = true;
+        match priority {
+            Priority::System => lanes.system.push_back(queued),
+            Priority::Moderator => lanes.moderator.push_back(queued),
+            Priority::Normal => {
+                if lanes.normal.len() >= lanes.normal_capacity {
+                    match self.capacity {
+                        QueueCapacity::Adaptive { max, .. } if lanes.normal_capacity < max => {
+                            lanes.normal_capacity = (lanes.normal_capacity * 2).min(max);
+                        }
+                        _ => {
+                            let dropped = lanes
+                                .normal
+                                .pop_front()
+                                .map_or(0, |queued| queued.line.len());
+                            lanes.bytes -= dropped;
+                            self.release(dropped);
+                            kept = false;
+                        }
+                    }
+                }
+                lanes.normal.push_back(queued);
+                if let Some(metrics) = &self.metrics {
+                    metrics.record_queue_depth(lanes.normal.len());
+                    if !kept {
+                        metrics.record_dropped_frame();
+                    }
+                }
+            }
+        }
+        drop(lanes);
+        self.notify.notify_one();
+        kept
+    }
+
+    /// Waits for the next frame, highest priority first. Returns `None`
+    /// once the queue is closed and empty.
+    pub async fn pop(&self) -> Option<Bytes> {
+        self.pop_batch(1).await.map(|mut batch| batch.remove(0))
+    }
+
+    /// Waits for at least one frame, then takes up to `max` of them,
+    /// highest priority first, so they can be written together. Returns
+    /// `None` once the queue is closed and empty.
+    pub async fn pop_batch(&self, max: usize) -> Option<Vec<Bytes>> {
+        loop {
+            {
+                let mut lanes = self.lanes.lock().unwrap();
+                let lanes = &mut *lanes;
+                let mut batch = Vec::new();
+                for lane in [&mut lanes.system, &mut lanes.moderator, &mut lanes.normal] {
+                    let take = lane.len().min(max - batch.len());
+                    batch.extend(lane.drain(..take));
+                }
+                if !batch.is_empty() {
+                    if let Some(metrics) = &self.metrics {
+                        for queued in &batch {
+                            if let Some(broadcast_at) = queued.broadcast_at {
+                                metrics.record_delivery(broadcast_at.elapsed());
+                            }
+                        }
+                    }
+                    let batch: Vec<Bytes> = batch.into_iter().map(|queued| queued.line).collect();
+                    let taken = batch.iter().map(Bytes::len).sum();
+                    lanes.bytes -= taken;
+                    self.release(taken);
+                    if let QueueCapacity::Adaptive { min, .. } = self.capacity
+                        && lanes.normal_capacity > min
+                        && lanes.normal.len() <= lanes.normal_capacity / 4
+                    {
+                        lanes.normal_capacity = (lanes.normal_capacity / 2).max(min);
+                        lanes.normal.shrink_to(lanes.normal_capacity);
+                    }
+                    return Some(batch);
+                }
+                if lanes.closed {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// How many normal-priority frames fit before the oldest is dropped
+    /// (or, for an adaptive queue, before it grows).
+    pub fn normal_capacity(&self) -> usize {
+        self.lanes.lock().unwrap().normal_capacity
+    }
+
+    /// How many frames are waiting to be written, in every lane.
+    pub fn queued_frames(&self) -> usize {
+        let lanes = self.lanes.lock().unwrap();
+        lanes.system.len() + lanes.moderator.len() + lanes.normal.len()
+    }
+
+    /// Total length of the frames waiting to be written.
+    pub fn queued_bytes(&self) -> usize {
+        self.lanes.lock().unwrap().bytes
+    }
+
+    /// Makes `pop` return `None` once what's queued has been drained.
+    pub fn close(&self) {
+        self.lanes.lock().unwrap().closed = true;
+        self.notify.notify_one();
+    }
+
+    /// Discards everything queued and closes the queue, so its writer
+    /// stops and the connection is dropped.
+    pub fn shed(&self) {
+        let mut lanes = self.lanes.lock().unwrap();
+        lanes.system.clear();
+        lanes.moderator.clear();
+        lanes.normal.clear();
+        let queued = std::mem::take(&mut lanes.bytes);
+        lanes.closed = true;
+        lanes.shed = true;
+        drop(lanes);
+        self.release(queued);
+        self.notify.notify_one();
+    }
+
+    fn charge(&self, bytes: usize) {
+        if let Some(account) = &self.account {
+            account.charge(bytes);
+        }
+    }
+
+    fn release(&self, bytes: usize) {
+        if let Some(account) = &self.account {
+            account.release(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_higher_lanes_drain_first() {
+        let queue = OutboundQueue::new(QueueCapacity::Fixed(2));
+        for line in ["chat 1", "chat 2", "chat 3"] {
+            queue.push(Priority::Normal, Bytes::from(line));
+        }
+        queue.push(Priority::Moderator, Bytes::from("room updated"));
+        queue.push(Priority::System, Bytes::from("shutting down"));
+        queue.close();
+
+        let mut drained = Vec::new();
+        while let Some(line) = queue.pop().await {
+            drained.push(line);
+        }
+        assert_eq!(
+            drained,
+            vec!["shutting down", "room updated", "chat 2", "chat 3"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pop_batch_takes_up_to_max() {
+        let queue = OutboundQueue::new(QueueCapacity::Fixed(8));
+        for line in ["chat 1", "chat 2", "chat 3"] {
+            queue.push(Priority::Normal, Bytes::from(line));
+        }
+        queue.push(Priority::System, Bytes::from("ping"));
+
+        assert_eq!(
+            queue.pop_batch(3).await.unwrap(),
+            vec!["ping", "chat 1", "chat 2"]
+        );
+        assert_eq!(queue.pop_batch(3).await.unwrap(), vec!["chat 3"]);
+        queue.close();
+        assert_eq!(queue.pop_batch(3).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_adaptive_capacity_grows_and_shrinks() {
+        let metrics = Arc::new(Metrics::new());
+        let queue = OutboundQueue::new(QueueCapacity::Adaptive { min: 2, max: 8 })
+            .with_metrics(metrics.clone());
+        for i in 0..10 {
+            queue.push(Priority::Normal, Bytes::from(format!("chat {}", i)));
+        }
+        assert_eq!(queue.normal_capacity(), 8);
+        let snapshot = metrics.snapshot();
+        assert_eq!((snapshot.queue_high_water, snapshot.dropped_frames), (8, 2));
+
+        assert_eq!(queue.pop_batch(8).await.unwrap()[0], "chat 2");
+        assert_eq!(queue.normal_capacity(), 4);
+        queue.push(Priority::Normal, Bytes::from("chat 10"));
+        queue.pop_batch(8).await.unwrap();
+        assert_eq!(queue.normal_capacity(), 2);
+    }
+}
@@ -0,0 +1,127 @@
+use crate::client::Client;
+use crate::protocol::ChatMessage;
+use anyhow::Result;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::Mutex;
+use tokio::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Settings for a `ClientPool`.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Number of connections kept open.
+    pub size: usize,
+    /// Connections unused for this long are checked and, if the server
+    /// closed them, reopened.
+    pub idle_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            size: 4,
+            idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Slot {
+    /// `None` after a failure, until the next use reconnects.
+    client: Option<Client>,
+    last_used: Instant,
+}
+
+/// A set of connections for high-throughput publishers such as bridges and
+/// bots. Sends are spread round-robin, skipping connections that are busy.
+///
+/// Pooled connections only publish: anything the server sends them is
+/// discarded.
+pub struct ClientPool {
+    addr: String,
+    config: PoolConfig,
+    slots: Vec<Mutex<Slot>>,
+    next: AtomicUsize,
+}
+
+impl ClientPool {
+    /// Opens `config.size` connections to `addr`.
+    pub async fn connect(addr: &str, config: PoolConfig) -> Result<Self> {
+        let mut slots = Vec::with_capacity(config.size.max(1));
+        for _ in 0..config.size.max(1) {
+            slots.push(Mutex::new(Slot {
+                client: Some(Client::connect(addr).await?),
+                last_used: Instant::now(),
+            }));
+        }
+        Ok(ClientPool {
+            addr: addr.to_string(),
+            config,
+            slots,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// Returns the number of connections in the pool.
+    pub fn size(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Sends `message` on the next free connection, reconnecting once if
+    /// the connection turns out to be broken.
+    pub async fn send(&self, message: ChatMessage) -> Result<()> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let free = (0..self.slots.len())
+            .map(|offset| (start + offset) % self.slots.len())
+            .find_map(|index| self.slots[index].try_lock().ok());
+        let mut slot = match free {
+            Some(slot) => slot,
+            None => self.slots[start % self.slots.len()].lock().await,
+        };
+        slot.last_used = Instant::now();
+        if let Some(client) = slot.client.as_mut() {
+            if client.discard_incoming() && client.send(message.clone()).await.is_ok() {
+                return Ok(());
+            }
+            warn!("Pooled connection to {} failed, reconnecting", self.addr);
+        }
+        slot.client = None;
+        let mut client = Client::connect(&self.addr).await?;
+        client.send(message).await?;
+        slot.client = Some(client);
+        Ok(())
+    }
+
+    /// Checks connections idle for longer than `idle_timeout`, reopening
+    /// any the server has closed. Returns how many were reopened.
+    pub async fn health_check(&self) -> usize {
+        let mut reopened = 0;
+        for slot in &self.slots {
+            let Ok(mut slot) = slot.try_lock() else {
+                continue; // In use, so not idle.
+            };
+            if slot.last_used.elapsed() < self.config.idle_timeout {
+                continue;
+            }
+            let alive = slot.client.as_mut().is_some_and(Client::discard_incoming);
+            if !alive {
+                slot.client = Client::connect(&self.addr).await.ok();
+                reopened += usize::from(slot.client.is_some());
+            }
+            slot.last_used = Instant::now();
+        }
+        if reopened > 0 {
+            info!("Reopened {} pooled connections to {}", reopened, self.addr);
+        }
+        reopened
+    }
+}
+
+/// Runs `pool.health_check` every `idle_timeout`, forever.
+pub async fn run_health_checks(pool: Arc<ClientPool>) {
+    let mut ticker = tokio::time::interval(pool.config.idle_timeout);
+    loop {
+        ticker.tick().await;
+        pool.health_check().await;
+    }
+}
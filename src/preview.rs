@@ -0,0 +1,532 @@
+use crate::protocol::{ChatMessage, EntityKind, LinkPreview};
+use crate::sanitize::strip_escapes;
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use tracing::debug;
+
+/// Longest preview title, in characters.
+const MAX_TITLE_LEN: usize = 200;
+/// Longest preview description, in characters.
+const MAX_DESCRIPTION_LEN: usize = 500;
+
+/// Which links are previewed and how hard the server tries.
+#[derive(Debug, Clone)]
+pub struct PreviewPolicy {
+    /// Links previewed per message; the rest are ignored.
+    pub max_links: usize,
+    /// Bytes of each page read, headers included; metadata further in is
+    /// missed.
+    pub max_page_size: usize,
+    /// How long a page has to arrive.
+    pub timeout: Duration,
+    /// How long a preview (or a failure to make one) is remembered.
+    pub cache_ttl: Duration,
+    /// Links remembered at once; the oldest is forgotten first.
+    pub cache_size: usize,
+    /// Fetches links to loopback, private and other non-public addresses.
+    /// Only for tests and trusted networks: it lets anyone who can post
+    /// make the server probe its own network.
+    pub allow_private: bool,
+}
+
+impl Default for PreviewPolicy {
+    fn default() -> Self {
+        PreviewPolicy {
+            max_links: 3,
+            max_page_size: 256 * 1024,
+            timeout: Duration::from_secs(5),
+            cache_ttl: Duration::from_secs(60 * 60),
+            cache_size: 1024,
+            allow_private: false,
+        }
+    }
+}
+
+/// An `http://` or `https://` URL split into the parts needed to fetch it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageUrl {
+    /// "http" or "https".
+    pub scheme: String,
+    pub host: String,
+    pub port: u16,
+    /// Path and query, starting with `/`.
+    pub path: String,
+}
+
+impl PageUrl {
+    /// Parses `url`, refusing schemes other than `http` and `https` and
+    /// URLs with credentials, which are mostly used to disguise the host.
+    pub fn parse(url: &str) -> Option<PageUrl> {
+        let (scheme, rest) = url.split_once("://")?;
+        let scheme = scheme.to_ascii_lowercase();
+        let default_port = match scheme.as_str() {
+            "http" => 80,
+            "https" => 443,
+            _ => return None,
+        };
+        let (authority, path) = rest.split_at(rest.find(['/', '?', '#']).unwrap_or(rest.len()));
+        if authority.contains('@') {
+            return None;
+        }
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed.split_once(']')?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return None;
+        }
+        let port = match port {
+            Some(port) => port.parse().ok()?,
+            None => default_port,
+        };
+        let path = path.split('#').next().unwrap_or_default();
+        let path = match path {
+            "" => "/".to_string(),
+            path if path.starts_with('?') => format!("/{}", path),
+            path => path.to_string(),
+        };
+        Some(PageUrl {
+            scheme,
+            host: host.to_ascii_lowercase(),
+            port,
+            path,
+        })
+    }
+
+    /// `scheme://host[:port]`, with the port only if it isn't the default.
+    pub fn origin(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match (self.scheme.as_str(), self.port) {
+            ("http", 80) | ("https", 443) => format!("{}://{}", self.scheme, host),
+            _ => format!("{}://{}:{}", self.scheme, host, self.port),
+        }
+    }
+}
+
+impl fmt::Display for PageUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", self.origin(), self.path)
+    }
+}
+
+/// Downloads pages for `LinkPreviewer`.
+#[async_trait]
+pub trait PageFetcher: Send + Sync {
+    /// Fetches the page at `url` from `addr`, returning its body. `addr` is
+    /// the host's address, already resolved and vetted; implementations
+    /// must connect to it rather than resolve the host again, which could
+    /// give another address. At most `limit` bytes are read. Fails unless
+    /// the page is HTML and answered with a 2xx status.
+    async fn fetch(&self, url: &PageUrl, addr: SocketAddr, limit: usize) -> Result<Vec<u8>>;
+}
+
+/// Fetches `http://` pages over plain TCP, without following redirects.
+/// `https://` links fail; previewing them takes a `PageFetcher` with TLS.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HttpFetcher;
+
+#[async_trait]
+impl PageFetcher for HttpFetcher {
+    async fn fetch(&self, url: &PageUrl, addr: SocketAddr, limit: usize) -> Result<Vec<u8>> {
+        if url.scheme != "http" {
+            return Err(anyhow!("HttpFetcher can't fetch {}", url));
+        }
+        // HTTP/1.0, so the body isn't chunked.
+        let request = format!(
+            "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: tokio-chat-server link preview\r\n\
+             Accept: text/html\r\n\r\n",
+            url.path,
+            url.origin().split_once("://").unwrap_or_default().1
+        );
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.take(limit as u64).read_to_end(&mut response).await?;
+        let split = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .ok_or_else(|| anyhow!("{} sent no headers", url))?;
+        let head = String::from_utf8_lossy(&response[..split]).to_ascii_lowercase();
+        let status = head.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            return Err(anyhow!("{} answered {}", url, status));
+        }
+        let content_type = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-type:"))
+            .map(str::trim);
+        if content_type.is_some_and(|content_type| !content_type.starts_with("text/html")) {
+            return Err(anyhow!("{} is not an HTML page", url));
+        }
+        response.drain(..split + 4);
+        Ok(response)
+    }
+}
+
+/// Fetches previews of the links in messages, remembering them for a while
+/// so a link posted again isn't fetched again.
+pub struct LinkPreviewer {
+    policy: PreviewPolicy,
+    fetcher: Arc<dyn PageFetcher>,
+    /// Previews by URL, with when they were made. `None` records a failure.
+    cache: Mutex<HashMap<String, (Instant, Option<LinkPreview>)>>,
+}
+
+impl LinkPreviewer {
+    pub fn new(fetcher: Arc<dyn PageFetcher>, policy: PreviewPolicy) -> Self {
+        LinkPreviewer {
+            policy,
+            fetcher,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The links in `message` worth previewing: its link entities, then
+    /// bare URLs in its content, without repeats and at most
+    /// `PreviewPolicy::max_links`.
+    pub fn links(&self, message: &ChatMessage) -> Vec<String> {
+        let entities = message
+            .entities
+            .iter()
+            .filter_map(|entity| match &entity.kind {
+                EntityKind::Link { url } => Some(url.as_str()),
+                _ => None,
+            });
+        let bare = message.content.split_whitespace().filter_map(|word| {
+            let start = word.find("http://").or_else(|| word.find("https://"))?;
+            Some(
+                word[start..].trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '"', '\'']),
+            )
+        });
+        let mut links: Vec<String> = Vec::new();
+        for link in entities.chain(bare) {
+            if links.len() == self.policy.max_links {
+                break;
+            }
+            if PageUrl::parse(link).is_some() && !links.iter().any(|seen| seen == link) {
+                links.push(link.to_string());
+            }
+        }
+        links
+    }
+
+    /// A preview of the page at `url`, or `None` if it can't be fetched,
+    /// isn't public, or has nothing to show.
+    pub async fn preview(&self, url: &str) -> Option<LinkPreview> {
+        if let Some((made, preview)) = self.cache.lock().unwrap().get(url)
+            && made.elapsed() < self.policy.cache_ttl
+        {
+            return preview.clone();
+        }
+        let preview = match timeout(self.policy.timeout, self.fetch_preview(url)).await {
+            Ok(Ok(preview)) => preview,
+            Ok(Err(e)) => {
+                debug!("No preview of {}: {}", url, e);
+                None
+            }
+            Err(_) => {
+                debug!("No preview of {}: timed out", url);
+                None
+            }
+        };
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (made, _)| made.elapsed() < self.policy.cache_ttl);
+        if cache.len() >= self.policy.cache_size
+            && let Some(oldest) = cache
+                .iter()
+                .min_by_key(|(_, (made, _))| *made)
+                .map(|(url, _)| url.clone())
+        {
+            cache.remove(&oldest);
+        }
+        if self.policy.cache_size > 0 {
+            cache.insert(url.to_string(), (Instant::now(), preview.clone()));
+        }
+        preview
+    }
+
+    async fn fetch_preview(&self, url: &str) -> Result<Option<LinkPreview>> {
+        let page_url = PageUrl::parse(url).ok_or_else(|| anyhow!("unsupported URL"))?;
+        let addrs: Vec<SocketAddr> =
+            tokio::net::lookup_host((page_url.host.as_str(), page_url.port))
+                .await?
+                .collect();
+        // Every address is checked, since another lookup could pick any.
+        if !self.policy.allow_private && addrs.iter().any(|addr| !is_public(addr.ip())) {
+            return Err(anyhow!("{} is not a public address", page_url.host));
+        }
+        let addr = *addrs
+            .first()
+            .ok_or_else(|| anyhow!("{} has no address", page_url.host))?;
+        let page = self
+            .fetcher
+            .fetch(&page_url, addr, self.policy.max_page_size)
+            .await?;
+        let preview = parse_page(url, &page_url, &String::from_utf8_lossy(&page));
+        Ok((preview.title.is_some() || preview.description.is_some()).then_some(preview))
+    }
+}
+
+/// Whether `ip` is on the public internet, rather than loopback, a private
+/// or link-local network, or a reserved range.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                || a >= 240
+                // Carrier-grade NAT, IETF protocol assignments, benchmarking.
+                || (a == 100 && (64..128).contains(&b))
+                || (a == 192 && b == 0 && c == 0)
+                || (a == 198 && (18..20).contains(&b)))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+            let [first, second, ..] = ip.segments();
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local, link-local, documentation.
+                || first & 0xfe00 == 0xfc00
+                || first & 0xffc0 == 0xfe80
+                || (first == 0x2001 && second == 0x0db8))
+        }
+    }
+}
+
+/// Picks the title, description and image out of a page's `<title>` and
+/// `<meta>` tags, preferring Open Graph ones.
+fn parse_page(url: &str, page_url: &PageUrl, html: &str) -> LinkPreview {
+    // ASCII lowercasing keeps byte offsets, so matches index `html` too.
+    let lower = html.to_ascii_lowercase();
+    let mut meta: HashMap<String, String> = HashMap::new();
+    for (start, _) in lower.match_indices("<meta") {
+        let end = lower[start..]
+            .find('>')
+            .map_or(lower.len(), |end| start + end);
+        let attributes = attributes(&html[start + "<meta".len()..end]);
+        let key = attributes
+            .get("property")
+            .or_else(|| attributes.get("name"));
+        if let (Some(key), Some(content)) = (key, attributes.get("content")) {
+            meta.entry(key.to_ascii_lowercase())
+                .or_insert_with(|| content.clone());
+        }
+    }
+    let title_tag = lower.find("<title").and_then(|start| {
+        let open = start + lower[start..].find('>')? + 1;
+        let close = open + lower[open..].find("</title")?;
+        Some(html[open..close].to_string())
+    });
+    let title = meta.remove("og:title").or(title_tag);
+    let description = meta
+        .remove("og:description")
+        .or_else(|| meta.remove("description"));
+    let image = meta
+        .remove("og:image")
+        .and_then(|image| absolute_url(page_url, &decode_entities(&image)));
+    LinkPreview {
+        url: url.to_string(),
+        title: title.and_then(|title| clean_text(&title, MAX_TITLE_LEN)),
+        description: description.and_then(|text| clean_text(&text, MAX_DESCRIPTION_LEN)),
+        image,
+    }
+}
+
+/// Parses the attributes of a tag, keyed by lowercase name.
+fn attributes(tag: &str) -> HashMap<String, String> {
+    let mut attributes = HashMap::new();
+    let mut rest = tag.trim_start();
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c == '=' || c == '/' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        let name = rest[..name_end].to_ascii_lowercase();
+        rest = rest[name_end..].trim_start();
+        let value = match rest.strip_prefix('=') {
+            Some(after) => {
+                let after = after.trim_start();
+                let (value, tail) = match after.chars().next() {
+                    Some(quote @ ('"' | '\'')) => {
+                        after[1..].split_once(quote).unwrap_or((&after[1..], ""))
+                    }
+                    _ => after.split_at(after.find(char::is_whitespace).unwrap_or(after.len())),
+                };
+                rest = tail;
+                value
+            }
+            None => {
+                rest = rest.strip_prefix('/').unwrap_or(rest);
+                ""
+            }
+        };
+        if !name.is_empty() {
+            attributes.entry(name).or_insert_with(|| value.to_string());
+        }
+        rest = rest.trim_start();
+    }
+    attributes
+}
+
+/// Decodes entities, strips escapes, collapses whitespace and shortens
+/// `text` to `max` characters; `None` if nothing is left.
+fn clean_text(text: &str, max: usize) -> Option<String> {
+    let decoded = decode_entities(text);
+    let collapsed = strip_escapes(&decoded)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ");
+    let mut cleaned: String = collapsed.chars().take(max).collect();
+    if cleaned.len() < collapsed.len() {
+        cleaned.push('\u{2026}');
+    }
+    (!cleaned.is_empty()).then_some(cleaned)
+}
+
+/// Decodes the HTML entities pages commonly use in titles.
+fn decode_entities(text: &str) -> String {
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let entity = rest
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| Some((decode_entity(&rest[1..end])?, end)));
+        match entity {
+            Some((c, end)) => {
+                decoded.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+fn decode_entity(name: &str) -> Option<char> {
+    Some(match name {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => ' ',
+        _ => {
+            let code = match name.strip_prefix("#x").or_else(|| name.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+                None => name.strip_prefix('#')?.parse().ok()?,
+            };
+            char::from_u32(code)?
+        }
+    })
+}
+
+/// Resolves an image reference against the page it's on, keeping only
+/// `http` and `https` results.
+fn absolute_url(page_url: &PageUrl, reference: &str) -> Option<String> {
+    let reference = reference.trim();
+    let url = if let Some(rest) = reference.strip_prefix("//") {
+        format!("{}://{}", page_url.scheme, rest)
+    } else if reference.starts_with('/') {
+        format!("{}{}", page_url.origin(), reference)
+    } else {
+        reference.to_string()
+    };
+    PageUrl::parse(&url).map(|_| url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_page_url() {
+        let url = PageUrl::parse("HTTP://Example.com:8080?q=1#top").unwrap();
+        assert_eq!(
+            (url.scheme.as_str(), url.host.as_str(), url.port),
+            ("http", "example.com", 8080)
+        );
+        assert_eq!(url.to_string(), "http://example.com:8080/?q=1");
+        let url = PageUrl::parse("https://[::1]/a/b").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 443));
+        assert_eq!(url.to_string(), "https://[::1]/a/b");
+        assert!(PageUrl::parse("http://admin@10.0.0.1/").is_none());
+        assert!(PageUrl::parse("file:///etc/passwd").is_none());
+        assert!(PageUrl::parse("http://:80/").is_none());
+    }
+
+    #[test]
+    fn test_only_public_addresses() {
+        for private in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(private.parse().unwrap()), "{}", private);
+        }
+        for public in ["93.184.216.34", "1.1.1.1", "2606:4700::1111"] {
+            assert!(is_public(public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[test]
+    fn test_parse_page() {
+        let html = r#"<html><head>
+            <TITLE>Fallback</TITLE>
+            <meta property="og:title" content="Tokio &amp; friends">
+            <meta name=description content='An   async
+                runtime'>
+            <meta property="og:image" content="/logo.png" />
+        </head></html>"#;
+        let page_url = PageUrl::parse("http://tokio.rs/blog").unwrap();
+        let preview = parse_page("http://tokio.rs/blog", &page_url, html);
+        assert_eq!(preview.title.as_deref(), Some("Tokio & friends"));
+        assert_eq!(preview.description.as_deref(), Some("An async runtime"));
+        assert_eq!(preview.image.as_deref(), Some("http://tokio.rs/logo.png"));
+
+        let preview = parse_page("http://x/", &page_url, "<title>Only &#x41; title</title>");
+        assert_eq!(preview.title.as_deref(), Some("Only A title"));
+        assert_eq!(preview.image, None);
+    }
+}
@@ -1,27 +1,2097 @@
+use crate::apikey::KeyScope;
+use crate::invite::Invite;
+use crate::nickname;
+use crate::quota::{QuotaResource, QuotaUsage, QuotaWindow, Resource};
+use crate::room::RoomConfig;
+use crate::sanitize;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Server-assigned identifier of a relayed chat message.
+pub type MessageId = u64;
+
+/// Room messages go to when they don't name one.
+pub const DEFAULT_ROOM: &str = "general";
+/// Longest sender name, in characters.
+pub const MAX_SENDER_LEN: usize = 32;
+/// Longest room name, in characters.
+pub const MAX_ROOM_NAME_LEN: usize = 32;
+/// Longest message content, in bytes.
+pub const MAX_CONTENT_LEN: usize = 4096;
+/// Most formatting entities one message may carry.
+pub const MAX_ENTITIES: usize = 100;
+/// Most rooms one user may mute.
+pub const MAX_MUTED_ROOMS: usize = 256;
+/// Most users and rooms one connection may follow the presence of.
+pub const MAX_PRESENCE_SUBSCRIPTIONS: usize = 1000;
+/// Most messages one user may save.
+pub const MAX_SAVED_MESSAGES: usize = 1000;
+/// Most rooms one user may keep a draft for; past it, the oldest draft is
+/// dropped.
+pub const MAX_DRAFTS: usize = 100;
+/// Furthest a time zone is from UTC, in minutes.
+const MAX_UTC_OFFSET: i16 = 14 * 60;
+const MINUTES_PER_DAY: i64 = 24 * 60;
+/// Longest email address, in bytes.
+const MAX_EMAIL_LEN: usize = 254;
+
+/// Why a chat message was rejected.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ValidationError {
+    /// A raw line had no `sender:content` separator.
+    InvalidFormat,
+    EmptySender,
+    SenderTooLong {
+        max: usize,
+    },
+    /// Senders may only use letters, digits, `_`, `-` and `.`.
+    InvalidSenderChar(char),
+    EmptyRoomName,
+    RoomNameTooLong {
+        max: usize,
+    },
+    /// Room names may only use letters, digits, `_`, `-` and `.`.
+    InvalidRoomNameChar(char),
+    ContentTooLong {
+        max: usize,
+    },
+    TooManyEntities {
+        max: usize,
+    },
+    /// The entity at `index` is empty, runs past the content, or doesn't
+    /// start and end on character boundaries.
+    EntityOutOfRange {
+        index: usize,
+    },
+    /// The entity at `index` partly overlaps an earlier one; entities must
+    /// nest or be apart.
+    EntitiesCross {
+        index: usize,
+    },
+    /// The link entity at `index` isn't an `http`, `https` or `mailto` URL.
+    UnsafeLink {
+        index: usize,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::InvalidFormat => write!(f, "Expected \"sender:content\""),
+            ValidationError::EmptySender => write!(f, "Sender is empty"),
+            ValidationError::SenderTooLong { max } => {
+                write!(f, "Sender exceeds {} characters", max)
+            }
+            ValidationError::InvalidSenderChar(c) => {
+                write!(f, "Sender contains invalid character {:?}", c)
+            }
+            ValidationError::EmptyRoomName => write!(f, "Room name is empty"),
+            ValidationError::RoomNameTooLong { max } => {
+                write!(f, "Room name exceeds {} characters", max)
+            }
+            ValidationError::InvalidRoomNameChar(c) => {
+                write!(f, "Room name contains invalid character {:?}", c)
+            }
+            ValidationError::ContentTooLong { max } => write!(f, "Content exceeds {} bytes", max),
+            ValidationError::TooManyEntities { max } => {
+                write!(f, "Content has more than {} entities", max)
+            }
+            ValidationError::EntityOutOfRange { index } => {
+                write!(f, "Entity {} is outside the content", index)
+            }
+            ValidationError::EntitiesCross { index } => {
+                write!(f, "Entity {} overlaps another without nesting", index)
+            }
+            ValidationError::UnsafeLink { index } => {
+                write!(f, "Entity {} links to an unsupported URL", index)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
 pub struct ChatMessage {
     pub sender: String,
     pub content: String,
+    /// Assigned by the server when the message is relayed; ignored on input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<MessageId>,
+    /// Target room, `DEFAULT_ROOM` when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Seconds until the message expires, for rooms that allow ephemeral content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl_secs: Option<u64>,
+    /// Unix time (seconds) to deliver the message at; times in the past are
+    /// delivered immediately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub send_at: Option<u64>,
+    /// Unix time (seconds) the server relayed the message; ignored on input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    /// Position in the room, assigned by the server; ignored on input.
+    /// Sequence numbers start at 1 and increase by one per message in each
+    /// room, and every client receives a room's messages in sequence order,
+    /// so a jump means messages were missed (e.g. after lagging behind).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+    /// How `content` is marked up; clients that don't render it show the
+    /// content as plain text.
+    #[serde(default, skip_serializing_if = "TextFormat::is_plain")]
+    pub format: TextFormat,
+    /// Formatting spans over `content`, for clients that render them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entities: Vec<Entity>,
+    /// Where a message sent with `ClientFrame::Forward` first appeared; set
+    /// by the server and ignored on input.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded: Option<Forwarded>,
+    /// A stored file shared with the message (e.g. uploaded over HTTP);
+    /// `content` is its caption.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attachment: Option<FileRef>,
+}
+
+/// The original of a forwarded message. Forwarding a forward keeps
+/// pointing at the first one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Forwarded {
+    pub message_id: MessageId,
+    pub room: String,
+    pub sender: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+}
+
+/// Markup in a message's content.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum TextFormat {
+    #[default]
+    Plain,
+    Markdown,
+}
+
+impl TextFormat {
+    pub fn is_plain(&self) -> bool {
+        *self == TextFormat::Plain
+    }
+}
+
+/// A span of a message's content with formatting or meaning attached.
+/// Offsets are in UTF-8 bytes and must fall on character boundaries.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Entity {
+    pub offset: usize,
+    pub length: usize,
+    #[serde(flatten)]
+    pub kind: EntityKind,
+}
+
+/// What an `Entity` marks.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EntityKind {
+    Bold,
+    Italic,
+    /// Inline code.
+    Code,
+    CodeBlock {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+    },
+    /// A link to `url`, which may differ from the text it covers.
+    Link {
+        url: String,
+    },
+    /// A mention of `user`.
+    Mention {
+        user: String,
+    },
+}
+
+impl Entity {
+    /// The part of `content` the entity covers, if it fits.
+    pub fn text<'a>(&self, content: &'a str) -> Option<&'a str> {
+        content.get(self.offset..self.offset.checked_add(self.length)?)
+    }
 }
 
 impl ChatMessage {
     /// Creates a new ChatMessage from a raw string
     /// (examples: "user:msg" or "sender:content" or "avery:bye")
-    pub fn from_raw(raw: &str) -> Result<Self> {
-        let parts: Vec<&str> = raw.splitn(2, ':').collect();
-        if parts.len() != 2 {
-            return Err(anyhow::anyhow!("Invalid message format: {}", raw));
-        }
-        Ok(ChatMessage {
-            sender: parts[0].trim().to_string(),
-            content: parts[1].trim().to_string(),
-        })
+    pub fn from_raw(raw: &str) -> Result<Self, ValidationError> {
+        let (sender, content) = raw.split_once(':').ok_or(ValidationError::InvalidFormat)?;
+        ChatMessage::builder()
+            .sender(sender.trim())
+            .content(content.trim())
+            .build()
     }
+
+    /// Starts building a message; `build` validates it.
+    ///
+    /// # Examples
+    /// ```
+    /// use tokio_chat_server::protocol::ChatMessage;
+    ///
+    /// let message = ChatMessage::builder()
+    ///     .sender("avery")
+    ///     .content("hello")
+    ///     .room("lobby")
+    ///     .build()
+    ///     .unwrap();
+    /// assert_eq!(message.room(), "lobby");
+    /// ```
+    pub fn builder() -> ChatMessageBuilder {
+        ChatMessageBuilder::default()
+    }
+
+    /// Strips terminal escape sequences and control characters (other
+    /// than newlines and tabs) from the content and puts the sender in NFKC
+    /// form, then checks the sender, room name and content limits and the
+    /// entities. Entities are dropped if the content had anything stripped,
+    /// since their offsets no longer line up.
+    pub fn validated(mut self) -> Result<Self, ValidationError> {
+        if let Cow::Owned(content) = sanitize::strip_escapes(&self.content) {
+            self.set_cleaned_content(content);
+        }
+        self.sender = nickname::normalize(&self.sender);
+        if self.sender.is_empty() {
+            return Err(ValidationError::EmptySender);
+        }
+        if self.sender.chars().count() > MAX_SENDER_LEN {
+            return Err(ValidationError::SenderTooLong {
+                max: MAX_SENDER_LEN,
+            });
+        }
+        if let Some(c) = self
+            .sender
+            .chars()
+            .find(|&c| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+        {
+            return Err(ValidationError::InvalidSenderChar(c));
+        }
+        if let Some(room) = &self.room {
+            check_room_name(room)?;
+        }
+        if self.content.len() > MAX_CONTENT_LEN {
+            return Err(ValidationError::ContentTooLong {
+                max: MAX_CONTENT_LEN,
+            });
+        }
+        self.check_entities()?;
+        Ok(self)
+    }
+
+    /// Replaces the content with a cleaned-up version of itself, dropping
+    /// entities whose offsets no longer line up.
+    pub fn set_cleaned_content(&mut self, content: String) {
+        if content != self.content {
+            self.content = content;
+            self.entities.clear();
+        }
+    }
+
+    fn check_entities(&self) -> Result<(), ValidationError> {
+        if self.entities.len() > MAX_ENTITIES {
+            return Err(ValidationError::TooManyEntities { max: MAX_ENTITIES });
+        }
+        for (index, entity) in self.entities.iter().enumerate() {
+            if entity.length == 0 || entity.text(&self.content).is_none() {
+                return Err(ValidationError::EntityOutOfRange { index });
+            }
+            let (start, end) = (entity.offset, entity.offset + entity.length);
+            if self.entities[..index].iter().any(|earlier| {
+                let (earlier_start, earlier_end) =
+                    (earlier.offset, earlier.offset + earlier.length);
+                (earlier_start < start && start < earlier_end && earlier_end < end)
+                    || (start < earlier_start && earlier_start < end && end < earlier_end)
+            }) {
+                return Err(ValidationError::EntitiesCross { index });
+            }
+            if let EntityKind::Link { url } = &entity.kind {
+                let scheme = url.split_once(':').map(|(scheme, _)| scheme);
+                if !scheme.is_some_and(|scheme| {
+                    ["http", "https", "mailto"]
+                        .iter()
+                        .any(|safe| scheme.eq_ignore_ascii_case(safe))
+                }) {
+                    return Err(ValidationError::UnsafeLink { index });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The capabilities a client needs to be sent this message as it is.
+    pub fn capabilities(&self) -> BTreeSet<Capability> {
+        let mut needed = BTreeSet::new();
+        if !self.format.is_plain() || !self.entities.is_empty() {
+            needed.insert(Capability::Formatting);
+        }
+        if self.forwarded.is_some() {
+            needed.insert(Capability::Forwarding);
+        }
+        if self.attachment.is_some() {
+            needed.insert(Capability::Attachments);
+        }
+        needed
+    }
+
+    /// Drops what a client with only `capabilities` can't render: markup
+    /// becomes plain text, and a forward's origin and an attachment move
+    /// into the content.
+    pub fn downgrade(&mut self, capabilities: &BTreeSet<Capability>) {
+        if !capabilities.contains(&Capability::Formatting) {
+            self.format = TextFormat::Plain;
+            self.entities.clear();
+        }
+        if !capabilities.contains(&Capability::Forwarding)
+            && let Some(forwarded) = self.forwarded.take()
+        {
+            let origin = format!(
+                "[Forwarded from {} in {}] ",
+                forwarded.sender, forwarded.room
+            );
+            for entity in &mut self.entities {
+                entity.offset += origin.len();
+            }
+            self.content.insert_str(0, &origin);
+        }
+        if !capabilities.contains(&Capability::Attachments)
+            && let Some(file) = self.attachment.take()
+        {
+            let described = format!("[Attachment: {}] ", file);
+            for entity in &mut self.entities {
+                entity.offset += described.len();
+            }
+            self.content.insert_str(0, &described);
+        }
+    }
+
     /// Serializes the message to JSON
     pub fn to_json(&self) -> Result<String> {
         Ok(serde_json::to_string(self)?)
     }
+
+    /// Returns the room this message belongs to.
+    pub fn room(&self) -> &str {
+        self.room.as_deref().unwrap_or(DEFAULT_ROOM)
+    }
+
+    /// Whether the message mentions `user`, with a mention entity or an
+    /// `@user` in its content.
+    pub fn mentions(&self, user: &str) -> bool {
+        let tagged = self.entities.iter().any(|entity| match &entity.kind {
+            EntityKind::Mention { user: mentioned } => mentioned == user,
+            _ => false,
+        });
+        tagged
+            || self.content.match_indices('@').any(|(at, _)| {
+                let rest = &self.content[at + 1..];
+                rest.strip_prefix(user).is_some_and(|after| {
+                    !after.starts_with(|c: char| c.is_alphanumeric() || matches!(c, '_' | '-'))
+                })
+            })
+    }
+}
+
+/// Builds a validated `ChatMessage`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChatMessageBuilder {
+    message: ChatMessage,
+}
+
+impl ChatMessageBuilder {
+    pub fn sender(mut self, sender: impl Into<String>) -> Self {
+        self.message.sender = sender.into();
+        self
+    }
+
+    pub fn content(mut self, content: impl Into<String>) -> Self {
+        self.message.content = content.into();
+        self
+    }
+
+    pub fn room(mut self, room: impl Into<String>) -> Self {
+        self.message.room = Some(room.into());
+        self
+    }
+
+    /// See `ChatMessage::ttl_secs`.
+    pub fn ttl_secs(mut self, ttl_secs: u64) -> Self {
+        self.message.ttl_secs = Some(ttl_secs);
+        self
+    }
+
+    /// See `ChatMessage::send_at`.
+    pub fn send_at(mut self, send_at: u64) -> Self {
+        self.message.send_at = Some(send_at);
+        self
+    }
+
+    pub fn format(mut self, format: TextFormat) -> Self {
+        self.message.format = format;
+        self
+    }
+
+    /// See `ChatMessage::attachment`.
+    pub fn attachment(mut self, file: FileRef) -> Self {
+        self.message.attachment = Some(file);
+        self
+    }
+
+    /// Adds a formatting span; see `Entity`.
+    pub fn entity(mut self, offset: usize, length: usize, kind: EntityKind) -> Self {
+        self.message.entities.push(Entity {
+            offset,
+            length,
+            kind,
+        });
+        self
+    }
+
+    /// Validates the message; see `ChatMessage::validated`.
+    pub fn build(self) -> Result<ChatMessage, ValidationError> {
+        self.message.validated()
+    }
+}
+
+/// Checks a room name, whether it's being created or posted to: at most
+/// `MAX_ROOM_NAME_LEN` characters, all letters, digits, `_`, `-` or `.`.
+pub fn check_room_name(room: &str) -> Result<(), ValidationError> {
+    if room.is_empty() {
+        return Err(ValidationError::EmptyRoomName);
+    }
+    if room.chars().count() > MAX_ROOM_NAME_LEN {
+        return Err(ValidationError::RoomNameTooLong {
+            max: MAX_ROOM_NAME_LEN,
+        });
+    }
+    match room
+        .chars()
+        .find(|&c| !(c.is_alphanumeric() || matches!(c, '_' | '-' | '.')))
+    {
+        Some(c) => Err(ValidationError::InvalidRoomNameChar(c)),
+        None => Ok(()),
+    }
+}
+
+/// How busy a room has been lately, by the messages relayed to it in the
+/// server's analytics window (the last hour by default).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ActivityLevel {
+    Idle,
+    Quiet,
+    Active,
+    Busy,
+}
+
+impl ActivityLevel {
+    pub fn from_messages(messages: u64) -> Self {
+        match messages {
+            0 => ActivityLevel::Idle,
+            1..10 => ActivityLevel::Quiet,
+            10..100 => ActivityLevel::Active,
+            _ => ActivityLevel::Busy,
+        }
+    }
+}
+
+/// A message being composed, kept on the server so the user can carry on
+/// from another device.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Draft {
+    pub content: String,
+    /// Unix time (seconds) the draft was last saved.
+    pub updated_at: u64,
+}
+
+/// A room in the directory.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RoomListing {
+    pub room: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    pub member_count: usize,
+    pub activity: ActivityLevel,
+}
+
+/// Availability a user advertises to everyone else.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceState {
+    #[default]
+    Online,
+    Away,
+    Dnd,
+}
+
+/// How prominently clients should show an announcement.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum AnnouncementLevel {
+    #[default]
+    Info,
+    Warning,
+    /// Worth interrupting the user for, e.g. imminent downtime.
+    Critical,
+}
+
+/// Profile fields a client can set about itself.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Profile {
+    pub display_name: Option<String>,
+    pub status_text: Option<String>,
+    pub state: PresenceState,
+}
+
+/// A daily do-not-disturb window, in minutes after midnight in the user's
+/// time zone. It runs past midnight when `start` is after `end`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct QuietHours {
+    pub start: u16,
+    pub end: u16,
+    /// The user's time zone, in minutes east of UTC.
+    #[serde(default)]
+    pub utc_offset: i16,
+}
+
+impl QuietHours {
+    /// Whether the window covers the Unix time `now`.
+    pub fn contains(&self, now: u64) -> bool {
+        let minute = (now / 60) as i64 + i64::from(self.utc_offset);
+        let minute = minute.rem_euclid(MINUTES_PER_DAY) as u16;
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        i64::from(self.start) < MINUTES_PER_DAY
+            && i64::from(self.end) < MINUTES_PER_DAY
+            && self.utc_offset.abs() <= MAX_UTC_OFFSET
+    }
+}
+
+/// When a user wants to be notified, shared by all their devices.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct NotificationPrefs {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quiet_hours: Option<QuietHours>,
+    /// Mentions and direct messages still notify during quiet hours.
+    #[serde(default)]
+    pub mentions_break_quiet_hours: bool,
+    /// Rooms that never notify.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub muted_rooms: BTreeSet<String>,
+    /// Only mentions and direct messages notify.
+    #[serde(default)]
+    pub mention_only: bool,
+    /// Emails mentions and direct messages missed while offline, on
+    /// servers that send email digests.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_digest: Option<EmailDigest>,
+}
+
+/// Where and how often missed messages are emailed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct EmailDigest {
+    pub address: String,
+    pub frequency: DigestFrequency,
+}
+
+/// How long a missed message waits before a digest with it is sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum DigestFrequency {
+    Hourly,
+    Daily,
+}
+
+impl DigestFrequency {
+    pub fn period(self) -> Duration {
+        match self {
+            DigestFrequency::Hourly => Duration::from_secs(60 * 60),
+            DigestFrequency::Daily => Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl EmailDigest {
+    /// A rough check that `address` is one mailbox: something, an `@`,
+    /// then a domain, with no spaces or line breaks to smuggle headers in.
+    fn is_valid(&self) -> bool {
+        self.address.len() <= MAX_EMAIL_LEN
+            && !self
+                .address
+                .contains(|c: char| c.is_whitespace() || c.is_control())
+            && self.address.split_once('@').is_some_and(|(local, domain)| {
+                !local.is_empty() && domain.contains('.') && !domain.contains('@')
+            })
+    }
+}
+
+impl NotificationPrefs {
+    /// Whether a message should notify: one in `room`, or a direct message
+    /// if `room` is `None`, that may mention the user, arriving at the
+    /// Unix time `now`.
+    pub fn notifies(&self, room: Option<&str>, mentioned: bool, now: u64) -> bool {
+        if room.is_some_and(|room| self.muted_rooms.contains(room)) {
+            return false;
+        }
+        let addressed = room.is_none() || mentioned;
+        if self.mention_only && !addressed {
+            return false;
+        }
+        let quiet = self.quiet_hours.is_some_and(|hours| hours.contains(now));
+        !quiet || (addressed && self.mentions_break_quiet_hours)
+    }
+
+    /// Checks the quiet hours are real times and there aren't too many
+    /// muted rooms.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.quiet_hours.is_some_and(|hours| !hours.is_valid()) {
+            return Err(format!(
+                "Quiet hours must be minutes from 0 to {} with an offset of at most {} minutes",
+                MINUTES_PER_DAY - 1,
+                MAX_UTC_OFFSET
+            ));
+        }
+        if self.muted_rooms.len() > MAX_MUTED_ROOMS {
+            return Err(format!("At most {} rooms can be muted", MAX_MUTED_ROOMS));
+        }
+        if self
+            .email_digest
+            .as_ref()
+            .is_some_and(|digest| !digest.is_valid())
+        {
+            return Err("Invalid email address".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A push notification service.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum PushPlatform {
+    /// Firebase Cloud Messaging, for Android.
+    Fcm,
+    /// Apple Push Notification service.
+    Apns,
+    /// The Web Push protocol, for browsers; the token is the subscription
+    /// as JSON.
+    WebPush,
+}
+
+impl fmt::Display for PushPlatform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushPlatform::Fcm => write!(f, "FCM"),
+            PushPlatform::Apns => write!(f, "APNs"),
+            PushPlatform::WebPush => write!(f, "Web Push"),
+        }
+    }
+}
+
+/// Where one of a user's devices receives push notifications.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PushToken {
+    pub platform: PushPlatform,
+    pub token: String,
+}
+
+/// A single entry in a presence listing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct UserPresence {
+    pub user: String,
+    /// The profile of the user's most available device.
+    pub profile: Profile,
+    /// Connections the user is signed in on.
+    #[serde(default)]
+    pub devices: usize,
+}
+
+/// Whose presence changes a connection hears about: the listed users and
+/// everyone taking part in the listed rooms. Connections that never
+/// subscribe hear about everyone's.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct PresenceSubscription {
+    #[serde(default)]
+    pub users: BTreeSet<String>,
+    #[serde(default)]
+    pub rooms: BTreeSet<String>,
+}
+
+impl PresenceSubscription {
+    pub fn len(&self) -> usize {
+        self.users.len() + self.rooms.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Control frames sent from a client to the server.
+///
+/// Plain `ChatMessage` JSON and "sender:content" lines are still accepted
+/// alongside these.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "type")]
+pub enum ClientFrame {
+    /// Updates the sender's profile; fields left as `None` are unchanged.
+    SetProfile {
+        display_name: Option<String>,
+        status_text: Option<String>,
+        state: Option<PresenceState>,
+    },
+    /// Asks for the presence of every connected user.
+    Presence,
+    /// Follows the presence of `users` and of the participants of `rooms`
+    /// on top of any already followed, after which `PresenceChanged` is
+    /// only sent for those followed. Answered with `PresenceSubscribed`.
+    SubscribePresence {
+        #[serde(default)]
+        users: Vec<String>,
+        #[serde(default)]
+        rooms: Vec<String>,
+    },
+    /// Stops following the presence of `users` and `rooms`. With neither,
+    /// goes back to hearing about everyone. Answered with
+    /// `PresenceSubscribed`.
+    UnsubscribePresence {
+        #[serde(default)]
+        users: Vec<String>,
+        #[serde(default)]
+        rooms: Vec<String>,
+    },
+    /// Announces an upload to share in `room` (`DEFAULT_ROOM` when unset);
+    /// the server answers with `FileAccepted`.
+    FileOffer {
+        name: String,
+        size: u64,
+        /// Hex SHA-256 of the whole file, checked once the upload completes.
+        hash: String,
+        #[serde(default)]
+        room: Option<String>,
+    },
+    /// One base64-encoded piece of an accepted upload.
+    FileChunk { transfer_id: u64, data: String },
+    /// Asks the server to stream back a file shared in a room the client
+    /// can read. Chunks beyond the first few are only sent as earlier ones
+    /// are acknowledged with `FileReceived`.
+    FetchFile { id: String },
+    /// Acknowledges the chunks of a `FetchFile` stream; `received` is the
+    /// running byte total.
+    FileReceived { id: String, received: u64 },
+    /// Creates an invite link to `room`, for those allowed to invite to it.
+    /// It expires after `expires_in_secs` and `max_uses` redemptions, if
+    /// set. Answered with `InviteCreated`.
+    CreateInvite {
+        room: String,
+        #[serde(default)]
+        expires_in_secs: Option<u64>,
+        #[serde(default)]
+        max_uses: Option<u32>,
+    },
+    /// Reposts the stored message `message_id` in `to_room` as the sender,
+    /// with `ChatMessage::forwarded` saying where it came from. Needs
+    /// permission to post in `to_room`.
+    Forward {
+        message_id: MessageId,
+        to_room: String,
+    },
+    /// Joins the room an invite is for, whatever its invite permission.
+    /// Answered with `InviteRedeemed`.
+    RedeemInvite { token: String },
+    /// Pages through the directory of public rooms, those anyone may
+    /// `/join`, busiest first. `filter` keeps the rooms whose name or topic
+    /// contains it, ignoring case; `page` counts from 0.
+    ListRooms {
+        #[serde(default)]
+        filter: Option<String>,
+        #[serde(default)]
+        page: usize,
+    },
+    /// Pages through a room's members by name: returns up to `limit`
+    /// named after `after`, or the first when `after` is unset.
+    ListMembers {
+        room: String,
+        after: Option<String>,
+        limit: Option<usize>,
+    },
+    /// Pages back through a room's history: returns up to `limit` messages
+    /// older than `before`, or the newest when `before` is unset.
+    FetchHistory {
+        room: Option<String>,
+        before: Option<MessageId>,
+        limit: Option<usize>,
+    },
+    /// Sent after reconnecting with the id of the last message the client
+    /// saw; the server answers with `Replay`.
+    Resume { last_id: MessageId },
+    /// Full-text search over stored history. Results are newest first; pass
+    /// the returned `next_cursor` as `before` to get the next page.
+    Search {
+        query: String,
+        room: Option<String>,
+        limit: Option<usize>,
+        before: Option<MessageId>,
+    },
+    /// Cancels a message scheduled with `send_at` before it is delivered.
+    /// Only the signed-in user who sent it may.
+    CancelScheduled { pending_id: u64 },
+    /// Proves the client is `user`; guests can send this mid-session to
+    /// upgrade. The server answers with `Authenticated` or an `Error`.
+    Authenticate { user: String, token: String },
+    /// Signs in as the service account or bot an API key was issued to,
+    /// limited to the key's scope. Answered like `Authenticate`.
+    ApiKey { key: String },
+    /// Answers a `ServerFrame::Challenge`.
+    ChallengeResponse { solution: String },
+    /// Picks which of the server's tenants to talk to. Only valid as the
+    /// first frame on a connection.
+    SelectTenant { tenant: String },
+    /// Asks for server-generated text (errors, notices, messages of the
+    /// day) in one of `locales`, most preferred first, e.g. `["pt-BR",
+    /// "en"]`. Usually sent first thing; answered with `LocaleSelected`.
+    SetLocale { locales: Vec<String> },
+    /// Declares which optional parts of the protocol the client handles;
+    /// names the server doesn't know are ignored. Until this is sent the
+    /// client is assumed to handle all of them. Answered with
+    /// `CapabilitiesSelected`.
+    SetCapabilities { capabilities: BTreeSet<Capability> },
+    /// Replaces the signed-in user's notification preferences; every one
+    /// of their connections is sent the new `NotificationPrefs`.
+    SetNotificationPrefs { prefs: NotificationPrefs },
+    /// Saves what the signed-in user is composing in `room`; every one of
+    /// their connections is sent the new `Draft`. Up to `MAX_DRAFTS` are
+    /// kept, and posting in the room clears its draft.
+    SetDraft { room: String, content: String },
+    /// Discards the signed-in user's draft in `room`.
+    ClearDraft { room: String },
+    /// Asks for the signed-in user's drafts. Answered with `Drafts`.
+    GetDrafts,
+    /// Bookmarks the stored message `message_id` in the signed-in user's
+    /// private saved list, up to `MAX_SAVED_MESSAGES`. Answered with
+    /// `MessageSaved`.
+    SaveMessage { message_id: MessageId },
+    /// Removes a message from the signed-in user's saved list. Answered
+    /// with `MessageUnsaved`.
+    UnsaveMessage { message_id: MessageId },
+    /// Asks for the signed-in user's saved messages. Answered with `Saved`.
+    ListSaved,
+    /// Has the signed-in user's notifications pushed to a device while it
+    /// isn't connected, on servers with a push gateway.
+    RegisterPushToken { push: PushToken },
+    /// Stops pushing to a device, e.g. when the user signs out of it.
+    UnregisterPushToken { token: String },
+    /// Sent after reconnecting with the `resume_token` of a session that
+    /// ended within the server's grace period, to carry on as that session
+    /// without authenticating again. Missed messages newer than `last_id`
+    /// (or than the last one relayed before the disconnect) are replayed.
+    ResumeSession {
+        token: String,
+        last_id: Option<MessageId>,
+    },
+    /// Asks for a room's messages with sequence numbers in
+    /// `[from_seq, to_seq]`, e.g. after noticing a jump in `ChatMessage::seq`.
+    /// The server answers with `Backfill`.
+    Backfill {
+        room: String,
+        from_seq: u64,
+        to_seq: u64,
+    },
+    /// Acknowledges every message received in `room` up to and including
+    /// `seq`, so an at-least-once room stops holding them for this user.
+    Ack { room: String, seq: u64 },
+    /// Keeps an idle connection open without counting as activity, so it
+    /// can still be marked away.
+    Heartbeat,
+    /// Asks for the custom emoji; the server answers with `EmojiList`.
+    ListEmoji,
+    /// Asks how the server sees this connection, e.g. to find out why
+    /// messages aren't arriving. Only users the server lets debug may ask;
+    /// answered with `DebugInfo`.
+    DebugInfo,
+}
+
+impl ClientFrame {
+    /// Serializes the frame to JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+/// A shareable reference to a stored file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct FileRef {
+    /// Blob id, the hex SHA-256 of the contents.
+    pub id: String,
+    pub name: String,
+    pub size: u64,
+}
+
+/// An emoji an admin added, written `:name:` in messages. Its image is
+/// the blob `blob_id`, fetched with `ClientFrame::FetchFile`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CustomEmoji {
+    pub name: String,
+    pub blob_id: String,
+}
+
+/// What a linked page is about, taken from its `<title>` and `<meta>` tags.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct LinkPreview {
+    /// The link as it appeared in the message.
+    pub url: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Absolute URL of the page's preview image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image: Option<String>,
+}
+
+/// A message matching a search, with the matching part of its content.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SearchHit {
+    pub message: ChatMessage,
+    pub snippet: String,
+}
+
+/// A message a user bookmarked, as it was when they saved it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct SavedMessage {
+    pub message: ChatMessage,
+    /// Unix time (seconds) it was saved.
+    pub saved_at: u64,
+}
+
+/// The server's view of one connection, sent in `ServerFrame::DebugInfo`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ConnectionDebugInfo {
+    /// The connection's address, as seen by the server.
+    pub addr: String,
+    /// Who the connection posts as.
+    pub user: String,
+    pub guest: bool,
+    /// What the API key it signed in with allows, if it used one.
+    pub api_key_scope: Option<KeyScope>,
+    pub locale: String,
+    /// Negotiated with `SetCapabilities`, or every capability.
+    pub capabilities: BTreeSet<Capability>,
+    pub profile: Profile,
+    /// Set once the connection has gone idle.
+    pub idle: bool,
+    /// Whose presence it follows; everyone's if unset.
+    pub presence_subscription: Option<PresenceSubscription>,
+    /// Rooms the user is a member of.
+    pub rooms: Vec<String>,
+    /// Rooms the user muted; they aren't notified of them.
+    pub muted_rooms: BTreeSet<String>,
+    /// Users whose messages the user doesn't receive.
+    pub blocked: Vec<String>,
+    /// Frames and bytes waiting to be written to the connection.
+    pub queued_frames: usize,
+    pub queued_bytes: usize,
+    /// Chat frames that fit before the oldest is dropped.
+    pub queue_capacity: usize,
+    /// What the user has sent against their quotas.
+    pub quota: QuotaUsage,
+}
+
+/// Event and response frames sent from the server to clients.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(tag = "type")]
+pub enum ServerFrame {
+    /// Sent on connect to clients that must prove work before anything
+    /// else they send is accepted: find a `solution` for which the SHA-256
+    /// of `nonce:solution` starts with `difficulty` zero bits, and send it
+    /// in `ClientFrame::ChallengeResponse`.
+    Challenge { nonce: String, difficulty: u8 },
+    /// Response to a correct `ClientFrame::ChallengeResponse`.
+    ChallengeAccepted,
+    /// Sent on connect when guests are enabled, with the nickname this
+    /// connection posts under until it authenticates.
+    Welcome {
+        user: String,
+        guest: bool,
+        resume_token: String,
+    },
+    /// Response to `ClientFrame::Authenticate`; messages from this
+    /// connection are now sent as `user`. `resume_token` can be passed to
+    /// `ResumeSession` after a disconnect. `notifications` are the user's
+    /// notification preferences, as last set from any of their devices.
+    Authenticated {
+        user: String,
+        resume_token: String,
+        #[serde(default)]
+        notifications: NotificationPrefs,
+    },
+    /// Rejects a `/register` because `nick` (after NFKC normalization)
+    /// looks like the registered nickname `conflicts_with`.
+    NicknameConflict {
+        nick: String,
+        conflicts_with: String,
+    },
+    /// Response to `ClientFrame::SetLocale`: the locale server-generated
+    /// text is now translated into, "en" if none of those asked for is
+    /// available.
+    LocaleSelected { locale: String },
+    /// Response to `ClientFrame::SetCapabilities`: the capabilities the
+    /// server will rely on. Frames needing any other are downgraded: a
+    /// message's attachment or forwarding origin is dropped and described
+    /// in a prefix to its content, e.g. "[Attachment: notes.txt (12
+    /// bytes)] ", formatting is dropped, and a link preview is sent as a
+    /// `Notice`.
+    CapabilitiesSelected { capabilities: BTreeSet<Capability> },
+    /// Sent to all of a user's connections when their notification
+    /// preferences change.
+    NotificationPrefs { prefs: NotificationPrefs },
+    /// Sent to all of a user's connections when their draft in `room` is
+    /// saved, or cleared if `draft` is unset.
+    Draft {
+        room: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        draft: Option<Draft>,
+    },
+    /// Response to `ClientFrame::GetDrafts`, by room.
+    Drafts { drafts: BTreeMap<String, Draft> },
+    /// Response to `ClientFrame::SaveMessage`.
+    MessageSaved { message_id: MessageId },
+    /// Response to `ClientFrame::UnsaveMessage`.
+    MessageUnsaved { message_id: MessageId },
+    /// Response to `ClientFrame::ListSaved`, most recently saved first.
+    Saved { messages: Vec<SavedMessage> },
+    /// Response to `ClientFrame::ResumeSession`, followed by a `Replay`.
+    /// `notifications` are as in `Authenticated`.
+    SessionResumed {
+        user: String,
+        resume_token: String,
+        #[serde(default)]
+        notifications: NotificationPrefs,
+    },
+    /// A chat message relayed to everyone; `from` is the sender's address.
+    Message { from: String, message: ChatMessage },
+    /// Follows a `Message` with a link in it once the linked page has been
+    /// fetched, for servers with link previews on. Not kept in history.
+    LinkPreview {
+        room: String,
+        message_id: MessageId,
+        preview: LinkPreview,
+    },
+    /// Response to `ClientFrame::Presence`.
+    Presence { users: Vec<UserPresence> },
+    /// Broadcast whenever a user's profile changes, to the connections
+    /// following them.
+    PresenceChanged(UserPresence),
+    /// Response to `ClientFrame::SubscribePresence` and
+    /// `UnsubscribePresence`: what the connection follows now, or `None`
+    /// for everyone, and the presence of those followed who are connected.
+    PresenceSubscribed {
+        subscription: Option<PresenceSubscription>,
+        users: Vec<UserPresence>,
+    },
+    /// Response to `ClientFrame::FileOffer`. The client may have at most
+    /// `window` chunks of up to `chunk_size` bytes unacknowledged at a time.
+    FileAccepted {
+        transfer_id: u64,
+        chunk_size: usize,
+        window: usize,
+    },
+    /// Acknowledges a chunk; `received` is the running byte total.
+    FileProgress { transfer_id: u64, received: u64 },
+    /// Sent to those who can read the room once an upload completes and is
+    /// stored.
+    FileShared {
+        from: String,
+        room: String,
+        file: FileRef,
+    },
+    /// One base64-encoded piece of a file requested with `FetchFile`. No
+    /// more chunks than an upload's `window` are sent ahead of the client's
+    /// `FileReceived`.
+    FileChunk {
+        id: String,
+        offset: u64,
+        data: String,
+    },
+    /// Ends a `FetchFile` stream.
+    FileEnd { id: String, size: u64 },
+    /// Broadcast when an ephemeral message's TTL elapses; clients should
+    /// remove it from view.
+    Expire { message_id: MessageId },
+    /// Response to `ClientFrame::FetchHistory`, oldest first. Pass the first
+    /// message's id as `before` to fetch the previous page while `has_more`.
+    History {
+        room: String,
+        messages: Vec<ChatMessage>,
+        has_more: bool,
+    },
+    /// Response to `ClientFrame::ListEmoji`, by name. Also sent to everyone
+    /// whenever an admin adds or removes one.
+    EmojiList { emoji: Vec<CustomEmoji> },
+    /// Response to `ClientFrame::Resume`: stored messages newer than
+    /// `last_id`, oldest first. When `complete` is false there are more;
+    /// resume again from the last one.
+    Replay {
+        messages: Vec<ChatMessage>,
+        complete: bool,
+    },
+    /// Response to `ClientFrame::Backfill`, oldest first. Requested sequence
+    /// numbers that are missing were deleted (expired or pruned). When
+    /// `complete` is false there are more; ask again from after the last one.
+    Backfill {
+        room: String,
+        messages: Vec<ChatMessage>,
+        complete: bool,
+    },
+    /// Sent after signing in or resuming: messages from at-least-once rooms
+    /// the user hasn't acknowledged yet, oldest first. Some may have been
+    /// received before; acknowledge them with `ClientFrame::Ack`.
+    Unacked { messages: Vec<ChatMessage> },
+    /// Response to `ClientFrame::Search`.
+    SearchResults {
+        hits: Vec<SearchHit>,
+        next_cursor: Option<MessageId>,
+    },
+    /// Confirms a message with a future `send_at` is being held; the id can
+    /// be passed to `CancelScheduled`.
+    Scheduled { pending_id: u64, send_at: u64 },
+    /// Confirms a `CancelScheduled`.
+    ScheduleCancelled { pending_id: u64 },
+    /// A message was rejected because its sender is over a quota; sends
+    /// are accepted again from `resets_at` (Unix seconds).
+    QuotaExceeded {
+        window: QuotaWindow,
+        resource: QuotaResource,
+        limit: u64,
+        resets_at: u64,
+    },
+    /// A message, room or member was rejected by the server's (or tenant's)
+    /// resource limits; `room` is set for per-room limits.
+    ResourceExceeded {
+        resource: Resource,
+        room: Option<String>,
+        limit: u64,
+    },
+    /// A message was rejected because `room` is an announcement room and
+    /// the sender isn't a moderator.
+    ReadOnlyRoom { room: String },
+    /// A join or invite was rejected because `room` has `max_members`
+    /// members. `waitlist_position` is set (1-based) if the sender was
+    /// queued instead; `WaitlistAdmitted` follows when a slot frees up.
+    RoomFull {
+        room: String,
+        max_members: usize,
+        waitlist_position: Option<usize>,
+    },
+    /// Response to `ClientFrame::CreateInvite`.
+    InviteCreated { invite: Invite },
+    /// Response to `ClientFrame::RedeemInvite`: the sender is now taking
+    /// part in `room`.
+    InviteRedeemed { room: String },
+    /// Broadcast when a temporary room expires; it's gone along with its
+    /// history.
+    RoomExpired { room: String },
+    /// Broadcast when `user` is admitted to `room` from its waitlist.
+    WaitlistAdmitted { room: String, user: String },
+    /// Broadcast when a room is created or `by` changes its settings.
+    /// `config.members` lists at most `room::MAX_LISTED_MEMBERS` of the
+    /// room's `member_count` members.
+    RoomUpdated {
+        room: String,
+        config: RoomConfig,
+        by: String,
+        #[serde(default)]
+        member_count: usize,
+    },
+    /// Broadcast instead of `RoomUpdated` when only the members of a room
+    /// with more than `room::MAX_LISTED_MEMBERS` changed.
+    MemberDelta {
+        room: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        added: Vec<String>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        removed: Vec<String>,
+        member_count: usize,
+    },
+    /// Response to `ClientFrame::ListRooms`; `total` counts the matching
+    /// rooms on every page.
+    Rooms {
+        rooms: Vec<RoomListing>,
+        page: usize,
+        total: usize,
+        has_more: bool,
+    },
+    /// Response to `ClientFrame::ListMembers`, in order by name. Pass the
+    /// last member as `after` to fetch the next page while `has_more`.
+    Members {
+        room: String,
+        members: Vec<String>,
+        member_count: usize,
+        has_more: bool,
+    },
+    /// Broadcast by the server's operators to everyone connected.
+    Notice { text: String },
+    /// A system announcement, to show apart from chat: to everyone, or to
+    /// a room's viewers when `room` is set. `repeats` counts identical
+    /// announcements coalesced into this one since it was last sent.
+    Announcement {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        text: String,
+        #[serde(default)]
+        level: AnnouncementLevel,
+        #[serde(default)]
+        repeats: u32,
+    },
+    /// The server's message of the day, sent on arrival, or a room's
+    /// when `room` is set, sent on joining it.
+    Motd {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        text: String,
+    },
+    /// Response to `ClientFrame::DebugInfo`.
+    DebugInfo(Box<ConnectionDebugInfo>),
+    /// A request from this client could not be served.
+    Error { message: String },
+}
+
+/// How urgently a server frame should reach a slow connection; each
+/// connection writes higher lanes first. Chat messages are always normal so
+/// a room's messages stay in sequence order.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Normal,
+    /// Changes made by room owners and moderators.
+    Moderator,
+    /// Replies about the connection itself, e.g. errors and sign-in.
+    System,
+}
+
+impl fmt::Display for Priority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Priority::Normal => "normal",
+            Priority::Moderator => "moderator",
+            Priority::System => "system",
+        })
+    }
+}
+
+/// An optional part of the protocol, which a client can leave out of
+/// `ClientFrame::SetCapabilities` if it doesn't handle it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Capability {
+    /// `ChatMessage::format` and `ChatMessage::entities`; without it they
+    /// are dropped and the content is sent as plain text.
+    Formatting,
+    /// `ChatMessage::forwarded`; without it the origin is written at the
+    /// start of the content.
+    Forwarding,
+    /// `ChatMessage::attachment`; without it the file is described at the
+    /// start of the content.
+    Attachments,
+    /// `ServerFrame::LinkPreview`.
+    LinkPreviews,
+    /// Server frames as length-prefixed MessagePack, starting with the
+    /// first after `ServerFrame::CapabilitiesSelected`; see
+    /// `codec::WireFormat`. Unlike the others, a client only has it if it
+    /// asks for it.
+    MessagePack,
+    /// One this server doesn't know of.
+    #[serde(other)]
+    Unknown,
+}
+
+impl Capability {
+    /// Every capability a client that hasn't sent
+    /// `ClientFrame::SetCapabilities` has.
+    pub const ALL: [Capability; 4] = [
+        Capability::Formatting,
+        Capability::Forwarding,
+        Capability::Attachments,
+        Capability::LinkPreviews,
+    ];
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::Formatting => "formatting",
+            Capability::Forwarding => "forwarding",
+            Capability::Attachments => "attachments",
+            Capability::LinkPreviews => "link_previews",
+            Capability::MessagePack => "message_pack",
+            Capability::Unknown => "unknown",
+        })
+    }
+}
+
+impl ServerFrame {
+    /// Returns the lane this frame is written in.
+    pub fn priority(&self) -> Priority {
+        match self {
+            ServerFrame::RoomUpdated { .. }
+            | ServerFrame::MemberDelta { .. }
+            | ServerFrame::RoomExpired { .. }
+            | ServerFrame::WaitlistAdmitted { .. }
+            | ServerFrame::Notice { .. } => Priority::Moderator,
+            ServerFrame::Announcement { level, .. } => match level {
+                AnnouncementLevel::Critical => Priority::System,
+                _ => Priority::Moderator,
+            },
+            ServerFrame::Challenge { .. }
+            | ServerFrame::ChallengeAccepted
+            | ServerFrame::Welcome { .. }
+            | ServerFrame::Authenticated { .. }
+            | ServerFrame::LocaleSelected { .. }
+            | ServerFrame::CapabilitiesSelected { .. }
+            | ServerFrame::DebugInfo(_)
+            | ServerFrame::NotificationPrefs { .. }
+            | ServerFrame::NicknameConflict { .. }
+            | ServerFrame::SessionResumed { .. }
+            | ServerFrame::QuotaExceeded { .. }
+            | ServerFrame::ResourceExceeded { .. }
+            | ServerFrame::ReadOnlyRoom { .. }
+            | ServerFrame::RoomFull { .. }
+            | ServerFrame::Error { .. } => Priority::System,
+            _ => Priority::Normal,
+        }
+    }
+
+    /// The capabilities a client needs to be sent this frame as it is.
+    pub fn capabilities(&self) -> BTreeSet<Capability> {
+        match self {
+            ServerFrame::LinkPreview { .. } => BTreeSet::from([Capability::LinkPreviews]),
+            _ => self
+                .messages()
+                .into_iter()
+                .flat_map(ChatMessage::capabilities)
+                .collect(),
+        }
+    }
+
+    /// Rewrites the frame for a client with only `capabilities`: frames it
+    /// can't handle at all become a `Notice` describing them, and messages
+    /// lose what it can't render.
+    pub fn downgrade(mut self, capabilities: &BTreeSet<Capability>) -> ServerFrame {
+        let needed = self.capabilities();
+        if needed.is_subset(capabilities) {
+            return self;
+        }
+        if matches!(self, ServerFrame::LinkPreview { .. }) {
+            return ServerFrame::Notice {
+                text: self.to_string(),
+            };
+        }
+        for message in self.messages_mut() {
+            message.downgrade(capabilities);
+        }
+        self
+    }
+
+    fn messages(&self) -> Vec<&ChatMessage> {
+        match self {
+            ServerFrame::Message { message, .. } => vec![message],
+            ServerFrame::History { messages, .. }
+            | ServerFrame::Replay { messages, .. }
+            | ServerFrame::Backfill { messages, .. }
+            | ServerFrame::Unacked { messages } => messages.iter().collect(),
+            ServerFrame::SearchResults { hits, .. } => {
+                hits.iter().map(|hit| &hit.message).collect()
+            }
+            ServerFrame::Saved { messages } => {
+                messages.iter().map(|saved| &saved.message).collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn messages_mut(&mut self) -> Vec<&mut ChatMessage> {
+        match self {
+            ServerFrame::Message { message, .. } => vec![message],
+            ServerFrame::History { messages, .. }
+            | ServerFrame::Replay { messages, .. }
+            | ServerFrame::Backfill { messages, .. }
+            | ServerFrame::Unacked { messages } => messages.iter_mut().collect(),
+            ServerFrame::SearchResults { hits, .. } => {
+                hits.iter_mut().map(|hit| &mut hit.message).collect()
+            }
+            ServerFrame::Saved { messages } => messages
+                .iter_mut()
+                .map(|saved| &mut saved.message)
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Serializes the frame to JSON
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+impl fmt::Display for ChatMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.room(), self.sender, self.content)
+    }
+}
+
+impl fmt::Display for AnnouncementLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnnouncementLevel::Info => "announcement",
+            AnnouncementLevel::Warning => "warning",
+            AnnouncementLevel::Critical => "critical",
+        })
+    }
+}
+
+impl fmt::Display for PresenceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PresenceState::Online => "online",
+            PresenceState::Away => "away",
+            PresenceState::Dnd => "do not disturb",
+        })
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.state)?;
+        if let Some(status_text) = &self.status_text {
+            write!(f, ": {}", status_text)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for UserPresence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.profile.display_name.as_deref().unwrap_or(&self.user);
+        write!(f, "{} ({})", name, self.profile)?;
+        if self.devices > 1 {
+            write!(f, " on {} devices", self.devices)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FileRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} bytes)", self.name, self.size)
+    }
+}
+
+impl fmt::Display for SearchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {}: {}",
+            self.message.room(),
+            self.message.sender,
+            self.snippet
+        )
+    }
+}
+
+impl fmt::Display for ClientFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientFrame::SetProfile { .. } => write!(f, "profile update"),
+            ClientFrame::Presence => write!(f, "presence request"),
+            ClientFrame::CreateInvite { room, .. } => write!(f, "invite to {}", room),
+            ClientFrame::RedeemInvite { .. } => write!(f, "invite redemption"),
+            ClientFrame::Forward {
+                message_id,
+                to_room,
+            } => write!(f, "forward of {} to {}", message_id, to_room),
+            ClientFrame::ListRooms { filter, page } => match filter {
+                Some(filter) => write!(f, "room directory page {} for {:?}", page, filter),
+                None => write!(f, "room directory page {}", page),
+            },
+            ClientFrame::ListMembers { room, .. } => write!(f, "member list of {}", room),
+            ClientFrame::SubscribePresence { users, rooms } => write!(
+                f,
+                "presence subscription to {} users and {} rooms",
+                users.len(),
+                rooms.len()
+            ),
+            ClientFrame::UnsubscribePresence { users, rooms } => write!(
+                f,
+                "presence unsubscription from {} users and {} rooms",
+                users.len(),
+                rooms.len()
+            ),
+            ClientFrame::FileOffer { name, size, .. } => {
+                write!(f, "offer of {} ({} bytes)", name, size)
+            }
+            ClientFrame::FileChunk { transfer_id, data } => {
+                write!(
+                    f,
+                    "chunk of transfer {} ({} bytes encoded)",
+                    transfer_id,
+                    data.len()
+                )
+            }
+            ClientFrame::FetchFile { id } => write!(f, "fetch of file {}", id),
+            ClientFrame::FileReceived { id, received } => {
+                write!(f, "receipt of {} bytes of file {}", received, id)
+            }
+            ClientFrame::FetchHistory { room, .. } => write!(
+                f,
+                "history request for {}",
+                room.as_deref().unwrap_or(DEFAULT_ROOM)
+            ),
+            ClientFrame::Resume { last_id } => write!(f, "resume after message {}", last_id),
+            ClientFrame::Search { query, .. } => write!(f, "search for {:?}", query),
+            ClientFrame::CancelScheduled { pending_id } => {
+                write!(f, "cancel of scheduled message {}", pending_id)
+            }
+            ClientFrame::Authenticate { user, .. } => write!(f, "authentication as {}", user),
+            ClientFrame::ApiKey { .. } => write!(f, "API key sign-in"),
+            ClientFrame::ChallengeResponse { .. } => write!(f, "challenge response"),
+            ClientFrame::SelectTenant { tenant } => write!(f, "selection of tenant {}", tenant),
+            ClientFrame::SetLocale { locales } => write!(f, "locale request {:?}", locales),
+            ClientFrame::SetCapabilities { capabilities } => {
+                write!(f, "capabilities {:?}", capabilities)
+            }
+            ClientFrame::SetNotificationPrefs { .. } => {
+                write!(f, "notification preferences update")
+            }
+            ClientFrame::SetDraft { room, .. } => write!(f, "draft in {}", room),
+            ClientFrame::ClearDraft { room } => write!(f, "draft cleared in {}", room),
+            ClientFrame::GetDrafts => write!(f, "drafts request"),
+            ClientFrame::SaveMessage { message_id } => write!(f, "save of {}", message_id),
+            ClientFrame::UnsaveMessage { message_id } => write!(f, "unsave of {}", message_id),
+            ClientFrame::ListSaved => write!(f, "saved messages request"),
+            ClientFrame::RegisterPushToken { push } => {
+                write!(f, "{} push token registration", push.platform)
+            }
+            ClientFrame::UnregisterPushToken { .. } => write!(f, "push token removal"),
+            ClientFrame::ResumeSession { .. } => write!(f, "session resumption"),
+            ClientFrame::Backfill {
+                room,
+                from_seq,
+                to_seq,
+            } => write!(f, "backfill of {} {}..={}", room, from_seq, to_seq),
+            ClientFrame::Ack { room, seq } => write!(f, "ack of {} up to {}", room, seq),
+            ClientFrame::Heartbeat => write!(f, "heartbeat"),
+            ClientFrame::ListEmoji => write!(f, "emoji list request"),
+            ClientFrame::DebugInfo => write!(f, "debug info request"),
+        }
+    }
+}
+
+impl fmt::Display for ServerFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerFrame::Challenge { difficulty, .. } => {
+                write!(f, "challenge of difficulty {}", difficulty)
+            }
+            ServerFrame::ChallengeAccepted => write!(f, "challenge accepted"),
+            ServerFrame::Welcome { user, guest, .. } => {
+                write!(
+                    f,
+                    "welcome, {}{}",
+                    user,
+                    if *guest { " (guest)" } else { "" }
+                )
+            }
+            ServerFrame::Authenticated { user, .. } => write!(f, "authenticated as {}", user),
+            ServerFrame::LocaleSelected { locale } => write!(f, "locale {}", locale),
+            ServerFrame::CapabilitiesSelected { capabilities } => {
+                write!(f, "{} capabilities", capabilities.len())
+            }
+            ServerFrame::NotificationPrefs { .. } => write!(f, "notification preferences"),
+            ServerFrame::Draft { room, draft } => match draft {
+                Some(_) => write!(f, "draft saved in {}", room),
+                None => write!(f, "draft cleared in {}", room),
+            },
+            ServerFrame::Drafts { drafts } => write!(f, "{} drafts", drafts.len()),
+            ServerFrame::MessageSaved { message_id } => write!(f, "message {} saved", message_id),
+            ServerFrame::MessageUnsaved { message_id } => {
+                write!(f, "message {} unsaved", message_id)
+            }
+            ServerFrame::Saved { messages } => write!(f, "{} saved messages", messages.len()),
+            ServerFrame::NicknameConflict {
+                nick,
+                conflicts_with,
+            } => write!(
+                f,
+                "nickname {} looks too much like {}",
+                nick, conflicts_with
+            ),
+            ServerFrame::SessionResumed { user, .. } => write!(f, "resumed session as {}", user),
+            ServerFrame::Message { message, .. } => write!(f, "{}", message),
+            ServerFrame::EmojiList { emoji } => write!(f, "{} custom emoji", emoji.len()),
+            ServerFrame::LinkPreview { preview, .. } => write!(
+                f,
+                "{}: {}",
+                preview.url,
+                preview
+                    .title
+                    .as_deref()
+                    .or(preview.description.as_deref())
+                    .unwrap_or_default()
+            ),
+            ServerFrame::Presence { users } => {
+                write!(f, "{} online", users.len())?;
+                for (i, user) in users.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { ":" } else { "," }, user)?;
+                }
+                Ok(())
+            }
+            ServerFrame::PresenceChanged(presence) => write!(f, "{}", presence),
+            ServerFrame::PresenceSubscribed {
+                subscription: Some(subscription),
+                users,
+            } => write!(
+                f,
+                "following {} users and {} rooms, {} online",
+                subscription.users.len(),
+                subscription.rooms.len(),
+                users.len()
+            ),
+            ServerFrame::PresenceSubscribed {
+                subscription: None,
+                users,
+            } => write!(f, "following everyone, {} online", users.len()),
+            ServerFrame::FileAccepted { transfer_id, .. } => {
+                write!(f, "transfer {} accepted", transfer_id)
+            }
+            ServerFrame::FileProgress {
+                transfer_id,
+                received,
+            } => write!(f, "transfer {}: {} bytes received", transfer_id, received),
+            ServerFrame::FileShared { from, room, file } => {
+                write!(f, "{} shared {} in {}", from, file, room)
+            }
+            ServerFrame::FileChunk { id, offset, .. } => {
+                write!(f, "file {} chunk at offset {}", id, offset)
+            }
+            ServerFrame::FileEnd { id, size } => write!(f, "file {} complete ({} bytes)", id, size),
+            ServerFrame::Expire { message_id } => write!(f, "message {} expired", message_id),
+            ServerFrame::History {
+                room,
+                messages,
+                has_more,
+            } => write!(
+                f,
+                "{} messages from {}{}",
+                messages.len(),
+                room,
+                if *has_more { ", more available" } else { "" }
+            ),
+            ServerFrame::Replay { messages, complete } => write!(
+                f,
+                "{} missed messages{}",
+                messages.len(),
+                if *complete { "" } else { ", more to resume" }
+            ),
+            ServerFrame::Backfill {
+                room,
+                messages,
+                complete,
+            } => write!(
+                f,
+                "{} backfilled messages from {}{}",
+                messages.len(),
+                room,
+                if *complete { "" } else { ", more available" }
+            ),
+            ServerFrame::Unacked { messages } => {
+                write!(f, "{} unacknowledged messages", messages.len())
+            }
+            ServerFrame::SearchResults { hits, next_cursor } => write!(
+                f,
+                "{} search results{}",
+                hits.len(),
+                if next_cursor.is_some() {
+                    ", more available"
+                } else {
+                    ""
+                }
+            ),
+            ServerFrame::Scheduled {
+                pending_id,
+                send_at,
+            } => write!(f, "message {} scheduled for {}", pending_id, send_at),
+            ServerFrame::ScheduleCancelled { pending_id } => {
+                write!(f, "scheduled message {} cancelled", pending_id)
+            }
+            ServerFrame::QuotaExceeded {
+                window,
+                resource,
+                limit,
+                resets_at,
+            } => write!(
+                f,
+                "{} {} quota of {} reached, resets at {}",
+                window, resource, limit, resets_at
+            ),
+            ServerFrame::ResourceExceeded {
+                resource,
+                room,
+                limit,
+            } => {
+                write!(f, "{} limit of {} reached", resource, limit)?;
+                if let Some(room) = room {
+                    write!(f, " in {}", room)?;
+                }
+                Ok(())
+            }
+            ServerFrame::ReadOnlyRoom { room } => write!(f, "{} is read-only", room),
+            ServerFrame::RoomFull {
+                room,
+                max_members,
+                waitlist_position,
+            } => {
+                write!(f, "{} is full ({} members)", room, max_members)?;
+                if let Some(position) = waitlist_position {
+                    write!(f, ", waitlist position {}", position)?;
+                }
+                Ok(())
+            }
+            ServerFrame::InviteCreated { invite } => write!(f, "invite to {}", invite.room),
+            ServerFrame::InviteRedeemed { room } => write!(f, "joined {} by invite", room),
+            ServerFrame::RoomExpired { room } => write!(f, "{} expired", room),
+            ServerFrame::WaitlistAdmitted { room, user } => {
+                write!(f, "{} admitted to {} from the waitlist", user, room)
+            }
+            ServerFrame::RoomUpdated { room, by, .. } => write!(f, "{} updated {}", by, room),
+            ServerFrame::MemberDelta {
+                room,
+                added,
+                removed,
+                member_count,
+            } => write!(
+                f,
+                "{} joined and {} left {}, now {} members",
+                added.len(),
+                removed.len(),
+                room,
+                member_count
+            ),
+            ServerFrame::Rooms { rooms, total, .. } => {
+                write!(f, "{} of {} rooms", rooms.len(), total)?;
+                for (i, listing) in rooms.iter().enumerate() {
+                    write!(f, "{} {}", if i == 0 { ":" } else { "," }, listing.room)?;
+                }
+                Ok(())
+            }
+            ServerFrame::Members {
+                room,
+                members,
+                member_count,
+                ..
+            } => write!(
+                f,
+                "{} of {} members of {}",
+                members.len(),
+                member_count,
+                room
+            ),
+            ServerFrame::Notice { text } => write!(f, "notice: {}", text),
+            ServerFrame::Announcement {
+                room,
+                text,
+                level,
+                repeats,
+            } => {
+                write!(f, "{}", level)?;
+                if let Some(room) = room {
+                    write!(f, " in {}", room)?;
+                }
+                write!(f, ": {}", text)?;
+                if *repeats > 0 {
+                    write!(f, " (repeated {} more times)", repeats)?;
+                }
+                Ok(())
+            }
+            ServerFrame::Motd { room: None, text } => write!(f, "motd: {}", text),
+            ServerFrame::Motd {
+                room: Some(room),
+                text,
+            } => write!(f, "motd for {}: {}", room, text),
+            ServerFrame::DebugInfo(info) => write!(
+                f,
+                "debug info for {}: {} frames queued",
+                info.addr, info.queued_frames
+            ),
+            ServerFrame::Error { message } => write!(f, "error: {}", message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_validation() {
+        let message = ChatMessage::from_raw("avery: hi\u{7}\nthere").unwrap();
+        assert_eq!(message.content, "hi\nthere");
+
+        assert_eq!(
+            ChatMessage::from_raw("no separator").unwrap_err(),
+            ValidationError::InvalidFormat
+        );
+        assert_eq!(
+            ChatMessage::from_raw(" : hi").unwrap_err(),
+            ValidationError::EmptySender
+        );
+        assert_eq!(
+            ChatMessage::from_raw("a b: hi").unwrap_err(),
+            ValidationError::InvalidSenderChar(' ')
+        );
+        assert_eq!(message.to_string(), "[general] avery: hi\nthere");
+
+        let long_sender = "a".repeat(MAX_SENDER_LEN + 1);
+        assert!(matches!(
+            ChatMessage::builder().sender(long_sender).build(),
+            Err(ValidationError::SenderTooLong { .. })
+        ));
+        let long_content = "x".repeat(MAX_CONTENT_LEN + 1);
+        assert!(matches!(
+            ChatMessage::builder()
+                .sender("avery")
+                .content(long_content)
+                .build(),
+            Err(ValidationError::ContentTooLong { .. })
+        ));
+
+        let in_room = |room: String| ChatMessage::builder().sender("avery").room(room).build();
+        assert_eq!(
+            in_room("\u{1b}[2Jops".to_string()).unwrap_err(),
+            ValidationError::InvalidRoomNameChar('\u{1b}')
+        );
+        assert_eq!(
+            in_room("r".repeat(MAX_ROOM_NAME_LEN + 1)).unwrap_err(),
+            ValidationError::RoomNameTooLong {
+                max: MAX_ROOM_NAME_LEN
+            }
+        );
+        assert_eq!(
+            in_room(String::new()).unwrap_err(),
+            ValidationError::EmptyRoomName
+        );
+        assert!(in_room("release-2.0".to_string()).is_ok());
+    }
+
+    #[test]
+    fn test_entities_are_checked() {
+        let link = || EntityKind::Link {
+            url: "https://example.com".to_string(),
+        };
+        let builder = || {
+            ChatMessage::builder()
+                .sender("avery")
+                .content("see **the docs** for caf\u{e9}")
+                .format(TextFormat::Markdown)
+        };
+        let message = builder()
+            .entity(4, 12, EntityKind::Bold)
+            .entity(6, 8, link())
+            .build()
+            .unwrap();
+        assert_eq!(message.entities[1].text(&message.content), Some("the docs"));
+
+        let check = |builder: ChatMessageBuilder| builder.build().unwrap_err();
+        assert_eq!(
+            check(builder().entity(24, 4, EntityKind::Code)),
+            ValidationError::EntityOutOfRange { index: 0 }
+        );
+        // Splits the two bytes of "\u{e9}".
+        assert_eq!(
+            check(builder().entity(25, 1, EntityKind::Italic)),
+            ValidationError::EntityOutOfRange { index: 0 }
+        );
+        assert_eq!(
+            check(builder().entity(4, 0, EntityKind::Italic)),
+            ValidationError::EntityOutOfRange { index: 0 }
+        );
+        assert_eq!(
+            check(
+                builder()
+                    .entity(4, 12, EntityKind::Bold)
+                    .entity(10, 10, EntityKind::Italic)
+            ),
+            ValidationError::EntitiesCross { index: 1 }
+        );
+        let script = EntityKind::Link {
+            url: "javascript:alert(1)".to_string(),
+        };
+        assert_eq!(
+            check(builder().entity(6, 8, script)),
+            ValidationError::UnsafeLink { index: 0 }
+        );
+
+        // Stripping an escape sequence shifts the text, so entities go.
+        let message = ChatMessage::builder()
+            .sender("avery")
+            .content("\u{1b}[1mbold")
+            .entity(4, 4, EntityKind::Bold)
+            .build()
+            .unwrap();
+        assert_eq!(message.content, "bold");
+        assert!(message.entities.is_empty());
+    }
+
+    #[test]
+    fn test_notification_prefs() {
+        // 23:30 UTC, which is 18:30 at UTC-5.
+        let late = 19_723 * 86_400 + 23 * 3600 + 30 * 60;
+        let overnight = QuietHours {
+            start: 22 * 60,
+            end: 7 * 60,
+            utc_offset: 0,
+        };
+        assert!(overnight.contains(late));
+        assert!(!overnight.contains(late + 8 * 3600));
+        let eastern = QuietHours {
+            utc_offset: -300,
+            ..overnight
+        };
+        assert!(!eastern.contains(late));
+
+        let mut prefs = NotificationPrefs {
+            quiet_hours: Some(overnight),
+            muted_rooms: ["random".to_string()].into(),
+            ..Default::default()
+        };
+        assert!(prefs.notifies(Some("general"), false, late + 8 * 3600));
+        assert!(!prefs.notifies(Some("random"), true, late + 8 * 3600));
+        assert!(!prefs.notifies(None, false, late));
+        prefs.mentions_break_quiet_hours = true;
+        assert!(prefs.notifies(None, false, late));
+        assert!(prefs.notifies(Some("general"), true, late));
+        assert!(!prefs.notifies(Some("general"), false, late));
+        prefs.mention_only = true;
+        assert!(!prefs.notifies(Some("general"), false, late + 8 * 3600));
+
+        prefs.quiet_hours = Some(QuietHours {
+            start: 24 * 60,
+            ..overnight
+        });
+        assert!(prefs.validate().is_err());
+    }
+
+    #[test]
+    fn test_mentions() {
+        let message = |content: &str| {
+            ChatMessage::builder()
+                .sender("blake")
+                .content(content)
+                .build()
+                .unwrap()
+        };
+        assert!(message("hey @avery, look").mentions("avery"));
+        assert!(!message("hey @averyb").mentions("avery"));
+        assert!(!message("hey avery").mentions("avery"));
+        let mut tagged = message("hey A.");
+        tagged.entities.push(Entity {
+            offset: 4,
+            length: 2,
+            kind: EntityKind::Mention {
+                user: "avery".to_string(),
+            },
+        });
+        assert!(tagged.mentions("avery"));
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let mut message = ChatMessage::builder()
+            .sender("blake")
+            .content("see **this**")
+            .build()
+            .unwrap();
+        message.format = TextFormat::Markdown;
+        message.entities.push(Entity {
+            offset: 4,
+            length: 8,
+            kind: EntityKind::Bold,
+        });
+        message.forwarded = Some(Forwarded {
+            message_id: 1,
+            room: "lobby".to_string(),
+            sender: "avery".to_string(),
+            timestamp: None,
+        });
+        let frame = ServerFrame::Message {
+            from: "server".to_string(),
+            message,
+        };
+        let all = BTreeSet::from(Capability::ALL);
+        assert_eq!(frame.capabilities().len(), 2);
+        assert_eq!(frame.clone().downgrade(&all), frame);
+
+        let ServerFrame::Message { message, .. } =
+            frame.downgrade(&BTreeSet::from([Capability::Formatting]))
+        else {
+            panic!("expected a message");
+        };
+        assert_eq!(
+            message.content,
+            "[Forwarded from avery in lobby] see **this**"
+        );
+        assert_eq!(message.entities[0].text(&message.content), Some("**this**"));
+        assert_eq!(message.forwarded, None);
+        let ServerFrame::Message { message, .. } = ServerFrame::Message {
+            from: "server".to_string(),
+            message,
+        }
+        .downgrade(&BTreeSet::new()) else {
+            panic!("expected a message");
+        };
+        assert!(message.format.is_plain() && message.entities.is_empty());
+
+        let mut attached = ChatMessage::builder()
+            .sender("blake")
+            .content("see **notes**")
+            .attachment(FileRef {
+                id: "abc".to_string(),
+                name: "notes.txt".to_string(),
+                size: 3,
+            })
+            .entity(4, 9, EntityKind::Bold)
+            .build()
+            .unwrap();
+        attached.downgrade(&BTreeSet::from([Capability::Formatting]));
+        assert_eq!(attached.attachment, None);
+        assert_eq!(
+            attached.content,
+            "[Attachment: notes.txt (3 bytes)] see **notes**"
+        );
+        assert_eq!(
+            attached.entities[0].text(&attached.content),
+            Some("**notes**")
+        );
+        let capabilities: BTreeSet<Capability> =
+            serde_json::from_str(r#"["formatting", "reactions"]"#).unwrap();
+        assert!(capabilities.contains(&Capability::Unknown));
+    }
 }
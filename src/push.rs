@@ -0,0 +1,247 @@
+use crate::onboarding::post_json;
+use crate::protocol::{ChatMessage, MessageId, NotificationPrefs, PushPlatform, PushToken};
+use anyhow::{Result, anyhow};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// Longest notification body, in characters; longer messages are cut.
+const MAX_BODY_LEN: usize = 200;
+/// Longest push token accepted from a client, in bytes.
+pub const MAX_TOKEN_LEN: usize = 4096;
+/// Push tokens kept per user; registering another drops the oldest.
+pub const MAX_TOKENS_PER_USER: usize = 16;
+
+/// What a push notification says about a message.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PushNotification {
+    /// The user being notified.
+    pub user: String,
+    /// Who sent the message, and where: "avery in #lobby", or just
+    /// "avery" for a direct message.
+    pub title: String,
+    pub body: String,
+    /// The room, or `None` for a direct message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<MessageId>,
+}
+
+impl PushNotification {
+    /// A notification to `user` about `message`, sent in `room` or
+    /// directly.
+    pub fn new(user: &str, message: &ChatMessage, room: Option<&str>) -> Self {
+        let title = match room {
+            Some(room) => format!("{} in #{}", message.sender, room),
+            None => message.sender.clone(),
+        };
+        let mut body: String = message.content.chars().take(MAX_BODY_LEN).collect();
+        if body.len() < message.content.len() {
+            body.push('\u{2026}');
+        }
+        PushNotification {
+            user: user.to_string(),
+            title,
+            body,
+            room: room.map(str::to_string),
+            message_id: message.id,
+        }
+    }
+}
+
+/// What became of a notification handed to a `PushProvider`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Sent,
+    /// The service no longer knows the token (the app was uninstalled, or
+    /// the subscription expired), so it's forgotten.
+    Unregistered,
+}
+
+/// Sends notifications through one push service, such as FCM, APNs or
+/// Web Push. Implementations hold the service's credentials.
+#[async_trait]
+pub trait PushProvider: Send + Sync {
+    async fn send(&self, token: &str, notification: &PushNotification) -> Result<Delivery>;
+}
+
+/// Forwards notifications to a push relay: each is posted as JSON, with
+/// the platform and token, to a plain `http://` URL. The relay, usually a
+/// sidecar holding the FCM, APNs and VAPID credentials, answers 2xx once
+/// it has the notification, or 404 or 410 for a token the service
+/// rejected.
+pub struct RelayProvider {
+    url: String,
+    platform: PushPlatform,
+}
+
+impl RelayProvider {
+    pub fn new(url: impl Into<String>, platform: PushPlatform) -> Self {
+        RelayProvider {
+            url: url.into(),
+            platform,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RelayRequest<'a> {
+    platform: PushPlatform,
+    token: &'a str,
+    notification: &'a PushNotification,
+}
+
+#[async_trait]
+impl PushProvider for RelayProvider {
+    async fn send(&self, token: &str, notification: &PushNotification) -> Result<Delivery> {
+        let request = RelayRequest {
+            platform: self.platform,
+            token,
+            notification,
+        };
+        match post_json(&self.url, &request).await? {
+            200..=299 => Ok(Delivery::Sent),
+            404 | 410 => Ok(Delivery::Unregistered),
+            status => Err(anyhow!("Push relay {} answered {}", self.url, status)),
+        }
+    }
+}
+
+/// Pushes notifications to users' devices when they're offline, or when
+/// a mention gets through their do-not-disturb, through a provider per
+/// platform.
+#[derive(Clone, Default)]
+pub struct PushGateway {
+    providers: HashMap<PushPlatform, Arc<dyn PushProvider>>,
+}
+
+impl PushGateway {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends notifications for devices on `platform` through `provider`.
+    pub fn with_provider(
+        mut self,
+        platform: PushPlatform,
+        provider: Arc<dyn PushProvider>,
+    ) -> Self {
+        self.providers.insert(platform, provider);
+        self
+    }
+
+    /// Whether devices on `platform` can be pushed to.
+    pub fn supports(&self, platform: PushPlatform) -> bool {
+        self.providers.contains_key(&platform)
+    }
+
+    /// Sends `notification` to each of `tokens`, returning the tokens
+    /// their services no longer know. Failures are logged and skipped.
+    pub async fn send(&self, tokens: &[PushToken], notification: &PushNotification) -> Vec<String> {
+        let mut unregistered = Vec::new();
+        for push in tokens {
+            let Some(provider) = self.providers.get(&push.platform) else {
+                continue;
+            };
+            match provider.send(&push.token, notification).await {
+                Ok(Delivery::Sent) => {
+                    debug!("Pushed to {} via {}", notification.user, push.platform)
+                }
+                Ok(Delivery::Unregistered) => unregistered.push(push.token.clone()),
+                Err(e) => warn!(
+                    "Failed to push to {} via {}: {}",
+                    notification.user, push.platform, e
+                ),
+            }
+        }
+        unregistered
+    }
+}
+
+/// Whether a message should be pushed to a user with `prefs`: when it
+/// would notify and they have no connection to show it on, or when it
+/// addresses them (a mention, or a direct message if `room` is `None`)
+/// while they're in do-not-disturb, either by presence or by quiet hours,
+/// and they let mentions through.
+pub fn should_push(
+    prefs: &NotificationPrefs,
+    online: bool,
+    do_not_disturb: bool,
+    room: Option<&str>,
+    mentioned: bool,
+    now: u64,
+) -> bool {
+    if !prefs.notifies(room, mentioned, now) {
+        return false;
+    }
+    if !online {
+        return true;
+    }
+    let quiet = do_not_disturb || prefs.quiet_hours.is_some_and(|hours| hours.contains(now));
+    quiet && (room.is_none() || mentioned) && prefs.mentions_break_quiet_hours
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::QuietHours;
+
+    #[test]
+    fn test_should_push() {
+        let noon = 19_723 * 86_400 + 12 * 3600;
+        let prefs = NotificationPrefs::default();
+        assert!(should_push(
+            &prefs,
+            false,
+            false,
+            Some("lobby"),
+            false,
+            noon
+        ));
+        assert!(!should_push(&prefs, true, false, Some("lobby"), true, noon));
+        // Online but in do-not-disturb: only mentions that are let through.
+        assert!(!should_push(&prefs, true, true, Some("lobby"), true, noon));
+        let prefs = NotificationPrefs {
+            quiet_hours: Some(QuietHours {
+                start: 11 * 60,
+                end: 13 * 60,
+                utc_offset: 0,
+            }),
+            mentions_break_quiet_hours: true,
+            ..Default::default()
+        };
+        assert!(should_push(&prefs, true, false, Some("lobby"), true, noon));
+        assert!(should_push(&prefs, true, false, None, false, noon));
+        assert!(!should_push(
+            &prefs,
+            true,
+            false,
+            Some("lobby"),
+            false,
+            noon
+        ));
+        assert!(!should_push(
+            &prefs,
+            false,
+            false,
+            Some("lobby"),
+            false,
+            noon
+        ));
+    }
+
+    #[test]
+    fn test_long_messages_are_cut() {
+        let message = ChatMessage::builder()
+            .sender("avery")
+            .content("x".repeat(300))
+            .build()
+            .unwrap();
+        let notification = PushNotification::new("blake", &message, Some("lobby"));
+        assert_eq!(notification.title, "avery in #lobby");
+        assert_eq!(notification.body.chars().count(), MAX_BODY_LEN + 1);
+    }
+}
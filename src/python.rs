@@ -0,0 +1,147 @@
+//! Python bindings, built as an extension module with `maturin` and the
+//! `python` feature. Client methods return asyncio awaitables:
+//!
+//! ```python
+//! from tokio_chat_server import ChatMessage, Client
+//!
+//! client = await Client.connect("127.0.0.1:8080")
+//! await client.send(ChatMessage("bot", "hello"))
+//! print(await client.receive())
+//! ```
+use crate::client;
+use crate::protocol::{self, MessageId};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// Python view of `protocol::ChatMessage`.
+#[pyclass(name = "ChatMessage", module = "tokio_chat_server")]
+#[derive(Clone)]
+pub struct PyChatMessage {
+    inner: protocol::ChatMessage,
+}
+
+#[pymethods]
+impl PyChatMessage {
+    #[new]
+    #[pyo3(signature = (sender, content, room = None))]
+    fn new(sender: String, content: String, room: Option<String>) -> Self {
+        PyChatMessage {
+            inner: protocol::ChatMessage {
+                sender,
+                content,
+                room,
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Parses a JSON message or a `"sender:content"` line.
+    #[staticmethod]
+    fn from_raw(raw: &str) -> PyResult<Self> {
+        Ok(PyChatMessage {
+            inner: protocol::ChatMessage::from_raw(raw)
+                .map_err(|e| PyValueError::new_err(e.to_string()))?,
+        })
+    }
+
+    #[getter]
+    fn sender(&self) -> &str {
+        &self.inner.sender
+    }
+
+    #[getter]
+    fn content(&self) -> &str {
+        &self.inner.content
+    }
+
+    #[getter]
+    fn room(&self) -> &str {
+        self.inner.room()
+    }
+
+    #[getter]
+    fn id(&self) -> Option<MessageId> {
+        self.inner.id
+    }
+
+    #[getter]
+    fn timestamp(&self) -> Option<u64> {
+        self.inner.timestamp
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        Ok(self.inner.to_json()?)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "ChatMessage(sender={:?}, content={:?}, room={:?})",
+            self.inner.sender,
+            self.inner.content,
+            self.inner.room()
+        )
+    }
+}
+
+/// Python wrapper around `client::Client`, driven by the shared tokio
+/// runtime of `pyo3-async-runtimes`.
+#[pyclass(name = "Client", module = "tokio_chat_server")]
+pub struct PyClient {
+    inner: Arc<Mutex<client::Client>>,
+}
+
+#[pymethods]
+impl PyClient {
+    /// Connects to the server at `addr`; resolves to a `Client`.
+    #[staticmethod]
+    fn connect(py: Python<'_>, addr: String) -> PyResult<Bound<'_, PyAny>> {
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let inner = client::Client::connect(&addr).await?;
+            Ok(PyClient {
+                inner: Arc::new(Mutex::new(inner)),
+            })
+        })
+    }
+
+    fn send<'py>(&self, py: Python<'py>, message: PyChatMessage) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            Ok(inner.lock().await.send(message.inner).await?)
+        })
+    }
+
+    /// Resolves to the next frame from the server, as a JSON string.
+    fn receive<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let frame = inner.lock().await.receive().await?;
+            Ok(frame.to_json()?)
+        })
+    }
+
+    /// Resolves to the next control frame, skipping chat messages, as a
+    /// JSON string.
+    fn receive_frame<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            let frame = inner.lock().await.receive_frame().await?;
+            Ok(frame.to_json()?)
+        })
+    }
+
+    fn reconnect<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let inner = self.inner.clone();
+        pyo3_async_runtimes::tokio::future_into_py(py, async move {
+            Ok(inner.lock().await.reconnect().await?)
+        })
+    }
+}
+
+#[pymodule]
+fn tokio_chat_server(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyChatMessage>()?;
+    m.add_class::<PyClient>()?;
+    Ok(())
+}
@@ -0,0 +1,554 @@
+use crate::registry::Registry;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+const HOUR_SECS: u64 = 60 * 60;
+const DAY_SECS: u64 = 24 * HOUR_SECS;
+
+/// Per-user send limits. Windows are fixed clock hours and UTC days; unset
+/// limits don't apply.
+#[derive(Debug, Clone, Default)]
+pub struct QuotaPolicy {
+    pub messages_per_hour: Option<u64>,
+    /// Content bytes per hour.
+    pub bytes_per_hour: Option<u64>,
+    pub messages_per_day: Option<u64>,
+    /// Content bytes per day.
+    pub bytes_per_day: Option<u64>,
+}
+
+/// The window a quota applies to.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaWindow {
+    Hourly,
+    Daily,
+}
+
+/// What a quota counts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaResource {
+    Messages,
+    Bytes,
+}
+
+impl fmt::Display for QuotaWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QuotaWindow::Hourly => "hourly",
+            QuotaWindow::Daily => "daily",
+        })
+    }
+}
+
+impl fmt::Display for QuotaResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            QuotaResource::Messages => "message",
+            QuotaResource::Bytes => "byte",
+        })
+    }
+}
+
+/// Why a send was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuotaExceeded {
+    pub window: QuotaWindow,
+    pub resource: QuotaResource,
+    pub limit: u64,
+    /// Unix time (seconds) the window resets.
+    pub resets_at: u64,
+}
+
+/// One user's usage in the current hour and day.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct QuotaUsage {
+    pub user: String,
+    pub messages_this_hour: u64,
+    pub bytes_this_hour: u64,
+    pub messages_today: u64,
+    pub bytes_today: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hour: u64,
+    hour_messages: u64,
+    hour_bytes: u64,
+    day: u64,
+    day_messages: u64,
+    day_bytes: u64,
+}
+
+impl Counters {
+    /// Resets counters whose window has passed.
+    fn roll(&mut self, now: u64) {
+        if self.hour != now / HOUR_SECS {
+            self.hour = now / HOUR_SECS;
+            self.hour_messages = 0;
+            self.hour_bytes = 0;
+        }
+        if self.day != now / DAY_SECS {
+            self.day = now / DAY_SECS;
+            self.day_messages = 0;
+            self.day_bytes = 0;
+        }
+    }
+}
+
+/// Counts what each user sends and enforces a `QuotaPolicy`, readable
+/// through `ChatServer::quotas`.
+#[derive(Debug, Default)]
+pub struct QuotaTracker {
+    policy: QuotaPolicy,
+    users: Mutex<HashMap<String, Counters>>,
+}
+
+impl QuotaTracker {
+    pub fn new(policy: QuotaPolicy) -> Self {
+        QuotaTracker {
+            policy,
+            users: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a message of `bytes` content bytes from `user` sent at `now`
+    /// (Unix seconds), unless it would exceed a quota.
+    pub fn record(&self, user: &str, bytes: u64, now: u64) -> Result<(), QuotaExceeded> {
+        let mut users = self.users.lock().unwrap();
+        if !users.contains_key(user) {
+            // Forget users with nothing counted today before adding another.
+            users.retain(|_, counters| counters.day == now / DAY_SECS);
+        }
+        let counters = users.entry(user.to_string()).or_default();
+        counters.roll(now);
+        let hour_reset = (now / HOUR_SECS + 1) * HOUR_SECS;
+        let day_reset = (now / DAY_SECS + 1) * DAY_SECS;
+        let checks = [
+            (
+                self.policy.messages_per_hour,
+                counters.hour_messages + 1,
+                QuotaWindow::Hourly,
+                QuotaResource::Messages,
+                hour_reset,
+            ),
+            (
+                self.policy.bytes_per_hour,
+                counters.hour_bytes + bytes,
+                QuotaWindow::Hourly,
+                QuotaResource::Bytes,
+                hour_reset,
+            ),
+            (
+                self.policy.messages_per_day,
+                counters.day_messages + 1,
+                QuotaWindow::Daily,
+                QuotaResource::Messages,
+                day_reset,
+            ),
+            (
+                self.policy.bytes_per_day,
+                counters.day_bytes + bytes,
+                QuotaWindow::Daily,
+                QuotaResource::Bytes,
+                day_reset,
+            ),
+        ];
+        for (limit, used, window, resource, resets_at) in checks {
+            if let Some(limit) = limit.filter(|&limit| used > limit) {
+                return Err(QuotaExceeded {
+                    window,
+                    resource,
+                    limit,
+                    resets_at,
+                });
+            }
+        }
+        counters.hour_messages += 1;
+        counters.hour_bytes += bytes;
+        counters.day_messages += 1;
+        counters.day_bytes += bytes;
+        Ok(())
+    }
+
+    /// Returns `user`'s usage as of `now`.
+    pub fn usage(&self, user: &str, now: u64) -> QuotaUsage {
+        let users = self.users.lock().unwrap();
+        let mut usage = QuotaUsage {
+            user: user.to_string(),
+            ..Default::default()
+        };
+        if let Some(counters) = users.get(user) {
+            if counters.hour == now / HOUR_SECS {
+                usage.messages_this_hour = counters.hour_messages;
+                usage.bytes_this_hour = counters.hour_bytes;
+            }
+            if counters.day == now / DAY_SECS {
+                usage.messages_today = counters.day_messages;
+                usage.bytes_today = counters.day_bytes;
+            }
+        }
+        usage
+    }
+
+    /// Returns the usage of every user who has sent something today.
+    pub fn snapshot(&self, now: u64) -> Vec<QuotaUsage> {
+        let users: Vec<String> = self.users.lock().unwrap().keys().cloned().collect();
+        let mut usage: Vec<QuotaUsage> = users
+            .iter()
+            .map(|user| self.usage(user, now))
+            .filter(|usage| usage.messages_today > 0)
+            .collect();
+        usage.sort_by(|a, b| a.user.cmp(&b.user));
+        usage
+    }
+}
+
+/// Limits on the rooms of a server, or of each tenant, shared by everyone
+/// in it. Unset limits don't apply.
+#[derive(Debug, Clone, Default)]
+pub struct ResourcePolicy {
+    /// Rooms configured in total; `/create` fails beyond it.
+    pub max_rooms: Option<usize>,
+    /// Members any one room admits, whatever the room's own limit.
+    pub max_members: Option<usize>,
+    /// Content bytes any one room's history may hold. Posts to a full room
+    /// are rejected until retention deletes some of it.
+    pub max_history_bytes: Option<u64>,
+    /// Messages per second across every room.
+    pub messages_per_second: Option<u64>,
+    /// Messages per second in any one room.
+    pub room_messages_per_second: Option<u64>,
+}
+
+/// What a `ResourcePolicy` limit counts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Resource {
+    Rooms,
+    Members,
+    HistoryBytes,
+    MessagesPerSecond,
+}
+
+impl fmt::Display for Resource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Resource::Rooms => "room",
+            Resource::Members => "member",
+            Resource::HistoryBytes => "history byte",
+            Resource::MessagesPerSecond => "messages per second",
+        })
+    }
+}
+
+/// Why a change was rejected by a `ResourcePolicy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceExceeded {
+    pub resource: Resource,
+    /// The room the limit applies to; `None` for server-wide limits.
+    pub room: Option<String>,
+    pub limit: u64,
+}
+
+/// One room's usage of its `ResourcePolicy` limits.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoomUsage {
+    pub room: String,
+    pub members: usize,
+    /// Content bytes of the room's history, as of the last post to it.
+    pub history_bytes: u64,
+    pub messages_this_second: u64,
+}
+
+/// Usage of a server's `ResourcePolicy` limits, from
+/// `ResourceTracker::usage`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub rooms: usize,
+    pub messages_this_second: u64,
+    /// Rooms that are configured or have had messages, by name.
+    pub by_room: Vec<RoomUsage>,
+}
+
+#[derive(Debug, Default)]
+struct RoomCounters {
+    second: u64,
+    messages: u64,
+    history_bytes: u64,
+    /// When `history_bytes` was last read from the store, in Unix seconds.
+    history_synced: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct ResourceCounters {
+    second: u64,
+    messages: u64,
+    rooms: HashMap<String, RoomCounters>,
+}
+
+/// Enforces a `ResourcePolicy` on one server's rooms, and reports their
+/// usage through `ChatServer::resources`. Room and member counts are read
+/// from the registry; history sizes are counted as messages are posted
+/// and periodically corrected from the store.
+#[derive(Default)]
+pub struct ResourceTracker {
+    policy: ResourcePolicy,
+    registry: Arc<Registry>,
+    counters: Mutex<ResourceCounters>,
+}
+
+impl ResourceTracker {
+    /// How long a room's history size is trusted before the server reads
+    /// it from the store again, to notice what retention has deleted.
+    pub const HISTORY_RESYNC_SECS: u64 = 60;
+
+    pub fn new(policy: ResourcePolicy, registry: Arc<Registry>) -> Self {
+        ResourceTracker {
+            policy,
+            registry,
+            counters: Mutex::new(ResourceCounters::default()),
+        }
+    }
+
+    pub fn policy(&self) -> &ResourcePolicy {
+        &self.policy
+    }
+
+    /// Checks that another room may be created.
+    pub fn check_new_room(&self) -> Result<(), ResourceExceeded> {
+        match self.policy.max_rooms {
+            Some(limit) if self.registry.room_count() >= limit => Err(ResourceExceeded {
+                resource: Resource::Rooms,
+                room: None,
+                limit: limit as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that `room`, which has `members` members, may admit another.
+    pub fn check_new_member(&self, room: &str, members: usize) -> Result<(), ResourceExceeded> {
+        match self.policy.max_members {
+            Some(limit) if members >= limit => Err(ResourceExceeded {
+                resource: Resource::Members,
+                room: Some(room.to_string()),
+                limit: limit as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether `room`'s history size should be read from the store before
+    /// the next `record_message` at `now`.
+    pub fn needs_history(&self, room: &str, now: u64) -> bool {
+        self.policy.max_history_bytes.is_some()
+            && self
+                .counters
+                .lock()
+                .unwrap()
+                .rooms
+                .get(room)
+                .and_then(|counters| counters.history_synced)
+                .is_none_or(|at| now >= at + Self::HISTORY_RESYNC_SECS)
+    }
+
+    /// Sets `room`'s history size as read from the store at `now`.
+    pub fn set_history(&self, room: &str, bytes: u64, now: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        let room = counters.rooms.entry(room.to_string()).or_default();
+        room.history_bytes = bytes;
+        room.history_synced = Some(now);
+    }
+
+    /// Records a message of `bytes` content bytes posted to `room` at `now`
+    /// (Unix seconds), unless it would exceed a limit.
+    pub fn record_message(&self, room: &str, bytes: u64, now: u64) -> Result<(), ResourceExceeded> {
+        let mut counters = self.counters.lock().unwrap();
+        if counters.second != now {
+            counters.second = now;
+            counters.messages = 0;
+        }
+        let total = counters.messages + 1;
+        let counters = &mut *counters;
+        let room_counters = counters.rooms.entry(room.to_string()).or_default();
+        if room_counters.second != now {
+            room_counters.second = now;
+            room_counters.messages = 0;
+        }
+        let checks = [
+            (
+                self.policy.messages_per_second,
+                total,
+                Resource::MessagesPerSecond,
+                false,
+            ),
+            (
+                self.policy.room_messages_per_second,
+                room_counters.messages + 1,
+                Resource::MessagesPerSecond,
+                true,
+            ),
+            (
+                self.policy.max_history_bytes,
+                room_counters.history_bytes + bytes,
+                Resource::HistoryBytes,
+                true,
+            ),
+        ];
+        for (limit, used, resource, per_room) in checks {
+            if let Some(limit) = limit.filter(|&limit| used > limit) {
+                return Err(ResourceExceeded {
+                    resource,
+                    room: per_room.then(|| room.to_string()),
+                    limit,
+                });
+            }
+        }
+        room_counters.messages += 1;
+        room_counters.history_bytes += bytes;
+        counters.messages = total;
+        Ok(())
+    }
+
+    /// Takes back a `record_message` made at `now` for a message that was
+    /// then rejected for some other reason.
+    pub fn refund_message(&self, room: &str, bytes: u64, now: u64) {
+        let mut counters = self.counters.lock().unwrap();
+        if counters.second == now {
+            counters.messages = counters.messages.saturating_sub(1);
+        }
+        if let Some(room) = counters.rooms.get_mut(room) {
+            if room.second == now {
+                room.messages = room.messages.saturating_sub(1);
+            }
+            room.history_bytes = room.history_bytes.saturating_sub(bytes);
+        }
+    }
+
+    /// Returns usage as of `now`.
+    pub fn usage(&self, now: u64) -> ResourceUsage {
+        let members = self.registry.room_members();
+        let counters = self.counters.lock().unwrap();
+        let mut by_room: HashMap<&str, RoomUsage> = members
+            .iter()
+            .map(|(room, members)| {
+                let usage = RoomUsage {
+                    room: room.clone(),
+                    members: *members,
+                    ..Default::default()
+                };
+                (room.as_str(), usage)
+            })
+            .collect();
+        for (room, room_counters) in &counters.rooms {
+            if self.registry.is_archived(room) {
+                continue;
+            }
+            let usage = by_room.entry(room).or_insert_with(|| RoomUsage {
+                room: room.clone(),
+                ..Default::default()
+            });
+            usage.history_bytes = room_counters.history_bytes;
+            if room_counters.second == now {
+                usage.messages_this_second = room_counters.messages;
+            }
+        }
+        let mut by_room: Vec<RoomUsage> = by_room.into_values().collect();
+        by_room.sort_by(|a, b| a.room.cmp(&b.room));
+        ResourceUsage {
+            rooms: members.len(),
+            messages_this_second: if counters.second == now {
+                counters.messages
+            } else {
+                0
+            },
+            by_room,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hourly_quota_resets_with_the_hour() {
+        let tracker = QuotaTracker::new(QuotaPolicy {
+            messages_per_hour: Some(2),
+            bytes_per_day: Some(100),
+            ..Default::default()
+        });
+        let now = 10 * DAY_SECS + 30;
+        tracker.record("avery", 10, now).unwrap();
+        tracker.record("avery", 10, now).unwrap();
+        let exceeded = tracker.record("avery", 10, now).unwrap_err();
+        assert_eq!(exceeded.window, QuotaWindow::Hourly);
+        assert_eq!(exceeded.resource, QuotaResource::Messages);
+        assert_eq!(exceeded.resets_at, 10 * DAY_SECS + HOUR_SECS);
+        tracker.record("blake", 10, now).unwrap();
+
+        let later = now + HOUR_SECS;
+        tracker.record("avery", 10, later).unwrap();
+        let exceeded = tracker.record("avery", 80, later).unwrap_err();
+        assert_eq!(exceeded.resource, QuotaResource::Bytes);
+        assert_eq!(exceeded.resets_at, 11 * DAY_SECS);
+
+        let usage = tracker.usage("avery", later);
+        assert_eq!((usage.messages_this_hour, usage.messages_today), (1, 3));
+        assert_eq!(usage.bytes_today, 30);
+        assert_eq!(tracker.snapshot(later).len(), 2);
+    }
+
+    #[test]
+    fn test_resource_limits() {
+        let registry = Arc::new(Registry::new());
+        registry.set_room("lobby", Default::default());
+        let tracker = ResourceTracker::new(
+            ResourcePolicy {
+                max_rooms: Some(1),
+                max_members: Some(2),
+                max_history_bytes: Some(25),
+                room_messages_per_second: Some(2),
+                ..Default::default()
+            },
+            registry,
+        );
+        assert_eq!(
+            tracker.check_new_room().unwrap_err().resource,
+            Resource::Rooms
+        );
+        assert!(tracker.check_new_member("lobby", 1).is_ok());
+        assert_eq!(
+            tracker
+                .check_new_member("lobby", 2)
+                .unwrap_err()
+                .room
+                .as_deref(),
+            Some("lobby")
+        );
+
+        assert!(tracker.needs_history("lobby", 100));
+        tracker.set_history("lobby", 5, 100);
+        assert!(!tracker.needs_history("lobby", 100));
+        tracker.record_message("lobby", 10, 100).unwrap();
+        tracker.record_message("lobby", 1, 100).unwrap();
+        let exceeded = tracker.record_message("lobby", 1, 100).unwrap_err();
+        assert_eq!(exceeded.resource, Resource::MessagesPerSecond);
+        // As if the second message had then failed its sender's quota.
+        tracker.refund_message("lobby", 1, 100);
+        tracker.record_message("lobby", 1, 100).unwrap();
+        let exceeded = tracker.record_message("lobby", 10, 101).unwrap_err();
+        assert_eq!(exceeded.resource, Resource::HistoryBytes);
+        tracker.record_message("other", 10, 101).unwrap();
+
+        let usage = tracker.usage(101);
+        assert_eq!((usage.rooms, usage.messages_this_second), (1, 1));
+        assert_eq!(usage.by_room.len(), 2);
+        assert_eq!(usage.by_room[0].history_bytes, 16);
+        assert_eq!(usage.by_room[0].messages_this_second, 0);
+    }
+}
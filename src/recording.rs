@@ -0,0 +1,321 @@
+use crate::codec::FrameDecoder;
+use crate::tasks::TaskRegistry;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant, sleep_until};
+use tracing::warn;
+
+/// Written in place of secrets: tokens, API keys and passwords.
+pub const REDACTED: &str = "[redacted]";
+/// Longest line `play` accepts from the server.
+const MAX_LINE_LEN: usize = 16 * 1024 * 1024;
+/// Frame fields holding secrets, by frame type.
+const SECRET_FIELDS: &[(&str, &str)] = &[
+    ("Authenticate", "token"),
+    ("ApiKey", "key"),
+    ("ResumeSession", "token"),
+    ("UnregisterPushToken", "token"),
+    ("Welcome", "resume_token"),
+    ("Authenticated", "resume_token"),
+    ("SessionResumed", "resume_token"),
+];
+/// Slash commands whose last argument is a password.
+const PASSWORD_COMMANDS: &[&str] = &["/register", "/identify"];
+
+/// Which way a recorded line went.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// From the client to the server.
+    Inbound,
+    /// From the server to the client.
+    Outbound,
+}
+
+/// One line of a recording.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Record {
+    /// Milliseconds since the connection was accepted.
+    pub elapsed_ms: u64,
+    pub direction: Direction,
+    /// The line as sent, without its newline and with secrets redacted.
+    pub line: String,
+}
+
+/// Records every line a connection sends and receives, one JSON `Record`
+/// per line, to `<unix time>-<address>.jsonl` in a directory. Lines are
+/// written by a background task so recording never blocks the connection;
+/// the file is complete once the recorder is dropped.
+pub struct Recorder {
+    path: PathBuf,
+    started: Instant,
+    records: mpsc::UnboundedSender<Record>,
+}
+
+impl Recorder {
+    /// Creates `dir` if needed and starts a recording there for the
+    /// connection from `addr`, written by a task spawned in `tasks`.
+    pub async fn create(dir: &Path, addr: SocketAddr, tasks: &TaskRegistry) -> Result<Recorder> {
+        tokio::fs::create_dir_all(dir).await?;
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = format!("{}-{}.jsonl", started_at, addr).replace([':', '[', ']'], "_");
+        let path = dir.join(name);
+        let file = tokio::fs::File::create(&path)
+            .await
+            .with_context(|| format!("Creating {}", path.display()))?;
+        let (records, pending) = mpsc::unbounded_channel();
+        tasks.spawn(
+            "recordings",
+            "recorder",
+            write_records(path.clone(), file, pending),
+        );
+        Ok(Recorder {
+            path,
+            started: Instant::now(),
+            records,
+        })
+    }
+
+    /// Where the recording is written.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Records a line, redacting any secrets in it.
+    pub fn record(&self, direction: Direction, line: &str) {
+        let record = Record {
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+            direction,
+            line: redact(line.trim_end()).into_owned(),
+        };
+        // The writer only stops early if the file can't be written, which
+        // it has already logged.
+        let _ = self.records.send(record);
+    }
+}
+
+async fn write_records(
+    path: PathBuf,
+    file: tokio::fs::File,
+    mut records: mpsc::UnboundedReceiver<Record>,
+) {
+    let mut file = BufWriter::new(file);
+    let mut batch = Vec::new();
+    while records.recv_many(&mut batch, 64).await > 0 {
+        let mut result = Ok(());
+        for record in batch.drain(..) {
+            let Ok(mut line) = serde_json::to_string(&record) else {
+                continue;
+            };
+            line.push('\n');
+            result = result.and(file.write_all(line.as_bytes()).await);
+        }
+        if let Err(e) = result.and(file.flush().await) {
+            warn!("Failed to write recording {}: {}", path.display(), e);
+            return;
+        }
+    }
+}
+
+/// Replaces the secrets in a frame or chat line with `REDACTED`: sign-in
+/// tokens and keys, resume tokens, and the passwords in `/register` and
+/// `/identify`. Other lines are returned as they are.
+pub fn redact(line: &str) -> Cow<'_, str> {
+    let Ok(Value::Object(mut frame)) = serde_json::from_str::<Value>(line) else {
+        // A "sender:content" chat line.
+        return match line.split_once(':') {
+            Some((sender, content)) => match redact_password(content.trim()) {
+                Some(content) => Cow::Owned(format!("{}:{}", sender, content)),
+                None => Cow::Borrowed(line),
+            },
+            None => Cow::Borrowed(line),
+        };
+    };
+    let mut redacted = false;
+    let kind = frame
+        .get("type")
+        .and_then(Value::as_str)
+        .map(str::to_string);
+    match kind.as_deref() {
+        Some(kind) => {
+            for (_, field) in SECRET_FIELDS.iter().filter(|(secret, _)| *secret == kind) {
+                if let Some(value) = frame.get_mut(*field) {
+                    *value = Value::from(REDACTED);
+                    redacted = true;
+                }
+            }
+            if kind == "RegisterPushToken"
+                && let Some(token) = frame.get_mut("push").and_then(|push| push.get_mut("token"))
+            {
+                *token = Value::from(REDACTED);
+                redacted = true;
+            }
+        }
+        None => {
+            let content = frame.get("content").and_then(Value::as_str);
+            if let Some(content) = content.and_then(redact_password) {
+                frame.insert("content".to_string(), Value::from(content));
+                redacted = true;
+            }
+        }
+    }
+    if redacted {
+        Cow::Owned(Value::Object(frame).to_string())
+    } else {
+        Cow::Borrowed(line)
+    }
+}
+
+fn redact_password(content: &str) -> Option<String> {
+    let mut words = content.split_whitespace();
+    let command = words.next()?;
+    if !PASSWORD_COMMANDS.contains(&command) {
+        return None;
+    }
+    let mut words: Vec<&str> = words.collect();
+    if let Some(password) = words.last_mut() {
+        *password = REDACTED;
+    }
+    Some(
+        std::iter::once(command)
+            .chain(words)
+            .collect::<Vec<_>>()
+            .join(" "),
+    )
+}
+
+/// Reads a recording made by `Recorder`.
+pub async fn read(path: impl AsRef<Path>) -> Result<Vec<Record>> {
+    let path = path.as_ref();
+    let contents = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Reading {}", path.display()))?;
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .enumerate()
+        .map(|(index, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("{} line {}", path.display(), index + 1))
+        })
+        .collect()
+}
+
+/// How `play` sends a recording.
+#[derive(Debug, Clone)]
+pub struct PlaybackOptions {
+    /// How many times faster than recorded to send. Zero or less sends as
+    /// fast as possible.
+    pub speed: f64,
+    /// Sent in place of redacted secrets, e.g. a token for a test user.
+    pub secret: Option<String>,
+    /// How long to keep listening after the last line is sent.
+    pub linger: Duration,
+}
+
+impl Default for PlaybackOptions {
+    fn default() -> Self {
+        PlaybackOptions {
+            speed: 1.0,
+            secret: None,
+            linger: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Sends the inbound lines of a recording to the server at `addr` on a
+/// new connection, keeping their recorded spacing, and returns every line
+/// the server sent back. Comparing those with the recording's outbound
+/// lines shows where a server's behaviour changed.
+pub async fn play(
+    records: &[Record],
+    addr: &str,
+    options: &PlaybackOptions,
+) -> Result<Vec<String>> {
+    let mut socket = TcpStream::connect(addr).await?;
+    let mut decoder = FrameDecoder::new(MAX_LINE_LEN);
+    let mut buffer = [0; 4096];
+    let mut received = Vec::new();
+    let secret = options
+        .secret
+        .as_deref()
+        .map(|secret| serde_json::to_string(secret).unwrap_or_default())
+        .map(|quoted| quoted[1..quoted.len() - 1].to_string());
+    let started = Instant::now();
+    let mut inbound = records
+        .iter()
+        .filter(|record| record.direction == Direction::Inbound)
+        .peekable();
+    let mut done_at = None;
+    loop {
+        let next = match inbound.peek() {
+            Some(record) if options.speed > 0.0 => {
+                started + Duration::from_millis(record.elapsed_ms).div_f64(options.speed)
+            }
+            Some(_) => started,
+            None => *done_at.get_or_insert_with(|| Instant::now() + options.linger),
+        };
+        tokio::select! {
+            _ = sleep_until(next) => {
+                let Some(record) = inbound.next() else {
+                    break;
+                };
+                let line = match &secret {
+                    Some(secret) => record.line.replace(REDACTED, secret),
+                    None => record.line.clone(),
+                };
+                socket.write_all(format!("{}\n", line).as_bytes()).await?;
+            }
+            read = socket.read(&mut buffer) => {
+                let n = read?;
+                if n == 0 {
+                    break;
+                }
+                decoder.extend(&buffer[..n]);
+                while let Some(line) = decoder.next_line()? {
+                    received.push(line);
+                }
+            }
+        }
+    }
+    Ok(received)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secrets_are_redacted() {
+        assert_eq!(
+            redact(r#"{"type":"Authenticate","user":"avery","token":"hunter2"}"#),
+            r#"{"token":"[redacted]","type":"Authenticate","user":"avery"}"#
+        );
+        assert_eq!(
+            redact(r#"{"type":"RegisterPushToken","push":{"platform":"fcm","token":"abc"}}"#),
+            r#"{"push":{"platform":"fcm","token":"[redacted]"},"type":"RegisterPushToken"}"#
+        );
+        assert_eq!(
+            redact(r#"{"sender":"avery","content":"/identify avery hunter2"}"#),
+            r#"{"content":"/identify avery [redacted]","sender":"avery"}"#
+        );
+        assert_eq!(
+            redact("avery: /register avery hunter2"),
+            "avery:/register avery [redacted]"
+        );
+        let untouched = r#"{"type":"Presence"}"#;
+        assert!(matches!(redact(untouched), Cow::Borrowed(line) if line == untouched));
+        assert!(matches!(redact("avery: hi"), Cow::Borrowed(_)));
+    }
+}
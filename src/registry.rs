@@ -0,0 +1,824 @@
+use crate::apikey::{ApiKeys, StoredApiKey};
+use crate::i18n::DEFAULT_LOCALE;
+use crate::invite::{Invite, InviteError, MAX_INVITES_PER_ROOM};
+use crate::nickname;
+use crate::protocol::{
+    Capability, CustomEmoji, Draft, NotificationPrefs, PresenceState, PresenceSubscription,
+    Profile, PushToken, UserPresence,
+};
+use crate::room::RoomConfig;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc;
+
+/// Shared state about connected clients, keyed by their socket address,
+/// and the rooms they talk in.
+#[derive(Default)]
+pub struct Registry {
+    devices: Mutex<HashMap<SocketAddr, Device>>,
+    rooms: Mutex<HashMap<String, RoomConfig>>,
+    /// Registered nicknames and their password hashes.
+    nicks: Mutex<HashMap<String, String>>,
+    api_keys: Arc<ApiKeys>,
+    /// Users an operator has banned.
+    bans: Mutex<BTreeSet<String>>,
+    /// Custom emoji names and the blob ids of their images.
+    emoji: Mutex<BTreeMap<String, String>>,
+    /// The users each user has blocked.
+    blocks: Mutex<HashMap<String, BTreeSet<String>>>,
+    /// Notification preferences of users who changed them.
+    notification_prefs: Mutex<HashMap<String, NotificationPrefs>>,
+    /// Each user's push tokens, oldest first.
+    push_tokens: Mutex<HashMap<String, Vec<PushToken>>>,
+    /// Outstanding room invites, by token.
+    invites: Mutex<HashMap<String, Invite>>,
+    /// Each user's drafts, by room.
+    drafts: Mutex<HashMap<String, BTreeMap<String, Draft>>>,
+    /// Told the name of each room whose settings change; see `watch_rooms`.
+    room_changes: Mutex<Option<mpsc::UnboundedSender<String>>>,
+}
+
+/// One connection. Connections signed in as the same user are that user's
+/// devices, and are listed together in presence.
+#[derive(Default)]
+struct Device {
+    user: Option<String>,
+    profile: Profile,
+    /// Negotiated with `SetLocale`; `i18n::DEFAULT_LOCALE` until then.
+    locale: Option<String>,
+    /// Negotiated with `SetCapabilities`; every capability until then.
+    capabilities: Option<BTreeSet<Capability>>,
+    /// Whose presence the connection follows; everyone's if `None`.
+    presence_subscription: Option<PresenceSubscription>,
+}
+
+impl Device {
+    /// The name presence lists this connection under.
+    fn user(&self, addr: SocketAddr) -> String {
+        self.user.clone().unwrap_or_else(|| addr.to_string())
+    }
+}
+
+/// The part of the registry that outlives connections, as saved by
+/// `crate::snapshot`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct RegistrySnapshot {
+    pub rooms: HashMap<String, RoomConfig>,
+    /// Registered nicknames and their password hashes.
+    pub nicks: HashMap<String, String>,
+    pub api_keys: Vec<StoredApiKey>,
+    pub bans: BTreeSet<String>,
+    /// Custom emoji names and the blob ids of their images.
+    pub emoji: BTreeMap<String, String>,
+    /// The users each user has blocked.
+    pub blocks: HashMap<String, BTreeSet<String>>,
+    pub notification_prefs: HashMap<String, NotificationPrefs>,
+    pub push_tokens: HashMap<String, Vec<PushToken>>,
+    pub invites: HashMap<String, Invite>,
+    pub drafts: HashMap<String, BTreeMap<String, Draft>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a freshly connected client with a default profile.
+    pub fn register(&self, addr: SocketAddr) {
+        self.devices.lock().unwrap().insert(addr, Device::default());
+    }
+
+    /// Removes a client once it disconnects.
+    pub fn unregister(&self, addr: SocketAddr) {
+        self.devices.lock().unwrap().remove(&addr);
+    }
+
+    /// Records who a client is signed in as.
+    pub fn set_user(&self, addr: SocketAddr, user: &str) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(addr).or_default().user = Some(user.to_string());
+    }
+
+    /// Records the locale a client's server-generated text is translated into.
+    pub fn set_locale(&self, addr: SocketAddr, locale: &str) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(addr).or_default().locale = Some(locale.to_string());
+    }
+
+    /// Returns a client's negotiated locale.
+    pub fn locale(&self, addr: SocketAddr) -> String {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .get(&addr)
+            .and_then(|device| device.locale.clone())
+            .unwrap_or_else(|| DEFAULT_LOCALE.to_string())
+    }
+
+    /// Records the capabilities a client declared.
+    pub fn set_capabilities(&self, addr: SocketAddr, capabilities: BTreeSet<Capability>) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(addr).or_default().capabilities = Some(capabilities);
+    }
+
+    /// Returns a client's negotiated capabilities, or `None` if it handles
+    /// them all.
+    pub fn capabilities(&self, addr: SocketAddr) -> Option<BTreeSet<Capability>> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .get(&addr)
+            .and_then(|device| device.capabilities.clone())
+    }
+
+    /// Returns the connections missing some of `needed`, grouped by the
+    /// capabilities they have.
+    pub fn lacking(
+        &self,
+        needed: &BTreeSet<Capability>,
+    ) -> HashMap<BTreeSet<Capability>, HashSet<SocketAddr>> {
+        let mut groups: HashMap<_, HashSet<_>> = HashMap::new();
+        if needed.is_empty() {
+            return groups;
+        }
+        let devices = self.devices.lock().unwrap();
+        for (addr, device) in devices.iter() {
+            if let Some(capabilities) = &device.capabilities
+                && !needed.is_subset(capabilities)
+            {
+                groups
+                    .entry(capabilities.clone())
+                    .or_default()
+                    .insert(*addr);
+            }
+        }
+        groups
+    }
+
+    /// Returns every connection with its negotiated locale.
+    pub fn locales(&self) -> Vec<(SocketAddr, String)> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .map(|(addr, device)| {
+                let locale = device.locale.as_deref().unwrap_or(DEFAULT_LOCALE);
+                (*addr, locale.to_string())
+            })
+            .collect()
+    }
+
+    /// Returns the connections signed in as `user`.
+    pub fn devices_of(&self, user: &str) -> Vec<SocketAddr> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .filter(|(addr, device)| device.user(**addr) == user)
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Returns every connection with the name presence lists it under.
+    pub fn connections(&self) -> Vec<(SocketAddr, String)> {
+        let devices = self.devices.lock().unwrap();
+        let mut connections: Vec<(SocketAddr, String)> = devices
+            .iter()
+            .map(|(addr, device)| (*addr, device.user(*addr)))
+            .collect();
+        connections.sort();
+        connections
+    }
+
+    /// Applies a partial profile update and returns the resulting presence
+    /// of the client's user, across all their devices.
+    pub fn update_profile(
+        &self,
+        addr: SocketAddr,
+        display_name: Option<String>,
+        status_text: Option<String>,
+        state: Option<PresenceState>,
+    ) -> UserPresence {
+        let mut devices = self.devices.lock().unwrap();
+        let device = devices.entry(addr).or_default();
+        let profile = &mut device.profile;
+        if let Some(display_name) = display_name {
+            profile.display_name = Some(display_name).filter(|name| !name.is_empty());
+        }
+        if let Some(state) = state {
+            profile.state = state;
+        }
+        if let Some(status_text) = status_text {
+            profile.status_text = Some(status_text).filter(|text| !text.is_empty());
+        }
+        let user = device.user(addr);
+        aggregate(&devices)
+            .remove(&user)
+            .expect("updated device is listed")
+    }
+
+    /// Returns a connected client's profile.
+    pub fn profile(&self, addr: SocketAddr) -> Option<Profile> {
+        let devices = self.devices.lock().unwrap();
+        devices.get(&addr).map(|device| device.profile.clone())
+    }
+
+    /// Replaces a client's profile, e.g. one carried over from a resumed
+    /// session.
+    pub fn set_profile(&self, addr: SocketAddr, profile: Profile) {
+        self.devices
+            .lock()
+            .unwrap()
+            .entry(addr)
+            .or_default()
+            .profile = profile;
+    }
+
+    /// Returns the presence of every connected user.
+    pub fn presence(&self) -> Vec<UserPresence> {
+        let mut users: Vec<UserPresence> = aggregate(&self.devices.lock().unwrap())
+            .into_values()
+            .collect();
+        users.sort_by(|a, b| a.user.cmp(&b.user));
+        users
+    }
+
+    /// Sets whose presence a client follows, or `None` for everyone's.
+    pub fn set_presence_subscription(
+        &self,
+        addr: SocketAddr,
+        subscription: Option<PresenceSubscription>,
+    ) {
+        let mut devices = self.devices.lock().unwrap();
+        devices.entry(addr).or_default().presence_subscription = subscription;
+    }
+
+    /// Returns whose presence a client follows, or `None` for everyone's.
+    pub fn presence_subscription(&self, addr: SocketAddr) -> Option<PresenceSubscription> {
+        let devices = self.devices.lock().unwrap();
+        devices
+            .get(&addr)
+            .and_then(|device| device.presence_subscription.clone())
+    }
+
+    /// Returns the presence of the connected users `subscription` follows.
+    pub fn followed_presence(&self, subscription: &PresenceSubscription) -> Vec<UserPresence> {
+        let followed = self.followed_users(subscription);
+        self.presence()
+            .into_iter()
+            .filter(|presence| followed.contains(&presence.user))
+            .collect()
+    }
+
+    /// Returns the connections that subscribed to presence but don't
+    /// follow `user`, and so shouldn't hear about their changes.
+    pub fn devices_not_following(&self, user: &str) -> HashSet<SocketAddr> {
+        let subscriptions: Vec<(SocketAddr, PresenceSubscription)> = {
+            let devices = self.devices.lock().unwrap();
+            devices
+                .iter()
+                .filter_map(|(addr, device)| Some((*addr, device.presence_subscription.clone()?)))
+                .collect()
+        };
+        subscriptions
+            .into_iter()
+            .filter(|(_, subscription)| !self.followed_users(subscription).contains(user))
+            .map(|(addr, _)| addr)
+            .collect()
+    }
+
+    /// The users `subscription` names, and the participants of its rooms.
+    fn followed_users(&self, subscription: &PresenceSubscription) -> HashSet<String> {
+        let rooms = self.rooms.lock().unwrap();
+        let participants = subscription
+            .rooms
+            .iter()
+            .filter_map(|room| rooms.get(room))
+            .flat_map(|config| config.participants().cloned());
+        subscription
+            .users
+            .iter()
+            .cloned()
+            .chain(participants)
+            .collect()
+    }
+
+    /// Configures a room's settings.
+    pub fn set_room(&self, name: impl Into<String>, config: RoomConfig) {
+        let name = name.into();
+        self.rooms.lock().unwrap().insert(name.clone(), config);
+        self.room_changed(&name);
+    }
+
+    /// Returns the names of rooms as they're configured, changed or
+    /// removed from now on, e.g. to persist their settings. Replaces any
+    /// earlier receiver.
+    pub fn watch_rooms(&self) -> mpsc::UnboundedReceiver<String> {
+        let (changes, receiver) = mpsc::unbounded_channel();
+        *self.room_changes.lock().unwrap() = Some(changes);
+        receiver
+    }
+
+    /// Stops sending to the `watch_rooms` receiver, which ends once it has
+    /// had the changes already sent.
+    pub fn unwatch_rooms(&self) {
+        self.room_changes.lock().unwrap().take();
+    }
+
+    fn room_changed(&self, name: &str) {
+        if let Some(changes) = &*self.room_changes.lock().unwrap() {
+            let _ = changes.send(name.to_string());
+        }
+    }
+
+    /// Returns the configuration for `room`, falling back to the defaults.
+    pub fn room_config(&self, room: &str) -> RoomConfig {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(room)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Configures a new room; returns false if `name` is already configured.
+    pub fn create_room(&self, name: &str, config: RoomConfig) -> bool {
+        let mut rooms = self.rooms.lock().unwrap();
+        if rooms.contains_key(name) {
+            return false;
+        }
+        rooms.insert(name.to_string(), config);
+        drop(rooms);
+        self.room_changed(name);
+        true
+    }
+
+    /// Applies `change` to a configured room's settings, returning its
+    /// result, or `None` if the room isn't configured.
+    pub fn update_room<R>(
+        &self,
+        name: &str,
+        change: impl FnOnce(&mut RoomConfig) -> R,
+    ) -> Option<R> {
+        let result = self.rooms.lock().unwrap().get_mut(name).map(change);
+        if result.is_some() {
+            self.room_changed(name);
+        }
+        result
+    }
+
+    /// Returns how many rooms are configured.
+    pub fn room_count(&self) -> usize {
+        self.rooms.lock().unwrap().len()
+    }
+
+    /// Returns each configured room that isn't archived with its number of
+    /// members.
+    pub fn room_members(&self) -> Vec<(String, usize)> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, config)| !config.archived)
+            .map(|(name, config)| (name.clone(), config.members.len()))
+            .collect()
+    }
+
+    /// Returns the rooms `user` is a member of, in order by name.
+    pub fn rooms_of(&self, user: &str) -> Vec<String> {
+        let mut rooms: Vec<String> = self
+            .rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, config)| config.members.contains(user))
+            .map(|(name, _)| name.clone())
+            .collect();
+        rooms.sort();
+        rooms
+    }
+
+    /// Returns the public rooms and their settings.
+    pub fn public_rooms(&self) -> Vec<(String, RoomConfig)> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, config)| config.is_public())
+            .map(|(name, config)| (name.clone(), config.clone()))
+            .collect()
+    }
+
+    /// Returns the temporary rooms.
+    pub fn temporary_rooms(&self) -> Vec<String> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, config)| config.expiry.is_some())
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Forgets a room's settings and the invites to it, returning the
+    /// settings if it was configured.
+    pub fn remove_room(&self, name: &str) -> Option<RoomConfig> {
+        self.invites
+            .lock()
+            .unwrap()
+            .retain(|_, invite| invite.room != name);
+        let removed = self.rooms.lock().unwrap().remove(name);
+        if removed.is_some() {
+            self.room_changed(name);
+        }
+        removed
+    }
+
+    /// Returns the configured rooms that aren't archived.
+    pub fn active_rooms(&self) -> Vec<String> {
+        self.rooms
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, config)| !config.archived)
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Returns how many connections are registered.
+    pub fn connection_count(&self) -> usize {
+        self.devices.lock().unwrap().len()
+    }
+
+    /// Returns whether `room`'s history is in cold storage.
+    pub fn is_archived(&self, room: &str) -> bool {
+        self.rooms
+            .lock()
+            .unwrap()
+            .get(room)
+            .is_some_and(|config| config.archived)
+    }
+
+    /// Returns whether `room` has been configured.
+    pub fn has_room(&self, room: &str) -> bool {
+        self.rooms.lock().unwrap().contains_key(room)
+    }
+
+    /// Claims `nick` with a password hash from `auth::hash_password`. If
+    /// `nick` or a lookalike of it is already registered, returns that
+    /// nickname instead.
+    pub fn register_nick(&self, nick: &str, password_hash: String) -> Result<(), String> {
+        let mut nicks = self.nicks.lock().unwrap();
+        if let Some(existing) = lookalike_nick(&nicks, nick) {
+            return Err(existing);
+        }
+        nicks.insert(nick.to_string(), password_hash);
+        Ok(())
+    }
+
+    /// Gives up a registration whose `register_nick` couldn't be persisted.
+    pub fn unregister_nick(&self, nick: &str) {
+        self.nicks.lock().unwrap().remove(nick);
+    }
+
+    /// Returns the registered nickname `nick` would be mistaken for: `nick`
+    /// itself, or one with the same `nickname::skeleton`.
+    pub fn lookalike_nick(&self, nick: &str) -> Option<String> {
+        lookalike_nick(&self.nicks.lock().unwrap(), nick)
+    }
+
+    /// Returns the password hash of a registered nickname.
+    pub fn nick_password_hash(&self, nick: &str) -> Option<String> {
+        self.nicks.lock().unwrap().get(nick).cloned()
+    }
+
+    /// Returns the API keys clients can sign in with.
+    pub fn api_keys(&self) -> Arc<ApiKeys> {
+        self.api_keys.clone()
+    }
+
+    /// Bans `user`; returns false if they already were.
+    pub fn ban(&self, user: &str) -> bool {
+        self.bans.lock().unwrap().insert(user.to_string())
+    }
+
+    /// Lifts a ban; returns false if `user` wasn't banned.
+    pub fn unban(&self, user: &str) -> bool {
+        self.bans.lock().unwrap().remove(user)
+    }
+
+    pub fn is_banned(&self, user: &str) -> bool {
+        self.bans.lock().unwrap().contains(user)
+    }
+
+    /// Returns every banned user, by name.
+    pub fn bans(&self) -> Vec<String> {
+        self.bans.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Adds or replaces the custom emoji `name`, with its image stored
+    /// under `blob_id`.
+    pub fn add_emoji(&self, name: &str, blob_id: &str) {
+        self.emoji
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), blob_id.to_string());
+    }
+
+    /// Removes a custom emoji; returns false if there was none by that name.
+    pub fn remove_emoji(&self, name: &str) -> bool {
+        self.emoji.lock().unwrap().remove(name).is_some()
+    }
+
+    /// Returns the custom emoji, by name.
+    pub fn emoji(&self) -> Vec<CustomEmoji> {
+        self.emoji
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, blob_id)| CustomEmoji {
+                name: name.clone(),
+                blob_id: blob_id.clone(),
+            })
+            .collect()
+    }
+
+    /// Whether `blob_id` is a custom emoji's image.
+    pub fn is_emoji_image(&self, blob_id: &str) -> bool {
+        self.emoji.lock().unwrap().values().any(|id| id == blob_id)
+    }
+
+    /// Stops `user` seeing what `blocked` sends; returns false if they
+    /// already didn't.
+    pub fn block(&self, user: &str, blocked: &str) -> bool {
+        let mut blocks = self.blocks.lock().unwrap();
+        blocks
+            .entry(user.to_string())
+            .or_default()
+            .insert(blocked.to_string())
+    }
+
+    /// Lifts a block; returns false if `user` hadn't blocked `blocked`.
+    pub fn unblock(&self, user: &str, blocked: &str) -> bool {
+        let mut blocks = self.blocks.lock().unwrap();
+        let Some(blocked_by_user) = blocks.get_mut(user) else {
+            return false;
+        };
+        let removed = blocked_by_user.remove(blocked);
+        if blocked_by_user.is_empty() {
+            blocks.remove(user);
+        }
+        removed
+    }
+
+    /// Whether `user` has blocked `sender`.
+    pub fn is_blocked(&self, user: &str, sender: &str) -> bool {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .get(user)
+            .is_some_and(|blocked| blocked.contains(sender))
+    }
+
+    /// Returns the users `user` has blocked, by name.
+    pub fn blocks(&self, user: &str) -> Vec<String> {
+        let blocks = self.blocks.lock().unwrap();
+        blocks
+            .get(user)
+            .map(|blocked| blocked.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the connections of everyone who has blocked `sender`.
+    pub fn devices_blocking(&self, sender: &str) -> HashSet<SocketAddr> {
+        let blockers: HashSet<String> = {
+            let blocks = self.blocks.lock().unwrap();
+            blocks
+                .iter()
+                .filter(|(_, blocked)| blocked.contains(sender))
+                .map(|(user, _)| user.clone())
+                .collect()
+        };
+        if blockers.is_empty() {
+            return HashSet::new();
+        }
+        let devices = self.devices.lock().unwrap();
+        devices
+            .iter()
+            .filter(|(_, device)| {
+                device
+                    .user
+                    .as_ref()
+                    .is_some_and(|user| blockers.contains(user))
+            })
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    /// Replaces `user`'s notification preferences.
+    pub fn set_notification_prefs(&self, user: &str, prefs: NotificationPrefs) {
+        let mut all = self.notification_prefs.lock().unwrap();
+        if prefs == NotificationPrefs::default() {
+            all.remove(user);
+        } else {
+            all.insert(user.to_string(), prefs);
+        }
+    }
+
+    /// Returns `user`'s notification preferences, the defaults if they
+    /// never set any.
+    pub fn notification_prefs(&self, user: &str) -> NotificationPrefs {
+        let all = self.notification_prefs.lock().unwrap();
+        all.get(user).cloned().unwrap_or_default()
+    }
+
+    /// Pushes `user`'s notifications to `push`, taking the token from
+    /// whoever had it before. Past `max` tokens, the oldest is dropped.
+    pub fn add_push_token(&self, user: &str, push: PushToken, max: usize) {
+        let mut all = self.push_tokens.lock().unwrap();
+        for tokens in all.values_mut() {
+            tokens.retain(|existing| existing.token != push.token);
+        }
+        all.retain(|_, tokens| !tokens.is_empty());
+        let tokens = all.entry(user.to_string()).or_default();
+        tokens.push(push);
+        if tokens.len() > max {
+            tokens.remove(0);
+        }
+    }
+
+    /// Forgets one of `user`'s push tokens; returns false if they didn't
+    /// have it.
+    pub fn remove_push_token(&self, user: &str, token: &str) -> bool {
+        let mut all = self.push_tokens.lock().unwrap();
+        let Some(tokens) = all.get_mut(user) else {
+            return false;
+        };
+        let before = tokens.len();
+        tokens.retain(|existing| existing.token != token);
+        let removed = tokens.len() != before;
+        if tokens.is_empty() {
+            all.remove(user);
+        }
+        removed
+    }
+
+    /// Returns `user`'s push tokens.
+    pub fn push_tokens(&self, user: &str) -> Vec<PushToken> {
+        let all = self.push_tokens.lock().unwrap();
+        all.get(user).cloned().unwrap_or_default()
+    }
+
+    /// Adds an invite, first forgetting any that can no longer be
+    /// redeemed at its creation time and, past `MAX_INVITES_PER_ROOM`, the
+    /// oldest of its room's.
+    pub fn add_invite(&self, invite: Invite) {
+        let mut invites = self.invites.lock().unwrap();
+        invites.retain(|_, existing| existing.check(invite.created_at).is_ok());
+        let mut same_room: Vec<(u64, String)> = invites
+            .values()
+            .filter(|existing| existing.room == invite.room)
+            .map(|existing| (existing.created_at, existing.token.clone()))
+            .collect();
+        same_room.sort();
+        let excess = (same_room.len() + 1).saturating_sub(MAX_INVITES_PER_ROOM);
+        for (_, token) in same_room.into_iter().take(excess) {
+            invites.remove(&token);
+        }
+        invites.insert(invite.token.clone(), invite);
+    }
+
+    /// Returns the invite with `token`.
+    pub fn invite(&self, token: &str) -> Option<Invite> {
+        self.invites.lock().unwrap().get(token).cloned()
+    }
+
+    /// Counts a redemption of `token` at the Unix time `now`, if it has
+    /// any left.
+    pub fn use_invite(&self, token: &str, now: u64) -> Result<(), InviteError> {
+        let mut invites = self.invites.lock().unwrap();
+        let invite = invites.get_mut(token).ok_or(InviteError::Unknown)?;
+        invite.check(now)?;
+        invite.uses += 1;
+        Ok(())
+    }
+
+    /// Takes back a redemption counted by `use_invite` that fell through.
+    pub fn refund_invite(&self, token: &str) {
+        if let Some(invite) = self.invites.lock().unwrap().get_mut(token) {
+            invite.uses = invite.uses.saturating_sub(1);
+        }
+    }
+
+    /// Saves `user`'s draft in `room`. Past `max` drafts, the one saved
+    /// longest ago is dropped.
+    pub fn set_draft(&self, user: &str, room: &str, draft: Draft, max: usize) {
+        let mut all = self.drafts.lock().unwrap();
+        let drafts = all.entry(user.to_string()).or_default();
+        drafts.insert(room.to_string(), draft);
+        if drafts.len() > max
+            && let Some(oldest) = drafts
+                .iter()
+                .min_by_key(|(_, draft)| draft.updated_at)
+                .map(|(room, _)| room.clone())
+        {
+            drafts.remove(&oldest);
+        }
+    }
+
+    /// Discards `user`'s draft in `room`; returns false if there was none.
+    pub fn clear_draft(&self, user: &str, room: &str) -> bool {
+        let mut all = self.drafts.lock().unwrap();
+        let Some(drafts) = all.get_mut(user) else {
+            return false;
+        };
+        let removed = drafts.remove(room).is_some();
+        if drafts.is_empty() {
+            all.remove(user);
+        }
+        removed
+    }
+
+    /// Returns `user`'s drafts, by room.
+    pub fn drafts(&self, user: &str) -> BTreeMap<String, Draft> {
+        let all = self.drafts.lock().unwrap();
+        all.get(user).cloned().unwrap_or_default()
+    }
+
+    /// Copies the state worth keeping across restarts.
+    pub fn snapshot(&self) -> RegistrySnapshot {
+        RegistrySnapshot {
+            rooms: self.rooms.lock().unwrap().clone(),
+            nicks: self.nicks.lock().unwrap().clone(),
+            api_keys: self.api_keys.snapshot(),
+            bans: self.bans.lock().unwrap().clone(),
+            emoji: self.emoji.lock().unwrap().clone(),
+            blocks: self.blocks.lock().unwrap().clone(),
+            notification_prefs: self.notification_prefs.lock().unwrap().clone(),
+            push_tokens: self.push_tokens.lock().unwrap().clone(),
+            invites: self.invites.lock().unwrap().clone(),
+            drafts: self.drafts.lock().unwrap().clone(),
+        }
+    }
+
+    /// Loads state saved by `snapshot`, keeping anything configured since
+    /// startup that the snapshot doesn't mention.
+    pub fn restore(&self, snapshot: RegistrySnapshot) {
+        for (name, config) in snapshot.rooms {
+            self.set_room(name, config);
+        }
+        self.nicks.lock().unwrap().extend(snapshot.nicks);
+        self.api_keys.restore(snapshot.api_keys);
+        self.bans.lock().unwrap().extend(snapshot.bans);
+        self.emoji.lock().unwrap().extend(snapshot.emoji);
+        self.blocks.lock().unwrap().extend(snapshot.blocks);
+        self.notification_prefs
+            .lock()
+            .unwrap()
+            .extend(snapshot.notification_prefs);
+        self.push_tokens
+            .lock()
+            .unwrap()
+            .extend(snapshot.push_tokens);
+        self.invites.lock().unwrap().extend(snapshot.invites);
+        self.drafts.lock().unwrap().extend(snapshot.drafts);
+    }
+}
+
+fn lookalike_nick(nicks: &HashMap<String, String>, nick: &str) -> Option<String> {
+    if nicks.contains_key(nick) {
+        return Some(nick.to_string());
+    }
+    let skeleton = nickname::skeleton(nick);
+    nicks
+        .keys()
+        .find(|registered| nickname::skeleton(registered) == skeleton)
+        .cloned()
+}
+
+/// Merges each user's devices into one presence entry, shown with the
+/// profile of their most available device.
+fn aggregate(devices: &HashMap<SocketAddr, Device>) -> HashMap<String, UserPresence> {
+    let mut users: HashMap<String, UserPresence> = HashMap::new();
+    for (addr, device) in devices {
+        let presence = users
+            .entry(device.user(*addr))
+            .or_insert_with_key(|user| UserPresence {
+                user: user.clone(),
+                profile: device.profile.clone(),
+                devices: 0,
+            });
+        presence.devices += 1;
+        if availability(device.profile.state) < availability(presence.profile.state) {
+            presence.profile = device.profile.clone();
+        }
+    }
+    users
+}
+
+/// Lower is more available.
+fn availability(state: PresenceState) -> u8 {
+    match state {
+        PresenceState::Online => 0,
+        PresenceState::Away => 1,
+        PresenceState::Dnd => 2,
+    }
+}
@@ -0,0 +1,133 @@
+use crate::client::Client;
+use crate::protocol::{ChatMessage, MessageId};
+use crate::store::{MemoryStore, MessageStore};
+use anyhow::Result;
+use std::path::Path;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Messages read from the store per query.
+const REPLAY_BATCH: usize = 500;
+
+/// Which stored messages `replay` sends, where, and how fast.
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// Only messages from this room; every room when unset.
+    pub room: Option<String>,
+    /// Sends everything to this room instead of where it was first posted.
+    pub into_room: Option<String>,
+    /// Only messages relayed at or after this Unix time (seconds).
+    pub since: Option<u64>,
+    /// Only messages relayed before this Unix time (seconds).
+    pub until: Option<u64>,
+    /// How many times faster than the original pace to send. Zero or less
+    /// sends as fast as possible.
+    pub speed: f64,
+    /// Longest wait between two messages, however far apart they were sent.
+    pub max_gap: Option<Duration>,
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        ReplayOptions {
+            room: None,
+            into_room: None,
+            since: None,
+            until: None,
+            speed: 1.0,
+            max_gap: None,
+        }
+    }
+}
+
+impl ReplayOptions {
+    fn selects(&self, message: &ChatMessage) -> bool {
+        let timestamp = message.timestamp.unwrap_or_default();
+        self.room
+            .as_deref()
+            .is_none_or(|room| room == message.room())
+            && self.since.is_none_or(|since| timestamp >= since)
+            && self.until.is_none_or(|until| timestamp < until)
+    }
+
+    /// How long to wait between messages relayed at `previous` and `next`.
+    fn delay(&self, previous: Option<u64>, next: Option<u64>) -> Duration {
+        let (Some(previous), Some(next)) = (previous, next) else {
+            return Duration::ZERO;
+        };
+        if self.speed <= 0.0 {
+            return Duration::ZERO;
+        }
+        let delay = Duration::from_secs_f64(next.saturating_sub(previous) as f64 / self.speed);
+        self.max_gap.map_or(delay, |max_gap| delay.min(max_gap))
+    }
+}
+
+/// Sends the messages in `store` that `options` selects through `client`,
+/// oldest first, spaced out as they were originally. Senders are kept, so
+/// the server must let the client post as anyone (i.e. not require
+/// authentication). Returns how many messages were sent.
+pub async fn replay(
+    store: &dyn MessageStore,
+    client: &mut Client,
+    options: &ReplayOptions,
+) -> Result<u64> {
+    let mut after: MessageId = 0;
+    let mut previous = None;
+    let mut sent = 0;
+    loop {
+        let batch = store.after(after, REPLAY_BATCH).await?;
+        let Some(last) = batch.last() else {
+            break;
+        };
+        after = last.id.unwrap_or(after);
+        for message in batch.into_iter().filter(|message| options.selects(message)) {
+            tokio::time::sleep(options.delay(previous, message.timestamp)).await;
+            previous = message.timestamp;
+            let room = options
+                .into_room
+                .clone()
+                .unwrap_or_else(|| message.room().to_string());
+            let message = ChatMessage::builder()
+                .sender(message.sender)
+                .content(message.content)
+                .room(room)
+                .build()?;
+            debug!("Replaying {}", message);
+            client.send(message).await?;
+            sent += 1;
+        }
+    }
+    info!("Replayed {} messages", sent);
+    Ok(sent)
+}
+
+/// Reads a JSONL history export (as written by `chat-server export`) into
+/// a store that `replay` can read from.
+pub async fn read_export(path: impl AsRef<Path>) -> Result<MemoryStore> {
+    let store = MemoryStore::new(usize::MAX);
+    let export = tokio::fs::read_to_string(path).await?;
+    for line in export.lines().filter(|line| !line.trim().is_empty()) {
+        store.append(&serde_json::from_str(line)?).await?;
+    }
+    Ok(store)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_scales_and_caps() {
+        let mut options = ReplayOptions {
+            speed: 2.0,
+            ..ReplayOptions::default()
+        };
+        assert_eq!(options.delay(Some(100), Some(110)), Duration::from_secs(5));
+        assert_eq!(options.delay(None, Some(110)), Duration::ZERO);
+        options.max_gap = Some(Duration::from_secs(3));
+        assert_eq!(options.delay(Some(100), Some(110)), Duration::from_secs(3));
+        options.speed = 0.0;
+        assert_eq!(options.delay(Some(100), Some(110)), Duration::ZERO);
+    }
+}
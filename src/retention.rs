@@ -0,0 +1,72 @@
+use crate::metrics::Metrics;
+use crate::store::MessageStore;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{error, info};
+
+/// Limits on how much history a store keeps. Unset limits don't apply.
+#[derive(Debug, Clone)]
+pub struct RetentionPolicy {
+    /// Messages older than this are deleted.
+    pub max_age: Option<Duration>,
+    /// Only the newest this many messages are kept in each room.
+    pub max_messages_per_room: Option<usize>,
+    /// Oldest messages are deleted until the stored JSON encodings fit.
+    pub max_total_bytes: Option<u64>,
+    /// How often the background pruner runs.
+    pub interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy {
+            max_age: None,
+            max_messages_per_room: None,
+            max_total_bytes: None,
+            interval: Duration::from_secs(60),
+        }
+    }
+}
+
+/// What a prune pass removed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PruneStats {
+    pub messages: u64,
+    /// Size of the removed messages' JSON encodings.
+    pub bytes: u64,
+}
+
+impl PruneStats {
+    pub fn add(&mut self, other: PruneStats) {
+        self.messages += other.messages;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Applies `policy` to `store` every `policy.interval`, forever.
+pub async fn run_pruner(
+    store: Arc<dyn MessageStore>,
+    policy: RetentionPolicy,
+    metrics: Arc<Metrics>,
+) {
+    let mut ticker = tokio::time::interval(policy.interval);
+    loop {
+        ticker.tick().await;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        match store.prune(&policy, now).await {
+            Ok(stats) => {
+                if stats.messages > 0 {
+                    info!(
+                        "Pruned {} messages ({} bytes) from history",
+                        stats.messages, stats.bytes
+                    );
+                }
+                metrics.record_prune(stats);
+            }
+            Err(e) => error!("History pruning failed: {:?}", e),
+        }
+    }
+}
@@ -0,0 +1,422 @@
+use crate::protocol::MessageId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops::Bound;
+use std::str::FromStr;
+
+/// Most members listed when a room's settings are broadcast. Rooms with
+/// more are sent `MemberDelta`s as members come and go, and the rest of
+/// the list is fetched with `ListMembers`.
+pub const MAX_LISTED_MEMBERS: usize = 100;
+
+/// Per-room settings, configured on the server with `ChatServer::with_room`
+/// or by users with `/create`. Rooms that aren't configured use the defaults.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct RoomConfig {
+    /// Whether messages may carry a `ttl_secs` and expire.
+    pub allow_ephemeral: bool,
+    /// The user who created the room; may change permissions and appoint
+    /// moderators.
+    pub owner: Option<String>,
+    pub moderators: BTreeSet<String>,
+    /// Users invited with `/invite`.
+    pub members: BTreeSet<String>,
+    pub topic: Option<String>,
+    pub pinned: Vec<MessageId>,
+    pub permissions: RoomPermissions,
+    /// Announcement rooms are read-only for everyone below moderator,
+    /// whatever `permissions.post` says.
+    pub announcement: bool,
+    /// Most members the room admits; unlimited when unset.
+    pub max_members: Option<usize>,
+    /// Whether users who `/join` a full room are queued for a free slot.
+    pub waitlist_enabled: bool,
+    /// Users waiting for a slot, first in line first.
+    pub waitlist: Vec<String>,
+    pub delivery: DeliveryMode,
+    /// Whether the room's history is in cold storage. Archived rooms are
+    /// left out of listings, and restored when next used.
+    pub archived: bool,
+    /// Set for temporary rooms, which are destroyed with their history
+    /// once it says they've expired.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<RoomExpiry>,
+}
+
+/// When a temporary room expires: after going unused for `idle_secs`, or
+/// at `expires_at`, whichever comes first.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(default)]
+pub struct RoomExpiry {
+    /// Seconds without a message after which the room expires.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_secs: Option<u64>,
+    /// Unix time (seconds) the room expires regardless of use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Unix time (seconds) the room became temporary; idleness is counted
+    /// from here until a message is posted.
+    pub since: u64,
+}
+
+impl RoomExpiry {
+    /// Whether the room has expired at the Unix time `now`, its newest
+    /// message having been posted at `last_message`.
+    pub fn is_due(&self, last_message: Option<u64>, now: u64) -> bool {
+        let last_used = last_message.unwrap_or_default().max(self.since);
+        self.expires_at.is_some_and(|expires_at| now >= expires_at)
+            || self
+                .idle_secs
+                .is_some_and(|idle| now >= last_used.saturating_add(idle))
+    }
+}
+
+/// How hard the server tries to get a room's messages to its members.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    /// Messages go to whoever is connected; anything missed can be
+    /// replayed or backfilled from history.
+    #[default]
+    BestEffort,
+    /// Each message is also held for the owner, moderators and members
+    /// (other than its sender) until they acknowledge it with `Ack`, and
+    /// redelivered when they next sign in.
+    AtLeastOnce,
+}
+
+/// Who may do what in a room.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct RoomPermissions {
+    /// Who receives the room's messages and may read its history.
+    pub read: Role,
+    pub post: Role,
+    pub invite: Role,
+    pub set_topic: Role,
+    pub pin: Role,
+}
+
+impl Default for RoomPermissions {
+    fn default() -> Self {
+        RoomPermissions {
+            read: Role::Everyone,
+            post: Role::Everyone,
+            invite: Role::Members,
+            set_topic: Role::Moderators,
+            pin: Role::Moderators,
+        }
+    }
+}
+
+/// A user's standing in a room; each role includes those before it.
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    #[default]
+    Everyone,
+    Members,
+    Moderators,
+    Owner,
+}
+
+/// Something a `RoomPermissions` entry controls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoomAction {
+    Read,
+    Post,
+    Invite,
+    SetTopic,
+    Pin,
+}
+
+impl RoomConfig {
+    /// Returns `user`'s role in the room.
+    pub fn role_of(&self, user: &str) -> Role {
+        if self.owner.as_deref() == Some(user) {
+            Role::Owner
+        } else if self.moderators.contains(user) {
+            Role::Moderators
+        } else if self.members.contains(user) {
+            Role::Members
+        } else {
+            Role::Everyone
+        }
+    }
+
+    /// Returns the role `action` requires.
+    pub fn required_role(&self, action: RoomAction) -> Role {
+        match action {
+            RoomAction::Read => self.permissions.read,
+            RoomAction::Post if self.announcement => self.permissions.post.max(Role::Moderators),
+            RoomAction::Post => self.permissions.post,
+            RoomAction::Invite => self.permissions.invite,
+            RoomAction::SetTopic => self.permissions.set_topic,
+            RoomAction::Pin => self.permissions.pin,
+        }
+    }
+
+    /// Adds `user` as a member if there's a free slot. When the room is
+    /// full and `queue` is set and the room has a waitlist, `user` joins
+    /// the waitlist and the error holds their 1-based position.
+    pub fn add_member(&mut self, user: &str, queue: bool) -> Result<(), Option<usize>> {
+        if self.members.contains(user) {
+            return Ok(());
+        }
+        if self.max_members.is_none_or(|max| self.members.len() < max) {
+            self.members.insert(user.to_string());
+            return Ok(());
+        }
+        if !(queue && self.waitlist_enabled) {
+            return Err(None);
+        }
+        let position = match self.waitlist.iter().position(|waiting| waiting == user) {
+            Some(index) => index,
+            None => {
+                self.waitlist.push(user.to_string());
+                self.waitlist.len() - 1
+            }
+        };
+        Err(Some(position + 1))
+    }
+
+    /// Removes `user` from the members and the waitlist, returning anyone
+    /// admitted from the waitlist into the freed slot.
+    pub fn remove_member(&mut self, user: &str) -> Vec<String> {
+        self.members.remove(user);
+        self.waitlist.retain(|waiting| waiting != user);
+        self.fill_from_waitlist()
+    }
+
+    /// Admits waiting users into free slots, returning them in order.
+    pub fn fill_from_waitlist(&mut self) -> Vec<String> {
+        let free = self
+            .max_members
+            .map_or(usize::MAX, |max| max.saturating_sub(self.members.len()));
+        let admitted: Vec<String> = self
+            .waitlist
+            .drain(..free.min(self.waitlist.len()))
+            .collect();
+        self.members.extend(admitted.iter().cloned());
+        admitted
+    }
+
+    /// Returns the settings with at most `MAX_LISTED_MEMBERS` members, the
+    /// first by name, for broadcasting.
+    pub fn listed(&self) -> RoomConfig {
+        RoomConfig {
+            members: self
+                .members
+                .iter()
+                .take(MAX_LISTED_MEMBERS)
+                .cloned()
+                .collect(),
+            ..self.clone()
+        }
+    }
+
+    /// Returns up to `limit` members named after `after`, or the first
+    /// `limit`, in order.
+    pub fn members_after(&self, after: Option<&str>, limit: usize) -> Vec<String> {
+        let start = match after {
+            Some(after) => Bound::Excluded(after),
+            None => Bound::Unbounded,
+        };
+        self.members
+            .range::<str, _>((start, Bound::Unbounded))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Whether the room is listed in the directory: anyone may `/join` it
+    /// and it isn't archived.
+    pub fn is_public(&self) -> bool {
+        !self.archived && self.required_role(RoomAction::Invite) == Role::Everyone
+    }
+
+    /// Whether `other` differs from these settings only in its members.
+    pub fn same_but_members(&self, other: &RoomConfig) -> bool {
+        *self
+            == RoomConfig {
+                members: self.members.clone(),
+                ..other.clone()
+            }
+    }
+
+    /// Returns the owner, moderators and members.
+    pub fn participants(&self) -> impl Iterator<Item = &String> {
+        self.owner
+            .iter()
+            .chain(&self.moderators)
+            .chain(&self.members)
+    }
+
+    /// Returns whether `user` may perform `action`.
+    pub fn allows(&self, user: &str, action: RoomAction) -> bool {
+        self.role_of(user) >= self.required_role(action)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Role::Everyone => "everyone",
+            Role::Members => "members",
+            Role::Moderators => "moderators",
+            Role::Owner => "owner",
+        })
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "everyone" => Ok(Role::Everyone),
+            "members" => Ok(Role::Members),
+            "moderators" => Ok(Role::Moderators),
+            "owner" => Ok(Role::Owner),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for DeliveryMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DeliveryMode::BestEffort => "best-effort",
+            DeliveryMode::AtLeastOnce => "at-least-once",
+        })
+    }
+}
+
+impl FromStr for DeliveryMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "best-effort" => Ok(DeliveryMode::BestEffort),
+            "at-least-once" => Ok(DeliveryMode::AtLeastOnce),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for RoomAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RoomAction::Read => "read",
+            RoomAction::Post => "post",
+            RoomAction::Invite => "invite",
+            RoomAction::SetTopic => "topic",
+            RoomAction::Pin => "pin",
+        })
+    }
+}
+
+impl FromStr for RoomAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "read" => Ok(RoomAction::Read),
+            "post" => Ok(RoomAction::Post),
+            "invite" => Ok(RoomAction::Invite),
+            "topic" => Ok(RoomAction::SetTopic),
+            "pin" => Ok(RoomAction::Pin),
+            _ => Err(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waitlist_fills_freed_slots() {
+        let mut room = RoomConfig {
+            max_members: Some(1),
+            waitlist_enabled: true,
+            ..Default::default()
+        };
+        assert_eq!(room.add_member("avery", true), Ok(()));
+        assert_eq!(room.add_member("blake", false), Err(None));
+        assert_eq!(room.add_member("blake", true), Err(Some(1)));
+        assert_eq!(room.add_member("casey", true), Err(Some(2)));
+        assert_eq!(room.add_member("blake", true), Err(Some(1)));
+
+        assert_eq!(room.remove_member("avery"), vec!["blake".to_string()]);
+        assert_eq!(room.waitlist, vec!["casey".to_string()]);
+        room.max_members = Some(3);
+        assert_eq!(room.fill_from_waitlist(), vec!["casey".to_string()]);
+        assert_eq!(room.members.len(), 2);
+    }
+
+    #[test]
+    fn test_temporary_rooms_expire() {
+        let idle = RoomExpiry {
+            idle_secs: Some(60),
+            since: 1000,
+            ..Default::default()
+        };
+        assert!(!idle.is_due(None, 1059));
+        assert!(idle.is_due(None, 1060));
+        assert!(!idle.is_due(Some(1050), 1100));
+        assert!(idle.is_due(Some(1050), 1110));
+        let ttl = RoomExpiry {
+            expires_at: Some(2000),
+            ..idle
+        };
+        assert!(ttl.is_due(Some(1999), 2000));
+        assert!(!RoomExpiry::default().is_due(None, u64::MAX));
+    }
+
+    #[test]
+    fn test_members_are_listed_in_pages() {
+        let room = RoomConfig {
+            members: (0..250).map(|i| format!("user{:03}", i)).collect(),
+            ..Default::default()
+        };
+        assert_eq!(room.listed().members.len(), MAX_LISTED_MEMBERS);
+        assert!(room.listed().same_but_members(&room));
+        assert_eq!(
+            room.members_after(Some("user247"), 10),
+            vec!["user248".to_string(), "user249".to_string()]
+        );
+        assert_eq!(room.members_after(None, 1), vec!["user000".to_string()]);
+        let topical = RoomConfig {
+            topic: Some("news".to_string()),
+            ..room.clone()
+        };
+        assert!(!topical.same_but_members(&room));
+    }
+
+    #[test]
+    fn test_roles_include_lower_ones() {
+        let mut room = RoomConfig {
+            owner: Some("avery".to_string()),
+            ..Default::default()
+        };
+        room.moderators.insert("blake".to_string());
+        room.members.insert("casey".to_string());
+        room.permissions.post = Role::Members;
+
+        for user in ["avery", "blake", "casey"] {
+            assert!(room.allows(user, RoomAction::Post));
+        }
+        assert!(!room.allows("drew", RoomAction::Post));
+        assert!(room.allows("blake", RoomAction::Pin));
+        assert!(!room.allows("casey", RoomAction::SetTopic));
+
+        room.announcement = true;
+        assert!(room.allows("blake", RoomAction::Post));
+        assert!(!room.allows("casey", RoomAction::Post));
+    }
+}
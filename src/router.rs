@@ -0,0 +1,26 @@
+use crate::protocol::ChatMessage;
+use async_trait::async_trait;
+use std::net::SocketAddr;
+
+/// Where an inbound chat message goes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Route {
+    /// Relayed to everyone in its room, as without a router. The message
+    /// may have been changed, e.g. moved to another room.
+    Broadcast(ChatMessage),
+    /// Sent only to `to`'s connections and the sender, without being
+    /// stored or given a sequence number.
+    Direct { to: String, message: ChatMessage },
+    /// Discarded. The sender is sent the reason, if there is one.
+    Drop(Option<String>),
+}
+
+/// Decides where chat messages go, for topic-based routing, sharding or
+/// content-based delivery. Messages reach the router once the sender is
+/// resolved and before room permissions and quotas are checked; commands
+/// and control frames never do.
+#[async_trait]
+pub trait Router: Send + Sync {
+    /// Routes a message from the client at `from`.
+    async fn route(&self, from: SocketAddr, message: ChatMessage) -> Route;
+}
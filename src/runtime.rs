@@ -1,15 +1,74 @@
 use anyhow::Result;
+use std::future::Future;
 use tokio::runtime::Runtime;
 
+/// Which scheduler a runtime uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RuntimeFlavor {
+    /// Tasks are spread over a pool of worker threads.
+    #[default]
+    MultiThread,
+    /// Every task runs on the thread that calls `block_on`, for embedders
+    /// that can't spare threads.
+    CurrentThread,
+}
+
+/// Settings for `create_runtime_with`. The defaults match `create_runtime`.
+#[derive(Debug, Clone)]
+pub struct RuntimeConfig {
+    pub flavor: RuntimeFlavor,
+    /// Worker threads for `RuntimeFlavor::MultiThread`; ignored otherwise.
+    pub worker_threads: usize,
+    /// Stack size of worker and blocking threads, in bytes.
+    pub thread_stack_size: usize,
+    pub thread_name: String,
+    /// Most threads kept for blocking work such as password hashing.
+    pub max_blocking_threads: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        RuntimeConfig {
+            flavor: RuntimeFlavor::MultiThread,
+            worker_threads: num_cpus::get(),
+            thread_stack_size: 3 * 1024 * 1024, // 3MB stack for deep recursion
+            thread_name: "tokio-chat-worker".to_string(),
+            max_blocking_threads: 512,
+        }
+    }
+}
+
 /// Manually create a tokio runtime
 pub fn create_runtime() -> Result<Runtime> {
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(num_cpus::get())
-        .thread_name("tokio-chat-worker")
+    create_runtime_with(&RuntimeConfig::default())
+}
+
+/// Creates a tokio runtime from `config`.
+pub fn create_runtime_with(config: &RuntimeConfig) -> Result<Runtime> {
+    let mut builder = match config.flavor {
+        RuntimeFlavor::MultiThread => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(config.worker_threads.max(1));
+            builder
+        }
+        RuntimeFlavor::CurrentThread => tokio::runtime::Builder::new_current_thread(),
+    };
+    let runtime = builder
+        .thread_name(config.thread_name.clone())
         // TODO: loom?
-        .thread_stack_size(3 * 1024 * 1024) // 3MB stack for deep recursion
+        .thread_stack_size(config.thread_stack_size)
+        .max_blocking_threads(config.max_blocking_threads.max(1))
         // TODO: enable specific features
         .enable_all()
         .build()?;
     Ok(runtime)
 }
+
+/// Runs `server` to completion on a runtime built from `config`, e.g. an
+/// async block that creates and runs a `ChatServer`.
+pub fn run_server_with<F>(config: &RuntimeConfig, server: F) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    create_runtime_with(config)?.block_on(server)
+}
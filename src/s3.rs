@@ -0,0 +1,212 @@
+use crate::archive::ColdStore;
+use crate::blob::{BlobStore, is_valid_blob_id};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use aws_sdk_s3::Client;
+use aws_sdk_s3::config::{
+    BehaviorVersion, Credentials, Region, RequestChecksumCalculation, ResponseChecksumValidation,
+};
+use aws_sdk_s3::primitives::ByteStream;
+use aws_smithy_http_client::tls::{Provider, rustls_provider::CryptoMode};
+use bytes::Bytes;
+
+/// Where an S3 store keeps its objects, and how it signs in.
+#[derive(Debug, Clone)]
+pub struct S3Config {
+    pub bucket: String,
+    /// Prepended to every object key, e.g. `chat/blobs/`.
+    pub prefix: String,
+    pub region: String,
+    /// An S3-compatible service's URL, e.g. `http://localhost:9000` for
+    /// MinIO, addressed path-style. AWS's regional endpoint if unset.
+    pub endpoint: Option<String>,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+impl S3Config {
+    /// Settings for `bucket` from the standard `AWS_REGION`,
+    /// `AWS_ENDPOINT_URL`, `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and
+    /// `AWS_SESSION_TOKEN` environment variables. The region defaults to
+    /// `us-east-1`; the keys are required.
+    pub fn from_env(bucket: impl Into<String>) -> Result<Self> {
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+        let required = |name: &str| var(name).with_context(|| format!("{} is not set", name));
+        Ok(S3Config {
+            bucket: bucket.into(),
+            prefix: String::new(),
+            region: var("AWS_REGION").unwrap_or_else(|| "us-east-1".to_string()),
+            endpoint: var("AWS_ENDPOINT_URL"),
+            access_key_id: required("AWS_ACCESS_KEY_ID")?,
+            secret_access_key: required("AWS_SECRET_ACCESS_KEY")?,
+            session_token: var("AWS_SESSION_TOKEN"),
+        })
+    }
+}
+
+/// A bucket's objects under a prefix, which the S3 stores keep theirs in.
+struct Bucket {
+    client: Client,
+    bucket: String,
+    prefix: String,
+}
+
+impl Bucket {
+    fn new(config: S3Config) -> Self {
+        let http = aws_smithy_http_client::Builder::new()
+            .tls_provider(Provider::Rustls(CryptoMode::Ring))
+            .build_https();
+        let credentials = Credentials::new(
+            config.access_key_id,
+            config.secret_access_key,
+            config.session_token,
+            None,
+            "chat-server",
+        );
+        // Checksums only where S3 requires them, which S3-compatible
+        // stores that predate the newer checksum headers also accept.
+        let mut builder = aws_sdk_s3::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .http_client(http)
+            .region(Region::new(config.region))
+            .credentials_provider(credentials)
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            .response_checksum_validation(ResponseChecksumValidation::WhenRequired);
+        if let Some(endpoint) = config.endpoint {
+            builder = builder.endpoint_url(endpoint).force_path_style(true);
+        }
+        Bucket {
+            client: Client::from_conf(builder.build()),
+            bucket: config.bucket,
+            prefix: config.prefix,
+        }
+    }
+
+    async fn put_object(&self, key: &str, data: Bytes) -> Result<()> {
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{}", self.prefix, key))
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .with_context(|| format!("Failed to put {}{}", self.prefix, key))?;
+        Ok(())
+    }
+
+    async fn get_object(&self, key: &str) -> Result<Option<Bytes>> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{}", self.prefix, key))
+            .send()
+            .await;
+        match result {
+            Ok(output) => Ok(Some(output.body.collect().await?.into_bytes())),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_no_such_key()) => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to get {}{}", self.prefix, key)),
+        }
+    }
+
+    async fn contains_object(&self, key: &str) -> Result<bool> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{}", self.prefix, key))
+            .send()
+            .await;
+        match result {
+            Ok(_) => Ok(true),
+            Err(e) if e.as_service_error().is_some_and(|e| e.is_not_found()) => Ok(false),
+            Err(e) => Err(e).with_context(|| format!("Failed to look up {}{}", self.prefix, key)),
+        }
+    }
+
+    async fn delete_object(&self, key: &str) -> Result<()> {
+        // Deleting a missing object succeeds.
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(format!("{}{}", self.prefix, key))
+            .send()
+            .await
+            .with_context(|| format!("Failed to delete {}{}", self.prefix, key))?;
+        Ok(())
+    }
+}
+
+/// Keeps blobs as objects in an S3 bucket, or an S3-compatible store such
+/// as MinIO or R2, so attachments don't have to fit on the server's disk.
+/// Objects are named by their id under `S3Config::prefix`.
+pub struct S3BlobStore {
+    bucket: Bucket,
+}
+
+impl S3BlobStore {
+    pub fn new(config: S3Config) -> Self {
+        S3BlobStore {
+            bucket: Bucket::new(config),
+        }
+    }
+}
+
+fn blob_key(id: &str) -> Result<&str> {
+    if !is_valid_blob_id(id) {
+        return Err(anyhow!("Invalid blob id: {}", id));
+    }
+    Ok(id)
+}
+
+#[async_trait]
+impl BlobStore for S3BlobStore {
+    async fn put(&self, id: &str, data: Bytes) -> Result<()> {
+        self.bucket.put_object(blob_key(id)?, data).await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Bytes>> {
+        self.bucket.get_object(blob_key(id)?).await
+    }
+
+    async fn contains(&self, id: &str) -> Result<bool> {
+        self.bucket.contains_object(blob_key(id)?).await
+    }
+}
+
+/// Keeps room archives as objects in an S3 bucket, so archived history
+/// doesn't have to fit on the server's disk. Objects are named by the hex
+/// of the room name, as `DirColdStore` names its files, under
+/// `S3Config::prefix`.
+pub struct S3ColdStore {
+    bucket: Bucket,
+}
+
+impl S3ColdStore {
+    pub fn new(config: S3Config) -> Self {
+        S3ColdStore {
+            bucket: Bucket::new(config),
+        }
+    }
+}
+
+fn archive_key(room: &str) -> String {
+    let name: String = room.bytes().map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}.jsonl.gz", name)
+}
+
+#[async_trait]
+impl ColdStore for S3ColdStore {
+    async fn put(&self, room: &str, data: Bytes) -> Result<()> {
+        self.bucket.put_object(&archive_key(room), data).await
+    }
+
+    async fn get(&self, room: &str) -> Result<Option<Bytes>> {
+        self.bucket.get_object(&archive_key(room)).await
+    }
+
+    async fn remove(&self, room: &str) -> Result<()> {
+        self.bucket.delete_object(&archive_key(room)).await
+    }
+}
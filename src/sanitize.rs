@@ -0,0 +1,218 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::ops::RangeInclusive;
+use unicode_normalization::char::is_combining_mark;
+
+const ESC: char = '\u{1b}';
+const BEL: char = '\u{7}';
+
+/// How much of what clients send is cleaned before it's relayed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Strictness {
+    /// Only what `strip_escapes` removes, which `ChatMessage::validated`
+    /// removes from every message anyway.
+    #[default]
+    Standard,
+    /// `Standard`, plus invisible characters (zero-width spaces and
+    /// joiners, bidirectional overrides, tag characters and the like) are
+    /// stripped and runs of combining marks are cut short. Emoji joined
+    /// with zero-width joiners fall apart into their parts.
+    Strict,
+}
+
+/// What the server strips from message content, display names and status
+/// text before relaying them.
+#[derive(Debug, Clone)]
+pub struct SanitizePolicy {
+    pub strictness: Strictness,
+    /// Combining marks kept on one character under `Strictness::Strict`;
+    /// the rest of a longer run ("zalgo" text) is dropped.
+    pub max_combining_marks: usize,
+}
+
+impl Default for SanitizePolicy {
+    fn default() -> Self {
+        SanitizePolicy {
+            strictness: Strictness::default(),
+            max_combining_marks: 2,
+        }
+    }
+}
+
+impl SanitizePolicy {
+    /// Returns `text` with what the policy forbids removed, borrowing it
+    /// if there was nothing to remove.
+    pub fn sanitize<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        match self.strictness {
+            Strictness::Standard => clean(text, None),
+            Strictness::Strict => clean(text, Some(self.max_combining_marks)),
+        }
+    }
+}
+
+/// Strips terminal escape sequences (CSI, OSC and the rest, in 7-bit and
+/// 8-bit form) whole, and control characters other than newline and tab,
+/// so text can't move the cursor, retitle or recolor a terminal client.
+pub fn strip_escapes(text: &str) -> Cow<'_, str> {
+    clean(text, None)
+}
+
+/// Strips escapes and controls, and with `max_marks` also invisible
+/// characters and combining marks past `max_marks` in a row.
+fn clean(text: &str, max_marks: Option<usize>) -> Cow<'_, str> {
+    let strict = max_marks.is_some();
+    // Combining marks are fine one at a time; runs are checked below.
+    let allowed = |c: char| {
+        !is_forbidden_control(c) && !(strict && (is_invisible(c) || is_combining_mark(c)))
+    };
+    if text.chars().all(allowed) {
+        return Cow::Borrowed(text);
+    }
+    let mut cleaned = String::with_capacity(text.len());
+    let mut marks = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            ESC => skip_escape(&mut chars),
+            // 8-bit CSI, and the 8-bit string controls (DCS, SOS, OSC, PM,
+            // APC).
+            '\u{9b}' => skip_csi(&mut chars),
+            '\u{90}' | '\u{98}' | '\u{9d}' | '\u{9e}' | '\u{9f}' => skip_string(&mut chars),
+            c if is_forbidden_control(c) => {}
+            c if strict && is_invisible(c) => {}
+            c if strict && is_combining_mark(c) => {
+                marks += 1;
+                if max_marks.is_some_and(|max| marks <= max) {
+                    cleaned.push(c);
+                }
+            }
+            c => {
+                marks = 0;
+                cleaned.push(c);
+            }
+        }
+    }
+    Cow::Owned(cleaned)
+}
+
+/// Control characters, which includes ESC and the C1 range, apart from
+/// newline and tab.
+fn is_forbidden_control(c: char) -> bool {
+    c.is_control() && c != '\n' && c != '\t'
+}
+
+/// Characters that render as nothing but can hide or reorder text.
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{ad}'
+            | '\u{34f}'
+            | '\u{61c}'
+            | '\u{115f}'
+            | '\u{1160}'
+            | '\u{17b4}'
+            | '\u{17b5}'
+            | '\u{180e}'
+            | '\u{200b}'..='\u{200f}'
+            | '\u{202a}'..='\u{202e}'
+            | '\u{2060}'..='\u{206f}'
+            | '\u{3164}'
+            | '\u{fe00}'..='\u{fe0f}'
+            | '\u{feff}'
+            | '\u{ffa0}'
+            | '\u{fff9}'..='\u{fffb}'
+            | '\u{e0000}'..='\u{e0fff}'
+    )
+}
+
+/// Skips the rest of a sequence that began with ESC.
+fn skip_escape(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+    match chars.peek() {
+        Some('[') => {
+            chars.next();
+            skip_csi(chars);
+        }
+        Some(']' | 'P' | 'X' | '^' | '_') => {
+            chars.next();
+            skip_string(chars);
+        }
+        // Intermediate bytes, then one final byte.
+        Some(_) => {
+            skip_all(chars, '\u{20}'..='\u{2f}');
+            chars.next_if(|c| ('\u{30}'..='\u{7e}').contains(c));
+        }
+        None => {}
+    }
+}
+
+/// Skips a control sequence's parameter and intermediate bytes and its
+/// final byte.
+fn skip_csi(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+    skip_all(chars, '\u{20}'..='\u{3f}');
+    chars.next_if(|c| ('\u{40}'..='\u{7e}').contains(c));
+}
+
+/// Skips characters for as long as they're in `range`.
+fn skip_all(chars: &mut std::iter::Peekable<std::str::Chars<'_>>, range: RangeInclusive<char>) {
+    while chars.next_if(|c| range.contains(c)).is_some() {}
+}
+
+/// Skips a control string up to and including its terminator: BEL, ESC \
+/// or the 8-bit ST. An unterminated string runs to the end of the text.
+fn skip_string(chars: &mut std::iter::Peekable<std::str::Chars<'_>>) {
+    while let Some(c) = chars.next() {
+        match c {
+            BEL | '\u{9c}' => return,
+            ESC => {
+                chars.next_if_eq(&'\\');
+                return;
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(strictness: Strictness) -> SanitizePolicy {
+        SanitizePolicy {
+            strictness,
+            ..SanitizePolicy::default()
+        }
+    }
+
+    #[test]
+    fn test_escape_sequences_are_stripped() {
+        let standard = policy(Strictness::Standard);
+        assert!(matches!(
+            standard.sanitize("plain\ttext\nhere"),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(standard.sanitize("\u{1b}[31mred\u{1b}[0m"), "red");
+        assert_eq!(standard.sanitize("\u{1b}[2J\u{1b}[1;1Hcleared"), "cleared");
+        assert_eq!(
+            standard.sanitize("\u{1b}]0;pwned\u{7}title \u{1b}]8;;http://x\u{1b}\\link"),
+            "title link"
+        );
+        assert_eq!(standard.sanitize("\u{9b}31mbold\u{1b}7saved"), "boldsaved");
+        assert_eq!(standard.sanitize("back\u{8}\u{8}space\r"), "backspace");
+        assert_eq!(standard.sanitize("\u{1b}]unterminated"), "");
+        assert_eq!(standard.sanitize("a\u{200b}b"), "a\u{200b}b");
+    }
+
+    #[test]
+    fn test_strict_strips_invisible_text() {
+        let strict = policy(Strictness::Strict);
+        assert_eq!(strict.sanitize("ad\u{200b}min\u{feff}"), "admin");
+        assert_eq!(strict.sanitize("abc\u{202e}fdp.exe"), "abcfdp.exe");
+        assert_eq!(strict.sanitize("jos\u{301}e"), "jos\u{301}e");
+        assert_eq!(
+            strict.sanitize("z\u{300}\u{301}\u{302}\u{303}\u{304}a"),
+            "z\u{300}\u{301}a"
+        );
+        assert_eq!(strict.sanitize("caf\u{e9}"), "caf\u{e9}");
+    }
+}
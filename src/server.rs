@@ -1,72 +1,1413 @@
-use crate::protocol::ChatMessage;
+use crate::admin::{AdminControl, TailFeed, close_all};
+use crate::analytics::{Analytics, AnalyticsPolicy};
+use crate::announce::{AnnouncePolicy, Announcer};
+use crate::apikey::{ApiKeyInfo, ApiKeys};
+use crate::archive::{ArchivePolicy, ColdStore, MemoryColdStore, archive_room, restore_room};
+use crate::auth::{
+    Authenticator, GUEST_PREFIX, GuestPolicy, RoomGrant, generate_token, hash_password,
+    verify_password,
+};
+use crate::blob::{BlobStore, MAX_BLOB_SIZE, MemoryBlobStore, blob_id, is_valid_blob_id};
+use crate::challenge::{Challenge, ChallengePolicy, ChallengeTracker};
+#[cfg(feature = "chaos")]
+use crate::chaos::Chaos;
+use crate::codec::{CodecError, Encoded, FrameDecoder, WireFormat};
+use crate::command::Command;
+use crate::delivery::{AckTracker, DeadLetter, DeadLetterReason, DeadLetterStore};
+use crate::digest::{self, DigestPolicy, EmailDigests, Mailer};
+use crate::fanout::FanOut;
+use crate::hooks::ConnectionHooks;
+use crate::i18n::Catalogs;
+use crate::invite::{self, Invite};
+use crate::limits::{ConnectionLimits, IpCounter};
+use crate::memory::{Account, MemoryBudget, Overload};
+use crate::metrics::Metrics;
+use crate::nickname;
+use crate::onboarding::{Onboarding, OnboardingEvent, WebhookEvent, WelcomeActions, spawn_webhook};
+use crate::outbound::{OutboundQueue, QueueCapacity};
+use crate::preview::{LinkPreviewer, PageFetcher, PreviewPolicy};
+use crate::protocol::{
+    ActivityLevel, AnnouncementLevel, Capability, ChatMessage, ClientFrame, ConnectionDebugInfo,
+    DEFAULT_ROOM, Draft, FileRef, Forwarded, MAX_CONTENT_LEN, MAX_DRAFTS,
+    MAX_PRESENCE_SUBSCRIPTIONS, MAX_SAVED_MESSAGES, MessageId, NotificationPrefs, PresenceState,
+    PresenceSubscription, Profile, RoomListing, ServerFrame, UserPresence, ValidationError,
+    check_room_name,
+};
+use crate::push::{MAX_TOKEN_LEN, MAX_TOKENS_PER_USER, PushGateway, PushNotification, should_push};
+use crate::quota::{
+    QuotaExceeded, QuotaPolicy, QuotaTracker, ResourceExceeded, ResourcePolicy, ResourceTracker,
+};
+use crate::recording::{Direction, Recorder};
+use crate::registry::{Registry, RegistrySnapshot};
+use crate::retention::{RetentionPolicy, run_pruner};
+use crate::room::{DeliveryMode, MAX_LISTED_MEMBERS, Role, RoomAction, RoomConfig, RoomExpiry};
+use crate::router::{Route, Router};
+use crate::sanitize::SanitizePolicy;
+use crate::shortcode::Shortcodes;
+use crate::snapshot;
+use crate::store::{MemoryStore, MessageStore, ScheduledMessage};
+use crate::supervisor::Supervisor;
+use crate::tasks::{TaskRegistry, catch_panic};
+use crate::transport::{BoxStream, Listener};
+use crate::wal::Wal;
 use anyhow::Result;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::{Buf, Bytes, BytesMut};
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, RandomState};
+use std::io::IoSlice;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::broadcast;
-use tokio::time::{Duration, timeout};
-use tracing::{Level, debug, error, info, span};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio::task::AbortHandle;
+use tokio::task::coop::consume_budget;
+use tokio::time::{Duration, Instant, timeout};
+use tracing::{Level, debug, error, info, span, warn};
 use tracing_futures::Instrument;
 
+/// Longest line accepted before the connection is dropped.
+const MAX_LINE_LEN: usize = 64 * 1024;
+/// Raw bytes per file chunk, before base64 encoding.
+const FILE_CHUNK_SIZE: usize = 16 * 1024;
+/// Unacknowledged chunks a client may have in flight per upload.
+const FILE_WINDOW: usize = 4;
+/// Concurrent uploads allowed per connection.
+const MAX_UPLOADS: usize = 4;
+/// Concurrent `FetchFile` streams allowed per connection.
+const MAX_DOWNLOADS: usize = 4;
+/// History messages returned when the client doesn't ask for a limit.
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+/// Most history messages returned in one page.
+const MAX_HISTORY_LIMIT: usize = 200;
+/// Rooms listed in one page of the directory.
+const ROOMS_PAGE_SIZE: usize = 50;
+/// Members listed when the client doesn't ask for a limit.
+const DEFAULT_MEMBERS_LIMIT: usize = MAX_LISTED_MEMBERS;
+/// Most members listed in one page.
+const MAX_MEMBERS_LIMIT: usize = 1000;
+/// Most messages replayed in answer to one `Resume`.
+const MAX_REPLAY: usize = 500;
+/// Search results returned when the client doesn't ask for a limit.
+const DEFAULT_SEARCH_LIMIT: usize = 20;
+/// Most search results returned in one page.
+const MAX_SEARCH_LIMIT: usize = 100;
+/// How long a disconnected session can be resumed by default.
+const DEFAULT_SESSION_GRACE: Duration = Duration::from_secs(120);
+/// How long a closing connection may take to flush its queued frames.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+/// Most queued frames flushed with one vectored write; below `IOV_MAX`.
+const MAX_WRITE_BATCH: usize = 64;
+/// How long the shedder waits for closing connections to free memory.
+const SHED_RETRY: Duration = Duration::from_millis(100);
+/// How often held messages are checked against the unacked TTL.
+const ACK_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+/// How often temporary rooms are checked for expiry, unless configured.
+const ROOM_EXPIRY_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a client of a server with tenants may take to send its first
+/// line, unless a handshake timeout is configured.
+const TENANT_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often a draining server checks whether its clients have gone.
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+/// Task subsystems that only end when stopped, aborted once a drain is done.
+const STOPPED_ON_SHUTDOWN: [&str; 4] = ["background", "admin", "scheduled", "ephemeral"];
+/// How long a drained server waits for its remaining tasks to finish.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+/// Sent to a client before it's closed because its handler panicked.
+const PANIC_MESSAGE: &str = "Internal server error, disconnecting";
+/// How far ahead a message may be scheduled with `send_at`.
+const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
 pub struct ChatServer {
-    listener: TcpListener,
-    broadcast_tx: broadcast::Sender<String>,
+    /// `None` for a tenant, which is served on its host's listener.
+    listener: Option<Box<dyn Listener>>,
+    state: ServerState,
+    /// Isolated namespaces served on the same listener, by name.
+    tenants: HashMap<String, ServerState>,
+    #[cfg(unix)]
+    admin_socket: Option<PathBuf>,
+}
+
+/// State shared by every connection handler.
+struct ServerState {
+    fanout: Arc<FanOut>,
+    /// Bytes queued for clients, charged by each connection's queue.
+    memory: Arc<MemoryBudget>,
+    overload: Overload,
+    outbound_capacity: QueueCapacity,
+    #[cfg(feature = "chaos")]
+    chaos: Arc<Chaos>,
+    #[cfg(feature = "geoip")]
+    geoip: Option<Arc<crate::geoip::GeoFilter>>,
+    registry: Arc<Registry>,
+    blobs: Arc<dyn BlobStore>,
+    store: Arc<dyn MessageStore>,
+    next_message_id: AtomicU64,
+    /// The last sequence number used in each room, once known, each behind
+    /// its own lock; see `room_seq`.
+    room_seqs: Mutex<HashMap<String, Arc<tokio::sync::Mutex<Option<u64>>>>>,
+    /// Messages held for a future `send_at`, with the user who sent them.
+    scheduled: Mutex<HashMap<u64, (String, AbortHandle)>>,
+    next_pending_id: AtomicU64,
+    retention: Option<RetentionPolicy>,
+    /// Where idle rooms' history goes, and the policy for moving it.
+    cold: Arc<dyn ColdStore>,
+    archive: Option<ArchivePolicy>,
+    /// How often temporary rooms are checked for expiry.
+    room_expiry_interval: Duration,
+    metrics: Arc<Metrics>,
+    /// Every task spawned for this namespace; shared with `metrics`.
+    tasks: Arc<TaskRegistry>,
+    /// Restarts the background jobs if they fail.
+    supervisor: Arc<Supervisor>,
+    quotas: Arc<QuotaTracker>,
+    resources: Arc<ResourceTracker>,
+    /// Set once an operator drains the server.
+    draining: Arc<tokio::sync::watch::Sender<bool>>,
+    /// Relayed messages for operators tailing the server.
+    tail: Arc<TailFeed>,
+    analytics: Arc<Analytics>,
+    announcer: Arc<Announcer>,
+    onboarding: Onboarding,
+    /// Translations of server-generated text, by locale.
+    catalogs: Arc<Catalogs>,
+    /// Users sent the welcome direct message since the server started.
+    welcomed: Mutex<HashSet<String>>,
+    /// What's stripped from message content and profiles before relay.
+    sanitize: SanitizePolicy,
+    previews: Option<Arc<LinkPreviewer>>,
+    shortcodes: Option<Shortcodes>,
+    push: Option<Arc<PushGateway>>,
+    digests: Option<Arc<EmailDigests>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    hooks: Option<Arc<dyn ConnectionHooks>>,
+    router: Option<Arc<dyn Router>>,
+    guests: Option<GuestPolicy>,
+    /// Nicknames held by connected guests.
+    guest_names: Mutex<HashSet<String>>,
+    guest_quotas: QuotaTracker,
+    /// Disconnected sessions awaiting `ResumeSession`, by token.
+    sessions: Mutex<HashMap<String, SuspendedSession>>,
+    session_grace: Duration,
+    /// Messages from at-least-once rooms awaiting each user's `Ack`.
+    acks: Arc<AckTracker>,
+    dead_letters: Arc<DeadLetterStore>,
+    /// Inactivity after which users are marked away.
+    idle_after: Option<Duration>,
+    /// Open connections per IP address, against their limit.
+    ip_counter: Arc<IpCounter>,
+    handshake_timeout: Option<Duration>,
+    challenges: Option<ChallengeTracker>,
+    wal: Option<Wal>,
+    /// Where and how often to save registry snapshots.
+    snapshots: Option<(PathBuf, Duration)>,
+    /// Directory each connection's frames are recorded to.
+    recordings: Option<PathBuf>,
+    /// Users who may ask for `DebugInfo` about their connections.
+    debuggers: HashSet<String>,
+}
+
+/// Who a connection posts as.
+enum Identity {
+    /// No authentication is configured; messages keep the sender they name.
+    Open,
+    /// Authentication is required and hasn't happened yet.
+    Unauthenticated,
+    Guest(String),
+    User(String),
+}
+
+impl Identity {
+    /// The user this connection posts as, if it has one.
+    fn user(&self) -> Option<&str> {
+        match self {
+            Identity::Guest(user) | Identity::User(user) => Some(user),
+            Identity::Open | Identity::Unauthenticated => None,
+        }
+    }
+}
+
+/// State belonging to one connection.
+struct Connection {
+    identity: Identity,
+    /// Issued once the connection has an identity worth resuming.
+    resume_token: Option<String>,
+    /// The key the connection signed in with, limiting what it may do.
+    api_key: Option<ApiKeyInfo>,
+    /// A challenge the connection must solve before it can do anything.
+    challenge: Option<Challenge>,
+    uploads: HashMap<u64, Upload>,
+    next_transfer_id: u64,
+    /// `FetchFile` streams in progress, by file id.
+    downloads: HashMap<String, Download>,
+    last_active: Instant,
+    /// Set once the idle timeout fires, until the next activity.
+    idle: bool,
+    /// Whether going idle moved the connection from online to away.
+    auto_away: bool,
+    recorder: Option<Arc<Recorder>>,
+}
+
+/// A disconnected session that can be resumed until `expires_at`.
+struct SuspendedSession {
+    identity: Identity,
+    api_key: Option<ApiKeyInfo>,
+    profile: Profile,
+    presence_subscription: Option<PresenceSubscription>,
+    /// Newest message relayed before the disconnect.
+    last_id: MessageId,
+    expires_at: Instant,
+}
+
+/// An upload in progress on one connection.
+struct Upload {
+    room: String,
+    name: String,
+    size: u64,
+    hash: String,
+    data: BytesMut,
+    /// Charges `data` to the memory budget until the upload ends.
+    memory: Account,
+}
+
+/// A `FetchFile` stream in progress on one connection.
+struct Download {
+    data: Bytes,
+    /// Bytes sent so far.
+    sent: usize,
+    /// Bytes the client has acknowledged with `FileReceived`.
+    acked: usize,
+    /// Charges `data` to the memory budget until the stream ends.
+    memory: Account,
+}
+
+impl Download {
+    /// Starts a stream of `data`, charging it to `memory`.
+    fn new(data: Bytes, memory: Account) -> Self {
+        let download = Download {
+            data,
+            sent: 0,
+            acked: 0,
+            memory,
+        };
+        download.memory.charge(download.data.len());
+        download
+    }
+
+    /// Returns as many chunks as the window allows, then `FileEnd` once
+    /// the last one has been sent.
+    fn next_frames(&mut self, id: &str) -> Vec<ServerFrame> {
+        let mut frames = Vec::new();
+        while self.sent < self.data.len() && self.sent - self.acked < FILE_WINDOW * FILE_CHUNK_SIZE
+        {
+            let end = self.data.len().min(self.sent + FILE_CHUNK_SIZE);
+            frames.push(ServerFrame::FileChunk {
+                id: id.to_string(),
+                offset: self.sent as u64,
+                data: BASE64.encode(&self.data[self.sent..end]),
+            });
+            self.sent = end;
+        }
+        if self.finished() {
+            frames.push(ServerFrame::FileEnd {
+                id: id.to_string(),
+                size: self.data.len() as u64,
+            });
+        }
+        frames
+    }
+
+    fn finished(&self) -> bool {
+        self.sent == self.data.len()
+    }
 }
 
 impl ChatServer {
     pub async fn new(addr: &str) -> Result<Self> {
         let listener = TcpListener::bind(addr).await?;
-        let (broadcast_tx, _) = broadcast::channel(100);
         info!("Chat server bound to {}", addr);
-        Ok(ChatServer {
-            listener,
-            broadcast_tx,
-        })
+        Ok(ChatServer::from_listener(listener))
+    }
+
+    /// Creates a server taking its clients from `listener` rather than
+    /// binding a TCP listener of its own.
+    pub fn from_listener(listener: impl Listener + 'static) -> Self {
+        ChatServer {
+            listener: Some(Box::new(listener)),
+            ..ChatServer::tenant()
+        }
+    }
+
+    /// Creates a server without a listener of its own, configured like any
+    /// other and hosted by another server through `with_tenant`.
+    pub fn tenant() -> Self {
+        let registry = Arc::new(Registry::new());
+        let metrics = Arc::new(Metrics::new());
+        ChatServer {
+            listener: None,
+            tenants: HashMap::new(),
+            #[cfg(unix)]
+            admin_socket: None,
+            state: ServerState {
+                fanout: Arc::new(FanOut::default()),
+                memory: Arc::new(MemoryBudget::default()),
+                overload: Overload::default(),
+                outbound_capacity: QueueCapacity::default(),
+                #[cfg(feature = "chaos")]
+                chaos: Arc::new(Chaos::new()),
+                #[cfg(feature = "geoip")]
+                geoip: None,
+                resources: Arc::new(ResourceTracker::new(
+                    ResourcePolicy::default(),
+                    registry.clone(),
+                )),
+                registry,
+                draining: Arc::new(tokio::sync::watch::Sender::new(false)),
+                tail: Arc::new(TailFeed::default()),
+                analytics: Arc::new(Analytics::default()),
+                announcer: Arc::new(Announcer::default()),
+                onboarding: Onboarding::default(),
+                catalogs: Arc::new(Catalogs::new()),
+                welcomed: Mutex::new(HashSet::new()),
+                sanitize: SanitizePolicy::default(),
+                previews: None,
+                shortcodes: None,
+                push: None,
+                digests: None,
+                blobs: Arc::new(MemoryBlobStore::new()),
+                store: Arc::new(MemoryStore::default()),
+                next_message_id: AtomicU64::new(1),
+                room_seqs: Mutex::new(HashMap::new()),
+                scheduled: Mutex::new(HashMap::new()),
+                next_pending_id: AtomicU64::new(1),
+                retention: None,
+                cold: Arc::new(MemoryColdStore::new()),
+                archive: None,
+                room_expiry_interval: ROOM_EXPIRY_INTERVAL,
+                tasks: metrics.tasks(),
+                supervisor: Arc::new(Supervisor::new(metrics.tasks())),
+                metrics,
+                quotas: Arc::new(QuotaTracker::default()),
+                authenticator: None,
+                hooks: None,
+                router: None,
+                guests: None,
+                guest_names: Mutex::new(HashSet::new()),
+                guest_quotas: QuotaTracker::default(),
+                sessions: Mutex::new(HashMap::new()),
+                session_grace: DEFAULT_SESSION_GRACE,
+                acks: Arc::new(AckTracker::default()),
+                dead_letters: Arc::new(DeadLetterStore::default()),
+                idle_after: None,
+                ip_counter: Arc::new(IpCounter::default()),
+                handshake_timeout: None,
+                challenges: None,
+                wal: None,
+                snapshots: None,
+                recordings: None,
+                debuggers: HashSet::new(),
+            },
+        }
+    }
+
+    /// Replaces the in-memory store used for uploaded files.
+    pub fn with_blob_store(mut self, blobs: Arc<dyn BlobStore>) -> Self {
+        self.state.blobs = blobs;
+        self
+    }
+
+    /// Configures a room's settings.
+    pub fn with_room(self, name: impl Into<String>, config: RoomConfig) -> Self {
+        self.state.registry.set_room(name, config);
+        self
+    }
+
+    /// Replaces the in-memory store used for chat history.
+    pub fn with_message_store(mut self, store: Arc<dyn MessageStore>) -> Self {
+        self.state.store = store;
+        self
+    }
+
+    /// Prunes stored history in the background according to `policy`.
+    pub fn with_retention(mut self, policy: RetentionPolicy) -> Self {
+        self.state.retention = Some(policy);
+        self
+    }
+
+    /// Moves the history of rooms idle for `policy.idle` to `cold`, in the
+    /// background. Archived rooms are restored when next posted to, joined or
+    /// read.
+    pub fn with_archiving(mut self, cold: Arc<dyn ColdStore>, policy: ArchivePolicy) -> Self {
+        self.state.cold = cold;
+        self.state.archive = Some(policy);
+        self
+    }
+
+    /// Checks temporary rooms for expiry every `interval` rather than every
+    /// 30 seconds.
+    pub fn with_room_expiry_interval(mut self, interval: Duration) -> Self {
+        self.state.room_expiry_interval = interval;
+        self
+    }
+
+    /// Limits how often announcements go out; see `ChatServer::announce`.
+    pub fn with_announce_policy(mut self, policy: AnnouncePolicy) -> Self {
+        self.state.announcer = Arc::new(Announcer::new(policy));
+        self
+    }
+
+    /// Greets arriving users and new room members as `onboarding` says.
+    pub fn with_onboarding(mut self, onboarding: Onboarding) -> Self {
+        self.state.onboarding = onboarding;
+        self
+    }
+
+    /// Translates errors, notices and messages of the day into the locale
+    /// each client asks for with `SetLocale`. Tenants added before this is
+    /// called use the same catalogs.
+    pub fn with_catalogs(mut self, catalogs: Catalogs) -> Self {
+        let catalogs = Arc::new(catalogs);
+        for tenant in self.tenants.values_mut() {
+            tenant.catalogs = catalogs.clone();
+        }
+        self.state.catalogs = catalogs;
+        self
+    }
+
+    /// Changes what's stripped from message content, display names and
+    /// status text before they're relayed; escape sequences and control
+    /// characters by default.
+    pub fn with_sanitize_policy(mut self, policy: SanitizePolicy) -> Self {
+        self.state.sanitize = policy;
+        self
+    }
+
+    /// Expands `:name:` shortcodes in chat messages (not commands) before
+    /// they're routed and relayed.
+    pub fn with_shortcodes(mut self, shortcodes: Shortcodes) -> Self {
+        self.state.shortcodes = Some(shortcodes);
+        self
+    }
+
+    /// Follows messages with links in them with a `LinkPreview` of each
+    /// linked page, fetched by `fetcher` in the background.
+    pub fn with_link_previews(
+        mut self,
+        fetcher: Arc<dyn PageFetcher>,
+        policy: PreviewPolicy,
+    ) -> Self {
+        self.state.previews = Some(Arc::new(LinkPreviewer::new(fetcher, policy)));
+        self
+    }
+
+    /// Pushes notifications through `gateway` to users who registered a
+    /// push token, while they're offline or in do-not-disturb.
+    pub fn with_push(mut self, gateway: PushGateway) -> Self {
+        self.state.push = Some(Arc::new(gateway));
+        self
+    }
+
+    /// Emails users who opted in a digest of the mentions and direct
+    /// messages they missed while offline, through `mailer`.
+    pub fn with_email_digests(mut self, mailer: Arc<dyn Mailer>, policy: DigestPolicy) -> Self {
+        let digests = EmailDigests::new(mailer, policy, self.state.registry.clone());
+        self.state.digests = Some(Arc::new(digests));
+        self
+    }
+
+    /// Buckets per-room activity as `policy` says, for `AdminControl::analytics`.
+    pub fn with_analytics(mut self, policy: AnalyticsPolicy) -> Self {
+        self.state.analytics = Arc::new(Analytics::new(policy));
+        self
+    }
+
+    /// Limits how much each sender may post per hour and day.
+    pub fn with_quotas(mut self, policy: QuotaPolicy) -> Self {
+        self.state.quotas = Arc::new(QuotaTracker::new(policy));
+        self
+    }
+
+    /// Limits the rooms, members, history and message rate of this server
+    /// or tenant, shared by everyone in it.
+    pub fn with_resource_limits(mut self, policy: ResourcePolicy) -> Self {
+        self.state.resources = Arc::new(ResourceTracker::new(policy, self.state.registry.clone()));
+        self
+    }
+
+    /// Requires clients to authenticate with `ClientFrame::Authenticate`
+    /// before sending messages, which are then attributed to the
+    /// authenticated user.
+    pub fn with_authenticator(mut self, authenticator: Arc<dyn Authenticator>) -> Self {
+        self.state.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Calls `hooks` as clients connect, authenticate and disconnect.
+    pub fn with_hooks(mut self, hooks: Arc<dyn ConnectionHooks>) -> Self {
+        self.state.hooks = Some(hooks);
+        self
+    }
+
+    /// Lets `router` decide where each chat message goes instead of
+    /// broadcasting it to its room.
+    pub fn with_router(mut self, router: Arc<dyn Router>) -> Self {
+        self.state.router = Some(router);
+        self
+    }
+
+    /// Lets unauthenticated clients post as guests with generated
+    /// nicknames, restricted by `policy`.
+    pub fn with_guests(mut self, policy: GuestPolicy) -> Self {
+        self.state.guest_quotas = QuotaTracker::new(policy.quota.clone());
+        self.state.guests = Some(policy);
+        self
+    }
+
+    /// Sets how long after a disconnect a session can be resumed with its
+    /// token (two minutes by default).
+    pub fn with_session_grace(mut self, grace: Duration) -> Self {
+        self.state.session_grace = grace;
+        self
+    }
+
+    /// Marks a connection away after `idle` without activity, and back
+    /// online when it next sends something other than a `Heartbeat`.
+    /// Connections set to do-not-disturb are left alone.
+    pub fn with_idle_timeout(mut self, idle: Duration) -> Self {
+        self.state.idle_after = Some(idle);
+        self
+    }
+
+    /// Lets `users` ask for `DebugInfo`, the server's view of the
+    /// connection they ask on. Nobody may by default, since it shows
+    /// quotas and internal queue state.
+    pub fn with_debuggers<I, S>(mut self, users: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.state.debuggers = users.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Caps connections per IP address and closes connections that don't
+    /// complete their handshake in time, as `limits` says.
+    pub fn with_connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.state.ip_counter = Arc::new(IpCounter::new(limits.max_per_ip));
+        self.state.handshake_timeout = limits.handshake_timeout;
+        self
+    }
+
+    /// Closes connections from addresses `filter`'s rules refuse as soon
+    /// as they're accepted, reloading its files as they change.
+    #[cfg(feature = "geoip")]
+    pub fn with_geoip(mut self, filter: crate::geoip::GeoFilter) -> Self {
+        self.state.geoip = Some(Arc::new(filter));
+        self
+    }
+
+    /// Makes connections from busy IP addresses solve a proof-of-work
+    /// challenge before anything else they send is accepted.
+    pub fn with_challenges(mut self, policy: ChallengePolicy) -> Self {
+        self.state.challenges = Some(ChallengeTracker::new(policy));
+        self
+    }
+
+    /// Sets how many messages at-least-once rooms hold for a user who
+    /// hasn't acknowledged them, and for how long, before dead-lettering
+    /// them. Defaults to 1000 messages for a week.
+    pub fn with_unacked_limits(mut self, max_per_user: usize, ttl: Duration) -> Self {
+        self.state.acks = Arc::new(AckTracker::new(max_per_user, ttl));
+        self
+    }
+
+    /// Sets how many worker tasks copy broadcasts into client queues (one
+    /// per CPU by default).
+    pub fn with_fanout_workers(mut self, workers: NonZeroUsize) -> Self {
+        self.state.fanout = Arc::new(FanOut::new(workers));
+        self
+    }
+
+    /// Sets how many chat frames a slow client may fall behind by before
+    /// the oldest are dropped, optionally growing under bursts. Defaults to
+    /// a fixed 1024.
+    pub fn with_outbound_capacity(mut self, capacity: QueueCapacity) -> Self {
+        self.state.outbound_capacity = capacity;
+        self
+    }
+
+    /// Caps the bytes queued for clients across all connections at
+    /// `limit`, handling overruns as `overload` says. Unlimited by default.
+    pub fn with_memory_limit(mut self, limit: usize, overload: Overload) -> Self {
+        self.state.memory = Arc::new(MemoryBudget::new(limit));
+        self.state.overload = overload;
+        self
+    }
+
+    /// Logs accepted messages to `wal` before broadcasting them; anything
+    /// the log recovered is written to the message store when the server
+    /// starts.
+    pub fn with_wal(mut self, wal: Wal) -> Self {
+        self.state.wal = Some(wal);
+        self
+    }
+
+    /// Restores rooms, moderation state and unacknowledged messages from the
+    /// snapshot at `path` on startup, then saves them there every `interval`
+    /// and once more when the server is drained.
+    pub fn with_snapshots(mut self, path: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.state.snapshots = Some((path.into(), interval));
+        self
+    }
+
+    /// Records every frame each connection sends and receives to its own
+    /// file in `dir`, with secrets redacted; see `crate::recording`.
+    pub fn with_recordings(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.state.recordings = Some(dir.into());
+        self
+    }
+
+    /// Returns the server's counters.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.state.metrics.clone()
+    }
+
+    /// Returns per-sender usage counters, tracked whether or not quotas
+    /// are configured.
+    pub fn quotas(&self) -> Arc<QuotaTracker> {
+        self.state.quotas.clone()
+    }
+
+    /// Returns the room resource limits and their usage, tracked whether or
+    /// not limits are configured.
+    pub fn resources(&self) -> Arc<ResourceTracker> {
+        self.state.resources.clone()
+    }
+
+    /// Returns the API keys service accounts and bots sign in with, to
+    /// issue, rotate and revoke them, e.g. from the admin HTTP routes.
+    pub fn api_keys(&self) -> Arc<ApiKeys> {
+        self.state.registry.api_keys()
+    }
+
+    /// Returns operator controls: kicking and banning users, sending
+    /// notices and announcements, and listing connections.
+    pub fn admin(&self) -> AdminControl {
+        AdminControl {
+            registry: self.state.registry.clone(),
+            fanout: self.state.fanout.clone(),
+            metrics: self.state.metrics.clone(),
+            resources: self.state.resources.clone(),
+            draining: self.state.draining.clone(),
+            supervisor: self.state.supervisor.clone(),
+            feed: self.state.tail.clone(),
+            analytics: self.state.analytics.clone(),
+            announcer: self.state.announcer.clone(),
+            catalogs: self.state.catalogs.clone(),
+            blobs: Some(self.state.blobs.clone()),
+            store: Some(self.state.store.clone()),
+            cold: Some(self.state.cold.clone()),
+        }
+    }
+
+    /// Sends a system announcement to everyone, or to `room`'s viewers,
+    /// apart from chat. Identical announcements within the announce
+    /// policy's window are coalesced, and returns false when this one was
+    /// held back. Keep `admin()` to announce once the server is running.
+    pub fn announce(
+        &self,
+        room: Option<&str>,
+        text: &str,
+        level: AnnouncementLevel,
+    ) -> Result<bool> {
+        self.admin().announce(room, text, level)
+    }
+
+    /// Returns the messages that couldn't be delivered, e.g. to share with
+    /// the admin HTTP routes.
+    pub fn dead_letters(&self) -> Arc<DeadLetterStore> {
+        self.state.dead_letters.clone()
+    }
+
+    /// Returns the email digests, if they're enabled, to send those due
+    /// without waiting for the background job.
+    pub fn digests(&self) -> Option<Arc<EmailDigests>> {
+        self.state.digests.clone()
+    }
+
+    /// Returns the fault injector, to change what it injects while the
+    /// server runs.
+    #[cfg(feature = "chaos")]
+    pub fn chaos(&self) -> Arc<Chaos> {
+        self.state.chaos.clone()
+    }
+
+    /// Returns the memory budget, for per-connection and total usage.
+    pub fn memory(&self) -> Arc<MemoryBudget> {
+        self.state.memory.clone()
+    }
+
+    /// Returns the store used for uploaded files, e.g. to share it with
+    /// the HTTP endpoint.
+    pub fn blob_store(&self) -> Arc<dyn BlobStore> {
+        self.state.blobs.clone()
+    }
+
+    /// Returns the address the server is listening on.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        match &self.listener {
+            Some(listener) => Ok(listener.local_addr()?),
+            None => Err(anyhow::anyhow!("A tenant has no listener")),
+        }
     }
 
+    /// Serves `tenant` as an isolated namespace on this server's listener:
+    /// its rooms, users, API keys and quotas are its own. Clients pick it
+    /// with a `SelectTenant` frame before anything else. Once any tenant is
+    /// added, clients of this server's own namespace must also send
+    /// something before they're greeted. The per-IP connection cap is this
+    /// server's, shared across tenants.
+    pub fn with_tenant(mut self, name: impl Into<String>, tenant: ChatServer) -> Self {
+        self.tenants.insert(name.into(), tenant.state);
+        self
+    }
+
+    /// Serves `AdminControl` requests on a Unix socket at `path`, for the
+    /// `chat-server admin` commands. A stale socket file there is replaced.
+    #[cfg(unix)]
+    pub fn with_admin_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.admin_socket = Some(path.into());
+        self
+    }
+
+    /// Accepts and serves clients until an error occurs, or until the
+    /// server is drained and every client has gone.
     pub async fn run(self) -> Result<()> {
+        #[cfg(unix)]
+        if let Some(path) = &self.admin_socket {
+            let _ = std::fs::remove_file(path);
+            let listener = tokio::net::UnixListener::bind(path)?;
+            info!("Admin socket bound to {}", path.display());
+            self.state.tasks.spawn(
+                "admin",
+                "admin socket",
+                crate::admin::serve_socket(listener, self.admin()),
+            );
+        }
+        let Some(listener) = self.listener else {
+            return Err(anyhow::anyhow!("A tenant can't run on its own"));
+        };
+        let state = start(self.state).await?;
+        let mut tenants = HashMap::new();
+        for (name, tenant) in self.tenants {
+            tenants.insert(name, start(tenant).await?);
+        }
+        let tenants = Arc::new(tenants);
+        let mut draining = state.draining.subscribe();
         loop {
-            let (socket, addr) = self.listener.accept().await?;
-            let broadcast_tx = self.broadcast_tx.clone();
-            let broadcast_rx = broadcast_tx.subscribe();
+            let (mut socket, addr) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                _ = draining.wait_for(|draining| *draining) => break,
+            };
+            #[cfg(feature = "geoip")]
+            if let Some(geoip) = &state.geoip
+                && !geoip.admits(addr.ip())
+            {
+                info!("Refused connection from {} by GeoIP rules", addr.ip());
+                state.metrics.record_rejected_connection();
+                continue;
+            }
+            let Some(slot) = state.ip_counter.acquire(addr.ip()) else {
+                warn!("Too many connections from {}, closing", addr.ip());
+                state.metrics.record_rejected_connection();
+                continue;
+            };
+            let host = state.clone();
+            let tenants = tenants.clone();
             info!("Accepted connection from {}", addr);
 
-            tokio::spawn(
-                handle_client(socket, addr, broadcast_tx, broadcast_rx)
-                    .instrument(span!(Level::INFO, "handle_client", client_addr = %addr)),
+            state.tasks.spawn(
+                "clients",
+                &format!("client {}", addr),
+                async move {
+                    let (decoder, state) = if tenants.is_empty() {
+                        (FrameDecoder::new(MAX_LINE_LEN), host)
+                    } else {
+                        match select_tenant(&mut socket, addr, &host, &tenants).await? {
+                            Some(selected) => selected,
+                            None => return Ok(()),
+                        }
+                    };
+                    state.registry.register(addr);
+                    state
+                        .analytics
+                        .record_connections(state.registry.connection_count(), unix_time());
+                    let result = catch_panic(handle_client(socket, decoder, addr, &state))
+                        .await
+                        .unwrap_or_else(|panic| {
+                            handler_panicked(&state, addr, &panic);
+                            Err(anyhow::anyhow!("Handler panicked: {}", panic))
+                        });
+                    state.registry.unregister(addr);
+                    state
+                        .analytics
+                        .record_connections(state.registry.connection_count(), unix_time());
+                    state.metrics.remove_connection(addr);
+                    drop(slot);
+                    result
+                }
+                .instrument(span!(Level::INFO, "handle_client", client_addr = %addr)),
             );
         }
+        drop(listener);
+        // Keep closing until everyone's gone: connections accepted just
+        // before the drain may only now be subscribing.
+        let namespaces: Vec<&Arc<ServerState>> =
+            std::iter::once(&state).chain(tenants.values()).collect();
+        loop {
+            for namespace in &namespaces {
+                close_all(&namespace.registry, &namespace.fanout);
+            }
+            let open: usize = namespaces
+                .iter()
+                .map(|namespace| namespace.registry.connections().len())
+                .sum();
+            if open == 0 {
+                break;
+            }
+            tokio::time::sleep(DRAIN_POLL).await;
+        }
+        for namespace in &namespaces {
+            stop(namespace).await;
+        }
+        info!("Drained");
+        Ok(())
+    }
+}
+
+/// Stops a drained namespace's jobs and waits for the rest of its tasks,
+/// logging any that outlive `SHUTDOWN_GRACE`.
+async fn stop(state: &ServerState) {
+    for subsystem in STOPPED_ON_SHUTDOWN {
+        state.tasks.abort(subsystem);
+    }
+    // Lets the room persister finish what's queued, then end.
+    state.registry.unwatch_rooms();
+    for task in state.tasks.join(SHUTDOWN_GRACE).await {
+        warn!(
+            "Task {} ({}) still running {:?} after shutdown, aborted",
+            task.name, task.subsystem, task.age
+        );
+    }
+    // Keep what changed since the last periodic snapshot.
+    if let Some((path, _)) = &state.snapshots {
+        let snapshot = snapshot::Snapshot::take(&state.registry, &state.acks);
+        if let Err(e) = snapshot::save(path, &snapshot).await {
+            error!("Failed to save snapshot to {}: {:?}", path.display(), e);
+        }
+    }
+}
+
+/// Restores persisted state and starts the background tasks of one
+/// namespace.
+async fn start(state: ServerState) -> Result<Arc<ServerState>> {
+    if let Some((path, interval)) = state.snapshots.clone() {
+        snapshot::restore(&state.registry, &state.acks, &path).await?;
+        let (registry, acks) = (state.registry.clone(), state.acks.clone());
+        state.supervisor.supervise("snapshots", move || {
+            snapshot::run_snapshots(registry.clone(), acks.clone(), path.clone(), interval)
+        });
+    }
+    // Registrations are written through to the store, which outranks a
+    // snapshot that may predate them.
+    state.registry.restore(RegistrySnapshot {
+        nicks: state.store.nicks().await?.into_iter().collect(),
+        ..Default::default()
+    });
+    // So are room settings.
+    for (name, config) in state.store.rooms().await? {
+        state.registry.set_room(name, config);
+    }
+    let changes = state.registry.watch_rooms();
+    for (name, config) in state.registry.snapshot().rooms {
+        state.store.put_room(&name, &config).await?;
+    }
+    state.tasks.spawn(
+        "rooms",
+        "room persister",
+        persist_rooms(state.registry.clone(), state.store.clone(), changes),
+    );
+    if let Some(wal) = &state.wal {
+        // Anything at or below the store's last id was persisted before
+        // the crash; only the ack was lost.
+        let stored = state.store.last_id().await?.unwrap_or_default();
+        for message in wal.take_recovered().await {
+            let id = message.id.unwrap_or_default();
+            if id > stored {
+                state.store.append(&message).await?;
+            }
+            wal.ack(id).await?;
+        }
+    }
+    // Ids of deleted messages stay used: clients resume from them, and
+    // archived rooms are restored with them.
+    let marks = state.store.high_waters().await?;
+    let last_id = marks
+        .iter()
+        .map(|(_, mark)| mark.id)
+        .chain(state.store.last_id().await?)
+        .max();
+    if let Some(last_id) = last_id {
+        state.next_message_id.store(last_id + 1, Ordering::Relaxed);
+    }
+    if let Some(policy) = state.retention.clone() {
+        let (store, metrics) = (state.store.clone(), state.metrics.clone());
+        state.supervisor.supervise("pruner", move || {
+            run_pruner(store.clone(), policy.clone(), metrics.clone())
+        });
+    }
+    state.fanout.start(
+        state.metrics.clone(),
+        #[cfg(feature = "chaos")]
+        state.chaos.clone(),
+    );
+    let scheduled = state.store.scheduled().await?;
+    if let Some(last) = scheduled.last() {
+        state
+            .next_pending_id
+            .store(last.pending_id + 1, Ordering::Relaxed);
+    }
+    let state = Arc::new(state);
+    // Expiries are timers, so the ones pending when the server stopped are
+    // set again from the stored messages; overdue ones fire straight away.
+    for message in state.store.ephemeral().await? {
+        if let (Some(id), Some(ttl_secs)) = (message.id, message.ttl_secs) {
+            let expires_at = message
+                .timestamp
+                .unwrap_or_default()
+                .saturating_add(ttl_secs);
+            let ttl = Duration::from_secs(expires_at.saturating_sub(unix_time()));
+            schedule_expiry(state.clone(), message.room().to_string(), id, ttl);
+        }
+    }
+    // So are held messages; overdue ones go out straight away.
+    for scheduled in scheduled {
+        let send_at = scheduled.message.send_at.unwrap_or_default();
+        let delay = Duration::from_secs(send_at.saturating_sub(unix_time()));
+        arm_scheduled(state.clone(), scheduled, delay);
+    }
+    let supervisor = &state.supervisor;
+    let job_state = state.clone();
+    supervisor.supervise("ack expiry", move || run_ack_expiry(job_state.clone()));
+    let job_state = state.clone();
+    supervisor.supervise("room expiry", move || run_room_expiry(job_state.clone()));
+    if let Some(digests) = state.digests.clone() {
+        supervisor.supervise("digests", move || digest::run_digests(digests.clone()));
+    }
+    if let Some(policy) = state.archive.clone() {
+        let job_state = state.clone();
+        supervisor.supervise("archiver", move || {
+            run_archiver(job_state.clone(), policy.clone())
+        });
+    }
+    #[cfg(feature = "geoip")]
+    if let Some(geoip) = state.geoip.clone() {
+        supervisor.supervise("geoip reloads", move || {
+            crate::geoip::run_reloads(geoip.clone())
+        });
+    }
+    if state.overload == Overload::Shed {
+        let job_state = state.clone();
+        supervisor.supervise("shedder", move || run_shedder(job_state.clone()));
+    }
+    Ok(state)
+}
+
+/// Writes the settings of each room `changes` names to the store, or
+/// deletes them if the room is gone, until the registry stops sending.
+async fn persist_rooms(
+    registry: Arc<Registry>,
+    store: Arc<dyn MessageStore>,
+    mut changes: mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(room) = changes.recv().await {
+        let result = if registry.has_room(&room) {
+            store.put_room(&room, &registry.room_config(&room)).await
+        } else {
+            store.delete_room(&room).await.map(drop)
+        };
+        if let Err(e) = result {
+            error!("Failed to persist room {}: {:?}", room, e);
+        }
     }
 }
 
+/// Reads the first line from a client of a server with tenants. If it
+/// selects a known tenant, returns that tenant's state; anything else is
+/// left in the decoder for the host to handle. Returns `None` if the client
+/// left or named an unknown tenant.
+async fn select_tenant(
+    socket: &mut BoxStream,
+    addr: SocketAddr,
+    host: &Arc<ServerState>,
+    tenants: &HashMap<String, Arc<ServerState>>,
+) -> Result<Option<(FrameDecoder, Arc<ServerState>)>> {
+    let wait = host.handshake_timeout.unwrap_or(TENANT_TIMEOUT);
+    let mut bytes = BytesMut::new();
+    let newline = loop {
+        if let Some(newline) = bytes.iter().position(|&b| b == b'\n') {
+            break Some(newline);
+        }
+        if bytes.len() > MAX_LINE_LEN {
+            break None;
+        }
+        match timeout(wait, socket.read_buf(&mut bytes)).await {
+            Ok(Ok(0)) => return Ok(None),
+            Ok(result) => {
+                result?;
+            }
+            Err(_) => {
+                info!("Client {} sent nothing, closing", addr);
+                host.metrics.record_handshake_timeout();
+                return Ok(None);
+            }
+        }
+    };
+    let selected = newline.and_then(|newline| {
+        let line = std::str::from_utf8(&bytes[..newline]).ok()?;
+        match serde_json::from_str(line.trim_end_matches('\r')) {
+            Ok(ClientFrame::SelectTenant { tenant }) => Some((newline, tenant)),
+            _ => None,
+        }
+    });
+    let mut decoder = FrameDecoder::new(MAX_LINE_LEN);
+    let Some((newline, name)) = selected else {
+        decoder.extend(&bytes);
+        return Ok(Some((decoder, host.clone())));
+    };
+    let Some(tenant) = tenants.get(&name) else {
+        info!("Client {} asked for unknown tenant {}", addr, name);
+        send_frame(
+            socket,
+            &error_frame(format!("Unknown tenant {}", name)),
+            None,
+        )
+        .await?;
+        return Ok(None);
+    };
+    info!("Client {} selected tenant {}", addr, name);
+    decoder.extend(&bytes[newline + 1..]);
+    Ok(Some((decoder, tenant.clone())))
+}
+
 async fn handle_client(
-    mut socket: TcpStream,
+    mut socket: BoxStream,
+    decoder: FrameDecoder,
     addr: SocketAddr,
-    broadcast_tx: broadcast::Sender<String>,
-    mut broadcast_rx: broadcast::Receiver<String>,
+    state: &Arc<ServerState>,
 ) -> Result<()> {
     info!("Handling client {}", addr);
+    if let Some(hooks) = &state.hooks
+        && let Err(e) = hooks.on_connect(addr).await
+    {
+        info!("Client {} rejected: {}", addr, e);
+        return send_frame(&mut socket, &error_frame(e.to_string()), None).await;
+    }
+    let recorder = match &state.recordings {
+        Some(dir) => match Recorder::create(dir, addr, &state.tasks).await {
+            Ok(recorder) => Some(Arc::new(recorder)),
+            Err(e) => {
+                warn!("Failed to record {}: {:#}", addr, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let mut conn = Connection {
+        identity: Identity::Open,
+        resume_token: None,
+        api_key: None,
+        challenge: None,
+        uploads: HashMap::new(),
+        next_transfer_id: 0,
+        downloads: HashMap::new(),
+        last_active: Instant::now(),
+        idle: false,
+        auto_away: false,
+        recorder,
+    };
+    if let Some(challenges) = &state.challenges
+        && let Some(challenge) = challenges.on_connect(addr.ip(), std::time::Instant::now())
+    {
+        info!("Challenging client {}", addr);
+        state.metrics.record_challenge();
+        let frame = ServerFrame::Challenge {
+            nonce: challenge.nonce.clone(),
+            difficulty: challenge.difficulty,
+        };
+        send_frame(&mut socket, &frame, conn.recorder.as_deref()).await?;
+        conn.challenge = Some(challenge);
+    }
+    if state.guests.is_some() {
+        let user = allocate_guest_name(state);
+        info!("Client {} is guest {}", addr, user);
+        let resume_token = generate_token();
+        state.registry.set_user(addr, &user);
+        conn.identity = Identity::Guest(user.clone());
+        conn.resume_token = Some(resume_token.clone());
+        authenticated(state, addr, &user, true).await;
+        let onboarding = onboard(state, &user).await.unwrap_or_else(|e| {
+            warn!("Failed to onboard {}: {}", user, e);
+            Vec::new()
+        });
+        let welcome = ServerFrame::Welcome {
+            user,
+            guest: true,
+            resume_token,
+        };
+        for frame in std::iter::once(&welcome).chain(&onboarding) {
+            if let Err(e) = send_frame(&mut socket, frame, conn.recorder.as_deref()).await {
+                disconnected(state, addr, &conn).await;
+                end_session(state, addr, conn);
+                return Err(e);
+            }
+        }
+    } else if state.authenticator.is_some() {
+        conn.identity = Identity::Unauthenticated;
+    }
+    let result = serve_client(socket, decoder, addr, state, &mut conn).await;
+    disconnected(state, addr, &conn).await;
+    end_session(state, addr, conn);
+    result
+}
+
+/// Tells the hooks, if any, that `addr` now posts as `user`.
+async fn authenticated(state: &ServerState, addr: SocketAddr, user: &str, guest: bool) {
+    if let Some(digests) = &state.digests {
+        digests.clear(user);
+    }
+    if let Some(hooks) = &state.hooks {
+        hooks.on_authenticated(addr, user, guest).await;
+    }
+}
+
+/// Logs and counts a panic while serving `addr`, which is then closed.
+fn handler_panicked(state: &ServerState, addr: SocketAddr, panic: &str) {
+    error!("Handler for client {} panicked: {}", addr, panic);
+    state.metrics.record_handler_panic();
+}
+
+/// Tells the hooks, if any, that `addr` has gone.
+async fn disconnected(state: &ServerState, addr: SocketAddr, conn: &Connection) {
+    if let Some(hooks) = &state.hooks {
+        hooks.on_disconnect(addr, conn.identity.user()).await;
+    }
+}
+
+/// Holds a disconnected session for resumption, or releases its guest
+/// nickname if it can't be resumed.
+fn end_session(state: &ServerState, addr: SocketAddr, conn: Connection) {
+    let mut sessions = state.sessions.lock().unwrap();
+    expire_sessions(state, &mut sessions);
+    match conn.resume_token {
+        Some(token) => {
+            let session = SuspendedSession {
+                identity: conn.identity,
+                api_key: conn.api_key,
+                profile: state.registry.profile(addr).unwrap_or_default(),
+                presence_subscription: state.registry.presence_subscription(addr),
+                last_id: state.next_message_id.load(Ordering::Relaxed) - 1,
+                expires_at: Instant::now() + state.session_grace,
+            };
+            sessions.insert(token, session);
+        }
+        None => {
+            if let Identity::Guest(user) = &conn.identity {
+                state.guest_names.lock().unwrap().remove(user);
+            }
+        }
+    }
+}
+
+/// Drops sessions past their grace period, releasing guest nicknames.
+fn expire_sessions(state: &ServerState, sessions: &mut HashMap<String, SuspendedSession>) {
+    let now = Instant::now();
+    sessions.retain(|_, session| {
+        if session.expires_at > now {
+            return true;
+        }
+        if let Identity::Guest(user) = &session.identity {
+            state.guest_names.lock().unwrap().remove(user);
+        }
+        false
+    });
+}
+
+/// Serves one connection: replies and broadcasts for the client go
+/// through an `OutboundQueue` drained by a writer task, so urgent frames
+/// overtake a backlog of chat on a slow connection.
+async fn serve_client(
+    socket: BoxStream,
+    decoder: FrameDecoder,
+    addr: SocketAddr,
+    state: &Arc<ServerState>,
+    conn: &mut Connection,
+) -> Result<()> {
+    let (reader, writer) = tokio::io::split(socket);
+    let outbound = Arc::new(
+        OutboundQueue::new(state.outbound_capacity)
+            .with_account(state.memory.account(addr))
+            .with_metrics(state.metrics.clone()),
+    );
+    let mut writer = state.tasks.spawn(
+        "writers",
+        &format!("writer {}", addr),
+        write_outbound(
+            writer,
+            outbound.clone(),
+            conn.recorder.clone(),
+            #[cfg(feature = "chaos")]
+            state.chaos.clone(),
+        ),
+    );
+    state.fanout.subscribe(addr, outbound.clone());
+    let result = tokio::select! {
+        result = catch_panic(read_client(reader, decoder, addr, state, conn, &outbound)) => {
+            result.unwrap_or_else(|panic| {
+                handler_panicked(state, addr, &panic);
+                let _ = queue_frame(&outbound, error_frame(PANIC_MESSAGE));
+                Err(anyhow::anyhow!("Handler panicked: {}", panic))
+            })
+        }
+        result = &mut writer => {
+            state.fanout.unsubscribe(addr);
+            return result?;
+        }
+    };
+    state.fanout.unsubscribe(addr);
+    // Give queued replies, e.g. a final error, a chance to go out.
+    outbound.close();
+    if timeout(FLUSH_TIMEOUT, &mut writer).await.is_err() {
+        writer.abort();
+    }
+    result
+}
+
+/// Writes queued frames to the client until the queue is closed, flushing
+/// whatever has piled up with one vectored write.
+async fn write_outbound(
+    mut writer: WriteHalf<BoxStream>,
+    outbound: Arc<OutboundQueue>,
+    recorder: Option<Arc<Recorder>>,
+    #[cfg(feature = "chaos")] chaos: Arc<Chaos>,
+) -> Result<()> {
+    while let Some(batch) = outbound.pop_batch(MAX_WRITE_BATCH).await {
+        if let Some(recorder) = &recorder {
+            for line in &batch {
+                recorder.record(Direction::Outbound, &WireFormat::to_json(line));
+            }
+        }
+        #[cfg(feature = "chaos")]
+        inject_write_faults(&mut writer, &chaos, &batch).await?;
+        write_all_vectored(&mut writer, batch.into()).await?;
+    }
+    Ok(())
+}
+
+/// Delays a write, or cuts it short and drops the connection, as `chaos`
+/// says.
+#[cfg(feature = "chaos")]
+async fn inject_write_faults(
+    writer: &mut WriteHalf<BoxStream>,
+    chaos: &Chaos,
+    batch: &[Bytes],
+) -> Result<()> {
+    let latency = chaos.latency();
+    if !latency.is_zero() {
+        tokio::time::sleep(latency).await;
+    }
+    if chaos.disconnect() {
+        return Err(anyhow::anyhow!("Chaos: dropped connection"));
+    }
+    if chaos.truncate() {
+        let line = &batch[0];
+        writer.write_all(&line[..line.len() / 2]).await?;
+        return Err(anyhow::anyhow!("Chaos: truncated write"));
+    }
+    Ok(())
+}
+
+/// Writes every frame in `batch`, resuming after partial writes.
+async fn write_all_vectored(
+    writer: &mut WriteHalf<BoxStream>,
+    mut batch: VecDeque<Bytes>,
+) -> std::io::Result<()> {
+    while !batch.is_empty() {
+        let slices: Vec<IoSlice> = batch.iter().map(|line| IoSlice::new(line)).collect();
+        let mut written = writer.write_vectored(&slices).await?;
+        if written == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        while let Some(line) = batch.front_mut() {
+            if written < line.len() {
+                line.advance(written);
+                break;
+            }
+            written -= line.len();
+            batch.pop_front();
+        }
+    }
+    Ok(())
+}
+
+/// Reads and handles the client's frames, queueing replies for it, until
+/// it disconnects.
+async fn read_client(
+    mut reader: ReadHalf<BoxStream>,
+    mut decoder: FrameDecoder,
+    addr: SocketAddr,
+    state: &Arc<ServerState>,
+    conn: &mut Connection,
+    outbound: &OutboundQueue,
+) -> Result<()> {
     let mut buffer = [0; 1024];
     let read_timeout = Duration::from_secs(30);
+    let mut handshake_by = state.handshake_timeout.map(|limit| Instant::now() + limit);
+    if !decoder.is_empty() {
+        // Read before the connection was handed over, while selecting a
+        // tenant.
+        handle_lines(&mut decoder, addr, state, conn, outbound).await?;
+    }
 
     loop {
+        let idle_at = state
+            .idle_after
+            .filter(|_| !conn.idle)
+            .map(|idle| conn.last_active + idle);
+        // Reading produces more frames to queue, so hold off while the
+        // server is out of memory for them.
+        let paused = state.overload == Overload::Backpressure && state.memory.exceeded();
         tokio::select! {
-            result = timeout(read_timeout, socket.read(&mut buffer)) => {
+            result = timeout(read_timeout, reader.read(&mut buffer)), if !paused => {
                 match result {
                     Ok(Ok(0)) => {
                         info!("Client {} disconnected", addr);
                         return Ok(());
                     }
                     Ok(Ok(n)) => {
-                        let raw = String::from_utf8_lossy(&buffer[..n]).trim().to_string();
-                        if !raw.is_empty() {
-                            let _span = span!(Level::DEBUG, "process_message", message = %raw).entered();
-                            let message = serde_json::from_str::<ChatMessage>(&raw)
-                                .or_else(|_| ChatMessage::from_raw(&raw))?;
-                            let json = message.to_json()?;
-                            let formatted = format!("{}: {}", addr, json);
-                            debug!("Broadcasting: {}", formatted);
-                            broadcast_tx.send(formatted)?;
+                        decoder.extend(&buffer[..n]);
+                        if handle_lines(&mut decoder, addr, state, conn, outbound).await?
+                            && conn.challenge.is_none()
+                            && !matches!(conn.identity, Identity::Unauthenticated)
+                        {
+                            handshake_by = None;
                         }
                     }
                     Ok(Err(e)) => {
@@ -79,22 +1420,2490 @@ async fn handle_client(
                     }
                 }
             }
-            result = broadcast_rx.recv() => {
-                match result {
-                    Ok(message) => {
-                        debug!("Sending to {}: {}", addr, message);
-                        socket.write_all(message.as_bytes()).await?;
-                    }
-                    Err(broadcast::error::RecvError::Closed) => {
-                        info!("Broadcast channel closed for {}", addr);
-                        return Ok(());
-                    }
-                    Err(e) => {
-                        error!("Broadcast receive error for {}: {:?}", addr, e);
-                        return Err(e.into());
-                    }
+            _ = state.memory.wait_for_room(), if paused => {}
+            _ = sleep_until_some(handshake_by) => {
+                info!("Client {} did not complete its handshake in time", addr);
+                state.metrics.record_handshake_timeout();
+                return Err(anyhow::anyhow!("Handshake timeout"));
+            }
+            _ = sleep_until_some(idle_at) => {
+                conn.idle = true;
+                if state.registry.profile(addr).unwrap_or_default().state == PresenceState::Online {
+                    debug!("Client {} is idle", addr);
+                    conn.auto_away = true;
+                    set_presence_state(state, addr, PresenceState::Away)?;
+                }
+            }
+        }
+    }
+}
+
+/// Handles every complete line in `decoder`, queueing the replies. Returns
+/// whether there were any.
+async fn handle_lines(
+    decoder: &mut FrameDecoder,
+    addr: SocketAddr,
+    state: &Arc<ServerState>,
+    conn: &mut Connection,
+    outbound: &OutboundQueue,
+) -> Result<bool> {
+    let (mut frames, mut busy) = (0, Duration::ZERO);
+    loop {
+        let line = match decoder.next_line() {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(e @ CodecError::InvalidUtf8) => {
+                // The bad line is dropped; later ones still parse.
+                debug!("Invalid UTF-8 from {}", addr);
+                for reply in localize(state, addr, vec![error_frame(e.to_string())]) {
+                    queue_frame(outbound, reply)?;
+                }
+                continue;
+            }
+            Err(e) => {
+                error!("Protocol error from {}: {}", addr, e);
+                for reply in localize(state, addr, vec![error_frame(e.to_string())]) {
+                    queue_frame(outbound, reply)?;
                 }
+                return Err(e.into());
             }
+        };
+        if let Some(recorder) = &conn.recorder {
+            recorder.record(Direction::Inbound, &line);
         }
+        let started = Instant::now();
+        let replies = process_line(&line, addr, state, conn)
+            .instrument(span!(Level::DEBUG, "process_message", message = %line))
+            .await?;
+        for reply in localize(state, addr, replies) {
+            // Frames after the one agreeing to a wire format are in it.
+            let format = match &reply {
+                ServerFrame::CapabilitiesSelected { capabilities } => {
+                    Some(WireFormat::negotiated(capabilities))
+                }
+                _ => None,
+            };
+            queue_frame(outbound, reply)?;
+            if let Some(format) = format {
+                outbound.set_format(format);
+            }
+        }
+        frames += 1;
+        busy += started.elapsed();
+        // A read can hold dozens of frames; let other connections on this
+        // worker run between them.
+        consume_budget().await;
+    }
+    state.metrics.record_connection_work(addr, frames, busy);
+    Ok(frames > 0)
+}
+
+/// Translates replies into the client's negotiated locale, after
+/// downgrading any it lacks the capabilities for.
+fn localize(state: &ServerState, addr: SocketAddr, frames: Vec<ServerFrame>) -> Vec<ServerFrame> {
+    let frames: Vec<ServerFrame> = match state.registry.capabilities(addr) {
+        Some(capabilities) => frames
+            .into_iter()
+            .map(|frame| frame.downgrade(&capabilities))
+            .collect(),
+        None => frames,
+    };
+    if state.catalogs.is_empty() {
+        return frames;
+    }
+    let locale = state.registry.locale(addr);
+    frames
+        .into_iter()
+        .map(|frame| state.catalogs.localize(&locale, frame))
+        .collect()
+}
+
+/// Handles one inbound line, either a control frame or a chat message,
+/// returning any replies for the sender.
+async fn process_line(
+    line: &str,
+    addr: SocketAddr,
+    state: &Arc<ServerState>,
+    conn: &mut Connection,
+) -> Result<Vec<ServerFrame>> {
+    if let Some(key) = &conn.api_key
+        && !state.registry.api_keys().is_active(&key.id, unix_time())
+    {
+        return Err(anyhow::anyhow!("API key {} was revoked", key.id));
+    }
+    if let Some(user) = conn.identity.user()
+        && state.registry.is_banned(user)
+    {
+        return Err(anyhow::anyhow!("{} is banned", user));
+    }
+    let frame = serde_json::from_str::<ClientFrame>(line).ok();
+    if let Some(challenge) = &conn.challenge {
+        return Ok(match frame {
+            Some(ClientFrame::ChallengeResponse { solution }) => {
+                if !challenge.is_solved_by(&solution) {
+                    return Ok(vec![error_frame("Wrong challenge solution")]);
+                }
+                debug!("Client {} solved its challenge", addr);
+                conn.challenge = None;
+                vec![ServerFrame::ChallengeAccepted]
+            }
+            Some(ClientFrame::Heartbeat) => Vec::new(),
+            _ => vec![error_frame("Solve the challenge first")],
+        });
+    }
+    if !matches!(frame, Some(ClientFrame::Heartbeat)) {
+        note_activity(state, addr, conn)?;
+    }
+    if let Some(frame) = frame {
+        if matches!(conn.identity, Identity::Unauthenticated) && !allowed_before_auth(&frame) {
+            return Ok(vec![error_frame("Authenticate first")]);
+        }
+        return handle_frame(frame, addr, state, conn).await;
+    }
+    let message = match serde_json::from_str::<ChatMessage>(line) {
+        Ok(message) => message.validated(),
+        Err(_) => ChatMessage::from_raw(line),
+    };
+    let mut message = match message {
+        Ok(message) => message,
+        Err(e) => return Ok(vec![error_frame(e.to_string())]),
+    };
+    // Only `ClientFrame::Forward` vouches for where a message came from.
+    message.forwarded = None;
+    if let Cow::Owned(content) = state.sanitize.sanitize(&message.content) {
+        debug!("Sanitized message content from {}", addr);
+        message.set_cleaned_content(content);
+    }
+    if conn.api_key.as_ref().is_some_and(|key| key.scope.read_only) {
+        return Ok(vec![error_frame("This API key is read-only")]);
+    }
+    if let Some(command) = Command::parse(&message.content) {
+        return match command {
+            Ok(command) => run_command(command, &message.sender, addr, state, conn).await,
+            Err(e) => Ok(vec![error_frame(e.to_string())]),
+        };
+    }
+    match &conn.identity {
+        Identity::Open => {
+            if state.registry.nick_password_hash(&message.sender).is_some() {
+                return Ok(vec![error_frame(format!(
+                    "Nickname {} is registered; /identify first",
+                    message.sender
+                ))]);
+            }
+        }
+        Identity::Unauthenticated => {
+            return Ok(vec![error_frame("Authenticate before sending messages")]);
+        }
+        Identity::User(user) => message.sender = user.clone(),
+        Identity::Guest(user) => {
+            message.sender = user.clone();
+            let room = message.room();
+            if room != DEFAULT_ROOM && !state.registry.has_room(room) {
+                return Ok(vec![error_frame("Guests can only post in existing rooms")]);
+            }
+            let bytes = message.content.len() as u64;
+            if let Err(exceeded) = state.guest_quotas.record(user, bytes, unix_time()) {
+                state.metrics.record_quota_rejection();
+                return Ok(vec![quota_exceeded_frame(exceeded)]);
+            }
+        }
+    }
+    post_message(state, addr, conn, message).await
+}
+
+/// Posts a chat message from `conn` once its sender is settled: checks the
+/// target room's permissions and the quotas, then relays, schedules or
+/// routes it.
+async fn post_message(
+    state: &Arc<ServerState>,
+    addr: SocketAddr,
+    conn: &Connection,
+    mut message: ChatMessage,
+) -> Result<Vec<ServerFrame>> {
+    if state.registry.is_banned(&message.sender) {
+        return Ok(vec![banned_frame(&message.sender)]);
+    }
+    if let Some(file) = &message.attachment
+        && (!is_valid_blob_id(&file.id) || !state.blobs.contains(&file.id).await?)
+    {
+        return Ok(vec![error_frame(format!("Unknown file {}", file.id))]);
+    }
+    if let Some(shortcodes) = &state.shortcodes {
+        shortcodes.expand_message(&mut message);
+        if message.content.len() > MAX_CONTENT_LEN {
+            let error = ValidationError::ContentTooLong {
+                max: MAX_CONTENT_LEN,
+            };
+            return Ok(vec![error_frame(error.to_string())]);
+        }
+    }
+    if let Some(router) = &state.router {
+        message = match router.route(addr, message).await {
+            Route::Broadcast(message) => message,
+            Route::Direct { to, message } => return send_direct(state, addr, &to, message),
+            Route::Drop(reason) => return Ok(reason.map(error_frame).into_iter().collect()),
+        };
+    }
+    let room = message.room().to_string();
+    if conn
+        .api_key
+        .as_ref()
+        .is_some_and(|key| !key.scope.allows_room(&room))
+    {
+        return Ok(vec![error_frame(format!(
+            "This API key can't post in {}",
+            room
+        ))]);
+    }
+    let config = state.registry.room_config(&room);
+    if !config.allows(&message.sender, RoomAction::Post) {
+        if config.announcement && config.role_of(&message.sender) < Role::Moderators {
+            return Ok(vec![ServerFrame::ReadOnlyRoom { room }]);
+        }
+        return Ok(vec![denied_frame(
+            &room,
+            config.required_role(RoomAction::Post),
+            "post",
+        )]);
+    }
+    if message.ttl_secs.is_some() && !config.allow_ephemeral {
+        return Ok(vec![error_frame(format!(
+            "Room {} does not allow ephemeral messages",
+            room
+        ))]);
+    }
+    // Checked before anything is charged for the message.
+    if let Some(send_at) = message.send_at
+        && Duration::from_secs(send_at.saturating_sub(unix_time())) > MAX_SCHEDULE_AHEAD
+    {
+        return Ok(vec![error_frame("send_at is too far in the future")]);
+    }
+    let bytes = message.content.len() as u64;
+    let now = unix_time();
+    if state.resources.needs_history(&room, now) {
+        let history_bytes = state.store.room_bytes(&room).await?;
+        state.resources.set_history(&room, history_bytes, now);
+    }
+    if let Err(exceeded) = state.resources.record_message(&room, bytes, now) {
+        return Ok(vec![resource_exceeded_frame(state, exceeded)]);
+    }
+    if let Err(exceeded) = state.quotas.record(&message.sender, bytes, now) {
+        // The room's budget was only charged on the way to this check.
+        state.resources.refund_message(&room, bytes, now);
+        state.metrics.record_quota_rejection();
+        return Ok(vec![quota_exceeded_frame(exceeded)]);
+    }
+    if let Some(user) = conn.identity.user()
+        && state.registry.clear_draft(user, &room)
+    {
+        send_to_user(state, user, &ServerFrame::Draft { room, draft: None })?;
+    }
+    if let Some(send_at) = message.send_at {
+        let delay = Duration::from_secs(send_at.saturating_sub(unix_time()));
+        if !delay.is_zero() {
+            let pending_id = schedule_message(state, addr, message, delay).await?;
+            return Ok(vec![ServerFrame::Scheduled {
+                pending_id,
+                send_at,
+            }]);
+        }
+    }
+    relay_message(state, addr, message).await?;
+    Ok(Vec::new())
+}
+
+/// Resets the idle timer, bringing a connection that went away when it
+/// idled back online.
+fn note_activity(state: &ServerState, addr: SocketAddr, conn: &mut Connection) -> Result<()> {
+    conn.last_active = Instant::now();
+    conn.idle = false;
+    if std::mem::take(&mut conn.auto_away)
+        && state.registry.profile(addr).unwrap_or_default().state == PresenceState::Away
+    {
+        debug!("Client {} is active again", addr);
+        set_presence_state(state, addr, PresenceState::Online)?;
+    }
+    Ok(())
+}
+
+/// Changes a connection's presence state and broadcasts the result.
+fn set_presence_state(
+    state: &ServerState,
+    addr: SocketAddr,
+    presence: PresenceState,
+) -> Result<()> {
+    let presence = state
+        .registry
+        .update_profile(addr, None, None, Some(presence));
+    broadcast_presence(state, presence)
+}
+
+/// Sends a presence change to the connections following that user.
+fn broadcast_presence(state: &ServerState, presence: UserPresence) -> Result<()> {
+    let except = state.registry.devices_not_following(&presence.user);
+    broadcast_frame_except(state, &ServerFrame::PresenceChanged(presence), except)
+}
+
+/// Changes whose presence `addr` follows, answering with who that is.
+fn subscribe_presence(
+    state: &ServerState,
+    addr: SocketAddr,
+    subscription: Option<PresenceSubscription>,
+) -> ServerFrame {
+    let users = match &subscription {
+        Some(subscription) => state.registry.followed_presence(subscription),
+        None => state.registry.presence(),
+    };
+    state
+        .registry
+        .set_presence_subscription(addr, subscription.clone());
+    ServerFrame::PresenceSubscribed {
+        subscription,
+        users,
+    }
+}
+
+/// Sleeps until `deadline`, or forever without one.
+async fn sleep_until_some(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The lock posts to `room` are stamped, stored and broadcast under, so
+/// its messages are numbered and delivered in order without holding up
+/// other rooms. Holds the room's last sequence number once it's known.
+fn room_seq(state: &ServerState, room: &str) -> Arc<tokio::sync::Mutex<Option<u64>>> {
+    let mut seqs = state.room_seqs.lock().unwrap();
+    seqs.entry(room.to_string()).or_default().clone()
+}
+
+/// Stamps a chat message with an id and sequence number, records it, and
+/// broadcasts it.
+async fn relay_message(
+    state: &Arc<ServerState>,
+    addr: SocketAddr,
+    mut message: ChatMessage,
+) -> Result<()> {
+    let room = message.room().to_string();
+    let lock = room_seq(state, &room);
+    let mut seq = lock.lock().await;
+    unarchive(state, &room).await?;
+    let last_seq = match *seq {
+        Some(seq) => seq,
+        // First message since startup: carry on from the store's mark,
+        // which outlives expired, pruned and archived messages.
+        None => state
+            .store
+            .high_water(&room)
+            .await?
+            .map_or(0, |mark| mark.seq),
+    };
+    let id = state.next_message_id.fetch_add(1, Ordering::Relaxed);
+    message.id = Some(id);
+    message.room = Some(room.clone());
+    message.timestamp = Some(unix_time());
+    message.seq = Some(last_seq + 1);
+    if let Some(wal) = &state.wal {
+        wal.append(&message).await?;
+    }
+    state.store.append(&message).await?;
+    *seq = Some(last_seq + 1);
+    if let Some(file) = &message.attachment {
+        state.store.share_file(&file.id, &room).await?;
+    }
+    let config = state.registry.room_config(&room);
+    if config.delivery == DeliveryMode::AtLeastOnce {
+        // Moderators can be members too; each is held one copy.
+        let recipients: BTreeSet<&str> = config
+            .participants()
+            .map(String::as_str)
+            .filter(|user| {
+                *user != message.sender && !state.registry.is_blocked(user, &message.sender)
+            })
+            .collect();
+        for user in recipients {
+            if let Some(dropped) = state.acks.hold(user, &message) {
+                dead_letter(state, user, dropped, DeadLetterReason::QueueOverflow);
+            }
+        }
+    }
+    state.tail.publish(&message);
+    // Handed to the fan-out, which doesn't wait, before the room's next
+    // message is stamped, so they go out in order. Whoever blocked the
+    // sender doesn't get it, and the sender can't tell.
+    let blocking = state.registry.devices_blocking(&message.sender);
+    let sent = broadcast_to_room(
+        state,
+        &room,
+        &ServerFrame::Message {
+            from: addr.to_string(),
+            message: message.clone(),
+        },
+        blocking,
+    );
+    drop(seq);
+    state.metrics.record_message();
+    state
+        .analytics
+        .record_message(&room, &message.sender, unix_time());
+    if let Some(gateway) = &state.push {
+        let recipients: BTreeSet<&str> = config
+            .participants()
+            .map(String::as_str)
+            .filter(|user| *user != message.sender)
+            .collect();
+        spawn_pushes(state, gateway.clone(), recipients, &message, Some(&room));
+    }
+    if let Some(digests) = &state.digests {
+        let recipients: BTreeSet<&str> = config
+            .participants()
+            .map(String::as_str)
+            .filter(|user| *user != message.sender && message.mentions(user))
+            .collect();
+        for user in recipients {
+            hold_for_digest(state, digests, user, &message, Some(&room));
+        }
+    }
+    if let Some(ttl_secs) = message.ttl_secs {
+        schedule_expiry(
+            state.clone(),
+            room.clone(),
+            id,
+            Duration::from_secs(ttl_secs),
+        );
+    }
+    if let Some(previewer) = &state.previews {
+        let links = previewer.links(&message);
+        if !links.is_empty() {
+            spawn_previews(state.clone(), previewer.clone(), room.clone(), id, links);
+        }
+    }
+    if let Some(wal) = &state.wal {
+        wal.ack(id).await?;
+    }
+    sent
+}
+
+/// Fetches previews of `links` in the background, broadcasting each one
+/// that turns up.
+fn spawn_previews(
+    state: Arc<ServerState>,
+    previewer: Arc<LinkPreviewer>,
+    room: String,
+    message_id: MessageId,
+    links: Vec<String>,
+) {
+    state
+        .tasks
+        .clone()
+        .spawn("previews", "link previews", async move {
+            for link in links {
+                let Some(preview) = previewer.preview(&link).await else {
+                    continue;
+                };
+                let frame = ServerFrame::LinkPreview {
+                    room: room.clone(),
+                    message_id,
+                    preview,
+                };
+                if let Err(e) = broadcast_to_room(&state, &room, &frame, HashSet::new()) {
+                    warn!("Failed to broadcast preview of {}: {}", link, e);
+                }
+            }
+        });
+}
+
+/// Pushes `message` in the background to each of `recipients` who has a
+/// push token and should hear about it now. `room` is `None` for a
+/// direct message.
+fn spawn_pushes<'a>(
+    state: &ServerState,
+    gateway: Arc<PushGateway>,
+    recipients: impl IntoIterator<Item = &'a str>,
+    message: &ChatMessage,
+    room: Option<&str>,
+) {
+    let now = unix_time();
+    let pushes: Vec<_> = recipients
+        .into_iter()
+        .filter(|user| !state.registry.is_blocked(user, &message.sender))
+        .filter_map(|user| {
+            let tokens = state.registry.push_tokens(user);
+            if tokens.is_empty() {
+                return None;
+            }
+            let devices = state.registry.devices_of(user);
+            let do_not_disturb = !devices.is_empty()
+                && devices.iter().all(|device| {
+                    state
+                        .registry
+                        .profile(*device)
+                        .is_some_and(|profile| profile.state == PresenceState::Dnd)
+                });
+            let prefs = state.registry.notification_prefs(user);
+            let mentioned = message.mentions(user);
+            should_push(
+                &prefs,
+                !devices.is_empty(),
+                do_not_disturb,
+                room,
+                mentioned,
+                now,
+            )
+            .then(|| (tokens, PushNotification::new(user, message, room)))
+        })
+        .collect();
+    if pushes.is_empty() {
+        return;
+    }
+    let registry = state.registry.clone();
+    state.tasks.clone().spawn("pushes", "pushes", async move {
+        for (tokens, notification) in pushes {
+            for token in gateway.send(&tokens, &notification).await {
+                info!("Forgetting a push token of {}", notification.user);
+                registry.remove_push_token(&notification.user, &token);
+            }
+        }
+    });
+}
+
+/// Holds `message` for `user`'s next email digest if they're offline,
+/// opted in and would have been notified of it. `room` is `None` for a
+/// direct message.
+fn hold_for_digest(
+    state: &ServerState,
+    digests: &EmailDigests,
+    user: &str,
+    message: &ChatMessage,
+    room: Option<&str>,
+) {
+    if !digests.wants(user)
+        || !state.registry.devices_of(user).is_empty()
+        || state.registry.is_blocked(user, &message.sender)
+    {
+        return;
+    }
+    // Quiet hours don't matter: the digest is read later anyway.
+    let prefs = NotificationPrefs {
+        quiet_hours: None,
+        ..state.registry.notification_prefs(user)
+    };
+    if prefs.notifies(room, true, unix_time()) {
+        digests.hold(user, message);
+    }
+}
+
+/// Delivers a message routed to `to` alone, echoing it to the sender. If
+/// `to` is offline, it's only accepted for their email digest.
+fn send_direct(
+    state: &ServerState,
+    addr: SocketAddr,
+    to: &str,
+    mut message: ChatMessage,
+) -> Result<Vec<ServerFrame>> {
+    let mut devices: HashSet<SocketAddr> = state.registry.devices_of(to).into_iter().collect();
+    let digests = state
+        .digests
+        .as_ref()
+        .filter(|digests| devices.is_empty() && digests.wants(to));
+    if devices.is_empty() && digests.is_none() {
+        return Ok(vec![error_frame(format!("{} is not connected", to))]);
+    }
+    let bytes = message.content.len() as u64;
+    if let Err(exceeded) = state.quotas.record(&message.sender, bytes, unix_time()) {
+        state.metrics.record_quota_rejection();
+        return Ok(vec![quota_exceeded_frame(exceeded)]);
+    }
+    if state.registry.is_blocked(to, &message.sender) {
+        // Echoed to the sender alone, as if it had been delivered.
+        devices.clear();
+    }
+    message.id = Some(state.next_message_id.fetch_add(1, Ordering::Relaxed));
+    message.timestamp = Some(unix_time());
+    if let Some(gateway) = &state.push {
+        spawn_pushes(state, gateway.clone(), [to], &message, None);
+    }
+    if let Some(digests) = digests {
+        hold_for_digest(state, digests, to, &message, None);
+    }
+    let frame = ServerFrame::Message {
+        from: addr.to_string(),
+        message,
+    };
+    debug!("Sending to {}: {}", to, frame);
+    devices.insert(addr);
+    send_to_devices(state, devices, &frame)?;
+    Ok(Vec::new())
+}
+
+/// Describes how the server sees the connection at `addr`, signed in as
+/// `user`.
+fn debug_info(
+    state: &ServerState,
+    addr: SocketAddr,
+    conn: &Connection,
+    user: &str,
+) -> ConnectionDebugInfo {
+    let queue = state.fanout.queue(addr);
+    let quotas = match conn.identity {
+        Identity::Guest(_) => &state.guest_quotas,
+        _ => &*state.quotas,
+    };
+    ConnectionDebugInfo {
+        addr: addr.to_string(),
+        user: user.to_string(),
+        guest: matches!(conn.identity, Identity::Guest(_)),
+        api_key_scope: conn.api_key.as_ref().map(|key| key.scope.clone()),
+        locale: state.registry.locale(addr),
+        capabilities: state
+            .registry
+            .capabilities(addr)
+            .unwrap_or_else(|| Capability::ALL.into()),
+        profile: state.registry.profile(addr).unwrap_or_default(),
+        idle: conn.idle,
+        presence_subscription: state.registry.presence_subscription(addr),
+        rooms: state.registry.rooms_of(user),
+        muted_rooms: state.registry.notification_prefs(user).muted_rooms,
+        blocked: state.registry.blocks(user),
+        queued_frames: queue.as_ref().map_or(0, |queue| queue.queued_frames()),
+        queued_bytes: queue.as_ref().map_or(0, |queue| queue.queued_bytes()),
+        queue_capacity: queue.as_ref().map_or(0, |queue| queue.normal_capacity()),
+        quota: quotas.usage(user, unix_time()),
+    }
+}
+
+/// Moves a message that couldn't be delivered to `user` to the dead-letter
+/// store.
+fn dead_letter(state: &ServerState, user: &str, message: ChatMessage, reason: DeadLetterReason) {
+    warn!(
+        "Dead-lettering message {:?} for {}: {}",
+        message.id, user, reason
+    );
+    state.metrics.record_dead_letter();
+    state.dead_letters.push(DeadLetter {
+        user: user.to_string(),
+        reason,
+        message,
+        dead_at: unix_time(),
+    });
+}
+
+/// Periodically dead-letters held messages whose ack never came.
+async fn run_ack_expiry(state: Arc<ServerState>) {
+    let mut ticker = tokio::time::interval(ACK_EXPIRY_INTERVAL);
+    loop {
+        ticker.tick().await;
+        for (user, message) in state.acks.expire(unix_time()) {
+            dead_letter(&state, &user, message, DeadLetterReason::Expired);
+        }
+    }
+}
+
+/// Periodically archives rooms nobody has posted in for `policy.idle`.
+async fn run_archiver(state: Arc<ServerState>, policy: ArchivePolicy) {
+    let mut ticker = tokio::time::interval(policy.interval);
+    loop {
+        ticker.tick().await;
+        let cutoff = unix_time().saturating_sub(policy.idle.as_secs());
+        for room in state.registry.active_rooms() {
+            // Held so nothing is posted to the room while it's moved.
+            let lock = room_seq(&state, &room);
+            let _seq = lock.lock().await;
+            let result = async {
+                match state.store.high_water(&room).await? {
+                    Some(mark) if mark.timestamp < cutoff => {
+                        let archived = archive_room(&*state.store, &*state.cold, &room).await?;
+                        state
+                            .registry
+                            .update_room(&room, |config| config.archived = true);
+                        info!("Archived {} messages from idle room {}", archived, room);
+                    }
+                    _ => {}
+                }
+                anyhow::Ok(())
+            }
+            .await;
+            if let Err(e) = result {
+                error!("Archiving {} failed: {:?}", room, e);
+            }
+        }
+    }
+}
+
+/// Periodically destroys temporary rooms that have expired.
+async fn run_room_expiry(state: Arc<ServerState>) {
+    let mut ticker = tokio::time::interval(state.room_expiry_interval);
+    loop {
+        ticker.tick().await;
+        for room in state.registry.temporary_rooms() {
+            if let Err(e) = expire_room(&state, &room).await {
+                error!("Expiring {} failed: {:?}", room, e);
+            }
+        }
+    }
+}
+
+/// Destroys `room` if it's temporary and has expired: purges its history,
+/// forgets its settings and tells everyone it's gone.
+async fn expire_room(state: &ServerState, room: &str) -> Result<()> {
+    // Held so nothing is posted to the room while it's purged.
+    let lock = room_seq(state, room);
+    let _seq = lock.lock().await;
+    let Some(expiry) = state.registry.room_config(room).expiry else {
+        return Ok(());
+    };
+    let last_message = state
+        .store
+        .high_water(room)
+        .await?
+        .map(|mark| mark.timestamp);
+    if !expiry.is_due(last_message, unix_time()) {
+        return Ok(());
+    }
+    let messages = state.store.range(room, None, None).await?;
+    for message in &messages {
+        if let Some(id) = message.id {
+            state.store.remove(room, id).await?;
+        }
+    }
+    if state.registry.is_archived(room) {
+        state.cold.remove(room).await?;
+    }
+    state.registry.remove_room(room);
+    state.room_seqs.lock().unwrap().remove(room);
+    info!(
+        "Temporary room {} expired, purged {} messages",
+        room,
+        messages.len()
+    );
+    broadcast_frame(
+        state,
+        &ServerFrame::RoomExpired {
+            room: room.to_string(),
+        },
+    )
+}
+
+/// Brings an archived room's history back before it's used.
+async fn restore_archived(state: &ServerState, room: &str) -> Result<()> {
+    if state.registry.is_archived(room) {
+        let lock = room_seq(state, room);
+        let _seq = lock.lock().await;
+        unarchive(state, room).await?;
+    }
+    Ok(())
+}
+
+/// Restores `room` if it's archived; call with its `room_seq` held.
+async fn unarchive(state: &ServerState, room: &str) -> Result<()> {
+    if state.registry.is_archived(room) {
+        let restored = restore_room(&*state.store, &*state.cold, room).await?;
+        state
+            .registry
+            .update_room(room, |config| config.archived = false);
+        info!("Restored {} archived messages to {}", restored, room);
+    }
+    Ok(())
+}
+
+/// Disconnects the connections with the most queued bytes whenever the
+/// memory budget is exceeded.
+async fn run_shedder(state: Arc<ServerState>) {
+    loop {
+        state.memory.wait_exceeded().await;
+        match state.fanout.heaviest() {
+            Some((addr, queue)) => {
+                warn!(
+                    "Memory budget exceeded, disconnecting {} with {} bytes queued",
+                    addr,
+                    queue.queued_bytes()
+                );
+                queue.shed();
+                state.metrics.record_shed_connection();
+            }
+            // What's left belongs to connections that are already closing.
+            None => tokio::time::sleep(SHED_RETRY).await,
+        }
+    }
+}
+
+/// Holds a message until `delay` elapses, returning its pending id. It's
+/// stored until then, so it survives a restart.
+async fn schedule_message(
+    state: &Arc<ServerState>,
+    addr: SocketAddr,
+    message: ChatMessage,
+    delay: Duration,
+) -> Result<u64> {
+    let scheduled = ScheduledMessage {
+        pending_id: state.next_pending_id.fetch_add(1, Ordering::Relaxed),
+        from: addr,
+        message,
+    };
+    state.store.put_scheduled(&scheduled).await?;
+    let pending_id = scheduled.pending_id;
+    arm_scheduled(state.clone(), scheduled, delay);
+    Ok(pending_id)
+}
+
+/// Relays a stored scheduled message once `delay` elapses, then forgets it.
+fn arm_scheduled(state: Arc<ServerState>, scheduled: ScheduledMessage, delay: Duration) {
+    let ScheduledMessage {
+        pending_id,
+        from,
+        message,
+    } = scheduled;
+    let owner = message.sender.clone();
+    let mut held = state.scheduled.lock().unwrap();
+    let task_state = state.clone();
+    let handle = state.tasks.spawn(
+        "scheduled",
+        &format!("scheduled {}", pending_id),
+        async move {
+            tokio::time::sleep(delay).await;
+            task_state.scheduled.lock().unwrap().remove(&pending_id);
+            // The sender may have been banned, or the room made read-only,
+            // since it was scheduled.
+            let config = task_state.registry.room_config(message.room());
+            if task_state.registry.is_banned(&message.sender)
+                || !config.allows(&message.sender, RoomAction::Post)
+            {
+                debug!(
+                    "Dropping scheduled message {}: {} may no longer post in {}",
+                    pending_id,
+                    message.sender,
+                    message.room()
+                );
+            } else {
+                debug!("Delivering scheduled message {}", pending_id);
+                if let Err(e) = relay_message(&task_state, from, message).await {
+                    debug!("Scheduled message {} not delivered: {:?}", pending_id, e);
+                }
+            }
+            if let Err(e) = task_state.store.delete_scheduled(pending_id).await {
+                error!("Failed to forget scheduled message {}: {:?}", pending_id, e);
+            }
+        },
+    );
+    held.insert(pending_id, (owner, handle.abort_handle()));
+}
+
+/// Builds a `Replay` of stored messages newer than `last_id`, from the rooms
+/// `user` may read.
+async fn replay_after(
+    state: &ServerState,
+    addr: SocketAddr,
+    user: Option<&str>,
+    last_id: MessageId,
+) -> Result<ServerFrame> {
+    let mut messages = state.store.after(last_id, MAX_REPLAY).await?;
+    let complete = messages.len() < MAX_REPLAY;
+    messages.retain(|message| can_read(state, user, message.room()));
+    debug!("Replaying {} messages to {}", messages.len(), addr);
+    Ok(ServerFrame::Replay { complete, messages })
+}
+
+/// Runs a slash command sent as `sender`, returning any replies for the
+/// sender.
+async fn run_command(
+    command: Command,
+    sender: &str,
+    addr: SocketAddr,
+    state: &ServerState,
+    conn: &mut Connection,
+) -> Result<Vec<ServerFrame>> {
+    match command {
+        Command::Register { nick, password } => {
+            let nick = match ChatMessage::builder().sender(&nick).build() {
+                Ok(validated) => validated.sender,
+                Err(e) => return Ok(vec![error_frame(e.to_string())]),
+            };
+            if nick.starts_with(GUEST_PREFIX) {
+                return Ok(vec![error_frame(format!(
+                    "Nicknames starting with {} are reserved",
+                    GUEST_PREFIX
+                ))]);
+            }
+            if let Some(existing) = state.registry.lookalike_nick(&nick) {
+                return Ok(vec![nick_unavailable(nick, existing)]);
+            }
+            let hash = tokio::task::spawn_blocking(move || hash_password(&password)).await??;
+            if let Err(existing) = state.registry.register_nick(&nick, hash.clone()) {
+                return Ok(vec![nick_unavailable(nick, existing)]);
+            }
+            // Persisted before signing in, so a restart can't free the nick.
+            if let Err(e) = state.store.register_nick(&nick, &hash).await {
+                state.registry.unregister_nick(&nick);
+                return Err(e);
+            }
+            info!("Client {} registered {}", addr, nick);
+            sign_in(state, addr, conn, nick).await
+        }
+        Command::Identify { nick, password } => {
+            let nick = nickname::normalize(&nick);
+            let Some(hash) = state.registry.nick_password_hash(&nick) else {
+                return Ok(vec![error_frame(format!(
+                    "Nickname {} is not registered",
+                    nick
+                ))]);
+            };
+            let verified =
+                tokio::task::spawn_blocking(move || verify_password(&password, &hash)).await?;
+            if !verified {
+                info!("Failed identification as {} from {}", nick, addr);
+                return Ok(vec![error_frame("Wrong password")]);
+            }
+            info!("Client {} identified as {}", addr, nick);
+            sign_in(state, addr, conn, nick).await
+        }
+        Command::Block { user } => {
+            let Some(actor) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before blocking anyone")]);
+            };
+            let Some(user) = user.map(|user| nickname::normalize(&user)) else {
+                let blocked = state.registry.blocks(actor);
+                return Ok(vec![ServerFrame::Notice {
+                    text: if blocked.is_empty() {
+                        "You haven't blocked anyone".to_string()
+                    } else {
+                        format!("Blocked: {}", blocked.join(", "))
+                    },
+                }]);
+            };
+            if user == actor {
+                return Ok(vec![error_frame("You can't block yourself")]);
+            }
+            state.registry.block(actor, &user);
+            info!("{} blocked {}", actor, user);
+            Ok(vec![ServerFrame::Notice {
+                text: format!("Blocked {}", user),
+            }])
+        }
+        Command::Unblock { user } => {
+            let Some(actor) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before blocking anyone")]);
+            };
+            let user = nickname::normalize(&user);
+            let text = if state.registry.unblock(actor, &user) {
+                info!("{} unblocked {}", actor, user);
+                format!("Unblocked {}", user)
+            } else {
+                format!("{} isn't blocked", user)
+            };
+            Ok(vec![ServerFrame::Notice { text }])
+        }
+        command => {
+            let actor = match acting_user(state, conn, sender) {
+                Ok(actor) => actor,
+                Err(e) => return Ok(vec![error_frame(e)]),
+            };
+            if matches!(command, Command::Create { .. })
+                && matches!(conn.identity, Identity::Guest(_))
+            {
+                return Ok(vec![error_frame("Guests can't create rooms")]);
+            }
+            if conn.api_key.as_ref().is_some_and(|key| !key.scope.admin) {
+                return Ok(vec![error_frame("This API key can't run room commands")]);
+            }
+            let joining = match &command {
+                Command::Join { room } => {
+                    restore_archived(state, room).await?;
+                    let config = state.registry.room_config(room);
+                    (!config.members.contains(&actor)).then(|| room.clone())
+                }
+                _ => None,
+            };
+            let mut replies = run_room_command(command, actor.clone(), state)?;
+            if let Some(room) = joining
+                && state.registry.room_config(&room).members.contains(&actor)
+            {
+                replies.extend(welcome_to_room(state, &actor, &room));
+            }
+            Ok(replies)
+        }
+    }
+}
+
+/// Runs a command that changes a room's settings on behalf of `actor`.
+fn run_room_command(
+    command: Command,
+    actor: String,
+    state: &ServerState,
+) -> Result<Vec<ServerFrame>> {
+    match command {
+        Command::Create { room } => {
+            if let Err(e) = check_room_name(&room) {
+                return Ok(vec![error_frame(e.to_string())]);
+            }
+            let config = RoomConfig {
+                owner: Some(actor.clone()),
+                ..Default::default()
+            };
+            if let Err(exceeded) = state.resources.check_new_room() {
+                return Ok(vec![resource_exceeded_frame(state, exceeded)]);
+            }
+            if room == DEFAULT_ROOM || !state.registry.create_room(&room, config.clone()) {
+                return Ok(vec![error_frame(format!("Room {} already exists", room))]);
+            }
+            info!("{} created room {}", actor, room);
+            broadcast_frame(
+                state,
+                &ServerFrame::RoomUpdated {
+                    room,
+                    config,
+                    by: actor,
+                    member_count: 0,
+                },
+            )?;
+            Ok(Vec::new())
+        }
+        Command::Invite { user, room } => change_room(
+            state,
+            &actor,
+            &room,
+            Requires::Action(RoomAction::Invite),
+            |config| {
+                if !config.members.contains(&user) {
+                    state
+                        .resources
+                        .check_new_member(&room, config.members.len())
+                        .map_err(|e| Box::new(resource_exceeded_frame(state, e)))?;
+                }
+                match config.add_member(&user, false) {
+                    Ok(()) => Ok(Vec::new()),
+                    Err(_) => Err(Box::new(room_full_frame(&room, config, None))),
+                }
+            },
+        ),
+        Command::Join { room } => change_room(
+            state,
+            &actor,
+            &room,
+            Requires::Action(RoomAction::Invite),
+            |config| {
+                if !config.members.contains(&actor) {
+                    state
+                        .resources
+                        .check_new_member(&room, config.members.len())
+                        .map_err(|e| Box::new(resource_exceeded_frame(state, e)))?;
+                }
+                match config.add_member(&actor, true) {
+                    Ok(()) => Ok(Vec::new()),
+                    Err(position) => Err(Box::new(room_full_frame(&room, config, position))),
+                }
+            },
+        ),
+        Command::Leave { room } => change_room(state, &actor, &room, Requires::Anyone, |config| {
+            let admitted = config.remove_member(&actor);
+            Ok(admitted_frames(&room, admitted))
+        }),
+        Command::Topic { room, topic } => change_room(
+            state,
+            &actor,
+            &room,
+            Requires::Action(RoomAction::SetTopic),
+            |config| {
+                config.topic = Some(topic);
+                Ok(Vec::new())
+            },
+        ),
+        Command::Pin { room, message_id } => change_room(
+            state,
+            &actor,
+            &room,
+            Requires::Action(RoomAction::Pin),
+            |config| {
+                if !config.pinned.contains(&message_id) {
+                    config.pinned.push(message_id);
+                }
+                Ok(Vec::new())
+            },
+        ),
+        Command::Permission { room, action, role } => {
+            change_room(state, &actor, &room, Requires::Owner, |config| {
+                let permissions = &mut config.permissions;
+                *match action {
+                    RoomAction::Read => &mut permissions.read,
+                    RoomAction::Post => &mut permissions.post,
+                    RoomAction::Invite => &mut permissions.invite,
+                    RoomAction::SetTopic => &mut permissions.set_topic,
+                    RoomAction::Pin => &mut permissions.pin,
+                } = role;
+                Ok(Vec::new())
+            })
+        }
+        Command::Announce { room, enabled } => {
+            change_room(state, &actor, &room, Requires::Owner, |config| {
+                config.announcement = enabled;
+                Ok(Vec::new())
+            })
+        }
+        Command::Limit { room, max_members } => {
+            change_room(state, &actor, &room, Requires::Owner, |config| {
+                config.max_members = max_members;
+                let admitted = config.fill_from_waitlist();
+                Ok(admitted_frames(&room, admitted))
+            })
+        }
+        Command::Waitlist { room, enabled } => {
+            change_room(state, &actor, &room, Requires::Owner, |config| {
+                config.waitlist_enabled = enabled;
+                if !enabled {
+                    config.waitlist.clear();
+                }
+                Ok(Vec::new())
+            })
+        }
+        Command::Delivery { room, mode } => {
+            change_room(state, &actor, &room, Requires::Owner, |config| {
+                config.delivery = mode;
+                Ok(Vec::new())
+            })
+        }
+        Command::Expire {
+            room,
+            idle_secs,
+            ttl_secs,
+        } => {
+            if room == DEFAULT_ROOM {
+                return Ok(vec![error_frame(format!("{} can't expire", room))]);
+            }
+            change_room(state, &actor, &room, Requires::Owner, |config| {
+                if idle_secs.is_none() && ttl_secs.is_none() {
+                    config.expiry = None;
+                    return Ok(Vec::new());
+                }
+                let now = unix_time();
+                let expiry = config.expiry.get_or_insert(RoomExpiry {
+                    since: now,
+                    ..Default::default()
+                });
+                if idle_secs.is_some() {
+                    expiry.idle_secs = idle_secs;
+                }
+                if let Some(ttl_secs) = ttl_secs {
+                    expiry.expires_at = Some(now.saturating_add(ttl_secs));
+                }
+                Ok(Vec::new())
+            })
+        }
+        Command::Moderator { room, user } => {
+            change_room(state, &actor, &room, Requires::Owner, |config| {
+                config.moderators.insert(user);
+                Ok(Vec::new())
+            })
+        }
+        Command::Register { .. }
+        | Command::Identify { .. }
+        | Command::Block { .. }
+        | Command::Unblock { .. } => {
+            unreachable!("handled by run_command")
+        }
+    }
+}
+
+/// Who may make a change to a room.
+enum Requires {
+    /// The role the room's permissions require for the action.
+    Action(RoomAction),
+    Owner,
+    Anyone,
+}
+
+/// Applies `change` to a configured room if `actor` is allowed to, then
+/// broadcasts the new settings followed by any frames `change` returns.
+/// If `change` fails, only its frame is sent, as a reply.
+fn change_room(
+    state: &ServerState,
+    actor: &str,
+    room: &str,
+    requires: Requires,
+    change: impl FnOnce(&mut RoomConfig) -> Result<Vec<ServerFrame>, Box<ServerFrame>>,
+) -> Result<Vec<ServerFrame>> {
+    let what = match requires {
+        Requires::Action(RoomAction::Read) => "read",
+        Requires::Action(RoomAction::Post) => "post",
+        Requires::Action(RoomAction::Invite) => "invite",
+        Requires::Action(RoomAction::SetTopic) => "set the topic",
+        Requires::Action(RoomAction::Pin) => "pin messages",
+        Requires::Owner | Requires::Anyone => "change that",
+    };
+    let updated = state.registry.update_room(room, |config| {
+        let required = match requires {
+            Requires::Action(action) => config.required_role(action),
+            Requires::Owner => Role::Owner,
+            Requires::Anyone => Role::Everyone,
+        };
+        if config.role_of(actor) < required {
+            return Err(Box::new(denied_frame(room, required, what)));
+        }
+        let before = config.clone();
+        let frames = change(config)?;
+        Ok((before, config.clone(), frames))
+    });
+    match updated {
+        None => Ok(vec![error_frame(format!("Room {} does not exist", room))]),
+        Some(Err(reply)) => Ok(vec![*reply]),
+        Some(Ok((before, config, frames))) => {
+            info!("{} updated room {}", actor, room);
+            broadcast_room_change(state, room, &before, config, actor)?;
+            for frame in &frames {
+                broadcast_frame(state, frame)?;
+            }
+            Ok(Vec::new())
+        }
+    }
+}
+
+/// Broadcasts the change of a room's settings from `before` to `after`.
+fn broadcast_room_change(
+    state: &ServerState,
+    room: &str,
+    before: &RoomConfig,
+    after: RoomConfig,
+    by: &str,
+) -> Result<()> {
+    broadcast_frame(state, &room_change_frame(room, before, after, by))
+}
+
+/// Describes the change of a room's settings from `before` to `after`:
+/// who joined and left, if that's all that changed in a room too big to
+/// list, or else the new settings.
+pub(crate) fn room_change_frame(
+    room: &str,
+    before: &RoomConfig,
+    after: RoomConfig,
+    by: &str,
+) -> ServerFrame {
+    let member_count = after.members.len();
+    if member_count.max(before.members.len()) > MAX_LISTED_MEMBERS && after.same_but_members(before)
+    {
+        ServerFrame::MemberDelta {
+            room: room.to_string(),
+            added: after.members.difference(&before.members).cloned().collect(),
+            removed: before.members.difference(&after.members).cloned().collect(),
+            member_count,
+        }
+    } else {
+        ServerFrame::RoomUpdated {
+            room: room.to_string(),
+            config: after.listed(),
+            by: by.to_string(),
+            member_count,
+        }
+    }
+}
+
+/// Rejects a new member of a full room; `waitlist_position` is set if
+/// they were queued instead.
+fn room_full_frame(
+    room: &str,
+    config: &RoomConfig,
+    waitlist_position: Option<usize>,
+) -> ServerFrame {
+    ServerFrame::RoomFull {
+        room: room.to_string(),
+        max_members: config.max_members.unwrap_or_default(),
+        waitlist_position,
+    }
+}
+
+/// Announces users admitted to `room` from its waitlist.
+fn admitted_frames(room: &str, admitted: Vec<String>) -> Vec<ServerFrame> {
+    admitted
+        .into_iter()
+        .map(|user| ServerFrame::WaitlistAdmitted {
+            room: room.to_string(),
+            user,
+        })
+        .collect()
+}
+
+/// Returns who a connection acts as: its signed-in or guest name, or in
+/// open mode the claimed `sender` unless that nickname is registered. The
+/// error explains why it can't act.
+fn acting_user(state: &ServerState, conn: &Connection, sender: &str) -> Result<String, String> {
+    match &conn.identity {
+        Identity::User(user) | Identity::Guest(user) => Ok(user.clone()),
+        Identity::Open if state.registry.nick_password_hash(sender).is_some() => Err(format!(
+            "Nickname {} is registered; /identify first",
+            sender
+        )),
+        Identity::Open => Ok(sender.to_string()),
+        Identity::Unauthenticated => Err("Authenticate before sending commands".to_string()),
+    }
+}
+
+/// Makes `user` the connection's identity, giving up any guest nickname,
+/// and returns the replies: `Authenticated`, any unacknowledged messages,
+/// and the onboarding.
+async fn sign_in(
+    state: &ServerState,
+    addr: SocketAddr,
+    conn: &mut Connection,
+    user: String,
+) -> Result<Vec<ServerFrame>> {
+    if state.registry.is_banned(&user) {
+        info!("Client {} tried to sign in as banned user {}", addr, user);
+        return Ok(vec![banned_frame(&user)]);
+    }
+    if let Identity::Guest(guest) = &conn.identity {
+        state.guest_names.lock().unwrap().remove(guest);
+    }
+    state.registry.set_user(addr, &user);
+    conn.identity = Identity::User(user.clone());
+    conn.api_key = None;
+    let resume_token = conn.resume_token.get_or_insert_with(generate_token).clone();
+    authenticated(state, addr, &user, false).await;
+    let unacked = unacked_frame(state, &user);
+    let onboarding = onboard(state, &user).await?;
+    let notifications = state.registry.notification_prefs(&user);
+    Ok(std::iter::once(ServerFrame::Authenticated {
+        user,
+        resume_token,
+        notifications,
+    })
+    .chain(unacked)
+    .chain(onboarding)
+    .collect())
+}
+
+/// Runs the server's welcome for `user`, who just arrived, and makes them
+/// a member of the auto-join rooms. Returns the frames for them.
+async fn onboard(state: &ServerState, user: &str) -> Result<Vec<ServerFrame>> {
+    let onboarding = &state.onboarding;
+    let first_arrival = state.welcomed.lock().unwrap().insert(user.to_string());
+    let mut frames = welcome(state, &onboarding.welcome, user, None, first_arrival);
+    for room in &onboarding.auto_join {
+        restore_archived(state, room).await?;
+        if auto_join(state, user, room)? {
+            frames.extend(welcome_to_room(state, user, room));
+        }
+    }
+    Ok(frames)
+}
+
+/// Runs `room`'s welcome for its new member `user`, returning the frames
+/// for them.
+fn welcome_to_room(state: &ServerState, user: &str, room: &str) -> Vec<ServerFrame> {
+    match state.onboarding.rooms.get(room) {
+        Some(actions) => welcome(state, actions, user, Some(room), true),
+        None => Vec::new(),
+    }
+}
+
+/// Posts `actions`' webhook, if any, and returns its message of the day
+/// and, if `direct_message` is set, its direct message.
+fn welcome(
+    state: &ServerState,
+    actions: &WelcomeActions,
+    user: &str,
+    room: Option<&str>,
+    direct_message: bool,
+) -> Vec<ServerFrame> {
+    if let Some(url) = &actions.webhook {
+        let event = WebhookEvent {
+            event: match room {
+                Some(_) => OnboardingEvent::JoinedRoom,
+                None => OnboardingEvent::Arrived,
+            },
+            user: user.to_string(),
+            room: room.map(str::to_string),
+            timestamp: unix_time(),
+        };
+        spawn_webhook(&state.tasks, url.clone(), event);
+    }
+    let motd = actions.motd.clone().map(|text| ServerFrame::Motd {
+        room: room.map(str::to_string),
+        text,
+    });
+    let system_user = &state.onboarding.system_user;
+    let direct = actions
+        .direct_message
+        .as_ref()
+        .filter(|_| direct_message)
+        .map(|content| ServerFrame::Message {
+            from: system_user.clone(),
+            message: ChatMessage {
+                sender: system_user.clone(),
+                content: content.clone(),
+                id: Some(state.next_message_id.fetch_add(1, Ordering::Relaxed)),
+                timestamp: Some(unix_time()),
+                ..Default::default()
+            },
+        });
+    motd.into_iter().chain(direct).collect()
+}
+
+/// Makes `user` a member of `room` as if the system user invited them.
+/// Returns whether they weren't a member before; missing and full rooms
+/// are skipped.
+fn auto_join(state: &ServerState, user: &str, room: &str) -> Result<bool> {
+    let updated = state.registry.update_room(room, |config| {
+        let before = config.clone();
+        if config.members.contains(user)
+            || state
+                .resources
+                .check_new_member(room, config.members.len())
+                .is_err()
+            || config.add_member(user, false).is_err()
+        {
+            return None;
+        }
+        Some((before, config.clone()))
+    });
+    let Some(Some((before, config))) = updated else {
+        return Ok(false);
+    };
+    info!("Added {} to {}", user, room);
+    broadcast_room_change(state, room, &before, config, &state.onboarding.system_user)?;
+    Ok(true)
+}
+
+/// Gives `user` the room roles their authenticator granted. Rooms that
+/// don't exist are skipped, and an owner is only granted to a room
+/// without one.
+fn grant_roles(state: &ServerState, user: &str, grants: &[RoomGrant]) -> Result<()> {
+    for grant in grants {
+        let updated = state.registry.update_room(&grant.room, |config| {
+            let before = config.clone();
+            match grant.role {
+                Role::Everyone => {}
+                Role::Members => {
+                    config.members.insert(user.to_string());
+                }
+                Role::Moderators => {
+                    config.members.insert(user.to_string());
+                    config.moderators.insert(user.to_string());
+                }
+                Role::Owner => {
+                    config.members.insert(user.to_string());
+                    config.owner.get_or_insert_with(|| user.to_string());
+                }
+            }
+            (*config != before).then(|| (before, config.clone()))
+        });
+        let Some(Some((before, config))) = updated else {
+            continue;
+        };
+        info!("Granted {} {:?} in {}", user, grant.role, grant.room);
+        broadcast_room_change(
+            state,
+            &grant.room,
+            &before,
+            config,
+            &state.onboarding.system_user,
+        )?;
+    }
+    Ok(())
+}
+
+/// Returns the messages `user` hasn't acknowledged, if there are any.
+fn unacked_frame(state: &ServerState, user: &str) -> Option<ServerFrame> {
+    let messages = state.acks.pending(user);
+    (!messages.is_empty()).then_some(ServerFrame::Unacked { messages })
+}
+
+/// Whether a connection that still has to authenticate may send `frame`:
+/// only frames that sign in or set up the connection.
+fn allowed_before_auth(frame: &ClientFrame) -> bool {
+    matches!(
+        frame,
+        ClientFrame::Authenticate { .. }
+            | ClientFrame::ApiKey { .. }
+            | ClientFrame::ResumeSession { .. }
+            | ClientFrame::ChallengeResponse { .. }
+            | ClientFrame::SetLocale { .. }
+            | ClientFrame::SetCapabilities { .. }
+            | ClientFrame::Heartbeat
+    )
+}
+
+/// Applies a control frame, returning any replies for the sender.
+async fn handle_frame(
+    frame: ClientFrame,
+    addr: SocketAddr,
+    state: &Arc<ServerState>,
+    conn: &mut Connection,
+) -> Result<Vec<ServerFrame>> {
+    let uploads = &mut conn.uploads;
+    match frame {
+        ClientFrame::SetProfile {
+            display_name,
+            status_text,
+            state: presence_state,
+        } => {
+            let sanitize = |text: String| state.sanitize.sanitize(&text).into_owned();
+            let presence = state.registry.update_profile(
+                addr,
+                display_name.map(sanitize),
+                status_text.map(sanitize),
+                presence_state,
+            );
+            broadcast_presence(state, presence)?;
+            Ok(Vec::new())
+        }
+        ClientFrame::Heartbeat => Ok(Vec::new()),
+        ClientFrame::SetNotificationPrefs { prefs } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame(
+                    "Sign in before setting notification preferences",
+                )]);
+            };
+            if let Err(e) = prefs.validate() {
+                return Ok(vec![error_frame(e)]);
+            }
+            state.registry.set_notification_prefs(user, prefs.clone());
+            send_to_user(state, user, &ServerFrame::NotificationPrefs { prefs })?;
+            Ok(Vec::new())
+        }
+        ClientFrame::SetDraft { room, content } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before saving drafts")]);
+            };
+            if let Err(e) = check_room_name(&room) {
+                return Ok(vec![error_frame(e.to_string())]);
+            }
+            if content.len() > MAX_CONTENT_LEN {
+                let error = ValidationError::ContentTooLong {
+                    max: MAX_CONTENT_LEN,
+                };
+                return Ok(vec![error_frame(error.to_string())]);
+            }
+            let draft = Draft {
+                content,
+                updated_at: unix_time(),
+            };
+            state
+                .registry
+                .set_draft(user, &room, draft.clone(), MAX_DRAFTS);
+            let draft = Some(draft);
+            send_to_user(state, user, &ServerFrame::Draft { room, draft })?;
+            Ok(Vec::new())
+        }
+        ClientFrame::ClearDraft { room } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before saving drafts")]);
+            };
+            if state.registry.clear_draft(user, &room) {
+                send_to_user(state, user, &ServerFrame::Draft { room, draft: None })?;
+            }
+            Ok(Vec::new())
+        }
+        ClientFrame::SaveMessage { message_id } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before saving messages")]);
+            };
+            // Messages the user can't see are as good as unknown.
+            let message = match state.store.get(message_id).await? {
+                Some(message)
+                    if !state.registry.is_blocked(user, &message.sender)
+                        && can_read(state, Some(user), message.room()) =>
+                {
+                    message
+                }
+                _ => return Ok(vec![error_frame(format!("Unknown message {}", message_id))]),
+            };
+            if state.store.saved(user).await?.len() >= MAX_SAVED_MESSAGES {
+                return Ok(vec![error_frame(format!(
+                    "Can't save more than {} messages",
+                    MAX_SAVED_MESSAGES
+                ))]);
+            }
+            state.store.save(user, &message, unix_time()).await?;
+            Ok(vec![ServerFrame::MessageSaved { message_id }])
+        }
+        ClientFrame::UnsaveMessage { message_id } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before saving messages")]);
+            };
+            if !state.store.unsave(user, message_id).await? {
+                return Ok(vec![error_frame(format!(
+                    "Message {} isn't saved",
+                    message_id
+                ))]);
+            }
+            Ok(vec![ServerFrame::MessageUnsaved { message_id }])
+        }
+        ClientFrame::ListSaved => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before fetching saved messages")]);
+            };
+            // Saved messages stay hidden while the user can't read their
+            // room, e.g. after leaving it.
+            let mut messages = state.store.saved(user).await?;
+            messages.retain(|saved| can_read(state, Some(user), saved.message.room()));
+            Ok(vec![ServerFrame::Saved { messages }])
+        }
+        ClientFrame::GetDrafts => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before fetching drafts")]);
+            };
+            Ok(vec![ServerFrame::Drafts {
+                drafts: state.registry.drafts(user),
+            }])
+        }
+        ClientFrame::RegisterPushToken { push } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before registering for pushes")]);
+            };
+            if !state
+                .push
+                .as_ref()
+                .is_some_and(|gateway| gateway.supports(push.platform))
+            {
+                return Ok(vec![error_frame(format!(
+                    "Push notifications via {} aren't available",
+                    push.platform
+                ))]);
+            }
+            if push.token.is_empty() || push.token.len() > MAX_TOKEN_LEN {
+                return Ok(vec![error_frame("Invalid push token")]);
+            }
+            info!("{} registered a {} push token", user, push.platform);
+            state
+                .registry
+                .add_push_token(user, push, MAX_TOKENS_PER_USER);
+            Ok(Vec::new())
+        }
+        ClientFrame::UnregisterPushToken { token } => {
+            if let Some(user) = conn.identity.user() {
+                state.registry.remove_push_token(user, &token);
+            }
+            Ok(Vec::new())
+        }
+        ClientFrame::ListEmoji => Ok(vec![ServerFrame::EmojiList {
+            emoji: state.registry.emoji(),
+        }]),
+        ClientFrame::DebugInfo => {
+            let Some(user) = conn
+                .identity
+                .user()
+                .filter(|user| state.debuggers.contains(*user))
+            else {
+                return Ok(vec![error_frame("Not allowed to ask for debug info")]);
+            };
+            Ok(vec![ServerFrame::DebugInfo(Box::new(debug_info(
+                state, addr, conn, user,
+            )))])
+        }
+        ClientFrame::Ack { room, seq } => match &conn.identity {
+            Identity::User(user) | Identity::Guest(user) => {
+                let released = state.acks.ack(user, &room, seq);
+                debug!("{} acknowledged {} messages in {}", user, released, room);
+                Ok(Vec::new())
+            }
+            Identity::Open | Identity::Unauthenticated => {
+                Ok(vec![error_frame("Sign in before acknowledging messages")])
+            }
+        },
+        ClientFrame::Backfill {
+            room,
+            from_seq,
+            to_seq,
+        } => {
+            if from_seq > to_seq {
+                return Ok(vec![error_frame("from_seq is after to_seq")]);
+            }
+            if !can_read(state, conn.identity.user(), &room) {
+                return Ok(vec![read_denied(state, &room)]);
+            }
+            restore_archived(state, &room).await?;
+            let messages = state
+                .store
+                .sequence(&room, from_seq, to_seq, MAX_REPLAY)
+                .await?;
+            debug!("Backfilling {} messages to {}", messages.len(), addr);
+            Ok(vec![ServerFrame::Backfill {
+                room,
+                complete: messages.len() < MAX_REPLAY,
+                messages,
+            }])
+        }
+        ClientFrame::Presence => Ok(vec![ServerFrame::Presence {
+            users: state.registry.presence(),
+        }]),
+        ClientFrame::SubscribePresence { users, rooms } => {
+            let mut subscription = state
+                .registry
+                .presence_subscription(addr)
+                .unwrap_or_default();
+            subscription.users.extend(users);
+            subscription.rooms.extend(rooms);
+            if subscription.len() > MAX_PRESENCE_SUBSCRIPTIONS {
+                return Ok(vec![error_frame(format!(
+                    "At most {} users and rooms can be followed",
+                    MAX_PRESENCE_SUBSCRIPTIONS
+                ))]);
+            }
+            Ok(vec![subscribe_presence(state, addr, Some(subscription))])
+        }
+        ClientFrame::UnsubscribePresence { users, rooms } => {
+            let subscription = state
+                .registry
+                .presence_subscription(addr)
+                .filter(|_| !users.is_empty() || !rooms.is_empty())
+                .map(|mut subscription| {
+                    for user in &users {
+                        subscription.users.remove(user);
+                    }
+                    for room in &rooms {
+                        subscription.rooms.remove(room);
+                    }
+                    subscription
+                });
+            Ok(vec![subscribe_presence(state, addr, subscription)])
+        }
+        ClientFrame::FileOffer {
+            name,
+            size,
+            hash,
+            room,
+        } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before sharing files")]);
+            };
+            let room = room.unwrap_or_else(|| DEFAULT_ROOM.to_string());
+            let config = state.registry.room_config(&room);
+            if !config.allows(user, RoomAction::Post) {
+                let required = config.required_role(RoomAction::Post);
+                return Ok(vec![denied_frame(&room, required, "share files")]);
+            }
+            if size > MAX_BLOB_SIZE {
+                return Ok(vec![error_frame(format!(
+                    "File too large: {} bytes (limit {})",
+                    size, MAX_BLOB_SIZE
+                ))]);
+            }
+            if !is_valid_blob_id(&hash) {
+                return Ok(vec![error_frame("File hash must be hex SHA-256")]);
+            }
+            if uploads.len() >= MAX_UPLOADS {
+                return Ok(vec![error_frame("Too many uploads in progress")]);
+            }
+            let transfer_id = conn.next_transfer_id;
+            conn.next_transfer_id += 1;
+            info!("Accepting upload {} ({} bytes) from {}", name, size, addr);
+            uploads.insert(
+                transfer_id,
+                Upload {
+                    room,
+                    name,
+                    size,
+                    hash,
+                    data: BytesMut::new(),
+                    memory: state.memory.account(addr),
+                },
+            );
+            let mut replies = vec![ServerFrame::FileAccepted {
+                transfer_id,
+                chunk_size: FILE_CHUNK_SIZE,
+                window: FILE_WINDOW,
+            }];
+            if size == 0 {
+                replies.extend(receive_chunk(addr, state, uploads, transfer_id, &[]).await?);
+            }
+            Ok(replies)
+        }
+        ClientFrame::FileChunk { transfer_id, data } => {
+            let Ok(data) = BASE64.decode(data) else {
+                uploads.remove(&transfer_id);
+                return Ok(vec![error_frame("File chunk is not valid base64")]);
+            };
+            receive_chunk(addr, state, uploads, transfer_id, &data).await
+        }
+        ClientFrame::CreateInvite {
+            room,
+            expires_in_secs,
+            max_uses,
+        } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before inviting anyone")]);
+            };
+            if conn.api_key.as_ref().is_some_and(|key| !key.scope.admin) {
+                return Ok(vec![error_frame("This API key can't run room commands")]);
+            }
+            if !state.registry.has_room(&room) {
+                return Ok(vec![error_frame(format!("Room {} does not exist", room))]);
+            }
+            let config = state.registry.room_config(&room);
+            if !config.allows(user, RoomAction::Invite) {
+                let required = config.required_role(RoomAction::Invite);
+                return Ok(vec![denied_frame(&room, required, "invite")]);
+            }
+            let now = unix_time();
+            let invite = Invite {
+                token: generate_token(),
+                room,
+                created_by: user.to_string(),
+                created_at: now,
+                expires_at: expires_in_secs.map(|secs| now.saturating_add(secs)),
+                max_uses,
+                uses: 0,
+            };
+            info!("{} created an invite to {}", user, invite.room);
+            state.registry.add_invite(invite.clone());
+            Ok(vec![ServerFrame::InviteCreated { invite }])
+        }
+        ClientFrame::Forward {
+            message_id,
+            to_room,
+        } => {
+            let Identity::User(user) = &conn.identity else {
+                return Ok(vec![error_frame("Sign in before forwarding messages")]);
+            };
+            if conn.api_key.as_ref().is_some_and(|key| key.scope.read_only) {
+                return Ok(vec![error_frame("This API key is read-only")]);
+            }
+            let Some(original) = state.store.get(message_id).await? else {
+                return Ok(vec![error_frame(format!("Unknown message {}", message_id))]);
+            };
+            if state.registry.is_blocked(user, &original.sender)
+                || !can_read(state, Some(user), original.room())
+            {
+                return Ok(vec![error_frame(format!("Unknown message {}", message_id))]);
+            }
+            let forwarded = original.forwarded.clone().unwrap_or_else(|| Forwarded {
+                message_id,
+                room: original.room().to_string(),
+                sender: original.sender.clone(),
+                timestamp: original.timestamp,
+            });
+            let message = ChatMessage {
+                sender: user.clone(),
+                content: original.content,
+                room: Some(to_room),
+                format: original.format,
+                entities: original.entities,
+                forwarded: Some(forwarded),
+                attachment: original.attachment,
+                ..Default::default()
+            };
+            let message = match message.validated() {
+                Ok(message) => message,
+                Err(e) => return Ok(vec![error_frame(e.to_string())]),
+            };
+            post_message(state, addr, conn, message).await
+        }
+        ClientFrame::RedeemInvite { token } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame("Sign in before using an invite")]);
+            };
+            if conn.api_key.as_ref().is_some_and(|key| key.scope.read_only) {
+                return Ok(vec![error_frame("This API key is read-only")]);
+            }
+            let redeemed =
+                invite::redeem(&state.registry, &state.resources, user, &token, unix_time());
+            let (room, joined) = match redeemed {
+                Ok(redeemed) => redeemed,
+                Err(e) => return Ok(vec![error_frame(e.to_string())]),
+            };
+            let mut replies = vec![ServerFrame::InviteRedeemed { room: room.clone() }];
+            if let Some(joined) = joined {
+                info!("{} joined {} by invite", user, room);
+                broadcast_room_change(state, &room, &joined.before, joined.after, user)?;
+                replies.extend(welcome_to_room(state, user, &room));
+            }
+            Ok(replies)
+        }
+        ClientFrame::ListRooms { filter, page } => {
+            let filter = filter.map(|filter| filter.to_lowercase());
+            let now = unix_time();
+            let mut rooms: Vec<(u64, RoomListing)> = state
+                .registry
+                .public_rooms()
+                .into_iter()
+                .filter(|(room, config)| {
+                    filter.as_ref().is_none_or(|filter| {
+                        room.to_lowercase().contains(filter)
+                            || config
+                                .topic
+                                .as_ref()
+                                .is_some_and(|topic| topic.to_lowercase().contains(filter))
+                    })
+                })
+                .map(|(room, config)| {
+                    let messages = state.analytics.recent_messages(&room, now);
+                    let listing = RoomListing {
+                        member_count: config.members.len(),
+                        topic: config.topic,
+                        activity: ActivityLevel::from_messages(messages),
+                        room,
+                    };
+                    (messages, listing)
+                })
+                .collect();
+            rooms.sort_by(|(a_messages, a), (b_messages, b)| {
+                (b_messages, b.member_count)
+                    .cmp(&(a_messages, a.member_count))
+                    .then_with(|| a.room.cmp(&b.room))
+            });
+            let total = rooms.len();
+            let start = page.saturating_mul(ROOMS_PAGE_SIZE).min(total);
+            let end = (start + ROOMS_PAGE_SIZE).min(total);
+            Ok(vec![ServerFrame::Rooms {
+                rooms: rooms
+                    .drain(start..end)
+                    .map(|(_, listing)| listing)
+                    .collect(),
+                page,
+                total,
+                has_more: end < total,
+            }])
+        }
+        ClientFrame::ListMembers { room, after, limit } => {
+            if !state.registry.has_room(&room) {
+                return Ok(vec![error_frame(format!("Room {} does not exist", room))]);
+            }
+            let config = state.registry.room_config(&room);
+            let limit = limit
+                .unwrap_or(DEFAULT_MEMBERS_LIMIT)
+                .min(MAX_MEMBERS_LIMIT);
+            let members = config.members_after(after.as_deref(), limit);
+            let has_more = members
+                .last()
+                .is_some_and(|last| !config.members_after(Some(last), 1).is_empty());
+            Ok(vec![ServerFrame::Members {
+                room,
+                members,
+                member_count: config.members.len(),
+                has_more,
+            }])
+        }
+        ClientFrame::FetchHistory {
+            room,
+            before,
+            limit,
+        } => {
+            let room = room.unwrap_or_else(|| DEFAULT_ROOM.to_string());
+            if !can_read(state, conn.identity.user(), &room) {
+                return Ok(vec![read_denied(state, &room)]);
+            }
+            let limit = limit
+                .unwrap_or(DEFAULT_HISTORY_LIMIT)
+                .min(MAX_HISTORY_LIMIT);
+            restore_archived(state, &room).await?;
+            let (mut messages, has_more) = state.store.page(&room, before, limit).await?;
+            if let Some(user) = conn.identity.user() {
+                messages.retain(|message| !state.registry.is_blocked(user, &message.sender));
+            }
+            Ok(vec![ServerFrame::History {
+                room,
+                messages,
+                has_more,
+            }])
+        }
+        ClientFrame::Resume { last_id } => Ok(vec![
+            replay_after(state, addr, conn.identity.user(), last_id).await?,
+        ]),
+        ClientFrame::ResumeSession { token, last_id } => {
+            let session = {
+                let mut sessions = state.sessions.lock().unwrap();
+                expire_sessions(state, &mut sessions);
+                sessions.remove(&token)
+            };
+            let Some(session) = session.filter(|session| {
+                session
+                    .api_key
+                    .as_ref()
+                    .is_none_or(|key| state.registry.api_keys().is_active(&key.id, unix_time()))
+                    && session
+                        .identity
+                        .user()
+                        .is_none_or(|user| !state.registry.is_banned(user))
+            }) else {
+                return Ok(vec![error_frame("Unknown or expired session")]);
+            };
+            let guest = matches!(session.identity, Identity::Guest(_));
+            let user = match session.identity.user() {
+                Some(user) => user.to_string(),
+                None => unreachable!("no token issued"),
+            };
+            if let Identity::Guest(guest) = &conn.identity {
+                state.guest_names.lock().unwrap().remove(guest);
+            }
+            info!("Client {} resumed session of {}", addr, user);
+            conn.identity = session.identity;
+            conn.api_key = session.api_key;
+            conn.resume_token = Some(token.clone());
+            state.registry.set_profile(addr, session.profile);
+            state
+                .registry
+                .set_presence_subscription(addr, session.presence_subscription);
+            state.registry.set_user(addr, &user);
+            authenticated(state, addr, &user, guest).await;
+            let unacked = unacked_frame(state, &user);
+            let notifications = state.registry.notification_prefs(&user);
+            let mut replies = vec![
+                ServerFrame::SessionResumed {
+                    user,
+                    resume_token: token,
+                    notifications,
+                },
+                replay_after(
+                    state,
+                    addr,
+                    conn.identity.user(),
+                    last_id.unwrap_or(session.last_id),
+                )
+                .await?,
+            ];
+            replies.extend(unacked);
+            Ok(replies)
+        }
+        ClientFrame::Search {
+            query,
+            room,
+            limit,
+            before,
+        } => {
+            if let Some(room) = &room
+                && !can_read(state, conn.identity.user(), room)
+            {
+                return Ok(vec![read_denied(state, room)]);
+            }
+            let limit = limit.unwrap_or(DEFAULT_SEARCH_LIMIT).min(MAX_SEARCH_LIMIT);
+            let (mut hits, next_cursor) = state
+                .store
+                .search(&query, room.as_deref(), before, limit)
+                .await?;
+            // Searching every room leaves out those the user can't read,
+            // so a page can come back short.
+            hits.retain(|hit| can_read(state, conn.identity.user(), hit.message.room()));
+            Ok(vec![ServerFrame::SearchResults { hits, next_cursor }])
+        }
+        ClientFrame::CancelScheduled { pending_id } => {
+            let Some(user) = conn.identity.user() else {
+                return Ok(vec![error_frame(
+                    "Sign in before cancelling scheduled messages",
+                )]);
+            };
+            let cancelled = {
+                let mut scheduled = state.scheduled.lock().unwrap();
+                match scheduled.get(&pending_id) {
+                    Some((owner, handle)) if owner == user => {
+                        handle.abort();
+                        scheduled.remove(&pending_id);
+                        true
+                    }
+                    _ => false,
+                }
+            };
+            if !cancelled {
+                return Ok(vec![error_frame(format!(
+                    "No scheduled message {}",
+                    pending_id
+                ))]);
+            }
+            state.store.delete_scheduled(pending_id).await?;
+            Ok(vec![ServerFrame::ScheduleCancelled { pending_id }])
+        }
+        ClientFrame::Authenticate { user, token } => {
+            let Some(authenticator) = &state.authenticator else {
+                return Ok(vec![error_frame("Authentication is not enabled")]);
+            };
+            let Some(signed_in) = authenticator.sign_in(&user, &token).await? else {
+                info!("Failed authentication as {} from {}", user, addr);
+                return Ok(vec![error_frame("Authentication failed")]);
+            };
+            let user = match ChatMessage::builder().sender(&signed_in.user).build() {
+                Ok(validated) if !validated.sender.starts_with(GUEST_PREFIX) => validated.sender,
+                _ => {
+                    info!(
+                        "Client {} signed in as unusable nickname {}",
+                        addr, signed_in.user
+                    );
+                    return Ok(vec![error_frame("Authentication failed")]);
+                }
+            };
+            info!("Client {} authenticated as {}", addr, user);
+            let replies = sign_in(state, addr, conn, user.clone()).await?;
+            if conn.identity.user() == Some(user.as_str()) {
+                grant_roles(state, &user, &signed_in.grants)?;
+            }
+            Ok(replies)
+        }
+        ClientFrame::ChallengeResponse { .. } => Ok(vec![error_frame("No challenge to answer")]),
+        ClientFrame::SetLocale { locales } => {
+            let locale = state.catalogs.negotiate(&locales);
+            debug!("Client {} asked for {:?}, got {}", addr, locales, locale);
+            state.registry.set_locale(addr, &locale);
+            Ok(vec![ServerFrame::LocaleSelected { locale }])
+        }
+        ClientFrame::SetCapabilities { mut capabilities } => {
+            capabilities.remove(&Capability::Unknown);
+            debug!("Client {} handles {:?}", addr, capabilities);
+            state.registry.set_capabilities(addr, capabilities.clone());
+            Ok(vec![ServerFrame::CapabilitiesSelected { capabilities }])
+        }
+        ClientFrame::SelectTenant { .. } => Ok(vec![error_frame(
+            "A tenant can only be selected as the first frame",
+        )]),
+        ClientFrame::ApiKey { key } => {
+            let Some(key) = state.registry.api_keys().verify(&key, unix_time()) else {
+                info!("Failed API key sign-in from {}", addr);
+                return Ok(vec![error_frame("Invalid API key")]);
+            };
+            info!(
+                "Client {} signed in as {} with API key {}",
+                addr, key.account, key.id
+            );
+            if state.registry.is_banned(&key.account) {
+                return Ok(vec![banned_frame(&key.account)]);
+            }
+            let replies = sign_in(state, addr, conn, key.account.clone()).await?;
+            conn.api_key = Some(key);
+            Ok(replies)
+        }
+        ClientFrame::FetchFile { id } => {
+            if !is_valid_blob_id(&id) {
+                return Ok(vec![error_frame("Invalid file id")]);
+            }
+            // Files the client can't see are as good as unknown.
+            let data = match can_fetch(state, conn.identity.user(), &id).await? {
+                true => state.blobs.get(&id).await?,
+                false => None,
+            };
+            let Some(data) = data else {
+                return Ok(vec![error_frame(format!("Unknown file {}", id))]);
+            };
+            if !conn.downloads.contains_key(&id) && conn.downloads.len() >= MAX_DOWNLOADS {
+                return Ok(vec![error_frame("Too many downloads in progress")]);
+            }
+            let mut download = Download::new(data, state.memory.account(addr));
+            let replies = download.next_frames(&id);
+            if download.finished() {
+                conn.downloads.remove(&id);
+            } else {
+                conn.downloads.insert(id, download);
+            }
+            Ok(replies)
+        }
+        ClientFrame::FileReceived { id, received } => {
+            // Receipts for a stream that has already ended need no reply.
+            let Some(download) = conn.downloads.get_mut(&id) else {
+                return Ok(Vec::new());
+            };
+            let received = usize::try_from(received).unwrap_or(usize::MAX);
+            download.acked = download.acked.max(received.min(download.sent));
+            let replies = download.next_frames(&id);
+            if download.finished() {
+                conn.downloads.remove(&id);
+            }
+            Ok(replies)
+        }
+    }
+}
+
+/// Whether `user` may fetch the file `blob_id`: a custom emoji's image, or
+/// a file shared in a room they can read.
+async fn can_fetch(state: &ServerState, user: Option<&str>, blob_id: &str) -> Result<bool> {
+    if state.registry.is_emoji_image(blob_id) {
+        return Ok(true);
+    }
+    let rooms = state.store.file_rooms(blob_id).await?;
+    Ok(rooms.iter().any(|room| can_read(state, user, room)))
+}
+
+/// Appends a chunk to an upload, storing and announcing the file once complete.
+async fn receive_chunk(
+    addr: SocketAddr,
+    state: &ServerState,
+    uploads: &mut HashMap<u64, Upload>,
+    transfer_id: u64,
+    chunk: &[u8],
+) -> Result<Vec<ServerFrame>> {
+    let Some(upload) = uploads.get_mut(&transfer_id) else {
+        return Ok(vec![error_frame(format!(
+            "Unknown transfer {}",
+            transfer_id
+        ))]);
+    };
+    if chunk.len() > FILE_CHUNK_SIZE || upload.data.len() as u64 + chunk.len() as u64 > upload.size
+    {
+        uploads.remove(&transfer_id);
+        return Ok(vec![error_frame("File chunk exceeds announced size")]);
+    }
+    upload.data.extend_from_slice(chunk);
+    upload.memory.charge(chunk.len());
+    let received = upload.data.len() as u64;
+    let mut replies = vec![ServerFrame::FileProgress {
+        transfer_id,
+        received,
+    }];
+    if received < upload.size {
+        return Ok(replies);
+    }
+
+    let upload = uploads.remove(&transfer_id).expect("upload present");
+    let data: Bytes = upload.data.freeze();
+    if blob_id(&data) != upload.hash {
+        replies.push(error_frame(format!("Hash mismatch for {}", upload.name)));
+        return Ok(replies);
+    }
+    state.blobs.put(&upload.hash, data).await?;
+    state.store.share_file(&upload.hash, &upload.room).await?;
+    info!("Stored upload {} from {}", upload.name, addr);
+    broadcast_to_room(
+        state,
+        &upload.room,
+        &ServerFrame::FileShared {
+            from: addr.to_string(),
+            room: upload.room.clone(),
+            file: FileRef {
+                id: upload.hash,
+                name: upload.name,
+                size: upload.size,
+            },
+        },
+        HashSet::new(),
+    )?;
+    Ok(replies)
+}
+
+/// Deletes an ephemeral message from history once its TTL elapses and tells
+/// clients to drop it.
+fn schedule_expiry(state: Arc<ServerState>, room: String, id: MessageId, ttl: Duration) {
+    state
+        .tasks
+        .clone()
+        .spawn("ephemeral", "ephemeral expiry", async move {
+            tokio::time::sleep(ttl).await;
+            let removed = match state.store.remove(&room, id).await {
+                Ok(removed) => removed,
+                Err(e) => {
+                    error!("Failed to expire message {}: {:?}", id, e);
+                    false
+                }
+            };
+            if removed {
+                debug!("Expiring message {} in {}", id, room);
+                let frame = ServerFrame::Expire { message_id: id };
+                if let Err(e) = broadcast_to_room(&state, &room, &frame, HashSet::new()) {
+                    debug!("No clients to notify of expiry: {:?}", e);
+                }
+            }
+        });
+}
+
+/// Seconds since the Unix epoch.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Picks an unused `guest-NNNN` nickname and reserves it.
+fn allocate_guest_name(state: &ServerState) -> String {
+    let mut names = state.guest_names.lock().unwrap();
+    let hasher = RandomState::new();
+    for attempt in 0u32.. {
+        // Use longer numbers once short ones keep colliding.
+        let low = 10u64.pow(3 + attempt / 100);
+        let name = format!("guest-{}", low + hasher.hash_one(attempt) % (9 * low));
+        if names.insert(name.clone()) {
+            return name;
+        }
+    }
+    unreachable!("guest names exhausted")
+}
+
+fn banned_frame(user: &str) -> ServerFrame {
+    error_frame(format!("{} is banned", user))
+}
+
+/// Counts a rejection by the resource limits and describes it to the client.
+fn resource_exceeded_frame(state: &ServerState, exceeded: ResourceExceeded) -> ServerFrame {
+    state.metrics.record_resource_rejection();
+    ServerFrame::ResourceExceeded {
+        resource: exceeded.resource,
+        room: exceeded.room,
+        limit: exceeded.limit,
+    }
+}
+
+fn quota_exceeded_frame(exceeded: QuotaExceeded) -> ServerFrame {
+    ServerFrame::QuotaExceeded {
+        window: exceeded.window,
+        resource: exceeded.resource,
+        limit: exceeded.limit,
+        resets_at: exceeded.resets_at,
+    }
+}
+
+/// Rejects an action in `room` that needs `required`.
+fn denied_frame(room: &str, required: Role, what: &str) -> ServerFrame {
+    let who = match required {
+        Role::Owner => "the owner".to_string(),
+        role => role.to_string(),
+    };
+    error_frame(format!("Only {} can {} in {}", who, what, room))
+}
+
+fn error_frame(message: impl Into<String>) -> ServerFrame {
+    ServerFrame::Error {
+        message: message.into(),
+    }
+}
+
+/// Why `nick` can't be registered: it's `existing`, or looks like it.
+fn nick_unavailable(nick: String, existing: String) -> ServerFrame {
+    if nick == existing {
+        error_frame(format!("Nickname {} is taken", nick))
+    } else {
+        ServerFrame::NicknameConflict {
+            nick,
+            conflicts_with: existing,
+        }
+    }
+}
+
+/// Sends a frame to every connected client.
+fn broadcast_frame(state: &ServerState, frame: &ServerFrame) -> Result<()> {
+    broadcast_frame_except(state, frame, HashSet::new())
+}
+
+/// Sends a frame to every connection of `user`.
+fn send_to_user(state: &ServerState, user: &str, frame: &ServerFrame) -> Result<()> {
+    send_to_devices(state, state.registry.devices_of(user), frame)
+}
+
+/// Sends a frame straight to each of `devices`, downgraded for those
+/// lacking the capabilities it needs.
+fn send_to_devices(
+    state: &ServerState,
+    devices: impl IntoIterator<Item = SocketAddr>,
+    frame: &ServerFrame,
+) -> Result<()> {
+    let encoded = Encoded::new(frame.clone())?;
+    for device in devices {
+        match state.registry.capabilities(device) {
+            Some(capabilities) if !frame.capabilities().is_subset(&capabilities) => {
+                let downgraded = Encoded::new(frame.clone().downgrade(&capabilities))?;
+                state.fanout.send_to(device, frame.priority(), &downgraded);
+            }
+            _ => {
+                state.fanout.send_to(device, frame.priority(), &encoded);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sends a frame to every connected client but those in `except`.
+/// Clients lacking the capabilities it needs are sent it downgraded.
+fn broadcast_frame_except(
+    state: &ServerState,
+    frame: &ServerFrame,
+    mut except: HashSet<SocketAddr>,
+) -> Result<()> {
+    let priority = frame.priority();
+    for (capabilities, mut devices) in state.registry.lacking(&frame.capabilities()) {
+        let downgraded = frame.clone().downgrade(&capabilities);
+        devices.retain(|device| !except.contains(device));
+        except.extend(devices.iter().copied());
+        state
+            .fanout
+            .send_only(priority, Arc::new(Encoded::new(downgraded)?), devices);
+    }
+    let frame = Encoded::new(frame.clone())?;
+    debug!("Broadcasting: {}", frame.json());
+    state.fanout.send_except(priority, Arc::new(frame), except);
+    Ok(())
+}
+
+/// Sends a frame about `room` to every connection that may read it but
+/// those in `except`: everyone, unless the room's read permission is
+/// limited, and then only the devices of the participants it admits.
+fn broadcast_to_room(
+    state: &ServerState,
+    room: &str,
+    frame: &ServerFrame,
+    except: HashSet<SocketAddr>,
+) -> Result<()> {
+    let config = state.registry.room_config(room);
+    if config.required_role(RoomAction::Read) == Role::Everyone {
+        return broadcast_frame_except(state, frame, except);
+    }
+    let readers = config
+        .participants()
+        .filter(|user| config.allows(user, RoomAction::Read))
+        .flat_map(|user| state.registry.devices_of(user))
+        .filter(|device| !except.contains(device))
+        .collect();
+    broadcast_frame_only(state, frame, readers)
+}
+
+/// Sends a frame to each of `only`, ordered with respect to broadcasts.
+/// Clients lacking the capabilities it needs are sent it downgraded.
+fn broadcast_frame_only(
+    state: &ServerState,
+    frame: &ServerFrame,
+    mut only: HashSet<SocketAddr>,
+) -> Result<()> {
+    let priority = frame.priority();
+    for (capabilities, mut devices) in state.registry.lacking(&frame.capabilities()) {
+        devices.retain(|device| only.remove(device));
+        if devices.is_empty() {
+            continue;
+        }
+        let downgraded = frame.clone().downgrade(&capabilities);
+        state
+            .fanout
+            .send_only(priority, Arc::new(Encoded::new(downgraded)?), devices);
+    }
+    let frame = Encoded::new(frame.clone())?;
+    debug!("Sending to {} connections: {}", only.len(), frame.json());
+    state.fanout.send_only(priority, Arc::new(frame), only);
+    Ok(())
+}
+
+/// Whether `user` may read `room`: receive its messages and fetch its
+/// history. Without a user, as on open connections, only rooms everyone
+/// may read can be.
+fn can_read(state: &ServerState, user: Option<&str>, room: &str) -> bool {
+    let config = state.registry.room_config(room);
+    match user {
+        Some(user) => config.allows(user, RoomAction::Read),
+        None => config.required_role(RoomAction::Read) == Role::Everyone,
+    }
+}
+
+/// The reply to reading `room` without permission.
+fn read_denied(state: &ServerState, room: &str) -> ServerFrame {
+    let required = state
+        .registry
+        .room_config(room)
+        .required_role(RoomAction::Read);
+    denied_frame(room, required, "read")
+}
+
+/// Queues a frame for one client in its priority lane.
+fn queue_frame(outbound: &OutboundQueue, frame: ServerFrame) -> Result<()> {
+    let priority = frame.priority();
+    let frame = Encoded::new(frame)?;
+    debug!("Replying: {}", frame.json());
+    outbound.push_frame(priority, &frame);
+    Ok(())
+}
+
+/// Writes a single newline-delimited frame directly to one client,
+/// recording it if the connection is recorded.
+async fn send_frame(
+    socket: &mut BoxStream,
+    frame: &ServerFrame,
+    recorder: Option<&Recorder>,
+) -> Result<()> {
+    let json = frame.to_json()?;
+    debug!("Replying: {}", json);
+    if let Some(recorder) = recorder {
+        recorder.record(Direction::Outbound, &json);
     }
+    socket.write_all(json.as_bytes()).await?;
+    socket.write_all(b"\n").await?;
+    Ok(())
 }
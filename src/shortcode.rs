@@ -0,0 +1,223 @@
+use crate::protocol::ChatMessage;
+use std::collections::HashMap;
+
+/// Shortcodes every table starts with.
+const BUILT_IN: [(&str, &str); 44] = [
+    ("+1", "\u{1f44d}"),
+    ("-1", "\u{1f44e}"),
+    ("100", "\u{1f4af}"),
+    ("blush", "\u{1f60a}"),
+    ("bug", "\u{1f41b}"),
+    ("check", "\u{2705}"),
+    ("clap", "\u{1f44f}"),
+    ("coffee", "\u{2615}"),
+    ("crab", "\u{1f980}"),
+    ("cry", "\u{1f622}"),
+    ("eyes", "\u{1f440}"),
+    ("facepalm", "\u{1f926}"),
+    ("fire", "\u{1f525}"),
+    ("grin", "\u{1f601}"),
+    ("heart", "\u{2764}\u{fe0f}"),
+    ("joy", "\u{1f602}"),
+    ("laughing", "\u{1f606}"),
+    ("ok_hand", "\u{1f44c}"),
+    ("partying_face", "\u{1f973}"),
+    ("pray", "\u{1f64f}"),
+    ("raised_hands", "\u{1f64c}"),
+    ("rocket", "\u{1f680}"),
+    ("rofl", "\u{1f923}"),
+    ("scream", "\u{1f631}"),
+    ("shrug", "\u{1f937}"),
+    ("slightly_smiling_face", "\u{1f642}"),
+    ("smile", "\u{1f604}"),
+    ("smiley", "\u{1f603}"),
+    ("sob", "\u{1f62d}"),
+    ("sparkles", "\u{2728}"),
+    ("star", "\u{2b50}"),
+    ("sunglasses", "\u{1f60e}"),
+    ("sweat_smile", "\u{1f605}"),
+    ("tada", "\u{1f389}"),
+    ("thinking", "\u{1f914}"),
+    ("thumbsdown", "\u{1f44e}"),
+    ("thumbsup", "\u{1f44d}"),
+    ("upside_down_face", "\u{1f643}"),
+    ("warning", "\u{26a0}\u{fe0f}"),
+    ("wave", "\u{1f44b}"),
+    ("white_check_mark", "\u{2705}"),
+    ("wink", "\u{1f609}"),
+    ("x", "\u{274c}"),
+    ("zap", "\u{26a1}"),
+];
+
+/// Replaces `:name:` shortcodes in messages with what they stand for,
+/// usually an emoji.
+#[derive(Debug, Clone)]
+pub struct Shortcodes {
+    table: HashMap<String, String>,
+}
+
+impl Default for Shortcodes {
+    fn default() -> Self {
+        Shortcodes {
+            table: BUILT_IN
+                .iter()
+                .map(|(name, emoji)| (name.to_string(), emoji.to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl Shortcodes {
+    /// The built-in table: `:smile:`, `:+1:`, `:tada:` and the like.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `:name:`, or changes what it stands for. Names are lowercase
+    /// letters, digits, `_`, `+` and `-`; others never match.
+    pub fn with(mut self, name: impl Into<String>, replacement: impl Into<String>) -> Self {
+        self.table.insert(name.into(), replacement.into());
+        self
+    }
+
+    /// Expands the shortcodes in `text`, leaving ones it doesn't know and
+    /// anything between backticks alone.
+    pub fn expand(&self, text: &str) -> String {
+        self.expand_spans(text).0
+    }
+
+    /// Expands the shortcodes in a message's content, moving its entities
+    /// to match. Entities are dropped if one started or ended inside a
+    /// shortcode.
+    pub fn expand_message(&self, message: &mut ChatMessage) {
+        let (expanded, spans) = self.expand_spans(&message.content);
+        if spans.is_empty() {
+            return;
+        }
+        // Where a byte offset into the old content ends up in the new.
+        let moved = |offset: usize| {
+            let mut moved = offset;
+            for &(start, end, len) in &spans {
+                if start < offset && offset < end {
+                    return None;
+                }
+                if end <= offset {
+                    moved = moved + len - (end - start);
+                }
+            }
+            Some(moved)
+        };
+        let entities = message
+            .entities
+            .iter()
+            .map(|entity| {
+                let start = moved(entity.offset)?;
+                let end = moved(entity.offset + entity.length)?;
+                let mut entity = entity.clone();
+                (entity.offset, entity.length) = (start, end - start);
+                Some(entity)
+            })
+            .collect::<Option<Vec<_>>>();
+        message.content = expanded;
+        message.entities = entities.unwrap_or_default();
+    }
+
+    /// Expands `text`, also returning each replaced shortcode's start and
+    /// end in `text` and the length of its replacement.
+    fn expand_spans(&self, text: &str) -> (String, Vec<(usize, usize, usize)>) {
+        let mut expanded = String::with_capacity(text.len());
+        let mut spans = Vec::new();
+        let mut in_code = false;
+        let mut copied = 0;
+        let mut i = 0;
+        let bytes = text.as_bytes();
+        while i < bytes.len() {
+            match bytes[i] {
+                b'`' => in_code = !in_code,
+                b':' if !in_code => {
+                    let name_len = bytes[i + 1..]
+                        .iter()
+                        .position(|&b| !is_name_byte(b))
+                        .unwrap_or(bytes.len() - i - 1);
+                    let end = i + 1 + name_len;
+                    if name_len > 0
+                        && bytes.get(end) == Some(&b':')
+                        && let Some(replacement) = self.table.get(&text[i + 1..end])
+                    {
+                        expanded.push_str(&text[copied..i]);
+                        expanded.push_str(replacement);
+                        spans.push((i, end + 1, replacement.len()));
+                        copied = end + 1;
+                        i = end + 1;
+                        continue;
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+        expanded.push_str(&text[copied..]);
+        (expanded, spans)
+    }
+}
+
+/// Whether `name` can be written as a shortcode: lowercase letters,
+/// digits, `_`, `+` and `-`.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(is_name_byte)
+}
+
+fn is_name_byte(b: u8) -> bool {
+    b.is_ascii_lowercase() || b.is_ascii_digit() || matches!(b, b'_' | b'+' | b'-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::EntityKind;
+
+    #[test]
+    fn test_expand() {
+        let shortcodes = Shortcodes::new().with("shipit", "\u{1f43f}\u{fe0f}");
+        assert_eq!(
+            shortcodes.expand("nice :tada::+1: time to :shipit:"),
+            "nice \u{1f389}\u{1f44d} time to \u{1f43f}\u{fe0f}"
+        );
+        assert_eq!(
+            shortcodes.expand("at 10:30:45 :nope: :"),
+            "at 10:30:45 :nope: :"
+        );
+        assert_eq!(
+            shortcodes.expand("`:smile:` :smile:"),
+            "`:smile:` \u{1f604}"
+        );
+        assert_eq!(shortcodes.expand("caf\u{e9}:fire:"), "caf\u{e9}\u{1f525}");
+    }
+
+    #[test]
+    fn test_entities_follow_expansion() {
+        let mut message = ChatMessage::builder()
+            .sender("avery")
+            .content(":wave: hi **blake**")
+            .entity(10, 9, EntityKind::Bold)
+            .build()
+            .unwrap();
+        Shortcodes::new().expand_message(&mut message);
+        assert_eq!(message.content, "\u{1f44b} hi **blake**");
+        assert_eq!(
+            message.entities[0].text(&message.content),
+            Some("**blake**")
+        );
+
+        // An entity ending inside a shortcode has nowhere to go.
+        let mut message = ChatMessage::builder()
+            .sender("avery")
+            .content("go :rocket:")
+            .entity(0, 6, EntityKind::Italic)
+            .build()
+            .unwrap();
+        Shortcodes::new().expand_message(&mut message);
+        assert_eq!(message.content, "go \u{1f680}");
+        assert!(message.entities.is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+use crate::delivery::AckTracker;
+use crate::protocol::ChatMessage;
+use crate::registry::{Registry, RegistrySnapshot};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{debug, error, info};
+
+/// Server state saved by `save`: the registry, and the messages each user
+/// hasn't acknowledged in at-least-once rooms, which are how far they've
+/// read there.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Snapshot {
+    #[serde(flatten)]
+    pub registry: RegistrySnapshot,
+    pub unacked: HashMap<String, VecDeque<ChatMessage>>,
+}
+
+impl Snapshot {
+    /// Takes a snapshot of `registry` and `acks`.
+    pub fn take(registry: &Registry, acks: &AckTracker) -> Self {
+        Snapshot {
+            registry: registry.snapshot(),
+            unacked: acks.snapshot(),
+        }
+    }
+}
+
+/// Reads a snapshot written by `save`, or `None` if there isn't one yet.
+pub async fn load(path: &Path) -> Result<Option<Snapshot>> {
+    match tokio::fs::read(path).await {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes a snapshot atomically: to a temporary file that is fsynced and
+/// then renamed over `path`, so a crash never leaves a torn snapshot.
+pub async fn save(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let file = tokio::fs::File::create(&tmp).await?;
+    let mut file = tokio::io::BufWriter::new(file);
+    tokio::io::AsyncWriteExt::write_all(&mut file, &serde_json::to_vec_pretty(snapshot)?).await?;
+    tokio::io::AsyncWriteExt::flush(&mut file).await?;
+    file.into_inner().sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
+
+/// Saves the registry and unacknowledged messages to `path` every
+/// `interval`, forever.
+pub async fn run_snapshots(
+    registry: Arc<Registry>,
+    acks: Arc<AckTracker>,
+    path: PathBuf,
+    interval: Duration,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately; the state was just restored.
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        match save(&path, &Snapshot::take(&registry, &acks)).await {
+            Ok(()) => debug!("Saved snapshot to {}", path.display()),
+            Err(e) => error!("Failed to save snapshot to {}: {:?}", path.display(), e),
+        }
+    }
+}
+
+/// Restores `registry` and `acks` from `path` if a snapshot exists.
+pub async fn restore(registry: &Registry, acks: &AckTracker, path: &Path) -> Result<()> {
+    if let Some(snapshot) = load(path).await? {
+        info!(
+            "Restored {} rooms from {}",
+            snapshot.registry.rooms.len(),
+            path.display()
+        );
+        registry.restore(snapshot.registry);
+        acks.restore(snapshot.unacked);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::room::RoomConfig;
+
+    #[tokio::test]
+    async fn save_and_restore_rooms() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("chat-snapshot-{}.json", std::process::id()));
+        let registry = Registry::new();
+        registry.set_room(
+            "huddle",
+            RoomConfig {
+                allow_ephemeral: true,
+                ..Default::default()
+            },
+        );
+        let acks = AckTracker::default();
+        save(&path, &Snapshot::take(&registry, &acks)).await?;
+
+        let restored = Registry::new();
+        restore(&restored, &acks, &path).await?;
+        tokio::fs::remove_file(&path).await?;
+        assert!(restored.room_config("huddle").allow_ephemeral);
+        assert!(!restored.room_config("general").allow_ephemeral);
+        Ok(())
+    }
+}
@@ -0,0 +1,987 @@
+use crate::protocol::{ChatMessage, MessageId, SavedMessage, SearchHit};
+use crate::retention::{PruneStats, RetentionPolicy};
+use crate::room::RoomConfig;
+use anyhow::Result;
+use async_trait::async_trait;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "postgres")]
+pub use postgres::PostgresStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+/// Messages kept per room before the oldest are dropped.
+pub const DEFAULT_HISTORY_CAPACITY: usize = 1000;
+
+/// Characters of context kept either side of a search match.
+const SNIPPET_CONTEXT: usize = 40;
+
+/// Messages `migrate` copies per batch.
+const MIGRATE_BATCH: usize = 1000;
+
+/// The newest sequence number, post time and message id a room has had,
+/// which a store keeps even once those messages are deleted, so numbering
+/// never goes back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HighWater {
+    pub seq: u64,
+    /// Unix time (seconds) of the newest message.
+    pub timestamp: u64,
+    /// Id of the newest message.
+    pub id: MessageId,
+}
+
+impl HighWater {
+    /// The mark of `message` alone.
+    fn of(message: &ChatMessage) -> Self {
+        HighWater {
+            seq: message.seq.unwrap_or_default(),
+            timestamp: message.timestamp.unwrap_or_default(),
+            id: message.id.unwrap_or_default(),
+        }
+    }
+
+    /// Raises the mark to at least `other`.
+    fn raise(&mut self, other: HighWater) {
+        self.seq = self.seq.max(other.seq);
+        self.timestamp = self.timestamp.max(other.timestamp);
+        self.id = self.id.max(other.id);
+    }
+}
+
+/// A message held for its `send_at`, owned by its sender.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduledMessage {
+    /// The id `CancelScheduled` takes.
+    pub pending_id: u64,
+    /// The connection that scheduled it, which it's relayed as coming from.
+    pub from: SocketAddr,
+    pub message: ChatMessage,
+}
+
+/// Storage for relayed chat history.
+///
+/// Messages handed to a store always carry a server-assigned `id`, `room`
+/// and `timestamp`, and ids increase in the order messages are appended.
+#[async_trait]
+pub trait MessageStore: Send + Sync {
+    /// Records a relayed message.
+    async fn append(&self, message: &ChatMessage) -> Result<()>;
+
+    /// Records several messages at once, e.g. an archived room's history
+    /// being restored: all of them or, on error, none. Any already in
+    /// their room under the same id are skipped, so an interrupted restore
+    /// can be run again. Returns how many were added.
+    async fn append_all(&self, messages: &[ChatMessage]) -> Result<u64>;
+
+    /// Deletes a message, returning whether it was still stored.
+    async fn remove(&self, room: &str, id: MessageId) -> Result<bool>;
+
+    /// Returns up to `limit` messages in a room older than `before` (or the
+    /// newest when `before` is `None`), oldest first, and whether older
+    /// messages remain.
+    async fn page(
+        &self,
+        room: &str,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<(Vec<ChatMessage>, bool)>;
+
+    /// Returns every message in a room relayed within `[since, until)`,
+    /// oldest first.
+    async fn range(
+        &self,
+        room: &str,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Vec<ChatMessage>>;
+
+    /// Returns up to `limit` messages from any room with ids above `after`,
+    /// oldest first.
+    async fn after(&self, after: MessageId, limit: usize) -> Result<Vec<ChatMessage>>;
+
+    /// Returns the message with id `id`, from any room, if it's stored.
+    async fn get(&self, id: MessageId) -> Result<Option<ChatMessage>> {
+        let next = self.after(id.saturating_sub(1), 1).await?;
+        Ok(next.into_iter().find(|message| message.id == Some(id)))
+    }
+
+    /// Returns up to `limit` messages in a room with sequence numbers in
+    /// `[from_seq, to_seq]`, oldest first.
+    async fn sequence(
+        &self,
+        room: &str,
+        from_seq: u64,
+        to_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>>;
+
+    /// Finds messages matching every whitespace-separated term of `query`,
+    /// newest first. Searches every room when `room` is `None`, and only
+    /// messages older than `before` when given.
+    ///
+    /// Returns the hits and, if more matched than `limit`, the cursor to pass
+    /// as `before` for the next page.
+    async fn search(
+        &self,
+        query: &str,
+        room: Option<&str>,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<(Vec<SearchHit>, Option<MessageId>)>;
+
+    /// Deletes whatever `policy` no longer allows, with `now` as the current
+    /// Unix time in seconds: first by age, then per-room count, then the
+    /// oldest messages overall until the total size fits.
+    async fn prune(&self, policy: &RetentionPolicy, now: u64) -> Result<PruneStats>;
+
+    /// Returns the highest stored message id, so a restarted server can
+    /// continue numbering after it.
+    async fn last_id(&self) -> Result<Option<MessageId>>;
+
+    /// Returns the high-water mark of every message appended to `room`,
+    /// whether or not they're still stored, or `None` if it has had none.
+    async fn high_water(&self, room: &str) -> Result<Option<HighWater>>;
+
+    /// Returns every room's high-water mark.
+    async fn high_waters(&self) -> Result<Vec<(String, HighWater)>>;
+
+    /// Raises `room`'s high-water mark to at least `mark`, e.g. when
+    /// migrating a room whose newest messages were deleted.
+    async fn raise_high_water(&self, room: &str, mark: HighWater) -> Result<()>;
+
+    /// Returns the total length of the content of a room's messages, for
+    /// `ResourcePolicy::max_history_bytes`.
+    async fn room_bytes(&self, room: &str) -> Result<u64>;
+
+    /// Returns every message with a `ttl_secs`, from any room, oldest
+    /// first, so a restarted server can still expire them.
+    async fn ephemeral(&self) -> Result<Vec<ChatMessage>>;
+
+    /// Records `nick` as registered with `password_hash`, from
+    /// `auth::hash_password`. Returns false if it already was.
+    async fn register_nick(&self, nick: &str, password_hash: &str) -> Result<bool>;
+
+    /// Returns every registered nickname with its password hash.
+    async fn nicks(&self) -> Result<Vec<(String, String)>>;
+
+    /// Adds a copy of `message` to `user`'s saved messages, which outlive
+    /// the message's history. Returns false if it was already saved.
+    async fn save(&self, user: &str, message: &ChatMessage, saved_at: u64) -> Result<bool>;
+
+    /// Removes message `id` from `user`'s saved messages, returning
+    /// whether it was there.
+    async fn unsave(&self, user: &str, id: MessageId) -> Result<bool>;
+
+    /// Returns `user`'s saved messages, most recently saved first.
+    async fn saved(&self, user: &str) -> Result<Vec<SavedMessage>>;
+
+    /// Returns every user's saved messages, with who saved each, oldest
+    /// saved first.
+    async fn all_saved(&self) -> Result<Vec<(String, SavedMessage)>>;
+
+    /// Records a room's settings, replacing any stored before.
+    async fn put_room(&self, room: &str, config: &RoomConfig) -> Result<()>;
+
+    /// Forgets a room's settings, returning whether they were stored.
+    async fn delete_room(&self, room: &str) -> Result<bool>;
+
+    /// Returns every room's stored settings, by name.
+    async fn rooms(&self) -> Result<Vec<(String, RoomConfig)>>;
+
+    /// Records a message held for later, replacing any with the same
+    /// pending id.
+    async fn put_scheduled(&self, scheduled: &ScheduledMessage) -> Result<()>;
+
+    /// Forgets a held message once it's sent or cancelled, returning
+    /// whether it was stored.
+    async fn delete_scheduled(&self, pending_id: u64) -> Result<bool>;
+
+    /// Returns the messages still held, by pending id.
+    async fn scheduled(&self) -> Result<Vec<ScheduledMessage>>;
+
+    /// Records that the file `blob_id` was shared in `room`.
+    async fn share_file(&self, blob_id: &str, room: &str) -> Result<()>;
+
+    /// Returns the rooms the file `blob_id` was shared in.
+    async fn file_rooms(&self, blob_id: &str) -> Result<Vec<String>>;
+
+    /// Returns every file share, as blob id and room.
+    async fn file_shares(&self) -> Result<Vec<(String, String)>>;
+}
+
+/// Keeps a bounded window of history per room in memory; the default store.
+/// Search is a case-insensitive substring match.
+pub struct MemoryStore {
+    rooms: Mutex<HashMap<String, VecDeque<ChatMessage>>>,
+    capacity: usize,
+    /// Each user's saved messages, oldest saved first.
+    saved: Mutex<HashMap<String, Vec<SavedMessage>>>,
+    high_water: Mutex<HashMap<String, HighWater>>,
+    /// Registered nicknames and their password hashes.
+    nicks: Mutex<HashMap<String, String>>,
+    room_configs: Mutex<HashMap<String, RoomConfig>>,
+    scheduled: Mutex<BTreeMap<u64, ScheduledMessage>>,
+    /// The rooms each file was shared in.
+    file_shares: Mutex<HashMap<String, BTreeSet<String>>>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_HISTORY_CAPACITY)
+    }
+}
+
+impl MemoryStore {
+    /// Keeps at most `capacity` messages per room.
+    pub fn new(capacity: usize) -> Self {
+        MemoryStore {
+            rooms: Mutex::new(HashMap::new()),
+            capacity,
+            saved: Mutex::new(HashMap::new()),
+            high_water: Mutex::new(HashMap::new()),
+            nicks: Mutex::new(HashMap::new()),
+            room_configs: Mutex::new(HashMap::new()),
+            scheduled: Mutex::new(BTreeMap::new()),
+            file_shares: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl MessageStore for MemoryStore {
+    async fn append(&self, message: &ChatMessage) -> Result<()> {
+        self.high_water
+            .lock()
+            .unwrap()
+            .entry(message.room().to_string())
+            .or_default()
+            .raise(HighWater::of(message));
+        let mut rooms = self.rooms.lock().unwrap();
+        let room = rooms.entry(message.room().to_string()).or_default();
+        room.push_back(message.clone());
+        while room.len() > self.capacity {
+            room.pop_front();
+        }
+        Ok(())
+    }
+
+    async fn append_all(&self, messages: &[ChatMessage]) -> Result<u64> {
+        let mut added = 0;
+        for message in messages {
+            let stored = self
+                .rooms
+                .lock()
+                .unwrap()
+                .get(message.room())
+                .is_some_and(|room| room.iter().any(|stored| stored.id == message.id));
+            if !stored {
+                self.append(message).await?;
+                added += 1;
+            }
+        }
+        Ok(added)
+    }
+
+    async fn remove(&self, room: &str, id: MessageId) -> Result<bool> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let Some(messages) = rooms.get_mut(room) else {
+            return Ok(false);
+        };
+        let before = messages.len();
+        messages.retain(|message| message.id != Some(id));
+        Ok(messages.len() != before)
+    }
+
+    async fn page(
+        &self,
+        room: &str,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<(Vec<ChatMessage>, bool)> {
+        let rooms = self.rooms.lock().unwrap();
+        let Some(messages) = rooms.get(room) else {
+            return Ok((Vec::new(), false));
+        };
+        let older: Vec<&ChatMessage> = messages
+            .iter()
+            .filter(|message| before.is_none_or(|before| message.id.is_some_and(|id| id < before)))
+            .collect();
+        let start = older.len().saturating_sub(limit);
+        Ok((
+            older[start..].iter().map(|&m| m.clone()).collect(),
+            start > 0,
+        ))
+    }
+
+    async fn range(
+        &self,
+        room: &str,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Vec<ChatMessage>> {
+        let rooms = self.rooms.lock().unwrap();
+        let Some(messages) = rooms.get(room) else {
+            return Ok(Vec::new());
+        };
+        Ok(messages
+            .iter()
+            .filter(|message| {
+                let timestamp = message.timestamp.unwrap_or_default();
+                since.is_none_or(|since| timestamp >= since)
+                    && until.is_none_or(|until| timestamp < until)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn after(&self, after: MessageId, limit: usize) -> Result<Vec<ChatMessage>> {
+        let rooms = self.rooms.lock().unwrap();
+        let mut newer: Vec<&ChatMessage> = rooms
+            .values()
+            .flatten()
+            .filter(|message| message.id.is_some_and(|id| id > after))
+            .collect();
+        newer.sort_by_key(|message| message.id);
+        Ok(newer.into_iter().take(limit).cloned().collect())
+    }
+
+    async fn sequence(
+        &self,
+        room: &str,
+        from_seq: u64,
+        to_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let rooms = self.rooms.lock().unwrap();
+        let Some(messages) = rooms.get(room) else {
+            return Ok(Vec::new());
+        };
+        Ok(messages
+            .iter()
+            .filter(|message| {
+                message
+                    .seq
+                    .is_some_and(|seq| (from_seq..=to_seq).contains(&seq))
+            })
+            .take(limit)
+            .cloned()
+            .collect())
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        room: Option<&str>,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<(Vec<SearchHit>, Option<MessageId>)> {
+        let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+        if terms.is_empty() || limit == 0 {
+            return Ok((Vec::new(), None));
+        }
+        let rooms = self.rooms.lock().unwrap();
+        let mut matches: Vec<&ChatMessage> = rooms
+            .iter()
+            .filter(|(name, _)| room.is_none_or(|room| room == name.as_str()))
+            .flat_map(|(_, messages)| messages.iter())
+            .filter(|message| before.is_none_or(|before| message.id.is_some_and(|id| id < before)))
+            .filter(|message| {
+                let content = message.content.to_lowercase();
+                terms.iter().all(|term| content.contains(term.as_str()))
+            })
+            .collect();
+        matches.sort_by_key(|message| std::cmp::Reverse(message.id));
+
+        let next_cursor = if matches.len() > limit {
+            matches[limit - 1].id
+        } else {
+            None
+        };
+        let hits = matches
+            .into_iter()
+            .take(limit)
+            .map(|message| SearchHit {
+                snippet: snippet(&message.content, &terms[0]),
+                message: message.clone(),
+            })
+            .collect();
+        Ok((hits, next_cursor))
+    }
+
+    async fn prune(&self, policy: &RetentionPolicy, now: u64) -> Result<PruneStats> {
+        let mut rooms = self.rooms.lock().unwrap();
+        let mut stats = PruneStats::default();
+        let mut drop = |message: &ChatMessage| {
+            stats.messages += 1;
+            stats.bytes += encoded_len(message);
+        };
+
+        if let Some(max_age) = policy.max_age {
+            let cutoff = now.saturating_sub(max_age.as_secs());
+            for messages in rooms.values_mut() {
+                messages.retain(|message| {
+                    let keep = message.timestamp.unwrap_or_default() >= cutoff;
+                    if !keep {
+                        drop(message);
+                    }
+                    keep
+                });
+            }
+        }
+        if let Some(max_messages) = policy.max_messages_per_room {
+            for messages in rooms.values_mut() {
+                while messages.len() > max_messages {
+                    drop(&messages.pop_front().expect("room is over its limit"));
+                }
+            }
+        }
+        if let Some(max_bytes) = policy.max_total_bytes {
+            let mut total: u64 = rooms.values().flatten().map(encoded_len).sum();
+            while total > max_bytes {
+                // Drop the oldest message across all rooms.
+                let Some(oldest) = rooms
+                    .values_mut()
+                    .filter(|messages| !messages.is_empty())
+                    .min_by_key(|messages| messages.front().and_then(|message| message.id))
+                else {
+                    break;
+                };
+                let message = oldest.pop_front().expect("room has messages");
+                total -= encoded_len(&message);
+                drop(&message);
+            }
+        }
+        rooms.retain(|_, messages| !messages.is_empty());
+        Ok(stats)
+    }
+
+    async fn last_id(&self) -> Result<Option<MessageId>> {
+        let rooms = self.rooms.lock().unwrap();
+        Ok(rooms
+            .values()
+            .filter_map(|messages| messages.back().and_then(|message| message.id))
+            .max())
+    }
+
+    async fn ephemeral(&self) -> Result<Vec<ChatMessage>> {
+        let rooms = self.rooms.lock().unwrap();
+        let mut ephemeral: Vec<ChatMessage> = rooms
+            .values()
+            .flatten()
+            .filter(|message| message.ttl_secs.is_some())
+            .cloned()
+            .collect();
+        ephemeral.sort_by_key(|message| message.id);
+        Ok(ephemeral)
+    }
+
+    async fn high_water(&self, room: &str) -> Result<Option<HighWater>> {
+        Ok(self.high_water.lock().unwrap().get(room).copied())
+    }
+
+    async fn high_waters(&self) -> Result<Vec<(String, HighWater)>> {
+        let high_water = self.high_water.lock().unwrap();
+        Ok(high_water
+            .iter()
+            .map(|(room, mark)| (room.clone(), *mark))
+            .collect())
+    }
+
+    async fn raise_high_water(&self, room: &str, mark: HighWater) -> Result<()> {
+        let mut high_water = self.high_water.lock().unwrap();
+        high_water.entry(room.to_string()).or_default().raise(mark);
+        Ok(())
+    }
+
+    async fn room_bytes(&self, room: &str) -> Result<u64> {
+        let rooms = self.rooms.lock().unwrap();
+        Ok(rooms.get(room).map_or(0, |messages| {
+            messages
+                .iter()
+                .map(|message| message.content.len() as u64)
+                .sum()
+        }))
+    }
+
+    async fn register_nick(&self, nick: &str, password_hash: &str) -> Result<bool> {
+        let mut nicks = self.nicks.lock().unwrap();
+        if nicks.contains_key(nick) {
+            return Ok(false);
+        }
+        nicks.insert(nick.to_string(), password_hash.to_string());
+        Ok(true)
+    }
+
+    async fn nicks(&self) -> Result<Vec<(String, String)>> {
+        let nicks = self.nicks.lock().unwrap();
+        Ok(nicks
+            .iter()
+            .map(|(nick, hash)| (nick.clone(), hash.clone()))
+            .collect())
+    }
+
+    async fn save(&self, user: &str, message: &ChatMessage, saved_at: u64) -> Result<bool> {
+        let mut saved = self.saved.lock().unwrap();
+        let messages = saved.entry(user.to_string()).or_default();
+        if messages.iter().any(|saved| saved.message.id == message.id) {
+            return Ok(false);
+        }
+        messages.push(SavedMessage {
+            message: message.clone(),
+            saved_at,
+        });
+        Ok(true)
+    }
+
+    async fn unsave(&self, user: &str, id: MessageId) -> Result<bool> {
+        let mut saved = self.saved.lock().unwrap();
+        let Some(messages) = saved.get_mut(user) else {
+            return Ok(false);
+        };
+        let before = messages.len();
+        messages.retain(|saved| saved.message.id != Some(id));
+        let removed = messages.len() != before;
+        if messages.is_empty() {
+            saved.remove(user);
+        }
+        Ok(removed)
+    }
+
+    async fn saved(&self, user: &str) -> Result<Vec<SavedMessage>> {
+        let saved = self.saved.lock().unwrap();
+        let messages = saved.get(user).map(Vec::as_slice).unwrap_or_default();
+        Ok(messages.iter().rev().cloned().collect())
+    }
+
+    async fn all_saved(&self) -> Result<Vec<(String, SavedMessage)>> {
+        let saved = self.saved.lock().unwrap();
+        let mut all: Vec<(String, SavedMessage)> = saved
+            .iter()
+            .flat_map(|(user, messages)| {
+                messages
+                    .iter()
+                    .map(move |message| (user.clone(), message.clone()))
+            })
+            .collect();
+        all.sort_by_key(|(_, saved)| saved.saved_at);
+        Ok(all)
+    }
+
+    async fn put_room(&self, room: &str, config: &RoomConfig) -> Result<()> {
+        let mut room_configs = self.room_configs.lock().unwrap();
+        room_configs.insert(room.to_string(), config.clone());
+        Ok(())
+    }
+
+    async fn delete_room(&self, room: &str) -> Result<bool> {
+        Ok(self.room_configs.lock().unwrap().remove(room).is_some())
+    }
+
+    async fn rooms(&self) -> Result<Vec<(String, RoomConfig)>> {
+        let room_configs = self.room_configs.lock().unwrap();
+        let mut rooms: Vec<_> = room_configs
+            .iter()
+            .map(|(room, config)| (room.clone(), config.clone()))
+            .collect();
+        rooms.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(rooms)
+    }
+
+    async fn put_scheduled(&self, scheduled: &ScheduledMessage) -> Result<()> {
+        let mut held = self.scheduled.lock().unwrap();
+        held.insert(scheduled.pending_id, scheduled.clone());
+        Ok(())
+    }
+
+    async fn delete_scheduled(&self, pending_id: u64) -> Result<bool> {
+        Ok(self.scheduled.lock().unwrap().remove(&pending_id).is_some())
+    }
+
+    async fn scheduled(&self) -> Result<Vec<ScheduledMessage>> {
+        Ok(self.scheduled.lock().unwrap().values().cloned().collect())
+    }
+
+    async fn share_file(&self, blob_id: &str, room: &str) -> Result<()> {
+        let mut file_shares = self.file_shares.lock().unwrap();
+        let rooms = file_shares.entry(blob_id.to_string()).or_default();
+        rooms.insert(room.to_string());
+        Ok(())
+    }
+
+    async fn file_rooms(&self, blob_id: &str) -> Result<Vec<String>> {
+        let file_shares = self.file_shares.lock().unwrap();
+        let rooms = file_shares.get(blob_id).into_iter().flatten();
+        Ok(rooms.cloned().collect())
+    }
+
+    async fn file_shares(&self) -> Result<Vec<(String, String)>> {
+        let file_shares = self.file_shares.lock().unwrap();
+        Ok(file_shares
+            .iter()
+            .flat_map(|(blob_id, rooms)| rooms.iter().map(|room| (blob_id.clone(), room.clone())))
+            .collect())
+    }
+}
+
+/// Opens the store a URL names: `memory:`, `sqlite://PATH` or a
+/// `postgres://` connection URL. Backends the crate was built without are
+/// errors.
+pub async fn open(url: &str) -> Result<Box<dyn MessageStore>> {
+    if url == "memory:" {
+        return Ok(Box::new(MemoryStore::default()));
+    }
+    if let Some(path) = url.strip_prefix("sqlite://") {
+        #[cfg(feature = "sqlite")]
+        return Ok(Box::new(SqliteStore::open(path).await?));
+        #[cfg(not(feature = "sqlite"))]
+        return Err(anyhow::anyhow!(
+            "Can't open {}: built without the sqlite feature",
+            path
+        ));
+    }
+    if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+        #[cfg(feature = "postgres")]
+        return Ok(Box::new(PostgresStore::connect(url).await?));
+        #[cfg(not(feature = "postgres"))]
+        return Err(anyhow::anyhow!(
+            "Can't open {}: built without the postgres feature",
+            url
+        ));
+    }
+    Err(anyhow::anyhow!("Unsupported store URL {}", url))
+}
+
+/// How far a `migrate` has got.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MigrationProgress {
+    /// Messages copied so far by this run.
+    pub copied: u64,
+    /// Newest message id in the destination.
+    pub last_id: MessageId,
+    /// Newest message id in the source when the run started.
+    pub source_last_id: MessageId,
+    /// Room settings copied by this run.
+    pub rooms: u64,
+    /// Nickname registrations copied by this run.
+    pub nicks: u64,
+    /// Saved messages copied by this run.
+    pub saved: u64,
+    /// Scheduled messages copied by this run.
+    pub scheduled: u64,
+    /// File shares copied by this run.
+    pub file_shares: u64,
+}
+
+/// Copies everything `from` stores to `to`: room settings, high-water
+/// marks, nickname registrations, saved and scheduled messages and file
+/// shares, then history oldest first, reporting `progress` after each batch
+/// of history.
+///
+/// History copying starts after the newest message already in `to`, and
+/// the rest is only added or raised, so running it again after an
+/// interruption carries on where it stopped. `to` should therefore be
+/// empty, or the destination of an earlier migration from the same
+/// source.
+pub async fn migrate(
+    from: &dyn MessageStore,
+    to: &dyn MessageStore,
+    mut progress: impl FnMut(&MigrationProgress),
+) -> Result<MigrationProgress> {
+    let mut state = MigrationProgress {
+        last_id: to.last_id().await?.unwrap_or(0),
+        source_last_id: from.last_id().await?.unwrap_or(0),
+        ..Default::default()
+    };
+    for (room, config) in from.rooms().await? {
+        to.put_room(&room, &config).await?;
+        state.rooms += 1;
+    }
+    for (room, mark) in from.high_waters().await? {
+        to.raise_high_water(&room, mark).await?;
+    }
+    for (nick, password_hash) in from.nicks().await? {
+        if to.register_nick(&nick, &password_hash).await? {
+            state.nicks += 1;
+        }
+    }
+    for (user, saved) in from.all_saved().await? {
+        if to.save(&user, &saved.message, saved.saved_at).await? {
+            state.saved += 1;
+        }
+    }
+    for scheduled in from.scheduled().await? {
+        to.put_scheduled(&scheduled).await?;
+        state.scheduled += 1;
+    }
+    for (blob_id, room) in from.file_shares().await? {
+        to.share_file(&blob_id, &room).await?;
+        state.file_shares += 1;
+    }
+    loop {
+        let batch = from.after(state.last_id, MIGRATE_BATCH).await?;
+        if batch.is_empty() {
+            return Ok(state);
+        }
+        for message in &batch {
+            to.append(message).await?;
+            state.copied += 1;
+            state.last_id = message.id.unwrap_or(state.last_id);
+        }
+        progress(&state);
+    }
+}
+
+/// Size of a message as counted against `max_total_bytes`.
+fn encoded_len(message: &ChatMessage) -> u64 {
+    serde_json::to_vec(message).map_or(0, |json| json.len() as u64)
+}
+
+/// Cuts `content` down to the first match of `term` with some context.
+fn snippet(content: &str, term: &str) -> String {
+    let lower = content.to_lowercase();
+    // Lowercasing can change byte lengths; fall back to the start then.
+    let start = match lower.find(term) {
+        Some(start) if lower.len() == content.len() && content.is_char_boundary(start) => start,
+        _ => 0,
+    };
+    let match_char = content[..start].chars().count();
+    let total = content.chars().count();
+    let from = match_char.saturating_sub(SNIPPET_CONTEXT);
+    let to = (match_char + term.chars().count() + SNIPPET_CONTEXT).min(total);
+
+    let mut snippet: String = content.chars().skip(from).take(to - from).collect();
+    if from > 0 {
+        snippet.insert(0, '…');
+    }
+    if to < total {
+        snippet.push('…');
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::DEFAULT_ROOM;
+    use std::time::Duration;
+
+    fn message(id: MessageId, content: &str) -> ChatMessage {
+        ChatMessage {
+            sender: "avery".to_string(),
+            content: content.to_string(),
+            id: Some(id),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn migrate_resumes_after_destination() {
+        let from = MemoryStore::default();
+        for id in 1..=5 {
+            from.append(&message(id, "hello")).await.unwrap();
+        }
+        let to = MemoryStore::default();
+        // As if an earlier run stopped after two messages.
+        to.append(&message(1, "hello")).await.unwrap();
+        to.append(&message(2, "hello")).await.unwrap();
+
+        let topical = RoomConfig {
+            topic: Some("releases".to_string()),
+            ..Default::default()
+        };
+        from.put_room("plans", &topical).await.unwrap();
+        from.register_nick("avery", "hash").await.unwrap();
+        from.save("avery", &message(2, "hello"), 10).await.unwrap();
+        let later = ScheduledMessage {
+            pending_id: 3,
+            from: "127.0.0.1:4000".parse().unwrap(),
+            message: message(0, "later"),
+        };
+        from.put_scheduled(&later).await.unwrap();
+        from.share_file(&"a".repeat(64), "plans").await.unwrap();
+        // The newest message is gone, but its number stays used.
+        let gone = ChatMessage {
+            seq: Some(6),
+            ..message(6, "gone")
+        };
+        from.append(&gone).await.unwrap();
+        from.remove(DEFAULT_ROOM, 6).await.unwrap();
+
+        let mut reports = 0;
+        let progress = migrate(&from, &to, |_| reports += 1).await.unwrap();
+        assert_eq!(
+            progress,
+            MigrationProgress {
+                copied: 3,
+                last_id: 5,
+                source_last_id: 5,
+                rooms: 1,
+                nicks: 1,
+                saved: 1,
+                scheduled: 1,
+                file_shares: 1,
+            }
+        );
+        assert_eq!(reports, 1);
+        let (messages, _) = to.page(DEFAULT_ROOM, None, 10).await.unwrap();
+        let ids: Vec<_> = messages.iter().map(|message| message.id.unwrap()).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5]);
+        assert_eq!(
+            to.rooms().await.unwrap(),
+            vec![("plans".to_string(), topical)]
+        );
+        assert_eq!(to.nicks().await.unwrap(), from.nicks().await.unwrap());
+        assert_eq!(to.saved("avery").await.unwrap().len(), 1);
+        assert_eq!(to.scheduled().await.unwrap(), vec![later]);
+        assert_eq!(to.file_rooms(&"a".repeat(64)).await.unwrap(), vec!["plans"]);
+        assert_eq!(
+            to.high_water(DEFAULT_ROOM).await.unwrap(),
+            from.high_water(DEFAULT_ROOM).await.unwrap()
+        );
+
+        let again = migrate(&from, &to, |_| {}).await.unwrap();
+        assert_eq!((again.copied, again.nicks, again.saved), (0, 0, 0));
+    }
+
+    #[tokio::test]
+    async fn search_pages_newest_first() {
+        let store = MemoryStore::default();
+        for id in 1..=5 {
+            store
+                .append(&message(id, &format!("Deploy number {}", id)))
+                .await
+                .unwrap();
+        }
+        store.append(&message(6, "unrelated")).await.unwrap();
+
+        let (hits, cursor) = store.search("deploy", None, None, 2).await.unwrap();
+        let ids: Vec<_> = hits.iter().map(|hit| hit.message.id.unwrap()).collect();
+        assert_eq!(ids, vec![5, 4]);
+        assert_eq!(cursor, Some(4));
+
+        let (hits, cursor) = store.search("DEPLOY", None, cursor, 10).await.unwrap();
+        let ids: Vec<_> = hits.iter().map(|hit| hit.message.id.unwrap()).collect();
+        assert_eq!(ids, vec![3, 2, 1]);
+        assert_eq!(cursor, None);
+    }
+
+    #[tokio::test]
+    async fn page_walks_backwards() {
+        let store = MemoryStore::default();
+        for id in 1..=5 {
+            store.append(&message(id, "hi")).await.unwrap();
+        }
+
+        let (messages, has_more) = store.page(DEFAULT_ROOM, None, 2).await.unwrap();
+        let ids: Vec<_> = messages.iter().map(|m| m.id.unwrap()).collect();
+        assert_eq!(ids, vec![4, 5]);
+        assert!(has_more);
+
+        let (messages, has_more) = store.page(DEFAULT_ROOM, Some(2), 2).await.unwrap();
+        let ids: Vec<_> = messages.iter().map(|m| m.id.unwrap()).collect();
+        assert_eq!(ids, vec![1]);
+        assert!(!has_more);
+
+        store.remove(DEFAULT_ROOM, 3).await.unwrap();
+        assert_eq!(store.get(4).await.unwrap(), Some(message(4, "hi")));
+        assert_eq!(store.get(3).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn saved_messages_outlive_history() {
+        let store = MemoryStore::new(1);
+        store.append(&message(1, "keep this")).await.unwrap();
+        assert!(
+            store
+                .save("avery", &message(1, "keep this"), 10)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !store
+                .save("avery", &message(1, "keep this"), 20)
+                .await
+                .unwrap()
+        );
+        store.append(&message(2, "later")).await.unwrap();
+        assert!(store.save("avery", &message(2, "later"), 30).await.unwrap());
+
+        let saved = store.saved("avery").await.unwrap();
+        let ids: Vec<_> = saved
+            .iter()
+            .map(|saved| saved.message.id.unwrap())
+            .collect();
+        assert_eq!(ids, vec![2, 1]);
+        assert!(store.saved("blake").await.unwrap().is_empty());
+        assert!(store.unsave("avery", 1).await.unwrap());
+        assert!(!store.unsave("avery", 1).await.unwrap());
+        assert_eq!(store.saved("avery").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn prune_applies_each_limit() {
+        let store = MemoryStore::default();
+        for id in 1..=6 {
+            let mut message = message(id, "hi");
+            message.timestamp = Some(id * 10);
+            message.seq = Some(id);
+            message.room = Some(if id % 2 == 0 { "even" } else { "odd" }.to_string());
+            store.append(&message).await.unwrap();
+        }
+
+        let by_age = RetentionPolicy {
+            max_age: Some(Duration::from_secs(100)),
+            ..Default::default()
+        };
+        let stats = store.prune(&by_age, 125).await.unwrap();
+        assert_eq!(stats.messages, 2);
+
+        let by_count = RetentionPolicy {
+            max_messages_per_room: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(store.prune(&by_count, 125).await.unwrap().messages, 2);
+        assert_eq!(store.last_id().await.unwrap(), Some(6));
+
+        assert_eq!(store.room_bytes("even").await.unwrap(), 2);
+        assert_eq!(store.room_bytes("none").await.unwrap(), 0);
+
+        let one_message = encoded_len(&store.range("even", None, None).await.unwrap()[0]);
+        let by_size = RetentionPolicy {
+            max_total_bytes: Some(one_message),
+            ..Default::default()
+        };
+        let stats = store.prune(&by_size, 125).await.unwrap();
+        assert_eq!(stats.messages, 1);
+        assert!(store.range("odd", None, None).await.unwrap().is_empty());
+        // Pruned, but never numbered again.
+        assert_eq!(
+            store.high_water("odd").await.unwrap(),
+            Some(HighWater {
+                seq: 5,
+                timestamp: 50,
+                id: 5,
+            })
+        );
+    }
+
+    #[test]
+    fn snippet_trims_long_content() {
+        let content = format!("{}needle{}", "a".repeat(100), "b".repeat(100));
+        let snippet = snippet(&content, "needle");
+        assert!(snippet.starts_with('…') && snippet.ends_with('…'));
+        assert!(snippet.contains("needle"));
+        assert_eq!(
+            snippet.chars().count(),
+            2 * SNIPPET_CONTEXT + "needle".len() + 2
+        );
+    }
+}
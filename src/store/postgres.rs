@@ -0,0 +1,659 @@
+use super::{HighWater, MessageStore, ScheduledMessage};
+use crate::protocol::{ChatMessage, MessageId, SavedMessage, SearchHit};
+use crate::retention::{PruneStats, RetentionPolicy};
+use crate::room::RoomConfig;
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+use tokio_postgres::types::ToSql;
+use tokio_postgres::{Client, NoTls, Row, Transaction};
+use tracing::error;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id BIGINT PRIMARY KEY,
+        room TEXT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        content TEXT NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);
+    CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);
+    CREATE INDEX IF NOT EXISTS messages_content_fts
+        ON messages USING GIN (to_tsvector('simple', content));
+    CREATE TABLE IF NOT EXISTS saved (
+        username TEXT NOT NULL,
+        id BIGINT NOT NULL,
+        saved_at BIGINT NOT NULL,
+        body TEXT NOT NULL,
+        PRIMARY KEY (username, id)
+    );
+    CREATE TABLE IF NOT EXISTS nicks (
+        nick TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS high_water (
+        room TEXT PRIMARY KEY,
+        seq BIGINT NOT NULL,
+        timestamp BIGINT NOT NULL,
+        last_id BIGINT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS rooms (
+        room TEXT PRIMARY KEY,
+        config TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS file_shares (
+        blob_id TEXT NOT NULL,
+        room TEXT NOT NULL,
+        PRIMARY KEY (blob_id, room)
+    );
+    CREATE TABLE IF NOT EXISTS scheduled (
+        pending_id BIGINT PRIMARY KEY,
+        from_addr TEXT NOT NULL,
+        body TEXT NOT NULL
+    );
+    INSERT INTO high_water (room, seq, timestamp, last_id)
+        SELECT room, COALESCE(MAX((body::jsonb ->> 'seq')::BIGINT), 0), MAX(timestamp), MAX(id)
+        FROM messages GROUP BY room
+        ON CONFLICT DO NOTHING;
+";
+
+/// Raises a room's high-water mark to at least `($2, $3, $4)`.
+const RAISE_HIGH_WATER: &str = "
+    INSERT INTO high_water (room, seq, timestamp, last_id) VALUES ($1, $2, $3, $4)
+    ON CONFLICT (room) DO UPDATE
+    SET seq = GREATEST(high_water.seq, excluded.seq),
+        timestamp = GREATEST(high_water.timestamp, excluded.timestamp),
+        last_id = GREATEST(high_water.last_id, excluded.last_id)
+";
+
+/// Persists history in Postgres, with full-text search through `tsvector`.
+///
+/// Search matches whole words rather than substrings.
+pub struct PostgresStore {
+    client: Mutex<Client>,
+}
+
+impl PostgresStore {
+    /// Connects using a libpq-style connection string (e.g.
+    /// `host=localhost user=chat dbname=chat`) and creates the schema.
+    pub async fn connect(config: &str) -> Result<Self> {
+        let (client, connection) = tokio_postgres::connect(config, NoTls).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                error!("Postgres connection error: {:?}", e);
+            }
+        });
+        client.batch_execute(SCHEMA).await?;
+        Ok(PostgresStore {
+            client: Mutex::new(client),
+        })
+    }
+}
+
+/// Deletes messages matching `condition`, returning what was removed.
+async fn delete_where(
+    tx: &Transaction<'_>,
+    condition: &str,
+    params: &[&(dyn ToSql + Sync)],
+) -> Result<PruneStats> {
+    let row = tx
+        .query_one(
+            &format!(
+                "SELECT COUNT(*), COALESCE(SUM(octet_length(body)), 0)::BIGINT
+                 FROM messages WHERE {}",
+                condition
+            ),
+            params,
+        )
+        .await?;
+    tx.execute(&format!("DELETE FROM messages WHERE {}", condition), params)
+        .await?;
+    Ok(PruneStats {
+        messages: row.get::<_, i64>(0) as u64,
+        bytes: row.get::<_, i64>(1) as u64,
+    })
+}
+
+/// Inserts a message and raises its room's high-water mark.
+async fn insert(tx: &Transaction<'_>, message: &ChatMessage) -> Result<()> {
+    let timestamp = message.timestamp.unwrap_or_default() as i64;
+    tx.execute(
+        "INSERT INTO messages (id, room, timestamp, content, body)
+         VALUES ($1, $2, $3, $4, $5)",
+        &[
+            &(message.id.unwrap_or_default() as i64),
+            &message.room(),
+            &timestamp,
+            &message.content,
+            &message.to_json()?,
+        ],
+    )
+    .await?;
+    tx.execute(
+        RAISE_HIGH_WATER,
+        &[
+            &message.room(),
+            &(message.seq.unwrap_or_default() as i64),
+            &timestamp,
+            &(message.id.unwrap_or_default() as i64),
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+fn decode(row: &Row) -> Result<ChatMessage> {
+    Ok(serde_json::from_str(row.get::<_, &str>(0))?)
+}
+
+fn high_water_row(row: &Row, first: usize) -> HighWater {
+    HighWater {
+        seq: row.get::<_, i64>(first) as u64,
+        timestamp: row.get::<_, i64>(first + 1) as u64,
+        id: row.get::<_, i64>(first + 2) as MessageId,
+    }
+}
+
+#[async_trait]
+impl MessageStore for PostgresStore {
+    async fn append(&self, message: &ChatMessage) -> Result<()> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        insert(&tx, message).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn append_all(&self, messages: &[ChatMessage]) -> Result<u64> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        let mut added = 0;
+        for message in messages {
+            let stored = tx
+                .query_opt(
+                    "SELECT 1 FROM messages WHERE id = $1 AND room = $2",
+                    &[&(message.id.unwrap_or_default() as i64), &message.room()],
+                )
+                .await?;
+            if stored.is_none() {
+                insert(&tx, message).await?;
+                added += 1;
+            }
+        }
+        tx.commit().await?;
+        Ok(added)
+    }
+
+    async fn remove(&self, room: &str, id: MessageId) -> Result<bool> {
+        let removed = self
+            .client
+            .lock()
+            .await
+            .execute(
+                "DELETE FROM messages WHERE room = $1 AND id = $2",
+                &[&room, &(id as i64)],
+            )
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn page(
+        &self,
+        room: &str,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<(Vec<ChatMessage>, bool)> {
+        let before = before.map_or(i64::MAX, |before| before as i64);
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body FROM messages WHERE room = $1 AND id < $2 ORDER BY id DESC LIMIT $3",
+                &[&room, &before, &(limit as i64 + 1)],
+            )
+            .await?;
+        let mut messages = rows.iter().map(decode).collect::<Result<Vec<_>>>()?;
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
+        messages.reverse();
+        Ok((messages, has_more))
+    }
+
+    async fn range(
+        &self,
+        room: &str,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Vec<ChatMessage>> {
+        let since = since.unwrap_or_default() as i64;
+        let until = until.map_or(i64::MAX, |until| until as i64);
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body FROM messages
+                 WHERE room = $1 AND timestamp >= $2 AND timestamp < $3 ORDER BY id",
+                &[&room, &since, &until],
+            )
+            .await?;
+        rows.iter().map(decode).collect()
+    }
+
+    async fn after(&self, after: MessageId, limit: usize) -> Result<Vec<ChatMessage>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body FROM messages WHERE id > $1 ORDER BY id LIMIT $2",
+                &[&(after as i64), &(limit as i64)],
+            )
+            .await?;
+        rows.iter().map(decode).collect()
+    }
+
+    async fn sequence(
+        &self,
+        room: &str,
+        from_seq: u64,
+        to_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body FROM messages
+                 WHERE room = $1 AND (body::jsonb ->> 'seq')::BIGINT BETWEEN $2 AND $3
+                 ORDER BY id LIMIT $4",
+                &[&room, &(from_seq as i64), &(to_seq as i64), &(limit as i64)],
+            )
+            .await?;
+        rows.iter().map(decode).collect()
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        room: Option<&str>,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<(Vec<SearchHit>, Option<MessageId>)> {
+        if query.trim().is_empty() || limit == 0 {
+            return Ok((Vec::new(), None));
+        }
+        let before = before.map_or(i64::MAX, |before| before as i64);
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body, ts_headline('simple', content, plainto_tsquery('simple', $1),
+                                          'StartSel=\"\", StopSel=\"\", MaxWords=16, MinWords=4')
+                 FROM messages
+                 WHERE to_tsvector('simple', content) @@ plainto_tsquery('simple', $1)
+                   AND ($2::TEXT IS NULL OR room = $2) AND id < $3
+                 ORDER BY id DESC LIMIT $4",
+                &[&query, &room, &before, &(limit as i64 + 1)],
+            )
+            .await?;
+        let mut hits = rows
+            .iter()
+            .map(|row| {
+                Ok(SearchHit {
+                    message: decode(row)?,
+                    snippet: row.get(1),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let next_cursor = if hits.len() > limit {
+            hits.truncate(limit);
+            hits.last().and_then(|hit| hit.message.id)
+        } else {
+            None
+        };
+        Ok((hits, next_cursor))
+    }
+
+    async fn prune(&self, policy: &RetentionPolicy, now: u64) -> Result<PruneStats> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        let mut stats = PruneStats::default();
+        if let Some(max_age) = policy.max_age {
+            let cutoff = now.saturating_sub(max_age.as_secs()) as i64;
+            stats.add(delete_where(&tx, "timestamp < $1", &[&cutoff]).await?);
+        }
+        if let Some(max_messages) = policy.max_messages_per_room {
+            stats.add(
+                delete_where(
+                    &tx,
+                    "id IN (SELECT id FROM (SELECT id, ROW_NUMBER()
+                        OVER (PARTITION BY room ORDER BY id DESC) AS newer FROM messages) ranked
+                     WHERE newer > $1)",
+                    &[&(max_messages as i64)],
+                )
+                .await?,
+            );
+        }
+        if let Some(max_bytes) = policy.max_total_bytes {
+            stats.add(
+                delete_where(
+                    &tx,
+                    "id IN (SELECT id FROM (SELECT id, SUM(octet_length(body))
+                        OVER (ORDER BY id DESC) AS kept FROM messages) ranked
+                     WHERE kept > $1)",
+                    &[&(max_bytes as i64)],
+                )
+                .await?,
+            );
+        }
+        tx.commit().await?;
+        Ok(stats)
+    }
+
+    async fn last_id(&self) -> Result<Option<MessageId>> {
+        let row = self
+            .client
+            .lock()
+            .await
+            .query_one("SELECT MAX(id) FROM messages", &[])
+            .await?;
+        Ok(row.get::<_, Option<i64>>(0).map(|id| id as MessageId))
+    }
+
+    async fn ephemeral(&self) -> Result<Vec<ChatMessage>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body FROM messages
+                 WHERE body::jsonb ? 'ttl_secs' ORDER BY id",
+                &[],
+            )
+            .await?;
+        rows.iter().map(decode).collect()
+    }
+
+    async fn high_water(&self, room: &str) -> Result<Option<HighWater>> {
+        let row = self
+            .client
+            .lock()
+            .await
+            .query_opt(
+                "SELECT seq, timestamp, last_id FROM high_water WHERE room = $1",
+                &[&room],
+            )
+            .await?;
+        Ok(row.map(|row| high_water_row(&row, 0)))
+    }
+
+    async fn high_waters(&self) -> Result<Vec<(String, HighWater)>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query("SELECT room, seq, timestamp, last_id FROM high_water", &[])
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| (row.get(0), high_water_row(row, 1)))
+            .collect())
+    }
+
+    async fn raise_high_water(&self, room: &str, mark: HighWater) -> Result<()> {
+        self.client
+            .lock()
+            .await
+            .execute(
+                RAISE_HIGH_WATER,
+                &[
+                    &room,
+                    &(mark.seq as i64),
+                    &(mark.timestamp as i64),
+                    &(mark.id as i64),
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn room_bytes(&self, room: &str) -> Result<u64> {
+        let row = self
+            .client
+            .lock()
+            .await
+            .query_one(
+                "SELECT COALESCE(SUM(octet_length(content)), 0)::BIGINT FROM messages
+                 WHERE room = $1",
+                &[&room],
+            )
+            .await?;
+        Ok(row.get::<_, i64>(0) as u64)
+    }
+
+    async fn register_nick(&self, nick: &str, password_hash: &str) -> Result<bool> {
+        let registered = self
+            .client
+            .lock()
+            .await
+            .execute(
+                "INSERT INTO nicks (nick, password_hash) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&nick, &password_hash],
+            )
+            .await?;
+        Ok(registered > 0)
+    }
+
+    async fn nicks(&self) -> Result<Vec<(String, String)>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query("SELECT nick, password_hash FROM nicks", &[])
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+
+    async fn save(&self, user: &str, message: &ChatMessage, saved_at: u64) -> Result<bool> {
+        let saved = self
+            .client
+            .lock()
+            .await
+            .execute(
+                "INSERT INTO saved (username, id, saved_at, body) VALUES ($1, $2, $3, $4)
+                 ON CONFLICT DO NOTHING",
+                &[
+                    &user,
+                    &(message.id.unwrap_or_default() as i64),
+                    &(saved_at as i64),
+                    &message.to_json()?,
+                ],
+            )
+            .await?;
+        Ok(saved > 0)
+    }
+
+    async fn unsave(&self, user: &str, id: MessageId) -> Result<bool> {
+        let removed = self
+            .client
+            .lock()
+            .await
+            .execute(
+                "DELETE FROM saved WHERE username = $1 AND id = $2",
+                &[&user, &(id as i64)],
+            )
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn saved(&self, user: &str) -> Result<Vec<SavedMessage>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body, saved_at FROM saved WHERE username = $1
+                 ORDER BY saved_at DESC, id DESC",
+                &[&user],
+            )
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(SavedMessage {
+                    message: decode(row)?,
+                    saved_at: row.get::<_, i64>(1) as u64,
+                })
+            })
+            .collect()
+    }
+
+    async fn all_saved(&self) -> Result<Vec<(String, SavedMessage)>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body, saved_at, username FROM saved ORDER BY saved_at, id",
+                &[],
+            )
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok((
+                    row.get(2),
+                    SavedMessage {
+                        message: decode(row)?,
+                        saved_at: row.get::<_, i64>(1) as u64,
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    async fn put_room(&self, room: &str, config: &RoomConfig) -> Result<()> {
+        self.client
+            .lock()
+            .await
+            .execute(
+                "INSERT INTO rooms (room, config) VALUES ($1, $2)
+                 ON CONFLICT (room) DO UPDATE SET config = excluded.config",
+                &[&room, &serde_json::to_string(config)?],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_room(&self, room: &str) -> Result<bool> {
+        let removed = self
+            .client
+            .lock()
+            .await
+            .execute("DELETE FROM rooms WHERE room = $1", &[&room])
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn rooms(&self) -> Result<Vec<(String, RoomConfig)>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query("SELECT room, config FROM rooms ORDER BY room", &[])
+            .await?;
+        rows.iter()
+            .map(|row| Ok((row.get(0), serde_json::from_str(row.get::<_, &str>(1))?)))
+            .collect()
+    }
+
+    async fn put_scheduled(&self, scheduled: &ScheduledMessage) -> Result<()> {
+        self.client
+            .lock()
+            .await
+            .execute(
+                "INSERT INTO scheduled (pending_id, from_addr, body) VALUES ($1, $2, $3)
+                 ON CONFLICT (pending_id) DO UPDATE
+                 SET from_addr = excluded.from_addr, body = excluded.body",
+                &[
+                    &(scheduled.pending_id as i64),
+                    &scheduled.from.to_string(),
+                    &scheduled.message.to_json()?,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_scheduled(&self, pending_id: u64) -> Result<bool> {
+        let removed = self
+            .client
+            .lock()
+            .await
+            .execute(
+                "DELETE FROM scheduled WHERE pending_id = $1",
+                &[&(pending_id as i64)],
+            )
+            .await?;
+        Ok(removed > 0)
+    }
+
+    async fn scheduled(&self) -> Result<Vec<ScheduledMessage>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT body, pending_id, from_addr FROM scheduled ORDER BY pending_id",
+                &[],
+            )
+            .await?;
+        rows.iter()
+            .map(|row| {
+                Ok(ScheduledMessage {
+                    pending_id: row.get::<_, i64>(1) as u64,
+                    from: row.get::<_, &str>(2).parse()?,
+                    message: decode(row)?,
+                })
+            })
+            .collect()
+    }
+
+    async fn share_file(&self, blob_id: &str, room: &str) -> Result<()> {
+        self.client
+            .lock()
+            .await
+            .execute(
+                "INSERT INTO file_shares (blob_id, room) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                &[&blob_id, &room],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn file_rooms(&self, blob_id: &str) -> Result<Vec<String>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query(
+                "SELECT room FROM file_shares WHERE blob_id = $1 ORDER BY room",
+                &[&blob_id],
+            )
+            .await?;
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    async fn file_shares(&self) -> Result<Vec<(String, String)>> {
+        let rows = self
+            .client
+            .lock()
+            .await
+            .query("SELECT blob_id, room FROM file_shares", &[])
+            .await?;
+        Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+    }
+}
@@ -0,0 +1,849 @@
+use super::{HighWater, MessageStore, ScheduledMessage};
+use crate::protocol::{ChatMessage, MessageId, SavedMessage, SearchHit};
+use crate::retention::{PruneStats, RetentionPolicy};
+use crate::room::RoomConfig;
+use anyhow::Result;
+use async_trait::async_trait;
+use rusqlite::{Connection, OptionalExtension, Params, Transaction, params};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS messages (
+        id INTEGER PRIMARY KEY,
+        room TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        body TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS messages_room_id ON messages (room, id);
+    CREATE INDEX IF NOT EXISTS messages_timestamp ON messages (timestamp);
+    CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5 (content);
+    CREATE TABLE IF NOT EXISTS saved (
+        user TEXT NOT NULL,
+        id INTEGER NOT NULL,
+        saved_at INTEGER NOT NULL,
+        body TEXT NOT NULL,
+        PRIMARY KEY (user, id)
+    );
+    CREATE TABLE IF NOT EXISTS nicks (
+        nick TEXT PRIMARY KEY,
+        password_hash TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS high_water (
+        room TEXT PRIMARY KEY,
+        seq INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        last_id INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS rooms (
+        room TEXT PRIMARY KEY,
+        config TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS file_shares (
+        blob_id TEXT NOT NULL,
+        room TEXT NOT NULL,
+        PRIMARY KEY (blob_id, room)
+    );
+    CREATE TABLE IF NOT EXISTS scheduled (
+        pending_id INTEGER PRIMARY KEY,
+        from_addr TEXT NOT NULL,
+        body TEXT NOT NULL
+    );
+    INSERT OR IGNORE INTO high_water (room, seq, timestamp, last_id)
+        SELECT room, COALESCE(MAX(json_extract(body, '$.seq')), 0), MAX(timestamp), MAX(id)
+        FROM messages GROUP BY room;
+";
+
+/// Raises a room's high-water mark to at least `(?2, ?3, ?4)`.
+const RAISE_HIGH_WATER: &str = "
+    INSERT INTO high_water (room, seq, timestamp, last_id) VALUES (?1, ?2, ?3, ?4)
+    ON CONFLICT (room) DO UPDATE
+    SET seq = MAX(seq, excluded.seq), timestamp = MAX(timestamp, excluded.timestamp),
+        last_id = MAX(last_id, excluded.last_id)
+";
+
+/// Persists history in SQLite, with full-text search through FTS5.
+///
+/// Search matches whole words (FTS5 tokens) rather than substrings.
+pub struct SqliteStore {
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Opens (or creates) the database at `path`.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let conn = tokio::task::spawn_blocking(move || Connection::open(path)).await??;
+        Self::from_connection(conn).await
+    }
+
+    /// Opens a private in-memory database, mostly useful for tests.
+    pub async fn open_in_memory() -> Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?).await
+    }
+
+    async fn from_connection(conn: Connection) -> Result<Self> {
+        let store = SqliteStore {
+            conn: Arc::new(Mutex::new(conn)),
+        };
+        store.with_conn(|conn| conn.execute_batch(SCHEMA)).await?;
+        Ok(store)
+    }
+
+    /// Runs blocking database work off the async workers.
+    async fn with_conn<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    {
+        let conn = self.conn.clone();
+        Ok(tokio::task::spawn_blocking(move || f(&mut conn.lock().unwrap())).await??)
+    }
+}
+
+/// Quotes each term so user input can't use FTS5 query syntax.
+fn fts_query(query: &str) -> String {
+    query
+        .split_whitespace()
+        .map(|term| format!("\"{}\"", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Deletes messages matching `condition`, returning what was removed.
+fn delete_where(
+    tx: &Transaction,
+    condition: &str,
+    params: impl Params + Copy,
+) -> rusqlite::Result<PruneStats> {
+    let (messages, bytes) = tx.query_row(
+        &format!(
+            "SELECT COUNT(*), COALESCE(SUM(LENGTH(body)), 0) FROM messages WHERE {}",
+            condition
+        ),
+        params,
+        |row| Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?)),
+    )?;
+    tx.execute(
+        &format!(
+            "DELETE FROM messages_fts WHERE rowid IN (SELECT id FROM messages WHERE {})",
+            condition
+        ),
+        params,
+    )?;
+    tx.execute(&format!("DELETE FROM messages WHERE {}", condition), params)?;
+    Ok(PruneStats {
+        messages: messages as u64,
+        bytes: bytes as u64,
+    })
+}
+
+/// A message's columns, encoded before it's handed to the database thread.
+struct MessageRow {
+    id: i64,
+    room: String,
+    timestamp: i64,
+    seq: i64,
+    content: String,
+    body: String,
+}
+
+impl MessageRow {
+    fn of(message: &ChatMessage) -> Result<Self> {
+        Ok(MessageRow {
+            id: message.id.unwrap_or_default() as i64,
+            room: message.room().to_string(),
+            timestamp: message.timestamp.unwrap_or_default() as i64,
+            seq: message.seq.unwrap_or_default() as i64,
+            content: message.content.clone(),
+            body: message.to_json()?,
+        })
+    }
+
+    /// Inserts the message, its search entry and its room's high-water mark.
+    fn insert(&self, tx: &Transaction) -> rusqlite::Result<()> {
+        tx.execute(
+            "INSERT INTO messages (id, room, timestamp, body) VALUES (?1, ?2, ?3, ?4)",
+            params![self.id, self.room, self.timestamp, self.body],
+        )?;
+        tx.execute(
+            RAISE_HIGH_WATER,
+            params![self.room, self.seq, self.timestamp, self.id],
+        )?;
+        tx.execute(
+            "INSERT INTO messages_fts (rowid, content) VALUES (?1, ?2)",
+            params![self.id, self.content],
+        )?;
+        Ok(())
+    }
+}
+
+fn decode(body: &str) -> rusqlite::Result<ChatMessage> {
+    from_json(body)
+}
+
+fn from_json<T: serde::de::DeserializeOwned>(body: &str) -> rusqlite::Result<T> {
+    serde_json::from_str(body).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn high_water_row(row: &rusqlite::Row, first: usize) -> rusqlite::Result<HighWater> {
+    Ok(HighWater {
+        seq: row.get::<_, i64>(first)? as u64,
+        timestamp: row.get::<_, i64>(first + 1)? as u64,
+        id: row.get::<_, i64>(first + 2)? as MessageId,
+    })
+}
+
+#[async_trait]
+impl MessageStore for SqliteStore {
+    async fn append(&self, message: &ChatMessage) -> Result<()> {
+        let row = MessageRow::of(message)?;
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            row.insert(&tx)?;
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn append_all(&self, messages: &[ChatMessage]) -> Result<u64> {
+        let rows = messages
+            .iter()
+            .map(MessageRow::of)
+            .collect::<Result<Vec<_>>>()?;
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let mut added = 0;
+            for row in &rows {
+                let stored = tx
+                    .query_row(
+                        "SELECT 1 FROM messages WHERE id = ?1 AND room = ?2",
+                        params![row.id, row.room],
+                        |_| Ok(()),
+                    )
+                    .optional()?;
+                if stored.is_none() {
+                    row.insert(&tx)?;
+                    added += 1;
+                }
+            }
+            tx.commit()?;
+            Ok(added)
+        })
+        .await
+    }
+
+    async fn remove(&self, room: &str, id: MessageId) -> Result<bool> {
+        let room = room.to_string();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let removed = tx.execute(
+                "DELETE FROM messages WHERE room = ?1 AND id = ?2",
+                params![room, id as i64],
+            )?;
+            if removed > 0 {
+                tx.execute("DELETE FROM messages_fts WHERE rowid = ?1", [id as i64])?;
+            }
+            tx.commit()?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn page(
+        &self,
+        room: &str,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<(Vec<ChatMessage>, bool)> {
+        let room = room.to_string();
+        let before = before.map_or(i64::MAX, |before| before as i64);
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT body FROM messages WHERE room = ?1 AND id < ?2 ORDER BY id DESC LIMIT ?3",
+            )?;
+            let mut messages = stmt
+                .query_map(params![room, before, limit as i64 + 1], |row| {
+                    decode(&row.get::<_, String>(0)?)
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let has_more = messages.len() > limit;
+            messages.truncate(limit);
+            messages.reverse();
+            Ok((messages, has_more))
+        })
+        .await
+    }
+
+    async fn range(
+        &self,
+        room: &str,
+        since: Option<u64>,
+        until: Option<u64>,
+    ) -> Result<Vec<ChatMessage>> {
+        let room = room.to_string();
+        let since = since.unwrap_or_default() as i64;
+        let until = until.map_or(i64::MAX, |until| until as i64);
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT body FROM messages
+                 WHERE room = ?1 AND timestamp >= ?2 AND timestamp < ?3 ORDER BY id",
+            )?;
+            stmt.query_map(params![room, since, until], |row| {
+                decode(&row.get::<_, String>(0)?)
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    async fn after(&self, after: MessageId, limit: usize) -> Result<Vec<ChatMessage>> {
+        self.with_conn(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT body FROM messages WHERE id > ?1 ORDER BY id LIMIT ?2")?;
+            stmt.query_map(params![after as i64, limit as i64], |row| {
+                decode(&row.get::<_, String>(0)?)
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    async fn sequence(
+        &self,
+        room: &str,
+        from_seq: u64,
+        to_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<ChatMessage>> {
+        let room = room.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT body FROM messages
+                 WHERE room = ?1 AND json_extract(body, '$.seq') BETWEEN ?2 AND ?3
+                 ORDER BY id LIMIT ?4",
+            )?;
+            stmt.query_map(
+                params![room, from_seq as i64, to_seq as i64, limit as i64],
+                |row| decode(&row.get::<_, String>(0)?),
+            )?
+            .collect()
+        })
+        .await
+    }
+
+    async fn search(
+        &self,
+        query: &str,
+        room: Option<&str>,
+        before: Option<MessageId>,
+        limit: usize,
+    ) -> Result<(Vec<SearchHit>, Option<MessageId>)> {
+        let query = fts_query(query);
+        if query.is_empty() || limit == 0 {
+            return Ok((Vec::new(), None));
+        }
+        let room = room.map(str::to_string);
+        let before = before.map_or(i64::MAX, |before| before as i64);
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT m.body, snippet(messages_fts, 0, '', '', '…', 16)
+                 FROM messages_fts JOIN messages m ON m.id = messages_fts.rowid
+                 WHERE messages_fts MATCH ?1 AND (?2 IS NULL OR m.room = ?2) AND m.id < ?3
+                 ORDER BY m.id DESC LIMIT ?4",
+            )?;
+            let mut hits = stmt
+                .query_map(params![query, room, before, limit as i64 + 1], |row| {
+                    Ok(SearchHit {
+                        message: decode(&row.get::<_, String>(0)?)?,
+                        snippet: row.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<_>>>()?;
+            let next_cursor = if hits.len() > limit {
+                hits.truncate(limit);
+                hits.last().and_then(|hit| hit.message.id)
+            } else {
+                None
+            };
+            Ok((hits, next_cursor))
+        })
+        .await
+    }
+
+    async fn prune(&self, policy: &RetentionPolicy, now: u64) -> Result<PruneStats> {
+        let policy = policy.clone();
+        self.with_conn(move |conn| {
+            let tx = conn.transaction()?;
+            let mut stats = PruneStats::default();
+            if let Some(max_age) = policy.max_age {
+                let cutoff = now.saturating_sub(max_age.as_secs()) as i64;
+                stats.add(delete_where(&tx, "timestamp < ?1", [cutoff])?);
+            }
+            if let Some(max_messages) = policy.max_messages_per_room {
+                stats.add(delete_where(
+                    &tx,
+                    "id IN (SELECT id FROM (SELECT id, ROW_NUMBER()
+                        OVER (PARTITION BY room ORDER BY id DESC) AS newer FROM messages)
+                     WHERE newer > ?1)",
+                    [max_messages as i64],
+                )?);
+            }
+            if let Some(max_bytes) = policy.max_total_bytes {
+                stats.add(delete_where(
+                    &tx,
+                    "id IN (SELECT id FROM (SELECT id, SUM(LENGTH(body))
+                        OVER (ORDER BY id DESC) AS kept FROM messages)
+                     WHERE kept > ?1)",
+                    [max_bytes as i64],
+                )?);
+            }
+            tx.commit()?;
+            Ok(stats)
+        })
+        .await
+    }
+
+    async fn last_id(&self) -> Result<Option<MessageId>> {
+        self.with_conn(|conn| {
+            conn.query_row("SELECT MAX(id) FROM messages", [], |row| {
+                row.get::<_, Option<i64>>(0)
+            })
+        })
+        .await
+        .map(|id| id.map(|id| id as MessageId))
+    }
+
+    async fn ephemeral(&self) -> Result<Vec<ChatMessage>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare(
+                "SELECT body FROM messages
+                 WHERE json_extract(body, '$.ttl_secs') IS NOT NULL ORDER BY id",
+            )?;
+            stmt.query_map([], |row| decode(&row.get::<_, String>(0)?))?
+                .collect()
+        })
+        .await
+    }
+
+    async fn high_water(&self, room: &str) -> Result<Option<HighWater>> {
+        let room = room.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT seq, timestamp, last_id FROM high_water WHERE room = ?1",
+                [room],
+                |row| high_water_row(row, 0),
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn high_waters(&self) -> Result<Vec<(String, HighWater)>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT room, seq, timestamp, last_id FROM high_water")?;
+            stmt.query_map([], |row| Ok((row.get(0)?, high_water_row(row, 1)?)))?
+                .collect()
+        })
+        .await
+    }
+
+    async fn raise_high_water(&self, room: &str, mark: HighWater) -> Result<()> {
+        let room = room.to_string();
+        self.with_conn(move |conn| {
+            conn.execute(
+                RAISE_HIGH_WATER,
+                params![room, mark.seq as i64, mark.timestamp as i64, mark.id as i64],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn room_bytes(&self, room: &str) -> Result<u64> {
+        let room = room.to_string();
+        self.with_conn(move |conn| {
+            conn.query_row(
+                "SELECT COALESCE(SUM(LENGTH(CAST(json_extract(body, '$.content') AS BLOB))), 0)
+                 FROM messages WHERE room = ?1",
+                params![room],
+                |row| row.get::<_, i64>(0),
+            )
+        })
+        .await
+        .map(|bytes| bytes as u64)
+    }
+
+    async fn register_nick(&self, nick: &str, password_hash: &str) -> Result<bool> {
+        let (nick, password_hash) = (nick.to_string(), password_hash.to_string());
+        self.with_conn(move |conn| {
+            let registered = conn.execute(
+                "INSERT OR IGNORE INTO nicks (nick, password_hash) VALUES (?1, ?2)",
+                params![nick, password_hash],
+            )?;
+            Ok(registered > 0)
+        })
+        .await
+    }
+
+    async fn nicks(&self) -> Result<Vec<(String, String)>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT nick, password_hash FROM nicks")?;
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .await
+    }
+
+    async fn save(&self, user: &str, message: &ChatMessage, saved_at: u64) -> Result<bool> {
+        let user = user.to_string();
+        let id = message.id.unwrap_or_default() as i64;
+        let body = message.to_json()?;
+        self.with_conn(move |conn| {
+            let saved = conn.execute(
+                "INSERT OR IGNORE INTO saved (user, id, saved_at, body) VALUES (?1, ?2, ?3, ?4)",
+                params![user, id, saved_at as i64, body],
+            )?;
+            Ok(saved > 0)
+        })
+        .await
+    }
+
+    async fn unsave(&self, user: &str, id: MessageId) -> Result<bool> {
+        let user = user.to_string();
+        self.with_conn(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM saved WHERE user = ?1 AND id = ?2",
+                params![user, id as i64],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn saved(&self, user: &str) -> Result<Vec<SavedMessage>> {
+        let user = user.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt = conn.prepare(
+                "SELECT body, saved_at FROM saved WHERE user = ?1
+                 ORDER BY saved_at DESC, rowid DESC",
+            )?;
+            stmt.query_map([user], |row| {
+                Ok(SavedMessage {
+                    message: decode(&row.get::<_, String>(0)?)?,
+                    saved_at: row.get::<_, i64>(1)? as u64,
+                })
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    async fn all_saved(&self) -> Result<Vec<(String, SavedMessage)>> {
+        self.with_conn(|conn| {
+            let mut stmt =
+                conn.prepare("SELECT user, body, saved_at FROM saved ORDER BY saved_at, rowid")?;
+            stmt.query_map([], |row| {
+                Ok((
+                    row.get(0)?,
+                    SavedMessage {
+                        message: decode(&row.get::<_, String>(1)?)?,
+                        saved_at: row.get::<_, i64>(2)? as u64,
+                    },
+                ))
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    async fn put_room(&self, room: &str, config: &RoomConfig) -> Result<()> {
+        let room = room.to_string();
+        let config = serde_json::to_string(config)?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT INTO rooms (room, config) VALUES (?1, ?2)
+                 ON CONFLICT (room) DO UPDATE SET config = excluded.config",
+                params![room, config],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_room(&self, room: &str) -> Result<bool> {
+        let room = room.to_string();
+        self.with_conn(move |conn| {
+            Ok(conn.execute("DELETE FROM rooms WHERE room = ?1", [room])? > 0)
+        })
+        .await
+    }
+
+    async fn rooms(&self) -> Result<Vec<(String, RoomConfig)>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT room, config FROM rooms ORDER BY room")?;
+            stmt.query_map([], |row| {
+                Ok((row.get(0)?, from_json(&row.get::<_, String>(1)?)?))
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    async fn put_scheduled(&self, scheduled: &ScheduledMessage) -> Result<()> {
+        let pending_id = scheduled.pending_id as i64;
+        let from = scheduled.from.to_string();
+        let body = scheduled.message.to_json()?;
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO scheduled (pending_id, from_addr, body) VALUES (?1, ?2, ?3)",
+                params![pending_id, from, body],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn delete_scheduled(&self, pending_id: u64) -> Result<bool> {
+        self.with_conn(move |conn| {
+            let removed = conn.execute(
+                "DELETE FROM scheduled WHERE pending_id = ?1",
+                [pending_id as i64],
+            )?;
+            Ok(removed > 0)
+        })
+        .await
+    }
+
+    async fn scheduled(&self) -> Result<Vec<ScheduledMessage>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn
+                .prepare("SELECT pending_id, from_addr, body FROM scheduled ORDER BY pending_id")?;
+            stmt.query_map([], |row| {
+                Ok(ScheduledMessage {
+                    pending_id: row.get::<_, i64>(0)? as u64,
+                    from: row.get::<_, String>(1)?.parse().map_err(|e| {
+                        rusqlite::Error::FromSqlConversionFailure(
+                            1,
+                            rusqlite::types::Type::Text,
+                            Box::new(e),
+                        )
+                    })?,
+                    message: decode(&row.get::<_, String>(2)?)?,
+                })
+            })?
+            .collect()
+        })
+        .await
+    }
+
+    async fn share_file(&self, blob_id: &str, room: &str) -> Result<()> {
+        let (blob_id, room) = (blob_id.to_string(), room.to_string());
+        self.with_conn(move |conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO file_shares (blob_id, room) VALUES (?1, ?2)",
+                params![blob_id, room],
+            )?;
+            Ok(())
+        })
+        .await
+    }
+
+    async fn file_rooms(&self, blob_id: &str) -> Result<Vec<String>> {
+        let blob_id = blob_id.to_string();
+        self.with_conn(move |conn| {
+            let mut stmt =
+                conn.prepare("SELECT room FROM file_shares WHERE blob_id = ?1 ORDER BY room")?;
+            stmt.query_map([blob_id], |row| row.get(0))?.collect()
+        })
+        .await
+    }
+
+    async fn file_shares(&self) -> Result<Vec<(String, String)>> {
+        self.with_conn(|conn| {
+            let mut stmt = conn.prepare("SELECT blob_id, room FROM file_shares")?;
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: MessageId, content: &str) -> ChatMessage {
+        ChatMessage {
+            sender: "avery".to_string(),
+            content: content.to_string(),
+            id: Some(id),
+            room: Some("general".to_string()),
+            timestamp: Some(id * 10),
+            seq: Some(id),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn sqlite_round_trip() -> Result<()> {
+        let store = SqliteStore::open_in_memory().await?;
+        for id in 1..=5 {
+            store
+                .append(&message(id, &format!("deploy number {}", id)))
+                .await?;
+        }
+        assert_eq!(store.last_id().await?, Some(5));
+        assert!(
+            store
+                .save("avery", &message(2, "deploy number 2"), 100)
+                .await?
+        );
+        assert!(
+            !store
+                .save("avery", &message(2, "deploy number 2"), 200)
+                .await?
+        );
+        assert_eq!(store.saved("avery").await?[0].message.id, Some(2));
+        assert!(store.unsave("avery", 2).await?);
+        assert!(store.saved("avery").await?.is_empty());
+
+        let (messages, has_more) = store.page("general", Some(5), 2).await?;
+        let ids: Vec<_> = messages.iter().map(|m| m.id.unwrap()).collect();
+        assert_eq!(ids, vec![3, 4]);
+        assert!(has_more);
+        let messages = store.sequence("general", 2, 3, 10).await?;
+        let ids: Vec<_> = messages.iter().map(|m| m.id.unwrap()).collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        let (hits, cursor) = store.search("deploy", Some("general"), None, 3).await?;
+        assert_eq!(hits.len(), 3);
+        assert_eq!(cursor, Some(3));
+
+        assert!(store.remove("general", 5).await?);
+        let policy = RetentionPolicy {
+            max_age: Some(std::time::Duration::from_secs(10)),
+            ..Default::default()
+        };
+        assert_eq!(store.prune(&policy, 35).await?.messages, 2);
+        let remaining = store.range("general", None, None).await?;
+        let ids: Vec<_> = remaining.iter().map(|m| m.id.unwrap()).collect();
+        assert_eq!(ids, vec![3, 4]);
+        assert_eq!(
+            store.high_water("general").await?,
+            Some(HighWater {
+                seq: 5,
+                timestamp: 50,
+                id: 5,
+            })
+        );
+        assert_eq!(store.high_water("ops").await?, None);
+        assert_eq!(
+            store.room_bytes("general").await?,
+            2 * "deploy number 3".len() as u64
+        );
+        assert_eq!(store.room_bytes("ops").await?, 0);
+
+        assert!(store.register_nick("avery", "$argon2id$hash").await?);
+        assert!(!store.register_nick("avery", "$argon2id$other").await?);
+        assert_eq!(
+            store.nicks().await?,
+            vec![("avery".to_string(), "$argon2id$hash".to_string())]
+        );
+
+        store
+            .raise_high_water(
+                "ops",
+                HighWater {
+                    seq: 7,
+                    timestamp: 70,
+                    id: 12,
+                },
+            )
+            .await?;
+        let mut marks = store.high_waters().await?;
+        marks.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(marks.len(), 2);
+        assert_eq!((marks[1].1.seq, marks[1].1.id), (7, 12));
+
+        let topical = RoomConfig {
+            topic: Some("releases".to_string()),
+            ..Default::default()
+        };
+        store.put_room("plans", &RoomConfig::default()).await?;
+        store.put_room("plans", &topical).await?;
+        assert_eq!(store.rooms().await?, vec![("plans".to_string(), topical)]);
+        assert!(store.delete_room("plans").await?);
+        assert!(!store.delete_room("plans").await?);
+        assert!(store.rooms().await?.is_empty());
+
+        store
+            .save("blake", &message(3, "deploy number 3"), 300)
+            .await?;
+        store
+            .save("avery", &message(4, "deploy number 4"), 200)
+            .await?;
+        let all: Vec<_> = store
+            .all_saved()
+            .await?
+            .into_iter()
+            .map(|(user, saved)| (user, saved.saved_at))
+            .collect();
+        assert_eq!(
+            all,
+            vec![("avery".to_string(), 200), ("blake".to_string(), 300)]
+        );
+
+        let later = ScheduledMessage {
+            pending_id: 4,
+            from: "127.0.0.1:4000".parse()?,
+            message: message(10, "later"),
+        };
+        store.put_scheduled(&later).await?;
+        store.put_scheduled(&later).await?;
+        assert_eq!(store.scheduled().await?, vec![later]);
+        assert!(store.delete_scheduled(4).await?);
+        assert!(!store.delete_scheduled(4).await?);
+        assert!(store.scheduled().await?.is_empty());
+
+        let blob = "b".repeat(64);
+        store.share_file(&blob, "ops").await?;
+        store.share_file(&blob, "general").await?;
+        store.share_file(&blob, "ops").await?;
+        assert_eq!(store.file_rooms(&blob).await?, vec!["general", "ops"]);
+        assert_eq!(store.file_shares().await?.len(), 2);
+        assert!(store.file_rooms(&"c".repeat(64)).await?.is_empty());
+
+        assert!(store.ephemeral().await?.is_empty());
+        let mut fleeting = message(9, "brb");
+        fleeting.ttl_secs = Some(60);
+        store.append(&fleeting).await?;
+        assert_eq!(store.ephemeral().await?, vec![fleeting]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn append_all_is_all_or_nothing() -> Result<()> {
+        let store = SqliteStore::open_in_memory().await?;
+        store.append(&message(1, "one")).await?;
+        let batch: Vec<_> = (1..=3).map(|id| message(id, "restored")).collect();
+        assert_eq!(store.append_all(&batch).await?, 2);
+        assert_eq!(store.append_all(&batch).await?, 0);
+
+        let mut elsewhere = message(4, "taken");
+        elsewhere.room = Some("ops".to_string());
+        store.append(&elsewhere).await?;
+        let batch: Vec<_> = (5..=6).chain([4]).map(|id| message(id, "late")).collect();
+        assert!(store.append_all(&batch).await.is_err());
+        let ids: Vec<_> = store
+            .range("general", None, None)
+            .await?
+            .iter()
+            .map(|m| m.id.unwrap())
+            .collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use crate::tasks::{TaskRegistry, catch_panic};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::time::{Duration, Instant};
+use tracing::{error, info};
+
+/// Wait before the first restart of a failed job; doubled for each failure
+/// in a row.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+/// Longest wait between restarts.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long a restarted job must run before its backoff starts over.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+/// Task subsystem supervised jobs run in.
+pub const SUBSYSTEM: &str = "background";
+
+/// How a supervised job is doing, as reported for readiness.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JobHealth {
+    pub name: String,
+    /// False while the job is waiting to be restarted.
+    pub healthy: bool,
+    /// Times the job has been restarted since the server started.
+    pub restarts: u64,
+    /// Why it last failed, if it ever has.
+    pub last_failure: Option<String>,
+}
+
+/// Runs background jobs that should never end, such as the pruner and the
+/// archiver, restarting any that panic or return, with exponential backoff
+/// so a job that keeps failing doesn't spin.
+#[derive(Debug, Default)]
+pub struct Supervisor {
+    tasks: Arc<TaskRegistry>,
+    jobs: Arc<Mutex<BTreeMap<&'static str, JobHealth>>>,
+}
+
+impl Supervisor {
+    /// Creates a supervisor that spawns its jobs in `tasks`.
+    pub fn new(tasks: Arc<TaskRegistry>) -> Self {
+        Supervisor {
+            tasks,
+            jobs: Arc::default(),
+        }
+    }
+
+    /// Starts `job` under `name`, calling it again to restart it whenever
+    /// the future it returns ends.
+    pub fn supervise<J, F>(&self, name: &'static str, job: J)
+    where
+        J: Fn() -> F + Send + 'static,
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.jobs.lock().unwrap().insert(
+            name,
+            JobHealth {
+                name: name.to_string(),
+                healthy: true,
+                restarts: 0,
+                last_failure: None,
+            },
+        );
+        let jobs = self.jobs.clone();
+        self.tasks.spawn(SUBSYSTEM, name, async move {
+            let mut backoff = INITIAL_BACKOFF;
+            loop {
+                let started = Instant::now();
+                let failure = match catch_panic(job()).await {
+                    Ok(()) => "exited".to_string(),
+                    Err(panic) => format!("panicked: {}", panic),
+                };
+                if started.elapsed() >= STABLE_AFTER {
+                    backoff = INITIAL_BACKOFF;
+                }
+                error!(
+                    "Background job {} {}; restarting in {:?}",
+                    name, failure, backoff
+                );
+                update(&jobs, name, |health| {
+                    health.healthy = false;
+                    health.last_failure = Some(failure);
+                });
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+                info!("Restarting background job {}", name);
+                update(&jobs, name, |health| {
+                    health.healthy = true;
+                    health.restarts += 1;
+                });
+            }
+        });
+    }
+
+    /// Returns the health of every job, by name.
+    pub fn health(&self) -> Vec<JobHealth> {
+        self.jobs.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Whether every job is running.
+    pub fn healthy(&self) -> bool {
+        self.jobs.lock().unwrap().values().all(|job| job.healthy)
+    }
+}
+
+fn update(
+    jobs: &Mutex<BTreeMap<&'static str, JobHealth>>,
+    name: &str,
+    change: impl FnOnce(&mut JobHealth),
+) {
+    if let Some(health) = jobs.lock().unwrap().get_mut(name) {
+        change(health);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_jobs_are_restarted_with_backoff() {
+        let supervisor = Supervisor::new(Arc::new(TaskRegistry::new()));
+        let runs = Arc::new(AtomicU32::new(0));
+        let counter = runs.clone();
+        supervisor.supervise("flaky", move || {
+            let run = counter.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if run < 2 {
+                    panic!("run {} failed", run);
+                }
+                std::future::pending::<()>().await
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        let health = &supervisor.health()[0];
+        assert!(!health.healthy);
+        assert_eq!(
+            health.last_failure.as_deref(),
+            Some("panicked: run 0 failed")
+        );
+        assert!(!supervisor.healthy());
+
+        // Restarted after 100ms, fails again, then after another 200ms.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(supervisor.healthy());
+        assert_eq!(supervisor.health()[0].restarts, 2);
+    }
+}
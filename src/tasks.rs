@@ -0,0 +1,249 @@
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use tokio::sync::Notify;
+use tokio::task::{AbortHandle, JoinHandle};
+use tokio::time::{Duration, Instant, timeout};
+
+/// Spawns `future` as a task called `name`. With the `console` feature
+/// (and `--cfg tokio_unstable`, set in `.cargo/config.toml`) the name is
+/// what tokio-console lists the task as; otherwise it's only a label.
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    #[cfg(all(feature = "console", tokio_unstable))]
+    {
+        tokio::task::Builder::new()
+            .name(name)
+            .spawn(future)
+            .expect("tasks can be spawned on a running runtime")
+    }
+    #[cfg(not(all(feature = "console", tokio_unstable)))]
+    {
+        let _ = name;
+        tokio::spawn(future)
+    }
+}
+
+/// Runs `future`, returning what it panicked with instead of unwinding,
+/// so a panic ends only the work that hit it and the caller can clean up.
+pub async fn catch_panic<F: Future>(future: F) -> Result<F::Output, String> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(panic_message(payload.as_ref()))),
+        }
+    })
+    .await
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+/// A task still running, as listed by `TaskRegistry::running`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    /// What the task belongs to, e.g. "clients" or "background".
+    pub subsystem: String,
+    pub name: String,
+    /// How long ago it was spawned.
+    pub age: Duration,
+}
+
+/// Every task a server has spawned and not yet seen finish, by subsystem,
+/// so shutdown can wait for them and tasks that never end can be found.
+/// Tasks leave the registry when they finish, panic or are aborted.
+#[derive(Debug, Default)]
+pub struct TaskRegistry {
+    running: Arc<Mutex<Running>>,
+    /// Woken whenever the last running task finishes.
+    idle: Arc<Notify>,
+}
+
+#[derive(Debug, Default)]
+struct Running {
+    next_id: u64,
+    tasks: HashMap<u64, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    subsystem: &'static str,
+    name: String,
+    started: Instant,
+    abort: Option<AbortHandle>,
+}
+
+/// Held by a task for as long as it runs; dropping it, however the task
+/// ends, takes the task out of the registry.
+struct Registered {
+    id: u64,
+    running: Arc<Mutex<Running>>,
+    idle: Arc<Notify>,
+}
+
+impl Drop for Registered {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap();
+        running.tasks.remove(&self.id);
+        if running.tasks.is_empty() {
+            self.idle.notify_waiters();
+        }
+    }
+}
+
+impl TaskRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawns `future` as a task called `name` in `subsystem`, tracked
+    /// until it ends.
+    pub fn spawn<F>(&self, subsystem: &'static str, name: &str, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let mut running = self.running.lock().unwrap();
+        let id = running.next_id;
+        running.next_id += 1;
+        running.tasks.insert(
+            id,
+            Entry {
+                subsystem,
+                name: name.to_string(),
+                started: Instant::now(),
+                abort: None,
+            },
+        );
+        drop(running);
+        let registered = Registered {
+            id,
+            running: self.running.clone(),
+            idle: self.idle.clone(),
+        };
+        let handle = spawn_named(name, async move {
+            let _registered = registered;
+            future.await
+        });
+        // Unless it has already finished and left.
+        if let Some(entry) = self.running.lock().unwrap().tasks.get_mut(&id) {
+            entry.abort = Some(handle.abort_handle());
+        }
+        handle
+    }
+
+    /// Returns how many tasks are running in each subsystem.
+    pub fn counts(&self) -> BTreeMap<String, usize> {
+        let mut counts = BTreeMap::new();
+        for entry in self.running.lock().unwrap().tasks.values() {
+            *counts.entry(entry.subsystem.to_string()).or_default() += 1;
+        }
+        counts
+    }
+
+    /// Returns every running task, oldest first.
+    pub fn running(&self) -> Vec<TaskInfo> {
+        let running = self.running.lock().unwrap();
+        let mut tasks: Vec<(u64, TaskInfo)> = running
+            .tasks
+            .iter()
+            .map(|(id, entry)| {
+                let info = TaskInfo {
+                    subsystem: entry.subsystem.to_string(),
+                    name: entry.name.clone(),
+                    age: entry.started.elapsed(),
+                };
+                (*id, info)
+            })
+            .collect();
+        tasks.sort_by_key(|(id, _)| *id);
+        tasks.into_iter().map(|(_, info)| info).collect()
+    }
+
+    /// Aborts every task in `subsystem`, e.g. jobs that loop until the
+    /// server stops. Returns how many were aborted.
+    pub fn abort(&self, subsystem: &str) -> usize {
+        let running = self.running.lock().unwrap();
+        let mut aborted = 0;
+        for entry in running.tasks.values() {
+            if entry.subsystem == subsystem
+                && let Some(abort) = &entry.abort
+            {
+                abort.abort();
+                aborted += 1;
+            }
+        }
+        aborted
+    }
+
+    /// Waits up to `grace` for every task to finish, then aborts and
+    /// returns those that haven't: tasks that leaked.
+    pub async fn join(&self, grace: Duration) -> Vec<TaskInfo> {
+        let idle = async {
+            loop {
+                let notified = self.idle.notified();
+                if self.running.lock().unwrap().tasks.is_empty() {
+                    return;
+                }
+                notified.await;
+            }
+        };
+        if timeout(grace, idle).await.is_ok() {
+            return Vec::new();
+        }
+        let leaked = self.running();
+        for entry in self.running.lock().unwrap().tasks.values() {
+            if let Some(abort) = &entry.abort {
+                abort.abort();
+            }
+        }
+        leaked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_tasks_are_tracked_until_they_end() {
+        let tasks = TaskRegistry::new();
+        let (finish, finished) = tokio::sync::oneshot::channel::<()>();
+        tasks.spawn("clients", "client 1", async move {
+            let _ = finished.await;
+        });
+        tasks.spawn("background", "pruner", std::future::pending::<()>());
+        tasks.spawn("background", "archiver", std::future::pending::<()>());
+        assert_eq!(
+            tasks.counts(),
+            BTreeMap::from([("background".to_string(), 2), ("clients".to_string(), 1)])
+        );
+
+        assert_eq!(tasks.abort("background"), 2);
+        finish.send(()).unwrap();
+        assert!(tasks.join(Duration::from_secs(5)).await.is_empty());
+        assert!(tasks.counts().is_empty());
+
+        tasks.spawn("pushes", "pushes", std::future::pending::<()>());
+        let leaked = tasks.join(Duration::from_millis(10)).await;
+        assert_eq!(leaked.len(), 1);
+        assert_eq!(leaked[0].name, "pushes");
+        assert!(tasks.join(Duration::from_secs(5)).await.is_empty());
+    }
+}
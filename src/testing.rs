@@ -0,0 +1,224 @@
+use crate::codec::FrameDecoder;
+use crate::protocol::{ChatMessage, ClientFrame, ServerFrame};
+use anyhow::{Result, anyhow, bail};
+use std::net::SocketAddr;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, timeout};
+
+/// How long `MockServer::finish` waits for the script to play out.
+const FINISH_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// A line sent by a client, parsed the way `ChatServer` parses it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Inbound {
+    Frame(ClientFrame),
+    /// A chat message, sent as JSON or as a "sender:content" line.
+    Message(Box<ChatMessage>),
+}
+
+impl Inbound {
+    fn parse(line: &str) -> Result<Inbound> {
+        if let Ok(frame) = serde_json::from_str(line) {
+            return Ok(Inbound::Frame(frame));
+        }
+        let message = match serde_json::from_str(line) {
+            Ok(message) => message,
+            Err(_) => ChatMessage::from_raw(line)?,
+        };
+        Ok(Inbound::Message(Box::new(message)))
+    }
+}
+
+type Check = Box<dyn Fn(&Inbound) -> bool + Send>;
+
+enum Step {
+    Expect { what: String, check: Check },
+    Send(Box<ServerFrame>),
+    Close,
+}
+
+/// Scripts a `MockServer`. Steps play out in order against the first
+/// client to connect.
+#[derive(Default)]
+pub struct MockServerBuilder {
+    steps: Vec<Step>,
+}
+
+impl MockServerBuilder {
+    /// Waits for the client to send exactly `frame`.
+    pub fn expect(mut self, frame: ClientFrame) -> Self {
+        let what = format!("{:?}", frame);
+        let expected = Inbound::Frame(frame);
+        self.steps.push(Step::Expect {
+            what,
+            check: Box::new(move |inbound| *inbound == expected),
+        });
+        self
+    }
+
+    /// Waits for the client to send a chat message that `check` accepts.
+    pub fn expect_message(
+        mut self,
+        what: impl Into<String>,
+        check: impl Fn(&ChatMessage) -> bool + Send + 'static,
+    ) -> Self {
+        self.steps.push(Step::Expect {
+            what: what.into(),
+            check: Box::new(
+                move |inbound| matches!(inbound, Inbound::Message(message) if check(message)),
+            ),
+        });
+        self
+    }
+
+    /// Sends `frame` to the client.
+    pub fn send(mut self, frame: ServerFrame) -> Self {
+        self.steps.push(Step::Send(Box::new(frame)));
+        self
+    }
+
+    /// Drops the connection, e.g. to exercise reconnects. Later steps are
+    /// skipped.
+    pub fn close(mut self) -> Self {
+        self.steps.push(Step::Close);
+        self
+    }
+
+    /// Starts listening on a free local port and plays the script.
+    pub async fn start(self) -> Result<MockServer> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let task = tokio::spawn(play(listener, self.steps));
+        Ok(MockServer { addr, task })
+    }
+}
+
+/// A scripted stand-in for `ChatServer`, so apps built on `Client` can
+/// test their chat integration without a real server:
+///
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use tokio_chat_server::client::Client;
+/// use tokio_chat_server::protocol::{ClientFrame, ServerFrame};
+/// use tokio_chat_server::testing::MockServer;
+///
+/// let server = MockServer::builder()
+///     .expect(ClientFrame::Presence)
+///     .send(ServerFrame::Presence { users: Vec::new() })
+///     .start()
+///     .await?;
+/// let mut client = Client::connect(&server.local_addr().to_string()).await?;
+/// client.send_frame(&ClientFrame::Presence).await?;
+/// assert!(matches!(client.receive().await?, ServerFrame::Presence { .. }));
+/// server.finish().await?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// The connection closes once the script ends.
+pub struct MockServer {
+    addr: SocketAddr,
+    task: JoinHandle<Result<()>>,
+}
+
+impl MockServer {
+    pub fn builder() -> MockServerBuilder {
+        MockServerBuilder::default()
+    }
+
+    /// Returns the address clients should connect to.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Waits for the script to finish, failing if the client sent
+    /// something unexpected, disconnected early, or left the script
+    /// unfinished for five seconds.
+    pub async fn finish(self) -> Result<()> {
+        match timeout(FINISH_TIMEOUT, self.task).await {
+            Ok(result) => result?,
+            Err(_) => Err(anyhow!("Mock server script did not finish")),
+        }
+    }
+}
+
+async fn play(listener: TcpListener, steps: Vec<Step>) -> Result<()> {
+    let (mut socket, _) = listener.accept().await?;
+    let mut decoder = FrameDecoder::new(MAX_LINE_LEN);
+    let mut buffer = [0; 1024];
+    for (index, step) in steps.into_iter().enumerate() {
+        match step {
+            Step::Send(frame) => {
+                socket
+                    .write_all(format!("{}\n", frame.to_json()?).as_bytes())
+                    .await?;
+            }
+            Step::Close => return Ok(()),
+            Step::Expect { what, check } => {
+                let line = loop {
+                    if let Some(line) = decoder.next_line()? {
+                        break line;
+                    }
+                    let n = socket.read(&mut buffer).await?;
+                    if n == 0 {
+                        bail!(
+                            "Step {}: expected {}, but the client disconnected",
+                            index + 1,
+                            what
+                        );
+                    }
+                    decoder.extend(&buffer[..n]);
+                };
+                let inbound = Inbound::parse(&line)?;
+                if !check(&inbound) {
+                    bail!("Step {}: expected {}, got {:?}", index + 1, what, inbound);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+
+    #[tokio::test]
+    async fn test_scripted_exchange() -> Result<()> {
+        let server = MockServer::builder()
+            .expect(ClientFrame::Presence)
+            .send(ServerFrame::Presence { users: Vec::new() })
+            .expect_message("a greeting", |message| message.content == "hello")
+            .start()
+            .await?;
+        let mut client = Client::connect(&server.local_addr().to_string()).await?;
+        client.send_frame(&ClientFrame::Presence).await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Presence { .. }
+        ));
+        client.send(ChatMessage::from_raw("avery: hello")?).await?;
+        server.finish().await
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_frame_fails() -> Result<()> {
+        let server = MockServer::builder()
+            .expect(ClientFrame::Heartbeat)
+            .start()
+            .await?;
+        let mut client = Client::connect(&server.local_addr().to_string()).await?;
+        client.send_frame(&ClientFrame::Presence).await?;
+        let error = server.finish().await.unwrap_err().to_string();
+        assert!(
+            error.starts_with("Step 1: expected Heartbeat, got"),
+            "{}",
+            error
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+use crate::transport::{BoxStream, Listener};
+use anyhow::{Context, Result, anyhow};
+use async_trait::async_trait;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::{Mutex, mpsc};
+use tokio_rustls::LazyConfigAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::Acceptor;
+use tokio_rustls::rustls::server::{ClientHello, ResolvesServerCert};
+use tokio_rustls::rustls::sign::CertifiedKey;
+use tracing::{debug, error, info, warn};
+
+/// How long a client has to complete the TLS handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Handshaken connections waiting for the server to take them.
+const ACCEPT_BACKLOG: usize = 128;
+/// The ALPN protocol ACME CAs validate TLS-ALPN-01 challenges over.
+#[cfg(feature = "acme")]
+const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Where `Certificates` come from.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// Holds a PEM certificate chain `<hostname>.crt` and private key
+    /// `<hostname>.key` for each hostname served. `*.example.com.crt`
+    /// covers example.com's subdomains.
+    pub cert_dir: PathBuf,
+    /// The hostname whose certificate clients that don't send SNI get.
+    pub default_host: Option<String>,
+    /// How often `cert_dir` is checked for new or changed certificates.
+    pub reload_interval: Duration,
+}
+
+/// The certificates in a `TlsConfig::cert_dir`, chosen by the hostname
+/// clients ask for through SNI, so one server can host chat for several
+/// domains.
+#[derive(Debug)]
+pub struct Certificates {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    loaded: RwLock<Loaded>,
+    /// TLS-ALPN-01 challenge certificates being validated, by hostname.
+    #[cfg(feature = "acme")]
+    challenges: RwLock<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+#[derive(Debug, Default)]
+struct Loaded {
+    hosts: HashMap<String, Arc<CertifiedKey>>,
+    /// The directory's files as loaded, with when they were modified.
+    modified: BTreeMap<PathBuf, SystemTime>,
+}
+
+impl Certificates {
+    /// Loads the certificates in `config.cert_dir`, failing if there are
+    /// none.
+    pub fn load(config: TlsConfig) -> Result<Arc<Self>> {
+        let certs = Certificates::open(config)?;
+        if certs.hosts().is_empty() {
+            return Err(anyhow!(
+                "No certificates in {}",
+                certs.config.cert_dir.display()
+            ));
+        }
+        Ok(certs)
+    }
+
+    /// Loads the certificates in `config.cert_dir`, which may have none
+    /// yet, e.g. because ACME hasn't issued them.
+    pub fn open(config: TlsConfig) -> Result<Arc<Self>> {
+        let certs = Certificates {
+            config,
+            provider: Arc::new(ring::default_provider()),
+            loaded: RwLock::default(),
+            #[cfg(feature = "acme")]
+            challenges: RwLock::default(),
+        };
+        certs.reload_if_changed()?;
+        Ok(Arc::new(certs))
+    }
+
+    /// The directory the certificates are loaded from.
+    pub fn cert_dir(&self) -> &Path {
+        &self.config.cert_dir
+    }
+
+    /// Returns the hostnames there are certificates for.
+    pub fn hosts(&self) -> Vec<String> {
+        let mut hosts: Vec<String> = self.loaded.read().unwrap().hosts.keys().cloned().collect();
+        hosts.sort();
+        hosts
+    }
+
+    /// Loads the directory again if its files have changed, returning
+    /// whether they had. A certificate that fails to load, e.g. because
+    /// its key hasn't been written yet, keeps its previous version.
+    pub fn reload_if_changed(&self) -> Result<bool> {
+        let modified = self.scan()?;
+        if modified == self.loaded.read().unwrap().modified {
+            return Ok(false);
+        }
+        let mut hosts = HashMap::new();
+        for path in modified.keys() {
+            if path.extension().is_none_or(|extension| extension != "crt") {
+                continue;
+            }
+            let Some(host) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            let host = host.to_ascii_lowercase();
+            match self.load_pair(path, &path.with_extension("key")) {
+                Ok(key) => {
+                    hosts.insert(host, Arc::new(key));
+                }
+                Err(e) => {
+                    warn!("Failed to load the certificate for {}: {:#}", host, e);
+                    if let Some(previous) = self.loaded.read().unwrap().hosts.get(&host) {
+                        hosts.insert(host, previous.clone());
+                    }
+                }
+            }
+        }
+        *self.loaded.write().unwrap() = Loaded { hosts, modified };
+        Ok(true)
+    }
+
+    fn scan(&self) -> Result<BTreeMap<PathBuf, SystemTime>> {
+        let dir = &self.config.cert_dir;
+        let mut modified = BTreeMap::new();
+        for entry in
+            std::fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            let entry = entry?;
+            modified.insert(entry.path(), entry.metadata()?.modified()?);
+        }
+        Ok(modified)
+    }
+
+    fn load_pair(&self, cert: &Path, key: &Path) -> Result<CertifiedKey> {
+        let chain =
+            CertificateDer::pem_slice_iter(&std::fs::read(cert)?).collect::<Result<Vec<_>, _>>()?;
+        let key = PrivateKeyDer::from_pem_slice(&std::fs::read(key)?)?;
+        let key = CertifiedKey::from_der(chain, key, &self.provider)?;
+        Ok(key)
+    }
+
+    /// Returns the certificate for `host`, or a wildcard one covering it,
+    /// or the default host's if the client didn't say.
+    fn find(&self, host: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let host = host
+            .or(self.config.default_host.as_deref())?
+            .to_ascii_lowercase();
+        let loaded = self.loaded.read().unwrap();
+        loaded.hosts.get(&host).cloned().or_else(|| {
+            let (_, parent) = host.split_once('.')?;
+            loaded.hosts.get(&format!("*.{}", parent)).cloned()
+        })
+    }
+
+    /// Serves `cert` to ACME CAs validating `host` over TLS-ALPN-01, until
+    /// it's cleared.
+    #[cfg(feature = "acme")]
+    pub(crate) fn answer_challenge(
+        &self,
+        host: &str,
+        cert: CertificateDer<'static>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<()> {
+        // Not `from_der`: it parses the certificate, and rejects the
+        // critical acmeIdentifier extension.
+        let key = CertifiedKey::new(
+            vec![cert],
+            self.provider.key_provider.load_private_key(key)?,
+        );
+        self.challenges
+            .write()
+            .unwrap()
+            .insert(host.to_ascii_lowercase(), Arc::new(key));
+        Ok(())
+    }
+
+    #[cfg(feature = "acme")]
+    pub(crate) fn clear_challenge(&self, host: &str) {
+        self.challenges
+            .write()
+            .unwrap()
+            .remove(&host.to_ascii_lowercase());
+    }
+}
+
+impl ResolvesServerCert for Certificates {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = self.find(hello.server_name());
+        if key.is_none() {
+            debug!("No certificate for {:?}", hello.server_name());
+        }
+        key
+    }
+}
+
+/// Resolves the challenge certificates of a `Certificates`, for
+/// connections from ACME CAs.
+#[cfg(feature = "acme")]
+#[derive(Debug)]
+struct Challenges(Arc<Certificates>);
+
+#[cfg(feature = "acme")]
+impl ResolvesServerCert for Challenges {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let host = hello.server_name()?.to_ascii_lowercase();
+        self.0.challenges.read().unwrap().get(&host).cloned()
+    }
+}
+
+/// The `ServerConfig`s a `TlsListener` handshakes with.
+struct Configs {
+    chat: Arc<ServerConfig>,
+    #[cfg(feature = "acme")]
+    challenges: Arc<ServerConfig>,
+}
+
+/// A `Listener` serving TLS over another's connections, with the
+/// certificate for the hostname each client asks for. Handshakes happen
+/// off the accept loop, so slow clients can't hold up others.
+pub struct TlsListener {
+    accepted: Mutex<mpsc::Receiver<io::Result<(BoxStream, SocketAddr)>>>,
+    local_addr: SocketAddr,
+}
+
+impl TlsListener {
+    /// Serves `certs` over `inner`'s connections, reloading them as they
+    /// change. Starts a task on the current runtime that runs until the
+    /// listener is dropped. With the `acme` feature, it also answers the
+    /// TLS-ALPN-01 challenges of `certs`.
+    pub fn new(inner: impl Listener + 'static, certs: Arc<Certificates>) -> io::Result<Self> {
+        let local_addr = inner.local_addr()?;
+        let builder = || {
+            ServerConfig::builder_with_provider(certs.provider.clone())
+                .with_safe_default_protocol_versions()
+                .map(|builder| builder.with_no_client_auth())
+                .map_err(io::Error::other)
+        };
+        let configs = Configs {
+            chat: Arc::new(builder()?.with_cert_resolver(certs.clone())),
+            #[cfg(feature = "acme")]
+            challenges: {
+                let mut config = builder()?.with_cert_resolver(Arc::new(Challenges(certs.clone())));
+                config.alpn_protocols = vec![ACME_TLS_ALPN.to_vec()];
+                Arc::new(config)
+            },
+        };
+        let (sender, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        tokio::spawn(accept_loop(inner, Arc::new(configs), certs, sender));
+        info!("Serving TLS on {}", local_addr);
+        Ok(TlsListener {
+            accepted: Mutex::new(accepted),
+            local_addr,
+        })
+    }
+}
+
+#[async_trait]
+impl Listener for TlsListener {
+    async fn accept(&self) -> io::Result<(BoxStream, SocketAddr)> {
+        match self.accepted.lock().await.recv().await {
+            Some(accepted) => accepted,
+            None => Err(io::Error::other("The TLS listener has stopped")),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Accepts connections and handshakes each on its own task, passing the
+/// server the ones that complete, and reloads the certificates as they
+/// change, until the `TlsListener` is dropped.
+async fn accept_loop(
+    inner: impl Listener,
+    configs: Arc<Configs>,
+    certs: Arc<Certificates>,
+    accepted: mpsc::Sender<io::Result<(BoxStream, SocketAddr)>>,
+) {
+    let mut reloads = tokio::time::interval(certs.config.reload_interval);
+    reloads.tick().await;
+    loop {
+        let (socket, addr) = tokio::select! {
+            result = inner.accept() => match result {
+                Ok(connection) => connection,
+                Err(e) => {
+                    if accepted.send(Err(e)).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            },
+            _ = reloads.tick() => {
+                let reloading = certs.clone();
+                match tokio::task::spawn_blocking(move || reloading.reload_if_changed()).await {
+                    Ok(Ok(true)) => info!("Reloaded certificates for {:?}", certs.hosts()),
+                    Ok(Ok(false)) => {}
+                    Ok(Err(e)) => error!("Failed to reload certificates: {:#}", e),
+                    Err(e) => error!("Certificate reload panicked: {}", e),
+                }
+                continue;
+            }
+            _ = accepted.closed() => break,
+        };
+        let (configs, accepted) = (configs.clone(), accepted.clone());
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(socket, &configs)).await {
+                Ok(Ok(Some(stream))) => {
+                    let _ = accepted.send(Ok((stream, addr))).await;
+                }
+                Ok(Ok(None)) => debug!("Answered an ACME challenge from {}", addr),
+                Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", addr, e),
+                Err(_) => debug!("TLS handshake with {} timed out", addr),
+            }
+        });
+    }
+}
+
+/// Completes the handshake of a chat client, or of an ACME CA validating
+/// a challenge, which gets `None` as it has nothing more to say.
+async fn handshake(socket: BoxStream, configs: &Configs) -> io::Result<Option<BoxStream>> {
+    let start = LazyConfigAcceptor::new(Acceptor::default(), socket).await?;
+    #[cfg(feature = "acme")]
+    if start
+        .client_hello()
+        .alpn()
+        .is_some_and(|mut protocols| protocols.any(|protocol| protocol == ACME_TLS_ALPN))
+    {
+        let mut stream = start.into_stream(configs.challenges.clone()).await?;
+        tokio::io::AsyncWriteExt::shutdown(&mut stream).await?;
+        return Ok(None);
+    }
+    let stream = start.into_stream(configs.chat.clone()).await?;
+    Ok(Some(Box::new(stream)))
+}
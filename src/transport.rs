@@ -0,0 +1,61 @@
+use async_trait::async_trait;
+use std::io;
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+/// A client connection's byte stream.
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+/// A `Stream` of any kind, as `Listener::accept` hands them out.
+pub type BoxStream = Box<dyn Stream>;
+
+/// Where the server's clients come from: a TCP listener by default, see
+/// `ChatServer::from_listener` for others.
+#[async_trait]
+pub trait Listener: Send + Sync {
+    /// Waits for the next client, returning its stream and address.
+    async fn accept(&self) -> io::Result<(BoxStream, SocketAddr)>;
+
+    /// Returns the address clients connect to.
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    async fn accept(&self) -> io::Result<(BoxStream, SocketAddr)> {
+        let (socket, addr) = TcpListener::accept(self).await?;
+        Ok((Box::new(socket), addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        TcpListener::local_addr(self)
+    }
+}
+
+#[async_trait]
+impl<L: Listener + ?Sized> Listener for Box<L> {
+    async fn accept(&self) -> io::Result<(BoxStream, SocketAddr)> {
+        (**self).accept().await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        (**self).local_addr()
+    }
+}
+
+/// Serves clients on turmoil's simulated network, from a simulation host.
+#[cfg(feature = "simulation")]
+#[async_trait]
+impl Listener for turmoil::net::TcpListener {
+    async fn accept(&self) -> io::Result<(BoxStream, SocketAddr)> {
+        let (socket, addr) = turmoil::net::TcpListener::accept(self).await?;
+        Ok((Box::new(socket), addr))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        turmoil::net::TcpListener::local_addr(self)
+    }
+}
@@ -0,0 +1,155 @@
+use crate::transport::{BoxStream, Listener};
+use async_trait::async_trait;
+use std::io;
+use std::net::{Shutdown, SocketAddr, ToSocketAddrs};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::sync::{Mutex, mpsc};
+use tokio_uring::buf::BoundedBuf;
+use tokio_uring::net::TcpStream;
+use tracing::{debug, info};
+
+/// Bytes buffered in each direction between a socket and its handler.
+const PIPE_CAPACITY: usize = 64 * 1024;
+/// Bytes read from or written to a socket per io_uring operation.
+const IO_BUFFER: usize = 16 * 1024;
+/// Accepted connections waiting for the server to take them.
+const ACCEPT_BACKLOG: usize = 128;
+
+/// A `Listener` whose sockets are accepted, read and written through
+/// io_uring rather than epoll, for `ChatServer::from_listener`.
+///
+/// This is a compatibility shim, not a faster transport. tokio-uring
+/// sockets are tied to the thread that made them, while the server's
+/// handlers move between runtime workers, so the sockets live on a single
+/// io_uring thread and each is handed to the server as an in-memory pipe
+/// that thread copies to and from. Every byte is copied once more than
+/// with the default TCP listener, and all sockets share one thread, so
+/// expect no throughput benefit; `benches/io.rs` measures the difference.
+pub struct UringListener {
+    accepted: Mutex<mpsc::Receiver<io::Result<(DuplexStream, SocketAddr)>>>,
+    local_addr: SocketAddr,
+}
+
+impl UringListener {
+    /// Binds to `addr` and starts the io_uring thread, which runs until
+    /// the listener is dropped and its connections have closed.
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let (sender, accepted) = mpsc::channel(ACCEPT_BACKLOG);
+        std::thread::Builder::new()
+            .name("io-uring".to_string())
+            .spawn(move || tokio_uring::start(accept_loop(listener, sender)))?;
+        info!("Chat server bound to {} with io_uring", local_addr);
+        Ok(UringListener {
+            accepted: Mutex::new(accepted),
+            local_addr,
+        })
+    }
+}
+
+#[async_trait]
+impl Listener for UringListener {
+    async fn accept(&self) -> io::Result<(BoxStream, SocketAddr)> {
+        match self.accepted.lock().await.recv().await {
+            Some(accepted) => {
+                let (pipe, addr) = accepted?;
+                Ok((Box::new(pipe), addr))
+            }
+            None => Err(io::Error::other("The io_uring thread has stopped")),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Accepts connections on the io_uring thread, passing the server its end
+/// of each one's pipe, until the `UringListener` is dropped. Then waits
+/// for the open connections to close.
+async fn accept_loop(
+    listener: std::net::TcpListener,
+    accepted: mpsc::Sender<io::Result<(DuplexStream, SocketAddr)>>,
+) {
+    let listener = tokio_uring::net::TcpListener::from_std(listener);
+    let mut connections = Vec::new();
+    loop {
+        let result = tokio::select! {
+            result = listener.accept() => result,
+            _ = accepted.closed() => break,
+        };
+        let (socket, addr) = match result {
+            Ok(connection) => connection,
+            Err(e) => {
+                if accepted.send(Err(e)).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let (pipe, handler) = tokio::io::duplex(PIPE_CAPACITY);
+        if accepted.send(Ok((handler, addr))).await.is_err() {
+            break;
+        }
+        connections.retain(|connection: &tokio::task::JoinHandle<()>| !connection.is_finished());
+        connections.push(tokio_uring::spawn(pump(socket, pipe, addr)));
+    }
+    for connection in connections {
+        let _ = connection.await;
+    }
+}
+
+/// Copies bytes between `socket` and its pipe until the client and the
+/// handler are both done with it.
+async fn pump(socket: TcpStream, pipe: DuplexStream, addr: SocketAddr) {
+    let (mut from_handler, mut to_handler) = tokio::io::split(pipe);
+    let inbound = async {
+        let result = copy_inbound(&socket, &mut to_handler).await;
+        // The handler sees the client leave as the end of its input.
+        let _ = to_handler.shutdown().await;
+        result
+    };
+    let outbound = async {
+        let result = copy_outbound(&mut from_handler, &socket).await;
+        // The handler has finished with the client; stop reading too.
+        let _ = socket.shutdown(Shutdown::Both);
+        result
+    };
+    let (inbound, outbound) = tokio::join!(inbound, outbound);
+    if let Err(e) = inbound.and(outbound) {
+        debug!("io_uring connection {} ended: {}", addr, e);
+    }
+}
+
+async fn copy_inbound(
+    socket: &TcpStream,
+    to_handler: &mut WriteHalf<DuplexStream>,
+) -> io::Result<()> {
+    let mut buffer = vec![0; IO_BUFFER];
+    loop {
+        let (read, returned) = socket.read(buffer).await;
+        buffer = returned;
+        let read = read?;
+        if read == 0 {
+            return Ok(());
+        }
+        to_handler.write_all(&buffer[..read]).await?;
+    }
+}
+
+async fn copy_outbound(
+    from_handler: &mut ReadHalf<DuplexStream>,
+    socket: &TcpStream,
+) -> io::Result<()> {
+    let mut buffer = vec![0; IO_BUFFER];
+    loop {
+        let read = from_handler.read(&mut buffer).await?;
+        if read == 0 {
+            return Ok(());
+        }
+        let (written, returned) = socket.write_all(buffer.slice(..read)).await;
+        buffer = returned.into_inner();
+        written?;
+    }
+}
@@ -0,0 +1,163 @@
+use crate::protocol::{ChatMessage, MessageId};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// Log size past which it is truncated once no entries are pending.
+const COMPACT_THRESHOLD: u64 = 4 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Entry {
+    Append { message: Box<ChatMessage> },
+    Ack { id: MessageId },
+}
+
+/// Append-only log of accepted messages. Each message is fsynced before it
+/// is broadcast and acknowledged once it reaches the message store, so a
+/// restarted server can store whatever was accepted but not yet persisted.
+pub struct Wal {
+    path: PathBuf,
+    inner: Mutex<WalFile>,
+}
+
+struct WalFile {
+    file: File,
+    len: u64,
+    pending: usize,
+    recovered: Vec<ChatMessage>,
+}
+
+impl Wal {
+    /// Opens (or creates) the log at `path`, collecting entries that were
+    /// appended but never acknowledged for `take_recovered`.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let mut unacked = BTreeMap::new();
+        match tokio::fs::read_to_string(&path).await {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    // A crash mid-write leaves at most one torn trailing line.
+                    match serde_json::from_str::<Entry>(line) {
+                        Ok(Entry::Append { message }) => {
+                            unacked.insert(message.id.unwrap_or_default(), *message);
+                        }
+                        Ok(Entry::Ack { id }) => {
+                            unacked.remove(&id);
+                        }
+                        Err(e) => warn!("Skipping unreadable WAL entry: {:?}", e),
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        let recovered: Vec<ChatMessage> = unacked.into_values().collect();
+        if !recovered.is_empty() {
+            info!(
+                "Recovered {} unacknowledged messages from WAL",
+                recovered.len()
+            );
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+        let len = file.metadata().await?.len();
+        Ok(Wal {
+            path,
+            inner: Mutex::new(WalFile {
+                file,
+                len,
+                pending: recovered.len(),
+                recovered,
+            }),
+        })
+    }
+
+    /// Returns the messages recovered at open, oldest first. They stay
+    /// pending until acknowledged.
+    pub async fn take_recovered(&self) -> Vec<ChatMessage> {
+        std::mem::take(&mut self.inner.lock().await.recovered)
+    }
+
+    /// Durably records an accepted message.
+    pub async fn append(&self, message: &ChatMessage) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        inner
+            .write(&Entry::Append {
+                message: Box::new(message.clone()),
+            })
+            .await?;
+        inner.file.sync_data().await?;
+        inner.pending += 1;
+        Ok(())
+    }
+
+    /// Marks a message as persisted, truncating the log once it has grown
+    /// large and nothing is pending.
+    pub async fn ack(&self, id: MessageId) -> Result<()> {
+        let mut inner = self.inner.lock().await;
+        inner.write(&Entry::Ack { id }).await?;
+        inner.pending = inner.pending.saturating_sub(1);
+        if inner.pending == 0 && inner.len > COMPACT_THRESHOLD {
+            inner.file.set_len(0).await?;
+            inner.file.sync_data().await?;
+            inner.len = 0;
+            info!("Compacted WAL {}", self.path.display());
+        }
+        Ok(())
+    }
+}
+
+impl WalFile {
+    async fn write(&mut self, entry: &Entry) -> Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line).await?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: MessageId) -> ChatMessage {
+        ChatMessage {
+            sender: "avery".to_string(),
+            content: format!("message {}", id),
+            id: Some(id),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn recovers_unacknowledged_messages() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("chat-wal-{}.log", std::process::id()));
+        let _ = tokio::fs::remove_file(&path).await;
+
+        let wal = Wal::open(&path).await?;
+        for id in 1..=3 {
+            wal.append(&message(id)).await?;
+        }
+        wal.ack(1).await?;
+        wal.ack(3).await?;
+        drop(wal);
+
+        let wal = Wal::open(&path).await?;
+        let recovered = wal.take_recovered().await;
+        tokio::fs::remove_file(&path).await?;
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(recovered[0].id, Some(2));
+        Ok(())
+    }
+}
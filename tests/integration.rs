@@ -1,10 +1,43 @@
 use anyhow::Result;
-use std::sync::Arc;
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Barrier;
-use tokio::time::{Duration, pause};
+use tokio::time::{Duration, pause, timeout};
 use tokio_chat_server::ChatServer;
-use tokio_chat_server::client::Client;
-use tokio_chat_server::protocol::ChatMessage;
+use tokio_chat_server::apikey::KeyScope;
+use tokio_chat_server::auth::{GuestPolicy, StaticTokens};
+use tokio_chat_server::blob::{BlobStore, MemoryBlobStore, blob_id};
+use tokio_chat_server::challenge::ChallengePolicy;
+use tokio_chat_server::client::{Client, ConnectionEvents};
+use tokio_chat_server::codec::{FrameDecoder, WireFormat};
+use tokio_chat_server::config::Config;
+use tokio_chat_server::delivery::DeadLetterReason;
+use tokio_chat_server::digest::{DigestPolicy, Mailer};
+use tokio_chat_server::hooks::ConnectionHooks;
+use tokio_chat_server::i18n::{Catalog, Catalogs};
+use tokio_chat_server::limits::ConnectionLimits;
+use tokio_chat_server::memory::Overload;
+use tokio_chat_server::onboarding::{Onboarding, OnboardingEvent, WebhookEvent};
+use tokio_chat_server::pool::{ClientPool, PoolConfig};
+use tokio_chat_server::preview::{HttpFetcher, PreviewPolicy};
+use tokio_chat_server::protocol::{
+    ActivityLevel, AnnouncementLevel, Capability, ChatMessage, ClientFrame, DigestFrequency,
+    EmailDigest, Entity, EntityKind, FileRef, NotificationPrefs, PresenceState, PushPlatform,
+    PushToken, QuietHours, ServerFrame, TextFormat,
+};
+use tokio_chat_server::push::{Delivery, PushGateway, PushNotification, PushProvider};
+use tokio_chat_server::quota::{QuotaPolicy, QuotaWindow, Resource, ResourcePolicy};
+use tokio_chat_server::recording::{self, Direction, PlaybackOptions};
+use tokio_chat_server::room::{DeliveryMode, Role, RoomConfig, RoomPermissions};
+use tokio_chat_server::router::{Route, Router};
+use tokio_chat_server::runtime::{RuntimeConfig, RuntimeFlavor, run_server_with};
+use tokio_chat_server::sanitize::{SanitizePolicy, Strictness};
 use tracing::info;
 
 #[tokio::test]
@@ -33,17 +66,6498 @@ async fn test_chat_server() -> Result<()> {
     let message = ChatMessage {
         sender: "avery".to_string(),
         content: "Hello from client1".to_string(),
+        ..Default::default()
     };
     client1.send(message).await?;
     tokio::time::advance(Duration::from_millis(20)).await; // Time for send
     tokio::time::advance(Duration::from_millis(30)).await; // Time for process/broadcast
 
-    let received = client2.receive().await?;
+    let ServerFrame::Message { from, message } = client2.receive().await? else {
+        panic!("expected a relayed message");
+    };
+    assert!(
+        from.starts_with("127.0.0.1:")
+            && message.sender == "avery"
+            && message.content == "Hello from client1"
+    );
+
+    Ok(())
+}
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+#[tokio::test]
+async fn test_uring_listener_relays_messages() -> Result<()> {
+    use tokio_chat_server::uring::UringListener;
+
+    let server = ChatServer::from_listener(UringListener::bind("127.0.0.1:0")?);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    let mut blake = Client::connect(&addr).await?;
+    // Connections reach the server through the io_uring thread; wait until
+    // both are being served.
+    for client in [&mut avery, &mut blake] {
+        client.fetch_history(None, None, Some(1)).await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::History { .. }
+        ));
+    }
+    avery
+        .send(ChatMessage::from_raw("avery: over io_uring")?)
+        .await?;
+    for client in [&mut avery, &mut blake] {
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Message { message, .. } if message.content == "over io_uring"
+        ));
+    }
+    blake
+        .send(ChatMessage::from_raw("blake: and back")?)
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Message { message, .. } if message.content == "and back"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_profile_updates_are_broadcast() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client1 = Client::connect(&addr).await?;
+    let mut client2 = Client::connect(&addr).await?;
+
+    client1
+        .set_profile(
+            Some("Avery".to_string()),
+            Some("lunch".to_string()),
+            Some(PresenceState::Away),
+        )
+        .await?;
+
+    let frame = client2.receive().await?;
+    let ServerFrame::PresenceChanged(presence) = frame else {
+        panic!("expected PresenceChanged, got {:?}", frame);
+    };
+    assert_eq!(presence.profile.display_name.as_deref(), Some("Avery"));
+    assert_eq!(presence.profile.status_text.as_deref(), Some("lunch"));
+    assert_eq!(presence.profile.state, PresenceState::Away);
+
+    client2.send_frame(&ClientFrame::Presence).await?;
+    let frame = client2.receive().await?;
+    let ServerFrame::Presence { users } = frame else {
+        panic!("expected Presence, got {:?}", frame);
+    };
+    assert_eq!(users.len(), 2);
+    assert!(
+        users
+            .iter()
+            .any(|user| user.profile.state == PresenceState::Away)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_file_upload_and_fetch() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let (addr, memory) = (server.local_addr()?.to_string(), server.memory());
+    tokio::spawn(server.run());
+
+    let mut uploader = Client::connect(&addr).await?;
+    let mut peer = Client::connect(&addr).await?;
+
+    // Seven chunks: more than the download window.
+    let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    let hash = blob_id(&contents);
+    let offer = ClientFrame::FileOffer {
+        name: "notes.bin".to_string(),
+        size: contents.len() as u64,
+        hash: hash.clone(),
+        room: None,
+    };
+    uploader.send_frame(&offer).await?;
+    assert!(matches!(
+        uploader.receive().await?,
+        ServerFrame::Error { message } if message == "Sign in before sharing files"
+    ));
+    uploader
+        .send(ChatMessage::from_raw("avery: /register avery hunter2")?)
+        .await?;
+    assert!(matches!(
+        uploader.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    uploader.send_frame(&offer).await?;
+    let ServerFrame::FileAccepted {
+        transfer_id,
+        chunk_size,
+        ..
+    } = uploader.receive().await?
+    else {
+        panic!("expected FileAccepted");
+    };
+
+    for chunk in contents.chunks(chunk_size) {
+        uploader
+            .send_frame(&ClientFrame::FileChunk {
+                transfer_id,
+                data: BASE64.encode(chunk),
+            })
+            .await?;
+        let ServerFrame::FileProgress { received, .. } = uploader.receive().await? else {
+            panic!("expected FileProgress");
+        };
+        // What's been received so far is charged to the memory budget.
+        if received < contents.len() as u64 {
+            assert!(memory.used() as u64 >= received);
+        }
+    }
+
+    let ServerFrame::FileShared { file, .. } = peer.receive().await? else {
+        panic!("expected FileShared");
+    };
+    assert_eq!(file.id, hash);
+    assert_eq!(file.name, "notes.bin");
+
+    peer.send_frame(&ClientFrame::FetchFile {
+        id: file.id.clone(),
+    })
+    .await?;
+    let mut fetched = Vec::new();
+    for _ in 0..4 {
+        let ServerFrame::FileChunk { data, .. } = peer.receive().await? else {
+            panic!("expected FileChunk");
+        };
+        fetched.extend(BASE64.decode(data)?);
+    }
+    // The window is full until the client acknowledges what it has.
+    assert!(
+        timeout(Duration::from_millis(200), peer.receive())
+            .await
+            .is_err()
+    );
+    loop {
+        peer.send_frame(&ClientFrame::FileReceived {
+            id: file.id.clone(),
+            received: fetched.len() as u64,
+        })
+        .await?;
+        match peer.receive().await? {
+            ServerFrame::FileChunk { offset, data, .. } => {
+                assert_eq!(offset, fetched.len() as u64);
+                fetched.extend(BASE64.decode(data)?);
+            }
+            ServerFrame::FileEnd { size, .. } => {
+                assert_eq!(size, contents.len() as u64);
+                break;
+            }
+            frame => panic!("unexpected frame {:?}", frame),
+        }
+    }
+    assert_eq!(fetched, contents);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_uploads_are_shared_with_room_readers() -> Result<()> {
+    let staff = RoomConfig {
+        owner: Some("avery".to_string()),
+        permissions: RoomPermissions {
+            read: Role::Members,
+            post: Role::Members,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b"),
+        ))
+        .with_room("staff", staff);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a").await?;
+    avery.receive().await?;
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    blake.receive().await?;
+
+    let offer = ClientFrame::FileOffer {
+        name: "empty.txt".to_string(),
+        size: 0,
+        hash: blob_id(&[]),
+        room: Some("staff".to_string()),
+    };
+    blake.send_frame(&offer).await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Error { message } if message == "Only members can share files in staff"
+    ));
+
+    // An empty upload completes as soon as it's accepted.
+    avery.send_frame(&offer).await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::FileAccepted { .. }
+    ));
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::FileProgress { .. }
+    ));
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::FileShared { room, .. } if room == "staff"
+    ));
+    avery.send(ChatMessage::from_raw("avery: shared")?).await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Message { message, .. } if message.content == "shared"
+    ));
+
+    // Only readers of a room the file was shared in can fetch it.
+    let fetch = ClientFrame::FetchFile { id: blob_id(&[]) };
+    blake.send_frame(&fetch).await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Error { message } if message.starts_with("Unknown file")
+    ));
+    avery.receive().await?;
+    avery.send_frame(&fetch).await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::FileEnd { size: 0, .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_attachment_references_stored_blob() -> Result<()> {
+    let blobs = Arc::new(MemoryBlobStore::new());
+    let contents = Bytes::from_static(b"quarterly report");
+    let id = blob_id(&contents);
+    blobs.put(&id, contents.clone()).await?;
+    let board = RoomConfig {
+        permissions: RoomPermissions {
+            post: Role::Members,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_blob_store(blobs)
+        .with_room("board", board);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut sender = Client::connect(&addr).await?;
+    let mut peer = Client::connect(&addr).await?;
+
+    let missing = FileRef {
+        id: "0".repeat(64),
+        name: "missing.txt".to_string(),
+        size: 1,
+    };
+    sender
+        .send(
+            ChatMessage::builder()
+                .sender("avery")
+                .attachment(missing)
+                .build()?,
+        )
+        .await?;
+    let frame = sender.receive().await?;
+    assert!(matches!(frame, ServerFrame::Error { .. }));
+
+    // Stored but not yet shared anywhere, so not fetchable.
+    let fetch = ClientFrame::FetchFile { id: id.clone() };
+    peer.send_frame(&fetch).await?;
+    assert!(matches!(
+        peer.receive().await?,
+        ServerFrame::Error { message } if message.starts_with("Unknown file")
+    ));
+
+    let file = FileRef {
+        id,
+        name: "report.txt".to_string(),
+        size: contents.len() as u64,
+    };
+    sender
+        .send(
+            ChatMessage::builder()
+                .sender("avery")
+                .room("reports")
+                .content("Q3")
+                .attachment(file.clone())
+                .build()?,
+        )
+        .await?;
+    let ServerFrame::Message { message, .. } = peer.receive().await? else {
+        panic!("expected the message");
+    };
+    assert_eq!(message.attachment, Some(file));
+    assert_eq!(message.content, "Q3");
+    assert_eq!(message.room(), "reports");
+    assert!(message.seq.is_some());
+    peer.send_frame(&fetch).await?;
+    let ServerFrame::FileChunk { data, .. } = peer.receive().await? else {
+        panic!("expected the attached file");
+    };
+    assert_eq!(BASE64.decode(data)?, contents);
+    assert!(matches!(peer.receive().await?, ServerFrame::FileEnd { .. }));
+
+    // Attachments are posted like any other message.
+    sender
+        .send(
+            ChatMessage::builder()
+                .sender("avery")
+                .room("board")
+                .attachment(message.attachment.unwrap())
+                .build()?,
+        )
+        .await?;
+    assert!(matches!(
+        sender.receive().await?,
+        ServerFrame::Message { message, .. } if message.room() == "reports"
+    ));
+    assert!(matches!(
+        sender.receive().await?,
+        ServerFrame::Error { message } if message == "Only members can post in board"
+    ));
+
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn test_http_blob_upload_and_fetch() -> Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_chat_server::apikey::ApiKeys;
+    use tokio_chat_server::http::UploadResponse;
+
+    async fn request(addr: std::net::SocketAddr, head: &str, body: &[u8]) -> Result<String> {
+        let mut stream = tokio::net::TcpStream::connect(addr).await?;
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    let blobs: Arc<dyn BlobStore> = Arc::new(MemoryBlobStore::new());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let api_keys = Arc::new(ApiKeys::new());
+    let writer = api_keys.issue("uploader", KeyScope::default(), 0);
+    let reader = api_keys.issue(
+        "watcher",
+        KeyScope {
+            read_only: true,
+            ..Default::default()
+        },
+        0,
+    );
+    tokio::spawn(tokio_chat_server::http::serve(listener, blobs, api_keys));
+
+    let body = b"hello over http";
+    let upload_head = |auth: &str| {
+        format!(
+            "POST /blobs?name=hello.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n",
+            auth,
+            body.len()
+        )
+    };
+    let response = request(addr, &upload_head(""), body).await?;
+    assert!(response.starts_with("HTTP/1.1 401"));
+    let auth = format!("Authorization: Bearer {}\r\n", reader.key);
+    let response = request(addr, &upload_head(&auth), body).await?;
+    assert!(response.starts_with("HTTP/1.1 403"));
+    let auth = format!("Authorization: Bearer {}\r\n", writer.key);
+    let response = request(addr, &upload_head(&auth), body).await?;
+    assert!(response.starts_with("HTTP/1.1 200"));
+    let (_, json) = response.split_once("\r\n\r\n").unwrap();
+    let upload: UploadResponse = serde_json::from_str(json)?;
+    assert_eq!(upload.file.name, "hello.txt");
+    assert_eq!(upload.file.size, body.len() as u64);
+
+    let head = format!(
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        upload.url
+    );
+    let response = request(addr, &head, &[]).await?;
+    assert!(response.ends_with("hello over http"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ephemeral_message_expires() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?.with_room(
+        "huddle",
+        RoomConfig {
+            allow_ephemeral: true,
+            ..Default::default()
+        },
+    );
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut sender = Client::connect(&addr).await?;
+
+    sender
+        .send(ChatMessage {
+            sender: "avery".to_string(),
+            content: "not here".to_string(),
+            ttl_secs: Some(1),
+            ..Default::default()
+        })
+        .await?;
+    let frame = sender.receive().await?;
+    assert!(matches!(frame, ServerFrame::Error { .. }));
+
+    sender
+        .send(ChatMessage {
+            sender: "avery".to_string(),
+            content: "gone soon".to_string(),
+            room: Some("huddle".to_string()),
+            ttl_secs: Some(1),
+            ..Default::default()
+        })
+        .await?;
+    let ServerFrame::Message { message, .. } = sender.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    let id = message.id.expect("server assigns an id");
+
+    let frame = sender.receive().await?;
+    let ServerFrame::Expire { message_id } = frame else {
+        panic!("expected Expire, got {:?}", frame);
+    };
+    assert_eq!(message_id, id);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_ephemeral_messages_expire_after_a_restart() -> Result<()> {
+    use tokio_chat_server::store::{MemoryStore, MessageStore};
+
+    let store = Arc::new(MemoryStore::default());
+    let huddle = RoomConfig {
+        allow_ephemeral: true,
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone())
+        .with_room("huddle", huddle.clone());
+    let (addr, admin) = (server.local_addr()?.to_string(), server.admin());
+    let running = tokio::spawn(server.run());
+    let mut sender = Client::connect(&addr).await?;
+    sender
+        .send(
+            ChatMessage::builder()
+                .sender("avery")
+                .content("gone soon")
+                .room("huddle")
+                .ttl_secs(2)
+                .build()?,
+        )
+        .await?;
+    let ServerFrame::Message { message, .. } = sender.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    admin.drain()?;
+    timeout(Duration::from_secs(10), running).await???;
+    assert_eq!(store.range("huddle", None, None).await?.len(), 1);
+
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone())
+        .with_room("huddle", huddle);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    let frame = timeout(Duration::from_secs(5), client.receive()).await??;
+    assert!(matches!(
+        frame,
+        ServerFrame::Expire { message_id } if Some(message_id) == message.id
+    ));
+    assert!(store.range("huddle", None, None).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_snapshot_restores_rooms_bans_and_read_state() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("chat-restart-{}.json", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut ops = RoomConfig {
+        owner: Some("avery".to_string()),
+        delivery: DeliveryMode::AtLeastOnce,
+        permissions: RoomPermissions {
+            invite: Role::Everyone,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    ops.members.insert("blake".to_string());
+    let start = |path: std::path::PathBuf, ops: RoomConfig| async move {
+        let server = ChatServer::new("127.0.0.1:0")
+            .await?
+            .with_authenticator(Arc::new(
+                StaticTokens::new()
+                    .with_user("avery", "a")
+                    .with_user("blake", "b"),
+            ))
+            .with_room("ops", ops)
+            // Long enough that only the snapshot taken on drain is written.
+            .with_snapshots(path, Duration::from_secs(3600));
+        let (addr, admin) = (server.local_addr()?.to_string(), server.admin());
+        anyhow::Ok((addr, admin, tokio::spawn(server.run())))
+    };
+
+    let (addr, admin, running) = start(path.clone(), ops.clone()).await?;
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a").await?;
+    avery.receive().await?;
+    avery
+        .send(ChatMessage::from_raw("avery: /topic ops incident review")?)
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::RoomUpdated { .. }
+    ));
+    for content in ["first", "second"] {
+        let message = ChatMessage::builder()
+            .sender("avery")
+            .content(content)
+            .room("ops")
+            .build()?;
+        avery.send(message).await?;
+        avery.receive().await?;
+    }
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    blake.receive().await?;
+    let ServerFrame::Unacked { messages } = blake.receive().await? else {
+        panic!("expected Unacked");
+    };
+    assert_eq!(messages.len(), 2);
+    blake.ack("ops", messages[0].seq.unwrap()).await?;
+    blake.send_frame(&ClientFrame::Presence).await?;
+    while !matches!(blake.receive().await?, ServerFrame::Presence { .. }) {}
+    admin.ban("casey", None);
+    admin.drain()?;
+    timeout(Duration::from_secs(10), running).await???;
+
+    let (addr, admin, _running) = start(path.clone(), ops).await?;
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    blake.receive().await?;
+    let ServerFrame::Unacked { messages } = blake.receive().await? else {
+        panic!("expected Unacked");
+    };
+    let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["second"]);
+    assert_eq!(admin.bans(), vec!["casey".to_string()]);
+    blake
+        .send_frame(&ClientFrame::ListRooms {
+            filter: Some("ops".to_string()),
+            page: 0,
+        })
+        .await?;
+    let ServerFrame::Rooms { rooms, .. } = blake.receive_frame().await? else {
+        panic!("expected Rooms");
+    };
+    assert_eq!(rooms[0].topic.as_deref(), Some("incident review"));
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scheduled_message_delivery_and_cancel() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut sender = Client::connect(&addr).await?;
+    sender
+        .send(ChatMessage::from_raw("avery: /register avery hunter2")?)
+        .await?;
+    assert!(matches!(
+        sender.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+
+    sender
+        .send(ChatMessage {
+            sender: "avery".to_string(),
+            content: "much later".to_string(),
+            send_at: Some(now + 3600),
+            ..Default::default()
+        })
+        .await?;
+    let ServerFrame::Scheduled { pending_id, .. } = sender.receive().await? else {
+        panic!("expected Scheduled");
+    };
+    // Only the signed-in sender may cancel it.
+    let mut other = Client::connect(&addr).await?;
+    other
+        .send(ChatMessage::from_raw("blake: /register blake hunter2")?)
+        .await?;
+    other.receive().await?;
+    other
+        .send_frame(&ClientFrame::CancelScheduled { pending_id })
+        .await?;
+    assert!(matches!(
+        other.receive().await?,
+        ServerFrame::Error { message } if message.contains("No scheduled message")
+    ));
+    sender
+        .send_frame(&ClientFrame::CancelScheduled { pending_id })
+        .await?;
+    let frame = sender.receive().await?;
+    assert!(matches!(frame, ServerFrame::ScheduleCancelled { pending_id: id } if id == pending_id));
+
+    sender
+        .send(ChatMessage {
+            sender: "avery".to_string(),
+            content: "soon".to_string(),
+            send_at: Some(now + 2),
+            ..Default::default()
+        })
+        .await?;
+    let frame = sender.receive().await?;
+    assert!(matches!(frame, ServerFrame::Scheduled { .. }));
+    let ServerFrame::Message { message, .. } = sender.receive().await? else {
+        panic!("expected the scheduled message");
+    };
+    assert_eq!(message.content, "soon");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scheduled_messages_are_dropped_once_the_sender_may_not_post() -> Result<()> {
+    use tokio_chat_server::store::{MemoryStore, MessageStore};
+
+    let store = Arc::new(MemoryStore::default());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone());
+    let (addr, admin) = (server.local_addr()?.to_string(), server.admin());
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    avery
+        .send(ChatMessage::from_raw("avery: /register avery hunter2")?)
+        .await?;
+    avery.receive().await?;
+    for command in ["/create plans", "/invite blake plans"] {
+        avery
+            .send(ChatMessage::from_raw(&format!("avery: {}", command))?)
+            .await?;
+        assert!(matches!(
+            avery.receive().await?,
+            ServerFrame::RoomUpdated { .. }
+        ));
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let mut senders = Vec::new();
+    for (user, room) in [("blake", "plans"), ("casey", "general")] {
+        let mut sender = Client::connect(&addr).await?;
+        sender
+            .send(ChatMessage::from_raw(&format!(
+                "{user}: /register {user} hunter2"
+            ))?)
+            .await?;
+        sender.receive().await?;
+        sender
+            .send(ChatMessage {
+                sender: user.to_string(),
+                content: "too late".to_string(),
+                room: Some(room.to_string()),
+                send_at: Some(now + 2),
+                ..Default::default()
+            })
+            .await?;
+        assert!(matches!(
+            sender.receive().await?,
+            ServerFrame::Scheduled { .. }
+        ));
+        senders.push(sender);
+    }
+    avery
+        .send(ChatMessage::from_raw("avery: /announce plans on")?)
+        .await?;
+    admin.ban("casey", None);
+
+    timeout(Duration::from_secs(10), async {
+        while !store.scheduled().await.unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await?;
+    while let Ok(frame) = timeout(Duration::from_millis(500), avery.receive()).await {
+        assert!(!matches!(frame?, ServerFrame::Message { .. }));
+    }
+    assert!(store.range("plans", None, None).await?.is_empty());
+    assert!(store.range("general", None, None).await?.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_scheduled_messages_survive_a_restart() -> Result<()> {
+    use tokio_chat_server::store::{MemoryStore, MessageStore};
+
+    let store = Arc::new(MemoryStore::default());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone());
+    let (addr, admin) = (server.local_addr()?.to_string(), server.admin());
+    let running = tokio::spawn(server.run());
+    let mut sender = Client::connect(&addr).await?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    for (content, send_at) in [("soon", now + 3), ("much later", now + 3600)] {
+        sender
+            .send(ChatMessage {
+                sender: "avery".to_string(),
+                content: content.to_string(),
+                send_at: Some(send_at),
+                ..Default::default()
+            })
+            .await?;
+        assert!(matches!(
+            sender.receive().await?,
+            ServerFrame::Scheduled { .. }
+        ));
+    }
+    admin.drain()?;
+    timeout(Duration::from_secs(10), running).await???;
+    assert_eq!(store.scheduled().await?.len(), 2);
+
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone());
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let mut reader = Client::connect(&addr).await?;
+    let ServerFrame::Message { message, .. } =
+        timeout(Duration::from_secs(10), reader.receive()).await??
+    else {
+        panic!("expected the scheduled message");
+    };
+    assert_eq!(message.content, "soon");
+    // Delivered messages are forgotten; the other is still held.
+    let held = timeout(Duration::from_secs(5), async {
+        loop {
+            let held = store.scheduled().await?;
+            if held.len() == 1 {
+                return anyhow::Ok(held);
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await??;
+    assert_eq!(held[0].message.content, "much later");
+
+    // New messages don't reuse a held message's pending id.
+    reader
+        .send(ChatMessage {
+            sender: "avery".to_string(),
+            content: "later still".to_string(),
+            send_at: Some(now + 7200),
+            ..Default::default()
+        })
+        .await?;
+    let ServerFrame::Scheduled { pending_id, .. } = reader.receive().await? else {
+        panic!("expected Scheduled");
+    };
+    assert!(pending_id > held[0].pending_id);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_reconnect_replays_missed_messages() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut reader = Client::connect(&addr).await?;
+    let mut writer = Client::connect(&addr).await?;
+    let say = |content: &str| ChatMessage {
+        sender: "avery".to_string(),
+        content: content.to_string(),
+        ..Default::default()
+    };
+
+    writer.send(say("first")).await?;
+    reader.receive().await?;
+    let seen = reader.last_message_id().expect("message id tracked");
+
+    // Drop the reader's connection while more messages are sent.
+    reader.reconnect().await?;
+    let ServerFrame::Replay { messages, complete } = reader.receive_frame().await? else {
+        panic!("expected Replay");
+    };
+    assert!(messages.is_empty() && complete);
+
+    writer.send(say("second")).await?;
+    writer.send(say("third")).await?;
+    while !matches!(
+        writer.receive().await?,
+        ServerFrame::Message { message, .. } if message.content == "third"
+    ) {}
+    reader.resume_from(seen).await?;
+    let replayed = loop {
+        if let ServerFrame::Replay { messages, .. } = reader.receive_frame().await? {
+            break messages;
+        }
+    };
+    let contents: Vec<_> = replayed.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["second", "third"]);
+    assert_eq!(reader.last_message_id(), replayed.last().unwrap().id);
+    assert_eq!(reader.cached_messages().count(), 3);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_history_pages_back() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    for i in 1..=5 {
+        client
+            .send(ChatMessage::from_raw(&format!("avery: note {}", i))?)
+            .await?;
+        client.receive().await?;
+    }
+
+    let mut before = None;
+    let mut pages = Vec::new();
+    loop {
+        client
+            .send_frame(&ClientFrame::FetchHistory {
+                room: None,
+                before,
+                limit: Some(2),
+            })
+            .await?;
+        let ServerFrame::History {
+            room,
+            messages,
+            has_more,
+        } = client.receive_frame().await?
+        else {
+            panic!("expected History");
+        };
+        assert_eq!(room, "general");
+        let contents: Vec<_> = messages.iter().map(|m| m.content.clone()).collect();
+        pages.push((contents, has_more));
+        if !has_more {
+            break;
+        }
+        before = messages.first().and_then(|message| message.id);
+    }
+    assert_eq!(
+        pages,
+        vec![
+            (vec!["note 4".to_string(), "note 5".to_string()], true),
+            (vec!["note 2".to_string(), "note 3".to_string()], true),
+            (vec!["note 1".to_string()], false),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search_matches_and_pages() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let long = format!("{} the deploy went out {}", "x".repeat(60), "y".repeat(60));
+    for (room, content) in [
+        ("general", "Deploy starts at noon"),
+        ("ops", "deploy is blocked"),
+        ("general", "lunch?"),
+        ("general", long.as_str()),
+    ] {
+        client
+            .send(
+                ChatMessage::builder()
+                    .sender("avery")
+                    .content(content)
+                    .room(room)
+                    .build()?,
+            )
+            .await?;
+        client.receive().await?;
+    }
+
+    let search = |room: Option<&str>, before| ClientFrame::Search {
+        query: "deploy".to_string(),
+        room: room.map(str::to_string),
+        limit: Some(2),
+        before,
+    };
+    client.send_frame(&search(None, None)).await?;
+    let ServerFrame::SearchResults { hits, next_cursor } = client.receive_frame().await? else {
+        panic!("expected SearchResults");
+    };
+    let rooms: Vec<_> = hits.iter().map(|hit| hit.message.room()).collect();
+    assert_eq!(rooms, vec!["general", "ops"]);
+    assert!(hits[0].snippet.starts_with('…') && hits[0].snippet.ends_with('…'));
+    assert!(hits[0].snippet.contains("the deploy went out"));
+    assert_eq!(hits[1].snippet, "deploy is blocked");
+    assert_eq!(next_cursor, hits[1].message.id);
+
+    client.send_frame(&search(None, next_cursor)).await?;
+    let ServerFrame::SearchResults { hits, next_cursor } = client.receive_frame().await? else {
+        panic!("expected SearchResults");
+    };
+    let contents: Vec<_> = hits
+        .iter()
+        .map(|hit| hit.message.content.as_str())
+        .collect();
+    assert_eq!(contents, vec!["Deploy starts at noon"]);
+    assert_eq!(next_cursor, None);
+
+    client.send_frame(&search(Some("ops"), None)).await?;
+    let ServerFrame::SearchResults { hits, next_cursor } = client.receive_frame().await? else {
+        panic!("expected SearchResults");
+    };
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].message.room(), "ops");
+    assert_eq!(next_cursor, None);
+    Ok(())
+}
+
+/// Runs a chat server on the simulation host "server", port 8080, keeping
+/// its history in `store` so it survives the host being bounced.
+#[cfg(feature = "simulation")]
+fn simulated_server(
+    sim: &mut turmoil::Sim,
+    store: Arc<dyn tokio_chat_server::store::MessageStore>,
+) {
+    sim.host("server", move || {
+        let store = store.clone();
+        async move {
+            let listener = turmoil::net::TcpListener::bind(("0.0.0.0", 8080)).await?;
+            ChatServer::from_listener(listener)
+                .with_message_store(store)
+                .run()
+                .await?;
+            Ok(())
+        }
+    });
+}
+
+/// Connects to the simulated server and waits until it's being served.
+#[cfg(feature = "simulation")]
+async fn simulated_client() -> Result<Client> {
+    let mut client = Client::simulated("server:8080").connect().await?;
+    client.fetch_history(None, None, Some(1)).await?;
+    while !matches!(client.receive().await?, ServerFrame::History { .. }) {}
+    Ok(client)
+}
+
+#[cfg(feature = "simulation")]
+#[test]
+fn test_simulated_partition_is_recovered_by_reconnecting() -> turmoil::Result {
+    use tokio_chat_server::store::MemoryStore;
+
+    let mut sim = turmoil::Builder::new().build();
+    simulated_server(&mut sim, Arc::new(MemoryStore::default()));
+    let barrier = Arc::new(Barrier::new(2));
+
+    let steps = barrier.clone();
+    sim.client("avery", async move {
+        let mut avery = simulated_client().await?;
+        steps.wait().await;
+        for content in ["before", "missed"] {
+            avery
+                .send(ChatMessage::from_raw(&format!("avery: {}", content))?)
+                .await?;
+            while !matches!(
+                avery.receive().await?,
+                ServerFrame::Message { message, .. } if message.content == content
+            ) {}
+            // Blake is partitioned away before the second message.
+            steps.wait().await;
+        }
+        Ok(())
+    });
+
+    sim.client("blake", async move {
+        let mut blake = simulated_client().await?;
+        barrier.wait().await;
+        assert!(matches!(
+            blake.receive().await?,
+            ServerFrame::Message { message, .. } if message.content == "before"
+        ));
+        turmoil::partition("blake", "server");
+        barrier.wait().await;
+        barrier.wait().await;
+        turmoil::repair("blake", "server");
+
+        // What was sent during the partition was lost with the segments
+        // carrying it.
+        assert!(
+            timeout(Duration::from_secs(1), blake.receive())
+                .await
+                .is_err()
+        );
+        blake.reconnect().await?;
+        let replayed = loop {
+            if let ServerFrame::Replay { messages, .. } = blake.receive_frame().await? {
+                break messages;
+            }
+        };
+        let contents: Vec<_> = replayed.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["missed"]);
+        Ok(())
+    });
+
+    sim.run()
+}
+
+#[cfg(feature = "simulation")]
+#[test]
+fn test_simulated_server_crash_keeps_stored_history() -> turmoil::Result {
+    use tokio_chat_server::store::MemoryStore;
+
+    let mut sim = turmoil::Builder::new().build();
+    simulated_server(&mut sim, Arc::new(MemoryStore::default()));
+
+    sim.client("avery", async {
+        let mut avery = simulated_client().await?;
+        avery
+            .send(ChatMessage::from_raw("avery: before the crash")?)
+            .await?;
+        while !matches!(avery.receive().await?, ServerFrame::Message { .. }) {}
+        Ok(())
+    });
+    sim.run()?;
+
+    sim.crash("server");
+    sim.client("blake", async {
+        let connect = Client::simulated("server:8080")
+            .connect_timeout(Duration::from_secs(1))
+            .connect();
+        assert!(connect.await.is_err());
+        Ok(())
+    });
+    sim.run()?;
+
+    sim.bounce("server");
+    sim.client("casey", async {
+        let mut casey = simulated_client().await?;
+        casey.fetch_history(None, None, None).await?;
+        let ServerFrame::History { messages, .. } = casey.receive_frame().await? else {
+            panic!("expected History");
+        };
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "before the crash");
+        casey
+            .send(ChatMessage::from_raw("casey: after the restart")?)
+            .await?;
+        let ServerFrame::Message { message, .. } = casey.receive().await? else {
+            panic!("expected the message back");
+        };
+        assert!(message.id > messages[0].id);
+        Ok(())
+    });
+    sim.run()
+}
+
+#[cfg(feature = "simulation")]
+#[test]
+fn test_simulated_latency_doesnt_change_the_order_clients_see() -> turmoil::Result {
+    use tokio_chat_server::store::MemoryStore;
+
+    const EACH: usize = 20;
+    let mut sim = turmoil::Builder::new()
+        .min_message_latency(Duration::from_millis(1))
+        .max_message_latency(Duration::from_millis(50))
+        .enable_random_order()
+        .rng_seed(405)
+        .build();
+    simulated_server(&mut sim, Arc::new(MemoryStore::default()));
+    let barrier = Arc::new(Barrier::new(2));
+    let seen = Arc::new(Mutex::new(Vec::new()));
+
+    for name in ["avery", "blake"] {
+        let (barrier, seen) = (barrier.clone(), seen.clone());
+        sim.client(name, async move {
+            let mut client = simulated_client().await?;
+            barrier.wait().await;
+            for i in 0..EACH {
+                client
+                    .send(ChatMessage::from_raw(&format!("{}: {}", name, i))?)
+                    .await?;
+            }
+            let mut order = Vec::new();
+            while order.len() < 2 * EACH {
+                if let ServerFrame::Message { message, .. } = client.receive().await? {
+                    order.push((message.seq, message.sender, message.content));
+                }
+            }
+            seen.lock().unwrap().push(order);
+            Ok(())
+        });
+    }
+    sim.run()?;
+
+    let seen = seen.lock().unwrap();
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[0], seen[1]);
+    assert!(seen[0].windows(2).all(|pair| pair[0].0 < pair[1].0));
+    for name in ["avery", "blake"] {
+        let sent: Vec<_> = seen[0]
+            .iter()
+            .filter(|(_, sender, _)| sender == name)
+            .map(|(_, _, content)| content.clone())
+            .collect();
+        let expected: Vec<_> = (0..EACH).map(|i| i.to_string()).collect();
+        assert_eq!(sent, expected);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_event_callbacks() -> Result<()> {
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<&'static str>>);
+    impl ConnectionEvents for Recorder {
+        fn on_connected(&self, _addr: &str) {
+            self.0.lock().unwrap().push("connected");
+        }
+        fn on_disconnected(&self, _addr: &str) {
+            self.0.lock().unwrap().push("disconnected");
+        }
+        fn on_reconnecting(&self, _addr: &str) {
+            self.0.lock().unwrap().push("reconnecting");
+        }
+    }
+
+    // A server that hangs up on every connection straight away.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?.to_string();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            drop(socket);
+        }
+    });
+
+    let recorder = Arc::new(Recorder::default());
+    let mut client = Client::connect_with_events(&addr, recorder.clone()).await?;
+    assert!(client.receive().await.is_err());
+    client.reconnect().await?;
+
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        vec!["connected", "disconnected", "reconnecting", "connected"]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_blocking_client() -> Result<()> {
+    let server_runtime = tokio::runtime::Runtime::new()?;
+    let addr = server_runtime.block_on(async {
+        let server = ChatServer::new("127.0.0.1:0").await?;
+        let addr = server.local_addr()?.to_string();
+        tokio::spawn(server.run());
+        anyhow::Ok(addr)
+    })?;
+
+    let mut client = tokio_chat_server::blocking::Client::connect(&addr)?;
+    client.send(ChatMessage::from_raw("avery: from sync code")?)?;
+    let ServerFrame::Message { message, .. } = client.receive()? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.content, "from sync code");
+
+    client.send_frame(&ClientFrame::Presence)?;
+    assert!(matches!(
+        client.receive_frame()?,
+        ServerFrame::Presence { .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_memory_limit_sheds_slow_connections() -> Result<()> {
+    let limit = 256 * 1024;
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_memory_limit(limit, Overload::Shed);
+    let addr = server.local_addr()?;
+    let (metrics, memory) = (server.metrics(), server.memory());
+    tokio::spawn(server.run());
+
+    // Never reads, so once the socket buffers fill its queue only grows.
+    let slow = tokio::net::TcpSocket::new_v4()?;
+    slow.set_recv_buffer_size(4096)?;
+    let _slow = slow.connect(addr).await?;
+
+    let (mut sender_rx, mut sender_tx) = tokio::net::TcpStream::connect(addr).await?.into_split();
+    let drain = tokio::spawn(async move {
+        let mut buffer = vec![0; 64 * 1024];
+        while sender_rx.read(&mut buffer).await? > 0 {}
+        anyhow::Ok(())
+    });
+    let line = format!("avery: {}\n", "x".repeat(4000));
+    for _ in 0..3000 {
+        sender_tx.write_all(line.as_bytes()).await?;
+        if metrics.snapshot().shed_connections > 0 {
+            break;
+        }
+    }
+    for _ in 0..100 {
+        if metrics.snapshot().shed_connections > 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(metrics.snapshot().shed_connections, 1);
+    assert!(!drain.is_finished(), "the reading client stays connected");
+    assert!(memory.used() <= limit);
+    Ok(())
+}
+
+#[test]
+fn test_server_runs_on_current_thread_runtime() -> Result<()> {
+    let config = RuntimeConfig {
+        flavor: RuntimeFlavor::CurrentThread,
+        thread_name: "chat-embedded".to_string(),
+        ..Default::default()
+    };
+    let (addr_tx, addr_rx) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        run_server_with(&config, async move {
+            let server = ChatServer::new("127.0.0.1:0").await?;
+            addr_tx.send(server.local_addr()?.to_string())?;
+            server.run().await
+        })
+    });
+
+    let mut client = tokio_chat_server::blocking::Client::connect(&addr_rx.recv()?)?;
+    client.send(ChatMessage::from_raw("avery: one thread is plenty")?)?;
+    let ServerFrame::Message { message, .. } = client.receive()? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.content, "one thread is plenty");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_pool_spreads_sends() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut observer = Client::connect(&addr).await?;
+    let config = PoolConfig {
+        size: 3,
+        idle_timeout: Duration::ZERO,
+    };
+    let pool = ClientPool::connect(&addr, config).await?;
+    assert_eq!(pool.size(), 3);
+
+    for i in 0..6 {
+        pool.send(ChatMessage::from_raw(&format!("bot: pooled-{}", i))?)
+            .await?;
+    }
+    let mut senders = std::collections::HashSet::new();
+    for _ in 0..6 {
+        let ServerFrame::Message { from, .. } = observer.receive().await? else {
+            panic!("expected a relayed message");
+        };
+        senders.insert(from);
+    }
+    assert_eq!(senders.len(), 3);
+    assert_eq!(pool.health_check().await, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_receive_timeout() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::builder(&addr)
+        .connect_timeout(Duration::from_secs(5))
+        .send_timeout(Duration::from_secs(5))
+        .receive_timeout(Duration::from_millis(100))
+        .keepalive(Duration::from_secs(60))
+        .nodelay(true)
+        .connect()
+        .await?;
+    let err = client.receive().await.unwrap_err();
+    assert!(err.to_string().contains("Timed out receiving"));
+
+    client
+        .send(ChatMessage::from_raw("avery: still usable")?)
+        .await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Message { message, .. } if message.content == "still usable"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_decodes_split_batched_and_invalid_lines() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut raw = tokio::net::TcpStream::connect(&addr).await?;
+    let mut decoder = FrameDecoder::new(1024 * 1024);
+    let mut next = async |raw: &mut tokio::net::TcpStream| -> Result<ServerFrame> {
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some(frame) = decoder.decode()? {
+                return Ok(frame);
+            }
+            let n = raw.read(&mut buffer).await?;
+            anyhow::ensure!(n > 0, "connection closed");
+            decoder.extend(&buffer[..n]);
+        }
+    };
+
+    raw.write_all(b"avery: sp").await?;
+    raw.flush().await?;
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    raw.write_all("lit \u{e9}\navery: one\navery: two\n".as_bytes())
+        .await?;
+    for expected in ["split \u{e9}", "one", "two"] {
+        let ServerFrame::Message { message, .. } = next(&mut raw).await? else {
+            panic!("expected a relayed message");
+        };
+        assert_eq!(message.content, expected);
+    }
+
+    raw.write_all(b"avery: \xff\xfe\navery: after\n").await?;
+    assert!(matches!(next(&mut raw).await?, ServerFrame::Error { .. }));
+    let ServerFrame::Message { message, .. } = next(&mut raw).await? else {
+        panic!("expected a relayed message");
+    };
+    assert_eq!(message.content, "after");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_quota_rejects_sends_over_limit() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_quotas(QuotaPolicy {
+            messages_per_hour: Some(1),
+            ..Default::default()
+        })
+        .with_resource_limits(ResourcePolicy {
+            max_history_bytes: Some(1024),
+            ..Default::default()
+        });
+    let addr = server.local_addr()?.to_string();
+    let (metrics, quotas, resources) = (server.metrics(), server.quotas(), server.resources());
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    // Refused outright, so not charged to the quota.
+    let too_late = ChatMessage::builder()
+        .sender("avery")
+        .content("next year")
+        .send_at(u64::MAX)
+        .build()?;
+    client.send(too_late).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Error { message } if message.contains("too far")
+    ));
+    client.send(ChatMessage::from_raw("avery: first")?).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+    client.send(ChatMessage::from_raw("avery: second")?).await?;
+    let ServerFrame::QuotaExceeded {
+        window, resets_at, ..
+    } = client.receive().await?
+    else {
+        panic!("expected QuotaExceeded");
+    };
+    assert_eq!(window, QuotaWindow::Hourly);
+    assert_eq!(resets_at % 3600, 0);
+
+    assert_eq!(metrics.snapshot().quota_rejections, 1);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    assert_eq!(quotas.usage("avery", now).messages_this_hour, 1);
+    // Only the message that was relayed counts against the room.
+    let by_room = resources.usage(now).by_room;
+    assert_eq!(by_room[0].history_bytes, "first".len() as u64);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_resource_limits() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_resource_limits(ResourcePolicy {
+            max_rooms: Some(1),
+            max_members: Some(1),
+            max_history_bytes: Some(10),
+            ..Default::default()
+        });
+    let addr = server.local_addr()?.to_string();
+    let (metrics, resources) = (server.metrics(), server.resources());
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    for command in ["/create plans", "/invite blake plans"] {
+        client
+            .send(ChatMessage::from_raw(&format!("avery: {}", command))?)
+            .await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::RoomUpdated { .. }
+        ));
+    }
+    let rejected = [
+        ("/create more", Resource::Rooms, None),
+        ("/invite casey plans", Resource::Members, Some("plans")),
+        ("hello world", Resource::HistoryBytes, Some("general")),
+    ];
+    client.send(ChatMessage::from_raw("avery: hi")?).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+    for (content, expected, expected_room) in rejected {
+        client
+            .send(ChatMessage::from_raw(&format!("avery: {}", content))?)
+            .await?;
+        let ServerFrame::ResourceExceeded { resource, room, .. } = client.receive().await? else {
+            panic!("expected ResourceExceeded for {}", content);
+        };
+        assert_eq!((resource, room.as_deref()), (expected, expected_room));
+    }
+
+    assert_eq!(metrics.snapshot().resource_rejections, 3);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let usage = resources.usage(now);
+    assert_eq!(usage.rooms, 1);
+    let plans = usage.by_room.iter().find(|room| room.room == "plans");
+    assert_eq!(plans.map(|room| room.members), Some(1));
+    assert!(usage.by_room.iter().any(|room| room.history_bytes == 2));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_work_is_measured() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    let metrics = server.metrics();
+    tokio::spawn(server.run());
+
+    let mut observer = Client::connect(&addr).await?;
+    let mut raw = tokio::net::TcpStream::connect(&addr).await?;
+    let burst: String = (0..50).map(|i| format!("avery: burst {}\n", i)).collect();
+    raw.write_all(burst.as_bytes()).await?;
+    for i in 0..50 {
+        let ServerFrame::Message { message, .. } = observer.receive().await? else {
+            panic!("expected a relayed message");
+        };
+        assert_eq!(message.content, format!("burst {}", i));
+    }
+
+    let snapshot = metrics.snapshot();
+    let stats = &snapshot.connections[&raw.local_addr()?.to_string()];
+    assert_eq!(stats.frames, 50);
+    assert!(stats.polls >= 1 && stats.polls <= 50);
+
+    // The observer never sent anything, so closing `raw` leaves nothing.
+    drop(raw);
+    for _ in 0..100 {
+        if metrics.snapshot().connections.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(metrics.snapshot().connections.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_guest_posts_then_authenticates() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "secret")))
+        .with_guests(GuestPolicy::default());
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let ServerFrame::Welcome { user: guest, .. } = client.receive().await? else {
+        panic!("expected Welcome");
+    };
+    assert!(guest.starts_with("guest-"));
+
+    client.send(ChatMessage::from_raw("avery: hi")?).await?;
+    let ServerFrame::Message { message, .. } = client.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.sender, guest);
+
+    let mut elsewhere = ChatMessage::from_raw("avery: new room")?;
+    elsewhere.room = Some("brand-new".to_string());
+    client.send(elsewhere).await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+
+    client.authenticate("avery", "wrong").await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+    client.authenticate("avery", "secret").await?;
+    assert!(
+        matches!(client.receive().await?, ServerFrame::Authenticated { user, .. } if user == "avery")
+    );
+
+    client
+        .send(ChatMessage::from_raw("whoever: signed in")?)
+        .await?;
+    let ServerFrame::Message { message, .. } = client.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.sender, "avery");
+    Ok(())
+}
+
+/// A test Ed25519 key pair: the PKCS#8 private key and the JWK `x` of its
+/// public half.
+#[cfg(feature = "oidc")]
+const OIDC_PRIVATE_KEY: &str = "MC4CAQAwBQYDK2VwBCIEIOBs491AzUHYra1QJqnZ9DWvkCHnHgJnhrzrtJ8uJvtB";
+#[cfg(feature = "oidc")]
+const OIDC_PUBLIC_KEY: &str = "d34T5_G0kPcuEKNpQyx7rm2qkod6KNs_xTSILyNB3_Y";
+
+/// Serves an issuer's discovery document and JWKS, recording the paths
+/// requested. Returns the issuer URL.
+#[cfg(feature = "oidc")]
+async fn oidc_issuer(requests: Arc<Mutex<Vec<String>>>) -> Result<String> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let issuer = format!("http://{}", listener.local_addr()?);
+    let discovery = serde_json::json!({ "jwks_uri": format!("{}/jwks", issuer) });
+    let jwks = serde_json::json!({ "keys": [{
+        "kty": "OKP", "crv": "Ed25519", "kid": "test", "alg": "EdDSA", "x": OIDC_PUBLIC_KEY,
+    }] });
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let Ok(read @ 1..) = socket.read(&mut buffer).await else {
+                    break;
+                };
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let request = String::from_utf8_lossy(&request);
+            let path = request.split(' ').nth(1).unwrap_or_default().to_string();
+            let body = match path.as_str() {
+                "/.well-known/openid-configuration" => discovery.to_string(),
+                _ => jwks.to_string(),
+            };
+            requests.lock().unwrap().push(path);
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        }
+    });
+    Ok(issuer)
+}
+
+/// Returns a token from `issuer` for `claims`, expiring in an hour.
+#[cfg(feature = "oidc")]
+fn oidc_token(issuer: &str, claims: serde_json::Value) -> Result<String> {
+    use jsonwebtoken::{Algorithm, EncodingKey, Header, encode};
+    let mut claims = claims;
+    claims["iss"] = issuer.into();
+    claims["exp"] = (jsonwebtoken::get_current_timestamp() + 3600).into();
+    let mut header = Header::new(Algorithm::EdDSA);
+    header.kid = Some("test".to_string());
+    let key = EncodingKey::from_ed_der(&BASE64.decode(OIDC_PRIVATE_KEY)?);
+    Ok(encode(&header, &claims, &key)?)
+}
+
+#[cfg(feature = "oidc")]
+#[tokio::test]
+async fn test_oidc_token_signs_in_with_claimed_nickname_and_roles() -> Result<()> {
+    use tokio_chat_server::auth::RoleMap;
+    use tokio_chat_server::oidc::{OidcAuthenticator, OidcConfig};
+
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let issuer = oidc_issuer(requests.clone()).await?;
+    let config = OidcConfig {
+        audiences: vec!["chat".to_string()],
+        roles: RoleMap::new().grant("engineering", "staff", Role::Members),
+        ..OidcConfig::new(&issuer)
+    };
+    let staff = RoomConfig {
+        permissions: RoomPermissions {
+            read: Role::Members,
+            post: Role::Members,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(OidcAuthenticator::new(config)))
+        .with_room("staff", staff);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let claims = serde_json::json!({
+        "aud": "chat",
+        "preferred_username": "avery",
+        "groups": ["engineering", "everyone"],
+    });
+    let token = oidc_token(&issuer, claims.clone())?;
+    let mut client = Client::connect(&addr).await?;
+    client.authenticate("blake", &token).await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+    let mut other_audience = claims.clone();
+    other_audience["aud"] = "billing".into();
+    client
+        .authenticate("", &oidc_token(&issuer, other_audience)?)
+        .await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+    let shared_secret = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(OIDC_PUBLIC_KEY.as_bytes()),
+    )?;
+    client.authenticate("", &shared_secret).await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+
+    client.authenticate("", &token).await?;
+    assert!(
+        matches!(client.receive().await?, ServerFrame::Authenticated { user, .. } if user == "avery")
+    );
+    let mut message = ChatMessage::from_raw("avery: hello staff")?;
+    message.room = Some("staff".to_string());
+    client.send(message).await?;
+    loop {
+        match client.receive().await? {
+            ServerFrame::Message { message, .. } => {
+                assert_eq!(message.content, "hello staff");
+                break;
+            }
+            ServerFrame::Error { message } => panic!("{}", message),
+            _ => {}
+        }
+    }
+
+    // The keys were discovered and fetched once, then cached.
+    assert_eq!(
+        *requests.lock().unwrap(),
+        ["/.well-known/openid-configuration", "/jwks"]
+    );
+    Ok(())
+}
+
+/// Answers LDAP binds and searches for a directory with one user, avery
+/// (password `hunter2`) in the engineering group, and a lookup account.
+/// Searches match avery when their filter mentions `avery` anywhere.
+#[cfg(feature = "ldap")]
+async fn ldap_directory() -> Result<String> {
+    use ldap3::asn1::{PL, StructureTag, TagClass, parse_tag};
+
+    const AVERY: &str = "uid=avery,ou=people,dc=example";
+    fn primitive(class: TagClass, id: u64, bytes: &[u8]) -> StructureTag {
+        StructureTag {
+            class,
+            id,
+            payload: PL::P(bytes.to_vec()),
+        }
+    }
+    fn constructed(class: TagClass, id: u64, tags: Vec<StructureTag>) -> StructureTag {
+        StructureTag {
+            class,
+            id,
+            payload: PL::C(tags),
+        }
+    }
+    fn string(value: &str) -> StructureTag {
+        primitive(TagClass::Universal, 4, value.as_bytes())
+    }
+    fn done(op: u64, rc: u8) -> StructureTag {
+        constructed(
+            TagClass::Application,
+            op,
+            vec![
+                primitive(TagClass::Universal, 10, &[rc]),
+                string(""),
+                string(""),
+            ],
+        )
+    }
+    fn strings(tag: &StructureTag) -> Vec<Vec<u8>> {
+        match &tag.payload {
+            PL::P(bytes) => vec![bytes.clone()],
+            PL::C(tags) => tags.iter().flat_map(strings).collect(),
+        }
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("ldap://{}", listener.local_addr()?);
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut received = Vec::new();
+                let mut buffer = [0; 4096];
+                loop {
+                    let (rest, message) = match parse_tag(&received) {
+                        Ok((rest, message)) => (rest.to_vec(), message),
+                        Err(_) => match socket.read(&mut buffer).await {
+                            Ok(read @ 1..) => {
+                                received.extend_from_slice(&buffer[..read]);
+                                continue;
+                            }
+                            _ => return,
+                        },
+                    };
+                    received = rest;
+                    let PL::C(parts) = message.payload else {
+                        return;
+                    };
+                    let (id, op) = (parts[0].clone(), &parts[1]);
+                    let PL::C(request) = &op.payload else {
+                        return;
+                    };
+                    let replies = match op.id {
+                        0 => {
+                            let credentials = (strings(&request[1]), strings(&request[2]));
+                            let valid = [("cn=chat,dc=example", "svc"), (AVERY, "hunter2")]
+                                .iter()
+                                .any(|(dn, password)| {
+                                    credentials
+                                        == (
+                                            vec![dn.as_bytes().to_vec()],
+                                            vec![password.as_bytes().to_vec()],
+                                        )
+                                });
+                            vec![done(1, if valid { 0 } else { 49 })]
+                        }
+                        3 if strings(&request[6]).contains(&b"avery".to_vec()) => {
+                            let group = "cn=engineering,ou=groups,dc=example";
+                            let member_of = constructed(
+                                TagClass::Universal,
+                                16,
+                                vec![
+                                    string("memberOf"),
+                                    constructed(TagClass::Universal, 17, vec![string(group)]),
+                                ],
+                            );
+                            let entry = constructed(
+                                TagClass::Application,
+                                4,
+                                vec![
+                                    string(AVERY),
+                                    constructed(TagClass::Universal, 16, vec![member_of]),
+                                ],
+                            );
+                            vec![entry, done(5, 0)]
+                        }
+                        3 => vec![done(5, 0)],
+                        _ => return,
+                    };
+                    for reply in replies {
+                        let message = constructed(TagClass::Universal, 16, vec![id.clone(), reply]);
+                        let mut encoded = bytes::BytesMut::new();
+                        ldap3::asn1::write::encode_into(&mut encoded, message).unwrap();
+                        if socket.write_all(&encoded).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    Ok(url)
+}
+
+#[cfg(feature = "ldap")]
+#[tokio::test]
+async fn test_ldap_signs_in_with_directory_password_and_groups() -> Result<()> {
+    use tokio_chat_server::auth::RoleMap;
+    use tokio_chat_server::ldap::{LdapAuthenticator, LdapConfig};
+
+    let url = ldap_directory().await?;
+    let config = LdapConfig {
+        bind_dn: Some("cn=chat,dc=example".to_string()),
+        bind_password: "svc".to_string(),
+        roles: RoleMap::new().grant("engineering", "staff", Role::Members),
+        ..LdapConfig::new(url, "dc=example")
+    };
+    let staff = RoomConfig {
+        permissions: RoomPermissions {
+            read: Role::Members,
+            post: Role::Members,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(LdapAuthenticator::new(config)))
+        .with_room("staff", staff);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client.authenticate("avery", "wrong").await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+    client.authenticate("avery", "").await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+    client.authenticate("blake", "hunter2").await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+
+    client.authenticate("avery", "hunter2").await?;
+    assert!(
+        matches!(client.receive().await?, ServerFrame::Authenticated { user, .. } if user == "avery")
+    );
+    let mut message = ChatMessage::from_raw("avery: hello staff")?;
+    message.room = Some("staff".to_string());
+    client.send(message).await?;
+    loop {
+        match client.receive().await? {
+            ServerFrame::Message { message, .. } => {
+                assert_eq!(message.content, "hello staff");
+                break;
+            }
+            ServerFrame::Error { message } => panic!("{}", message),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Writes a MaxMind Country database placing each IPv4 address in
+/// `countries` in its country.
+#[cfg(feature = "geoip")]
+fn country_db(path: &std::path::Path, countries: &[([u8; 4], &str)]) -> Result<()> {
+    // Control bytes: a type in the top three bits and a size below 29 in
+    // the rest, with types from 8 up extended into a second byte.
+    fn control(out: &mut Vec<u8>, kind: u8, size: usize) {
+        if kind < 8 {
+            out.push(kind << 5 | size as u8);
+        } else {
+            out.extend([size as u8, kind - 7]);
+        }
+    }
+    fn string(out: &mut Vec<u8>, value: &str) {
+        control(out, 2, value.len());
+        out.extend_from_slice(value.as_bytes());
+    }
+    fn uint(out: &mut Vec<u8>, kind: u8, value: u64) {
+        let bytes = value.to_be_bytes();
+        let bytes = &bytes[bytes.iter().position(|&b| b != 0).unwrap_or(8)..];
+        control(out, kind, bytes.len());
+        out.extend_from_slice(bytes);
+    }
+
+    #[derive(Clone, Copy)]
+    enum Record {
+        Empty,
+        Node(usize),
+        Data(usize),
+    }
+    let mut data = Vec::new();
+    let mut nodes = vec![[Record::Empty; 2]];
+    for (ip, country) in countries {
+        let offset = data.len();
+        control(&mut data, 7, 1);
+        string(&mut data, "country");
+        control(&mut data, 7, 1);
+        string(&mut data, "iso_code");
+        string(&mut data, country);
+        let bits = u32::from_be_bytes(*ip);
+        let mut node = 0;
+        for depth in 0..32 {
+            let bit = (bits >> (31 - depth) & 1) as usize;
+            if depth == 31 {
+                nodes[node][bit] = Record::Data(offset);
+            } else if let Record::Node(next) = nodes[node][bit] {
+                node = next;
+            } else {
+                nodes.push([Record::Empty; 2]);
+                nodes[node][bit] = Record::Node(nodes.len() - 1);
+                node = nodes.len() - 1;
+            }
+        }
+    }
+
+    let count = nodes.len();
+    let mut db = Vec::new();
+    for record in nodes.iter().flatten() {
+        let value = match *record {
+            Record::Empty => count,
+            Record::Node(node) => node,
+            Record::Data(offset) => count + 16 + offset,
+        };
+        db.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+    }
+    db.extend([0; 16]);
+    db.extend(data);
+    db.extend(b"\xab\xcd\xefMaxMind.com");
+    control(&mut db, 7, 9);
+    string(&mut db, "binary_format_major_version");
+    uint(&mut db, 5, 2);
+    string(&mut db, "binary_format_minor_version");
+    uint(&mut db, 5, 0);
+    string(&mut db, "build_epoch");
+    uint(&mut db, 9, 0);
+    string(&mut db, "database_type");
+    string(&mut db, "GeoLite2-Country");
+    string(&mut db, "description");
+    control(&mut db, 7, 0);
+    string(&mut db, "ip_version");
+    uint(&mut db, 5, 4);
+    string(&mut db, "languages");
+    control(&mut db, 11, 0);
+    string(&mut db, "node_count");
+    uint(&mut db, 6, count as u64);
+    string(&mut db, "record_size");
+    uint(&mut db, 5, 24);
+    std::fs::write(path, db)?;
+    Ok(())
+}
+
+/// Connects to `addr` from `local`, returning whether the server serves
+/// the connection rather than closing it.
+#[cfg(feature = "geoip")]
+async fn served_from(local: &str, addr: &str) -> Result<bool> {
+    let socket = tokio::net::TcpSocket::new_v4()?;
+    socket.bind(format!("{}:0", local).parse()?)?;
+    let mut stream = socket.connect(addr.parse()?).await?;
+    let request = ClientFrame::FetchHistory {
+        room: None,
+        before: None,
+        limit: Some(1),
+    };
+    stream
+        .write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())
+        .await?;
+    let mut reply = [0; 1];
+    Ok(matches!(stream.read(&mut reply).await, Ok(1..)))
+}
+
+#[cfg(feature = "geoip")]
+#[tokio::test]
+async fn test_geoip_rules_refuse_connections_and_reload() -> Result<()> {
+    use tokio_chat_server::geoip::{GeoConfig, GeoFilter, Location};
+
+    let dir = std::env::temp_dir().join(format!("chat-geoip-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let config = GeoConfig {
+        country_db: Some(dir.join("country.mmdb")),
+        asn_db: None,
+        rules: dir.join("rules.json"),
+        reload_interval: Duration::from_millis(20),
+    };
+    country_db(
+        config.country_db.as_deref().unwrap(),
+        &[([127, 0, 0, 1], "NZ"), ([127, 0, 0, 2], "KP")],
+    )?;
+    std::fs::write(&config.rules, r#"{"deny_countries": ["KP"]}"#)?;
+    let filter = GeoFilter::open(config.clone())?;
+    assert_eq!(
+        filter.locate("127.0.0.2".parse()?),
+        Location {
+            country: Some("KP".to_string()),
+            asn: None
+        }
+    );
+    assert_eq!(filter.locate("127.0.0.3".parse()?), Location::default());
+    let server = ChatServer::new("127.0.0.1:0").await?.with_geoip(filter);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    assert!(served_from("127.0.0.1", &addr).await?);
+    assert!(!served_from("127.0.0.2", &addr).await?);
+    assert!(served_from("127.0.0.3", &addr).await?);
+
+    std::fs::write(&config.rules, r#"{"allow_countries": ["KP"]}"#)?;
+    timeout(Duration::from_secs(5), async {
+        while served_from("127.0.0.1", &addr).await? {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    assert!(served_from("127.0.0.2", &addr).await?);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// Issues `host` a certificate from `ca`, writing it and its key to `dir`
+/// as `TlsListener` expects, and returns it.
+#[cfg(feature = "tls")]
+fn issue_cert(
+    dir: &std::path::Path,
+    ca: &rcgen::CertifiedIssuer<'_, rcgen::KeyPair>,
+    host: &str,
+) -> Result<Vec<u8>> {
+    let key = rcgen::KeyPair::generate()?;
+    let cert = rcgen::CertificateParams::new(vec![host.to_string()])?.signed_by(&key, ca)?;
+    // The key goes first, so the certificate is never reloaded without it.
+    std::fs::write(dir.join(format!("{}.key", host)), key.serialize_pem())?;
+    std::fs::write(dir.join(format!("{}.crt", host)), cert.pem())?;
+    Ok(cert.der().to_vec())
+}
+
+/// Connects to `addr` over TLS as `host`, trusting `ca`. Returns the
+/// certificate the server presented once it has answered a frame.
+#[cfg(feature = "tls")]
+async fn tls_served_cert(addr: &str, ca: &[u8], host: &str) -> Result<Vec<u8>> {
+    use tokio::io::AsyncBufReadExt;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore, crypto::ring};
+
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from(ca.to_vec()))?;
+    let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let socket = tokio::net::TcpStream::connect(addr).await?;
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from(host.to_string())?, socket)
+        .await?;
+    let served = stream.get_ref().1.peer_certificates().unwrap()[0].to_vec();
+    let mut stream = tokio::io::BufReader::new(stream);
+    let request = ClientFrame::FetchHistory {
+        room: None,
+        before: None,
+        limit: Some(1),
+    };
+    stream
+        .write_all(format!("{}\n", serde_json::to_string(&request)?).as_bytes())
+        .await?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        anyhow::ensure!(stream.read_line(&mut line).await? > 0, "Connection closed");
+        if let ServerFrame::History { .. } = serde_json::from_str(&line)? {
+            return Ok(served);
+        }
+    }
+}
+
+#[cfg(feature = "tls")]
+#[tokio::test]
+async fn test_tls_certificates_chosen_by_sni_and_reloaded() -> Result<()> {
+    use tokio_chat_server::tls::{Certificates, TlsConfig, TlsListener};
+
+    let dir = std::env::temp_dir().join(format!("chat-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let mut ca = rcgen::CertificateParams::new(Vec::<String>::new())?;
+    ca.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = rcgen::CertifiedIssuer::self_signed(ca, rcgen::KeyPair::generate()?)?;
+    let first = issue_cert(&dir, &ca, "a.test")?;
+    let certs = Certificates::load(TlsConfig {
+        cert_dir: dir.clone(),
+        default_host: None,
+        reload_interval: Duration::from_millis(20),
+    })?;
+    let listener = TlsListener::new(tokio::net::TcpListener::bind("127.0.0.1:0").await?, certs)?;
+    let server = ChatServer::from_listener(listener);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    assert_eq!(tls_served_cert(&addr, ca.der(), "a.test").await?, first);
+    assert!(tls_served_cert(&addr, ca.der(), "b.test").await.is_err());
+
+    let second = issue_cert(&dir, &ca, "b.test")?;
+    let wildcard = issue_cert(&dir, &ca, "*.c.test")?;
+    let renewed = issue_cert(&dir, &ca, "a.test")?;
+    timeout(Duration::from_secs(5), async {
+        // The renewal was written last.
+        while tls_served_cert(&addr, ca.der(), "a.test").await? != renewed {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        anyhow::Ok(())
+    })
+    .await??;
+    assert_eq!(tls_served_cert(&addr, ca.der(), "b.test").await?, second);
+    assert_eq!(
+        tls_served_cert(&addr, ca.der(), "chat.c.test").await?,
+        wildcard
+    );
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// What a fake ACME CA knows about its one account and order.
+#[cfg(feature = "acme")]
+#[derive(Default)]
+struct AcmeOrder {
+    thumbprint: String,
+    domain: String,
+    validated: bool,
+    chain: Option<String>,
+}
+
+/// Serves a fake ACME CA over HTTPS with a certificate from `ca`, issuing
+/// certificates from it once the TLS-ALPN-01 challenge is answered on
+/// `chat_addr`. Returns its directory URL.
+#[cfg(feature = "acme")]
+async fn acme_ca(
+    ca: Arc<rcgen::CertifiedIssuer<'static, rcgen::KeyPair>>,
+    chat_addr: String,
+) -> Result<String> {
+    use tokio::io::AsyncBufReadExt;
+    use tokio_rustls::rustls::ServerConfig;
+    use tokio_rustls::rustls::crypto::ring;
+    use tokio_rustls::rustls::pki_types::PrivateKeyDer;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let base = format!("https://{}", listener.local_addr()?);
+    let key = rcgen::KeyPair::generate()?;
+    let cert =
+        rcgen::CertificateParams::new(vec!["127.0.0.1".to_string()])?.signed_by(&key, &ca)?;
+    let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(
+            vec![cert.der().clone()],
+            PrivateKeyDer::try_from(key.serialize_der()).map_err(anyhow::Error::msg)?,
+        )?;
+    let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(config));
+    let order = Arc::new(Mutex::new(AcmeOrder::default()));
+    let directory = format!("{}/directory", base);
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let (acceptor, base, ca, chat_addr, order) = (
+                acceptor.clone(),
+                base.clone(),
+                ca.clone(),
+                chat_addr.clone(),
+                order.clone(),
+            );
+            tokio::spawn(async move {
+                let Ok(stream) = acceptor.accept(socket).await else {
+                    return;
+                };
+                let mut stream = tokio::io::BufReader::new(stream);
+                let mut head = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        length = value.trim().parse().unwrap_or(0);
+                    }
+                    head.push_str(&line);
+                }
+                let mut body = vec![0; length];
+                if stream.read_exact(&mut body).await.is_err() {
+                    return;
+                }
+                let path = head.split(' ').nth(1).unwrap_or_default().to_string();
+                let (status, location, response) =
+                    acme_response(&base, &path, &body, &ca, &chat_addr, &order).await;
+                let location = location
+                    .map(|url| format!("Location: {}\r\n", url))
+                    .unwrap_or_default();
+                static NONCES: AtomicUsize = AtomicUsize::new(0);
+                let response = format!(
+                    "HTTP/1.1 {}\r\nReplay-Nonce: nonce-{}\r\n{}Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    NONCES.fetch_add(1, Ordering::Relaxed),
+                    location,
+                    response.len(),
+                    response
+                );
+                let _ = stream.write_all(response.as_bytes()).await;
+                let _ = stream.shutdown().await;
+            });
+        }
+    });
+    Ok(directory)
+}
+
+/// Answers one request to the fake ACME CA with its status, `Location`
+/// and body.
+#[cfg(feature = "acme")]
+async fn acme_response(
+    base: &str,
+    path: &str,
+    body: &[u8],
+    ca: &rcgen::CertifiedIssuer<'static, rcgen::KeyPair>,
+    chat_addr: &str,
+    order: &Mutex<AcmeOrder>,
+) -> (&'static str, Option<String>, String) {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL;
+    use sha2::{Digest, Sha256};
+
+    const TOKEN: &str = "challenge-token";
+    let payload: serde_json::Value = serde_json::from_slice(body)
+        .ok()
+        .and_then(|jws: serde_json::Value| {
+            let payload = BASE64_URL.decode(jws["payload"].as_str()?).ok()?;
+            serde_json::from_slice(&payload).ok()
+        })
+        .unwrap_or_default();
+    let order_state = |order: &AcmeOrder| {
+        let status = match (order.validated, &order.chain) {
+            (_, Some(_)) => "valid",
+            (true, None) => "ready",
+            (false, None) => "pending",
+        };
+        serde_json::json!({
+            "status": status,
+            "identifiers": [{ "type": "dns", "value": order.domain }],
+            "authorizations": [format!("{}/authz", base)],
+            "finalize": format!("{}/finalize", base),
+            "certificate": order.chain.as_ref().map(|_| format!("{}/cert", base)),
+        })
+        .to_string()
+    };
+    match path {
+        "/directory" => {
+            let directory = serde_json::json!({
+                "newNonce": format!("{}/nonce", base),
+                "newAccount": format!("{}/account", base),
+                "newOrder": format!("{}/order", base),
+            });
+            ("200 OK", None, directory.to_string())
+        }
+        "/nonce" => ("200 OK", None, String::new()),
+        "/account" => {
+            let jws: serde_json::Value = serde_json::from_slice(body).unwrap_or_default();
+            let protected = BASE64_URL
+                .decode(jws["protected"].as_str().unwrap_or_default())
+                .unwrap_or_default();
+            let protected: serde_json::Value =
+                serde_json::from_slice(&protected).unwrap_or_default();
+            let jwk = &protected["jwk"];
+            // RFC 7638: the required members in lexicographic order.
+            let thumbprint = format!(
+                r#"{{"crv":"{}","kty":"{}","x":"{}","y":"{}"}}"#,
+                jwk["crv"].as_str().unwrap_or_default(),
+                jwk["kty"].as_str().unwrap_or_default(),
+                jwk["x"].as_str().unwrap_or_default(),
+                jwk["y"].as_str().unwrap_or_default(),
+            );
+            order.lock().unwrap().thumbprint = BASE64_URL.encode(Sha256::digest(thumbprint));
+            let location = format!("{}/account/1", base);
+            (
+                "201 Created",
+                Some(location),
+                r#"{"status":"valid"}"#.to_string(),
+            )
+        }
+        "/order" => {
+            let mut order = order.lock().unwrap();
+            order.domain = payload["identifiers"][0]["value"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            let location = format!("{}/order/1", base);
+            ("201 Created", Some(location), order_state(&order))
+        }
+        "/order/1" => ("200 OK", None, order_state(&order.lock().unwrap())),
+        "/authz" | "/challenge" => {
+            let (domain, validated) = {
+                let order = order.lock().unwrap();
+                (order.domain.clone(), order.validated)
+            };
+            let validated = if path == "/challenge" && !validated {
+                let thumbprint = order.lock().unwrap().thumbprint.clone();
+                let digest = Sha256::digest(format!("{}.{}", TOKEN, thumbprint));
+                let presented = acme_challenge_cert(chat_addr, &domain).await;
+                let valid = presented.is_ok_and(|cert| {
+                    cert.windows(digest.len())
+                        .any(|window| window == digest.as_slice())
+                });
+                order.lock().unwrap().validated = valid;
+                valid
+            } else {
+                validated
+            };
+            let status = if validated { "valid" } else { "pending" };
+            let challenge = serde_json::json!({
+                "type": "tls-alpn-01",
+                "url": format!("{}/challenge", base),
+                "token": TOKEN,
+                "status": status,
+            });
+            let body = if path == "/challenge" {
+                challenge
+            } else {
+                serde_json::json!({
+                    "status": status,
+                    "identifier": { "type": "dns", "value": domain },
+                    "challenges": [challenge],
+                })
+            };
+            ("200 OK", None, body.to_string())
+        }
+        "/finalize" => {
+            let csr = BASE64_URL
+                .decode(payload["csr"].as_str().unwrap_or_default())
+                .unwrap_or_default();
+            let csr = rcgen::CertificateSigningRequestParams::from_der(&csr.into());
+            let mut order = order.lock().unwrap();
+            match csr.and_then(|csr| csr.signed_by(ca)) {
+                Ok(cert) if order.validated => {
+                    order.chain = Some(format!("{}{}", cert.pem(), ca.pem()));
+                    ("200 OK", None, order_state(&order))
+                }
+                _ => (
+                    "403 Forbidden",
+                    None,
+                    r#"{"type":"urn:ietf:params:acme:error:unauthorized"}"#.to_string(),
+                ),
+            }
+        }
+        "/cert" => {
+            let chain = order.lock().unwrap().chain.clone();
+            ("200 OK", None, chain.unwrap_or_default())
+        }
+        _ => ("404 Not Found", None, "{}".to_string()),
+    }
+}
+
+/// Validates a TLS-ALPN-01 challenge as a CA would, returning the
+/// certificate `addr` presents for `domain` over `acme-tls/1`.
+#[cfg(feature = "acme")]
+async fn acme_challenge_cert(addr: &str, domain: &str) -> Result<Vec<u8>> {
+    use tokio_rustls::rustls::client::danger::{
+        HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+    };
+    use tokio_rustls::rustls::crypto::{CryptoProvider, ring};
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+    use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, SignatureScheme};
+
+    /// Takes any certificate: challenge certificates are self-signed, and
+    /// webpki rejects their critical acmeIdentifier extension.
+    #[derive(Debug)]
+    struct AnyCert(CryptoProvider);
+
+    impl ServerCertVerifier for AnyCert {
+        fn verify_server_cert(
+            &self,
+            _: &CertificateDer<'_>,
+            _: &[CertificateDer<'_>],
+            _: &ServerName<'_>,
+            _: &[u8],
+            _: UnixTime,
+        ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+            Ok(ServerCertVerified::assertion())
+        }
+
+        fn verify_tls12_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn verify_tls13_signature(
+            &self,
+            _: &[u8],
+            _: &CertificateDer<'_>,
+            _: &DigitallySignedStruct,
+        ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+            Ok(HandshakeSignatureValid::assertion())
+        }
+
+        fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+            self.0.signature_verification_algorithms.supported_schemes()
+        }
+    }
+
+    let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyCert(ring::default_provider())))
+        .with_no_client_auth();
+    config.alpn_protocols = vec![b"acme-tls/1".to_vec()];
+    let socket = tokio::net::TcpStream::connect(addr).await?;
+    let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect(ServerName::try_from(domain.to_string())?, socket)
+        .await?;
+    let (_, connection) = stream.get_ref();
+    anyhow::ensure!(connection.alpn_protocol() == Some(b"acme-tls/1".as_slice()));
+    Ok(connection.peer_certificates().unwrap()[0].to_vec())
+}
+
+#[cfg(feature = "acme")]
+#[tokio::test]
+async fn test_acme_issues_certificates_over_tls_alpn_challenges() -> Result<()> {
+    use tokio_chat_server::acme::{Acme, AcmeConfig};
+    use tokio_chat_server::tls::{Certificates, TlsConfig, TlsListener};
+
+    let dir = std::env::temp_dir().join(format!("chat-acme-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let mut ca = rcgen::CertificateParams::new(Vec::<String>::new())?;
+    ca.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+    let ca = Arc::new(rcgen::CertifiedIssuer::self_signed(
+        ca,
+        rcgen::KeyPair::generate()?,
+    )?);
+    let root = dir.join("ca.pem");
+    std::fs::write(&root, ca.pem())?;
+
+    std::fs::create_dir_all(dir.join("certs"))?;
+    let certs = Certificates::open(TlsConfig {
+        cert_dir: dir.join("certs"),
+        default_host: None,
+        reload_interval: Duration::from_secs(60),
+    })?;
+    let listener = TlsListener::new(
+        tokio::net::TcpListener::bind("127.0.0.1:0").await?,
+        certs.clone(),
+    )?;
+    let server = ChatServer::from_listener(listener);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    assert!(tls_served_cert(&addr, ca.der(), "chat.test").await.is_err());
+
+    let acme = Acme::new(
+        AcmeConfig {
+            directory_url: acme_ca(ca.clone(), addr.clone()).await?,
+            directory_root: Some(root),
+            ..AcmeConfig::new(vec!["chat.test".to_string()])
+        },
+        certs.clone(),
+    )?;
+    assert!(acme.renew_if_due("chat.test").await?);
+    assert_eq!(certs.hosts(), vec!["chat.test".to_string()]);
+    let issued = std::fs::read(dir.join("certs/chat.test.crt"))?;
+    let issued = tokio_rustls::rustls::pki_types::pem::PemObject::from_pem_slice(&issued)
+        .map(|cert: tokio_rustls::rustls::pki_types::CertificateDer| cert.to_vec())?;
+    assert_eq!(tls_served_cert(&addr, ca.der(), "chat.test").await?, issued);
+    // The challenge is no longer answered, and the certificate isn't due.
+    assert!(acme_challenge_cert(&addr, "chat.test").await.is_err());
+    assert!(!acme.renew_if_due("chat.test").await?);
+    assert!(dir.join("certs/acme-account.json").exists());
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+/// Serves a fake S3 over plain HTTP, keeping objects in `objects` by
+/// `bucket/key`. Returns its endpoint.
+#[cfg(feature = "s3")]
+async fn s3_server(
+    objects: Arc<Mutex<std::collections::HashMap<String, Vec<u8>>>>,
+) -> Result<String> {
+    use tokio::io::AsyncBufReadExt;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = format!("http://{}", listener.local_addr()?);
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            let objects = objects.clone();
+            tokio::spawn(async move {
+                let mut socket = tokio::io::BufReader::new(socket);
+                let mut head = Vec::new();
+                loop {
+                    let mut line = String::new();
+                    if socket.read_line(&mut line).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    if line == "\r\n" {
+                        break;
+                    }
+                    head.push(line.trim_end().to_string());
+                }
+                let header = |name: &str| {
+                    head.iter().find_map(|line| {
+                        let (key, value) = line.split_once(':')?;
+                        key.eq_ignore_ascii_case(name)
+                            .then(|| value.trim().to_string())
+                    })
+                };
+                if header("expect").is_some_and(|expect| expect == "100-continue") {
+                    let _ = socket.write_all(b"HTTP/1.1 100 Continue\r\n\r\n").await;
+                }
+                let length = header("content-length").map_or(0, |length| length.parse().unwrap());
+                let mut body = vec![0; length];
+                if socket.read_exact(&mut body).await.is_err() {
+                    return;
+                }
+                let mut request = head[0].split(' ');
+                let method = request.next().unwrap_or_default().to_string();
+                let path = request.next().unwrap_or_default();
+                let key = path.split('?').next().unwrap_or_default()[1..].to_string();
+                let (status, body) = {
+                    let mut objects = objects.lock().unwrap();
+                    match (method.as_str(), objects.get(&key)) {
+                        ("PUT", _) => {
+                            objects.insert(key, body);
+                            ("200 OK", Vec::new())
+                        }
+                        ("DELETE", _) => {
+                            objects.remove(&key);
+                            ("204 No Content", Vec::new())
+                        }
+                        ("GET" | "HEAD", Some(object)) => ("200 OK", object.clone()),
+                        ("HEAD", None) => ("404 Not Found", Vec::new()),
+                        _ => (
+                            "404 Not Found",
+                            b"<Error><Code>NoSuchKey</Code></Error>".to_vec(),
+                        ),
+                    }
+                };
+                let mut response = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .into_bytes();
+                if method != "HEAD" {
+                    response.extend(body);
+                }
+                let _ = socket.write_all(&response).await;
+            });
+        }
+    });
+    Ok(endpoint)
+}
+
+/// Settings for objects under `prefix` in the fake S3's `chat` bucket.
+#[cfg(feature = "s3")]
+async fn s3_config(
+    objects: &Arc<Mutex<std::collections::HashMap<String, Vec<u8>>>>,
+    prefix: &str,
+) -> Result<tokio_chat_server::s3::S3Config> {
+    Ok(tokio_chat_server::s3::S3Config {
+        bucket: "chat".to_string(),
+        prefix: prefix.to_string(),
+        region: "us-east-1".to_string(),
+        endpoint: Some(s3_server(objects.clone()).await?),
+        access_key_id: "AKIDEXAMPLE".to_string(),
+        secret_access_key: "secret".to_string(),
+        session_token: None,
+    })
+}
+
+#[cfg(feature = "s3")]
+#[tokio::test]
+async fn test_s3_store_keeps_blobs_under_its_prefix() -> Result<()> {
+    use tokio_chat_server::s3::S3BlobStore;
+
+    let objects = Arc::new(Mutex::new(std::collections::HashMap::new()));
+    let store = S3BlobStore::new(s3_config(&objects, "prod/blobs/").await?);
+    let contents = Bytes::from_static(b"quarterly report");
+    let id = blob_id(&contents);
+    assert!(!store.contains(&id).await?);
+    assert_eq!(store.get(&id).await?, None);
+
+    store.put(&id, contents.clone()).await?;
+    assert!(store.contains(&id).await?);
+    assert_eq!(store.get(&id).await?, Some(contents.clone()));
+    assert_eq!(
+        objects
+            .lock()
+            .unwrap()
+            .get(&format!("chat/prod/blobs/{}", id)),
+        Some(&contents.to_vec())
+    );
+    assert!(store.put("../escape", contents).await.is_err());
+    Ok(())
+}
+
+#[cfg(feature = "s3")]
+#[tokio::test]
+async fn test_idle_rooms_are_archived_to_s3() -> Result<()> {
+    use tokio_chat_server::archive::ArchivePolicy;
+    use tokio_chat_server::s3::S3ColdStore;
+
+    let objects = Arc::new(Mutex::new(std::collections::HashMap::new()));
+    let cold = S3ColdStore::new(s3_config(&objects, "prod/archives/").await?);
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_room("old", RoomConfig::default())
+        .with_archiving(
+            Arc::new(cold),
+            ArchivePolicy {
+                idle: Duration::ZERO,
+                interval: Duration::from_secs(1),
+            },
+        );
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    let mut message = ChatMessage::from_raw("avery: before the lull")?;
+    message.room = Some("old".to_string());
+    client.send(message).await?;
+    client.receive().await?;
+
+    // "old" in hex.
+    let key = "chat/prod/archives/6f6c64.jsonl.gz";
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(objects.lock().unwrap().contains_key(key));
+
+    client
+        .fetch_history(Some("old".to_string()), None, None)
+        .await?;
+    let ServerFrame::History { messages, .. } = client.receive().await? else {
+        panic!("expected History");
+    };
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].content, "before the lull");
+    assert!(!objects.lock().unwrap().contains_key(key));
+    Ok(())
+}
+
+/// Records lifecycle events, rejecting connections once `reject` is set.
+#[derive(Default)]
+struct RecordingHooks {
+    events: Mutex<Vec<String>>,
+    reject: AtomicBool,
+}
+
+#[async_trait]
+impl ConnectionHooks for RecordingHooks {
+    async fn on_connect(&self, _addr: SocketAddr) -> Result<()> {
+        if self.reject.load(Ordering::Relaxed) {
+            anyhow::bail!("Server is full");
+        }
+        self.events.lock().unwrap().push("connect".to_string());
+        Ok(())
+    }
+
+    async fn on_authenticated(&self, _addr: SocketAddr, user: &str, guest: bool) {
+        let kind = if guest { "guest" } else { "user" };
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("{} {}", kind, user));
+    }
+
+    async fn on_disconnect(&self, _addr: SocketAddr, user: Option<&str>) {
+        let user = user.unwrap_or("nobody");
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("disconnect {}", user));
+    }
+}
+
+#[tokio::test]
+async fn test_connection_hooks() -> Result<()> {
+    let hooks = Arc::new(RecordingHooks::default());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "secret")))
+        .with_guests(GuestPolicy::default())
+        .with_hooks(hooks.clone());
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let ServerFrame::Welcome { user: guest, .. } = client.receive().await? else {
+        panic!("expected Welcome");
+    };
+    client.authenticate("avery", "secret").await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    drop(client);
+    for _ in 0..100 {
+        if hooks.events.lock().unwrap().len() == 4 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        *hooks.events.lock().unwrap(),
+        [
+            "connect".to_string(),
+            format!("guest {}", guest),
+            "user avery".to_string(),
+            "disconnect avery".to_string(),
+        ]
+    );
+
+    hooks.reject.store(true, Ordering::Relaxed);
+    let mut client = Client::connect(&addr).await?;
+    assert!(
+        matches!(client.receive().await?, ServerFrame::Error { message } if message == "Server is full")
+    );
+    assert!(client.receive().await.is_err());
+    assert_eq!(hooks.events.lock().unwrap().len(), 4);
+    Ok(())
+}
+
+/// Accepts one HTTP request on a local port, answering 204, and returns
+/// the port and the request's body once it arrives.
+async fn webhook_receiver() -> Result<(SocketAddr, tokio::task::JoinHandle<Result<String>>)> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let received = tokio::spawn(async move {
+        let (mut socket, _) = listener.accept().await?;
+        let mut request = Vec::new();
+        let mut buffer = [0; 1024];
+        let body = loop {
+            let n = socket.read(&mut buffer).await?;
+            request.extend_from_slice(&buffer[..n]);
+            let text = String::from_utf8_lossy(&request).to_string();
+            if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                let length: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap_or("0")
+                    .parse()?;
+                if body.len() >= length {
+                    break body.to_string();
+                }
+            }
+        };
+        socket.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await?;
+        Ok(body)
+    });
+    Ok((addr, received))
+}
+
+#[tokio::test]
+async fn test_onboarding_welcomes_new_users_and_members() -> Result<()> {
+    let (webhook, received) = webhook_receiver().await?;
+    let onboarding: Onboarding = serde_json::from_value(serde_json::json!({
+        "system_user": "greeter",
+        "welcome": {
+            "motd": "Be kind",
+            "direct_message": "Welcome aboard",
+            "webhook": format!("http://{}/arrivals", webhook),
+        },
+        "auto_join": ["lobby"],
+        "rooms": {
+            "lobby": { "motd": "Lobby rules apply" },
+            "dev": { "direct_message": "Builds are in #ci" },
+        },
+    }))?;
+    let open = RoomConfig {
+        permissions: RoomPermissions {
+            invite: Role::Everyone,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "a")))
+        .with_room("lobby", RoomConfig::default())
+        .with_room("dev", open)
+        .with_onboarding(onboarding);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client.authenticate("avery", "a").await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    let mut frames = Vec::new();
+    while frames.len() < 4 {
+        frames.push(client.receive().await?);
+    }
+    assert!(frames.contains(&ServerFrame::Motd {
+        room: None,
+        text: "Be kind".to_string(),
+    }));
+    assert!(frames.contains(&ServerFrame::Motd {
+        room: Some("lobby".to_string()),
+        text: "Lobby rules apply".to_string(),
+    }));
+    assert!(frames.iter().any(|frame| matches!(
+        frame,
+        ServerFrame::Message { message, .. }
+            if message.sender == "greeter" && message.content == "Welcome aboard"
+    )));
+    assert!(frames.iter().any(|frame| matches!(
+        frame,
+        ServerFrame::RoomUpdated {
+            room, config, by, ..
+        }
+            if room == "lobby" && by == "greeter" && config.members.contains("avery")
+    )));
+
+    let event: WebhookEvent = serde_json::from_str(&received.await??)?;
+    assert_eq!(event.event, OnboardingEvent::Arrived);
+    assert_eq!(event.user, "avery");
+
+    client
+        .send(
+            ChatMessage::builder()
+                .sender("avery")
+                .content("/join dev")
+                .build()?,
+        )
+        .await?;
+    loop {
+        if let ServerFrame::Message { message, .. } = client.receive().await? {
+            assert_eq!(message.content, "Builds are in #ci");
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_config_default_rooms_exist_and_are_joined() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("chat-config-{}.json", std::process::id()));
+    let config = serde_json::json!({
+        "rooms": {
+            "lobby": { "topic": "Say hello" },
+            "staff": { "auto_join": false },
+        },
+    });
+    tokio::fs::write(&path, config.to_string()).await?;
+    let config = Config::load(&path).await?;
+    let server = config.apply(
+        ChatServer::new("127.0.0.1:0")
+            .await?
+            .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "a"))),
+    );
+    let addr = server.local_addr()?.to_string();
+    let admin = server.admin();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client.authenticate("avery", "a").await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    let ServerFrame::RoomUpdated { room, config, .. } = client.receive().await? else {
+        panic!("expected RoomUpdated");
+    };
+    assert_eq!(room, "lobby");
+    assert_eq!(config.topic.as_deref(), Some("Say hello"));
+    assert!(config.members.contains("avery"));
+    assert!(admin.announce(Some("staff"), "Rooms exist", AnnouncementLevel::Info)?);
+
+    tokio::fs::write(&path, r#"{"rooms": {"no spaces": {}}}"#).await?;
+    assert!(Config::load(&path).await.is_err());
+    tokio::fs::remove_file(&path).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_server_text_is_localized() -> Result<()> {
+    let french = [
+        (
+            "Nickname {} is not registered",
+            "Le pseudo {} n'est pas enregistré",
+        ),
+        ("Restarting soon", "Redémarrage imminent"),
+    ]
+    .into_iter()
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .collect();
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_catalogs(Catalogs::new().with_catalog("fr", Catalog::new(french)));
+    let addr = server.local_addr()?.to_string();
+    let admin = server.admin();
+    tokio::spawn(server.run());
+
+    let mut french = Client::builder(&addr)
+        .locales(["fr-CA", "en"])
+        .connect()
+        .await?;
+    assert_eq!(
+        french.receive().await?,
+        ServerFrame::LocaleSelected {
+            locale: "fr".to_string()
+        }
+    );
+    let mut english = Client::connect(&addr).await?;
+    english
+        .send_frame(&ClientFrame::SetLocale {
+            locales: vec!["de".to_string()],
+        })
+        .await?;
+    assert_eq!(
+        english.receive().await?,
+        ServerFrame::LocaleSelected {
+            locale: "en".to_string()
+        }
+    );
+
+    french
+        .send(
+            ChatMessage::builder()
+                .sender("avery")
+                .content("/identify nobody hunter2")
+                .build()?,
+        )
+        .await?;
+    assert!(matches!(
+        french.receive().await?,
+        ServerFrame::Error { message } if message == "Le pseudo nobody n'est pas enregistré"
+    ));
+
+    admin.notice("Restarting soon")?;
+    assert!(matches!(
+        french.receive().await?,
+        ServerFrame::Notice { text } if text == "Redémarrage imminent"
+    ));
+    assert!(matches!(
+        english.receive().await?,
+        ServerFrame::Notice { text } if text == "Restarting soon"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_key_scopes_and_revocation() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    let api_keys = server.api_keys();
+    tokio::spawn(server.run());
+
+    let deploy = api_keys.issue(
+        "deploy-bot",
+        KeyScope {
+            rooms: Some(["general".to_string()].into()),
+            ..Default::default()
+        },
+        0,
+    );
+    let watcher = api_keys.issue(
+        "watcher",
+        KeyScope {
+            read_only: true,
+            ..Default::default()
+        },
+        0,
+    );
+
+    let mut client = Client::connect(&addr).await?;
+    client.authenticate_with_api_key("not.a-key").await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+    client.authenticate_with_api_key(&deploy.key).await?;
+    assert!(
+        matches!(client.receive().await?, ServerFrame::Authenticated { user, .. } if user == "deploy-bot")
+    );
+    client.send(ChatMessage::from_raw("x: shipped")?).await?;
+    let ServerFrame::Message { message, .. } = client.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.sender, "deploy-bot");
+    let mut elsewhere = ChatMessage::from_raw("x: shipped")?;
+    elsewhere.room = Some("ops".to_string());
+    client.send(elsewhere).await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+    client
+        .send(ChatMessage::from_raw("x: /create ops")?)
+        .await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+
+    let mut reader = Client::connect(&addr).await?;
+    reader.authenticate_with_api_key(&watcher.key).await?;
+    assert!(matches!(
+        reader.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    reader.send(ChatMessage::from_raw("x: hello")?).await?;
+    assert!(
+        matches!(reader.receive().await?, ServerFrame::Error { message } if message == "This API key is read-only")
+    );
+
+    assert!(api_keys.revoke(&deploy.info.id));
+    client
+        .send(ChatMessage::from_raw("x: still here?")?)
+        .await?;
+    assert!(client.receive().await.is_err());
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn test_admin_api_key_routes() -> Result<()> {
+    use tokio_chat_server::apikey::{ApiKeyInfo, ApiKeys, IssuedApiKey};
+    use tokio_chat_server::http::{Admin, serve_admin};
+
+    let api_keys = Arc::new(ApiKeys::new());
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let admin_addr = listener.local_addr()?;
+    tokio::spawn(serve_admin(
+        listener,
+        Admin {
+            metrics: Default::default(),
+            dead_letters: Default::default(),
+            api_keys: api_keys.clone(),
+            resources: Default::default(),
+            control: Default::default(),
+            token: "operator".to_string(),
+        },
+    ));
+    let request = |method: &str, path: &str, body: &str| {
+        format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer operator\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            body.len(),
+            body
+        )
+    };
+    let send = |request: String| async move {
+        let mut stream = tokio::net::TcpStream::connect(admin_addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        anyhow::Ok(response)
+    };
+
+    let response = send(request(
+        "POST",
+        "/admin/api-keys",
+        r#"{"account":"deploy-bot","scope":{"read_only":true}}"#,
+    ))
+    .await?;
+    let (_, json) = response.split_once("\r\n\r\n").unwrap();
+    let issued: IssuedApiKey = serde_json::from_str(json)?;
+    assert!(issued.info.scope.read_only);
+    assert!(api_keys.verify(&issued.key, 0).is_some());
+
+    let path = format!("/admin/api-keys/{}/rotate?grace_secs=60", issued.info.id);
+    let response = send(request("POST", &path, "")).await?;
+    let (_, json) = response.split_once("\r\n\r\n").unwrap();
+    let rotated: IssuedApiKey = serde_json::from_str(json)?;
+    assert_eq!(rotated.info.account, "deploy-bot");
+
+    let path = format!("/admin/api-keys/{}", issued.info.id);
+    let response = send(request("DELETE", &path, "")).await?;
+    assert!(response.starts_with("HTTP/1.1 204"));
+    let response = send(request("DELETE", &path, "")).await?;
+    assert!(response.starts_with("HTTP/1.1 404"));
+
+    let response = send(request("GET", "/admin/api-keys", "")).await?;
+    let (_, json) = response.split_once("\r\n\r\n").unwrap();
+    let listed: Vec<ApiKeyInfo> = serde_json::from_str(json)?;
+    assert_eq!(listed, vec![rotated.info]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_operator_kick_ban_and_notice() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b"),
+        ));
+    let addr = server.local_addr()?.to_string();
+    let admin = server.admin();
+    tokio::spawn(server.run());
+
+    let mut clients = Vec::new();
+    for (user, token) in [("avery", "a"), ("blake", "b")] {
+        let mut client = Client::connect(&addr).await?;
+        client.authenticate(user, token).await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Authenticated { .. }
+        ));
+        clients.push(client);
+    }
+    assert_eq!(admin.connections().len(), 2);
+
+    admin.notice("Restarting soon")?;
+    for client in &mut clients {
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Notice { text } if text == "Restarting soon"
+        ));
+    }
+
+    let mut blake = clients.pop().unwrap();
+    assert_eq!(admin.kick("blake", Some("spam")), 1);
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Error { message } if message == "Disconnected by an operator: spam"
+    ));
+    assert!(blake.receive().await.is_err());
+
+    let mut avery = clients.pop().unwrap();
+    assert_eq!(admin.ban("avery", None), 1);
+    assert!(matches!(avery.receive().await?, ServerFrame::Error { .. }));
+    assert!(avery.receive().await.is_err());
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a").await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Error { message } if message == "avery is banned"
+    ));
+
+    assert!(admin.unban("avery"));
+    avery.authenticate("avery", "a").await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_announcements_are_coalesced() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    let admin = server.admin();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    while admin.connections().is_empty() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    assert!(admin.announce(None, "Maintenance at 02:00", AnnouncementLevel::Warning)?);
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Announcement { room: None, text, level: AnnouncementLevel::Warning, repeats: 0 }
+            if text == "Maintenance at 02:00"
+    ));
+    assert!(!admin.announce(None, "Maintenance at 02:00", AnnouncementLevel::Warning)?);
+    assert!(
+        admin
+            .announce(Some("nowhere"), "Hello", AnnouncementLevel::Info)
+            .is_err()
+    );
+
+    assert!(admin.announce(None, "Back up", AnnouncementLevel::Info)?);
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Announcement { text, .. } if text == "Back up"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idle_rooms_are_archived_and_restored() -> Result<()> {
+    use tokio_chat_server::archive::{ArchivePolicy, ColdStore, MemoryColdStore};
+
+    let cold = Arc::new(MemoryColdStore::new());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_room("old", RoomConfig::default())
+        .with_archiving(
+            cold.clone(),
+            ArchivePolicy {
+                idle: Duration::ZERO,
+                interval: Duration::from_secs(1),
+            },
+        );
+    let addr = server.local_addr()?.to_string();
+    let resources = server.resources();
+    tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    let mut message = ChatMessage::from_raw("avery: before the lull")?;
+    message.room = Some("old".to_string());
+    client.send(message).await?;
+    client.receive().await?;
+
+    // The second check, a second in, finds the message idle; the next
+    // one is after the history has been read.
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    assert!(cold.get("old").await?.is_some());
+    let usage = resources.usage(0);
+    assert!(usage.by_room.iter().all(|room| room.room != "old"));
+
+    client
+        .fetch_history(Some("old".to_string()), None, None)
+        .await?;
+    let ServerFrame::History { messages, .. } = client.receive().await? else {
+        panic!("expected History");
+    };
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].content, "before the lull");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_replay_stored_history() -> Result<()> {
+    use tokio_chat_server::replay::{ReplayOptions, replay};
+    use tokio_chat_server::store::{MemoryStore, MessageStore};
+
+    let store = MemoryStore::default();
+    for (id, room, raw) in [
+        (1, "archive", "avery: first"),
+        (2, "other", "blake: elsewhere"),
+        (3, "archive", "casey: second"),
+    ] {
+        let mut message = ChatMessage::from_raw(raw)?;
+        message.id = Some(id);
+        message.room = Some(room.to_string());
+        message.timestamp = Some(1000 + id * 60);
+        store.append(&message).await?;
+    }
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let mut watcher = Client::connect(&addr).await?;
+    let mut replayer = Client::connect(&addr).await?;
+    let options = ReplayOptions {
+        room: Some("archive".to_string()),
+        into_room: Some("general".to_string()),
+        speed: 0.0,
+        ..ReplayOptions::default()
+    };
+    assert_eq!(replay(&store, &mut replayer, &options).await?, 2);
+    for expected in ["avery: first", "casey: second"] {
+        let ServerFrame::Message { message, .. } = watcher.receive().await? else {
+            panic!("expected Message");
+        };
+        assert_eq!(format!("{}: {}", message.sender, message.content), expected);
+        assert_eq!(message.room(), "general");
+        assert_ne!(message.timestamp, Some(1060));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_admin_socket_commands_and_drain() -> Result<()> {
+    use tokio_chat_server::admin::{self, AdminRequest, AdminResponse};
+
+    let socket = std::env::temp_dir().join(format!("chat-admin-{}.sock", std::process::id()));
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_admin_socket(&socket);
+    let addr = server.local_addr()?.to_string();
+    let running = tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    client.send(ChatMessage::from_raw("avery: hello")?).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+
+    let AdminResponse::Stats { metrics } = admin::request(&socket, &AdminRequest::Stats).await?
+    else {
+        panic!("expected Stats");
+    };
+    assert_eq!(metrics.messages, 1);
+    assert!(metrics.delivery_micros.quantile(0.99).is_some());
+    assert!(metrics.broadcast_lag_micros.count >= metrics.delivery_micros.count);
+    let AdminResponse::Analytics { report } =
+        admin::request(&socket, &AdminRequest::Analytics).await?
+    else {
+        panic!("expected Analytics");
+    };
+    let activity = report.server.last().unwrap();
+    assert_eq!(activity.messages, 1);
+    assert_eq!(activity.active_users, 1);
+    assert_eq!(activity.peak_connections, Some(1));
+    assert_eq!(report.rooms[0].room, "general");
+    let AdminResponse::Connections { connections } =
+        admin::request(&socket, &AdminRequest::Connections).await?
+    else {
+        panic!("expected Connections");
+    };
+    assert_eq!(connections.len(), 1);
+    let response = admin::request(
+        &socket,
+        &AdminRequest::Unban {
+            user: "nobody".to_string(),
+        },
+    )
+    .await?;
+    assert!(matches!(
+        response,
+        AdminResponse::Unbanned { was_banned: false }
+    ));
+
+    let response = admin::request(&socket, &AdminRequest::Drain).await?;
+    assert!(matches!(
+        response,
+        AdminResponse::Disconnected { connections: 1 }
+    ));
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Notice { .. }
+    ));
+    assert!(client.receive().await.is_err());
+    tokio::time::timeout(Duration::from_secs(5), running).await???;
+    assert!(Client::connect(&addr).await.is_err());
+    let _ = std::fs::remove_file(&socket);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_admin_tail_filters_and_redacts() -> Result<()> {
+    use tokio_chat_server::admin::{AdminConnection, AdminRequest, AdminResponse, TailFilter};
+
+    let socket = std::env::temp_dir().join(format!("chat-tail-{}.sock", std::process::id()));
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_admin_socket(&socket);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let mut avery = Client::connect(&addr).await?;
+    let mut blake = Client::connect(&addr).await?;
+    // Wait for the socket to be bound.
+    blake.send(ChatMessage::from_raw("blake: hi")?).await?;
+    blake.receive().await?;
+
+    let mut tail = AdminConnection::connect(&socket).await?;
+    tail.send(&AdminRequest::Tail {
+        filter: TailFilter {
+            user: Some("blake".to_string()),
+            pattern: Some("sec(ret)?".to_string()),
+            redact_content: true,
+            redact_senders: true,
+            ..TailFilter::default()
+        },
+    })
+    .await?;
+    assert!(matches!(tail.receive().await?, AdminResponse::Done));
+    avery
+        .send(ChatMessage::from_raw("avery: a secret")?)
+        .await?;
+    blake.send(ChatMessage::from_raw("blake: nothing")?).await?;
+    blake
+        .send(ChatMessage::from_raw("blake: my secret")?)
+        .await?;
+    let AdminResponse::Message { message } = tail.receive().await? else {
+        panic!("expected Message");
+    };
+    assert_eq!(message.content, "[9 bytes]");
+    assert!(message.sender.starts_with("user-"));
+    assert_ne!(message.sender, "blake");
+
+    let mut invalid = AdminConnection::connect(&socket).await?;
+    invalid
+        .send(&AdminRequest::Tail {
+            filter: TailFilter {
+                pattern: Some("(".to_string()),
+                ..TailFilter::default()
+            },
+        })
+        .await?;
+    assert!(matches!(
+        invalid.receive().await?,
+        AdminResponse::Error { .. }
+    ));
+    let _ = std::fs::remove_file(&socket);
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_admin_export_streams_pages() -> Result<()> {
+    use tokio_chat_server::admin::{AdminConnection, AdminRequest, AdminResponse};
+    use tokio_chat_server::export::ExportFormat;
+    use tokio_chat_server::store::{MemoryStore, MessageStore};
+
+    let store = Arc::new(MemoryStore::new(2000));
+    for id in 1..=1200 {
+        store
+            .append(&ChatMessage {
+                sender: "avery".to_string(),
+                content: format!("message {}", id),
+                id: Some(id),
+                seq: Some(id),
+                room: Some("general".to_string()),
+                timestamp: Some(1000 + id),
+                ..Default::default()
+            })
+            .await?;
+    }
+    let socket = std::env::temp_dir().join(format!("chat-export-{}.sock", std::process::id()));
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store)
+        .with_admin_socket(&socket);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    // Wait for the socket to be bound.
+    let mut client = Client::connect(&addr).await?;
+    client.send(ChatMessage::from_raw("blake: hi")?).await?;
+    client.receive().await?;
+
+    let mut admin = AdminConnection::connect(&socket).await?;
+    admin
+        .send(&AdminRequest::Export {
+            room: None,
+            format: ExportFormat::Csv,
+            since: Some(1101),
+            until: None,
+        })
+        .await?;
+    let mut chunks = Vec::new();
+    loop {
+        match admin.receive().await? {
+            AdminResponse::ExportChunk { data } => chunks.push(data),
+            AdminResponse::Done => break,
+            response => panic!("unexpected {:?}", response),
+        }
+    }
+    assert_eq!(chunks.len(), 3);
+    let csv = chunks.concat();
+    let mut lines = csv.lines();
+    assert_eq!(lines.next(), Some("id,timestamp,room,sender,content"));
+    // 101 to 1200, then the message just sent.
+    assert_eq!(lines.clone().count(), 1101);
+    assert!(lines.next().unwrap().starts_with("101,1101,general,avery,"));
+    assert!(csv.ends_with(",general,blake,hi\n"));
+
+    // The connection takes further requests after an export.
+    admin.send(&AdminRequest::Emoji).await?;
+    assert!(matches!(
+        admin.receive().await?,
+        AdminResponse::Emoji { .. }
+    ));
+    let _ = std::fs::remove_file(&socket);
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn test_admin_dashboard_requires_token() -> Result<()> {
+    use tokio_chat_server::admin::ConnectionInfo;
+    use tokio_chat_server::http::{Admin, serve_admin};
+
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    let admin = Admin {
+        metrics: server.metrics(),
+        dead_letters: server.dead_letters(),
+        api_keys: server.api_keys(),
+        resources: server.resources(),
+        control: server.admin(),
+        token: String::new(),
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    assert!(serve_admin(listener, admin.clone()).await.is_err());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let admin_addr = listener.local_addr()?;
+    tokio::spawn(serve_admin(
+        listener,
+        Admin {
+            token: "letmein".to_string(),
+            ..admin
+        },
+    ));
+    tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+
+    let send = |method: &str, path: &str, token: Option<&str>, body: &str| {
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\n{}Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            method,
+            path,
+            token.map_or(String::new(), |token| format!(
+                "Authorization: Bearer {}\r\n",
+                token
+            )),
+            body.len(),
+            body
+        );
+        async move {
+            let mut stream = tokio::net::TcpStream::connect(admin_addr).await?;
+            stream.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await?;
+            anyhow::Ok(response)
+        }
+    };
+
+    let page = send("GET", "/admin", None, "").await?;
+    assert!(page.starts_with("HTTP/1.1 200") && page.contains("<canvas"));
+    for token in [None, Some("wrong")] {
+        let response = send("GET", "/admin/connections", token, "").await?;
+        assert!(response.starts_with("HTTP/1.1 401"), "{}", response);
+    }
+
+    let response = send("GET", "/admin/connections", Some("letmein"), "").await?;
+    let (_, json) = response.split_once("\r\n\r\n").unwrap();
+    let connections: Vec<ConnectionInfo> = serde_json::from_str(json)?;
+    assert_eq!(connections.len(), 1);
+
+    let body = r#"{"text":"hello from ops"}"#;
+    let response = send("POST", "/admin/notice", Some("letmein"), body).await?;
+    assert!(response.starts_with("HTTP/1.1 204"));
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Notice { text } if text == "hello from ops"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_connection_limits() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "secret")))
+        .with_connection_limits(ConnectionLimits {
+            max_per_ip: Some(2),
+            handshake_timeout: Some(Duration::from_millis(200)),
+        });
+    let addr = server.local_addr()?.to_string();
+    let metrics = server.metrics();
+    tokio::spawn(server.run());
+
+    let mut idle = Client::connect(&addr).await?;
+    let mut signed_in = Client::connect(&addr).await?;
+    signed_in.authenticate("avery", "secret").await?;
+    assert!(matches!(
+        signed_in.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    let mut over = Client::connect(&addr).await?;
+    assert!(over.receive().await.is_err());
+    assert_eq!(metrics.snapshot().rejected_connections, 1);
+
+    // The idle connection is closed at its deadline, and its slot is
+    // freed just after.
+    assert!(idle.receive().await.is_err());
+    assert_eq!(metrics.snapshot().handshake_timeouts, 1);
+    let mut admitted = false;
+    for _ in 0..100 {
+        let mut next = Client::connect(&addr).await?;
+        if next.authenticate("avery", "secret").await.is_ok()
+            && let Ok(ServerFrame::Authenticated { .. }) = next.receive().await
+        {
+            admitted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert!(admitted);
+
+    signed_in
+        .send(ChatMessage::from_raw("x: still here")?)
+        .await?;
+    assert!(matches!(
+        signed_in.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_busy_addresses_are_challenged() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_challenges(ChallengePolicy {
+            difficulty: 8,
+            free_connections: 1,
+            window: Duration::from_secs(60),
+        });
+    let addr = server.local_addr()?.to_string();
+    let metrics = server.metrics();
+    tokio::spawn(server.run());
+
+    let mut first = Client::connect(&addr).await?;
+    first.send(ChatMessage::from_raw("avery: hi")?).await?;
+    assert!(matches!(
+        first.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+
+    let mut second = Client::connect(&addr).await?;
+    let ServerFrame::Challenge { nonce, difficulty } = second.receive().await? else {
+        panic!("expected a Challenge");
+    };
+    assert_eq!(difficulty, 8);
+    second.send(ChatMessage::from_raw("blake: hi")?).await?;
+    assert!(
+        matches!(second.receive().await?, ServerFrame::Error { message } if message == "Solve the challenge first")
+    );
+    second.answer_challenge(&nonce, difficulty).await?;
+    assert_eq!(second.receive().await?, ServerFrame::ChallengeAccepted);
+    second.send(ChatMessage::from_raw("blake: hi")?).await?;
+    assert!(matches!(
+        second.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+    assert_eq!(metrics.snapshot().challenges, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_registered_nickname_requires_identify() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut owner = Client::connect(&addr).await?;
+    owner
+        .send(ChatMessage::from_raw("avery: /register avery hunter2")?)
+        .await?;
+    assert!(
+        matches!(owner.receive().await?, ServerFrame::Authenticated { user, .. } if user == "avery")
+    );
+
+    let mut other = Client::connect(&addr).await?;
+    other
+        .send(ChatMessage::from_raw("avery: it's me, honest")?)
+        .await?;
+    assert!(matches!(other.receive().await?, ServerFrame::Error { .. }));
+    other
+        .send(ChatMessage::from_raw("x: /register avery again")?)
+        .await?;
+    assert!(matches!(other.receive().await?, ServerFrame::Error { .. }));
+    other
+        .send(ChatMessage::from_raw("x: /identify avery wrong")?)
+        .await?;
+    assert!(matches!(other.receive().await?, ServerFrame::Error { .. }));
+
+    // Reconnecting owner identifies and keeps the nickname.
+    other
+        .send(ChatMessage::from_raw("x: /identify avery hunter2")?)
+        .await?;
+    assert!(matches!(
+        other.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    other.send(ChatMessage::from_raw("x: back again")?).await?;
+    let ServerFrame::Message { message, .. } = other.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.sender, "avery");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_registered_nicknames_survive_a_restart() -> Result<()> {
+    use tokio_chat_server::store::MemoryStore;
+
+    let store = Arc::new(MemoryStore::default());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone());
+    let (addr, admin) = (server.local_addr()?.to_string(), server.admin());
+    let running = tokio::spawn(server.run());
+    let mut owner = Client::connect(&addr).await?;
+    owner
+        .send(ChatMessage::from_raw("avery: /register avery hunter2")?)
+        .await?;
+    assert!(matches!(
+        owner.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    admin.drain()?;
+    timeout(Duration::from_secs(10), running).await???;
+
+    // No snapshot: the registration comes back from the store.
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let mut other = Client::connect(&addr).await?;
+    other
+        .send(ChatMessage::from_raw("x: /register avery stolen")?)
+        .await?;
+    assert!(matches!(
+        other.receive().await?,
+        ServerFrame::Error { message } if message.contains("taken")
+    ));
+    other
+        .send(ChatMessage::from_raw("x: /identify avery hunter2")?)
+        .await?;
+    assert!(matches!(
+        other.receive().await?,
+        ServerFrame::Authenticated { user, .. } if user == "avery"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rooms_survive_a_restart() -> Result<()> {
+    use tokio_chat_server::store::{MemoryStore, MessageStore};
+
+    let store = Arc::new(MemoryStore::default());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone());
+    let (addr, admin) = (server.local_addr()?.to_string(), server.admin());
+    let running = tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    for command in ["/create plans", "/topic plans releases"] {
+        client
+            .send(ChatMessage::from_raw(&format!("avery: {}", command))?)
+            .await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::RoomUpdated { .. }
+        ));
+    }
+    admin.drain()?;
+    timeout(Duration::from_secs(10), running).await???;
+    let rooms = store.rooms().await?;
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(rooms[0].1.topic.as_deref(), Some("releases"));
+
+    // No snapshot: the room comes back from the store.
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    client
+        .send(ChatMessage::from_raw("blake: /create plans")?)
+        .await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Error { message } if message.contains("already exists")
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_confusable_nicknames_are_rejected() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut owner = Client::connect(&addr).await?;
+    owner
+        .send(ChatMessage::from_raw("x: /register admin hunter2")?)
+        .await?;
+    assert!(matches!(
+        owner.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+
+    let mut impostor = Client::connect(&addr).await?;
+    impostor
+        .send(ChatMessage::from_raw("x: /register \u{430}dmin hunter2")?)
+        .await?;
+    assert_eq!(
+        impostor.receive().await?,
+        ServerFrame::NicknameConflict {
+            nick: "\u{430}dmin".to_string(),
+            conflicts_with: "admin".to_string(),
+        }
+    );
+
+    // Fullwidth letters are normalized to the nickname they spell.
+    impostor
+        .send(ChatMessage::from_raw("x: /identify ａｄｍｉｎ hunter2")?)
+        .await?;
+    assert!(
+        matches!(impostor.receive().await?, ServerFrame::Authenticated { user, .. } if user == "admin")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_formatting_entities_are_relayed_and_checked() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let message = ChatMessage::builder()
+        .sender("avery")
+        .content("ping @blake about `cargo test`")
+        .format(TextFormat::Markdown)
+        .entity(
+            5,
+            6,
+            EntityKind::Mention {
+                user: "blake".to_string(),
+            },
+        )
+        .entity(18, 12, EntityKind::Code)
+        .build()?;
+    client.send(message.clone()).await?;
+    let ServerFrame::Message {
+        message: relayed, ..
+    } = client.receive().await?
+    else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(relayed.format, TextFormat::Markdown);
+    assert_eq!(relayed.entities, message.entities);
+    assert_eq!(
+        relayed.entities[1].text(&relayed.content),
+        Some("`cargo test`")
+    );
+
+    // Skipping the builder doesn't get a bad span past the server.
+    let mut unchecked = message;
+    unchecked.entities.push(Entity {
+        offset: 28,
+        length: 10,
+        kind: EntityKind::Bold,
+    });
+    client.send(unchecked).await?;
+    assert_eq!(
+        client.receive().await?,
+        ServerFrame::Error {
+            message: "Entity 2 is outside the content".to_string(),
+        }
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_links_are_previewed_once() -> Result<()> {
+    let pages = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let page_addr = pages.local_addr()?;
+    let fetches = Arc::new(AtomicUsize::new(0));
+    let counted = fetches.clone();
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = pages.accept().await {
+            counted.fetch_add(1, Ordering::SeqCst);
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = socket.read(&mut buffer).await?;
+                anyhow::ensure!(n > 0, "request ended early");
+                request.extend_from_slice(&buffer[..n]);
+            }
+            socket
+                .write_all(
+                    b"HTTP/1.0 200 OK\r\nContent-Type: text/html\r\n\r\n\
+                      <title>Release notes</title><meta name=description content=\"What's new\">",
+                )
+                .await?;
+        }
+        Ok::<_, anyhow::Error>(())
+    });
+    let server = ChatServer::new("127.0.0.1:0").await?.with_link_previews(
+        Arc::new(HttpFetcher),
+        PreviewPolicy {
+            allow_private: true,
+            ..PreviewPolicy::default()
+        },
+    );
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    for _ in 0..2 {
+        let content = format!("avery: see http://{}/notes.", page_addr);
+        client.send(ChatMessage::from_raw(&content)?).await?;
+        let ServerFrame::Message { message, .. } = client.receive().await? else {
+            panic!("expected the relayed message");
+        };
+        let ServerFrame::LinkPreview {
+            room,
+            message_id,
+            preview,
+        } = client.receive().await?
+        else {
+            panic!("expected a link preview");
+        };
+        assert_eq!((room.as_str(), Some(message_id)), ("general", message.id));
+        assert_eq!(preview.url, format!("http://{}/notes", page_addr));
+        assert_eq!(preview.title.as_deref(), Some("Release notes"));
+        assert_eq!(preview.description.as_deref(), Some("What's new"));
+    }
+    assert_eq!(fetches.load(Ordering::SeqCst), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_messages_are_sanitized_before_broadcast() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_sanitize_policy(SanitizePolicy {
+            strictness: Strictness::Strict,
+            ..SanitizePolicy::default()
+        });
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let mut message = ChatMessage::builder()
+        .sender("avery")
+        .content("\u{1b}]0;owned\u{7}\u{1b}[2Jhel\u{200b}lo\u{1b}[0m")
+        .build()?;
+    client.send(message.clone()).await?;
+    let ServerFrame::Message {
+        message: relayed, ..
+    } = client.receive().await?
+    else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(relayed.content, "hello");
+
+    client
+        .set_profile(
+            Some("ad\u{202e}nimda".to_string()),
+            Some("\u{1b}[5mbusy".to_string()),
+            None,
+        )
+        .await?;
+    let ServerFrame::PresenceChanged(presence) = client.receive().await? else {
+        panic!("expected PresenceChanged");
+    };
+    assert_eq!(presence.profile.display_name.as_deref(), Some("adnimda"));
+    assert_eq!(presence.profile.status_text.as_deref(), Some("busy"));
+
+    // Commands are cleaned too.
+    message.content = "/create \u{1b}[1mdev".to_string();
+    client.send(message).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::RoomUpdated { room, .. } if room == "dev"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_session_resumes_after_reconnect() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_guests(GuestPolicy::default());
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let ServerFrame::Welcome { user: guest, .. } = client.receive().await? else {
+        panic!("expected Welcome");
+    };
+    client
+        .set_profile(None, Some("brb".to_string()), Some(PresenceState::Away))
+        .await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::PresenceChanged(_)
+    ));
+
+    let mut other = Client::connect(&addr).await?;
+    other.receive().await?; // Welcome
+    client.reconnect().await?;
+    other
+        .send(ChatMessage::from_raw("x: while you were out")?)
+        .await?;
+
+    // The new connection is greeted as a fresh guest before resuming.
+    let resumed = loop {
+        match client.receive().await? {
+            ServerFrame::SessionResumed { user, .. } => break user,
+            ServerFrame::Welcome { .. } | ServerFrame::Message { .. } => continue,
+            frame => panic!("unexpected {:?}", frame),
+        }
+    };
+    assert_eq!(resumed, guest);
+    loop {
+        match client.receive().await? {
+            ServerFrame::Replay { .. } | ServerFrame::Message { .. } => {}
+            frame => panic!("unexpected {:?}", frame),
+        }
+        if client
+            .cached_messages()
+            .any(|message| message.content == "while you were out")
+        {
+            break;
+        }
+    }
+
+    client.send_frame(&ClientFrame::Presence).await?;
+    // The replay may still be on its way if the live broadcast came first.
+    let users = loop {
+        match client.receive_frame().await? {
+            ServerFrame::Presence { users } => break users,
+            ServerFrame::Replay { .. } => continue,
+            frame => panic!("unexpected {:?}", frame),
+        }
+    };
+    assert!(
+        users
+            .iter()
+            .any(|user| user.profile.state == PresenceState::Away)
+    );
+    client.send(ChatMessage::from_raw("x: back")?).await?;
+    let ServerFrame::Message { message, .. } = client.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.sender, guest);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_on_multiple_devices() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "secret")));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut phone = Client::connect(&addr).await?;
+    let mut laptop = Client::connect(&addr).await?;
+    for device in [&mut phone, &mut laptop] {
+        device.authenticate("avery", "secret").await?;
+        assert!(matches!(
+            device.receive().await?,
+            ServerFrame::Authenticated { .. }
+        ));
+    }
+
+    phone
+        .set_profile(None, None, Some(PresenceState::Away))
+        .await?;
+    let ServerFrame::PresenceChanged(presence) = laptop.receive().await? else {
+        panic!("expected PresenceChanged");
+    };
+    // The laptop is still online, so the user is.
+    assert_eq!(presence.user, "avery");
+    assert_eq!(presence.devices, 2);
+    assert_eq!(presence.profile.state, PresenceState::Online);
+    assert!(matches!(
+        phone.receive().await?,
+        ServerFrame::PresenceChanged(_)
+    ));
+
+    phone
+        .send(ChatMessage::from_raw("x: from my phone")?)
+        .await?;
+    for device in [&mut phone, &mut laptop] {
+        let ServerFrame::Message { message, .. } = device.receive().await? else {
+            panic!("expected the relayed message");
+        };
+        assert_eq!(message.sender, "avery");
+    }
+    Ok(())
+}
+
+/// Sends "@user ..." to that user alone and drops anything mentioning spam.
+struct MentionRouter;
+
+#[async_trait]
+impl Router for MentionRouter {
+    async fn route(&self, _from: SocketAddr, message: ChatMessage) -> Route {
+        if message.content.contains("spam") {
+            return Route::Drop(Some("No spam".to_string()));
+        }
+        match message.content.strip_prefix('@') {
+            Some(rest) => Route::Direct {
+                to: rest.split(' ').next().unwrap_or_default().to_string(),
+                message,
+            },
+            None => Route::Broadcast(message),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_router_directs_and_drops_messages() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b")
+                .with_user("casey", "c"),
+        ))
+        .with_router(Arc::new(MentionRouter));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut clients = Vec::new();
+    for (user, token) in [("avery", "a"), ("blake", "b"), ("casey", "c")] {
+        let mut client = Client::connect(&addr).await?;
+        client.authenticate(user, token).await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Authenticated { .. }
+        ));
+        clients.push(client);
+    }
+    let [avery, blake, casey] = &mut clients[..] else {
+        unreachable!();
+    };
+
+    avery.send(ChatMessage::from_raw("x: buy spam")?).await?;
+    assert!(
+        matches!(avery.receive().await?, ServerFrame::Error { message } if message == "No spam")
+    );
+    avery.send(ChatMessage::from_raw("x: @nobody hi")?).await?;
+    assert!(matches!(avery.receive().await?, ServerFrame::Error { .. }));
+
+    avery.send(ChatMessage::from_raw("x: @blake psst")?).await?;
+    for client in [&mut *avery, &mut *blake] {
+        let ServerFrame::Message { message, .. } = client.receive().await? else {
+            panic!("expected the direct message");
+        };
+        assert_eq!(message.content, "@blake psst");
+        assert_eq!(message.seq, None);
+    }
+
+    avery.send(ChatMessage::from_raw("x: hello all")?).await?;
+    for client in [avery, blake, casey] {
+        let ServerFrame::Message { message, .. } = client.receive().await? else {
+            panic!("expected the broadcast");
+        };
+        assert_eq!(message.content, "hello all");
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_notification_prefs_follow_the_user() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "a")));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut devices = Vec::new();
+    for _ in 0..2 {
+        let mut device = Client::connect(&addr).await?;
+        device.authenticate("avery", "a").await?;
+        assert!(matches!(
+            device.receive().await?,
+            ServerFrame::Authenticated { notifications, .. }
+                if notifications == NotificationPrefs::default()
+        ));
+        devices.push(device);
+    }
+
+    let prefs = NotificationPrefs {
+        quiet_hours: Some(QuietHours {
+            start: 22 * 60,
+            end: 7 * 60,
+            utc_offset: 60,
+        }),
+        muted_rooms: ["random".to_string()].into(),
+        mention_only: true,
+        ..Default::default()
+    };
+    let bad = NotificationPrefs {
+        quiet_hours: Some(QuietHours {
+            start: 25 * 60,
+            end: 0,
+            utc_offset: 0,
+        }),
+        ..Default::default()
+    };
+    devices[0]
+        .send_frame(&ClientFrame::SetNotificationPrefs { prefs: bad })
+        .await?;
+    assert!(matches!(
+        devices[0].receive().await?,
+        ServerFrame::Error { .. }
+    ));
+    devices[0]
+        .send_frame(&ClientFrame::SetNotificationPrefs {
+            prefs: prefs.clone(),
+        })
+        .await?;
+    for device in &mut devices {
+        assert!(matches!(
+            device.receive().await?,
+            ServerFrame::NotificationPrefs { prefs: synced } if synced == prefs
+        ));
+    }
+
+    let mut phone = Client::connect(&addr).await?;
+    phone.authenticate("avery", "a").await?;
+    assert!(matches!(
+        phone.receive().await?,
+        ServerFrame::Authenticated { notifications, .. } if notifications == prefs
+    ));
+    Ok(())
+}
+
+/// Hands every notification to a channel.
+struct ChannelPush(tokio::sync::mpsc::UnboundedSender<(String, PushNotification)>);
+
+#[async_trait]
+impl PushProvider for ChannelPush {
+    async fn send(&self, token: &str, notification: &PushNotification) -> Result<Delivery> {
+        let _ = self.0.send((token.to_string(), notification.clone()));
+        Ok(Delivery::Sent)
+    }
+}
+
+#[tokio::test]
+async fn test_offline_and_do_not_disturb_users_get_pushes() -> Result<()> {
+    let (pushes, mut pushed) = tokio::sync::mpsc::unbounded_channel();
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b"),
+        ))
+        .with_push(
+            PushGateway::new().with_provider(PushPlatform::Fcm, Arc::new(ChannelPush(pushes))),
+        );
+    let addr = server.local_addr()?.to_string();
+    let admin = server.admin();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a").await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    for command in ["/create plans", "/invite blake plans"] {
+        avery
+            .send(ChatMessage::from_raw(&format!("x: {}", command))?)
+            .await?;
+        assert!(matches!(
+            avery.receive().await?,
+            ServerFrame::RoomUpdated { .. }
+        ));
+    }
+    let post = |content: &str| {
+        ChatMessage::builder()
+            .sender("avery")
+            .content(content)
+            .room("plans")
+            .build()
+    };
+
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    blake
+        .send_frame(&ClientFrame::RegisterPushToken {
+            push: PushToken {
+                platform: PushPlatform::Apns,
+                token: "apns-token".to_string(),
+            },
+        })
+        .await?;
+    assert!(matches!(blake.receive().await?, ServerFrame::Error { .. }));
+    blake
+        .send_frame(&ClientFrame::RegisterPushToken {
+            push: PushToken {
+                platform: PushPlatform::Fcm,
+                token: "fcm-token".to_string(),
+            },
+        })
+        .await?;
+    // Online and available: no push.
+    avery.send(post("lunch?")?).await?;
+    for client in [&mut avery, &mut blake] {
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Message { .. }
+        ));
+    }
+
+    // In do-not-disturb, only mentions get through, and only if allowed.
+    blake
+        .send_frame(&ClientFrame::SetNotificationPrefs {
+            prefs: NotificationPrefs {
+                mentions_break_quiet_hours: true,
+                ..Default::default()
+            },
+        })
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::NotificationPrefs { .. }
+    ));
+    blake
+        .set_profile(None, None, Some(PresenceState::Dnd))
+        .await?;
+    for client in [&mut avery, &mut blake] {
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::PresenceChanged(_)
+        ));
+    }
+    avery.send(post("still on for later")?).await?;
+    avery.send(post("@blake need you")?).await?;
+    let (token, notification) = timeout(Duration::from_secs(5), pushed.recv())
+        .await?
+        .unwrap();
+    assert_eq!(token, "fcm-token");
+    assert_eq!(notification.body, "@blake need you");
+
+    drop(blake);
+    while admin.connections().len() > 1 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    avery.send(post("see you tomorrow")?).await?;
+    let (_, notification) = timeout(Duration::from_secs(5), pushed.recv())
+        .await?
+        .unwrap();
+    assert_eq!(
+        (notification.user.as_str(), notification.title.as_str()),
+        ("blake", "avery in #plans")
+    );
+    assert_eq!(notification.body, "see you tomorrow");
+    Ok(())
+}
+
+/// Hands every email to a channel.
+struct ChannelMailer(tokio::sync::mpsc::UnboundedSender<(String, String, String)>);
+
+#[async_trait]
+impl Mailer for ChannelMailer {
+    async fn send(&self, to: &str, subject: &str, body: &str) -> Result<()> {
+        let _ = self
+            .0
+            .send((to.to_string(), subject.to_string(), body.to_string()));
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_missed_mentions_are_emailed_in_a_digest() -> Result<()> {
+    let (mailer, mut mailed) = tokio::sync::mpsc::unbounded_channel();
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b")
+                .with_user("casey", "c"),
+        ))
+        .with_router(Arc::new(MentionRouter))
+        .with_email_digests(Arc::new(ChannelMailer(mailer)), DigestPolicy::default());
+    let addr = server.local_addr()?.to_string();
+    let admin = server.admin();
+    let digests = server.digests().unwrap();
+    tokio::spawn(server.run());
+
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    blake
+        .send_frame(&ClientFrame::SetNotificationPrefs {
+            prefs: NotificationPrefs {
+                email_digest: Some(EmailDigest {
+                    address: "blake@example.com".to_string(),
+                    frequency: DigestFrequency::Hourly,
+                }),
+                ..Default::default()
+            },
+        })
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::NotificationPrefs { .. }
+    ));
+
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a").await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    // Blake moderates as well as being a member, but is emailed once.
+    for command in ["/create plans", "/invite blake plans", "/mod plans blake"] {
+        avery
+            .send(ChatMessage::from_raw(&format!("x: {}", command))?)
+            .await?;
+        assert!(matches!(
+            avery.receive().await?,
+            ServerFrame::RoomUpdated { .. }
+        ));
+    }
+    drop(blake);
+    while admin.connections().len() > 1 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let post = |content: &str| {
+        ChatMessage::builder()
+            .sender("avery")
+            .content(content)
+            .room("plans")
+            .build()
+    };
+    for content in ["lunch?", "ping @blake"] {
+        avery.send(post(content)?).await?;
+        assert!(matches!(
+            avery.receive().await?,
+            ServerFrame::Message { .. }
+        ));
+    }
+    // Offline, but opted in: accepted for the digest.
+    avery
+        .send(ChatMessage::from_raw("avery: @blake call me")?)
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Message { message, .. } if message.content == "@blake call me"
+    ));
+    avery
+        .send(ChatMessage::from_raw("avery: @casey call me")?)
+        .await?;
+    assert!(matches!(avery.receive().await?, ServerFrame::Error { .. }));
+
+    let later = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs()
+        + 3600;
+    assert_eq!(digests.send_due(later).await, 1);
+    let (to, subject, body) = mailed.recv().await.unwrap();
+    assert_eq!(to, "blake@example.com");
+    assert_eq!(subject, "2 missed messages");
+    assert_eq!(body, "#plans avery: ping @blake\navery: @blake call me\n");
+
+    // Signing back in clears what was missed.
+    avery.send(post("@blake again")?).await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    while !matches!(blake.receive().await?, ServerFrame::Authenticated { .. }) {}
+    assert_eq!(digests.send_due(later + 3600).await, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_presence_subscriptions_filter_changes() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b")
+                .with_user("casey", "c"),
+        ));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut clients = Vec::new();
+    for (user, token) in [("avery", "a"), ("blake", "b"), ("casey", "c")] {
+        let mut client = Client::connect(&addr).await?;
+        client.authenticate(user, token).await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Authenticated { .. }
+        ));
+        clients.push(client);
+    }
+    let [avery, blake, casey] = &mut clients[..] else {
+        unreachable!();
+    };
+
+    avery
+        .send_frame(&ClientFrame::SubscribePresence {
+            users: vec!["blake".to_string(), "dana".to_string()],
+            rooms: Vec::new(),
+        })
+        .await?;
+    match avery.receive().await? {
+        ServerFrame::PresenceSubscribed {
+            subscription: Some(subscription),
+            users,
+        } => {
+            assert_eq!(subscription.users.len(), 2);
+            let online: Vec<_> = users
+                .iter()
+                .map(|presence| presence.user.as_str())
+                .collect();
+            assert_eq!(online, vec!["blake"]);
+        }
+        other => panic!("Expected PresenceSubscribed, got {:?}", other),
+    }
+
+    // Casey isn't followed, so only blake's change reaches avery.
+    casey
+        .set_profile(None, Some("lunch".to_string()), None)
+        .await?;
+    assert!(matches!(
+        casey.receive().await?,
+        ServerFrame::PresenceChanged(presence) if presence.user == "casey"
+    ));
+    blake
+        .set_profile(None, None, Some(PresenceState::Away))
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::PresenceChanged(presence) if presence.user == "blake"
+    ));
+
+    // Unsubscribing from nothing in particular follows everyone again.
+    avery
+        .send_frame(&ClientFrame::UnsubscribePresence {
+            users: Vec::new(),
+            rooms: Vec::new(),
+        })
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::PresenceSubscribed { subscription: None, users } if users.len() == 3
+    ));
+    casey.set_profile(None, Some(String::new()), None).await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::PresenceChanged(presence) if presence.user == "casey"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_large_rooms_send_member_deltas() -> Result<()> {
+    let town = RoomConfig {
+        members: (0..150).map(|i| format!("resident{:03}", i)).collect(),
+        permissions: RoomPermissions {
+            invite: Role::Everyone,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_room("town", town);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client
+        .send(ChatMessage::from_raw("avery: /join town")?)
+        .await?;
+    match client.receive().await? {
+        ServerFrame::MemberDelta {
+            room,
+            added,
+            removed,
+            member_count,
+        } => {
+            assert_eq!(room, "town");
+            assert_eq!(added, vec!["avery".to_string()]);
+            assert!(removed.is_empty());
+            assert_eq!(member_count, 151);
+        }
+        other => panic!("Expected MemberDelta, got {:?}", other),
+    }
+
+    // Leaving is sent as a delta too.
+    client
+        .send(ChatMessage::from_raw("avery: /leave town")?)
+        .await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::MemberDelta { removed, member_count: 150, .. } if removed == ["avery"]
+    ));
+
+    let mut after = None;
+    let mut pages = Vec::new();
+    loop {
+        client
+            .send_frame(&ClientFrame::ListMembers {
+                room: "town".to_string(),
+                after: after.clone(),
+                limit: Some(100),
+            })
+            .await?;
+        let ServerFrame::Members {
+            members, has_more, ..
+        } = client.receive().await?
+        else {
+            panic!("Expected Members");
+        };
+        pages.push(members.len());
+        after = members.last().cloned();
+        if !has_more {
+            break;
+        }
+    }
+    assert_eq!(pages, vec![100, 50]);
+    assert_eq!(after.as_deref(), Some("resident149"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_room_directory_lists_public_rooms() -> Result<()> {
+    let open = |topic: &str| RoomConfig {
+        topic: Some(topic.to_string()),
+        permissions: RoomPermissions {
+            invite: Role::Everyone,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_room("dev", open("Rust builds"))
+        .with_room("ops", open("Pager duty"))
+        .with_room("secret", RoomConfig::default());
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let post = ChatMessage::builder()
+        .sender("avery")
+        .content("anyone up?")
+        .room("ops")
+        .build()?;
+    client.send(post).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+
+    client
+        .send_frame(&ClientFrame::ListRooms {
+            filter: None,
+            page: 0,
+        })
+        .await?;
+    let ServerFrame::Rooms {
+        rooms,
+        total,
+        has_more,
+        ..
+    } = client.receive().await?
+    else {
+        panic!("Expected Rooms");
+    };
+    let names: Vec<_> = rooms.iter().map(|listing| listing.room.as_str()).collect();
+    assert_eq!(names, vec!["ops", "dev"]);
+    assert_eq!((total, has_more), (2, false));
+    assert_eq!(rooms[0].activity, ActivityLevel::Quiet);
+    assert_eq!(rooms[1].activity, ActivityLevel::Idle);
+
+    client
+        .send_frame(&ClientFrame::ListRooms {
+            filter: Some("RUST".to_string()),
+            page: 0,
+        })
+        .await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Rooms { rooms, .. } if rooms.len() == 1 && rooms[0].room == "dev"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_invites_admit_to_invite_only_rooms() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b")
+                .with_user("casey", "c"),
+        ))
+        .with_room(
+            "plans",
+            RoomConfig {
+                owner: Some("avery".to_string()),
+                ..Default::default()
+            },
+        );
+    let addr = server.local_addr()?.to_string();
+    #[cfg(feature = "http")]
+    let (control, api_keys) = (server.admin(), server.api_keys());
+    tokio::spawn(server.run());
+
+    let mut clients = Vec::new();
+    for (user, token) in [("avery", "a"), ("blake", "b"), ("casey", "c")] {
+        let mut client = Client::connect(&addr).await?;
+        client.authenticate(user, token).await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Authenticated { .. }
+        ));
+        clients.push(client);
+    }
+
+    clients[1]
+        .send_frame(&ClientFrame::CreateInvite {
+            room: "plans".to_string(),
+            expires_in_secs: None,
+            max_uses: None,
+        })
+        .await?;
+    assert!(matches!(
+        clients[1].receive().await?,
+        ServerFrame::Error { .. }
+    ));
+
+    clients[0]
+        .send_frame(&ClientFrame::CreateInvite {
+            room: "plans".to_string(),
+            expires_in_secs: Some(3600),
+            max_uses: Some(1),
+        })
+        .await?;
+    let ServerFrame::InviteCreated { invite } = clients[0].receive().await? else {
+        panic!("Expected InviteCreated");
+    };
+    assert_eq!(invite.max_uses, Some(1));
+
+    let redeem = ClientFrame::RedeemInvite {
+        token: invite.token,
+    };
+    clients[1].send_frame(&redeem).await?;
+    loop {
+        match clients[1].receive().await? {
+            ServerFrame::InviteRedeemed { room } => {
+                assert_eq!(room, "plans");
+                break;
+            }
+            ServerFrame::RoomUpdated { .. } => {}
+            frame => panic!("Unexpected frame: {:?}", frame),
+        }
+    }
+    let members = loop {
+        if let ServerFrame::RoomUpdated { config, .. } = clients[0].receive().await? {
+            break config.members;
+        }
+    };
+    assert!(members.contains("blake"));
+
+    clients[2].send_frame(&redeem).await?;
+    loop {
+        match clients[2].receive().await? {
+            ServerFrame::Error { message, .. } => {
+                assert!(message.contains("used up"));
+                break;
+            }
+            ServerFrame::RoomUpdated { .. } => {}
+            frame => panic!("Unexpected frame: {:?}", frame),
+        }
+    }
+
+    #[cfg(feature = "http")]
+    {
+        use tokio_chat_server::http::{Admin, RedeemInviteResponse, serve_admin};
+
+        let ClientFrame::RedeemInvite { token: used_up } = redeem else {
+            unreachable!();
+        };
+        clients[0]
+            .send_frame(&ClientFrame::CreateInvite {
+                room: "plans".to_string(),
+                expires_in_secs: None,
+                max_uses: None,
+            })
+            .await?;
+        let token = loop {
+            if let ServerFrame::InviteCreated { invite } = clients[0].receive().await? {
+                break invite.token;
+            }
+        };
+        let casey = api_keys.issue("casey", Default::default(), 0);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let admin_addr = listener.local_addr()?;
+        tokio::spawn(serve_admin(
+            listener,
+            Admin {
+                metrics: Default::default(),
+                dead_letters: Default::default(),
+                api_keys,
+                resources: Default::default(),
+                control,
+                token: "operator".to_string(),
+            },
+        ));
+        let post = |token: &str, key: &str| {
+            let request = format!(
+                "POST /invites/{} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                token, key
+            );
+            async move {
+                let mut stream = tokio::net::TcpStream::connect(admin_addr).await?;
+                stream.write_all(request.as_bytes()).await?;
+                let mut response = String::new();
+                stream.read_to_string(&mut response).await?;
+                anyhow::Ok(response)
+            }
+        };
+
+        assert!(post(&token, "not.a-key").await?.starts_with("HTTP/1.1 401"));
+        // Creating the second invite pruned the used-up one.
+        assert!(
+            post(&used_up, &casey.key)
+                .await?
+                .starts_with("HTTP/1.1 404")
+        );
+        let response = post(&token, &casey.key).await?;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        let (_, json) = response.split_once("\r\n\r\n").unwrap();
+        let redeemed: RedeemInviteResponse = serde_json::from_str(json)?;
+        assert_eq!(redeemed.room, "plans");
+        let members = loop {
+            if let ServerFrame::RoomUpdated { config, .. } = clients[0].receive().await? {
+                break config.members;
+            }
+        };
+        assert!(members.contains("casey"));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_temporary_rooms_expire_with_their_history() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_room(
+            "huddle",
+            RoomConfig {
+                owner: Some("avery".to_string()),
+                ..Default::default()
+            },
+        )
+        .with_room_expiry_interval(Duration::from_millis(50));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let post = ChatMessage::builder()
+        .sender("avery")
+        .content("quick sync?")
+        .room("huddle")
+        .build()?;
+    client.send(post).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+
+    client
+        .send(ChatMessage::from_raw("avery: /expire huddle idle 3600")?)
+        .await?;
+    let ServerFrame::RoomUpdated { config, .. } = client.receive().await? else {
+        panic!("Expected RoomUpdated");
+    };
+    assert_eq!(
+        config.expiry.and_then(|expiry| expiry.idle_secs),
+        Some(3600)
+    );
+    tokio::time::sleep(Duration::from_millis(150)).await;
+
+    client
+        .send(ChatMessage::from_raw("avery: /expire huddle after 0")?)
+        .await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::RoomUpdated { .. }
+    ));
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::RoomExpired { room } if room == "huddle"
+    ));
+
+    client
+        .send_frame(&ClientFrame::FetchHistory {
+            room: Some("huddle".to_string()),
+            before: None,
+            limit: None,
+        })
+        .await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::History { messages, .. } if messages.is_empty()
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forwarded_messages_keep_their_origin() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b"),
+        ))
+        .with_room(
+            "news",
+            RoomConfig {
+                owner: Some("avery".to_string()),
+                permissions: RoomPermissions {
+                    post: Role::Moderators,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        )
+        .with_room(
+            "staff",
+            RoomConfig {
+                owner: Some("avery".to_string()),
+                permissions: RoomPermissions {
+                    read: Role::Members,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a").await?;
+    avery.receive().await?;
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    blake.receive().await?;
+
+    // Blake can't read staff, so can't forward out of it either.
+    let mut memo = ChatMessage::from_raw("avery: staff only")?;
+    memo.room = Some("staff".to_string());
+    avery.send(memo).await?;
+    let ServerFrame::Message { message: memo, .. } = avery.receive().await? else {
+        panic!("Expected the staff message");
+    };
+    blake
+        .send_frame(&ClientFrame::Forward {
+            message_id: memo.id.unwrap(),
+            to_room: "general".to_string(),
+        })
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Error { message } if message.starts_with("Unknown message")
+    ));
+
+    avery
+        .send(ChatMessage::from_raw("avery: release is out")?)
+        .await?;
+    let ServerFrame::Message { message, .. } = blake.receive().await? else {
+        panic!("Expected the original message");
+    };
+    let original = message;
+
+    blake
+        .send_frame(&ClientFrame::Forward {
+            message_id: original.id.unwrap(),
+            to_room: "ops\u{1b}[2J".to_string(),
+        })
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Error { message } if message.starts_with("Room name contains invalid")
+    ));
+    blake
+        .send_frame(&ClientFrame::Forward {
+            message_id: original.id.unwrap(),
+            to_room: "ops".to_string(),
+        })
+        .await?;
+    let ServerFrame::Message { message, .. } = blake.receive().await? else {
+        panic!("Expected the forwarded message");
+    };
+    assert_eq!(message.sender, "blake");
+    assert_eq!(message.room.as_deref(), Some("ops"));
+    assert_eq!(message.content, original.content);
+    let forwarded = message.forwarded.clone().unwrap();
+    assert_eq!(forwarded.message_id, original.id.unwrap());
+    assert_eq!(forwarded.room, "general");
+    assert_eq!(forwarded.sender, "avery");
+    assert_eq!(forwarded.timestamp, original.timestamp);
+
+    // A forward of a forward still points at the original.
+    blake
+        .send_frame(&ClientFrame::Forward {
+            message_id: message.id.unwrap(),
+            to_room: "general".to_string(),
+        })
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Message { message, .. } if message.forwarded == Some(forwarded.clone())
+    ));
+
+    blake
+        .send_frame(&ClientFrame::Forward {
+            message_id: original.id.unwrap(),
+            to_room: "news".to_string(),
+        })
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Error { message } if message.contains("news")
+    ));
+    blake
+        .send_frame(&ClientFrame::Forward {
+            message_id: 999,
+            to_room: "ops".to_string(),
+        })
+        .await?;
+    assert!(matches!(blake.receive().await?, ServerFrame::Error { .. }));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_frames_are_downgraded_to_client_capabilities() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b"),
+        ));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a").await?;
+    avery.receive().await?;
+    let mut blake = Client::builder(&addr)
+        .capabilities([Capability::Attachments])
+        .connect()
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::CapabilitiesSelected { capabilities }
+            if capabilities.iter().eq(&[Capability::Attachments])
+    ));
+    blake.authenticate("blake", "b").await?;
+    blake.receive().await?;
+
+    avery
+        .send(
+            ChatMessage::builder()
+                .sender("avery")
+                .content("ship **it**")
+                .format(TextFormat::Markdown)
+                .entity(5, 6, EntityKind::Bold)
+                .build()?,
+        )
+        .await?;
+    let ServerFrame::Message { message, .. } = avery.receive().await? else {
+        panic!("Expected the formatted message");
+    };
+    assert_eq!(message.format, TextFormat::Markdown);
+    assert_eq!(message.entities.len(), 1);
+    let ServerFrame::Message { message, .. } = blake.receive().await? else {
+        panic!("Expected the plain message");
+    };
+    assert_eq!(message.content, "ship **it**");
+    assert_eq!(message.format, TextFormat::Plain);
+    assert!(message.entities.is_empty());
+
+    avery
+        .send_frame(&ClientFrame::Forward {
+            message_id: message.id.unwrap(),
+            to_room: "general".to_string(),
+        })
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Message { message, .. } if message.forwarded.is_some()
+    ));
+    let ServerFrame::Message { message, .. } = blake.receive().await? else {
+        panic!("Expected the forwarded message");
+    };
+    assert_eq!(message.forwarded, None);
+    assert_eq!(
+        message.content,
+        "[Forwarded from avery in general] ship **it**"
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_message_pack_is_negotiated_per_connection() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    let mut blake = Client::builder(&addr)
+        .capabilities([Capability::Formatting, Capability::MessagePack])
+        .connect()
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::CapabilitiesSelected { capabilities }
+            if capabilities.contains(&Capability::MessagePack)
+    ));
+    let mut raw = tokio::net::TcpStream::connect(&addr).await?;
+    raw.write_all(b"{\"type\":\"SetCapabilities\",\"capabilities\":[\"message_pack\"]}\n")
+        .await?;
+    let mut decoder = FrameDecoder::new(1024 * 1024);
+    let mut next = async |raw: &mut tokio::net::TcpStream| -> Result<ServerFrame> {
+        let mut buffer = [0; 1024];
+        loop {
+            if let Some(frame) = decoder.decode()? {
+                if let ServerFrame::CapabilitiesSelected { capabilities } = &frame {
+                    decoder.set_format(WireFormat::negotiated(capabilities));
+                }
+                return Ok(frame);
+            }
+            let n = raw.read(&mut buffer).await?;
+            anyhow::ensure!(n > 0, "connection closed");
+            decoder.extend(&buffer[..n]);
+        }
+    };
+    // The reply is the last JSON frame; a JSON decoder couldn't read what
+    // follows.
+    assert!(matches!(
+        next(&mut raw).await?,
+        ServerFrame::CapabilitiesSelected { .. }
+    ));
+
+    avery
+        .send(
+            ChatMessage::builder()
+                .sender("avery")
+                .content("packed")
+                .build()?,
+        )
+        .await?;
+    for frame in [
+        avery.receive().await?,
+        blake.receive().await?,
+        next(&mut raw).await?,
+    ] {
+        assert!(matches!(
+            frame,
+            ServerFrame::Message { message, .. } if message.content == "packed"
+        ));
+    }
+
+    // Replies are in the negotiated format too.
+    let locales = vec!["en".to_string()];
+    blake
+        .send_frame(&ClientFrame::SetLocale { locales })
+        .await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::LocaleSelected { .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_drafts_follow_users_across_devices() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "a")));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut phone = Client::connect(&addr).await?;
+    phone.authenticate("avery", "a").await?;
+    phone.receive().await?;
+    let mut laptop = Client::connect(&addr).await?;
+    laptop.authenticate("avery", "a").await?;
+    laptop.receive().await?;
+
+    phone
+        .send_frame(&ClientFrame::SetDraft {
+            room: "general".to_string(),
+            content: "half a thou".to_string(),
+        })
+        .await?;
+    for client in [&mut phone, &mut laptop] {
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Draft { room, draft: Some(draft) }
+                if room == "general" && draft.content == "half a thou"
+        ));
+    }
+    laptop.send_frame(&ClientFrame::GetDrafts).await?;
+    let ServerFrame::Drafts { drafts } = laptop.receive().await? else {
+        panic!("Expected Drafts");
+    };
+    assert_eq!(drafts["general"].content, "half a thou");
+
+    // Posting in the room clears its draft everywhere.
+    laptop
+        .send(ChatMessage::from_raw("avery: half a thought")?)
+        .await?;
+    for client in [&mut phone, &mut laptop] {
+        let mut cleared = false;
+        for _ in 0..2 {
+            match client.receive().await? {
+                ServerFrame::Draft { draft: None, .. } => cleared = true,
+                ServerFrame::Message { .. } => {}
+                frame => panic!("Unexpected frame: {:?}", frame),
+            }
+        }
+        assert!(cleared);
+    }
+    phone.send_frame(&ClientFrame::GetDrafts).await?;
+    assert!(matches!(
+        phone.receive().await?,
+        ServerFrame::Drafts { drafts } if drafts.is_empty()
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_saved_messages_last_across_sessions() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "a")));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client.authenticate("avery", "a").await?;
+    client.receive().await?;
+    client
+        .send(ChatMessage::from_raw(
+            "avery: the wifi password is hunter2",
+        )?)
+        .await?;
+    let ServerFrame::Message { message, .. } = client.receive().await? else {
+        panic!("Expected the message");
+    };
+    let message_id = message.id.unwrap();
+    client
+        .send_frame(&ClientFrame::SaveMessage { message_id })
+        .await?;
+    assert_eq!(
+        client.receive().await?,
+        ServerFrame::MessageSaved { message_id }
+    );
+    client
+        .send_frame(&ClientFrame::SaveMessage { message_id: 999 })
+        .await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+    drop(client);
+
+    let mut client = Client::connect(&addr).await?;
+    client.authenticate("avery", "a").await?;
+    client.receive().await?;
+    client.send_frame(&ClientFrame::ListSaved).await?;
+    let ServerFrame::Saved { messages } = client.receive().await? else {
+        panic!("Expected Saved");
+    };
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].message, message);
+
+    client
+        .send_frame(&ClientFrame::UnsaveMessage { message_id })
+        .await?;
+    assert_eq!(
+        client.receive().await?,
+        ServerFrame::MessageUnsaved { message_id }
+    );
+    client.send_frame(&ClientFrame::ListSaved).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Saved { messages } if messages.is_empty()
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_saved_messages_need_read_access() -> Result<()> {
+    let staff = RoomConfig {
+        owner: Some("avery".to_string()),
+        members: ["blake".to_string()].into(),
+        permissions: RoomPermissions {
+            read: Role::Members,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b")
+                .with_user("casey", "c"),
+        ))
+        .with_room("staff", staff);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut clients = Vec::new();
+    for (user, token) in [("avery", "a"), ("blake", "b"), ("casey", "c")] {
+        let mut client = Client::connect(&addr).await?;
+        client.authenticate(user, token).await?;
+        client.receive().await?;
+        clients.push(client);
+    }
+    let [avery, blake, casey] = &mut clients[..] else {
+        unreachable!();
+    };
+    let mut memo = ChatMessage::from_raw("avery: staff only")?;
+    memo.room = Some("staff".to_string());
+    avery.send(memo).await?;
+    let ServerFrame::Message { message, .. } = blake.receive().await? else {
+        panic!("Expected the message");
+    };
+    let message_id = message.id.unwrap();
+
+    casey
+        .send_frame(&ClientFrame::SaveMessage { message_id })
+        .await?;
+    assert!(matches!(
+        casey.receive().await?,
+        ServerFrame::Error { message } if message == format!("Unknown message {}", message_id)
+    ));
+    blake
+        .send_frame(&ClientFrame::SaveMessage { message_id })
+        .await?;
+    assert_eq!(
+        blake.receive().await?,
+        ServerFrame::MessageSaved { message_id }
+    );
+
+    // Once Blake leaves, the saved message is no longer listed.
+    blake
+        .send(ChatMessage::from_raw("blake: /leave staff")?)
+        .await?;
+    blake.send_frame(&ClientFrame::ListSaved).await?;
+    loop {
+        if let ServerFrame::Saved { messages } = blake.receive().await? {
+            assert!(messages.is_empty());
+            break;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recorded_connections_can_be_played_back() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("chat-recordings-{}", std::process::id()));
+    let tokens = || Arc::new(StaticTokens::new().with_user("avery", "a"));
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(tokens())
+        .with_recordings(&dir);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client.authenticate("avery", "a").await?;
+    client.receive().await?;
+    client.send(ChatMessage::from_raw("avery: hello")?).await?;
+    client.receive().await?;
+    drop(client);
+
+    let path = loop {
+        let mut entries = tokio::fs::read_dir(&dir).await?;
+        if let Some(entry) = entries.next_entry().await? {
+            // The last line may still be half written.
+            let records = recording::read(entry.path()).await.unwrap_or_default();
+            if records.len() == 4 {
+                break entry.path();
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    };
+    let records = recording::read(&path).await?;
+    let directions: Vec<Direction> = records.iter().map(|record| record.direction).collect();
+    assert_eq!(
+        directions,
+        [
+            Direction::Inbound,
+            Direction::Outbound,
+            Direction::Inbound,
+            Direction::Outbound
+        ]
+    );
+    assert!(records[0].line.contains(recording::REDACTED));
+    assert!(!records[0].line.contains("\"a\""));
+    assert!(records[1].line.contains(recording::REDACTED));
+
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(tokens());
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let options = PlaybackOptions {
+        speed: 0.0,
+        secret: Some("a".to_string()),
+        linger: Duration::from_millis(200),
+    };
+    let received = recording::play(&records, &addr, &options).await?;
+    let frames: Vec<ServerFrame> = received
+        .iter()
+        .map(|line| serde_json::from_str(line))
+        .collect::<Result<_, _>>()?;
+    assert!(matches!(frames[0], ServerFrame::Authenticated { .. }));
+    assert!(matches!(
+        &frames[1],
+        ServerFrame::Message { message, .. } if message.content == "hello"
+    ));
+    tokio::fs::remove_dir_all(&dir).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_debug_info_is_limited_to_debuggers() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b"),
+        ))
+        .with_debuggers(["avery"]);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::builder(&addr)
+        .capabilities([Capability::Formatting])
+        .connect()
+        .await?;
+    avery.receive().await?;
+    avery.authenticate("avery", "a").await?;
+    avery.receive().await?;
+    avery.send(ChatMessage::from_raw("avery: testing")?).await?;
+    avery.receive().await?;
+    avery.send_frame(&ClientFrame::DebugInfo).await?;
+    let ServerFrame::DebugInfo(info) = avery.receive().await? else {
+        panic!("Expected debug info");
+    };
+    assert_eq!(info.user, "avery");
+    assert!(!info.guest);
+    assert_eq!(info.capabilities, [Capability::Formatting].into());
+    assert_eq!(info.quota.messages_today, 1);
+    assert_eq!(info.presence_subscription, None);
+
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    blake.receive().await?;
+    blake.send_frame(&ClientFrame::DebugInfo).await?;
+    assert!(matches!(blake.receive().await?, ServerFrame::Error { .. }));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tasks_are_counted_and_joined_on_shutdown() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    let admin = server.admin();
+    let metrics = server.metrics();
+    let tasks = metrics.tasks();
+    let running = tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    client.send(ChatMessage::from_raw("avery: hello")?).await?;
+    client.receive().await?;
+
+    let counts = metrics.snapshot().tasks;
+    assert_eq!(counts.get("clients"), Some(&1));
+    assert_eq!(counts.get("writers"), Some(&1));
+    assert!(counts.get("background").is_some_and(|&count| count > 0));
+
+    assert_eq!(admin.drain()?, 1);
+    timeout(Duration::from_secs(10), running).await???;
+    assert!(tasks.running().is_empty());
+    assert!(metrics.snapshot().tasks.is_empty());
+    Ok(())
+}
+
+/// Panics on "boom", like a buggy plugin.
+struct PanickingRouter;
+
+#[async_trait]
+impl Router for PanickingRouter {
+    async fn route(&self, _from: SocketAddr, message: ChatMessage) -> Route {
+        if message.content.contains("boom") {
+            panic!("router exploded");
+        }
+        Route::Broadcast(message)
+    }
+}
+
+#[tokio::test]
+async fn test_handler_panic_closes_only_its_connection() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_router(Arc::new(PanickingRouter));
+    let addr = server.local_addr()?.to_string();
+    let metrics = server.metrics();
+    let admin = server.admin();
+    tokio::spawn(server.run());
+    let mut avery = Client::connect(&addr).await?;
+    let mut blake = Client::connect(&addr).await?;
+
+    avery.send(ChatMessage::from_raw("avery: boom")?).await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Error { message } if message.contains("Internal server error")
+    ));
+    assert!(avery.receive().await.is_err());
+    assert_eq!(metrics.snapshot().handler_panics, 1);
+
+    blake
+        .send(ChatMessage::from_raw("blake: still here")?)
+        .await?;
+    let ServerFrame::Message { message, .. } = blake.receive().await? else {
+        panic!("expected the message");
+    };
+    assert_eq!(message.content, "still here");
+    timeout(Duration::from_secs(5), async {
+        while admin.connections().len() != 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+#[tokio::test]
+async fn test_readiness_reports_background_jobs_and_drain() -> Result<()> {
+    use tokio_chat_server::admin::Readiness;
+    use tokio_chat_server::http::{Admin, serve_admin};
+
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let control = server.admin();
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let admin_addr = listener.local_addr()?;
+    tokio::spawn(serve_admin(
+        listener,
+        Admin {
+            metrics: server.metrics(),
+            dead_letters: server.dead_letters(),
+            api_keys: server.api_keys(),
+            resources: server.resources(),
+            control: control.clone(),
+            token: "secret".to_string(),
+        },
+    ));
+    let addr = server.local_addr()?.to_string();
+    let running = tokio::spawn(server.run());
+    // Once a client is taken, the jobs have started.
+    let _client = Client::connect(&addr).await?;
+    let ready = || async move {
+        let mut stream = tokio::net::TcpStream::connect(admin_addr).await?;
+        stream
+            .write_all(b"GET /ready HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        let (head, json) = response.split_once("\r\n\r\n").unwrap();
+        let readiness: Readiness = serde_json::from_str(json)?;
+        anyhow::Ok((head.to_string(), readiness))
+    };
+
+    let (head, readiness) = ready().await?;
+    assert!(head.starts_with("HTTP/1.1 200"));
+    assert!(readiness.ready);
+    let jobs: Vec<&str> = readiness.jobs.iter().map(|job| job.name.as_str()).collect();
+    assert_eq!(jobs, ["ack expiry", "room expiry"]);
+
+    control.drain()?;
+    let (head, readiness) = ready().await?;
+    assert!(head.starts_with("HTTP/1.1 503"));
+    assert!(readiness.draining && !readiness.ready);
+    timeout(Duration::from_secs(10), running).await???;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_resolves_hosts_and_races_addresses() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?;
+    tokio::spawn(server.run());
+    let closed = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await?
+        .local_addr()?;
+
+    let mut avery = Client::connect(("localhost", addr.port())).await?;
+    let mut blake = Client::connect(&[closed, addr][..]).await?;
+    avery.send(ChatMessage::from_raw("avery: hi")?).await?;
+    let ServerFrame::Message { message, .. } = blake.receive().await? else {
+        panic!("expected the message");
+    };
+    assert_eq!(message.content, "hi");
+    blake.reconnect().await?;
+
+    assert!(Client::connect(closed).await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_unauthenticated_clients_can_only_sign_in() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(StaticTokens::new().with_user("avery", "secret")));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client.fetch_history(None, None, None).await?;
+    assert!(
+        matches!(client.receive().await?, ServerFrame::Error { message } if message == "Authenticate first")
+    );
+    client.send_frame(&ClientFrame::ListSaved).await?;
+    assert!(matches!(client.receive().await?, ServerFrame::Error { .. }));
+
+    client.authenticate("avery", "secret").await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    client.fetch_history(None, None, None).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::History { .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rooms_with_limited_reading_reach_only_readers() -> Result<()> {
+    let secret = RoomConfig {
+        owner: Some("avery".to_string()),
+        permissions: RoomPermissions {
+            read: Role::Members,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b"),
+        ))
+        .with_room("secret", secret);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a").await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b").await?;
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Authenticated { .. }
+    ));
+
+    let mut hidden = ChatMessage::from_raw("avery: the plan")?;
+    hidden.room = Some("secret".to_string());
+    avery.send(hidden).await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Message { message, .. } if message.content == "the plan"
+    ));
+    avery
+        .send(ChatMessage::from_raw("avery: hello all")?)
+        .await?;
+    // Blake gets the next message in general, but not the one in secret.
+    assert!(matches!(
+        blake.receive().await?,
+        ServerFrame::Message { message, .. } if message.content == "hello all"
+    ));
+
+    blake
+        .fetch_history(Some("secret".to_string()), None, None)
+        .await?;
+    assert!(
+        matches!(blake.receive().await?, ServerFrame::Error { message } if message == "Only members can read in secret")
+    );
+    blake
+        .send_frame(&ClientFrame::Backfill {
+            room: "secret".to_string(),
+            from_seq: 1,
+            to_seq: 10,
+        })
+        .await?;
+    assert!(matches!(blake.receive().await?, ServerFrame::Error { .. }));
+    blake
+        .send_frame(&ClientFrame::Search {
+            query: "plan".to_string(),
+            room: None,
+            limit: None,
+            before: None,
+        })
+        .await?;
+    assert!(
+        matches!(blake.receive().await?, ServerFrame::SearchResults { hits, .. } if hits.is_empty())
+    );
+    blake
+        .send_frame(&ClientFrame::Resume { last_id: 0 })
+        .await?;
+    let ServerFrame::Replay { messages, .. } = blake.receive().await? else {
+        panic!("expected Replay");
+    };
+    assert_eq!(messages.len(), 1);
+    assert_eq!(messages[0].content, "hello all");
+
+    avery
+        .fetch_history(Some("secret".to_string()), None, None)
+        .await?;
+    // Avery's own copy of "hello all" arrives first.
+    avery.receive().await?;
     assert!(
-        received.starts_with("127.0.0.1:")
-            && received.contains("\"sender\":\"avery\"")
-            && received.contains("\"content\":\"Hello from client1\"")
+        matches!(avery.receive().await?, ServerFrame::History { messages, .. } if messages.len() == 1)
     );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_blocked_users_are_filtered_silently() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a")
+                .with_user("blake", "b")
+                .with_user("casey", "c"),
+        ))
+        .with_router(Arc::new(MentionRouter));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut clients = Vec::new();
+    for (user, token) in [("avery", "a"), ("blake", "b"), ("casey", "c")] {
+        let mut client = Client::connect(&addr).await?;
+        client.authenticate(user, token).await?;
+        assert!(matches!(
+            client.receive().await?,
+            ServerFrame::Authenticated { .. }
+        ));
+        clients.push(client);
+    }
+    let [avery, blake, casey] = &mut clients[..] else {
+        unreachable!();
+    };
+
+    avery
+        .send(ChatMessage::from_raw("x: /block blake")?)
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Notice { text } if text == "Blocked blake"
+    ));
+
+    // Blake's messages reach everyone but avery, and blake sees nothing
+    // different.
+    blake
+        .send(ChatMessage::from_raw("x: anyone there?")?)
+        .await?;
+    for client in [&mut *blake, casey] {
+        let ServerFrame::Message { message, .. } = client.receive().await? else {
+            panic!("expected blake's message");
+        };
+        assert_eq!(message.content, "anyone there?");
+    }
+    blake.send(ChatMessage::from_raw("x: @avery psst")?).await?;
+    let ServerFrame::Message { message, .. } = blake.receive().await? else {
+        panic!("expected the direct message echoed");
+    };
+    assert_eq!(message.content, "@avery psst");
+
+    avery.send(ChatMessage::from_raw("x: /block")?).await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Notice { text } if text == "Blocked: blake"
+    ));
+    avery
+        .send_frame(&ClientFrame::FetchHistory {
+            room: None,
+            before: None,
+            limit: None,
+        })
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::History { messages, .. } if messages.is_empty()
+    ));
+
+    avery
+        .send(ChatMessage::from_raw("x: /unblock blake")?)
+        .await?;
+    assert!(matches!(
+        avery.receive().await?,
+        ServerFrame::Notice { text } if text == "Unblocked blake"
+    ));
+    blake.send(ChatMessage::from_raw("x: hello again")?).await?;
+    let ServerFrame::Message { message, .. } = avery.receive().await? else {
+        panic!("expected blake's message once unblocked");
+    };
+    assert_eq!(message.content, "hello again");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_shortcodes_expand_per_tenant() -> Result<()> {
+    let config: Config = serde_json::from_value(serde_json::json!({
+        "tenants": {
+            "acme": { "shortcodes": { "shipit": "\u{1f43f}\u{fe0f}" } },
+        },
+    }))?;
+    let server = config.apply(ChatServer::new("127.0.0.1:0").await?);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut acme = Client::builder(&addr).tenant("acme").connect().await?;
+    let mut host = Client::connect(&addr).await?;
+    for (client, expected) in [
+        (&mut acme, "\u{1f43f}\u{fe0f} \u{1f389} `:tada:`"),
+        (&mut host, ":shipit: :tada: `:tada:`"),
+    ] {
+        client
+            .send(ChatMessage::from_raw("avery: :shipit: :tada: `:tada:`")?)
+            .await?;
+        let ServerFrame::Message { message, .. } = client.receive().await? else {
+            panic!("expected the relayed message");
+        };
+        assert_eq!(message.content, expected);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_custom_emoji_are_listed_and_fetchable() -> Result<()> {
+    let acme = ChatServer::tenant();
+    let acme_admin = acme.admin();
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_tenant("acme", acme);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::builder(&addr).tenant("acme").connect().await?;
+    let mut host = Client::connect(&addr).await?;
+    client.send_frame(&ClientFrame::ListEmoji).await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::EmojiList { emoji } if emoji.is_empty()
+    ));
+
+    let image = Bytes::from_static(b"\x89PNG party parrot");
+    assert!(acme_admin.add_emoji("Party!", image.clone()).await.is_err());
+    let party = acme_admin.add_emoji("partyparrot", image.clone()).await?;
+    assert_eq!(party.blob_id, blob_id(&image));
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::EmojiList { emoji } if emoji == [party.clone()]
+    ));
+
+    client
+        .send_frame(&ClientFrame::FetchFile {
+            id: party.blob_id.clone(),
+        })
+        .await?;
+    let ServerFrame::FileChunk { data, .. } = client.receive().await? else {
+        panic!("expected the emoji image");
+    };
+    assert_eq!(BASE64.decode(data)?, image);
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::FileEnd { .. }
+    ));
+
+    // Other tenants have emoji of their own.
+    host.send_frame(&ClientFrame::ListEmoji).await?;
+    assert!(matches!(
+        host.receive().await?,
+        ServerFrame::EmojiList { emoji } if emoji.is_empty()
+    ));
+
+    assert!(acme_admin.remove_emoji("partyparrot")?);
+    assert!(!acme_admin.remove_emoji("partyparrot")?);
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::EmojiList { emoji } if emoji.is_empty()
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_tenants_are_isolated() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_tenant("acme", ChatServer::tenant())
+        .with_tenant("globex", ChatServer::tenant());
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut acme = Vec::new();
+    for _ in 0..2 {
+        acme.push(Client::builder(&addr).tenant("acme").connect().await?);
+    }
+    let mut globex = Client::builder(&addr).tenant("globex").connect().await?;
+    let mut host = Client::connect(&addr).await?;
+
+    acme[0]
+        .send(ChatMessage::from_raw("avery: hi acme")?)
+        .await?;
+    for client in &mut acme {
+        let ServerFrame::Message { message, .. } = client.receive().await? else {
+            panic!("expected the acme message");
+        };
+        assert_eq!(message.content, "hi acme");
+    }
+    for (client, content) in [(&mut globex, "hi globex"), (&mut host, "hi host")] {
+        client
+            .send(ChatMessage::from_raw(&format!("blake: {}", content))?)
+            .await?;
+        let ServerFrame::Message { message, .. } = client.receive().await? else {
+            panic!("expected its own message");
+        };
+        assert_eq!(message.content, content);
+    }
+
+    let mut lost = Client::builder(&addr).tenant("initech").connect().await?;
+    assert!(matches!(
+        lost.receive().await?,
+        ServerFrame::Error { message } if message == "Unknown tenant initech"
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_room_permissions() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut owner = Client::connect(&addr).await?;
+    let mut other = Client::connect(&addr).await?;
+    for command in ["/create plans", "/perm plans post members"] {
+        owner
+            .send(ChatMessage::from_raw(&format!("avery: {}", command))?)
+            .await?;
+        for client in [&mut owner, &mut other] {
+            let ServerFrame::RoomUpdated { room, by, .. } = client.receive().await? else {
+                panic!("expected RoomUpdated");
+            };
+            assert_eq!((room.as_str(), by.as_str()), ("plans", "avery"));
+        }
+    }
+
+    let post = ChatMessage::builder()
+        .sender("blake")
+        .content("can I join?")
+        .room("plans")
+        .build()?;
+    other.send(post.clone()).await?;
+    let ServerFrame::Error { message } = other.receive().await? else {
+        panic!("expected Error");
+    };
+    assert_eq!(message, "Only members can post in plans");
+    other
+        .send(ChatMessage::from_raw("blake: /topic plans mine now")?)
+        .await?;
+    assert!(matches!(other.receive().await?, ServerFrame::Error { .. }));
+
+    owner
+        .send(ChatMessage::from_raw("avery: /invite blake plans")?)
+        .await?;
+    let ServerFrame::RoomUpdated { config, .. } = other.receive().await? else {
+        panic!("expected RoomUpdated");
+    };
+    assert!(config.members.contains("blake"));
+    other.send(post).await?;
+    let ServerFrame::Message { message, .. } = other.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.room(), "plans");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_announcement_room_is_read_only() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?.with_room(
+        "news",
+        RoomConfig {
+            owner: Some("avery".to_string()),
+            announcement: true,
+            ..Default::default()
+        },
+    );
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    let post = |sender: &str| {
+        ChatMessage::builder()
+            .sender(sender)
+            .content("release tonight")
+            .room("news")
+            .build()
+    };
+    client.send(post("blake")?).await?;
+    let ServerFrame::ReadOnlyRoom { room } = client.receive().await? else {
+        panic!("expected ReadOnlyRoom");
+    };
+    assert_eq!(room, "news");
+    client.send(post("avery")?).await?;
+    let ServerFrame::Message { message, .. } = client.receive().await? else {
+        panic!("expected the relayed message");
+    };
+    assert_eq!(message.sender, "avery");
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_room_capacity_and_waitlist() -> Result<()> {
+    let mut config = RoomConfig {
+        owner: Some("avery".to_string()),
+        max_members: Some(1),
+        waitlist_enabled: true,
+        ..Default::default()
+    };
+    config.permissions.invite = Role::Everyone;
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_room("stage", config);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client
+        .send(ChatMessage::from_raw("blake: /join stage")?)
+        .await?;
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::RoomUpdated { .. }
+    ));
+    client
+        .send(ChatMessage::from_raw("casey: /join stage")?)
+        .await?;
+    let ServerFrame::RoomFull {
+        max_members,
+        waitlist_position,
+        ..
+    } = client.receive().await?
+    else {
+        panic!("expected RoomFull");
+    };
+    assert_eq!((max_members, waitlist_position), (1, Some(1)));
+
+    client
+        .send(ChatMessage::from_raw("blake: /leave stage")?)
+        .await?;
+    let ServerFrame::RoomUpdated { config, .. } = client.receive().await? else {
+        panic!("expected RoomUpdated");
+    };
+    assert!(config.members.contains("casey") && config.waitlist.is_empty());
+    let ServerFrame::WaitlistAdmitted { room, user } = client.receive().await? else {
+        panic!("expected WaitlistAdmitted");
+    };
+    assert_eq!((room.as_str(), user.as_str()), ("stage", "casey"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_idle_connection_goes_away() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_idle_timeout(Duration::from_millis(200));
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    client.send_frame(&ClientFrame::Heartbeat).await?;
+    let ServerFrame::PresenceChanged(presence) = client.receive().await? else {
+        panic!("expected PresenceChanged");
+    };
+    assert_eq!(presence.profile.state, PresenceState::Away);
+
+    client.send(ChatMessage::from_raw("avery: back")?).await?;
+    let ServerFrame::PresenceChanged(presence) = client.receive().await? else {
+        panic!("expected PresenceChanged");
+    };
+    assert_eq!(presence.profile.state, PresenceState::Online);
+    assert!(matches!(
+        client.receive().await?,
+        ServerFrame::Message { .. }
+    ));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sequence_numbers_per_room() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    for room in ["general", "lobby", "general"] {
+        let message = ChatMessage::builder()
+            .sender("avery")
+            .content("hi")
+            .room(room)
+            .build()?;
+        client.send(message).await?;
+    }
+    let mut seqs = Vec::new();
+    for _ in 0..3 {
+        let ServerFrame::Message { message, .. } = client.receive().await? else {
+            panic!("expected a relayed message");
+        };
+        seqs.push((message.room().to_string(), message.seq));
+    }
+    assert_eq!(
+        seqs,
+        vec![
+            ("general".to_string(), Some(1)),
+            ("lobby".to_string(), Some(1)),
+            ("general".to_string(), Some(2)),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sequence_numbers_and_ids_outlive_deleted_messages() -> Result<()> {
+    use tokio_chat_server::store::{MemoryStore, MessageStore};
+
+    let store = Arc::new(MemoryStore::default());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone());
+    let (addr, admin) = (server.local_addr()?.to_string(), server.admin());
+    let running = tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    for content in ["one", "two"] {
+        client
+            .send(ChatMessage::from_raw(&format!("avery: {}", content))?)
+            .await?;
+        client.receive().await?;
+    }
+    admin.drain()?;
+    timeout(Duration::from_secs(10), running).await???;
+    // As if the newest message had expired or been pruned.
+    let newest = client.last_message_id().unwrap();
+    assert!(store.remove("general", newest).await?);
+
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_message_store(store.clone());
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+    let mut client = Client::connect(&addr).await?;
+    client.send(ChatMessage::from_raw("avery: three")?).await?;
+    let ServerFrame::Message { message, .. } = client.receive().await? else {
+        panic!("expected a relayed message");
+    };
+    assert_eq!(message.seq, Some(3));
+    // A client that saw the deleted message resumes past its id.
+    assert_eq!(message.id, Some(newest + 1));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_backfill_fills_sequence_gaps() -> Result<()> {
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    for content in ["one", "two", "three"] {
+        client
+            .send(ChatMessage::from_raw(&format!("avery: {}", content))?)
+            .await?;
+        client.receive().await?;
+    }
+    assert_eq!(client.last_seq("general"), Some(3));
+    client.backfill("general", 2, 3).await?;
+    let ServerFrame::Backfill {
+        messages, complete, ..
+    } = client.receive().await?
+    else {
+        panic!("expected Backfill");
+    };
+    let contents: Vec<_> = messages.iter().map(|m| m.content.as_str()).collect();
+    assert_eq!(contents, vec!["two", "three"]);
+    assert!(complete);
+
+    // A client that sees a jump asks for the missing range by itself.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let fake_addr = listener.local_addr()?.to_string();
+    let mut client = Client::builder(&fake_addr)
+        .backfill_gaps(true)
+        .connect()
+        .await?;
+    let (mut socket, _) = listener.accept().await?;
+    for seq in [1, 4] {
+        let frame = ServerFrame::Message {
+            from: "server".to_string(),
+            message: ChatMessage {
+                seq: Some(seq),
+                ..ChatMessage::from_raw("avery: hi")?
+            },
+        };
+        socket
+            .write_all(format!("{}\n", frame.to_json()?).as_bytes())
+            .await?;
+        client.receive().await?;
+    }
+    let mut decoder = FrameDecoder::new(1024);
+    let mut buffer = [0; 1024];
+    let request = loop {
+        if let Some(frame) = decoder.decode::<ClientFrame>()? {
+            break frame;
+        }
+        let n = socket.read(&mut buffer).await?;
+        decoder.extend(&buffer[..n]);
+    };
+    assert_eq!(
+        request,
+        ClientFrame::Backfill {
+            room: "general".to_string(),
+            from_seq: 2,
+            to_seq: 3,
+        }
+    );
+    Ok(())
+}
+
+#[cfg(feature = "chaos")]
+#[tokio::test]
+async fn test_chaos_drops_broadcasts_and_connections() -> Result<()> {
+    use tokio_chat_server::chaos::ChaosConfig;
+
+    let server = ChatServer::new("127.0.0.1:0").await?;
+    let addr = server.local_addr()?.to_string();
+    let chaos = server.chaos();
+    tokio::spawn(server.run());
+
+    let mut observer = Client::builder(&addr).backfill_gaps(true).connect().await?;
+    let mut sender = Client::connect(&addr).await?;
+    sender.send(ChatMessage::from_raw("avery: zero")?).await?;
+    observer.receive().await?;
+    sender.receive().await?;
+
+    chaos.set(ChaosConfig {
+        drop_broadcast_rate: 1.0,
+        ..Default::default()
+    });
+    sender.send(ChatMessage::from_raw("avery: one")?).await?;
+    // Replies aren't broadcasts, so this one gets through once "one" has
+    // been handed to the fan-out.
+    sender.send_frame(&ClientFrame::Presence).await?;
+    assert!(matches!(
+        sender.receive().await?,
+        ServerFrame::Presence { .. }
+    ));
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    chaos.set(ChaosConfig::default());
+    sender.send(ChatMessage::from_raw("avery: two")?).await?;
+    let ServerFrame::Message { message, .. } = observer.receive().await? else {
+        panic!("expected a relayed message");
+    };
+    assert_eq!((message.content.as_str(), message.seq), ("two", Some(3)));
+    let ServerFrame::Backfill { messages, .. } = observer.receive().await? else {
+        panic!("expected the dropped message to be backfilled");
+    };
+    assert_eq!(messages[0].content, "one");
+
+    chaos.set(ChaosConfig {
+        disconnect_rate: 1.0,
+        ..Default::default()
+    });
+    sender.send(ChatMessage::from_raw("avery: three")?).await?;
+    assert!(observer.receive().await.is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_at_least_once_room_redelivers_until_acked() -> Result<()> {
+    let mut config = RoomConfig {
+        owner: Some("avery".to_string()),
+        delivery: DeliveryMode::AtLeastOnce,
+        ..Default::default()
+    };
+    config.members.insert("blake".to_string());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a-secret")
+                .with_user("blake", "b-secret"),
+        ))
+        .with_room("ops", config);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a-secret").await?;
+    avery.receive().await?;
+    let alert = ChatMessage::builder()
+        .sender("avery")
+        .content("disk full on db-2")
+        .room("ops")
+        .build()?;
+    avery.send(alert).await?;
+    avery.receive().await?;
+
+    // Blake was offline, so gets the message on signing in, until acking it.
+    for attempt in 0..2 {
+        let mut blake = Client::connect(&addr).await?;
+        blake.authenticate("blake", "b-secret").await?;
+        assert!(matches!(
+            blake.receive().await?,
+            ServerFrame::Authenticated { .. }
+        ));
+        if attempt == 0 {
+            let ServerFrame::Unacked { messages } = blake.receive().await? else {
+                panic!("expected Unacked");
+            };
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].content, "disk full on db-2");
+            blake.ack("ops", messages[0].seq.unwrap()).await?;
+        }
+        // The server handles frames in order, so the ack is applied by the
+        // time the presence reply arrives.
+        blake.send_frame(&ClientFrame::Presence).await?;
+        assert!(matches!(
+            blake.receive().await?,
+            ServerFrame::Presence { .. }
+        ));
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_at_least_once_holds_one_copy_per_participant() -> Result<()> {
+    let mut config = RoomConfig {
+        owner: Some("avery".to_string()),
+        delivery: DeliveryMode::AtLeastOnce,
+        ..Default::default()
+    };
+    config.moderators.insert("blake".to_string());
+    config.members.insert("blake".to_string());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_authenticator(Arc::new(
+            StaticTokens::new()
+                .with_user("avery", "a-secret")
+                .with_user("blake", "b-secret"),
+        ))
+        .with_room("ops", config);
+    let addr = server.local_addr()?.to_string();
+    tokio::spawn(server.run());
+
+    let mut avery = Client::connect(&addr).await?;
+    avery.authenticate("avery", "a-secret").await?;
+    avery.receive().await?;
+    let alert = ChatMessage::builder()
+        .sender("avery")
+        .content("disk full on db-2")
+        .room("ops")
+        .build()?;
+    avery.send(alert).await?;
+    avery.receive().await?;
+
+    let mut blake = Client::connect(&addr).await?;
+    blake.authenticate("blake", "b-secret").await?;
+    blake.receive().await?;
+    let ServerFrame::Unacked { messages } = blake.receive().await? else {
+        panic!("expected Unacked");
+    };
+    assert_eq!(messages.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_overflowing_unacked_messages_are_dead_lettered() -> Result<()> {
+    let mut config = RoomConfig {
+        owner: Some("avery".to_string()),
+        delivery: DeliveryMode::AtLeastOnce,
+        ..Default::default()
+    };
+    config.members.insert("blake".to_string());
+    let server = ChatServer::new("127.0.0.1:0")
+        .await?
+        .with_room("ops", config)
+        .with_unacked_limits(1, Duration::from_secs(3600));
+    let addr = server.local_addr()?.to_string();
+    let dead_letters = server.dead_letters();
+    let metrics = server.metrics();
+    tokio::spawn(server.run());
+
+    let mut client = Client::connect(&addr).await?;
+    for content in ["first", "second"] {
+        let message = ChatMessage::builder()
+            .sender("avery")
+            .content(content)
+            .room("ops")
+            .build()?;
+        client.send(message).await?;
+        client.receive().await?;
+    }
+    let letters = dead_letters.list(Some("blake"));
+    assert_eq!(letters.len(), 1);
+    assert_eq!(letters[0].message.content, "first");
+    assert_eq!(letters[0].reason, DeadLetterReason::QueueOverflow);
+    assert_eq!(metrics.snapshot().dead_letters, 1);
+
+    #[cfg(feature = "http")]
+    {
+        use tokio_chat_server::delivery::DeadLetter;
+        use tokio_chat_server::http::{Admin, serve_admin};
 
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let admin_addr = listener.local_addr()?;
+        tokio::spawn(serve_admin(
+            listener,
+            Admin {
+                metrics,
+                dead_letters,
+                api_keys: Default::default(),
+                resources: Default::default(),
+                control: Default::default(),
+                token: "operator".to_string(),
+            },
+        ));
+        let mut stream = tokio::net::TcpStream::connect(admin_addr).await?;
+        stream
+            .write_all(
+                b"GET /admin/dead-letters?user=blake HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer operator\r\nConnection: close\r\n\r\n",
+            )
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        let (_, json) = response.split_once("\r\n\r\n").unwrap();
+        let listed: Vec<DeadLetter> = serde_json::from_str(json)?;
+        assert_eq!(listed, letters);
+    }
     Ok(())
 }